@@ -0,0 +1,310 @@
+//! the admin API, for the operators of a server
+//!
+//! Operators are not clients: they have a name, a token and a role, kept in `AdminCredentials`,
+//! and authenticate every `AdminQuery` with them, whatever the client auth of the transport. Each
+//! query needs a role, the roles are ordered and a role can do what the ones below it can:
+//!
+//!  * `ReadOnly` lists the users;
+//!  * `Moderator` kicks and bans clients, and issues invites;
+//!  * `Operator` bans addresses.
+//!
+//! `AdminApi` answers the queries with an `AdminServer`, and reports every one of them, refused
+//! or not, as an `AdminEvent`: who asked for what, and how it went.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::channel::Receiver;
+use crypto_hash::{digest, Algorithm};
+
+use crate::audit::{same, EventLog};
+use crate::clock::SharedClock;
+use crate::core::{AdminServer, SpamChecker};
+use crate::invite::Invite;
+use crate::messages::{ClientId, ClientReply};
+
+/// what an operator may do, each role can do what the ones below it can
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AdminRole {
+  ReadOnly,
+  Moderator,
+  Operator,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminQuery {
+  ListUsers,
+  KickClient(ClientId),
+  BanClient(ClientId),
+  BanIp(IpAddr),
+  /// an invite for this many registrations, valid for this long
+  Invite {
+    ttl: Duration,
+    uses: u32,
+  },
+}
+
+impl AdminQuery {
+  /// the lowest role allowed to send the query
+  pub fn role(&self) -> AdminRole {
+    match self {
+      AdminQuery::ListUsers => AdminRole::ReadOnly,
+      AdminQuery::KickClient(_) | AdminQuery::BanClient(_) | AdminQuery::Invite { .. } => {
+        AdminRole::Moderator
+      }
+      AdminQuery::BanIp(_) => AdminRole::Operator,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminReply {
+  Users(HashMap<ClientId, String>),
+  /// the replies of the server, with the transfers for the federation driver
+  Replies(Vec<ClientReply>),
+  /// none when the server registers clients without invites
+  Invite(Option<Invite>),
+  Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdminError {
+  /// unknown operator, or wrong token
+  Unauthenticated,
+  /// the role of the operator is below the one of the query
+  Forbidden(AdminRole),
+}
+
+impl std::fmt::Display for AdminError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AdminError::Unauthenticated => "Unknown operator or wrong token".fmt(f),
+      AdminError::Forbidden(role) => write!(f, "Forbidden for the {:?} role", role),
+    }
+  }
+}
+
+impl std::error::Error for AdminError {}
+
+/// The operators, with the SHA-256 of their token and their role.
+#[derive(Debug, Default)]
+pub struct AdminCredentials {
+  operators: HashMap<String, (Vec<u8>, AdminRole)>,
+}
+
+impl AdminCredentials {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// adds, or replaces, the operator `name`
+  pub fn insert(&mut self, name: impl Into<String>, token: &[u8], role: AdminRole) {
+    self
+      .operators
+      .insert(name.into(), (digest(Algorithm::SHA256, token), role));
+  }
+
+  pub fn remove(&mut self, name: &str) {
+    self.operators.remove(name);
+  }
+
+  /// the role of `name`, if `token` is its own
+  pub fn authenticate(&self, name: &str, token: &[u8]) -> Option<AdminRole> {
+    let (expected, role) = self.operators.get(name)?;
+    same(expected, &digest(Algorithm::SHA256, token)).then_some(*role)
+  }
+}
+
+/// audit trail of the admin queries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdminEvent {
+  /// when, in ms since the epoch
+  pub timestamp: u64,
+  /// the name the query came with, authenticated or not
+  pub admin: String,
+  pub query: AdminQuery,
+  pub outcome: Result<(), AdminError>,
+}
+
+/// events kept for the audit, the oldest ones are dropped above
+pub const ADMIN_EVENTS: usize = 1024;
+
+/// Answers the admin queries with `server`, once the operator is authenticated and allowed.
+pub struct AdminApi<S, C> {
+  server: Arc<S>,
+  credentials: AdminCredentials,
+  clock: SharedClock,
+  events: EventLog<AdminEvent>,
+  checker: PhantomData<fn() -> C>,
+}
+
+impl<S, C> AdminApi<S, C>
+where
+  S: AdminServer<C> + Send + Sync,
+  C: SpamChecker,
+{
+  pub fn new(server: Arc<S>, credentials: AdminCredentials, clock: SharedClock) -> Self {
+    AdminApi {
+      server,
+      credentials,
+      clock,
+      events: EventLog::new(ADMIN_EVENTS),
+      checker: PhantomData,
+    }
+  }
+
+  /// the audit events, as they happen
+  pub fn events(&self) -> Receiver<AdminEvent> {
+    self.events.events()
+  }
+
+  /// the query of `admin`, authenticated by `token`
+  pub async fn call(
+    &self,
+    admin: &str,
+    token: &[u8],
+    query: AdminQuery,
+  ) -> Result<AdminReply, AdminError> {
+    let allowed = match self.credentials.authenticate(admin, token) {
+      None => Err(AdminError::Unauthenticated),
+      Some(role) if role < query.role() => Err(AdminError::Forbidden(role)),
+      Some(_) => Ok(()),
+    };
+    match allowed {
+      Ok(()) => log::info!("{} sent {:?}", admin, query),
+      Err(rr) => log::warn!("{} was refused {:?}: {}", admin, query, rr),
+    }
+    self.events.emit(AdminEvent {
+      timestamp: self.clock.now_ms(),
+      admin: admin.to_string(),
+      query: query.clone(),
+      outcome: allowed,
+    });
+    allowed?;
+    Ok(match query {
+      AdminQuery::ListUsers => AdminReply::Users(self.server.list_users().await),
+      AdminQuery::KickClient(client) => AdminReply::Replies(self.server.kick_client(client).await),
+      AdminQuery::BanClient(client) => {
+        AdminReply::Replies(vec![self.server.ban_client(client).await])
+      }
+      AdminQuery::BanIp(ip) => {
+        self.server.ban_ip(ip).await;
+        AdminReply::Done
+      }
+      AdminQuery::Invite { ttl, uses } => {
+        AdminReply::Invite(self.server.issue_invite(ttl, uses).await)
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::clock::ManualClock;
+  use crate::core::{DefaultChecker, MessageServer, ServerConfig};
+  use crate::messages::{ClientError, ClientMessage, ServerId};
+  use crate::solutions::descamps_femery::Server;
+
+  #[test]
+  fn roles() {
+    async_std::task::block_on(async {
+      let server: Arc<Server<DefaultChecker>> = Arc::new(MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      ));
+      let ip: IpAddr = "10.0.0.1".parse().unwrap();
+      let client = server.register_local_client(ip, "c".into()).await.unwrap();
+      let mut credentials = AdminCredentials::new();
+      credentials.insert("watcher", b"w", AdminRole::ReadOnly);
+      credentials.insert("moderator", b"m", AdminRole::Moderator);
+      let api = AdminApi::new(server.clone(), credentials, Arc::new(ManualClock::new(7)));
+      let events = api.events();
+
+      let r = api.call("watcher", b"w", AdminQuery::ListUsers).await;
+      assert!(matches!(r, Ok(AdminReply::Users(users)) if users.contains_key(&client)));
+      assert_eq!(
+        api
+          .call("watcher", b"w", AdminQuery::BanClient(client))
+          .await,
+        Err(AdminError::Forbidden(AdminRole::ReadOnly))
+      );
+      assert_eq!(
+        api.call("moderator", b"w", AdminQuery::ListUsers).await,
+        Err(AdminError::Unauthenticated)
+      );
+      assert_eq!(
+        api
+          .call("moderator", b"m", AdminQuery::BanClient(client))
+          .await,
+        Ok(AdminReply::Replies(vec![ClientReply::Delivered(None)]))
+      );
+      assert_eq!(
+        api.call("moderator", b"m", AdminQuery::BanIp(ip)).await,
+        Err(AdminError::Forbidden(AdminRole::Moderator))
+      );
+      assert_eq!(
+        server
+          .register_local_client(ip, "d".into())
+          .await
+          .map(|_| ()),
+        Ok(())
+      );
+      assert_eq!(
+        api.call("nobody", b"", AdminQuery::ListUsers).await,
+        Err(AdminError::Unauthenticated)
+      );
+
+      // every query is in the audit, refused or not
+      let event = |admin: &str, query, outcome| AdminEvent {
+        timestamp: 7,
+        admin: admin.to_string(),
+        query,
+        outcome,
+      };
+      let forbidden = |role| Err(AdminError::Forbidden(role));
+      let expected = [
+        event("watcher", AdminQuery::ListUsers, Ok(())),
+        event(
+          "watcher",
+          AdminQuery::BanClient(client),
+          forbidden(AdminRole::ReadOnly),
+        ),
+        event(
+          "moderator",
+          AdminQuery::ListUsers,
+          Err(AdminError::Unauthenticated),
+        ),
+        event("moderator", AdminQuery::BanClient(client), Ok(())),
+        event(
+          "moderator",
+          AdminQuery::BanIp(ip),
+          forbidden(AdminRole::Moderator),
+        ),
+        event(
+          "nobody",
+          AdminQuery::ListUsers,
+          Err(AdminError::Unauthenticated),
+        ),
+      ];
+      for expected in expected {
+        assert_eq!(events.try_recv(), Ok(expected));
+      }
+      assert!(events.try_recv().is_err());
+      let r = server
+        .handle_client_message(
+          client,
+          ClientMessage::Text {
+            dest: client,
+            content: "hi".into(),
+          },
+        )
+        .await;
+      assert_eq!(r, [ClientReply::Error(ClientError::Banned)]);
+    });
+  }
+}
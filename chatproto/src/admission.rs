@@ -0,0 +1,205 @@
+//! admission control for expensive operations, such as registrations
+//!
+//! At most `concurrency` operations run at the same time, at most `queue` more wait for their
+//! turn, and everything beyond that is rejected right away with `ClientError::ServerBusy`. Waiting
+//! operations get the slots in their order of arrival, newcomers do not jump the queue.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use futures::channel::oneshot;
+
+use crate::messages::ClientError;
+
+/// snapshot of the queue counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AdmissionStats {
+  /// operations waiting for a slot
+  pub waiting: usize,
+  /// operations currently running
+  pub in_flight: usize,
+  pub admitted: u64,
+  pub rejected: u64,
+}
+
+pub struct AdmissionQueue {
+  queue: usize,
+  slots: Mutex<Slots>,
+  in_flight: AtomicUsize,
+  admitted: AtomicU64,
+  rejected: AtomicU64,
+}
+
+// the free slots, and the operations waiting for one in their order of arrival
+struct Slots {
+  free: usize,
+  waiters: VecDeque<oneshot::Sender<()>>,
+}
+
+/// a running slot, released when dropped
+pub struct Permit<'a> {
+  owner: &'a AdmissionQueue,
+}
+
+// an operation waiting for its slot, that hands it over if it is dropped once given one
+struct Waiting<'a> {
+  owner: &'a AdmissionQueue,
+  slot: oneshot::Receiver<()>,
+}
+
+impl AdmissionQueue {
+  pub fn new(concurrency: usize, queue: usize) -> Self {
+    AdmissionQueue {
+      queue,
+      slots: Mutex::new(Slots {
+        free: concurrency.max(1),
+        waiters: VecDeque::new(),
+      }),
+      in_flight: AtomicUsize::new(0),
+      admitted: AtomicU64::new(0),
+      rejected: AtomicU64::new(0),
+    }
+  }
+
+  /// waits for a slot, or fails immediately if too many operations are already waiting
+  pub async fn admit(&self) -> Result<Permit<'_>, ClientError> {
+    let slot = {
+      let mut slots = self.slots.lock().unwrap();
+      slots.waiters.retain(|w| !w.is_canceled());
+      // a free slot goes to the operations already waiting for one
+      if slots.free > 0 && slots.waiters.is_empty() {
+        slots.free -= 1;
+        None
+      } else if slots.waiters.len() >= self.queue {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        return Err(ClientError::ServerBusy);
+      } else {
+        let (waiter, slot) = oneshot::channel();
+        slots.waiters.push_back(waiter);
+        Some(slot)
+      }
+    };
+    if let Some(slot) = slot {
+      let mut waiting = Waiting { owner: self, slot };
+      // the sender is only dropped by `release`, after sending
+      if (&mut waiting.slot).await.is_err() {
+        return Err(ClientError::InternalError);
+      }
+    }
+    Ok(self.permit())
+  }
+
+  // hands a slot to the first operation still waiting, or frees it
+  fn release(&self) {
+    let mut slots = self.slots.lock().unwrap();
+    while let Some(waiter) = slots.waiters.pop_front() {
+      if waiter.send(()).is_ok() {
+        return;
+      }
+    }
+    slots.free += 1;
+  }
+
+  fn permit(&self) -> Permit<'_> {
+    self.in_flight.fetch_add(1, Ordering::SeqCst);
+    self.admitted.fetch_add(1, Ordering::Relaxed);
+    Permit { owner: self }
+  }
+
+  pub fn stats(&self) -> AdmissionStats {
+    AdmissionStats {
+      waiting: self.slots.lock().unwrap().waiters.len(),
+      in_flight: self.in_flight.load(Ordering::SeqCst),
+      admitted: self.admitted.load(Ordering::Relaxed),
+      rejected: self.rejected.load(Ordering::Relaxed),
+    }
+  }
+}
+
+impl Drop for Permit<'_> {
+  fn drop(&mut self) {
+    self.owner.in_flight.fetch_sub(1, Ordering::SeqCst);
+    self.owner.release();
+  }
+}
+
+impl Drop for Waiting<'_> {
+  fn drop(&mut self) {
+    self.slot.close();
+    if let Ok(Some(())) = self.slot.try_recv() {
+      self.owner.release();
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn bounded() {
+    async_std::task::block_on(async {
+      let queue = AdmissionQueue::new(2, 1);
+      let p1 = queue.admit().await.unwrap();
+      let _p2 = queue.admit().await.unwrap();
+      assert_eq!(queue.stats().in_flight, 2);
+
+      // the third one has to wait, the fourth one is turned away
+      let waiter = queue.admit();
+      futures::pin_mut!(waiter);
+      assert!(futures::poll!(waiter.as_mut()).is_pending());
+      assert_eq!(queue.stats().waiting, 1);
+      assert_eq!(queue.admit().await.err(), Some(ClientError::ServerBusy));
+
+      drop(p1);
+      let _p3 = waiter.await.unwrap();
+      assert_eq!(
+        queue.stats(),
+        AdmissionStats {
+          waiting: 0,
+          in_flight: 2,
+          admitted: 3,
+          rejected: 1
+        }
+      );
+    })
+  }
+
+  #[test]
+  fn in_order() {
+    async_std::task::block_on(async {
+      let queue = AdmissionQueue::new(1, 2);
+      let running = queue.admit().await.unwrap();
+      let first = queue.admit();
+      futures::pin_mut!(first);
+      assert!(futures::poll!(first.as_mut()).is_pending());
+
+      // the released slot is for the waiting operation, not for the one that comes next
+      drop(running);
+      let second = queue.admit();
+      futures::pin_mut!(second);
+      assert!(futures::poll!(second.as_mut()).is_pending());
+      let first = first.await.unwrap();
+      assert!(futures::poll!(second.as_mut()).is_pending());
+      drop(first);
+      second.await.unwrap();
+    })
+  }
+
+  #[test]
+  fn cancelled() {
+    async_std::task::block_on(async {
+      let queue = AdmissionQueue::new(1, 1);
+      let running = queue.admit().await.unwrap();
+      let mut waiter = Box::pin(queue.admit());
+      assert!(futures::poll!(waiter.as_mut()).is_pending());
+
+      // given the slot, but gone before taking it: the slot is not lost
+      drop(running);
+      drop(waiter);
+      let _permit = queue.admit().await.unwrap();
+      assert_eq!(queue.stats().in_flight, 1);
+    })
+  }
+}
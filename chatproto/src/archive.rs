@@ -0,0 +1,344 @@
+//! portable message archives
+//!
+//! An archive is a JSONL file: one JSON encoded `ArchiveRecord` per line. Exports write one
+//! archive per conversation, so that they can be handed over or deleted independently, and
+//! `export_history` exports the histories of a server that way.
+//!
+//! Imports only load records, they never deliver anything to mailboxes: the records go to the
+//! histories of their recipients with `AdminServer::import_history`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::{AdminServer, SpamChecker};
+use crate::messages::{
+  ClientId, ClientPollReply, ContentType, HistoryEntry, MessageId, RichContent, ServerId,
+};
+
+/// a single archived message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveRecord {
+  pub id: MessageId,
+  /// milliseconds since the unix epoch
+  pub timestamp: u64,
+  pub src: ClientId,
+  pub srcsrv: ServerId,
+  pub dst: ClientId,
+  pub content: String,
+  pub content_type: ContentType,
+}
+
+impl ArchiveRecord {
+  /// the record of an entry of the history of `dst`, that reached it at `timestamp`, none for the
+  /// entries that are not direct text messages (room messages, binary payloads, tombstones). The
+  /// history does not keep the server of rich messages, they are recorded with `srcsrv`.
+  pub fn of_entry(
+    dst: ClientId,
+    entry: &HistoryEntry,
+    timestamp: u64,
+    srcsrv: ServerId,
+  ) -> Option<Self> {
+    let (src, srcsrv, content, content_type, timestamp) = match entry.message.unthreaded() {
+      ClientPollReply::Message {
+        src,
+        srcsrv,
+        content,
+        timestamp,
+      } => (*src, *srcsrv, content, ContentType::Plain, *timestamp),
+      ClientPollReply::RichMessage { src, content } => (
+        *src,
+        srcsrv,
+        &content.text,
+        content.content_type.clone(),
+        timestamp,
+      ),
+      _ => return None,
+    };
+    Some(ArchiveRecord {
+      id: entry.id,
+      timestamp,
+      src,
+      srcsrv,
+      dst,
+      content: content.clone(),
+      content_type,
+    })
+  }
+
+  /// the history entry of the record, as its recipient would have polled it: plain text is a
+  /// message, the other content types rich messages
+  pub fn to_entry(&self) -> HistoryEntry {
+    let message = match &self.content_type {
+      ContentType::Plain => ClientPollReply::Message {
+        src: self.src,
+        srcsrv: self.srcsrv,
+        content: self.content.clone(),
+        timestamp: self.timestamp,
+      },
+      content_type => ClientPollReply::RichMessage {
+        src: self.src,
+        content: RichContent {
+          text: self.content.clone(),
+          mentions: Vec::new(),
+          content_type: content_type.clone(),
+        },
+      },
+    };
+    HistoryEntry {
+      id: self.id,
+      message,
+      reactions: Vec::new(),
+    }
+  }
+}
+
+/// identifies a conversation, for now the (unordered) pair of its participants
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Conversation(pub ClientId, pub ClientId);
+
+impl Conversation {
+  pub fn of(record: &ArchiveRecord) -> Self {
+    if record.src <= record.dst {
+      Conversation(record.src, record.dst)
+    } else {
+      Conversation(record.dst, record.src)
+    }
+  }
+
+  /// file name of the archive for this conversation
+  pub fn file_name(&self) -> String {
+    format!("{}_{}.jsonl", self.0 .0, self.1 .0)
+  }
+}
+
+/// selects the records to export, all bounds are optional
+#[derive(Clone, Debug, Default)]
+pub struct ExportFilter {
+  /// inclusive lower bound on the timestamp
+  pub since: Option<u64>,
+  /// exclusive upper bound on the timestamp
+  pub until: Option<u64>,
+  /// only keep messages sent or received by this client
+  pub participant: Option<ClientId>,
+}
+
+impl ExportFilter {
+  pub fn matches(&self, record: &ArchiveRecord) -> bool {
+    self.since.is_none_or(|since| record.timestamp >= since)
+      && self.until.is_none_or(|until| record.timestamp < until)
+      && self
+        .participant
+        .is_none_or(|p| record.src == p || record.dst == p)
+  }
+}
+
+/// writes the matching records as JSONL, returns how many were written
+pub fn export<'a, W, I>(w: &mut W, records: I, filter: &ExportFilter) -> anyhow::Result<usize>
+where
+  W: Write,
+  I: IntoIterator<Item = &'a ArchiveRecord>,
+{
+  let mut written = 0;
+  for record in records.into_iter().filter(|r| filter.matches(r)) {
+    serde_json::to_writer(&mut *w, record)?;
+    w.write_all(b"\n")?;
+    written += 1;
+  }
+  Ok(written)
+}
+
+/// writes one archive per conversation in `dir`, returns the files that were created
+/// records keep their relative order within each archive
+pub fn export_conversations<'a, I>(
+  dir: &Path,
+  records: I,
+  filter: &ExportFilter,
+) -> anyhow::Result<Vec<PathBuf>>
+where
+  I: IntoIterator<Item = &'a ArchiveRecord>,
+{
+  let mut conversations: BTreeMap<Conversation, Vec<&ArchiveRecord>> = BTreeMap::new();
+  for record in records.into_iter().filter(|r| filter.matches(r)) {
+    conversations
+      .entry(Conversation::of(record))
+      .or_default()
+      .push(record);
+  }
+
+  std::fs::create_dir_all(dir)?;
+  let mut files = Vec::new();
+  for (conversation, records) in conversations {
+    let path = dir.join(conversation.file_name());
+    let mut w = BufWriter::new(File::create(&path)?);
+    export(&mut w, records, &ExportFilter::default())?;
+    w.flush()?;
+    files.push(path);
+  }
+  Ok(files)
+}
+
+/// exports the matching messages of the histories of `srv`, one archive per conversation in
+/// `dir` (see `export_conversations`), returns the files that were created
+pub async fn export_history<S, C>(
+  srv: &S,
+  dir: &Path,
+  filter: &ExportFilter,
+) -> anyhow::Result<Vec<PathBuf>>
+where
+  S: AdminServer<C>,
+  C: SpamChecker,
+{
+  let records = srv.export_history(filter).await;
+  export_conversations(dir, &records, &ExportFilter::default())
+}
+
+/// how far in the future an imported timestamp may be, to account for clock skew
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// current time, in milliseconds since the unix epoch
+pub fn now_ms() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+/// reads and validates a JSONL archive
+///
+/// Records whose id is already in `seen` are skipped, so that importing the same archive twice
+/// is harmless, and the ids of the returned records are added to it. The whole import fails,
+/// without touching `seen`, if any line is malformed, has a nil id, or has a timestamp that is
+/// zero or in the future.
+pub fn import<R: BufRead>(
+  rd: R,
+  seen: &mut HashSet<MessageId>,
+) -> anyhow::Result<Vec<ArchiveRecord>> {
+  let latest = now_ms() + MAX_CLOCK_SKEW.as_millis() as u64;
+  let mut new_ids = HashSet::new();
+  let mut records = Vec::new();
+  for (lineno, line) in rd.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let record: ArchiveRecord = serde_json::from_str(&line)
+      .map_err(|rr| anyhow::anyhow!("line {}: malformed record: {}", lineno + 1, rr))?;
+    if record.id.0.is_nil() {
+      anyhow::bail!("line {}: nil message id", lineno + 1);
+    }
+    if record.timestamp == 0 || record.timestamp > latest {
+      anyhow::bail!(
+        "line {}: invalid timestamp {} for {}",
+        lineno + 1,
+        record.timestamp,
+        record.id
+      );
+    }
+    if seen.contains(&record.id) || !new_ids.insert(record.id) {
+      continue;
+    }
+    records.push(record);
+  }
+  seen.extend(new_ids);
+  Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn record(timestamp: u64, src: ClientId, dst: ClientId, content: &str) -> ArchiveRecord {
+    ArchiveRecord {
+      id: MessageId::default(),
+      timestamp,
+      src,
+      srcsrv: ServerId::from(1),
+      dst,
+      content: content.to_string(),
+      content_type: ContentType::Plain,
+    }
+  }
+
+  #[test]
+  fn filter() {
+    let (a, b, c) = (ClientId::from(1), ClientId::from(2), ClientId::from(3));
+    let records = vec![
+      record(10, a, b, "early"),
+      record(20, b, a, "reply"),
+      record(30, a, c, "other"),
+      record(40, c, b, "late"),
+    ];
+    let filter = ExportFilter {
+      since: Some(20),
+      until: Some(40),
+      participant: Some(a),
+    };
+    let mut out = Vec::new();
+    assert_eq!(export(&mut out, &records, &filter).unwrap(), 2);
+    let lines = String::from_utf8(out).unwrap();
+    let decoded = lines
+      .lines()
+      .map(|l| serde_json::from_str::<ArchiveRecord>(l).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(decoded, [records[1].clone(), records[2].clone()]);
+  }
+
+  #[test]
+  fn per_conversation() {
+    let (a, b, c) = (ClientId::from(1), ClientId::from(2), ClientId::from(3));
+    let records = vec![
+      record(10, a, b, "1"),
+      record(20, c, a, "2"),
+      record(30, b, a, "3"),
+    ];
+    let dir = std::env::temp_dir().join(format!("chat-archive-{}", MessageId::default().0));
+    let files = export_conversations(&dir, &records, &ExportFilter::default()).unwrap();
+    assert_eq!(files.len(), 2);
+    let ab = std::fs::read_to_string(dir.join(Conversation(a, b).file_name())).unwrap();
+    assert_eq!(ab.lines().count(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn import_round_trip() {
+    let (a, b) = (ClientId::from(1), ClientId::from(2));
+    let records = vec![record(10, a, b, "1"), record(20, b, a, "2")];
+    let mut out = Vec::new();
+    export(&mut out, &records, &ExportFilter::default()).unwrap();
+
+    let mut seen = HashSet::new();
+    let imported = import(out.as_slice(), &mut seen).unwrap();
+    assert_eq!(imported, records);
+    // importing twice does not duplicate anything
+    assert!(import(out.as_slice(), &mut seen).unwrap().is_empty());
+  }
+
+  #[test]
+  fn import_invalid() {
+    let (a, b) = (ClientId::from(1), ClientId::from(2));
+    let mut future = record(now_ms() + 3_600_000, a, b, "from the future");
+    let mut out = Vec::new();
+    export(
+      &mut out,
+      [&record(10, a, b, "ok"), &future],
+      &ExportFilter::default(),
+    )
+    .unwrap();
+    let mut seen = HashSet::new();
+    assert!(import(out.as_slice(), &mut seen).is_err());
+    assert!(seen.is_empty());
+
+    future.timestamp = 10;
+    future.id = MessageId::from(0);
+    let mut out = Vec::new();
+    export(&mut out, [&future], &ExportFilter::default()).unwrap();
+    assert!(import(out.as_slice(), &mut seen).is_err());
+
+    assert!(import("not json\n".as_bytes(), &mut seen).is_err());
+  }
+}
@@ -0,0 +1,66 @@
+//! what the audit trails of the crate share
+//!
+//! An `EventLog` keeps the latest events for their reader, and drops the oldest ones when nobody
+//! reads them. `same` compares the secrets that the audited operations check (tokens, responses,
+//! signatures) without leaking where they differ.
+
+use async_std::channel::{bounded, Receiver, Sender};
+
+/// The latest `capacity` events, for the readers of `events`.
+pub struct EventLog<T> {
+  sender: Sender<T>,
+  // kept to drop the oldest events when nobody reads them
+  receiver: Receiver<T>,
+}
+
+impl<T> EventLog<T> {
+  pub fn new(capacity: usize) -> Self {
+    let (sender, receiver) = bounded(capacity.max(1));
+    EventLog { sender, receiver }
+  }
+
+  /// the events, as they happen
+  pub fn events(&self) -> Receiver<T> {
+    self.receiver.clone()
+  }
+
+  /// logs `event`, in place of the oldest one when the log is full
+  pub fn emit(&self, event: T) {
+    if self.sender.is_full() {
+      let _ = self.receiver.try_recv();
+    }
+    let _ = self.sender.try_send(event);
+  }
+}
+
+/// compares without leaking where the first difference is, only the lengths
+pub fn same(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn event_log() {
+    let log = EventLog::new(2);
+    let events = log.events();
+    for event in 0..3 {
+      log.emit(event);
+    }
+    assert_eq!(events.try_recv(), Ok(1));
+    log.emit(3);
+    assert_eq!(events.try_recv(), Ok(2));
+    assert_eq!(events.try_recv(), Ok(3));
+    assert!(events.try_recv().is_err());
+  }
+
+  #[test]
+  fn compare() {
+    assert!(same(b"secret", b"secret"));
+    assert!(!same(b"secret", b"secreT"));
+    assert!(!same(b"secret", b"secrets"));
+    assert!(same(b"", b""));
+  }
+}
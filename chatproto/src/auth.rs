@@ -0,0 +1,615 @@
+//! challenge-response authentication of clients, bound to their connection
+//!
+//! The client says `Hello` with its id and a nonce, the server answers with a nonce of its own,
+//! and the client proves it knows its shared secret with an `Auth` response: the first 16 bytes
+//! of the SHA-256 of the secret, the client id and both nonces.
+//!
+//! When the transport gives the handshake a channel, the server sends a `BoundNonce` instead, and
+//! the response also covers the channel. The channel is what the transport knows of the
+//! connection: a TLS exporter, or a nonce the server drew for the connection. A response captured
+//! on one connection then does not verify on any other. A client that reads the same TLS exporter
+//! checks that the channel of the challenge is its own, which also catches a relay in the middle.
+//!
+//! A handshake `guarded` by an `AuthThrottle` counts the failed responses per address and per
+//! client id. Past `AuthPolicy::free_failures`, the address or the client is locked out for a
+//! time that doubles with each failure, and its `Hello`s are refused with `LockedOut` without a
+//! challenge. The throttle reports the failures, lockouts and refusals as `AuthEvent`s.
+//!
+//! The secrets are looked up in a `CredentialStore` (see `credentials`) by `receive_from`, or by
+//! any function given to `receive`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::channel::Receiver;
+use crypto_hash::{digest, Algorithm};
+
+use crate::audit::{same, EventLog};
+use crate::clock::SharedClock;
+use crate::credentials::CredentialStore;
+use crate::messages::{AuthMessage, ClientId, ServerId};
+use crate::rng::Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthError {
+  /// a message the handshake did not expect at this step
+  Unexpected,
+  /// the client has no secret on this server
+  UnknownClient,
+  /// the response does not match the secret, nonces and channel
+  BadResponse,
+  /// the challenge is not bound to the channel the client is on
+  ChannelMismatch,
+  /// too many failures from this address or for this client, try again later
+  LockedOut,
+}
+
+impl std::fmt::Display for AuthError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AuthError::Unexpected => "Unexpected auth message".fmt(f),
+      AuthError::UnknownClient => "Unknown client".fmt(f),
+      AuthError::BadResponse => "Bad auth response".fmt(f),
+      AuthError::ChannelMismatch => "Challenge bound to another channel".fmt(f),
+      AuthError::LockedOut => "Locked out after failed attempts".fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for AuthError {}
+
+/// how failed attempts are throttled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuthPolicy {
+  /// failures allowed before the first lockout
+  pub free_failures: u32,
+  /// the first lockout, doubled by each further failure
+  pub lockout: Duration,
+  /// the longest lockout, and how long failures are remembered once it is over
+  pub max_lockout: Duration,
+}
+
+impl Default for AuthPolicy {
+  fn default() -> Self {
+    AuthPolicy {
+      free_failures: 3,
+      lockout: Duration::from_secs(1),
+      max_lockout: Duration::from_secs(15 * 60),
+    }
+  }
+}
+
+/// what failures are counted against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Attempter {
+  Address(IpAddr),
+  Client(ClientId),
+}
+
+/// audit trail of the throttled handshakes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthEvent {
+  /// a response did not verify, or the client has no secret
+  Failed { peer: IpAddr, user: ClientId },
+  /// an address or a client is locked out for `duration`
+  LockedOut {
+    attempter: Attempter,
+    failures: u32,
+    duration: Duration,
+  },
+  /// a `Hello` came in while locked out
+  Refused { peer: IpAddr, user: ClientId },
+  /// a client proved who it is, which clears its failures
+  Succeeded { peer: IpAddr, user: ClientId },
+}
+
+/// events kept for the operators, the oldest ones are dropped above
+pub const AUTH_EVENTS: usize = 1024;
+
+struct Failures {
+  count: u32,
+  last: Instant,
+  locked_until: Option<Instant>,
+}
+
+/// Failed attempts per address and per client, shared by the handshakes of a server.
+pub struct AuthThrottle {
+  policy: AuthPolicy,
+  clock: SharedClock,
+  failures: Mutex<HashMap<Attempter, Failures>>,
+  events: EventLog<AuthEvent>,
+}
+
+impl AuthThrottle {
+  pub fn new(policy: AuthPolicy, clock: SharedClock) -> Self {
+    AuthThrottle {
+      policy,
+      clock,
+      failures: Mutex::default(),
+      events: EventLog::new(AUTH_EVENTS),
+    }
+  }
+
+  /// the audit events, as they happen
+  pub fn events(&self) -> Receiver<AuthEvent> {
+    self.events.events()
+  }
+
+  /// until when `peer` or `user` is locked out, if it is
+  pub fn locked_until(&self, peer: IpAddr, user: ClientId) -> Option<Instant> {
+    let now = self.clock.now();
+    let failures = self.failures.lock().unwrap();
+    [Attempter::Address(peer), Attempter::Client(user)]
+      .iter()
+      .filter_map(|a| failures.get(a)?.locked_until)
+      .filter(|until| *until > now)
+      .max()
+  }
+
+  fn check(&self, peer: IpAddr, user: ClientId) -> Result<(), AuthError> {
+    match self.locked_until(peer, user) {
+      None => Ok(()),
+      Some(_) => {
+        self.events.emit(AuthEvent::Refused { peer, user });
+        Err(AuthError::LockedOut)
+      }
+    }
+  }
+
+  fn failed(&self, peer: IpAddr, user: ClientId) {
+    let now = self.clock.now();
+    let policy = self.policy;
+    let mut locked = Vec::new();
+    {
+      let mut failures = self.failures.lock().unwrap();
+      // forgotten once quiet for `max_lockout` after the last failure, or the end of its lockout
+      failures.retain(|_, f| {
+        now.saturating_duration_since(f.locked_until.unwrap_or(f.last)) < policy.max_lockout
+      });
+      for attempter in [Attempter::Address(peer), Attempter::Client(user)] {
+        let f = failures.entry(attempter).or_insert(Failures {
+          count: 0,
+          last: now,
+          locked_until: None,
+        });
+        f.count += 1;
+        f.last = now;
+        if let Some(over) = f.count.checked_sub(policy.free_failures + 1) {
+          let duration = policy
+            .lockout
+            .saturating_mul(1 << over.min(31))
+            .min(policy.max_lockout);
+          f.locked_until = Some(now + duration);
+          locked.push(AuthEvent::LockedOut {
+            attempter,
+            failures: f.count,
+            duration,
+          });
+        }
+      }
+    }
+    self.events.emit(AuthEvent::Failed { peer, user });
+    for event in locked {
+      self.events.emit(event);
+    }
+  }
+
+  // the address keeps its failures, other clients may have been guessed from it
+  fn succeeded(&self, peer: IpAddr, user: ClientId) {
+    self
+      .failures
+      .lock()
+      .unwrap()
+      .remove(&Attempter::Client(user));
+    self.events.emit(AuthEvent::Succeeded { peer, user });
+  }
+}
+
+/// a fresh channel for a connection whose transport has nothing better
+pub fn connection_nonce(rng: &dyn Rng) -> [u8; 16] {
+  rng.u128().to_le_bytes()
+}
+
+/// the response proving the knowledge of `secret`
+pub fn response(
+  secret: &[u8],
+  user: &ClientId,
+  client_nonce: &[u8; 8],
+  server_nonce: &[u8; 8],
+  channel: Option<&[u8; 16]>,
+) -> [u8; 16] {
+  let mut data = Vec::with_capacity(secret.len() + 48);
+  data.extend_from_slice(secret);
+  data.extend_from_slice(user.0.as_bytes());
+  data.extend_from_slice(client_nonce);
+  data.extend_from_slice(server_nonce);
+  if let Some(channel) = channel {
+    data.extend_from_slice(channel);
+  }
+  let mut out = [0u8; 16];
+  out.copy_from_slice(&digest(Algorithm::SHA256, &data)[..16]);
+  out
+}
+
+enum ServerState {
+  Start,
+  Challenged {
+    user: ClientId,
+    client_nonce: [u8; 8],
+    server_nonce: [u8; 8],
+  },
+  Done(ClientId),
+  Failed,
+}
+
+/// the server side of a handshake, on one connection
+pub struct ServerHandshake {
+  server: ServerId,
+  channel: Option<[u8; 16]>,
+  state: ServerState,
+  guard: Option<(Arc<AuthThrottle>, IpAddr)>,
+}
+
+impl ServerHandshake {
+  /// a handshake bound to `channel`, or unbound without one
+  pub fn new(server: ServerId, channel: Option<[u8; 16]>) -> Self {
+    ServerHandshake {
+      server,
+      channel,
+      state: ServerState::Start,
+      guard: None,
+    }
+  }
+
+  /// counts the failures of `peer` in `throttle`, and refuses it while it is locked out
+  pub fn guarded(mut self, throttle: Arc<AuthThrottle>, peer: IpAddr) -> Self {
+    self.guard = Some((throttle, peer));
+    self
+  }
+
+  /// Handles a message of the client, and returns the one to answer with, if any.
+  /// `secret` looks up the shared secret of a client. Any error ends the handshake.
+  pub fn receive<F>(
+    &mut self,
+    msg: &AuthMessage,
+    rng: &dyn Rng,
+    secret: F,
+  ) -> Result<Option<AuthMessage>, AuthError>
+  where
+    F: FnOnce(&ClientId) -> Option<Vec<u8>>,
+  {
+    let state = std::mem::replace(&mut self.state, ServerState::Failed);
+    match (state, msg) {
+      (ServerState::Start, AuthMessage::Hello { user, nonce }) => {
+        if let Some((throttle, peer)) = &self.guard {
+          throttle.check(*peer, *user)?;
+        }
+        let mut server_nonce = [0u8; 8];
+        rng.fill(&mut server_nonce);
+        self.state = ServerState::Challenged {
+          user: *user,
+          client_nonce: *nonce,
+          server_nonce,
+        };
+        Ok(Some(match self.channel {
+          None => AuthMessage::Nonce {
+            server: self.server,
+            nonce: server_nonce,
+          },
+          Some(channel) => AuthMessage::BoundNonce {
+            server: self.server,
+            nonce: server_nonce,
+            channel,
+          },
+        }))
+      }
+      (
+        ServerState::Challenged {
+          user,
+          client_nonce,
+          server_nonce,
+        },
+        AuthMessage::Auth { response: got },
+      ) => {
+        let verified = match secret(&user) {
+          None => Err(AuthError::UnknownClient),
+          Some(secret) => {
+            let expected = response(
+              &secret,
+              &user,
+              &client_nonce,
+              &server_nonce,
+              self.channel.as_ref(),
+            );
+            if same(&expected, got) {
+              Ok(())
+            } else {
+              Err(AuthError::BadResponse)
+            }
+          }
+        };
+        if let Some((throttle, peer)) = &self.guard {
+          match verified {
+            Ok(()) => throttle.succeeded(*peer, user),
+            Err(_) => throttle.failed(*peer, user),
+          }
+        }
+        verified?;
+        self.state = ServerState::Done(user);
+        Ok(None)
+      }
+      _ => Err(AuthError::Unexpected),
+    }
+  }
+
+  /// `receive`, with the secrets of `store`, a client without one is unknown
+  pub fn receive_from(
+    &mut self,
+    msg: &AuthMessage,
+    rng: &dyn Rng,
+    store: &dyn CredentialStore,
+  ) -> Result<Option<AuthMessage>, AuthError> {
+    self.receive(msg, rng, |user| {
+      store
+        .credential(user)
+        .and_then(|c| c.secret().map(<[u8]>::to_vec))
+    })
+  }
+
+  /// the client, once it proved who it is
+  pub fn authenticated(&self) -> Option<ClientId> {
+    match self.state {
+      ServerState::Done(user) => Some(user),
+      _ => None,
+    }
+  }
+}
+
+/// the client side of a handshake
+pub struct ClientHandshake {
+  user: ClientId,
+  secret: Vec<u8>,
+  nonce: [u8; 8],
+  // the channel the client reads from its own transport, if it can
+  channel: Option<[u8; 16]>,
+}
+
+impl ClientHandshake {
+  pub fn new(user: ClientId, secret: Vec<u8>, rng: &dyn Rng) -> Self {
+    let mut nonce = [0u8; 8];
+    rng.fill(&mut nonce);
+    ClientHandshake {
+      user,
+      secret,
+      nonce,
+      channel: None,
+    }
+  }
+
+  /// requires the challenge to be bound to `channel`, such as the TLS exporter of the connection
+  pub fn expect_channel(mut self, channel: [u8; 16]) -> Self {
+    self.channel = Some(channel);
+    self
+  }
+
+  pub fn hello(&self) -> AuthMessage {
+    AuthMessage::Hello {
+      user: self.user,
+      nonce: self.nonce,
+    }
+  }
+
+  /// the response to the challenge of the server
+  pub fn respond(&self, challenge: &AuthMessage) -> Result<AuthMessage, AuthError> {
+    let (nonce, channel) = match challenge {
+      AuthMessage::Nonce { nonce, .. } => (nonce, None),
+      AuthMessage::BoundNonce { nonce, channel, .. } => (nonce, Some(channel)),
+      _ => return Err(AuthError::Unexpected),
+    };
+    if self.channel.is_some() && self.channel.as_ref() != channel {
+      return Err(AuthError::ChannelMismatch);
+    }
+    Ok(AuthMessage::Auth {
+      response: response(&self.secret, &self.user, &self.nonce, nonce, channel),
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::clock::{Clock, ManualClock};
+  use crate::credentials::{Credential, MemoryCredentials};
+  use crate::rng::SeededRng;
+
+  fn handshake(
+    server: &mut ServerHandshake,
+    client: &ClientHandshake,
+    rng: &dyn Rng,
+  ) -> Result<AuthMessage, AuthError> {
+    let challenge = server
+      .receive(&client.hello(), rng, |_| None)?
+      .expect("a challenge");
+    client.respond(&challenge)
+  }
+
+  #[test]
+  fn bound_to_the_channel() {
+    let rng = SeededRng::new(7);
+    let user = ClientId::default();
+    let server = ServerId::default();
+    let secrets = |c: &ClientId| (*c == user).then(|| b"secret".to_vec());
+    let client = ClientHandshake::new(user, b"secret".to_vec(), &rng);
+
+    let channel = connection_nonce(&rng);
+    let mut first = ServerHandshake::new(server, Some(channel));
+    let auth = handshake(&mut first, &client, &SeededRng::new(1)).unwrap();
+    assert_eq!(first.receive(&auth, &rng, secrets), Ok(None));
+    assert_eq!(first.authenticated(), Some(user));
+
+    // the response does not verify on another connection, even with the same nonces
+    let mut replayed = ServerHandshake::new(server, Some(connection_nonce(&rng)));
+    handshake(&mut replayed, &client, &SeededRng::new(1)).unwrap();
+    assert_eq!(
+      replayed.receive(&auth, &rng, secrets),
+      Err(AuthError::BadResponse)
+    );
+    assert_eq!(replayed.authenticated(), None);
+
+    // nor on an unbound one
+    let mut unbound = ServerHandshake::new(server, None);
+    let unbound_auth = handshake(&mut unbound, &client, &SeededRng::new(1)).unwrap();
+    assert_ne!(unbound_auth, auth);
+    assert_eq!(
+      unbound.receive(&auth, &rng, secrets),
+      Err(AuthError::BadResponse)
+    );
+  }
+
+  #[test]
+  fn client_checks_the_channel() {
+    let rng = SeededRng::new(3);
+    let client =
+      ClientHandshake::new(ClientId::default(), b"secret".to_vec(), &rng).expect_channel([1; 16]);
+    let mut relayed = ServerHandshake::new(ServerId::default(), Some([2; 16]));
+    assert_eq!(
+      handshake(&mut relayed, &client, &rng),
+      Err(AuthError::ChannelMismatch)
+    );
+    let mut unbound = ServerHandshake::new(ServerId::default(), None);
+    assert_eq!(
+      handshake(&mut unbound, &client, &rng),
+      Err(AuthError::ChannelMismatch)
+    );
+    let mut same = ServerHandshake::new(ServerId::default(), Some([1; 16]));
+    assert!(handshake(&mut same, &client, &rng).is_ok());
+  }
+
+  #[test]
+  fn failures_end_the_handshake() {
+    let rng = SeededRng::new(5);
+    let user = ClientId::default();
+    let client = ClientHandshake::new(user, b"secret".to_vec(), &rng);
+    let mut server = ServerHandshake::new(ServerId::default(), Some([0; 16]));
+    let unexpected = AuthMessage::Auth { response: [0; 16] };
+    assert_eq!(
+      server.receive(&unexpected, &rng, |_| None),
+      Err(AuthError::Unexpected)
+    );
+    assert_eq!(
+      server.receive(&client.hello(), &rng, |_| None),
+      Err(AuthError::Unexpected)
+    );
+
+    let mut server = ServerHandshake::new(ServerId::default(), Some([0; 16]));
+    let auth = handshake(&mut server, &client, &rng).unwrap();
+    assert_eq!(
+      server.receive(&auth, &rng, |_| None),
+      Err(AuthError::UnknownClient)
+    );
+  }
+
+  #[test]
+  fn secrets_from_a_store() {
+    let rng = SeededRng::new(9);
+    let (alice, bob) = (ClientId::from(1), ClientId::from(2));
+    let store = MemoryCredentials::new();
+    store.insert(alice, Credential::Secret(b"secret".to_vec()));
+    // a key can't answer the challenge
+    store.insert(bob, Credential::PublicKey(b"secret".to_vec()));
+    for (user, expected) in [(alice, Ok(None)), (bob, Err(AuthError::UnknownClient))] {
+      let client = ClientHandshake::new(user, b"secret".to_vec(), &rng);
+      let mut server = ServerHandshake::new(ServerId::default(), None);
+      let auth = handshake(&mut server, &client, &rng).unwrap();
+      assert_eq!(server.receive_from(&auth, &rng, &store), expected);
+    }
+    store.remove(&alice);
+    let client = ClientHandshake::new(alice, b"secret".to_vec(), &rng);
+    let mut server = ServerHandshake::new(ServerId::default(), None);
+    let auth = handshake(&mut server, &client, &rng).unwrap();
+    assert_eq!(
+      server.receive_from(&auth, &rng, &store),
+      Err(AuthError::UnknownClient)
+    );
+  }
+
+  #[test]
+  fn exponential_lockout() {
+    let clock = ManualClock::new(0);
+    let policy = AuthPolicy {
+      free_failures: 2,
+      lockout: Duration::from_secs(1),
+      max_lockout: Duration::from_secs(3),
+    };
+    let throttle = Arc::new(AuthThrottle::new(policy, Arc::new(clock.clone())));
+    let events = throttle.events();
+    let rng = SeededRng::new(11);
+    let peer: IpAddr = "10.0.0.1".parse().unwrap();
+    let user = ClientId::default();
+    let secrets = |_: &ClientId| Some(b"secret".to_vec());
+    let attempt = |secret: &[u8]| {
+      let client = ClientHandshake::new(user, secret.to_vec(), &rng);
+      let mut server =
+        ServerHandshake::new(ServerId::default(), None).guarded(throttle.clone(), peer);
+      let auth = handshake(&mut server, &client, &rng)?;
+      server.receive(&auth, &rng, secrets)
+    };
+
+    for _ in 0..2 {
+      assert_eq!(attempt(b"guess"), Err(AuthError::BadResponse));
+      assert_eq!(events.try_recv(), Ok(AuthEvent::Failed { peer, user }));
+    }
+    assert_eq!(throttle.locked_until(peer, user), None);
+    // the third failure locks out for 1s, the fourth for 2s, then it is capped
+    for (failures, lockout) in [(3, 1), (4, 2), (5, 3), (6, 3)] {
+      assert_eq!(attempt(b"guess"), Err(AuthError::BadResponse));
+      let duration = Duration::from_secs(lockout);
+      assert_eq!(
+        throttle.locked_until(peer, user),
+        Some(clock.now() + duration)
+      );
+      // even the right secret is refused meanwhile
+      assert_eq!(attempt(b"secret"), Err(AuthError::LockedOut));
+      clock.advance(duration);
+      let locked = |attempter| AuthEvent::LockedOut {
+        attempter,
+        failures,
+        duration,
+      };
+      let expected = [
+        AuthEvent::Failed { peer, user },
+        locked(Attempter::Address(peer)),
+        locked(Attempter::Client(user)),
+        AuthEvent::Refused { peer, user },
+      ];
+      for event in expected {
+        assert_eq!(events.try_recv(), Ok(event));
+      }
+    }
+    assert!(events.try_recv().is_err());
+
+    // a success clears the client, but not the address
+    assert_eq!(attempt(b"secret"), Ok(None));
+    assert_eq!(events.try_recv(), Ok(AuthEvent::Succeeded { peer, user }));
+    let other = ClientId::from(1);
+    let client = ClientHandshake::new(other, b"guess".to_vec(), &rng);
+    let mut server =
+      ServerHandshake::new(ServerId::default(), None).guarded(throttle.clone(), peer);
+    let auth = handshake(&mut server, &client, &rng).unwrap();
+    assert_eq!(
+      server.receive(&auth, &rng, secrets),
+      Err(AuthError::BadResponse)
+    );
+    assert!(throttle.locked_until(peer, other).is_some());
+    assert!(throttle
+      .locked_until("10.0.0.2".parse().unwrap(), user)
+      .is_none());
+
+    // old failures are forgotten
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(attempt(b"guess"), Err(AuthError::BadResponse));
+    assert_eq!(throttle.locked_until(peer, other), None);
+  }
+}
@@ -0,0 +1,45 @@
+//! what registered clients are allowed to do
+
+/// how a client registered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Tier {
+  /// registered without spam checks, read-only
+  Guest,
+  /// registered after passing the spam checks
+  #[default]
+  Member,
+  /// set by the server operators
+  Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+  Send,
+  Poll,
+  ListUsers,
+  Report,
+  /// sending to every client, local and remote
+  Broadcast,
+  /// registering a client with a chosen id, without spam checks
+  RegisterTrusted,
+}
+
+/// Decides which actions each tier may perform.
+pub trait Authorizer {
+  fn allows(&self, tier: Tier, action: Action) -> bool;
+}
+
+/// Admins can do everything, members everything but broadcasts and trusted registrations, and
+/// guests can only read.
+#[derive(Clone, Copy, Default)]
+pub struct DefaultAuthorizer {}
+
+impl Authorizer for DefaultAuthorizer {
+  fn allows(&self, tier: Tier, action: Action) -> bool {
+    match tier {
+      Tier::Admin => true,
+      Tier::Member => !matches!(action, Action::Broadcast | Action::RegisterTrusted),
+      Tier::Guest => matches!(action, Action::Poll | Action::ListUsers),
+    }
+  }
+}
@@ -1,4 +1,23 @@
-use crate::messages::{ClientId, Sequence};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use async_std::channel::Receiver;
+use async_std::future::timeout;
+use async_std::net::UdpSocket;
+use async_std::task::sleep;
+use serde::de::DeserializeOwned;
+
+use crate::audit::EventLog;
+use crate::messages::{
+  ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Codec,
+  NotificationPrefs, ReportTarget, RoomId, SearchPage, SearchQuery, Sequence, SyncCursor,
+  SyncReply, TransportError, UserEntry, UserPage, UserQuery,
+};
+use crate::netproto::{codec, decode, encode};
+use crate::rng::{os_rng, SharedRng};
+use crate::store::LocalStore;
 
 #[derive(Debug, Default)]
 pub struct Client {
@@ -10,6 +29,9 @@ impl Client {
   pub fn new(id: ClientId) -> Self {
     Client { id, curid: 0 }
   }
+  pub fn id(&self) -> ClientId {
+    self.id
+  }
   pub fn sequence<A>(&mut self, content: A) -> Sequence<A> {
     self.curid += 1;
     Sequence {
@@ -18,4 +40,1068 @@ impl Client {
       content,
     }
   }
+  /// skips the sequence numbers the server no longer accepts, so that the next one is at least
+  /// `expected`, see `TransportError::SequenceReuse`
+  pub fn resync(&mut self, expected: u128) {
+    self.curid = self.curid.max(expected.saturating_sub(1));
+  }
+}
+
+/// how a `ChatClient` watches its server, see `ChatClient::supervise`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Supervision {
+  /// the server is pinged when nothing was heard from it for this long
+  pub keepalive: Duration,
+  /// how long the pings, and the queries that resume the session, wait for their reply
+  pub timeout: Duration,
+  /// delay before the second attempt to reconnect, doubled after each failure
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for Supervision {
+  fn default() -> Self {
+    Supervision {
+      keepalive: Duration::from_secs(30),
+      timeout: Duration::from_secs(5),
+      initial_backoff: Duration::from_millis(500),
+      max_backoff: Duration::from_secs(60),
+    }
+  }
+}
+
+impl Supervision {
+  /// the delay after the failed `attempt` (from 1), between half and all of the exponential
+  /// backoff, depending on `unit` (in [0, 1))
+  pub fn backoff(&self, attempt: u32, unit: f64) -> Duration {
+    let factor = 1u32
+      .checked_shl(attempt.saturating_sub(1))
+      .unwrap_or(u32::MAX);
+    let backoff = self
+      .initial_backoff
+      .saturating_mul(factor)
+      .min(self.max_backoff);
+    backoff.mul_f64(0.5 + unit.clamp(0.0, 1.0) / 2.0)
+  }
+}
+
+/// what happens to the connection of a `ChatClient`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+  /// the server can't be reached anymore
+  Lost,
+  /// the attempt to reach the server failed, the next one is in `retry_in`
+  Retrying { attempt: u32, retry_in: Duration },
+  /// the server answers again, and still knows us
+  Resumed,
+  /// the server answers again, but forgot us: we registered again under this identity
+  Reregistered(ClientId),
+  /// the queued messages were sent, once reconnected
+  Flushed(usize),
+}
+
+/// events kept for the application, the oldest ones are dropped above
+pub const CONNECTION_EVENTS: usize = 64;
+
+/// A registered connection to a chat server.
+///
+/// Every query is sent as a single sequenced datagram, and the matching reply is read back
+/// before returning, so a `ChatClient` must not be shared between concurrent tasks.
+/// When the server could not handle a query, the error is a `TransportError`.
+///
+/// The polled messages and the listed users are cached in a `LocalStore`, and `send_or_queue`
+/// keeps the messages that could not reach the server for `flush`. `supervise` reconnects, and
+/// tells the application about it through `connection_events`.
+///
+/// Queries are binary until `negotiate_codec` picks another codec with the server.
+pub struct ChatClient {
+  socket: UdpSocket,
+  client: Client,
+  codec: Codec,
+  rng: SharedRng,
+  store: LocalStore,
+  timeout: Option<Duration>,
+  // to register again when the server forgot us
+  registration: ClientQuery,
+  supervision: Supervision,
+  connected: bool,
+  last_contact: Instant,
+  events: EventLog<ConnectionEvent>,
+}
+
+impl ChatClient {
+  /// connects to the server at `target` and registers under the given screen name
+  pub async fn connect(target: SocketAddr, name: String) -> anyhow::Result<Self> {
+    Self::register(target, ClientQuery::Register(name)).await
+  }
+
+  /// connects as a read-only guest, see `upgrade`
+  pub async fn connect_guest(target: SocketAddr, name: String) -> anyhow::Result<Self> {
+    Self::register(target, ClientQuery::RegisterGuest(name)).await
+  }
+
+  /// connects to a server that only registers invited clients
+  pub async fn connect_invited(
+    target: SocketAddr,
+    name: String,
+    invite: Vec<u8>,
+  ) -> anyhow::Result<Self> {
+    Self::register(target, ClientQuery::RegisterInvited { name, invite }).await
+  }
+
+  async fn register(target: SocketAddr, query: ClientQuery) -> anyhow::Result<Self> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(target).await?;
+
+    // the server does not know us yet, so the registration uses a throwaway identity
+    let register = Sequence {
+      seqid: 0,
+      src: ClientId::default(),
+      content: query,
+    };
+    send_query(&socket, Codec::Binary, &register).await?;
+    let id = recv_reply(&socket, Codec::Binary, None, decode::clientid).await?;
+    Ok(ChatClient {
+      socket,
+      client: Client::new(id),
+      codec: Codec::Binary,
+      rng: os_rng(),
+      store: LocalStore::in_memory(),
+      timeout: None,
+      registration: register.content,
+      supervision: Supervision::default(),
+      connected: true,
+      last_contact: Instant::now(),
+      events: EventLog::new(CONNECTION_EVENTS),
+    })
+  }
+
+  /// replaces the randomness of the nonces, the OS generator by default
+  pub fn with_rng(mut self, rng: SharedRng) -> Self {
+    self.rng = rng;
+    self
+  }
+
+  /// replaces the store, an in-memory one by default
+  pub fn with_store(mut self, store: LocalStore) -> Self {
+    self.store = store;
+    self
+  }
+
+  /// gives up on the replies that take longer than `timeout` (on top of the wait of the waiting
+  /// polls), by default replies are awaited forever
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  pub fn with_supervision(mut self, supervision: Supervision) -> Self {
+    self.supervision = supervision;
+    self
+  }
+
+  pub fn store(&self) -> &LocalStore {
+    &self.store
+  }
+
+  /// the changes of the connection, for a single listener
+  pub fn connection_events(&self) -> Receiver<ConnectionEvent> {
+    self.events.events()
+  }
+
+  /// false from the moment the server could not be reached, until `supervise` reconnects
+  pub fn is_connected(&self) -> bool {
+    self.connected
+  }
+
+  /// checks the connection, to be called regularly: pings the server when nothing was heard from
+  /// it for a while, and when it can't be reached, tries again (with a jittered exponential
+  /// backoff) until it can. Once reconnected, the session is resumed if the server still knows
+  /// us, or a new one is registered under the same name, and the queued messages are flushed.
+  pub async fn supervise(&mut self) -> anyhow::Result<()> {
+    if self.connected {
+      if self.last_contact.elapsed() < self.supervision.keepalive {
+        return Ok(());
+      }
+      match self.ping_within(Some(self.supervision.timeout)).await {
+        Err(rr) if unreachable(&rr) => (),
+        r => return r.map(|_| ()),
+      }
+    }
+    self.reconnect().await
+  }
+
+  async fn reconnect(&mut self) -> anyhow::Result<()> {
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      match self.ping_within(Some(self.supervision.timeout)).await {
+        Ok(_) => break,
+        Err(rr) if unreachable(&rr) => (),
+        Err(rr) => return Err(rr),
+      }
+      let retry_in = self.supervision.backoff(attempt, self.rng.unit());
+      self
+        .events
+        .emit(ConnectionEvent::Retrying { attempt, retry_in });
+      sleep(retry_in).await;
+    }
+
+    // the server may have forgotten us, or restarted, meanwhile: the first sequenced query tells.
+    // It is the first queued message when we sent it, as the queries made while offline took
+    // sequence numbers that would push it below the window of the server otherwise.
+    let queued = self
+      .store
+      .next_queued()
+      .filter(|queued| queued.src == self.id())
+      .map(|queued| Sequence {
+        seqid: queued.seqid,
+        src: queued.src,
+        content: ClientQuery::Message(queued.content.clone()),
+      });
+    let timeout = Some(self.supervision.timeout);
+    let known = match &queued {
+      Some(sq) => match self.exchange(sq, timeout, decode::client_replies).await {
+        Err(rr) if rr.downcast_ref::<TransportError>() == Some(&TransportError::Replayed) => {
+          Ok(Some(Vec::new()))
+        }
+        r => r.map(Some),
+      },
+      None => {
+        let sq = self.client.sequence(ClientQuery::GetPrefs);
+        let prefs = self.exchange(&sq, timeout, decode::notification_prefs);
+        prefs.await.map(|_| None)
+      }
+    };
+    let mut flushed = Vec::new();
+    match known {
+      Ok(first) => {
+        if let Some(first) = first {
+          self.store.dequeue();
+          flushed.push(Ok(first));
+        }
+        self.events.emit(ConnectionEvent::Resumed)
+      }
+      Err(rr) if rr.downcast_ref::<TransportError>() == Some(&TransportError::Refused) => {
+        let register = Sequence {
+          seqid: 0,
+          src: ClientId::default(),
+          content: self.registration.clone(),
+        };
+        let id = self
+          .exchange(&register, Some(self.supervision.timeout), decode::clientid)
+          .await?;
+        self.client = Client::new(id);
+        self.events.emit(ConnectionEvent::Reregistered(id));
+      }
+      Err(rr) => return Err(rr),
+    }
+    self.connected = true;
+    flushed.extend(self.flush().await?);
+    if !flushed.is_empty() {
+      self.events.emit(ConnectionEvent::Flushed(flushed.len()));
+    }
+    Ok(())
+  }
+
+  /// identity assigned by the server at registration
+  pub fn id(&self) -> ClientId {
+    self.client.id()
+  }
+
+  /// the codec of the queries and their replies
+  pub fn codec(&self) -> Codec {
+    self.codec
+  }
+
+  /// offers the `preferred` codecs to the server, best first, and uses the one it picked from
+  /// now on, which is returned (binary when the server enables none of them)
+  pub async fn negotiate_codec(&mut self, preferred: &[Codec]) -> anyhow::Result<Codec> {
+    let sq = Sequence {
+      seqid: 0,
+      src: self.id(),
+      content: ClientQuery::Hello(preferred.to_vec()),
+    };
+    let picked = self.exchange(&sq, self.timeout, decode::codec).await?;
+    if picked != Codec::Binary && !preferred.contains(&picked) {
+      anyhow::bail!("The server picked a codec that was not offered: {}", picked);
+    }
+    self.codec = picked;
+    Ok(picked)
+  }
+
+  /// fetches the next message (or delayed error) waiting in our mailbox
+  pub async fn poll(&mut self) -> anyhow::Result<ClientPollReply> {
+    let reply = self
+      .query(ClientQuery::Poll, decode::client_poll_reply)
+      .await?;
+    self.cache(std::slice::from_ref(&reply))?;
+    Ok(reply)
+  }
+
+  /// polls, waiting up to `wait` (the server can wait less) for something to poll
+  pub async fn poll_wait(&mut self, wait: Duration) -> anyhow::Result<ClientPollReply> {
+    let reply = self
+      .query_waiting(
+        ClientQuery::PollWait(wait.as_millis()),
+        wait,
+        decode::client_poll_reply,
+      )
+      .await?;
+    self.cache(std::slice::from_ref(&reply))?;
+    Ok(reply)
+  }
+
+  /// polls everything, and returns what changed since `cursor`, start with
+  /// `SyncCursor::default()` and follow the `cursor` of the replies (the reply is `full` when the
+  /// cursor is too old); the reply uses the compact encoding
+  /// a full reply replaces the cached messages
+  pub async fn sync(
+    &mut self,
+    cursor: SyncCursor,
+    limit: usize,
+  ) -> anyhow::Result<Result<SyncReply, ClientError>> {
+    let reply = self
+      .query(
+        ClientQuery::Sync {
+          cursor,
+          limit: limit as u128,
+          compact: true,
+        },
+        decode::sync_reply,
+      )
+      .await?;
+    if let Ok(reply) = &reply {
+      if reply.full {
+        self.store.clear_history();
+      }
+      for entry in &reply.messages {
+        self.store.record(&entry.message);
+      }
+      self.store.save()?;
+    }
+    Ok(reply)
+  }
+
+  /// polls up to `max` messages at once, an empty reply meaning there is nothing to poll
+  pub async fn poll_n(&mut self, max: usize) -> anyhow::Result<Vec<ClientPollReply>> {
+    let replies = self
+      .query(ClientQuery::PollN(max as u128), decode::client_poll_replies)
+      .await?;
+    self.cache(&replies)?;
+    Ok(replies)
+  }
+
+  // keeps the polled messages in the store
+  fn cache(&mut self, replies: &[ClientPollReply]) -> anyhow::Result<()> {
+    let mut kept = false;
+    for reply in replies {
+      kept |= self.store.record(reply);
+    }
+    if kept {
+      self.store.save()?;
+    }
+    Ok(())
+  }
+
+  /// lists the users known to the server, which are kept as the roster of the store
+  pub async fn list_users(&mut self) -> anyhow::Result<HashMap<ClientId, String>> {
+    let users = self.query(ClientQuery::ListUsers, decode::userlist).await?;
+    self.store.set_roster(users.clone());
+    self.store.save()?;
+    Ok(users)
+  }
+
+  /// lists the users known to the server, with their server, and tags for the shared names
+  pub async fn list_user_entries(&mut self) -> anyhow::Result<Vec<UserEntry>> {
+    self
+      .query(ClientQuery::ListUserEntries, decode::user_entries)
+      .await
+  }
+
+  /// a page of the users known to the server, start with `after: None` and follow `next`
+  pub async fn list_users_page(&mut self, query: UserQuery) -> anyhow::Result<UserPage> {
+    self
+      .query(ClientQuery::ListUsersPage(query), decode::user_page)
+      .await
+  }
+
+  /// a page of the matching messages of our history, start with `before: None` and follow `next`
+  pub async fn search(
+    &mut self,
+    query: SearchQuery,
+  ) -> anyhow::Result<Result<SearchPage, ClientError>> {
+    self
+      .query(ClientQuery::Search(query), decode::search_reply)
+      .await
+  }
+
+  /// the id of the user with this name, if the server knows one
+  pub async fn lookup_user(&mut self, name: &str) -> anyhow::Result<Option<ClientId>> {
+    self
+      .query(
+        ClientQuery::LookupUser(name.to_string()),
+        decode::user_lookup,
+      )
+      .await
+  }
+
+  /// sends a message, returning one reply per destination
+  pub async fn send(&mut self, msg: ClientMessage) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(ClientQuery::Message(msg), decode::client_replies)
+      .await
+  }
+
+  /// sends a message like `send`, but when the server can't be reached (or earlier messages are
+  /// still queued), queues it in the store and returns `None`
+  pub async fn send_or_queue(
+    &mut self,
+    msg: ClientMessage,
+  ) -> anyhow::Result<Option<Vec<ClientReply>>> {
+    let sq = self.client.sequence(msg);
+    if self.store.next_queued().is_none() {
+      match self.send_sequenced(&sq).await {
+        Err(rr) if unreachable(&rr) => (),
+        r => return r.map(Some),
+      }
+    }
+    self.store.queue(sq);
+    self.store.save()?;
+    Ok(None)
+  }
+
+  /// sends the queued messages in order, and returns their outcomes, until the queue is empty or
+  /// the server can't be reached (or limits our rate)
+  ///
+  /// A queued message keeps the sequence number it was first sent with, which is its idempotency
+  /// key: when the server had handled it, but its reply was lost, the server refuses it as a
+  /// replay and its outcome is an empty reply. Messages queued under another identity (by an
+  /// earlier registration) get a new sequence number instead, and can't be deduplicated.
+  pub async fn flush(&mut self) -> anyhow::Result<Vec<anyhow::Result<Vec<ClientReply>>>> {
+    let mut outcomes = Vec::new();
+    while let Some(queued) = self.store.next_queued() {
+      let sq = if queued.src == self.id() {
+        queued.clone()
+      } else {
+        self.client.sequence(queued.content.clone())
+      };
+      let outcome = match self.send_sequenced(&sq).await {
+        Err(rr) if unreachable(&rr) => break,
+        Err(rr) => match rr.downcast_ref::<TransportError>() {
+          Some(TransportError::RateLimited) => break,
+          Some(TransportError::Replayed) => Ok(Vec::new()),
+          _ => Err(rr),
+        },
+        r => r,
+      };
+      outcomes.push(outcome);
+      self.store.dequeue();
+    }
+    self.store.save()?;
+    Ok(outcomes)
+  }
+
+  async fn send_sequenced(
+    &mut self,
+    sq: &Sequence<ClientMessage>,
+  ) -> anyhow::Result<Vec<ClientReply>> {
+    let query = Sequence {
+      seqid: sq.seqid,
+      src: sq.src,
+      content: ClientQuery::Message(sq.content.clone()),
+    };
+    self
+      .exchange(&query, self.timeout, decode::client_replies)
+      .await
+  }
+
+  /// creates a room, that we are the first member of
+  pub async fn create_room(&mut self, name: &str) -> anyhow::Result<RoomId> {
+    self
+      .query(ClientQuery::CreateRoom(name.to_string()), decode::roomid)
+      .await
+  }
+
+  pub async fn join_room(&mut self, room: RoomId) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(ClientQuery::JoinRoom(room), decode::client_replies)
+      .await
+  }
+
+  pub async fn leave_room(&mut self, room: RoomId) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(ClientQuery::LeaveRoom(room), decode::client_replies)
+      .await
+  }
+
+  /// asks the server to turn this guest into a full member
+  pub async fn upgrade(&mut self) -> anyhow::Result<Vec<ClientReply>> {
+    let replies = self
+      .query(ClientQuery::Upgrade, decode::client_replies)
+      .await?;
+    if let (ClientQuery::RegisterGuest(name), Some(ClientReply::Delivered(_))) =
+      (&self.registration, replies.first())
+    {
+      self.registration = ClientQuery::Register(name.clone());
+    }
+    Ok(replies)
+  }
+
+  /// removes this client from the server, it can't be used afterwards
+  pub async fn unregister(&mut self) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(ClientQuery::Unregister, decode::client_replies)
+      .await
+  }
+
+  /// checks that the server is alive, and returns the round trip time
+  pub async fn ping(&mut self) -> anyhow::Result<Duration> {
+    self.ping_within(self.timeout).await
+  }
+
+  async fn ping_within(&mut self, wait: Option<Duration>) -> anyhow::Result<Duration> {
+    let nonce = self.rng.u128();
+    let start = Instant::now();
+    let sq = Sequence {
+      seqid: 0,
+      src: self.id(),
+      content: ClientQuery::Ping(nonce),
+    };
+    match self.exchange(&sq, wait, decode::client_replies).await?[..] {
+      [ClientReply::Pong(n)] if n == nonce => Ok(start.elapsed()),
+      ref other => Err(anyhow::anyhow!("Unexpected answer to a ping: {:?}", other)),
+    }
+  }
+
+  /// notification preferences stored by the server
+  pub async fn prefs(&mut self) -> anyhow::Result<NotificationPrefs> {
+    self
+      .query(ClientQuery::GetPrefs, decode::notification_prefs)
+      .await
+  }
+
+  pub async fn set_prefs(&mut self, prefs: NotificationPrefs) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(ClientQuery::SetPrefs(prefs), decode::client_replies)
+      .await
+  }
+
+  /// registers a client with a chosen id, which only admins can do
+  /// the new client uses this one's address, and its own sequence numbers
+  pub async fn register_trusted(
+    &mut self,
+    id: ClientId,
+    name: String,
+  ) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(
+        ClientQuery::RegisterTrusted { id, name },
+        decode::client_replies,
+      )
+      .await
+  }
+
+  /// changes our name, here and on the other servers
+  pub async fn rename(&mut self, name: String) -> anyhow::Result<Vec<ClientReply>> {
+    let replies = self
+      .query(ClientQuery::Rename(name.clone()), decode::client_replies)
+      .await?;
+    if let Some(ClientReply::Delivered(_)) = replies.first() {
+      self.registration = match &self.registration {
+        ClientQuery::RegisterGuest(_) => ClientQuery::RegisterGuest(name),
+        ClientQuery::RegisterInvited { invite, .. } => ClientQuery::RegisterInvited {
+          name,
+          invite: invite.clone(),
+        },
+        _ => ClientQuery::Register(name),
+      };
+    }
+    Ok(replies)
+  }
+
+  /// replaces the clients whose messages the server refuses for us
+  pub async fn set_blocked(&mut self, blocked: Vec<ClientId>) -> anyhow::Result<Vec<ClientReply>> {
+    self
+      .query(ClientQuery::SetBlocked(blocked), decode::client_replies)
+      .await
+  }
+
+  /// reports a message or a client to the server operators
+  pub async fn report(
+    &mut self,
+    target: ReportTarget,
+    reason: &str,
+  ) -> anyhow::Result<Vec<ClientReply>> {
+    let query = ClientQuery::Report {
+      target,
+      reason: reason.to_string(),
+    };
+    self.query(query, decode::client_replies).await
+  }
+
+  async fn query<X, F>(&mut self, query: ClientQuery, f: F) -> anyhow::Result<X>
+  where
+    X: DeserializeOwned,
+    F: Fn(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    self.query_waiting(query, Duration::ZERO, f).await
+  }
+
+  // for the queries the server can take `wait` to answer, sent again once with a new sequence
+  // number when the server says ours fell behind its window
+  async fn query_waiting<X, F>(
+    &mut self,
+    query: ClientQuery,
+    wait: Duration,
+    f: F,
+  ) -> anyhow::Result<X>
+  where
+    X: DeserializeOwned,
+    F: Fn(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let wait = self.timeout.map(|t| t + wait);
+    let sq = self.client.sequence(query);
+    match self.exchange(&sq, wait, &f).await {
+      Err(rr) => match rr.downcast_ref::<TransportError>() {
+        Some(TransportError::SequenceReuse { expected }) => {
+          self.client.resync(*expected);
+          let sq = self.client.sequence(sq.content);
+          self.exchange(&sq, wait, f).await
+        }
+        _ => Err(rr),
+      },
+      r => r,
+    }
+  }
+
+  // sends a query and reads its reply, noting whether the server could be reached
+  async fn exchange<X, F>(
+    &mut self,
+    sq: &Sequence<ClientQuery>,
+    wait: Option<Duration>,
+    f: F,
+  ) -> anyhow::Result<X>
+  where
+    X: DeserializeOwned,
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    drain(&self.socket).await;
+    let r = match send_query(&self.socket, self.codec, sq).await {
+      Ok(()) => recv_reply(&self.socket, self.codec, wait, f).await,
+      Err(rr) => Err(rr),
+    };
+    match &r {
+      Err(rr) if unreachable(rr) => {
+        if self.connected {
+          self.connected = false;
+          self.events.emit(ConnectionEvent::Lost);
+        }
+      }
+      _ => self.last_contact = Instant::now(),
+    }
+    r
+  }
+}
+
+// the server could not be reached: the socket was refused, or the reply did not come in time
+fn unreachable(rr: &anyhow::Error) -> bool {
+  use std::io::ErrorKind;
+  rr.downcast_ref::<std::io::Error>().is_some_and(|rr| {
+    matches!(
+      rr.kind(),
+      ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::NotConnected
+        | ErrorKind::TimedOut
+    )
+  })
+}
+
+// drops the replies that arrived after their query gave up on them, so that the next query does
+// not take one of them for its own; reply frames don't say which query they answer
+async fn drain(socket: &UdpSocket) {
+  let mut buf = vec![0u8; 65536];
+  while let Ok(Ok(_)) = timeout(Duration::ZERO, socket.recv(&mut buf)).await {}
+}
+
+async fn send_query(
+  socket: &UdpSocket,
+  codec: Codec,
+  sq: &Sequence<ClientQuery>,
+) -> anyhow::Result<()> {
+  let query = codec::encode(codec, sq, |w, sq| {
+    encode::sequence(w, sq, encode::client_query)
+  })?;
+  socket.send(&query).await?;
+  Ok(())
+}
+
+// `f` decodes the binary replies
+async fn recv_reply<X, F>(
+  socket: &UdpSocket,
+  codec: Codec,
+  wait: Option<Duration>,
+  f: F,
+) -> anyhow::Result<X>
+where
+  X: DeserializeOwned,
+  F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+{
+  // the largest datagram, json replies can be much larger than binary ones
+  let mut buf = vec![0u8; 65536];
+  let n = match wait {
+    None => socket.recv(&mut buf).await?,
+    Some(wait) => timeout(wait, socket.recv(&mut buf))
+      .await
+      .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??,
+  };
+  let mut cursor = Cursor::new(buf[..n].to_vec());
+  decode::frame(&mut cursor, |rd| codec::decode(codec, rd, f))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::collections::HashSet;
+
+  // the tests against a real server
+  #[cfg(feature = "server")]
+  use {
+    crate::core::{DefaultChecker, MessageServer, ServerConfig},
+    crate::messages::ServerId,
+    crate::service::{frame, Request, ServerService, Service},
+    crate::solutions::descamps_femery::Server,
+    std::sync::Arc,
+  };
+
+  // answers the registration and `messages` messages, without answering the first sending of
+  // the first message, as if its reply was lost
+  async fn lossy_server(socket: UdpSocket, id: ClientId, messages: usize) {
+    let mut handled = HashSet::new();
+    let mut buf = vec![0u8; 8192];
+    let mut answered = 0;
+    while answered <= messages {
+      let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+      let sq = decode::sequence(&mut Cursor::new(&buf[..n]), decode::client_query).unwrap();
+      let mut frame = Vec::new();
+      match sq.content {
+        ClientQuery::Register(_) => {
+          let mut reply = Vec::new();
+          encode::clientid(&mut reply, &id).unwrap();
+          encode::reply_frame(&mut frame, &reply).unwrap();
+        }
+        ClientQuery::Message(_) if !handled.insert(sq.seqid) => {
+          encode::error_frame(&mut frame, &TransportError::Replayed).unwrap();
+        }
+        ClientQuery::Message(_) if sq.seqid == 1 => continue,
+        ClientQuery::Message(_) => {
+          let mut reply = Vec::new();
+          encode::client_replies(&mut reply, &[ClientReply::Delivered(None)]).unwrap();
+          encode::reply_frame(&mut frame, &reply).unwrap();
+        }
+        other => panic!("unexpected query {:?}", other),
+      }
+      socket.send_to(&frame, peer).await.unwrap();
+      answered += 1;
+    }
+  }
+
+  // a server that knows the client `id`, until cancelled
+  async fn serve(socket: UdpSocket, id: ClientId) {
+    let mut buf = vec![0u8; 8192];
+    loop {
+      let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+      let sq = decode::sequence(&mut Cursor::new(&buf[..n]), decode::client_query).unwrap();
+      let mut reply = Vec::new();
+      match sq.content {
+        ClientQuery::Register(_) => encode::clientid(&mut reply, &id).unwrap(),
+        ClientQuery::Ping(nonce) => {
+          encode::client_replies(&mut reply, &[ClientReply::Pong(nonce)]).unwrap()
+        }
+        _ if sq.src != id => {
+          let mut frame = Vec::new();
+          encode::error_frame(&mut frame, &TransportError::Refused).unwrap();
+          socket.send_to(&frame, peer).await.unwrap();
+          continue;
+        }
+        ClientQuery::GetPrefs => {
+          encode::notification_prefs(&mut reply, &NotificationPrefs::default()).unwrap()
+        }
+        ClientQuery::Message(_) => {
+          encode::client_replies(&mut reply, &[ClientReply::Delivered(None)]).unwrap()
+        }
+        other => panic!("unexpected query {:?}", other),
+      }
+      let mut frame = Vec::new();
+      encode::reply_frame(&mut frame, &reply).unwrap();
+      socket.send_to(&frame, peer).await.unwrap();
+    }
+  }
+
+  // hands the queries to `service`, until cancelled
+  #[cfg(feature = "server")]
+  async fn serve_service<S: Service>(socket: Arc<UdpSocket>, service: S) {
+    let mut buf = vec![0u8; 8192];
+    loop {
+      let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+      let (codec, query) = codec::query(buf[..n].to_vec(), &[]).unwrap();
+      let request = Request {
+        peer,
+        size: n,
+        max_size: None,
+        codec,
+        query,
+      };
+      let frame = match service.call(request).await {
+        Ok(rsp) => frame(Ok(&rsp.reply)),
+        Err(rr) => frame(Err(TransportError::of(&rr))),
+      };
+      socket.send_to(&frame, peer).await.unwrap();
+    }
+  }
+
+  #[test]
+  #[cfg(feature = "server")]
+  fn resync() {
+    async_std::task::block_on(async {
+      let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+      let target = socket.local_addr().unwrap();
+      let server: Arc<Server<DefaultChecker>> = Arc::new(MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      ));
+      let service = ServerService::new(server.clone());
+      let served = async_std::task::spawn(serve_service(socket, service));
+      let mut client = ChatClient::connect(target, "user".to_string())
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(500));
+
+      // another session of the same client moved its sequence numbers far ahead
+      let mut other = Client::new(client.id());
+      other.resync(1000);
+      server
+        .handle_sequenced_message(other.sequence(ClientQuery::Poll))
+        .await
+        .unwrap();
+
+      assert_eq!(client.poll().await.unwrap(), ClientPollReply::Nothing);
+      assert_eq!(client.poll().await.unwrap(), ClientPollReply::Nothing);
+      served.cancel().await;
+    })
+  }
+
+  #[test]
+  #[cfg(feature = "server")]
+  fn offline_polls() {
+    async_std::task::block_on(async {
+      let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+      let target = socket.local_addr().unwrap();
+      let mut server: Server<DefaultChecker> = MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_sequence_window(2);
+      let server = Arc::new(server);
+      let other = server
+        .register_local_client("127.0.0.1".parse().unwrap(), "other".into())
+        .await
+        .unwrap();
+      let served =
+        async_std::task::spawn(serve_service(socket, ServerService::new(server.clone())));
+      let supervision = Supervision {
+        keepalive: Duration::ZERO,
+        timeout: Duration::from_millis(50),
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(40),
+      };
+      let mut client = ChatClient::connect(target, "user".to_string())
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50))
+        .with_supervision(supervision);
+      let events = client.connection_events();
+
+      // the message is queued, and the polls that follow take the next sequence numbers
+      served.cancel().await;
+      let text = ClientMessage::Text {
+        dest: other,
+        content: "hi".to_string(),
+      };
+      assert_eq!(client.send_or_queue(text).await.unwrap(), None);
+      for _ in 0..3 {
+        assert!(client.poll().await.is_err());
+      }
+
+      let socket = Arc::new(UdpSocket::bind(target).await.unwrap());
+      let served =
+        async_std::task::spawn(serve_service(socket, ServerService::new(server.clone())));
+      client.supervise().await.unwrap();
+      assert_eq!(client.store().pending().count(), 0);
+      let events: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+      assert_eq!(
+        events[events.len() - 2..],
+        [ConnectionEvent::Resumed, ConnectionEvent::Flushed(1)]
+      );
+      assert!(matches!(
+        server.client_poll(other).await,
+        ClientPollReply::Message { src, .. } if src == client.id()
+      ));
+      // and the queries resume after the polls
+      assert_eq!(client.poll().await.unwrap(), ClientPollReply::Nothing);
+      served.cancel().await;
+    })
+  }
+
+  #[test]
+  fn backoff() {
+    let supervision = Supervision {
+      initial_backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(10),
+      ..Supervision::default()
+    };
+    assert_eq!(supervision.backoff(1, 0.5), Duration::from_millis(750));
+    assert_eq!(supervision.backoff(3, 0.0), Duration::from_secs(2));
+    assert_eq!(supervision.backoff(100, 0.0), Duration::from_secs(5));
+  }
+
+  #[test]
+  fn supervise() {
+    async_std::task::block_on(async {
+      let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+      let target = socket.local_addr().unwrap();
+      let (first, second) = (ClientId::from(1), ClientId::from(2));
+      let server = async_std::task::spawn(serve(socket, first));
+      let supervision = Supervision {
+        keepalive: Duration::ZERO,
+        timeout: Duration::from_millis(50),
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(40),
+      };
+      let mut client = ChatClient::connect(target, "user".to_string())
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50))
+        .with_supervision(supervision);
+      let events = client.connection_events();
+      client.supervise().await.unwrap();
+      assert!(events.try_recv().is_err());
+
+      // the server goes away, and comes back without knowing us
+      server.cancel().await;
+      let text = ClientMessage::Text {
+        dest: ClientId::from(3),
+        content: "hi".to_string(),
+      };
+      assert_eq!(client.send_or_queue(text).await.unwrap(), None);
+      assert!(!client.is_connected());
+      let server = async_std::task::spawn(async move {
+        sleep(Duration::from_millis(100)).await;
+        serve(UdpSocket::bind(target).await.unwrap(), second).await
+      });
+      client.supervise().await.unwrap();
+      assert!(client.is_connected());
+      assert_eq!(client.id(), second);
+      assert_eq!(client.store().pending().count(), 0);
+
+      let events: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+      assert_eq!(events.first(), Some(&ConnectionEvent::Lost));
+      assert!(matches!(
+        events[1],
+        ConnectionEvent::Retrying { attempt: 1, .. }
+      ));
+      assert_eq!(
+        events[events.len() - 2..],
+        [
+          ConnectionEvent::Reregistered(second),
+          ConnectionEvent::Flushed(1)
+        ]
+      );
+      server.cancel().await;
+    })
+  }
+
+  #[test]
+  fn late_reply() {
+    async_std::task::block_on(async {
+      let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+      let target = socket.local_addr().unwrap();
+      let id = ClientId::from(1);
+      // answers the messages once the client gave up on them
+      let server = async_std::task::spawn(async move {
+        let mut buf = vec![0u8; 8192];
+        loop {
+          let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+          let sq = decode::sequence(&mut Cursor::new(&buf[..n]), decode::client_query).unwrap();
+          let mut reply = Vec::new();
+          match sq.content {
+            ClientQuery::Register(_) => encode::clientid(&mut reply, &id).unwrap(),
+            ClientQuery::Ping(nonce) => {
+              encode::client_replies(&mut reply, &[ClientReply::Pong(nonce)]).unwrap()
+            }
+            ClientQuery::Message(_) => {
+              sleep(Duration::from_millis(100)).await;
+              encode::client_replies(&mut reply, &[ClientReply::Delivered(None)]).unwrap()
+            }
+            other => panic!("unexpected query {:?}", other),
+          }
+          let mut frame = Vec::new();
+          encode::reply_frame(&mut frame, &reply).unwrap();
+          socket.send_to(&frame, peer).await.unwrap();
+        }
+      });
+      let mut client = ChatClient::connect(target, "user".to_string())
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50));
+      let text = ClientMessage::Text {
+        dest: ClientId::from(2),
+        content: "hi".to_string(),
+      };
+
+      assert!(client.send(text).await.is_err());
+      sleep(Duration::from_millis(150)).await;
+      // the late reply to the message is not taken for the one to the ping
+      client.ping().await.unwrap();
+      server.cancel().await;
+    })
+  }
+
+  #[test]
+  fn offline_queue() {
+    async_std::task::block_on(async {
+      let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+      let target = socket.local_addr().unwrap();
+      let id = ClientId::from(1);
+      let server = async_std::task::spawn(lossy_server(socket, id, 2));
+      let mut client = ChatClient::connect(target, "user".to_string())
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50));
+      let text = |content: &str| ClientMessage::Text {
+        dest: ClientId::from(2),
+        content: content.to_string(),
+      };
+
+      // the reply is lost, and the next message waits behind the first one
+      assert_eq!(client.send_or_queue(text("one")).await.unwrap(), None);
+      assert_eq!(client.send_or_queue(text("two")).await.unwrap(), None);
+      assert_eq!(client.store().pending().count(), 2);
+
+      // the server had handled the first one already
+      let outcomes = client.flush().await.unwrap();
+      let outcomes: Vec<_> = outcomes.into_iter().map(Result::unwrap).collect();
+      assert_eq!(outcomes, [vec![], vec![ClientReply::Delivered(None)]]);
+      assert_eq!(client.store().pending().count(), 0);
+
+      // the server is gone
+      server.await;
+      assert_eq!(client.send_or_queue(text("three")).await.unwrap(), None);
+      assert_eq!(client.flush().await.unwrap().len(), 0);
+      assert_eq!(client.store().pending().count(), 1);
+    })
+  }
 }
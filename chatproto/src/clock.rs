@@ -0,0 +1,165 @@
+//! time, behind a `Clock` that can be replaced
+//!
+//! The server reads the time (deadlines, expiry, timestamps) and waits (timeouts) through a
+//! `Clock`, the `SystemClock` by default. Tests can use a `ManualClock` instead, that only moves
+//! when told to, to check expiry and timeouts without waiting for them.
+
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::archive;
+
+pub trait Clock: Send + Sync {
+  /// the monotonic time, for deadlines and expiry
+  fn now(&self) -> Instant;
+
+  /// milliseconds since the Unix epoch, to timestamp messages
+  fn now_ms(&self) -> u64;
+
+  /// completes once `wait` has passed, by this clock
+  fn sleep(&self, wait: Duration) -> Sleep;
+}
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// the clock used when nothing else is configured
+pub fn system_clock() -> SharedClock {
+  Arc::new(SystemClock)
+}
+
+/// The time of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn now_ms(&self) -> u64 {
+    archive::now_ms()
+  }
+
+  fn sleep(&self, wait: Duration) -> Sleep {
+    Box::pin(async_std::task::sleep(wait))
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl std::fmt::Display for TimedOut {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    "timed out".fmt(f)
+  }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// `fut`, unless `wait` passes first by `clock`
+pub async fn timeout<F: Future>(
+  clock: &dyn Clock,
+  wait: Duration,
+  fut: F,
+) -> Result<F::Output, TimedOut> {
+  let mut fut = std::pin::pin!(fut);
+  let mut sleep = clock.sleep(wait);
+  poll_fn(|cx| match fut.as_mut().poll(cx) {
+    Poll::Ready(output) => Poll::Ready(Ok(output)),
+    Poll::Pending => sleep.as_mut().poll(cx).map(|()| Err(TimedOut)),
+  })
+  .await
+}
+
+/// A clock that only moves with `advance`, for tests.
+#[derive(Clone)]
+pub struct ManualClock(Arc<Mutex<Manual>>);
+
+struct Manual {
+  now: Instant,
+  epoch_ms: u64,
+  // the sleeps to poll again when the clock moves
+  sleepers: Vec<Waker>,
+}
+
+impl ManualClock {
+  /// a clock at `epoch_ms` milliseconds since the Unix epoch
+  pub fn new(epoch_ms: u64) -> Self {
+    ManualClock(Arc::new(Mutex::new(Manual {
+      now: Instant::now(),
+      epoch_ms,
+      sleepers: Vec::new(),
+    })))
+  }
+
+  /// moves the clock forward, completing the sleeps that are over
+  pub fn advance(&self, by: Duration) {
+    let sleepers = {
+      let mut manual = self.0.lock().unwrap();
+      manual.now += by;
+      manual.epoch_ms += by.as_millis() as u64;
+      std::mem::take(&mut manual.sleepers)
+    };
+    sleepers.into_iter().for_each(Waker::wake);
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Instant {
+    self.0.lock().unwrap().now
+  }
+
+  fn now_ms(&self) -> u64 {
+    self.0.lock().unwrap().epoch_ms
+  }
+
+  fn sleep(&self, wait: Duration) -> Sleep {
+    let clock = self.clone();
+    let deadline = self.now() + wait;
+    Box::pin(poll_fn(move |cx| {
+      let mut manual = clock.0.lock().unwrap();
+      if manual.now >= deadline {
+        Poll::Ready(())
+      } else {
+        manual.sleepers.push(cx.waker().clone());
+        Poll::Pending
+      }
+    }))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn manual() {
+    async_std::task::block_on(async {
+      let clock = ManualClock::new(1000);
+      let start = clock.now();
+      let sleeping = async_std::task::spawn(clock.sleep(Duration::from_secs(5)));
+      clock.advance(Duration::from_secs(2));
+      assert_eq!(clock.now_ms(), 3000);
+      assert_eq!(clock.now() - start, Duration::from_secs(2));
+      clock.advance(Duration::from_secs(3));
+      sleeping.await;
+
+      let never = std::future::pending::<()>;
+      assert_eq!(timeout(&clock, Duration::ZERO, async { 7 }).await, Ok(7));
+      assert_eq!(
+        timeout(&clock, Duration::ZERO, never()).await,
+        Err(TimedOut)
+      );
+      let system = SystemClock;
+      assert_eq!(
+        timeout(&system, Duration::from_millis(1), never()).await,
+        Err(TimedOut)
+      );
+    });
+  }
+}
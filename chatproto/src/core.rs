@@ -1,19 +1,196 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use futures::stream::BoxStream;
 
+use crate::archive::{ArchiveRecord, ExportFilter};
+use crate::invite::Invite;
 use crate::messages::{
   ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, Sequence, ServerId,
 };
-use crate::messages::{ServerMessage, ServerReply};
+use crate::messages::{
+  HistoryEntry, MessageId, NotificationPrefs, Outgoing, ReportTarget, RoomId, SearchPage,
+  SearchQuery, ServerMessage, ServerReply, ServerSequence, SyncCursor, SyncReply, UserEntry,
+  UserPage, UserQuery,
+};
+
+/// how long a message waits to be polled, or for its recipient to be known
+pub const MESSAGE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// messages kept for a recipient that is not known yet, further ones are refused with `BoxFull`
+pub const DELAYED_SIZE: usize = 256;
+/// messages a sender can have waiting for unknown recipients, refused with `DelayedQuota` above
+pub const DELAYED_PER_SENDER: usize = 4 * DELAYED_SIZE;
+/// messages the server keeps for unknown recipients, refused with `ServerBusy` above
+pub const DELAYED_TOTAL: usize = 64 * 1024;
+/// largest number of recipients of a single message, refused with `TooManyDestinations` above
+pub const MAX_DESTINATIONS: usize = 256;
+
+/// largest binary payload, in bytes, refused with `MessageTooLarge` above
+pub const MAX_DATA_SIZE: usize = 4 * 1024;
+/// largest reaction, in bytes, refused with `MessageTooLarge` above
+pub const MAX_REACTION_SIZE: usize = 32;
+/// longest reason of an abuse report, in bytes, refused with `MessageTooLarge` above
+pub const MAX_REPORT_REASON: usize = 1024;
+/// messages waiting for a client to leave do-not-disturb mode, further ones are refused with
+/// `BoxFull` (or make room with `OverflowPolicy::DropOldest`)
+pub const DEFERRED_SIZE: usize = 1024;
+
+/// what a deployment tunes without recompiling, given to `MessageServer::new`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerConfig {
+  /// mails waiting in a mailbox, see `OverflowPolicy::RejectNew`
+  pub mailbox_size: usize,
+  /// the mailbox size of some clients, instead of `mailbox_size`
+  pub mailbox_sizes: HashMap<ClientId, usize>,
+  /// signs the invites (see `invite`), only invited clients can register when set
+  pub invite_key: Option<Vec<u8>>,
+}
 
-pub const MAILBOX_SIZE: usize = 256;
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      mailbox_size: 256,
+      mailbox_sizes: HashMap::new(),
+      invite_key: None,
+    }
+  }
+}
+
+impl ServerConfig {
+  /// the mailbox size of `client`
+  pub fn mailbox_size(&self, client: &ClientId) -> usize {
+    self
+      .mailbox_sizes
+      .get(client)
+      .copied()
+      .unwrap_or(self.mailbox_size)
+  }
+}
+
+/// messages a sender can send: `burst` at once, and then `rate` per second
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SendRate {
+  pub rate: f64,
+  pub burst: u32,
+}
+
+/// what happens when a message reaches a full mailbox
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// new messages are refused with `BoxFull` once the mailbox size of the client (see
+  /// `ServerConfig`) is reached
+  #[default]
+  RejectNew,
+  /// the oldest waiting message is dropped to make room for the new one
+  DropOldest,
+  /// no limit on the number of waiting messages, but new ones are refused with `BoxFull` when
+  /// the mailbox would use more than this many bytes
+  MemoryCap(usize),
+}
+
+impl std::str::FromStr for OverflowPolicy {
+  type Err = anyhow::Error;
+
+  /// `reject`, `drop-oldest` or `cap:<bytes>`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reject" => Ok(OverflowPolicy::RejectNew),
+      "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+      _ => match s.strip_prefix("cap:") {
+        Some(bytes) => Ok(OverflowPolicy::MemoryCap(bytes.parse()?)),
+        None => Err(anyhow::anyhow!(
+          "expected reject, drop-oldest or cap:<bytes>, got {}",
+          s
+        )),
+      },
+    }
+  }
+}
+
+/// whether local clients can share a name
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NamePolicy {
+  /// the name of another local client is refused with `NameTaken`
+  #[default]
+  Unique,
+  /// clients can share names, and `list_user_entries` tags them apart
+  Shared,
+}
+
+impl std::str::FromStr for NamePolicy {
+  type Err = anyhow::Error;
+
+  /// `unique` or `shared`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "unique" => Ok(NamePolicy::Unique),
+      "shared" => Ok(NamePolicy::Shared),
+      _ => Err(anyhow::anyhow!("expected unique or shared, got {}", s)),
+    }
+  }
+}
+
+/// sequence numbers accepted below the highest one of a client, for the retries that arrive out
+/// of order (at most 128)
+pub const SEQUENCE_WINDOW: u32 = 32;
+
+/// largest page of `list_users_page`
+pub const USER_PAGE_SIZE: usize = 256;
+
+/// longest wait of a `PollWait` query
+pub const MAX_POLL_WAIT: Duration = Duration::from_secs(30);
+/// polled messages kept per client, the oldest ones are forgotten first
+pub const HISTORY_SIZE: usize = 256;
+/// how long the tombstones of the messages removed from a history are kept, so that the other
+/// devices of the client learn about the removal when they sync
+pub const TOMBSTONE_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// tombstones kept per client, the oldest ones are forgotten first
+pub const TOMBSTONES_SIZE: usize = HISTORY_SIZE;
+/// ephemeral events waiting for a client, the oldest are dropped beyond this
+pub const EVENTS_SIZE: usize = 32;
+/// how long a message can't be polled while the earlier messages of its sender are missing
+pub const REORDER_WAIT: Duration = Duration::from_secs(5);
+/// registrations (and their spam checks) running at the same time
+pub const REGISTRATION_CONCURRENCY: usize = 32;
+/// registrations waiting for their turn before new ones get `ServerBusy`
+pub const REGISTRATION_QUEUE: usize = 1024;
+/// abuse reports kept for the operators, the oldest ones are dropped above
+pub const REPORTS_SIZE: usize = 1024;
+/// reports of a single reporter among the kept ones, further ones are refused with `RateLimited`
+pub const REPORTS_PER_REPORTER: usize = 16;
 
 #[async_trait]
 pub trait SpamChecker {
   async fn is_user_spammer(&self, name: &str) -> bool;
   async fn is_ip_spammer(&self, name: &IpAddr) -> bool;
+
+  /// `is_user_spammer`, telling apart the answers that are only a fallback
+  async fn user_verdict(&self, name: &str) -> Verdict {
+    Verdict::Checked(self.is_user_spammer(name).await)
+  }
+  /// `is_ip_spammer`, telling apart the answers that are only a fallback
+  async fn ip_verdict(&self, name: &IpAddr) -> Verdict {
+    Verdict::Checked(self.is_ip_spammer(name).await)
+  }
+}
+
+/// The answer of a spam check, see `SpamChecker::user_verdict`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+  /// given by the backend
+  Checked(bool),
+  /// given by a fail policy because the backend could not be reached, it must not be remembered
+  Fallback(bool),
+}
+
+impl Verdict {
+  pub fn is_spammer(self) -> bool {
+    match self {
+      Verdict::Checked(spammer) | Verdict::Fallback(spammer) => spammer,
+    }
+  }
 }
 
 #[async_trait]
@@ -22,41 +199,349 @@ pub trait MessageServer<C: SpamChecker> {
   const GROUP_NAME: &'static str;
 
   /// create a new server, this is the constructor function
-  fn new(checker: C, id: ServerId) -> Self;
+  fn new(checker: C, id: ServerId, config: ServerConfig) -> Self;
 
   /// register a new client, that will then be able to send and receive messages.
   /// The first argument is the client screen name.
   ///
-  /// if any of the spam check fails, you should return None and not register the client.
-  async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId>;
+  /// if any of the spam check fails, you should return an error and not register the client.
+  /// Registrations go through a bounded queue, and `ServerBusy` is returned when it is full.
+  /// Names are unique among the local clients (see `same_name`) unless the `NamePolicy` of the
+  /// server is `Shared`: the name of another client is refused with `NameTaken`, whatever the way
+  /// it registered. A server with an invite key refuses it with `InvalidInvite`, see
+  /// `register_invited_client`.
+  async fn register_local_client(
+    &self,
+    src_ip: IpAddr,
+    name: String,
+  ) -> Result<ClientId, ClientError>;
+
+  /// registers like `register_local_client`, once the invite is checked and used, before the spam
+  /// checks: the use is given back when the registration is refused afterwards
+  /// a server without invite key ignores the invite
+  async fn register_invited_client(
+    &self,
+    src_ip: IpAddr,
+    name: String,
+    invite: Vec<u8>,
+  ) -> Result<ClientId, ClientError>;
+
+  /// removes a local client, its mailbox, history, subscriptions and room memberships
+  /// the first reply is `Delivered`, followed by a withdraw transferred to every neighbour, and
+  /// the new members of each room the client was in
+  async fn unregister_local_client(&self, client: ClientId) -> Vec<ClientReply>;
+
+  /// removes, like `unregister_local_client`, the local clients that did not poll for `max_age`
+  /// (registering counts as polling, and waiting pollers or open streams keep a client), and
+  /// returns them with a single withdraw of all of them for every neighbour, and the new members
+  /// of their rooms, nothing is transferred when no client was removed
+  async fn evict_idle(
+    &self,
+    now: Instant,
+    max_age: Duration,
+  ) -> (Vec<ClientId>, Vec<Outgoing<ServerMessage>>);
+
+  /// changes the name of a local client, members go through the spam checks again
+  /// reserved names are refused with `Forbidden`, and the names of other local clients with
+  /// `NameTaken` (unless names are shared); otherwise the first reply is `Delivered`,
+  /// followed by a new announce transferred to every neighbour so that they learn the name
+  async fn rename_client(&self, client: ClientId, name: String) -> Vec<ClientReply>;
+
+  /// register a read-only guest, without spam checks, refused with `InvalidInvite` by a server
+  /// with an invite key
+  /// guests can poll and list users, but every message they send is refused with `Forbidden`
+  async fn register_guest(&self, src_ip: IpAddr, name: String) -> Result<ClientId, ClientError>;
+
+  /// registers a member with a chosen id, without spam checks nor the registration queue
+  /// `admin` must be a local client allowed `Action::RegisterTrusted`, otherwise `Forbidden` is
+  /// returned, and ids of known clients (local or remote) are refused with `AlreadyRegistered`
+  async fn register_trusted_client(
+    &self,
+    admin: ClientId,
+    id: ClientId,
+    name: String,
+    src_ip: IpAddr,
+  ) -> Result<(), ClientError>;
+
+  /// runs the spam checks for a guest, and makes it a full member if they pass
+  async fn upgrade_guest(&self, client: ClientId) -> Result<(), ClientError>;
 
   /// list known users
-  /// also lists known remote users if federation is enabled
+  /// only the local ones, `list_users_page` can add the remote ones
   async fn list_users(&self) -> HashMap<ClientId, String>;
 
+  /// remote users learnt from announces, with the server they are registered on
+  async fn remote_users(&self) -> HashMap<ClientId, ServerId>;
+
+  /// a page of the users of `list_users` that match the filter, in the order of their ids, with
+  /// the remote users of `remote_users` if the query is `federated`
+  /// pages hold at most `USER_PAGE_SIZE` users (and at least one, whatever the limit); `next` is
+  /// the last id of the page when more users match
+  async fn list_users_page(&self, query: &UserQuery) -> UserPage;
+
+  /// the users of `list_users`, and the remote ones, with their server
+  /// the users whose name is shared by another listed user get their `ClientId::short` as tag
+  async fn list_user_entries(&self) -> Vec<UserEntry>;
+
+  /// the client with this name, if a single local client has it, otherwise a remote one if a
+  /// single remote client has it (names are only unique on each server, if at all)
+  async fn lookup_user(&self, name: &str) -> Option<ClientId>;
+
   /// handles a sequenced message
-  /// you must verify that sequence numbers are increasing
+  /// sequence numbers must be unused, and at most `SEQUENCE_WINDOW` below the highest one of the
+  /// client (so that retries can arrive out of order); a number that was already used is refused
+  /// with `Replayed`, and one below the window with `SequenceReuse`
   async fn handle_sequenced_message<A: Send>(&self, msg: Sequence<A>) -> Result<A, ClientError>;
 
+  /// handles a frame sequenced by another server
+  /// sequence numbers must be increasing for each sending server, replayed frames give `None`
+  async fn handle_server_sequence<A: Send>(&self, msg: ServerSequence<A>) -> Option<A>;
+
   /// pull function for the client
+  /// polled messages are moved to the client history
   async fn client_poll(&self, client: ClientId) -> ClientPollReply;
 
+  /// polls like `client_poll`, but when there is nothing to poll, waits for the next mail or
+  /// event until `wait` is over, and then returns `Nothing`
+  async fn client_poll_wait(&self, client: ClientId, wait: Duration) -> ClientPollReply;
+
+  /// the text messages of the client as they arrive, with their sender, for the transports that
+  /// push to connected clients
+  /// messages are polled (and kept in the history) like with `client_poll`, rich messages give
+  /// their text and room messages lose their room; the other mails and the events are left for
+  /// `client_poll`, like the messages the `NotificationPrefs` of the client silence. The stream
+  /// ends when the client is removed.
+  fn client_stream(&self, client: ClientId) -> BoxStream<'_, (ClientId, String)>;
+
+  /// polls everything like `client_poll_n`, and returns in a single reply
+  /// * up to `limit` entries of the history after the cursor, including the tombstones and the
+  ///   messages that were just polled
+  /// * the presence changes and receipts that were polled, and the rest of what was polled
+  /// * the read markers (the last message of each author the client acked) that changed after
+  ///   the cursor version
+  /// * the cursor of the next sync
+  ///
+  /// Without a cursor entry, or when it is gone from the history, the reply is `full`: the
+  /// history from its start, the current presence of every subscription instead of the changes,
+  /// and every read marker.
+  async fn sync(
+    &self,
+    client: ClientId,
+    cursor: &SyncCursor,
+    limit: usize,
+  ) -> Result<SyncReply, ClientError>;
+
+  /// polls up to `max` messages at once, in the order `client_poll` would return them
+  /// `Nothing` is not part of the result, which is empty when there is nothing to poll
+  async fn client_poll_n(&self, client: ClientId, max: usize) -> Vec<ClientPollReply>;
+
+  /// drops the messages that were not polled (or kept for an unknown recipient) before their
+  /// expiration time, and returns how many were dropped
+  /// local senders are told on their next poll with a `DelayedError::Expired`, once per recipient
+  async fn expire_messages(&self, now: Instant) -> usize;
+
+  /// the last `limit` messages polled by a local client, oldest first
+  /// with `before`, only the messages polled before that one are returned (it must still be in
+  /// the history, otherwise nothing is)
+  /// Deleted messages, and the ones forgotten to keep `HISTORY_SIZE` messages, leave a `Deleted`
+  /// tombstone in their place for `TOMBSTONE_GRACE` (at most `TOMBSTONES_SIZE` of them), that is
+  /// only returned `include_tombstones`. Tombstones are dropped by `expire_messages`.
+  async fn client_history(
+    &self,
+    client: ClientId,
+    limit: usize,
+    before: Option<MessageId>,
+    include_tombstones: bool,
+  ) -> Result<Vec<HistoryEntry>, ClientError>;
+
+  /// the messages of the history of a local client that match `query`, newest first, in pages of
+  /// at most `HISTORY_SIZE`
+  /// only text is searched: binary payloads, custom content types, and the messages of the
+  /// conversations that went through a key agreement (end-to-end encrypted) are never indexed.
+  /// Servers built without the `search` feature answer `Forbidden`.
+  async fn search_history(
+    &self,
+    client: ClientId,
+    query: &SearchQuery,
+  ) -> Result<SearchPage, ClientError>;
+
   /// handles a client message
   /// * if the user is unknown, it might be that it is remote, so messages should be kept until the user becomes known
-  ///   as a result, the "Delayed" message should be sent
+  ///   as a result, the "Delayed" message should be sent (up to `DELAYED_SIZE` messages per recipient,
+  ///   `DELAYED_PER_SENDER` per sender and `DELAYED_TOTAL` for the whole server), the sender is told
+  ///   on its next poll when the message is not delivered in the end: with a `DelayedError::Expired`
+  ///   when the user never becomes known, or a `DelayedError::Rejected` when the message is refused
+  ///   once it is (its sender was banned meanwhile)
   /// * until polled, messages are to be stored. There is a maximum mailbox size after which an error should be returned
+  ///   (or the oldest message dropped, depending on the server `OverflowPolicy`)
+  /// * room messages are delivered to every other local member of the room, with one reply per
+  ///   member, followed by a single `RoomText` transferred to each nexthop of the servers where
+  ///   the room has members
+  /// * acks refer to the reader history, and send a receipt to the author (guests can ack too)
+  /// * presence changes are delivered to the local subscribers, and transferred to every
+  ///   neighbour (guests can subscribe, but not change their presence)
+  /// * events skip the mailbox: they are kept in a separate queue of `EVENTS_SIZE`, dropping
+  ///   the oldest, and polled before the mailbox; they are not in the history either. Events
+  ///   from a sender the recipient muted, or during its quiet hours, are dropped (see
+  ///   `NotificationPrefs`)
+  /// * rich messages whose mention spans fall outside the text, or inside a character, are
+  ///   refused with one `Forbidden` per recipient. The recipients a rich message mentions also
+  ///   get an `Event::Mentioned` with its id, under the same prefs as the other events; the
+  ///   spans travel in `FullyQualifiedMessage::mentions`, so remote recipients are told by their
+  ///   own server. Clients can't send that event themselves (`Forbidden`)
+  /// * broadcasts need `Action::Broadcast`, and go to every other known client: a single
+  ///   `Broadcast` summary comes first, followed by the transfers for the remote clients
+  /// * prioritized messages wait in their own lane of the mailbox, and higher lanes are polled
+  ///   first; `Priority::System` is reserved to the server (notices, broadcasts) and refused
+  ///   with `Forbidden`. System mails are never refused, they make room in full mailboxes.
+  ///   Priorities do not survive federation.
+  /// * every accepted message gets a `MessageId`, chosen by the sender with `WithId` or by the
+  ///   server otherwise. It is returned in the `Delivered`, `Delayed` or `Transfer` reply, and
+  ///   becomes the id of the history entry, on the server of a remote recipient too (it travels
+  ///   in `FullyQualifiedMessage::id`), so receipts and reactions refer to it. Senders can edit
+  ///   or delete (replace with a `Deleted` tombstone) their messages to local clients with these
+  ///   ids, while they are in the mailbox or the history; `UnknownMessage` is returned otherwise
+  /// * binary payloads (`Data`) above the limit of the server (`MAX_DATA_SIZE` by default) are
+  ///   refused with `MessageTooLarge`. Federated messages are text, so they are only delivered to
+  ///   local clients, and `Forbidden` otherwise
+  /// * servers can limit how fast each sender, and each address, sends messages (acks and
+  ///   subscriptions excepted): the messages above the limit are refused with `RateLimited`.
+  ///   Broadcasts can have a lower limit of their own.
+  /// * messages to more than `MAX_DESTINATIONS` recipients (or the limit of the server) are
+  ///   refused with a single `TooManyDestinations`
+  /// * key agreements are opaque: they wait in mailboxes and are transferred like messages, but
+  ///   are not kept for unknown recipients, get no id, never reach the history and can't be
+  ///   edited. Their payload is limited like the binary ones.
+  /// * text, rich, room and data messages can be wrapped in `ReplyTo`, the recipient polls them
+  ///   wrapped in a `ClientPollReply::ReplyTo` with the same `in_reply_to`, locally or across
+  ///   federation, and they stay replies in the history when they are edited. Any other message
+  ///   in a `ReplyTo` is refused with `Forbidden`. The id is not checked: it is the one of a
+  ///   message of the history of the sender.
+  /// * reactions refer to the reactor history, like acks: the author gets a
+  ///   `ClientPollReply::Reaction`, locally or across federation, that is not kept in its
+  ///   history, and the reactor history entry keeps the emoji (once). Empty reactions are
+  ///   refused with `Forbidden`, and the ones above `MAX_REACTION_SIZE` with `MessageTooLarge`.
+  /// * text messages are polled with the server of their sender and the time that server
+  ///   accepted them, carried in `FullyQualifiedMessage::timestamp` across federation; delayed
+  ///   and edited messages keep their time, notices get the one of the notice.
+  /// * `SetDoNotDisturb` is allowed to everyone and answered with `Delivered`. While it is on,
+  ///   the mails to the client (system ones excepted) wait aside, up to `DEFERRED_SIZE`, without
+  ///   counting toward the mailbox limit. They expire, and can be edited, like the waiting
+  ///   ones, and move to the mailbox in the order they came when it is turned off.
+  /// * `MText` is answered with a single `Multi` reply, holding the reply of each recipient in the
+  ///   order of `dest`, even when it is refused as a whole (`RateLimited`, `Forbidden`).
+  /// * `AtomicMText` is delivered to all its recipients or to none, with a single reply: one
+  ///   `Delivered` with the id all the copies share, or the error of the first recipient that
+  ///   would refuse it (`BoxFull`, `Blocked`, or `UnknownClient` for a recipient that is not
+  ///   local, as remote mailboxes can't be checked).
+  ///
+  /// Ordering: messages from a given sender to a given recipient reach it in the order they were
+  /// sent, whether they are delivered locally, transferred, or delayed and flushed on announce,
+  /// as long as they have the same priority.
+  /// All the messages kept for an unknown recipient are flushed, oldest first, before any message
+  /// sent after the announce.
   async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply>;
 
   /// handles a server message
   /// * might be an announce (which might trigger waiting messages to be sent)
-  /// * might be a message for this server, or another
+  /// * might be a message for this server, or another; messages delivered to a local client are
+  ///   confirmed with a `Delivered` forwarded to the server of the sender, which shows it to the
+  ///   sender in `client_poll`, with the id of the message the sender got back
+  /// * `RoomMembers` replaces what is known of the members of a room on a server, if it is newer,
+  ///   and is forwarded to every neighbour; the room can then be joined. A neighbour announcing
+  ///   itself is sent the memberships known here, so that it learns the rooms created before
+  /// * `RoomText` is delivered to the local members of the room if this server is one of its
+  ///   destinations, and split by nexthop for the others
+  /// * `Resume` and `Session` are about the link between neighbours, and are kept by the
+  ///   federation driver; the server has nothing to do with them
   async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply;
 
+  /// creates a room, with `client` as its first member
+  async fn create_room(&self, client: ClientId, name: String) -> Result<RoomId, ClientError>;
+
+  /// adds a client to a room, joining twice is not an error
+  async fn join_room(&self, client: ClientId, room: RoomId) -> ClientReply;
+
+  /// removes a client from a room, rooms without members (on any server) are deleted
+  async fn leave_room(&self, client: ClientId, room: RoomId) -> ClientReply;
+
+  /// the announce of this server and its local clients, to send to neighbours
+  async fn make_announce(&self) -> ServerMessage;
+
+  /// the local members of a room, in a new `RoomMembers` for every neighbour, to be sent after
+  /// they changed (`unregister_local_client` includes the ones of the rooms the client was in)
+  async fn announce_room(&self, room: RoomId) -> Vec<Outgoing<ServerMessage>>;
+
+  /// notification preferences of a local client
+  async fn notification_prefs(&self, client: ClientId) -> Result<NotificationPrefs, ClientError>;
+
+  /// replaces the notification preferences of a local client
+  async fn set_notification_prefs(&self, client: ClientId, prefs: NotificationPrefs)
+    -> ClientReply;
+
+  /// replaces the clients a local client blocked (guests can block too)
+  /// messages from a blocked local sender are refused with `Blocked`, and the ones from a
+  /// blocked remote sender are dropped
+  async fn set_blocked(&self, client: ClientId, blocked: Vec<ClientId>) -> ClientReply;
+
+  /// records an abuse report from a local client
+  /// reports about a client that is not known, locally or remotely, are refused with `UnknownClient`,
+  /// reasons longer than `MAX_REPORT_REASON` with `MessageTooLarge`, and the reports of a client
+  /// that has `REPORTS_PER_REPORTER` of them kept with `RateLimited`
+  async fn handle_report(&self, src: ClientId, target: ReportTarget, reason: String)
+    -> ClientReply;
+
   /// gives the best route to a server
   /// as a first approximation, you can give any route
   async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>>;
 }
 
+/// What the operators of a server can do to its local clients, on top of `MessageServer`.
+///
+/// Banned clients stay registered and can still poll, but every message they send is refused
+/// with `Banned`. Bans are kept until the server stops.
+#[async_trait]
+pub trait AdminServer<C: SpamChecker>: MessageServer<C> {
+  /// bans a local client, `UnknownClient` if there is none
+  async fn ban_client(&self, client: ClientId) -> ClientReply;
+
+  /// removes a local client, like `unregister_local_client`, it can register again
+  async fn kick_client(&self, client: ClientId) -> Vec<ClientReply>;
+
+  /// refuses the registrations from `ip` with `Banned`, and bans the local clients registered
+  /// from it
+  async fn ban_ip(&self, ip: IpAddr);
+
+  /// an invite for `uses` registrations, valid for `ttl`, none without invite key
+  async fn issue_invite(&self, ttl: Duration, uses: u32) -> Option<Invite>;
+
+  /// inserts archived messages (see `archive::import`) in the histories of their local
+  /// recipients, by timestamp, without delivering anything, and returns how many were inserted.
+  /// The records of unknown recipients, whose id is already in the history, or older than a full
+  /// history, are refused. Devices that already synced past them do not get them.
+  async fn import_history(&self, records: &[ArchiveRecord]) -> usize;
+
+  /// the messages of the histories of the local clients that match `filter`, as archive records
+  /// (see `ArchiveRecord::of_entry`), oldest first for each recipient
+  async fn export_history(&self, filter: &ExportFilter) -> Vec<ArchiveRecord>;
+}
+
+#[async_trait]
+impl<T: SpamChecker + Send + Sync + ?Sized> SpamChecker for Box<T> {
+  async fn is_user_spammer(&self, name: &str) -> bool {
+    (**self).is_user_spammer(name).await
+  }
+  async fn is_ip_spammer(&self, name: &IpAddr) -> bool {
+    (**self).is_ip_spammer(name).await
+  }
+  async fn user_verdict(&self, name: &str) -> Verdict {
+    (**self).user_verdict(name).await
+  }
+  async fn ip_verdict(&self, name: &IpAddr) -> Verdict {
+    (**self).ip_verdict(name).await
+  }
+}
+
 // a spam checker that does nothing
 #[derive(Clone, Copy, Default)]
 pub struct DefaultChecker {}
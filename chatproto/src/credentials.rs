@@ -0,0 +1,189 @@
+//! where the auth handshake finds the credentials of the clients
+//!
+//! A `CredentialStore` maps a client id to its shared secret, or to its public key. The server
+//! side of the handshake (`ServerHandshake::receive_from`) looks the client up in it, so that a
+//! deployment can plug its own user database behind the trait. Two stores come with the crate:
+//!
+//!  * `MemoryCredentials`, filled by the code that builds the server;
+//!  * `FileCredentials`, read from a text file, one client per line: its id, `secret` or `key`,
+//!    and the value in hex. Empty lines and lines starting with `#` are skipped. The file is read
+//!    when the store is opened, and again on `reload`.
+//!
+//! The handshake is a challenge-response on a shared secret: a client that only has a public key
+//! is unknown to it. The keys are kept for the transports that verify signatures.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::messages::ClientId;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Credential {
+  /// shared with the client, proved by the challenge-response of the handshake
+  Secret(Vec<u8>),
+  /// the public half of a key pair held by the client
+  PublicKey(Vec<u8>),
+}
+
+impl Credential {
+  /// the shared secret, if it is one
+  pub fn secret(&self) -> Option<&[u8]> {
+    match self {
+      Credential::Secret(secret) => Some(secret),
+      Credential::PublicKey(_) => None,
+    }
+  }
+}
+
+/// Credentials of the clients, looked up by the handshake of every connection.
+pub trait CredentialStore: Send + Sync {
+  /// the credential of `user`, none when it has none here
+  fn credential(&self, user: &ClientId) -> Option<Credential>;
+}
+
+/// credentials given by the code that builds the server
+#[derive(Debug, Default)]
+pub struct MemoryCredentials {
+  credentials: RwLock<HashMap<ClientId, Credential>>,
+}
+
+impl MemoryCredentials {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// adds, or replaces, the credential of `user`
+  pub fn insert(&self, user: ClientId, credential: Credential) {
+    self.credentials.write().unwrap().insert(user, credential);
+  }
+
+  pub fn remove(&self, user: &ClientId) -> Option<Credential> {
+    self.credentials.write().unwrap().remove(user)
+  }
+}
+
+impl CredentialStore for MemoryCredentials {
+  fn credential(&self, user: &ClientId) -> Option<Credential> {
+    self.credentials.read().unwrap().get(user).cloned()
+  }
+}
+
+/// credentials read from a file, see the module documentation for its format
+#[derive(Debug)]
+pub struct FileCredentials {
+  path: PathBuf,
+  credentials: RwLock<HashMap<ClientId, Credential>>,
+}
+
+impl FileCredentials {
+  pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+    let path = path.into();
+    let credentials = RwLock::new(Self::read(&path)?);
+    Ok(FileCredentials { path, credentials })
+  }
+
+  /// reads the file again, the credentials are kept when it can't be read
+  pub fn reload(&self) -> anyhow::Result<()> {
+    let credentials = Self::read(&self.path)?;
+    *self.credentials.write().unwrap() = credentials;
+    Ok(())
+  }
+
+  fn read(path: &Path) -> anyhow::Result<HashMap<ClientId, Credential>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut credentials = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let (user, credential) = parse_line(line)
+        .map_err(|rr| anyhow::anyhow!("{}:{}: {}", path.display(), number + 1, rr))?;
+      if credentials.insert(user, credential).is_some() {
+        anyhow::bail!(
+          "{}:{}: {} is listed twice",
+          path.display(),
+          number + 1,
+          user
+        );
+      }
+    }
+    Ok(credentials)
+  }
+}
+
+impl CredentialStore for FileCredentials {
+  fn credential(&self, user: &ClientId) -> Option<Credential> {
+    self.credentials.read().unwrap().get(user).cloned()
+  }
+}
+
+fn parse_line(line: &str) -> anyhow::Result<(ClientId, Credential)> {
+  let fields: Vec<&str> = line.split_whitespace().collect();
+  let [user, kind, value] = fields[..] else {
+    anyhow::bail!("expected a client id, a kind and a value");
+  };
+  let user = ClientId(user.parse()?);
+  let value = hex(value)?;
+  match kind {
+    "secret" => Ok((user, Credential::Secret(value))),
+    "key" => Ok((user, Credential::PublicKey(value))),
+    _ => anyhow::bail!("unknown kind {:?}, expected secret or key", kind),
+  }
+}
+
+pub(crate) fn hex(value: &str) -> anyhow::Result<Vec<u8>> {
+  if !value.len().is_multiple_of(2) || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+    anyhow::bail!("invalid hex value");
+  }
+  (0..value.len())
+    .step_by(2)
+    .map(|i| Ok(u8::from_str_radix(&value[i..i + 2], 16)?))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::messages::MessageId;
+
+  #[test]
+  fn file_store() {
+    let path = std::env::temp_dir().join(format!("chat-credentials-{}", MessageId::default().0));
+    let (alice, bob) = (ClientId::from(1), ClientId::from(2));
+    let line = |user: ClientId, kind, value| format!("{} {} {}\n", user.0, kind, value);
+    let text = [
+      "# operators\n\n".to_string(),
+      line(alice, "secret", "736563726574"),
+    ]
+    .concat();
+    std::fs::write(&path, &text).unwrap();
+    let store = FileCredentials::open(&path).unwrap();
+    assert_eq!(
+      store.credential(&alice),
+      Some(Credential::Secret(b"secret".to_vec()))
+    );
+    assert_eq!(store.credential(&bob), None);
+
+    std::fs::write(&path, text.clone() + &line(bob, "key", "00ff")).unwrap();
+    store.reload().unwrap();
+    assert_eq!(
+      store.credential(&bob),
+      Some(Credential::PublicKey(vec![0, 255]))
+    );
+
+    // a broken file leaves the store as it was
+    for broken in [
+      line(bob, "password", "00"),
+      line(bob, "key", "0"),
+      line(alice, "key", "00"),
+      "bob secret 00\n".to_string(),
+    ] {
+      std::fs::write(&path, text.clone() + &broken).unwrap();
+      assert!(store.reload().is_err(), "{:?}", broken);
+    }
+    assert!(store.credential(&bob).is_some());
+    std::fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,945 @@
+//! sending messages to other servers
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use crate::messages::{
+  ClientId, ClientReply, FullyQualifiedMessage, NextHop, Outgoing, ServerId, ServerMessage,
+  ServerSequence,
+};
+use crate::netproto::encode;
+use crate::rng::{os_rng, Rng};
+
+pub mod memory;
+
+/// how long outgoing messages are held, waiting for others going to the same next hop
+pub const COALESCE_WINDOW: Duration = Duration::from_millis(5);
+/// most messages sent in a single frame
+pub const MAX_BATCH: usize = 64;
+/// failed sends before a message is given up on
+pub const MAX_ATTEMPTS: u32 = 5;
+/// largest encoded frame, small enough for a datagram on most paths
+pub const MAX_FRAME_SIZE: usize = 1200;
+/// bytes of a frame that are not messages: the sequence, and the batch header
+const FRAME_OVERHEAD: usize = 40;
+/// how long a connection attempt to a peer address runs alone, before the next one starts
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Delivers encoded frames to neighbouring servers.
+#[async_trait]
+pub trait FederationTransport {
+  async fn send_frame(&self, nexthop: ServerId, frame: Vec<u8>) -> anyhow::Result<()>;
+}
+
+/// Connects to the first address that answers, happy-eyeballs style.
+///
+/// Attempts are started in order, the next one `delay` after the previous one (or as soon as it
+/// failed), and run in parallel: the first to succeed is kept, and the others are dropped. The
+/// last error is returned when they all fail.
+pub async fn connect_first<T, F, Fut>(
+  addrs: &[SocketAddr],
+  delay: Duration,
+  attempt: F,
+) -> anyhow::Result<T>
+where
+  F: Fn(SocketAddr) -> Fut,
+  Fut: Future<Output = anyhow::Result<T>>,
+{
+  let mut addrs = addrs.iter().copied();
+  let mut attempts = FuturesUnordered::new();
+  let mut last = anyhow::anyhow!("no address to connect to");
+  loop {
+    if attempts.is_empty() {
+      match addrs.next() {
+        Some(addr) => attempts.push(attempt(addr)),
+        None => return Err(last),
+      }
+    }
+    match async_std::future::timeout(delay, attempts.next()).await {
+      Ok(Some(Ok(connected))) => return Ok(connected),
+      Ok(None) => continue,
+      Ok(Some(Err(rr))) => last = rr,
+      // still waiting, the next address gets its chance too
+      Err(_) => (),
+    }
+    if let Some(addr) = addrs.next() {
+      attempts.push(attempt(addr));
+    }
+  }
+}
+
+/// Orders addresses for `connect_first`, alternating IPv6 and IPv4 (IPv6 first), and otherwise
+/// keeping their order.
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+  let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+  let mut out = Vec::with_capacity(v6.len() + v4.len());
+  while !v6.is_empty() || !v4.is_empty() {
+    out.extend(v6.pop_front());
+    out.extend(v4.pop_front());
+  }
+  out
+}
+
+/// the transfers among the replies to a client, the ones of a `Multi` too
+pub fn transfers(replies: Vec<ClientReply>) -> Vec<Outgoing<ServerMessage>> {
+  replies
+    .into_iter()
+    .flat_map(|r| match r {
+      ClientReply::Multi(each) => each.into_iter().map(|(_, r)| r).collect(),
+      r => vec![r],
+    })
+    .filter_map(|r| match r {
+      ClientReply::Transfer(nexthop, message, _) => Some(Outgoing { nexthop, message }),
+      _ => None,
+    })
+    .collect()
+}
+
+fn encoded_size(message: &ServerMessage) -> usize {
+  let mut encoded = Cursor::new(Vec::new());
+  match encode::server(&mut encoded, message) {
+    Ok(()) => encoded.into_inner().len(),
+    Err(_) => 0,
+  }
+}
+
+/// Splits an announce whose encoding is larger than `max` bytes into announces of the same route,
+/// each with a part of the clients. Other messages, and announces that fit, are left alone.
+///
+/// A single client that does not fit still gets its own announce.
+pub fn split_announce(message: ServerMessage, max: usize) -> Vec<ServerMessage> {
+  if encoded_size(&message) <= max {
+    return vec![message];
+  }
+  let ServerMessage::Announce { route, clients } = message else {
+    return vec![message];
+  };
+  // the client count may take a few more bytes than in the empty announce
+  let base = encoded_size(&ServerMessage::Announce {
+    route: route.clone(),
+    clients: HashMap::new(),
+  }) + 2;
+  let mut chunks: Vec<HashMap<ClientId, String>> = Vec::new();
+  let mut size = max;
+  for (client, name) in clients {
+    let mut encoded = Cursor::new(Vec::new());
+    let _ =
+      encode::clientid(&mut encoded, &client).and_then(|()| encode::string(&mut encoded, &name));
+    let client_size = encoded.into_inner().len();
+    if size + client_size > max {
+      chunks.push(HashMap::new());
+      size = base;
+    }
+    size += client_size;
+    chunks.last_mut().unwrap().insert(client, name);
+  }
+  chunks
+    .into_iter()
+    .map(|clients| ServerMessage::Announce {
+      route: route.clone(),
+      clients,
+    })
+    .collect()
+}
+
+/// messages from one client to another, that must stay in order
+/// messages that are not between clients (announces, ...) share the `None` flow
+type Flow = Option<(ClientId, ClientId)>;
+
+fn flow(message: &ServerMessage) -> Flow {
+  match message {
+    ServerMessage::Message(m) => m.dsts.first().map(|(dst, _)| (m.src, *dst)),
+    ServerMessage::Receipt { reader, dst, .. } => Some((*reader, *dst)),
+    ServerMessage::Event { src, dst, .. } | ServerMessage::KeyAgreement { src, dst, .. } => {
+      Some((*src, *dst))
+    }
+    ServerMessage::Delivered { src, dst, .. } => Some((*dst, *src)),
+    _ => None,
+  }
+}
+
+struct Pending {
+  message: ServerMessage,
+  // encoded
+  size: usize,
+  attempts: u32,
+  // part of the announce of this generation, sent by `link_up`
+  announce: Option<u64>,
+}
+
+// an announce sent in full to a neighbour, and not acknowledged yet
+struct Sent {
+  announce: ServerMessage,
+  generation: u64,
+  // parts not sent yet
+  unsent: usize,
+  // frames that carried a part, and were not acknowledged yet
+  frames: HashSet<u128>,
+}
+
+/// what a neighbour holds of our announces
+#[derive(Default)]
+struct Session {
+  // handed out by the neighbour, it changes when the neighbour restarts
+  token: Option<u128>,
+  // last announce acknowledged by the neighbour
+  acked: Option<ServerMessage>,
+  sent: Option<Sent>,
+  generation: u64,
+}
+
+type HopQueue = HashMap<Flow, VecDeque<Pending>>;
+
+/// Queues the messages produced by the server for other servers, and sends them in batches.
+///
+/// Messages queued for the same next hop within the coalescing window are sent as
+/// `ServerMessage::Batch` frames of at most `max_batch` messages, and at most `max_frame_size`
+/// bytes once encoded. A lone message is sent as is, whatever its size, but announces are split
+/// when queued so that they fit.
+///
+/// Messages are kept in one FIFO queue per flow (sender, recipient). When a frame can't be sent,
+/// its messages stay at the head of their flows, and nothing else from these flows is sent until
+/// they are, so a retry never lets a later message overtake an earlier one. Other flows to the
+/// same next hop carry on.
+///
+/// Every frame is a `ServerSequence` from this server, so that neighbours can refuse replays.
+/// Sequence numbers start at the current time, and a restarted server is not taken for a replay.
+///
+/// Neighbours acknowledge our announces with a `Session` token, and the frame they came in. Once
+/// all the frames of an announce are acknowledged, the same announce is not sent again: the
+/// neighbour gets a `Resume` with its token instead. A neighbour that restarted answers with a
+/// new token, and gets the full announce again. The driver acknowledges the announces of its own
+/// neighbours the same way, with a token drawn when it starts.
+pub struct FederationDriver<T> {
+  me: ServerId,
+  seqid: AtomicU64,
+  token: u128,
+  sessions: Mutex<HashMap<ServerId, Session>>,
+  transport: T,
+  window: Duration,
+  max_batch: usize,
+  max_attempts: u32,
+  max_frame_size: usize,
+  pending: Mutex<HashMap<ServerId, HopQueue>>,
+}
+
+impl<T: FederationTransport + Send + Sync> FederationDriver<T> {
+  pub fn new(me: ServerId, transport: T, window: Duration) -> Self {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    FederationDriver {
+      me,
+      seqid: AtomicU64::new(now.as_micros() as u64),
+      token: os_rng().u128(),
+      sessions: Mutex::new(HashMap::new()),
+      transport,
+      window,
+      max_batch: MAX_BATCH,
+      max_attempts: MAX_ATTEMPTS,
+      max_frame_size: MAX_FRAME_SIZE,
+      pending: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// draws the session token from `rng`, instead of the OS generator
+  pub fn with_rng(mut self, rng: &dyn Rng) -> Self {
+    self.token = rng.u128();
+    self
+  }
+
+  /// overrides the frame size and the number of attempts per message
+  pub fn with_limits(mut self, max_batch: usize, max_attempts: u32) -> Self {
+    self.max_batch = max_batch.max(1);
+    self.max_attempts = max_attempts.max(1);
+    self
+  }
+
+  /// overrides the largest encoded frame, in bytes
+  pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+    self.max_frame_size = max_frame_size.max(FRAME_OVERHEAD + 1);
+    self
+  }
+
+  // room for messages in a frame
+  fn budget(&self) -> usize {
+    self.max_frame_size - FRAME_OVERHEAD
+  }
+
+  pub fn transport(&self) -> &T {
+    &self.transport
+  }
+
+  pub async fn queue(&self, nexthop: NextHop, message: ServerMessage) {
+    let mut pending = self.pending.lock().await;
+    self.push(pending.entry(nexthop.server()).or_default(), message, None);
+  }
+
+  /// queues our announce for a neighbour, when the link to it is established (or refreshed)
+  /// a neighbour is its own next hop, so no route is needed
+  /// the announce is replaced by a `Resume` when the neighbour already acknowledged it
+  pub async fn link_up(&self, peer: ServerId, announce: ServerMessage) {
+    let mut sessions = self.sessions.lock().await;
+    let session = sessions.entry(peer).or_default();
+    match (session.token, &session.acked) {
+      (Some(token), Some(acked)) if *acked == announce => {
+        let mut pending = self.pending.lock().await;
+        self.push(
+          pending.entry(peer).or_default(),
+          ServerMessage::Resume { token },
+          None,
+        );
+      }
+      _ => self.announce(peer, session, announce).await,
+    }
+  }
+
+  // queues an announce in full, and waits for its acknowledgement
+  async fn announce(&self, peer: ServerId, session: &mut Session, announce: ServerMessage) {
+    session.generation += 1;
+    let mut pending = self.pending.lock().await;
+    let unsent = self.push(
+      pending.entry(peer).or_default(),
+      announce.clone(),
+      Some(session.generation),
+    );
+    session.sent = Some(Sent {
+      announce,
+      generation: session.generation,
+      unsent,
+      frames: HashSet::new(),
+    });
+  }
+
+  /// Handles the link state in a frame received from a neighbour, and returns what is left for
+  /// the server, if anything.
+  ///
+  /// Announces from the neighbour itself are acknowledged, and so are its resumes: a resume with
+  /// an old token gets the current one, and the neighbour announces itself again.
+  pub async fn receive(
+    &self,
+    src: ServerId,
+    seqid: u128,
+    message: ServerMessage,
+  ) -> Option<ServerMessage> {
+    match message {
+      ServerMessage::Announce { ref route, .. } if route[..] == [src] => {
+        self.acknowledge(src, seqid).await;
+        Some(message)
+      }
+      ServerMessage::Resume { .. } => {
+        self.acknowledge(src, seqid).await;
+        None
+      }
+      ServerMessage::Session { token, seqid } => {
+        self.acknowledged(src, token, seqid).await;
+        None
+      }
+      ServerMessage::Batch(messages) => {
+        let mut left = Vec::new();
+        for message in messages {
+          left.extend(Box::pin(self.receive(src, seqid, message)).await);
+        }
+        (!left.is_empty()).then_some(ServerMessage::Batch(left))
+      }
+      message => Some(message),
+    }
+  }
+
+  async fn acknowledge(&self, peer: ServerId, seqid: u128) {
+    let mut pending = self.pending.lock().await;
+    let session = ServerMessage::Session {
+      token: self.token,
+      seqid,
+    };
+    self.push(pending.entry(peer).or_default(), session, None);
+  }
+
+  async fn acknowledged(&self, peer: ServerId, token: u128, seqid: u128) {
+    let mut sessions = self.sessions.lock().await;
+    let session = sessions.entry(peer).or_default();
+    if session.token.is_some_and(|known| known != token) {
+      // the neighbour forgot what we announced, it gets the last announce again
+      log::info!("{} lost our session, announcing again", peer);
+      let last = session
+        .sent
+        .take()
+        .map(|s| s.announce)
+        .or(session.acked.take());
+      *session = Session {
+        token: Some(token),
+        generation: session.generation,
+        ..Session::default()
+      };
+      if let Some(announce) = last {
+        self.announce(peer, session, announce).await;
+      }
+      return;
+    }
+    session.token = Some(token);
+    let Some(sent) = &mut session.sent else {
+      return;
+    };
+    sent.frames.remove(&seqid);
+    if sent.unsent == 0 && sent.frames.is_empty() {
+      session.acked = session.sent.take().map(|s| s.announce);
+    }
+  }
+
+  /// queues the content of a `ServerReply::Outgoing`
+  pub async fn queue_outgoing(&self, outgoing: Vec<Outgoing<FullyQualifiedMessage>>) {
+    let mut pending = self.pending.lock().await;
+    for o in outgoing {
+      self.push(
+        pending.entry(o.nexthop.server()).or_default(),
+        ServerMessage::Message(o.message),
+        None,
+      );
+    }
+  }
+
+  // returns the number of messages queued, once split
+  fn push(&self, queue: &mut HopQueue, message: ServerMessage, announce: Option<u64>) -> usize {
+    let parts = split_announce(message, self.budget());
+    let count = parts.len();
+    for message in parts {
+      queue.entry(flow(&message)).or_default().push_back(Pending {
+        size: encoded_size(&message),
+        message,
+        attempts: 0,
+        announce,
+      });
+    }
+    count
+  }
+
+  /// messages waiting to be sent (or retried) to a next hop
+  pub async fn pending(&self, nexthop: ServerId) -> usize {
+    match self.pending.lock().await.get(&nexthop) {
+      Some(queue) => queue.values().map(|flow| flow.len()).sum(),
+      None => 0,
+    }
+  }
+
+  /// sends everything that is pending, and keeps what could not be sent for the next flush
+  /// all next hops are tried, and the last error is returned
+  pub async fn flush(&self) -> anyhow::Result<()> {
+    let pending = std::mem::take(&mut *self.pending.lock().await);
+    let mut result = Ok(());
+    for (nexthop, mut queue) in pending {
+      if let Err(rr) = self.flush_hop(nexthop, &mut queue).await {
+        log::error!("Could not send to {}: {}", nexthop, rr);
+        result = Err(rr);
+      }
+      queue.retain(|_, flow| !flow.is_empty());
+      if queue.is_empty() {
+        continue;
+      }
+      // what is left goes before anything queued while we were sending
+      let mut pending = self.pending.lock().await;
+      let current = pending.entry(nexthop).or_default();
+      for (flow, mut left) in queue {
+        let newer = current.remove(&flow).unwrap_or_default();
+        left.extend(newer);
+        current.insert(flow, left);
+      }
+    }
+    result
+  }
+
+  async fn flush_hop(&self, nexthop: ServerId, queue: &mut HopQueue) -> anyhow::Result<()> {
+    let mut blocked = HashSet::new();
+    let mut result = Ok(());
+    loop {
+      // fill a frame, taking from every flow that is not blocked
+      let mut frame = Vec::new();
+      let mut size = 0;
+      for (flow, messages) in queue.iter_mut() {
+        if blocked.contains(flow) {
+          continue;
+        }
+        while frame.len() < self.max_batch {
+          match messages.front() {
+            // the first message of a frame always fits
+            Some(p) if frame.is_empty() || size + p.size <= self.budget() => {
+              size += p.size;
+              frame.push((*flow, messages.pop_front().unwrap()));
+            }
+            _ => break,
+          }
+        }
+      }
+      if frame.is_empty() {
+        return result;
+      }
+      let mut messages: Vec<ServerMessage> = frame.iter().map(|(_, p)| p.message.clone()).collect();
+      let message = if messages.len() == 1 {
+        messages.remove(0)
+      } else {
+        ServerMessage::Batch(messages)
+      };
+      let seqid = self.seqid.fetch_add(1, Ordering::SeqCst) as u128 + 1;
+      let sequence = ServerSequence {
+        seqid,
+        src: self.me,
+        content: message,
+      };
+      let mut encoded = Cursor::new(Vec::new());
+      encode::server_sequence(&mut encoded, &sequence, encode::server)?;
+      if let Err(rr) = self
+        .transport
+        .send_frame(nexthop, encoded.into_inner())
+        .await
+      {
+        // put the messages back at the head of their flows, in order, and hold these flows
+        for (flow, mut p) in frame.into_iter().rev() {
+          blocked.insert(flow);
+          p.attempts += 1;
+          if p.attempts >= self.max_attempts {
+            log::error!(
+              "Giving up on a message to {} after {} attempts",
+              nexthop,
+              p.attempts
+            );
+            continue;
+          }
+          queue.entry(flow).or_default().push_front(p);
+        }
+        result = Err(rr);
+      } else {
+        self.sent(nexthop, seqid, &frame).await;
+      }
+    }
+  }
+
+  // keeps track of the frames that carried the parts of the announce waiting for an
+  // acknowledgement
+  async fn sent(&self, nexthop: ServerId, seqid: u128, frame: &[(Flow, Pending)]) {
+    let mut sessions = self.sessions.lock().await;
+    let Some(sent) = sessions.get_mut(&nexthop).and_then(|s| s.sent.as_mut()) else {
+      return;
+    };
+    for (_, p) in frame {
+      if p.announce == Some(sent.generation) {
+        sent.unsent = sent.unsent.saturating_sub(1);
+        sent.frames.insert(seqid);
+      }
+    }
+  }
+
+  /// flushes the queue at every coalescing window, forever
+  pub async fn run(&self) {
+    loop {
+      async_std::task::sleep(self.window).await;
+      let _ = self.flush().await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::messages::{ClientId, ContentType, MessageId};
+  use crate::netproto::decode;
+
+  #[derive(Default)]
+  struct Recorder {
+    frames: Mutex<Vec<(ServerId, ServerMessage)>>,
+    seqids: Mutex<Vec<u128>>,
+    /// scripted outcome of the next sends, true meaning failure
+    failures: Mutex<VecDeque<bool>>,
+  }
+
+  #[async_trait]
+  impl FederationTransport for Recorder {
+    async fn send_frame(&self, nexthop: ServerId, frame: Vec<u8>) -> anyhow::Result<()> {
+      if self.failures.lock().await.pop_front() == Some(true) {
+        anyhow::bail!("link down");
+      }
+      let sequence = decode::server_sequence(&mut Cursor::new(frame), decode::server)?;
+      assert_eq!(sequence.src, ServerId::from(1));
+      self.seqids.lock().await.push(sequence.seqid);
+      self.frames.lock().await.push((nexthop, sequence.content));
+      Ok(())
+    }
+  }
+
+  impl Recorder {
+    /// contents of all the messages sent, batches flattened
+    async fn contents(&self) -> Vec<String> {
+      fn flatten(m: &ServerMessage, out: &mut Vec<String>) {
+        match m {
+          ServerMessage::Message(m) => out.push(m.content.clone()),
+          ServerMessage::Batch(ms) => ms.iter().for_each(|m| flatten(m, out)),
+          ServerMessage::Announce { .. }
+          | ServerMessage::Receipt { .. }
+          | ServerMessage::Reaction { .. }
+          | ServerMessage::Event { .. }
+          | ServerMessage::KeyAgreement { .. }
+          | ServerMessage::Delivered { .. }
+          | ServerMessage::Presence { .. }
+          | ServerMessage::Withdraw { .. }
+          | ServerMessage::Resume { .. }
+          | ServerMessage::Session { .. }
+          | ServerMessage::RoomMembers { .. }
+          | ServerMessage::RoomText { .. } => (),
+        }
+      }
+      let mut out = Vec::new();
+      for (_, m) in self.frames.lock().await.iter() {
+        flatten(m, &mut out);
+      }
+      out
+    }
+  }
+
+  fn message(content: &str) -> FullyQualifiedMessage {
+    from(1, content)
+  }
+
+  fn from(src: u128, content: &str) -> FullyQualifiedMessage {
+    FullyQualifiedMessage {
+      src: ClientId::from(src),
+      srcsrv: ServerId::from(1),
+      dsts: vec![(ClientId::from(2), ServerId::from(2))],
+      content: content.to_string(),
+      content_type: ContentType::Plain,
+      mentions: Vec::new(),
+      seq: 0,
+      in_reply_to: None,
+      timestamp: 0,
+      id: MessageId::from(src),
+    }
+  }
+
+  #[test]
+  fn coalesce() {
+    async_std::task::block_on(async {
+      let (s2, s3) = (ServerId::from(2), ServerId::from(3));
+      let driver = FederationDriver::new(ServerId::from(1), Recorder::default(), COALESCE_WINDOW);
+      driver
+        .queue_outgoing(vec![
+          Outgoing {
+            nexthop: NextHop(s2),
+            message: message("a"),
+          },
+          Outgoing {
+            nexthop: NextHop(s2),
+            message: message("b"),
+          },
+        ])
+        .await;
+      let announce = ServerMessage::Announce {
+        route: vec![ServerId::from(1)],
+        clients: HashMap::new(),
+      };
+      driver.queue(NextHop(s3), announce.clone()).await;
+      driver.flush().await.unwrap();
+
+      let mut frames = driver.transport().frames.lock().await.clone();
+      frames.sort_by_key(|(nexthop, _)| *nexthop);
+      assert_eq!(
+        frames,
+        [
+          (
+            s2,
+            ServerMessage::Batch(vec![
+              ServerMessage::Message(message("a")),
+              ServerMessage::Message(message("b"))
+            ])
+          ),
+          (s3, announce)
+        ]
+      );
+
+      // nothing left to send
+      driver.flush().await.unwrap();
+      assert_eq!(driver.transport().frames.lock().await.len(), 2);
+    })
+  }
+
+  #[test]
+  fn retries_keep_flow_order() {
+    async_std::task::block_on(async {
+      let s2 = ServerId::from(2);
+      let driver = FederationDriver::new(ServerId::from(1), Recorder::default(), COALESCE_WINDOW)
+        .with_limits(2, 5);
+      let outgoing = |src: u128, prefix: &str, range: std::ops::Range<usize>| {
+        range
+          .map(|i| Outgoing {
+            nexthop: NextHop(s2),
+            message: from(src, &format!("{}{}", prefix, i)),
+          })
+          .collect::<Vec<_>>()
+      };
+      driver.queue_outgoing(outgoing(1, "a", 0..4)).await;
+      driver.queue_outgoing(outgoing(3, "b", 0..4)).await;
+
+      // the second frame is lost, its flows are held while the others carry on
+      driver
+        .transport()
+        .failures
+        .lock()
+        .await
+        .extend([false, true]);
+      assert!(driver.flush().await.is_err());
+      assert!(driver.pending(s2).await > 0);
+      driver.queue_outgoing(outgoing(1, "a", 4..6)).await;
+      driver.flush().await.unwrap();
+      assert_eq!(driver.pending(s2).await, 0);
+      // retried frames get a new sequence number
+      let seqids = driver.transport().seqids.lock().await.clone();
+      assert!(seqids.windows(2).all(|w| w[0] < w[1]));
+
+      let sent = driver.transport().contents().await;
+      assert_eq!(sent.len(), 10);
+      for (prefix, count) in [("a", 6), ("b", 4)] {
+        let flow: Vec<&String> = sent.iter().filter(|c| c.starts_with(prefix)).collect();
+        let expected: Vec<String> = (0..count).map(|i| format!("{}{}", prefix, i)).collect();
+        assert_eq!(flow, expected.iter().collect::<Vec<_>>());
+      }
+    })
+  }
+
+  #[test]
+  fn gives_up() {
+    async_std::task::block_on(async {
+      let s2 = ServerId::from(2);
+      let driver = FederationDriver::new(ServerId::from(1), Recorder::default(), COALESCE_WINDOW)
+        .with_limits(8, 2);
+      driver
+        .transport()
+        .failures
+        .lock()
+        .await
+        .extend([true, true]);
+      driver
+        .queue(NextHop(s2), ServerMessage::Message(message("a")))
+        .await;
+      assert!(driver.flush().await.is_err());
+      assert_eq!(driver.pending(s2).await, 1);
+      assert!(driver.flush().await.is_err());
+      assert_eq!(driver.pending(s2).await, 0);
+    })
+  }
+
+  #[test]
+  fn connect_first_keeps_the_fastest() {
+    async_std::task::block_on(async {
+      let addrs: Vec<SocketAddr> = ["[::1]:4667", "127.0.0.1:4667", "127.0.0.2:4667"]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+      let started = Mutex::new(Vec::new());
+      // the first address never answers, the second fails, the third succeeds
+      let connected = connect_first(&addrs, Duration::from_millis(20), |addr| {
+        let started = &started;
+        async move {
+          started.lock().await.push(addr);
+          match addr.ip().to_string().as_str() {
+            "::1" => futures::future::pending().await,
+            "127.0.0.1" => anyhow::bail!("refused"),
+            _ => Ok(addr),
+          }
+        }
+      })
+      .await
+      .unwrap();
+      assert_eq!(connected, addrs[2]);
+      assert_eq!(*started.lock().await, addrs);
+
+      let rr = connect_first::<(), _, _>(&addrs, Duration::from_millis(20), |addr| async move {
+        anyhow::bail!("{} refused", addr)
+      })
+      .await
+      .unwrap_err();
+      assert_eq!(rr.to_string(), "127.0.0.2:4667 refused");
+      assert!(connect_first(&[], ATTEMPT_DELAY, |_| async { Ok(()) })
+        .await
+        .is_err());
+    })
+  }
+
+  #[test]
+  fn interleave() {
+    let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1", "[::2]:1"]
+      .iter()
+      .map(|a| a.parse().unwrap())
+      .collect();
+    assert_eq!(
+      interleave_families(addrs.clone()),
+      [addrs[2], addrs[0], addrs[3], addrs[1]]
+    );
+  }
+
+  #[test]
+  fn frame_size() {
+    async_std::task::block_on(async {
+      let s2 = ServerId::from(2);
+      let driver = FederationDriver::new(ServerId::from(1), Recorder::default(), COALESCE_WINDOW)
+        .with_max_frame_size(300);
+      let long = "x".repeat(100);
+      driver
+        .queue_outgoing(
+          (0..5)
+            .map(|_| Outgoing {
+              nexthop: NextHop(s2),
+              message: message(&long),
+            })
+            .collect(),
+        )
+        .await;
+      let clients = (0..20)
+        .map(|i| (ClientId::from(i), format!("client {}", i)))
+        .collect::<HashMap<_, _>>();
+      let announce = ServerMessage::Announce {
+        route: vec![ServerId::from(1)],
+        clients: clients.clone(),
+      };
+      driver.link_up(s2, announce).await;
+      driver.flush().await.unwrap();
+
+      let mut announced = HashMap::new();
+      for (_, m) in driver.transport().frames.lock().await.iter() {
+        let mut encoded = Cursor::new(Vec::new());
+        encode::server(&mut encoded, m).unwrap();
+        assert!(encoded.into_inner().len() <= 300 - FRAME_OVERHEAD);
+        // a small part of the announce can share a frame with the messages
+        let parts = match m {
+          ServerMessage::Batch(ms) => ms.iter().collect(),
+          m => vec![m],
+        };
+        for m in parts {
+          if let ServerMessage::Announce { clients, .. } = m {
+            announced.extend(clients.clone());
+          }
+        }
+      }
+      assert_eq!(announced, clients);
+      assert_eq!(driver.transport().contents().await, vec![long; 5]);
+    })
+  }
+
+  #[test]
+  fn resume() {
+    async_std::task::block_on(async {
+      let s2 = ServerId::from(2);
+      let driver = FederationDriver::new(ServerId::from(1), Recorder::default(), COALESCE_WINDOW);
+      let announce = |name: &str| ServerMessage::Announce {
+        route: vec![ServerId::from(1)],
+        clients: HashMap::from([(ClientId::from(1), name.to_string())]),
+      };
+      let last_frame = || async {
+        let frames = driver.transport().frames.lock().await;
+        let seqid = *driver.transport().seqids.lock().await.last().unwrap();
+        (seqid, frames.last().unwrap().1.clone())
+      };
+
+      // nothing to resume before the neighbour acknowledged the announce
+      driver.link_up(s2, announce("alice")).await;
+      driver.flush().await.unwrap();
+      let (first, _) = last_frame().await;
+      driver.link_up(s2, announce("alice")).await;
+      driver.flush().await.unwrap();
+      let (_, frame) = last_frame().await;
+      assert_eq!(frame, announce("alice"));
+      // only the frames of the last announce count
+      let ack = ServerMessage::Session {
+        token: 7,
+        seqid: first,
+      };
+      driver.receive(s2, 1, ack).await;
+      driver.link_up(s2, announce("alice")).await;
+      driver.flush().await.unwrap();
+      let (seqid, frame) = last_frame().await;
+      assert_eq!(frame, announce("alice"));
+      let ack = ServerMessage::Session { token: 7, seqid };
+      assert_eq!(driver.receive(s2, 1, ack).await, None);
+
+      driver.link_up(s2, announce("alice")).await;
+      driver.flush().await.unwrap();
+      assert_eq!(last_frame().await.1, ServerMessage::Resume { token: 7 });
+      // a new announce is sent in full
+      driver.link_up(s2, announce("bob")).await;
+      driver.flush().await.unwrap();
+      let (seqid, frame) = last_frame().await;
+      assert_eq!(frame, announce("bob"));
+      let ack = ServerMessage::Session { token: 7, seqid };
+      driver.receive(s2, 2, ack).await;
+
+      // the neighbour restarted, it gets the announce back right away
+      let ack = ServerMessage::Session { token: 8, seqid };
+      driver.receive(s2, 3, ack).await;
+      driver.flush().await.unwrap();
+      let (seqid, frame) = last_frame().await;
+      assert_eq!(frame, announce("bob"));
+      let ack = ServerMessage::Session { token: 8, seqid };
+      driver.receive(s2, 4, ack).await;
+      driver.link_up(s2, announce("bob")).await;
+      driver.flush().await.unwrap();
+      assert_eq!(last_frame().await.1, ServerMessage::Resume { token: 8 });
+    })
+  }
+
+  #[test]
+  fn acknowledge() {
+    async_std::task::block_on(async {
+      let s2 = ServerId::from(2);
+      let driver = FederationDriver::new(ServerId::from(1), Recorder::default(), COALESCE_WINDOW);
+      let announce = ServerMessage::Announce {
+        route: vec![s2],
+        clients: HashMap::new(),
+      };
+      // relayed announces are not acknowledged
+      let relayed = ServerMessage::Announce {
+        route: vec![ServerId::from(3), s2],
+        clients: HashMap::new(),
+      };
+      assert_eq!(
+        driver.receive(s2, 10, announce.clone()).await,
+        Some(announce)
+      );
+      assert_eq!(driver.receive(s2, 11, relayed.clone()).await, Some(relayed));
+      let message = ServerMessage::Message(message("a"));
+      let batch = ServerMessage::Batch(vec![ServerMessage::Resume { token: 1 }, message.clone()]);
+      assert_eq!(
+        driver.receive(s2, 12, batch).await,
+        Some(ServerMessage::Batch(vec![message]))
+      );
+      driver.flush().await.unwrap();
+      let token = driver.token;
+      assert_eq!(
+        driver.transport().frames.lock().await[..],
+        [(
+          s2,
+          ServerMessage::Batch(vec![
+            ServerMessage::Session { token, seqid: 10 },
+            ServerMessage::Session { token, seqid: 12 },
+          ])
+        )]
+      );
+    })
+  }
+
+  #[test]
+  fn split_small_announce() {
+    let announce = ServerMessage::Announce {
+      route: vec![ServerId::from(1)],
+      clients: HashMap::from([(ClientId::from(1), "one".to_string())]),
+    };
+    // a single client can't be split further
+    let expected = vec![announce.clone()];
+    assert_eq!(split_announce(announce.clone(), 10), expected);
+    assert_eq!(split_announce(announce, 1000), expected);
+  }
+}
@@ -0,0 +1,215 @@
+//! servers of a single process, federated without a transport
+//!
+//! `MemoryFederation` links servers that run in the same process: what one of them sends to a
+//! neighbour is handed to the `handle_server_message` of that neighbour as it is, a
+//! `ServerMessage` that is never encoded, sequenced nor acknowledged. It stands for the
+//! `FederationDriver` and its transport in tests, and for the embedders that run several logical
+//! servers in one binary.
+//!
+//! Like over a real link, announces only reach the neighbours, and messages go from neighbour to
+//! neighbour: `send` delivers them one at a time, in order, with everything their handling sends
+//! in turn, until none is left.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use super::transfers;
+use crate::core::{MessageServer, SpamChecker};
+use crate::messages::{ClientReply, NextHop, Outgoing, ServerId, ServerMessage, ServerReply};
+
+/// Servers, and the links between them, see the module documentation.
+pub struct MemoryFederation<S, C> {
+  servers: HashMap<ServerId, Arc<S>>,
+  links: HashMap<ServerId, BTreeSet<ServerId>>,
+  checker: PhantomData<fn() -> C>,
+}
+
+impl<S, C> Default for MemoryFederation<S, C> {
+  fn default() -> Self {
+    MemoryFederation {
+      servers: HashMap::new(),
+      links: HashMap::new(),
+      checker: PhantomData,
+    }
+  }
+}
+
+impl<S, C> MemoryFederation<S, C>
+where
+  S: MessageServer<C> + Send + Sync,
+  C: SpamChecker,
+{
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// adds, or replaces, the server `id`, that must be the id it was built with
+  pub fn add(&mut self, id: ServerId, server: Arc<S>) {
+    self.servers.insert(id, server);
+  }
+
+  pub fn server(&self, id: ServerId) -> Option<&Arc<S>> {
+    self.servers.get(&id)
+  }
+
+  /// the servers linked to `id`
+  pub fn neighbours(&self, id: ServerId) -> impl Iterator<Item = ServerId> + '_ {
+    self.links.get(&id).into_iter().flatten().copied()
+  }
+
+  /// links two servers, that announce themselves to each other
+  pub async fn link(&mut self, a: ServerId, b: ServerId) -> anyhow::Result<usize> {
+    for id in [a, b] {
+      if !self.servers.contains_key(&id) {
+        anyhow::bail!("Unknown server {}", id);
+      }
+    }
+    self.links.entry(a).or_default().insert(b);
+    self.links.entry(b).or_default().insert(a);
+    let announces = vec![
+      (a, self.announce_to(a, b).await),
+      (b, self.announce_to(b, a).await),
+    ];
+    self.deliver(announces.into()).await
+  }
+
+  /// sends the local clients of `id` to its neighbours, once they changed
+  pub async fn announce(&self, id: ServerId) -> anyhow::Result<usize> {
+    let mut queue = VecDeque::new();
+    for neighbour in self.neighbours(id) {
+      queue.push_back((id, self.announce_to(id, neighbour).await));
+    }
+    self.deliver(queue).await
+  }
+
+  /// delivers messages sent by `from`, and returns how many messages were delivered in all
+  pub async fn send(
+    &self,
+    from: ServerId,
+    messages: Vec<Outgoing<ServerMessage>>,
+  ) -> anyhow::Result<usize> {
+    self
+      .deliver(messages.into_iter().map(|o| (from, o)).collect())
+      .await
+  }
+
+  /// delivers the transfers among the replies to a client of `from`, like `send`
+  pub async fn transfer(&self, from: ServerId, replies: &[ClientReply]) -> anyhow::Result<usize> {
+    self.send(from, transfers(replies.to_vec())).await
+  }
+
+  async fn announce_to(&self, id: ServerId, neighbour: ServerId) -> Outgoing<ServerMessage> {
+    Outgoing {
+      nexthop: NextHop(neighbour),
+      message: self.servers[&id].make_announce().await,
+    }
+  }
+
+  // hands each message to its nexthop, and queues what it sends in turn
+  async fn deliver(
+    &self,
+    mut queue: VecDeque<(ServerId, Outgoing<ServerMessage>)>,
+  ) -> anyhow::Result<usize> {
+    let mut delivered = 0;
+    while let Some((from, Outgoing { nexthop, message })) = queue.pop_front() {
+      let to = nexthop.server();
+      if !self.neighbours(from).any(|n| n == to) {
+        anyhow::bail!("{} is not linked to {}", from, to);
+      }
+      delivered += 1;
+      match self.servers[&to].handle_server_message(message).await {
+        ServerReply::Outgoing(outgoing) => queue.extend(outgoing.into_iter().map(|o| {
+          let message = ServerMessage::Message(o.message);
+          (
+            to,
+            Outgoing {
+              nexthop: o.nexthop,
+              message,
+            },
+          )
+        })),
+        ServerReply::Forward(outgoing) => queue.extend(outgoing.into_iter().map(|o| (to, o))),
+        ServerReply::EmptyRoute => log::warn!("Empty route announced to {}", to),
+        ServerReply::Error(rr) => log::warn!("Error when {} handled a message: {}", to, rr),
+      }
+    }
+    Ok(delivered)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::net::IpAddr;
+
+  use super::*;
+  use crate::core::{DefaultChecker, ServerConfig};
+  use crate::messages::{ClientMessage, ClientPollReply};
+  use crate::solutions::descamps_femery::Server;
+
+  type Federation = MemoryFederation<Server<DefaultChecker>, DefaultChecker>;
+
+  fn server(id: ServerId) -> Arc<Server<DefaultChecker>> {
+    Arc::new(MessageServer::new(
+      DefaultChecker::default(),
+      id,
+      ServerConfig::default(),
+    ))
+  }
+
+  #[test]
+  fn neighbours() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let (s1, s2, s3) = (ServerId::from(1), ServerId::from(2), ServerId::from(3));
+      let (a, b) = (server(s1), server(s2));
+      let mut federation = Federation::new();
+      federation.add(s1, a.clone());
+      federation.add(s2, b.clone());
+      federation.add(s3, server(s3));
+      let ca = a.register_local_client(ip, "a".into()).await.unwrap();
+      let text = |dest| ClientMessage::Text {
+        dest,
+        content: "hi".into(),
+      };
+
+      assert_eq!(federation.link(s1, s2).await.unwrap(), 2);
+
+      // kept until the recipient is announced, and then handed over with its delivery back to
+      // the sender
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      let r = a.handle_client_message(ca, text(cb)).await;
+      let [ClientReply::Delayed(id)] = r[..] else {
+        panic!("Expected a delayed message, got {:?}", r);
+      };
+      assert_eq!(federation.announce(s2).await.unwrap(), 3);
+      assert!(matches!(
+        b.client_poll(cb).await,
+        ClientPollReply::Message { src, .. } if src == ca
+      ));
+      assert_eq!(
+        a.client_poll(ca).await,
+        ClientPollReply::Delivered { id, dst: cb }
+      );
+
+      // transfers to a known client
+      let r = b.handle_client_message(cb, text(ca)).await;
+      assert_eq!(federation.transfer(s2, &r).await.unwrap(), 2);
+      assert!(matches!(
+        a.client_poll(ca).await,
+        ClientPollReply::Message { src, .. } if src == cb
+      ));
+
+      // only linked servers talk to each other
+      let stray = Outgoing {
+        nexthop: NextHop(s3),
+        message: ServerMessage::Announce {
+          route: vec![s1],
+          clients: HashMap::new(),
+        },
+      };
+      assert!(federation.send(s1, vec![stray]).await.is_err());
+      assert!(federation.link(s1, ServerId::from(4)).await.is_err());
+    });
+  }
+}
@@ -0,0 +1,223 @@
+//! invites, for closed deployments
+//!
+//! A server whose `ServerConfig` has an `invite_key` only registers the clients that come with an
+//! invite (`ClientQuery::RegisterInvited`), issued by an operator. An invite is an id, the time it
+//! expires, how many registrations it allows, and a signature of the three by the key (the first
+//! 16 bytes of their HMAC-SHA256): the server does not keep the invites it issued, only how many
+//! times each one was used, until it expires.
+//!
+//! Invites travel as bytes, and are written in hex for links. Anything that is not an invite of
+//! this key, expired or used up, is refused with `InvalidInvite`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crypto_hash::{digest, Algorithm};
+
+use crate::audit::same;
+use crate::credentials::hex;
+use crate::messages::ClientError;
+use crate::rng::Rng;
+
+/// the size of an encoded invite: id, expiry, uses and signature
+pub const INVITE_SIZE: usize = 16 + 8 + 4 + 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Invite {
+  pub id: u128,
+  /// when it expires, in ms since the epoch
+  pub expires: u64,
+  /// the registrations it allows
+  pub uses: u32,
+  signature: [u8; 16],
+}
+
+impl Invite {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut out = self.signed_part();
+    out.extend_from_slice(&self.signature);
+    out
+  }
+
+  /// none when `bytes` are not an encoded invite, the signature is not checked
+  pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    if bytes.len() != INVITE_SIZE {
+      return None;
+    }
+    Some(Invite {
+      id: u128::from_be_bytes(bytes[0..16].try_into().ok()?),
+      expires: u64::from_be_bytes(bytes[16..24].try_into().ok()?),
+      uses: u32::from_be_bytes(bytes[24..28].try_into().ok()?),
+      signature: bytes[28..].try_into().ok()?,
+    })
+  }
+
+  fn signed_part(&self) -> Vec<u8> {
+    let mut out = Vec::with_capacity(INVITE_SIZE);
+    out.extend_from_slice(&self.id.to_be_bytes());
+    out.extend_from_slice(&self.expires.to_be_bytes());
+    out.extend_from_slice(&self.uses.to_be_bytes());
+    out
+  }
+}
+
+impl std::fmt::Display for Invite {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    self
+      .to_bytes()
+      .iter()
+      .try_for_each(|b| write!(f, "{:02x}", b))
+  }
+}
+
+impl std::str::FromStr for Invite {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Invite::from_bytes(&hex(s)?).ok_or_else(|| anyhow::anyhow!("not an invite"))
+  }
+}
+
+/// Issues the invites of a key, and counts the registrations each one was used for.
+pub struct Invites {
+  key: Vec<u8>,
+  // id -> (expiry, uses), forgotten once expired
+  used: Mutex<HashMap<u128, (u64, u32)>>,
+}
+
+impl Invites {
+  pub fn new(key: Vec<u8>) -> Self {
+    Invites {
+      key,
+      used: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// a new invite, for `uses` registrations until `ttl` after `now` (in ms since the epoch)
+  pub fn issue(&self, rng: &dyn Rng, now: u64, ttl: Duration, uses: u32) -> Invite {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    let mut invite = Invite {
+      id: rng.u128(),
+      expires: now.saturating_add(ttl),
+      uses,
+      signature: [0; 16],
+    };
+    invite.signature = self.signature(&invite);
+    invite
+  }
+
+  /// uses `invite` for a registration, see `refund` when the registration is refused afterwards
+  pub fn redeem(&self, invite: &[u8], now: u64) -> Result<Invite, ClientError> {
+    let invite = Invite::from_bytes(invite).ok_or(ClientError::InvalidInvite)?;
+    if !same(&invite.signature, &self.signature(&invite)) || invite.expires <= now {
+      return Err(ClientError::InvalidInvite);
+    }
+    let mut used = self.used.lock().unwrap();
+    used.retain(|_, (expires, _)| *expires > now);
+    let (_, count) = used.entry(invite.id).or_insert((invite.expires, 0));
+    if *count >= invite.uses {
+      return Err(ClientError::InvalidInvite);
+    }
+    *count += 1;
+    Ok(invite)
+  }
+
+  /// gives back the use taken by `redeem`
+  pub fn refund(&self, invite: &Invite) {
+    if let Some((_, count)) = self.used.lock().unwrap().get_mut(&invite.id) {
+      *count = count.saturating_sub(1);
+    }
+  }
+
+  fn signature(&self, invite: &Invite) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&hmac(&self.key, &invite.signed_part())[..16]);
+    out
+  }
+}
+
+// HMAC-SHA256 (RFC 2104)
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+  const BLOCK: usize = 64;
+  let mut block = [0u8; BLOCK];
+  if key.len() > BLOCK {
+    block[..32].copy_from_slice(&digest(Algorithm::SHA256, key));
+  } else {
+    block[..key.len()].copy_from_slice(key);
+  }
+  let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+  let mut inner = pad(0x36);
+  inner.extend_from_slice(data);
+  let mut outer = pad(0x5c);
+  outer.extend_from_slice(&digest(Algorithm::SHA256, &inner));
+  digest(Algorithm::SHA256, &outer)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::rng::SeededRng;
+
+  #[test]
+  fn hmac_sha256() {
+    // RFC 4231, test cases 2 and 6
+    let hex = |bytes: Vec<u8>| {
+      bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>()
+    };
+    assert_eq!(
+      hex(hmac(b"Jefe", b"what do ya want for nothing?")),
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert_eq!(
+      hex(hmac(
+        &[0xaa; 131],
+        b"Test Using Larger Than Block-Size Key - Hash Key First"
+      )),
+      "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+    );
+  }
+
+  #[test]
+  fn invites() {
+    let rng = SeededRng::new(1);
+    let invites = Invites::new(b"key".to_vec());
+    let invite = invites.issue(&rng, 1000, Duration::from_secs(1), 2);
+    assert_eq!(invite.expires, 2000);
+    assert_eq!(invite.to_string().parse::<Invite>().unwrap(), invite);
+    let bytes = invite.to_bytes();
+
+    assert_eq!(invites.redeem(&bytes, 1000), Ok(invite));
+    let second = invites.redeem(&bytes, 1500).unwrap();
+    assert_eq!(
+      invites.redeem(&bytes, 1500),
+      Err(ClientError::InvalidInvite)
+    );
+    invites.refund(&second);
+    assert_eq!(invites.redeem(&bytes, 1999), Ok(invite));
+    assert_eq!(
+      invites.redeem(&bytes, 2000),
+      Err(ClientError::InvalidInvite)
+    );
+
+    // more uses, or another key, break the signature
+    let fresh = invites.issue(&rng, 1000, Duration::from_secs(1), 1);
+    let mut forged = fresh;
+    forged.uses = 10;
+    let other = Invites::new(b"other".to_vec());
+    for refused in [forged.to_bytes(), bytes[1..].to_vec()] {
+      assert_eq!(
+        invites.redeem(&refused, 1000),
+        Err(ClientError::InvalidInvite)
+      );
+    }
+    assert_eq!(
+      other.redeem(&fresh.to_bytes(), 1000),
+      Err(ClientError::InvalidInvite)
+    );
+    assert_eq!(invites.redeem(&fresh.to_bytes(), 1000), Ok(fresh));
+  }
+}
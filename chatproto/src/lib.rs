@@ -1,7 +1,53 @@
+//! The chat protocol.
+//!
+//! `messages` (the `chattypes` crate) and `netproto` (the codec) are always built. The `client`
+//! feature adds the async `ChatClient` (with its local `store`), and the `server` feature (on by
+//! default) the message server and everything it runs on. Client-only consumers should disable
+//! the default features. The `search` feature adds a full-text index of the client histories.
+
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "server")]
+pub mod admission;
+#[cfg(feature = "server")]
+pub mod archive;
+#[cfg(feature = "client")]
+pub mod audit;
+#[cfg(feature = "server")]
+pub mod auth;
+#[cfg(feature = "server")]
+pub mod authz;
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "server")]
+pub mod clock;
+#[cfg(feature = "server")]
 pub mod core;
-pub mod messages;
+#[cfg(feature = "server")]
+pub mod credentials;
+#[cfg(feature = "server")]
+pub mod federation;
+#[cfg(feature = "server")]
+pub mod invite;
+pub use chattypes as messages;
+#[cfg(feature = "server")]
+pub mod mux;
 pub mod netproto;
+#[cfg(feature = "server")]
+pub mod ratelimit;
+#[cfg(feature = "client")]
+pub mod rng;
+#[cfg(feature = "server")]
+pub mod routing;
+#[cfg(feature = "search")]
+pub mod search;
+#[cfg(feature = "server")]
+pub mod service;
+#[cfg(feature = "server")]
 pub mod solutions;
-#[cfg(test)]
+#[cfg(feature = "server")]
+pub mod spam;
+#[cfg(feature = "client")]
+pub mod store;
+#[cfg(all(test, feature = "server"))]
 pub mod testing;
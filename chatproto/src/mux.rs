@@ -0,0 +1,738 @@
+//! many logical clients over one TCP connection
+//!
+//! Every frame is a channel number, a length, and a payload, the first two encoded as `u128`
+//! (see the README). Requests carry an encoded `Sequence<ClientQuery>`, exactly like a UDP
+//! datagram (in any of the enabled codecs), and the reply frame comes back on the same channel
+//! (see `encode::reply_frame`).
+//!
+//! A channel usually carries a single client: the requests of a channel are handled one after
+//! the other, in order, while different channels are handled concurrently. A connection has a
+//! bounded number of channels, each with a bounded queue (`ChannelLimits`): a full queue stops the
+//! reading of the connection until it drains, a channel idle for long enough is dropped, and the
+//! requests on a new channel over the limit are refused with `RateLimited`.
+//!
+//! A connection starts with small frames (`FrameLimits::anonymous`). Once a client proved who it
+//! is with the handshake of `auth`, larger frames can be negotiated on `CONTROL_CHANNEL`: the
+//! payload is the requested maximum, and the server answers with the one it granted, up to
+//! `FrameLimits::authenticated`. Frames larger than the current maximum close the connection.
+//!
+//! The handshake goes over `AUTH_CHANNEL`, one encoded `AuthMessage` per frame, with the
+//! challenge bound to a nonce of the connection. The server answers the `Hello` with the
+//! challenge, and the response with an empty reply, in reply frames. A failed handshake is
+//! answered with `AuthRequired` (`RateLimited` while locked out, `Refused` on a server without
+//! credentials), and can be started again.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::channel::{bounded, unbounded, Receiver, SendError, Sender};
+use async_std::future::timeout;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use async_std::sync::Mutex;
+use async_std::task;
+
+use crate::auth::{connection_nonce, AuthError, AuthThrottle, ClientHandshake, ServerHandshake};
+use crate::credentials::CredentialStore;
+use crate::messages::{
+  AuthMessage, ClientId, ClientQuery, Codec, Sequence, ServerId, TransportError,
+};
+use crate::netproto::budget::{BudgetExceeded, FrameBudget};
+use crate::netproto::mode::{self, DecodeMode};
+use crate::netproto::{codec, decode, encode};
+use crate::rng::{Rng, SharedRng};
+use crate::service::{frame, Request, Service};
+
+/// largest payload accepted in a frame
+pub const MAX_FRAME: usize = 65536;
+
+/// channel of the frames negotiating the largest frame of the connection
+pub const CONTROL_CHANNEL: u128 = u128::MAX;
+
+/// channel of the auth handshake, that unlocks the larger frames
+pub const AUTH_CHANNEL: u128 = u128::MAX - 1;
+
+/// largest payloads the server accepts on a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameLimits {
+  /// until a client authenticated on the connection
+  pub anonymous: usize,
+  /// the most an authenticated client can negotiate
+  pub authenticated: usize,
+}
+
+impl Default for FrameLimits {
+  fn default() -> Self {
+    FrameLimits {
+      anonymous: 8 * 1024,
+      authenticated: MAX_FRAME,
+    }
+  }
+}
+
+impl FrameLimits {
+  /// the maximum granted for `requested`, never below the anonymous one
+  pub fn grant(&self, requested: u128, authenticated: bool) -> usize {
+    if !authenticated {
+      return self.anonymous;
+    }
+    let ceiling = self.authenticated.max(self.anonymous);
+    usize::try_from(requested)
+      .unwrap_or(usize::MAX)
+      .clamp(self.anonymous, ceiling)
+  }
+}
+
+/// the channels of a connection, see the module documentation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelLimits {
+  /// channels open at the same time
+  pub channels: usize,
+  /// requests waiting to be handled on a channel
+  pub queue: usize,
+  /// a channel without requests for this long is dropped
+  pub idle: Duration,
+}
+
+impl Default for ChannelLimits {
+  fn default() -> Self {
+    ChannelLimits {
+      channels: 256,
+      queue: 16,
+      idle: Duration::from_secs(60),
+    }
+  }
+}
+
+pub async fn read_frame<R: ReadExt + Unpin>(
+  rd: &mut R,
+  max: usize,
+) -> anyhow::Result<(u128, Vec<u8>)> {
+  let channel = read_u128(rd).await?;
+  let len = read_u128(rd).await?;
+  if len > max as u128 {
+    anyhow::bail!("Frame too large ({} bytes)", len);
+  }
+  let mut payload = vec![0u8; len as usize];
+  rd.read_exact(&mut payload).await?;
+  Ok((channel, payload))
+}
+
+pub async fn write_frame<W: WriteExt + Unpin>(
+  w: &mut W,
+  channel: u128,
+  payload: &[u8],
+) -> anyhow::Result<()> {
+  let mut frame = Cursor::new(Vec::with_capacity(payload.len() + 8));
+  encode::u128(&mut frame, channel)?;
+  encode::u128(&mut frame, payload.len() as u128)?;
+  std::io::Write::write_all(&mut frame, payload)?;
+  w.write_all(&frame.into_inner()).await?;
+  Ok(())
+}
+
+async fn read_u128<R: ReadExt + Unpin>(rd: &mut R) -> anyhow::Result<u128> {
+  let mut prefix = [0u8; 1];
+  rd.read_exact(&mut prefix).await?;
+  let extra = match prefix[0] {
+    0..=250 => 0,
+    251 => 2,
+    252 => 4,
+    253 => 8,
+    _ => 16,
+  };
+  let mut buf = vec![0u8; 1 + extra];
+  buf[0] = prefix[0];
+  rd.read_exact(&mut buf[1..]).await?;
+  decode::u128(&mut Cursor::new(buf))
+}
+
+/// How the handshakes of the connections are checked.
+pub struct MuxAuth {
+  pub server: ServerId,
+  pub credentials: Arc<dyn CredentialStore>,
+  pub throttle: Arc<AuthThrottle>,
+  pub rng: SharedRng,
+}
+
+impl MuxAuth {
+  // a handshake bound to a fresh nonce of the connection
+  fn handshake(&self, peer: SocketAddr) -> ServerHandshake {
+    ServerHandshake::new(self.server, Some(connection_nonce(&*self.rng)))
+      .guarded(self.throttle.clone(), peer.ip())
+  }
+
+  // the reply frame to a message of the handshake, that starts again when it fails
+  fn step(&self, peer: SocketAddr, handshake: &mut ServerHandshake, payload: Vec<u8>) -> Vec<u8> {
+    let received = match decode::auth(&mut Cursor::new(payload)) {
+      Ok(msg) => handshake.receive_from(&msg, &*self.rng, &*self.credentials),
+      Err(_) => return frame(Err(TransportError::Malformed)),
+    };
+    match received {
+      Ok(None) => frame(Ok(&[])),
+      Ok(Some(challenge)) => {
+        let mut reply = Vec::new();
+        match encode::auth(&mut reply, &challenge) {
+          Ok(()) => frame(Ok(&reply)),
+          Err(_) => frame(Err(TransportError::Malformed)),
+        }
+      }
+      Err(rr) => {
+        log::warn!("Handshake from {} failed: {}", peer, rr);
+        *handshake = self.handshake(peer);
+        frame(Err(match rr {
+          AuthError::LockedOut => TransportError::RateLimited,
+          _ => TransportError::AuthRequired,
+        }))
+      }
+    }
+  }
+}
+
+/// what the channels of a connection decode their queries with
+#[derive(Clone)]
+pub struct Decoding {
+  pub budget: Arc<FrameBudget>,
+  pub codecs: Arc<[Codec]>,
+  pub mode: DecodeMode,
+}
+
+// the requests of a channel, and how long it waits for the next one before it is dropped
+struct Queue {
+  requests: Receiver<(Vec<u8>, usize)>,
+  idle: Duration,
+}
+
+/// Serves the requests of a multiplexed connection, until it is closed.
+/// The queries are decoded with the codecs the service enables, on top of binary. Without `auth`,
+/// the connection never gets larger frames.
+pub async fn serve_connection<S>(
+  stream: TcpStream,
+  service: Arc<S>,
+  limits: FrameLimits,
+  channel_limits: ChannelLimits,
+  auth: Option<Arc<MuxAuth>>,
+  decoding: Decoding,
+) -> anyhow::Result<()>
+where
+  S: Service + Send + Sync + 'static,
+{
+  let peer = stream.peer_addr()?;
+  let writer = Arc::new(Mutex::new(stream.clone()));
+  let mut reader = stream;
+  let mut channels: HashMap<u128, Sender<(Vec<u8>, usize)>> = HashMap::new();
+  let mut handshake = auth.as_ref().map(|auth| auth.handshake(peer));
+  // once a client proved who it is on the connection
+  let mut authenticated = false;
+  let mut max = limits.anonymous;
+  loop {
+    let (channel, payload) = match read_frame(&mut reader, max).await {
+      Ok(frame) => frame,
+      Err(rr) => {
+        log::debug!("Connection from {} closed: {}", peer, rr);
+        return Ok(());
+      }
+    };
+    if channel == AUTH_CHANNEL {
+      let reply = match (&auth, &mut handshake) {
+        (Some(auth), Some(handshake)) => {
+          let reply = auth.step(peer, handshake, payload);
+          if let Some(user) = handshake.authenticated() {
+            log::debug!("{} authenticated as {}", peer, user);
+            authenticated = true;
+          }
+          reply
+        }
+        _ => frame(Err(TransportError::Refused)),
+      };
+      write_frame(&mut *writer.lock().await, AUTH_CHANNEL, &reply).await?;
+      continue;
+    }
+    if channel == CONTROL_CHANNEL {
+      // a malformed request keeps the current maximum
+      if let Ok(requested) = decode::u128(&mut Cursor::new(payload)) {
+        max = limits.grant(requested, authenticated);
+      }
+      log::debug!("Largest frame from {} is now {} bytes", peer, max);
+      let mut granted = Vec::new();
+      encode::u128(&mut granted, max as u128)?;
+      write_frame(&mut *writer.lock().await, CONTROL_CHANNEL, &granted).await?;
+      continue;
+    }
+    let mut request = (payload, max);
+    loop {
+      if !channels.contains_key(&channel) {
+        // the idle channels make room for the new ones
+        channels.retain(|_, queue| !queue.is_closed());
+        if channels.len() >= channel_limits.channels {
+          log::warn!("Too many channels from {}, refusing {}", peer, channel);
+          let refused = frame(Err(TransportError::RateLimited));
+          write_frame(&mut *writer.lock().await, channel, &refused).await?;
+          break;
+        }
+        let (tx, rx) = bounded(channel_limits.queue.max(1));
+        task::spawn(serve_channel(
+          peer,
+          channel,
+          Queue {
+            requests: rx,
+            idle: channel_limits.idle,
+          },
+          service.clone(),
+          writer.clone(),
+          decoding.clone(),
+        ));
+        channels.insert(channel, tx);
+      }
+      // waits for room in the queue, and starts the channel again if it went idle meanwhile
+      match channels[&channel].send(request).await {
+        Ok(()) => break,
+        Err(SendError(unsent)) => {
+          channels.remove(&channel);
+          request = unsent;
+        }
+      }
+    }
+  }
+}
+
+async fn serve_channel<S: Service + Send + Sync>(
+  peer: SocketAddr,
+  channel: u128,
+  queue: Queue,
+  service: Arc<S>,
+  writer: Arc<Mutex<TcpStream>>,
+  decoding: Decoding,
+) {
+  let Decoding {
+    budget,
+    codecs,
+    mode,
+  } = decoding;
+  let Queue { requests, idle } = queue;
+  loop {
+    let (payload, max_size) = match timeout(idle, requests.recv()).await {
+      Ok(Ok(request)) => request,
+      // the connection is closed
+      Ok(Err(_)) => return,
+      // idle: the channel takes no more requests, but answers the ones already queued
+      Err(_) => {
+        requests.close();
+        match requests.try_recv() {
+          Ok(request) => request,
+          Err(_) => return,
+        }
+      }
+    };
+    let size = payload.len();
+    let query = budget.decode(peer.ip(), || {
+      mode::within(mode, || codec::query(payload, &codecs))
+    });
+    let reply = match query {
+      Err(rr) => {
+        log::error!("Could not decode message from {}/{}: {}", peer, channel, rr);
+        if rr.is::<BudgetExceeded>() {
+          let count = budget.violations(peer.ip());
+          log::warn!("{} went over its decode budget {} times", peer.ip(), count);
+        }
+        frame(Err(TransportError::Malformed))
+      }
+      Ok((codec, query)) => match service
+        .call(Request {
+          peer,
+          size,
+          max_size: Some(max_size),
+          codec,
+          query,
+        })
+        .await
+      {
+        Ok(rsp) => frame(Ok(&rsp.reply)),
+        Err(rr) => {
+          log::error!(
+            "Error when handling message from {}/{}: {}",
+            peer,
+            channel,
+            rr
+          );
+          frame(Err(TransportError::of(&rr)))
+        }
+      },
+    };
+    if let Err(rr) = write_frame(&mut *writer.lock().await, channel, &reply).await {
+      log::error!("Error when sending message to {}: {}", peer, rr);
+      return;
+    }
+  }
+}
+
+/// The client side of a multiplexed connection.
+pub struct MuxConnection {
+  writer: Mutex<TcpStream>,
+  channels: Arc<Mutex<HashMap<u128, Sender<Vec<u8>>>>>,
+  next: AtomicU64,
+}
+
+impl MuxConnection {
+  pub async fn connect(target: SocketAddr) -> anyhow::Result<Self> {
+    let stream = TcpStream::connect(target).await?;
+    let channels: Arc<Mutex<HashMap<u128, Sender<Vec<u8>>>>> = Arc::default();
+    let dispatch = channels.clone();
+    let mut reader = stream.clone();
+    task::spawn(async move {
+      while let Ok((channel, payload)) = read_frame(&mut reader, MAX_FRAME).await {
+        if let Some(tx) = dispatch.lock().await.get(&channel) {
+          let _ = tx.send(payload).await;
+        }
+      }
+      // closing the channels wakes up everyone waiting for a reply
+      dispatch.lock().await.clear();
+    });
+    Ok(MuxConnection {
+      writer: Mutex::new(stream),
+      channels,
+      next: AtomicU64::new(0),
+    })
+  }
+
+  /// proves that the connection is used by `user`, with the secret it shares with the server
+  pub async fn authenticate(
+    &self,
+    user: ClientId,
+    secret: Vec<u8>,
+    rng: &dyn Rng,
+  ) -> anyhow::Result<()> {
+    let (tx, rx) = unbounded();
+    self.channels.lock().await.insert(AUTH_CHANNEL, tx);
+    let client = ClientHandshake::new(user, secret, rng);
+    let exchange = async {
+      let challenge = self.auth_step(&client.hello(), &rx, decode::auth).await?;
+      let response = client.respond(&challenge)?;
+      self.auth_step(&response, &rx, |_| Ok(())).await
+    };
+    let done = exchange.await;
+    self.channels.lock().await.remove(&AUTH_CHANNEL);
+    done
+  }
+
+  async fn auth_step<X, F>(
+    &self,
+    msg: &AuthMessage,
+    replies: &Receiver<Vec<u8>>,
+    f: F,
+  ) -> anyhow::Result<X>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let mut payload = Vec::new();
+    encode::auth(&mut payload, msg)?;
+    write_frame(&mut *self.writer.lock().await, AUTH_CHANNEL, &payload).await?;
+    decode::frame(&mut Cursor::new(replies.recv().await?), f)
+  }
+
+  /// asks for frames up to `max` bytes, and returns the maximum the server granted
+  /// only a connection a client authenticated on gets more than the anonymous maximum
+  pub async fn negotiate(&self, max: usize) -> anyhow::Result<usize> {
+    let (tx, rx) = unbounded();
+    self.channels.lock().await.insert(CONTROL_CHANNEL, tx);
+    let mut requested = Vec::new();
+    encode::u128(&mut requested, max as u128)?;
+    write_frame(&mut *self.writer.lock().await, CONTROL_CHANNEL, &requested).await?;
+    let granted = rx.recv().await;
+    self.channels.lock().await.remove(&CONTROL_CHANNEL);
+    Ok(usize::try_from(decode::u128(&mut Cursor::new(granted?))?)?)
+  }
+
+  /// opens a new channel on this connection
+  pub async fn channel(&self) -> MuxChannel<'_> {
+    let id = self.next.fetch_add(1, Ordering::Relaxed) as u128;
+    let (tx, rx) = unbounded();
+    self.channels.lock().await.insert(id, tx);
+    MuxChannel {
+      conn: self,
+      id,
+      replies: rx,
+    }
+  }
+}
+
+pub struct MuxChannel<'a> {
+  conn: &'a MuxConnection,
+  id: u128,
+  replies: Receiver<Vec<u8>>,
+}
+
+impl MuxChannel<'_> {
+  pub fn id(&self) -> u128 {
+    self.id
+  }
+
+  /// sends a query, and decodes its reply
+  pub async fn query<X, F>(&self, sq: &Sequence<ClientQuery>, f: F) -> anyhow::Result<X>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let mut wr = Cursor::new(Vec::new());
+    encode::sequence(&mut wr, sq, encode::client_query)?;
+    write_frame(
+      &mut *self.conn.writer.lock().await,
+      self.id,
+      &wr.into_inner(),
+    )
+    .await?;
+    let reply = self.replies.recv().await?;
+    decode::frame(&mut Cursor::new(reply), f)
+  }
+}
+
+impl Drop for MuxChannel<'_> {
+  fn drop(&mut self) {
+    if let Some(mut channels) = self.conn.channels.try_lock() {
+      channels.remove(&self.id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use async_std::net::TcpListener;
+
+  use super::*;
+  use crate::auth::AuthPolicy;
+  use crate::client::Client;
+  use crate::clock::system_clock;
+  use crate::core::{DefaultChecker, MessageServer, ServerConfig};
+  use crate::credentials::{Credential, MemoryCredentials};
+  use crate::messages::{ClientMessage, ClientPollReply, ClientReply};
+  use crate::rng::SeededRng;
+  use crate::service::ServerService;
+  use crate::solutions::descamps_femery::Server;
+
+  #[test]
+  fn frames() {
+    async_std::task::block_on(async {
+      let mut buf = Vec::new();
+      write_frame(&mut buf, 300, b"abc").await.unwrap();
+      assert_eq!(buf, [251, 44, 1, 3, 97, 98, 99]);
+      let (channel, payload) = read_frame(&mut &buf[..], MAX_FRAME).await.unwrap();
+      assert_eq!((channel, payload.as_slice()), (300, &b"abc"[..]));
+      assert!(read_frame(&mut &buf[..], 2).await.is_err());
+
+      let mut buf = Vec::new();
+      encode::u128(&mut buf, 0).unwrap();
+      encode::u128(&mut buf, MAX_FRAME as u128 + 1).unwrap();
+      assert!(read_frame(&mut &buf[..], MAX_FRAME).await.is_err());
+    })
+  }
+
+  // a server accepting a single connection
+  async fn listen() -> SocketAddr {
+    listen_with(ChannelLimits::default()).await
+  }
+
+  // the only client with a secret
+  fn user() -> ClientId {
+    ClientId::from(1)
+  }
+
+  async fn listen_with(channel_limits: ChannelLimits) -> SocketAddr {
+    let credentials = MemoryCredentials::new();
+    credentials.insert(user(), Credential::Secret(b"secret".to_vec()));
+    let auth = MuxAuth {
+      server: ServerId::default(),
+      credentials: Arc::new(credentials),
+      throttle: Arc::new(AuthThrottle::new(AuthPolicy::default(), system_clock())),
+      rng: Arc::new(SeededRng::new(1)),
+    };
+    let server: Server<DefaultChecker> = MessageServer::new(
+      DefaultChecker::default(),
+      ServerId::default(),
+      ServerConfig::default(),
+    );
+    let service = Arc::new(ServerService::<_, DefaultChecker>::new(Arc::new(server)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      serve_connection(
+        stream,
+        service,
+        FrameLimits::default(),
+        channel_limits,
+        Some(Arc::new(auth)),
+        Decoding {
+          budget: Arc::default(),
+          codecs: Arc::new([]),
+          mode: DecodeMode::default(),
+        },
+      )
+      .await
+      .unwrap();
+    });
+    addr
+  }
+
+  #[test]
+  fn negotiate() {
+    async_std::task::block_on(async {
+      let limits = FrameLimits::default();
+      let conn = MuxConnection::connect(listen().await).await.unwrap();
+      // anonymous connections can't get larger frames
+      assert_eq!(conn.negotiate(MAX_FRAME).await.unwrap(), limits.anonymous);
+
+      let channel = conn.channel().await;
+      let register = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Register("bot".to_string()),
+      };
+      let mut client = Client::new(channel.query(&register, decode::clientid).await.unwrap());
+      // registering proves nothing, nor does a wrong secret
+      assert_eq!(conn.negotiate(MAX_FRAME).await.unwrap(), limits.anonymous);
+      let rng = SeededRng::new(2);
+      let rr = conn.authenticate(user(), b"guess".to_vec(), &rng).await;
+      assert_eq!(
+        rr.unwrap_err().downcast::<TransportError>().unwrap(),
+        TransportError::AuthRequired
+      );
+      assert_eq!(conn.negotiate(MAX_FRAME).await.unwrap(), limits.anonymous);
+
+      conn
+        .authenticate(user(), b"secret".to_vec(), &rng)
+        .await
+        .unwrap();
+      assert_eq!(conn.negotiate(usize::MAX).await.unwrap(), MAX_FRAME);
+      assert_eq!(conn.negotiate(10).await.unwrap(), limits.anonymous);
+      assert_eq!(conn.negotiate(20000).await.unwrap(), 20000);
+
+      let mut text = |len| {
+        client.sequence(ClientQuery::Message(ClientMessage::Text {
+          dest: client.id(),
+          content: "a".repeat(len),
+        }))
+      };
+      let sq = text(10000);
+      let r = channel.query(&sq, decode::client_replies).await.unwrap();
+      assert!(
+        matches!(r[..], [ClientReply::Delivered(Some(_))]),
+        "{:?}",
+        r
+      );
+      // too large, the connection is closed
+      let sq = text(30000);
+      assert!(channel.query(&sq, decode::client_replies).await.is_err());
+    })
+  }
+
+  #[test]
+  fn many_clients_one_connection() {
+    async_std::task::block_on(async {
+      let addr = listen().await;
+      let conn = MuxConnection::connect(addr).await.unwrap();
+      let mut bots = Vec::new();
+      for i in 0..3 {
+        let channel = conn.channel().await;
+        let register = Sequence {
+          seqid: 0,
+          src: ClientId::default(),
+          content: ClientQuery::Register(format!("bot {}", i)),
+        };
+        let id = channel.query(&register, decode::clientid).await.unwrap();
+        bots.push((channel, Client::new(id)));
+      }
+      let ids: Vec<ClientId> = bots.iter().map(|(_, c)| c.id()).collect();
+
+      // every bot greets the next one, concurrently
+      let sends = bots.iter_mut().enumerate().map(|(i, (channel, client))| {
+        let sq = client.sequence(ClientQuery::Message(ClientMessage::Text {
+          dest: ids[(i + 1) % 3],
+          content: format!("hello from {}", i),
+        }));
+        async move { channel.query(&sq, decode::client_replies).await }
+      });
+      for r in futures::future::join_all(sends).await {
+        let r = r.unwrap();
+        assert!(
+          matches!(r[..], [ClientReply::Delivered(Some(_))]),
+          "{:?}",
+          r
+        );
+      }
+      for (i, (channel, client)) in bots.iter_mut().enumerate() {
+        let sq = client.sequence(ClientQuery::Poll);
+        let reply = channel.query(&sq, decode::client_poll_reply).await.unwrap();
+        assert!(
+          matches!(&reply, ClientPollReply::Message { src, content, .. }
+            if *src == ids[(i + 2) % 3] && *content == format!("hello from {}", (i + 2) % 3)),
+          "{:?}",
+          reply
+        );
+      }
+
+      // a broken request gets an error frame, and the channel keeps working
+      let (channel, client) = &mut bots[0];
+      let stale = Sequence {
+        seqid: 0,
+        src: client.id(),
+        content: ClientQuery::Poll,
+      };
+      let rr = channel
+        .query(&stale, decode::client_poll_reply)
+        .await
+        .unwrap_err();
+      assert_eq!(
+        rr.downcast_ref::<TransportError>(),
+        Some(&TransportError::Replayed)
+      );
+      let sq = client.sequence(ClientQuery::ListUsers);
+      // the system identities are listed along the bots
+      let users = channel.query(&sq, decode::userlist).await.unwrap();
+      assert_eq!(users.len(), 3 + ClientId::SYSTEM.len());
+      assert_eq!(users.get(&ClientId::ADMIN), Some(&"[admin]".to_string()));
+    })
+  }
+
+  #[test]
+  fn channel_limits() {
+    async_std::task::block_on(async {
+      let limits = ChannelLimits {
+        channels: 2,
+        queue: 1,
+        idle: Duration::from_millis(50),
+      };
+      let conn = MuxConnection::connect(listen_with(limits).await)
+        .await
+        .unwrap();
+      let ping = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Ping(7),
+      };
+      let pong = |r: anyhow::Result<Vec<ClientReply>>| r.unwrap() == [ClientReply::Pong(7)];
+      let (first, second, third) = (
+        conn.channel().await,
+        conn.channel().await,
+        conn.channel().await,
+      );
+      assert!(pong(first.query(&ping, decode::client_replies).await));
+      assert!(pong(second.query(&ping, decode::client_replies).await));
+      let rr = third
+        .query(&ping, decode::client_replies)
+        .await
+        .unwrap_err();
+      assert_eq!(
+        rr.downcast_ref::<TransportError>(),
+        Some(&TransportError::RateLimited)
+      );
+
+      // idle channels are dropped, and start again on their next request
+      task::sleep(Duration::from_millis(100)).await;
+      assert!(pong(third.query(&ping, decode::client_replies).await));
+      assert!(pong(first.query(&ping, decode::client_replies).await));
+    })
+  }
+}
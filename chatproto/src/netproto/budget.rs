@@ -0,0 +1,165 @@
+//! limits of the work done decoding a single frame
+//!
+//! A frame within the size limits can still be expensive to decode: deeply nested messages, or
+//! huge numbers of tiny elements. Transports decode each frame `within` a `DecodeBudget`, that
+//! the decoders spend as they go, and the frame is refused with `BudgetExceeded` once it is
+//! spent. Outside of `within`, only the nesting is limited, to the default depth: a deep enough
+//! message would overflow the stack of any decoder.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// how deep messages can be nested by default, and outside of a budget
+pub const DEFAULT_DEPTH: usize = 8;
+
+/// work allowed to decode one frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeBudget {
+  /// messages nested in other messages (wrappers and batches)
+  pub depth: usize,
+  /// elements of all the lists and maps
+  pub elements: usize,
+  /// bytes of all the strings and binary payloads
+  pub bytes: usize,
+}
+
+impl Default for DecodeBudget {
+  fn default() -> Self {
+    DecodeBudget {
+      depth: DEFAULT_DEPTH,
+      elements: 4096,
+      bytes: 64 * 1024,
+    }
+  }
+}
+
+/// the part of the budget a frame went over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetExceeded {
+  Depth,
+  Elements,
+  Bytes,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BudgetExceeded::Depth => "Decode budget exceeded (depth)".fmt(f),
+      BudgetExceeded::Elements => "Decode budget exceeded (elements)".fmt(f),
+      BudgetExceeded::Bytes => "Decode budget exceeded (bytes)".fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+thread_local! {
+  // what is left for the frame being decoded on this thread, decoding is synchronous
+  static LEFT: Cell<Option<DecodeBudget>> = const { Cell::new(None) };
+  // how deep the message being decoded outside of a budget is
+  static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// runs a decoder with a budget
+pub fn within<X>(budget: DecodeBudget, f: impl FnOnce() -> anyhow::Result<X>) -> anyhow::Result<X> {
+  let outer = LEFT.replace(Some(budget));
+  let r = f();
+  LEFT.set(outer);
+  r
+}
+
+fn spend(f: impl FnOnce(&mut DecodeBudget) -> Result<(), BudgetExceeded>) -> anyhow::Result<()> {
+  match LEFT.get() {
+    None => Ok(()),
+    Some(mut left) => {
+      let r = f(&mut left);
+      LEFT.set(Some(left));
+      Ok(r?)
+    }
+  }
+}
+
+fn take(left: &mut usize, n: u128, rr: BudgetExceeded) -> Result<(), BudgetExceeded> {
+  match usize::try_from(n) {
+    Ok(n) if n <= *left => {
+      *left -= n;
+      Ok(())
+    }
+    _ => Err(rr),
+  }
+}
+
+/// `n` elements, charged before decoding them
+pub(crate) fn elements(n: u128) -> anyhow::Result<()> {
+  spend(|left| take(&mut left.elements, n, BudgetExceeded::Elements))
+}
+
+/// `n` bytes, charged before reading them
+pub(crate) fn bytes(n: u128) -> anyhow::Result<()> {
+  spend(|left| take(&mut left.bytes, n, BudgetExceeded::Bytes))
+}
+
+/// decodes a message nested in another one
+pub(crate) fn nested<X>(f: impl FnOnce() -> anyhow::Result<X>) -> anyhow::Result<X> {
+  if LEFT.get().is_none() {
+    let depth = DEPTH.get();
+    if depth >= DEFAULT_DEPTH {
+      return Err(BudgetExceeded::Depth.into());
+    }
+    DEPTH.set(depth + 1);
+    let r = f();
+    DEPTH.set(depth);
+    return r;
+  }
+  spend(|left| take(&mut left.depth, 1, BudgetExceeded::Depth))?;
+  let r = f();
+  spend(|left| {
+    left.depth += 1;
+    Ok(())
+  })?;
+  r
+}
+
+/// The budget of the frames of a transport, and how many frames went over it, per peer address.
+#[derive(Debug, Default)]
+pub struct FrameBudget {
+  budget: DecodeBudget,
+  violations: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl FrameBudget {
+  pub fn new(budget: DecodeBudget) -> Self {
+    FrameBudget {
+      budget,
+      violations: Mutex::default(),
+    }
+  }
+
+  /// decodes a frame from `peer` within the budget, counting the violations
+  pub fn decode<X>(
+    &self,
+    peer: IpAddr,
+    f: impl FnOnce() -> anyhow::Result<X>,
+  ) -> anyhow::Result<X> {
+    let r = within(self.budget, f);
+    if let Err(rr) = &r {
+      if rr.is::<BudgetExceeded>() {
+        *self.violations.lock().unwrap().entry(peer).or_default() += 1;
+      }
+    }
+    r
+  }
+
+  /// frames from `peer` that went over the budget
+  pub fn violations(&self, peer: IpAddr) -> u64 {
+    self
+      .violations
+      .lock()
+      .unwrap()
+      .get(&peer)
+      .copied()
+      .unwrap_or(0)
+  }
+}
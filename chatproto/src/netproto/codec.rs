@@ -0,0 +1,80 @@
+//! the codecs a client can pick for its queries and their replies
+//!
+//! Clients start with the binary codec of `encode` and `decode`, and can ask for another one with
+//! a `ClientQuery::Hello`. Servers decode every query with the codecs they enable, binary first,
+//! and encode its reply with the one it was decoded with, so they keep nothing per client. Only
+//! the payloads change: frames keep their status byte (see `encode::reply_frame`).
+
+use std::io::{Cursor, Read};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::budget::BudgetExceeded;
+use super::decode;
+use super::mode;
+use crate::messages::{ClientQuery, Codec, Sequence};
+
+/// the first of the `offered` codecs that is `enabled`, binary otherwise
+pub fn pick(offered: &[Codec], enabled: &[Codec]) -> Codec {
+  offered
+    .iter()
+    .copied()
+    .find(|c| *c == Codec::Binary || enabled.contains(c))
+    .unwrap_or_default()
+}
+
+/// encodes `value` with `codec`, using `e` for the binary one
+pub fn encode<T, ENC>(codec: Codec, value: &T, e: ENC) -> anyhow::Result<Vec<u8>>
+where
+  T: Serialize + ?Sized,
+  ENC: FnOnce(&mut Cursor<Vec<u8>>, &T) -> std::io::Result<()>,
+{
+  match codec {
+    Codec::Binary => {
+      let mut ocurs = Cursor::new(Vec::new());
+      e(&mut ocurs, value)?;
+      Ok(ocurs.into_inner())
+    }
+    Codec::Json => Ok(serde_json::to_vec(value)?),
+  }
+}
+
+/// decodes the rest of `rd` with `codec`, using `d` for the binary one
+pub fn decode<T, DEC>(codec: Codec, rd: &mut Cursor<Vec<u8>>, d: DEC) -> anyhow::Result<T>
+where
+  T: DeserializeOwned,
+  DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<T>,
+{
+  match codec {
+    Codec::Binary => d(rd),
+    Codec::Json => {
+      let mut rest = Vec::new();
+      rd.read_to_end(&mut rest)?;
+      Ok(serde_json::from_slice(&rest)?)
+    }
+  }
+}
+
+/// decodes a query with the first of the `enabled` codecs (and binary) that can, and returns it
+/// with that codec
+/// the error is the binary one, a frame over its decode budget is not decoded again
+pub fn query(frame: Vec<u8>, enabled: &[Codec]) -> anyhow::Result<(Codec, Sequence<ClientQuery>)> {
+  let mut cursor = Cursor::new(frame);
+  let binary = decode::sequence(&mut cursor, decode::client_query)
+    .and_then(|query| mode::end(&mut cursor).map(|()| query));
+  let rr = match binary {
+    Ok(query) => return Ok((Codec::Binary, query)),
+    Err(rr) if rr.is::<BudgetExceeded>() => return Err(rr),
+    Err(rr) => rr,
+  };
+  for codec in enabled.iter().filter(|c| **c != Codec::Binary) {
+    cursor.set_position(0);
+    if let Ok(query) = decode(*codec, &mut cursor, |_| {
+      unreachable!("binary is tried first")
+    }) {
+      return Ok((*codec, query));
+    }
+  }
+  Err(rr)
+}
@@ -0,0 +1,346 @@
+//! human readable frames, to see what goes on the wire
+//!
+//! `pretty` prints an encoded frame as a hex dump followed by its decoded structure. Replies carry
+//! nothing about the query they answer, so they can only be decoded with it (`Frame::Reply`).
+//!
+//! `dissector` writes a Wireshark dissector in Lua for the client and federation datagrams. It
+//! names the sequence header, the variant tags of `QUERIES`, `CLIENT_MESSAGES` and
+//! `SERVER_MESSAGES`, and the `TRANSPORT_ERRORS` of the reply frames. These tables follow `encode`,
+//! the tests check them against the message definitions.
+
+use std::fmt::{Debug, Write};
+use std::io::Cursor;
+
+use serde::de::DeserializeOwned;
+
+use super::budget::{self, DecodeBudget};
+use super::{codec, decode};
+use crate::messages::{ClientQuery, Codec};
+
+/// the frames that can be decoded on their own, or with the query they answer
+#[derive(Clone, Copy, Debug)]
+pub enum Frame<'a> {
+  /// a client datagram, or the payload of a mux request, in any codec
+  Query,
+  /// the reply frame to `query`, that was sent with `codec`
+  Reply(Codec, &'a ClientQuery),
+  /// a datagram between servers
+  Server,
+}
+
+/// the tag of each `ClientQuery`
+pub const QUERIES: &[(u8, &str)] = &[
+  (0, "Register"),
+  (1, "Message"),
+  (2, "Poll"),
+  (3, "ListUsers"),
+  (4, "Report"),
+  (5, "GetPrefs"),
+  (6, "SetPrefs"),
+  (7, "RegisterGuest"),
+  (8, "Upgrade"),
+  (9, "CreateRoom"),
+  (10, "JoinRoom"),
+  (11, "LeaveRoom"),
+  (12, "Unregister"),
+  (13, "Ping"),
+  (14, "PollN"),
+  (15, "SetBlocked"),
+  (16, "RegisterTrusted"),
+  (17, "Rename"),
+  (18, "LookupUser"),
+  (19, "ListUserEntries"),
+  (20, "ListUsersPage"),
+  (21, "Search"),
+  (22, "PollWait"),
+  (23, "Sync"),
+  (24, "Hello"),
+  (25, "RegisterInvited"),
+];
+
+/// the tag of each `ClientMessage`, after the one of `ClientQuery::Message`
+pub const CLIENT_MESSAGES: &[(u8, &str)] = &[
+  (0, "Text"),
+  (1, "MText"),
+  (2, "Rich"),
+  (3, "RoomText"),
+  (4, "Ack"),
+  (5, "SetPresence"),
+  (6, "SubscribePresence"),
+  (7, "Broadcast"),
+  (8, "Event"),
+  (9, "Prioritized"),
+  (10, "WithId"),
+  (11, "Edit"),
+  (12, "Delete"),
+  (13, "Data"),
+  (14, "KeyAgreement"),
+  (15, "ReplyTo"),
+  (16, "React"),
+  (17, "SetDoNotDisturb"),
+  (18, "AtomicMText"),
+];
+
+/// the tag of each `ServerMessage`
+pub const SERVER_MESSAGES: &[(u8, &str)] = &[
+  (0, "Announce"),
+  (1, "Message"),
+  (2, "Batch"),
+  (3, "Receipt"),
+  (4, "Presence"),
+  (5, "Withdraw"),
+  (6, "Event"),
+  (7, "Delivered"),
+  (8, "Resume"),
+  (9, "Session"),
+  (10, "KeyAgreement"),
+  (11, "Reaction"),
+  (12, "RoomMembers"),
+  (13, "RoomText"),
+];
+
+/// the tag of each `TransportError`, after the status byte of an error frame
+pub const TRANSPORT_ERRORS: &[(u8, &str)] = &[
+  (0, "Malformed"),
+  (1, "AuthRequired"),
+  (2, "RateLimited"),
+  (3, "TooLarge"),
+  (4, "Refused"),
+  (5, "Replayed"),
+  (6, "SequenceReuse"),
+];
+
+/// decodes `bytes` as a `frame`, the error frames of replies are returned as `TransportError`
+/// queries come with the codec they were decoded with
+///
+/// The bytes are untrusted: they are decoded within the default `DecodeBudget`, like the frames
+/// of a transport.
+pub fn decode(frame: Frame, bytes: &[u8]) -> anyhow::Result<Box<dyn Debug>> {
+  let mut rd = Cursor::new(bytes.to_vec());
+  budget::within(DecodeBudget::default(), || match frame {
+    Frame::Query => Ok(Box::new(codec::query(bytes.to_vec(), &[Codec::Json])?) as Box<dyn Debug>),
+    Frame::Reply(codec, query) => decode::frame(&mut rd, |rd| reply(codec, query, rd)),
+    Frame::Server => Ok(Box::new(decode::server_sequence(&mut rd, decode::server)?)),
+  })
+}
+
+/// the payload of a reply to `query`, decoded like `ChatClient` does
+fn reply(
+  codec: Codec,
+  query: &ClientQuery,
+  rd: &mut Cursor<Vec<u8>>,
+) -> anyhow::Result<Box<dyn Debug>> {
+  match query {
+    ClientQuery::Hello(_) => boxed(codec, rd, decode::codec),
+    ClientQuery::Register(_)
+    | ClientQuery::RegisterGuest(_)
+    | ClientQuery::RegisterInvited { .. } => boxed(codec, rd, decode::clientid),
+    ClientQuery::Poll | ClientQuery::PollWait(_) => boxed(codec, rd, decode::client_poll_reply),
+    ClientQuery::PollN(_) => boxed(codec, rd, decode::client_poll_replies),
+    ClientQuery::Sync { .. } => boxed(codec, rd, decode::sync_reply),
+    ClientQuery::ListUsers => boxed(codec, rd, decode::userlist),
+    ClientQuery::ListUserEntries => boxed(codec, rd, decode::user_entries),
+    ClientQuery::ListUsersPage(_) => boxed(codec, rd, decode::user_page),
+    ClientQuery::Search(_) => boxed(codec, rd, decode::search_reply),
+    ClientQuery::LookupUser(_) => boxed(codec, rd, decode::user_lookup),
+    ClientQuery::GetPrefs => boxed(codec, rd, decode::notification_prefs),
+    ClientQuery::CreateRoom(_) => boxed(codec, rd, decode::roomid),
+    ClientQuery::Message(_)
+    | ClientQuery::Report { .. }
+    | ClientQuery::SetPrefs(_)
+    | ClientQuery::Upgrade
+    | ClientQuery::JoinRoom(_)
+    | ClientQuery::LeaveRoom(_)
+    | ClientQuery::Unregister
+    | ClientQuery::Ping(_)
+    | ClientQuery::SetBlocked(_)
+    | ClientQuery::RegisterTrusted { .. }
+    | ClientQuery::Rename(_) => boxed(codec, rd, decode::client_replies),
+  }
+}
+
+fn boxed<T, DEC>(codec: Codec, rd: &mut Cursor<Vec<u8>>, d: DEC) -> anyhow::Result<Box<dyn Debug>>
+where
+  T: DeserializeOwned + Debug + 'static,
+  DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<T>,
+{
+  Ok(Box::new(codec::decode(codec, rd, d)?))
+}
+
+/// 16 bytes per line, with their offset and as ASCII
+pub fn hexdump(bytes: &[u8]) -> String {
+  let mut out = String::new();
+  for (line, chunk) in bytes.chunks(16).enumerate() {
+    let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+    let ascii: String = chunk
+      .iter()
+      .map(|&b| {
+        if b.is_ascii_graphic() || b == b' ' {
+          b as char
+        } else {
+          '.'
+        }
+      })
+      .collect();
+    let _ = writeln!(out, "{:04x}  {:<47}  |{}|", line * 16, hex.join(" "), ascii);
+  }
+  out
+}
+
+/// the hex dump of `bytes`, followed by the `frame` they decode to, or why they don't
+pub fn pretty(frame: Frame, bytes: &[u8]) -> String {
+  let decoded = match decode(frame, bytes) {
+    Ok(decoded) => format!("{:#?}", decoded),
+    Err(rr) => format!("undecodable: {}", rr),
+  };
+  format!("{}{}", hexdump(bytes), decoded)
+}
+
+fn lua_table(out: &mut String, name: &str, tags: &[(u8, &str)]) {
+  let _ = writeln!(out, "local {} = {{", name);
+  for (tag, variant) in tags {
+    let _ = writeln!(out, "  [{}] = \"{}\",", tag, variant);
+  }
+  let _ = writeln!(out, "}}\n");
+}
+
+/// a Wireshark dissector for the client datagrams on `client_port` (queries and their replies)
+/// and the federation datagrams on `server_port`, to save in the Wireshark plugin directory
+pub fn dissector(client_port: u16, server_port: u16) -> String {
+  let mut out = String::new();
+  out.push_str("-- generated by chatproto::netproto::debug::dissector, edit it there\n\n");
+  let _ = writeln!(out, "local client_port = {}", client_port);
+  let _ = writeln!(out, "local server_port = {}\n", server_port);
+  lua_table(&mut out, "queries", QUERIES);
+  lua_table(&mut out, "client_messages", CLIENT_MESSAGES);
+  lua_table(&mut out, "server_messages", SERVER_MESSAGES);
+  lua_table(&mut out, "transport_errors", TRANSPORT_ERRORS);
+  out.push_str(DISSECTOR);
+  out
+}
+
+const DISSECTOR: &str = r#"local statuses = { [0] = "Ok", [1] = "Error" }
+
+local chat = Proto("chat", "Chat client protocol")
+chat.fields.seqid = ProtoField.string("chat.seqid", "Sequence number")
+chat.fields.src = ProtoField.guid("chat.src", "Client")
+chat.fields.query = ProtoField.uint8("chat.query", "Query", base.DEC, queries)
+chat.fields.message = ProtoField.uint8("chat.message", "Message", base.DEC, client_messages)
+chat.fields.text = ProtoField.string("chat.text", "Text codec")
+chat.fields.status = ProtoField.uint8("chat.status", "Status", base.DEC, statuses)
+chat.fields.error = ProtoField.uint8("chat.error", "Transport error", base.DEC, transport_errors)
+chat.fields.payload = ProtoField.bytes("chat.payload", "Payload")
+
+local chatfed = Proto("chatfed", "Chat federation protocol")
+chatfed.fields.seqid = ProtoField.string("chatfed.seqid", "Sequence number")
+chatfed.fields.src = ProtoField.guid("chatfed.src", "Server")
+chatfed.fields.message = ProtoField.uint8("chatfed.message", "Message", base.DEC, server_messages)
+chatfed.fields.payload = ProtoField.bytes("chatfed.payload", "Payload")
+
+-- a u128: below 251 in a byte, else a prefix and 2, 4, 8 or 16 bytes, little endian
+local varint_sizes = { [251] = 2, [252] = 4, [253] = 8, [254] = 16 }
+
+local function varint(tvb, offset)
+  local prefix = tvb(offset, 1):uint()
+  if prefix < 251 then
+    return tostring(prefix), 1
+  end
+  local size = varint_sizes[prefix]
+  if size == nil or tvb:len() < offset + 1 + size then
+    return nil, 0
+  end
+  local value = tvb(offset + 1, size)
+  if size == 16 then
+    return value:bytes():tohex() .. " (little endian)", 1 + size
+  elseif size == 8 then
+    return tostring(value:le_uint64()), 1 + size
+  end
+  return tostring(value:le_uint()), 1 + size
+end
+
+-- the sequence number and the uuid of the sender, returns where the content starts
+local function header(tvb, tree, fields)
+  local seqid, size = varint(tvb, 0)
+  if seqid == nil or tvb:len() < size + 18 or tvb(size, 1):uint() ~= 16 then
+    return nil
+  end
+  tree:add(fields.seqid, tvb(0, size), seqid)
+  tree:add(fields.src, tvb(size + 1, 16))
+  return size + 17
+end
+
+local function query(tvb, pinfo, tree)
+  local offset = header(tvb, tree, chat.fields)
+  if offset == nil then
+    -- not binary, the other codecs are text
+    tree:add(chat.fields.text, tvb())
+    pinfo.cols.info = "Query (text)"
+    return
+  end
+  local tag = tvb(offset, 1):uint()
+  tree:add(chat.fields.query, tvb(offset, 1))
+  local info = queries[tag] or "Unknown query"
+  offset = offset + 1
+  if tag == 1 and offset < tvb:len() then
+    tree:add(chat.fields.message, tvb(offset, 1))
+    info = info .. " " .. (client_messages[tvb(offset, 1):uint()] or "unknown")
+    offset = offset + 1
+  end
+  if offset < tvb:len() then
+    tree:add(chat.fields.payload, tvb(offset))
+  end
+  pinfo.cols.info = info
+end
+
+-- the payload depends on the query, see netproto::debug::pretty
+local function reply(tvb, pinfo, tree)
+  local status = tvb(0, 1):uint()
+  tree:add(chat.fields.status, tvb(0, 1))
+  if status == 1 and tvb:len() > 1 then
+    tree:add(chat.fields.error, tvb(1, 1))
+    pinfo.cols.info = "Error " .. (transport_errors[tvb(1, 1):uint()] or "unknown")
+  else
+    if tvb:len() > 1 then
+      tree:add(chat.fields.payload, tvb(1))
+    end
+    pinfo.cols.info = "Reply " .. (statuses[status] or "unknown")
+  end
+end
+
+function chat.dissector(tvb, pinfo, tree)
+  if tvb:len() == 0 then
+    return 0
+  end
+  pinfo.cols.protocol = chat.name
+  local subtree = tree:add(chat, tvb())
+  if pinfo.dst_port == client_port then
+    query(tvb, pinfo, subtree)
+  else
+    reply(tvb, pinfo, subtree)
+  end
+  return tvb:len()
+end
+
+function chatfed.dissector(tvb, pinfo, tree)
+  if tvb:len() == 0 then
+    return 0
+  end
+  pinfo.cols.protocol = chatfed.name
+  local subtree = tree:add(chatfed, tvb())
+  local offset = header(tvb, subtree, chatfed.fields)
+  if offset == nil then
+    pinfo.cols.info = "Malformed"
+    return tvb:len()
+  end
+  subtree:add(chatfed.fields.message, tvb(offset, 1))
+  pinfo.cols.info = server_messages[tvb(offset, 1):uint()] or "Unknown message"
+  if offset + 1 < tvb:len() then
+    subtree:add(chatfed.fields.payload, tvb(offset + 1))
+  end
+  return tvb:len()
+end
+
+local udp = DissectorTable.get("udp.port")
+udp:add(client_port, chat)
+udp:add(server_port, chatfed)
+"#;
@@ -4,9 +4,15 @@ use anyhow::Ok;
 use byteorder::{LittleEndian, ReadBytesExt};
 use uuid::Uuid;
 
+use super::budget;
+use super::ext::Extension;
+use super::mode;
 use crate::messages::{
   AuthMessage, ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply,
-  DelayedError, FullyQualifiedMessage, Sequence, ServerId, ServerMessage,
+  Codec, ContentType, DelayedError, Event, FullyQualifiedMessage, HistoryEntry, Mention, MessageId,
+  NameFilter, NextHop, NotificationPrefs, Presence, Priority, QuietHours, ReportTarget,
+  RichContent, RoomId, SearchHit, SearchPage, SearchQuery, Sequence, ServerId, ServerMessage,
+  ServerSequence, SyncCursor, SyncReply, TransportError, UserEntry, UserPage, UserQuery,
 };
 
 // look at the README.md for guidance on writing this function
@@ -17,26 +23,26 @@ pub fn u128<R: Read>(rd: &mut R) -> anyhow::Result<u128> {
     0..=250 => Ok(prefix as u128),
     251 => {
       let value = rd.read_u16::<LittleEndian>()?;
-      Ok(value as u128)
+      mode::varint(value as u128, 251)
     }
     252 => {
       let value = rd.read_u32::<LittleEndian>()?;
-      Ok(value as u128)
+      mode::varint(value as u128, 1 << 16)
     }
     253 => {
       let value = rd.read_u64::<LittleEndian>()?;
-      Ok(value as u128)
+      mode::varint(value as u128, 1 << 32)
     }
     254 => {
       let value = rd.read_u128::<LittleEndian>()?;
-      Ok(value)
+      mode::varint(value, 1 << 64)
     }
     _ => Err(anyhow::anyhow!("Invalid prefix byte for u128 encoding")),
   }
 }
 
 fn uuid<R: Read>(rd: &mut R) -> anyhow::Result<Uuid> {
-  if rd.read_u8().unwrap() == 16 {
+  if rd.read_u8()? == 16 {
     let mut buffer = [0; 16];
     rd.read_exact(&mut buffer)?;
     Ok(Uuid::from_bytes(buffer))
@@ -55,11 +61,44 @@ pub fn serverid<R: Read>(rd: &mut R) -> anyhow::Result<ServerId> {
   Ok(ServerId(uuid(rd)?))
 }
 
+pub fn messageid<R: Read>(rd: &mut R) -> anyhow::Result<MessageId> {
+  Ok(MessageId(uuid(rd)?))
+}
+
+pub fn option_messageid<R: Read>(rd: &mut R) -> anyhow::Result<Option<MessageId>> {
+  match rd.read_u8()? {
+    0 => Ok(None),
+    1 => Ok(Some(messageid(rd)?)),
+    _ => Err(anyhow::anyhow!("Invalid Option<MessageId>")),
+  }
+}
+
+pub fn roomid<R: Read>(rd: &mut R) -> anyhow::Result<RoomId> {
+  Ok(RoomId(uuid(rd)?))
+}
+
 pub fn string<R: Read>(rd: &mut R) -> anyhow::Result<String> {
-  let size = u128(rd)? as usize;
-  let mut buf = vec![0u8; size];
-  rd.read_exact(&mut buf)?;
-  Ok(String::from_utf8(buf)?)
+  Ok(String::from_utf8(bytes(rd)?)?)
+}
+
+// the number of elements of a list or map
+fn count<R: Read>(rd: &mut R) -> anyhow::Result<usize> {
+  let n = u128(rd)?;
+  budget::elements(n)?;
+  Ok(usize::try_from(n)?)
+}
+
+pub fn bytes<R: Read>(rd: &mut R) -> anyhow::Result<Vec<u8>> {
+  let size = u128(rd)?;
+  budget::bytes(size)?;
+  // the size comes from the wire: the buffer only grows with the bytes actually there, so that
+  // decoding never allocates more than the frame it reads
+  let mut buf = Vec::new();
+  rd.take(u64::try_from(size)?).read_to_end(&mut buf)?;
+  if buf.len() as u128 != size {
+    anyhow::bail!("Truncated bytes ({} of {})", buf.len(), size);
+  }
+  Ok(buf)
 }
 
 pub fn auth<R: Read>(rd: &mut R) -> anyhow::Result<AuthMessage> {
@@ -82,6 +121,18 @@ pub fn auth<R: Read>(rd: &mut R) -> anyhow::Result<AuthMessage> {
       rd.read_exact(&mut response)?;
       Ok(AuthMessage::Auth { response })
     }
+    3 => {
+      let server = serverid(rd)?;
+      let mut nonce = [0u8; 8];
+      rd.read_exact(&mut nonce)?;
+      let mut channel = [0u8; 16];
+      rd.read_exact(&mut channel)?;
+      Ok(AuthMessage::BoundNonce {
+        server,
+        nonce,
+        channel,
+      })
+    }
     _ => Err(anyhow::anyhow!("Invalid AuthMessage")),
   }
 }
@@ -90,12 +141,12 @@ pub fn server<R: Read>(rd: &mut R) -> anyhow::Result<ServerMessage> {
   let variant = rd.read_u8()?;
   match variant {
     0 => {
-      let nb_routes = u128(rd)? as usize;
+      let nb_routes = count(rd)?;
       let mut route = Vec::new();
       for _ in 0..nb_routes {
         route.push(serverid(rd)?);
       }
-      let nb_clients = u128(rd)? as usize;
+      let nb_clients = count(rd)?;
       let mut clients = HashMap::new();
       for _ in 0..nb_clients {
         clients.insert(clientid(rd)?, string(rd)?);
@@ -106,24 +157,200 @@ pub fn server<R: Read>(rd: &mut R) -> anyhow::Result<ServerMessage> {
       let src = clientid(rd)?;
       let srcsrv = serverid(rd)?;
 
-      let nb_dsts = u128(rd)? as usize;
+      let nb_dsts = count(rd)?;
       let mut dsts = Vec::new();
       for _ in 0..nb_dsts {
         dsts.push((clientid(rd)?, serverid(rd)?));
       }
 
       let content = string(rd)?;
-      Ok(ServerMessage::Message(FullyQualifiedMessage {
+      let content_type = content_type(rd)?;
+      let mentions = mentions(rd)?;
+      let seq = u128(rd)?;
+      let in_reply_to = option_messageid(rd)?;
+      let timestamp = u64::try_from(u128(rd)?)?;
+      let id = messageid(rd)?;
+      let message = FullyQualifiedMessage {
         src,
         srcsrv,
         dsts,
         content,
-      }))
+        content_type,
+        mentions,
+        seq,
+        in_reply_to,
+        timestamp,
+        id,
+      };
+      message.validate()?;
+      Ok(ServerMessage::Message(message))
+    }
+    2 => {
+      let nb_messages = count(rd)?;
+      let mut messages = Vec::new();
+      for _ in 0..nb_messages {
+        messages.push(budget::nested(|| server(rd))?);
+      }
+      Ok(ServerMessage::Batch(messages))
+    }
+    3 => Ok(ServerMessage::Receipt {
+      id: messageid(rd)?,
+      reader: clientid(rd)?,
+      dst: clientid(rd)?,
+      dstsrv: serverid(rd)?,
+    }),
+    4 => Ok(ServerMessage::Presence {
+      client: clientid(rd)?,
+      presence: presence(rd)?,
+    }),
+    5 => {
+      let srv = serverid(rd)?;
+      let nb_clients = count(rd)?;
+      let mut clients = Vec::new();
+      for _ in 0..nb_clients {
+        clients.push(clientid(rd)?);
+      }
+      Ok(ServerMessage::Withdraw { srv, clients })
+    }
+    6 => Ok(ServerMessage::Event {
+      src: clientid(rd)?,
+      dst: clientid(rd)?,
+      dstsrv: serverid(rd)?,
+      event: event(rd)?,
+    }),
+    7 => Ok(ServerMessage::Delivered {
+      id: messageid(rd)?,
+      src: clientid(rd)?,
+      srcsrv: serverid(rd)?,
+      dst: clientid(rd)?,
+    }),
+    8 => Ok(ServerMessage::Resume { token: u128(rd)? }),
+    9 => Ok(ServerMessage::Session {
+      token: u128(rd)?,
+      seqid: u128(rd)?,
+    }),
+    10 => Ok(ServerMessage::KeyAgreement {
+      src: clientid(rd)?,
+      dst: clientid(rd)?,
+      dstsrv: serverid(rd)?,
+      payload: bytes(rd)?,
+    }),
+    11 => Ok(ServerMessage::Reaction {
+      id: messageid(rd)?,
+      reactor: clientid(rd)?,
+      dst: clientid(rd)?,
+      dstsrv: serverid(rd)?,
+      emoji: string(rd)?,
+    }),
+    12 => {
+      let room = roomid(rd)?;
+      let srv = serverid(rd)?;
+      let version = u64::try_from(u128(rd)?)?;
+      let nb_members = count(rd)?;
+      let mut members = Vec::new();
+      for _ in 0..nb_members {
+        members.push(clientid(rd)?);
+      }
+      Ok(ServerMessage::RoomMembers {
+        room,
+        srv,
+        version,
+        members,
+      })
+    }
+    13 => {
+      let room = roomid(rd)?;
+      let src = clientid(rd)?;
+      let srcsrv = serverid(rd)?;
+      let nb_dstsrvs = count(rd)?;
+      let mut dstsrvs = Vec::new();
+      for _ in 0..nb_dstsrvs {
+        dstsrvs.push(serverid(rd)?);
+      }
+      Ok(ServerMessage::RoomText {
+        room,
+        src,
+        srcsrv,
+        dstsrvs,
+        content: string(rd)?,
+      })
     }
     _ => Err(anyhow::anyhow!("Invalid ServerMessage")),
   }
 }
 
+pub fn content_type<R: Read>(rd: &mut R) -> anyhow::Result<ContentType> {
+  let variant = rd.read_u8()?;
+  match variant {
+    0 => Ok(ContentType::Plain),
+    1 => Ok(ContentType::Markdown),
+    2 => Ok(ContentType::Custom(string(rd)?)),
+    _ => Err(anyhow::anyhow!("Invalid ContentType")),
+  }
+}
+
+pub fn rich_content<R: Read>(rd: &mut R) -> anyhow::Result<RichContent> {
+  let text = string(rd)?;
+  let mentions = mentions(rd)?;
+  let content_type = content_type(rd)?;
+  let content = RichContent {
+    text,
+    mentions,
+    content_type,
+  };
+  if !content.is_valid() {
+    return Err(anyhow::anyhow!("Invalid mention span"));
+  }
+  Ok(content)
+}
+
+pub fn mentions<R: Read>(rd: &mut R) -> anyhow::Result<Vec<Mention>> {
+  let nb_mentions = count(rd)?;
+  let mut mentions = Vec::new();
+  for _ in 0..nb_mentions {
+    let client = clientid(rd)?;
+    let start = u128(rd)? as usize;
+    let len = u128(rd)? as usize;
+    mentions.push(Mention { client, start, len });
+  }
+  Ok(mentions)
+}
+
+pub fn presence<R: Read>(rd: &mut R) -> anyhow::Result<Presence> {
+  match rd.read_u8()? {
+    0 => Ok(Presence::Online),
+    1 => Ok(Presence::Away),
+    2 => Ok(Presence::Offline),
+    _ => Err(anyhow::anyhow!("Invalid Presence")),
+  }
+}
+
+pub fn event<R: Read>(rd: &mut R) -> anyhow::Result<Event> {
+  match rd.read_u8()? {
+    0 => Ok(Event::Typing),
+    1 => Ok(Event::StoppedTyping),
+    2 => Ok(Event::Mentioned(messageid(rd)?)),
+    _ => Err(anyhow::anyhow!("Invalid Event")),
+  }
+}
+
+pub fn priority<R: Read>(rd: &mut R) -> anyhow::Result<Priority> {
+  match rd.read_u8()? {
+    0 => Ok(Priority::System),
+    1 => Ok(Priority::High),
+    2 => Ok(Priority::Normal),
+    _ => Err(anyhow::anyhow!("Invalid Priority")),
+  }
+}
+
+pub fn codec<R: Read>(rd: &mut R) -> anyhow::Result<Codec> {
+  match rd.read_u8()? {
+    0 => Ok(Codec::Binary),
+    1 => Ok(Codec::Json),
+    _ => Err(anyhow::anyhow!("Invalid Codec")),
+  }
+}
+
 pub fn client<R: Read>(rd: &mut R) -> anyhow::Result<ClientMessage> {
   let variant = rd.read_u8()?;
   match variant {
@@ -133,7 +360,7 @@ pub fn client<R: Read>(rd: &mut R) -> anyhow::Result<ClientMessage> {
       Ok(ClientMessage::Text { dest, content })
     }
     1 => {
-      let nb_dest = u128(rd)? as usize;
+      let nb_dest = count(rd)?;
       let mut dest = Vec::new();
       for _ in 0..nb_dest {
         dest.push(clientid(rd)?);
@@ -141,63 +368,242 @@ pub fn client<R: Read>(rd: &mut R) -> anyhow::Result<ClientMessage> {
       let content = string(rd)?;
       Ok(ClientMessage::MText { dest, content })
     }
+    2 => {
+      let nb_dest = count(rd)?;
+      let mut dest = Vec::new();
+      for _ in 0..nb_dest {
+        dest.push(clientid(rd)?);
+      }
+      let content = rich_content(rd)?;
+      Ok(ClientMessage::Rich { dest, content })
+    }
+    3 => {
+      let room = roomid(rd)?;
+      let content = string(rd)?;
+      Ok(ClientMessage::RoomText { room, content })
+    }
+    4 => Ok(ClientMessage::Ack(messageid(rd)?)),
+    5 => Ok(ClientMessage::SetPresence(presence(rd)?)),
+    6 => Ok(ClientMessage::SubscribePresence(clientid(rd)?)),
+    7 => Ok(ClientMessage::Broadcast {
+      content: string(rd)?,
+    }),
+    8 => Ok(ClientMessage::Event {
+      dest: clientid(rd)?,
+      event: event(rd)?,
+    }),
+    9 => Ok(ClientMessage::Prioritized {
+      priority: priority(rd)?,
+      message: Box::new(budget::nested(|| client(rd))?),
+    }),
+    10 => Ok(ClientMessage::WithId {
+      id: messageid(rd)?,
+      message: Box::new(budget::nested(|| client(rd))?),
+    }),
+    11 => Ok(ClientMessage::Edit {
+      dest: clientid(rd)?,
+      id: messageid(rd)?,
+      content: string(rd)?,
+    }),
+    12 => Ok(ClientMessage::Delete {
+      dest: clientid(rd)?,
+      id: messageid(rd)?,
+    }),
+    13 => Ok(ClientMessage::Data {
+      dest: clientid(rd)?,
+      mime: string(rd)?,
+      bytes: bytes(rd)?,
+    }),
+    14 => Ok(ClientMessage::KeyAgreement {
+      dest: clientid(rd)?,
+      payload: bytes(rd)?,
+    }),
+    15 => Ok(ClientMessage::ReplyTo {
+      in_reply_to: messageid(rd)?,
+      message: Box::new(budget::nested(|| client(rd))?),
+    }),
+    16 => Ok(ClientMessage::React {
+      message: messageid(rd)?,
+      emoji: string(rd)?,
+    }),
+    17 => Ok(ClientMessage::SetDoNotDisturb(bool(rd)?)),
+    18 => {
+      let nb_dest = count(rd)?;
+      let mut dest = Vec::new();
+      for _ in 0..nb_dest {
+        dest.push(clientid(rd)?);
+      }
+      let content = string(rd)?;
+      Ok(ClientMessage::AtomicMText { dest, content })
+    }
     _ => Err(anyhow::anyhow!("Invalid ClientMessage")),
   }
 }
 
-pub fn client_replies<R: Read>(rd: &mut R) -> anyhow::Result<Vec<ClientReply>> {
-  let nb_replies = u128(rd)? as usize;
-  let mut replies = Vec::with_capacity(nb_replies);
+pub fn client_error<R: Read>(rd: &mut R) -> anyhow::Result<ClientError> {
+  let error_variant = rd.read_u8()?;
+  let error = match error_variant {
+    0 => ClientError::UnknownClient,
+    1 => ClientError::BoxFull(clientid(rd)?),
+    2 => ClientError::InternalError,
+    3 => ClientError::ServerBusy,
+    4 => ClientError::SpamDetected,
+    5 => ClientError::Forbidden,
+    6 => ClientError::UnknownRoom(roomid(rd)?),
+    7 => ClientError::UnknownMessage(messageid(rd)?),
+    8 => ClientError::Blocked(clientid(rd)?),
+    9 => ClientError::AlreadyRegistered(clientid(rd)?),
+    10 => ClientError::MessageTooLarge(u128(rd)?),
+    11 => ClientError::RateLimited,
+    12 => ClientError::Replayed(u128(rd)?),
+    13 => ClientError::TooManyDestinations(u128(rd)?),
+    14 => ClientError::NameTaken(clientid(rd)?),
+    15 => ClientError::DelayedQuota(u128(rd)?),
+    16 => ClientError::Banned,
+    17 => ClientError::InvalidInvite,
+    18 => ClientError::SequenceReuse {
+      expected: u128(rd)?,
+      got: u128(rd)?,
+    },
+    _ => return Err(anyhow::anyhow!("Invalid ClientError variant")),
+  };
+  Ok(error)
+}
 
+pub fn client_replies<R: Read>(rd: &mut R) -> anyhow::Result<Vec<ClientReply>> {
+  let nb_replies = count(rd)?;
+  // the count comes from the wire, see `bytes`
+  let mut replies = Vec::new();
   for _ in 0..nb_replies {
-    let variant = rd.read_u8()?;
-    let reply = match variant {
-      0 => ClientReply::Delivered,
-      1 => {
-        let error_variant = rd.read_u8()?;
-        let error = match error_variant {
-          0 => ClientError::UnknownClient,
-          1 => ClientError::BoxFull(clientid(rd)?),
-          2 => ClientError::InternalError,
-          _ => return Err(anyhow::anyhow!("Invalid ClientError variant")),
-        };
-        ClientReply::Error(error)
-      }
-      2 => ClientReply::Delayed,
-      3 => {
-        let server_id = serverid(rd)?;
-        let server_message = server(rd)?;
-        ClientReply::Transfer(server_id, server_message)
-      }
-      _ => return Err(anyhow::anyhow!("Invalid ClientReply variant")),
-    };
-    replies.push(reply);
+    replies.push(client_reply(rd)?);
   }
+  Ok(replies)
+}
 
+pub fn client_reply<R: Read>(rd: &mut R) -> anyhow::Result<ClientReply> {
+  let variant = rd.read_u8()?;
+  client_reply_variant(rd, variant)
+}
+
+fn client_reply_variant<R: Read>(rd: &mut R, variant: u8) -> anyhow::Result<ClientReply> {
+  let reply = match variant {
+    0 => ClientReply::Delivered(option_messageid(rd)?),
+    1 => ClientReply::Error(client_error(rd)?),
+    2 => ClientReply::Delayed(messageid(rd)?),
+    3 => {
+      let nexthop = NextHop(serverid(rd)?);
+      let server_message = server(rd)?;
+      ClientReply::Transfer(nexthop, server_message, option_messageid(rd)?)
+    }
+    4 => ClientReply::Pong(u128(rd)?),
+    5 => {
+      let delivered = u128(rd)?;
+      let nb_failed = count(rd)?;
+      let mut failed = Vec::new();
+      for _ in 0..nb_failed {
+        failed.push((clientid(rd)?, client_error(rd)?));
+      }
+      ClientReply::Broadcast { delivered, failed }
+    }
+    6 => {
+      let nb_replies = count(rd)?;
+      let mut replies = Vec::new();
+      for _ in 0..nb_replies {
+        let client = clientid(rd)?;
+        // refused before it is decoded, so that the nesting stays bounded
+        let variant = rd.read_u8()?;
+        if variant == 6 {
+          anyhow::bail!("Nested Multi reply");
+        }
+        replies.push((client, client_reply_variant(rd, variant)?));
+      }
+      ClientReply::Multi(replies)
+    }
+    _ => return Err(anyhow::anyhow!("Invalid ClientReply variant")),
+  };
+  Ok(reply)
+}
+
+pub fn client_poll_replies<R: Read>(rd: &mut R) -> anyhow::Result<Vec<ClientPollReply>> {
+  let len = u128(rd)?;
+  let mut replies = Vec::new();
+  for _ in 0..len {
+    replies.push(client_poll_reply(rd)?);
+  }
   Ok(replies)
 }
 
 pub fn client_poll_reply<R: Read>(rd: &mut R) -> anyhow::Result<ClientPollReply> {
   let variant = rd.read_u8()?;
   match variant {
-    0 => {
-      let src = clientid(rd)?;
-      let content = string(rd)?;
-      Ok(ClientPollReply::Message { src, content })
-    }
+    0 => Ok(ClientPollReply::Message {
+      src: clientid(rd)?,
+      srcsrv: serverid(rd)?,
+      content: string(rd)?,
+      timestamp: u64::try_from(u128(rd)?)?,
+    }),
     1 => {
-      let delayed_error = clientid(rd)?;
-      Ok(ClientPollReply::DelayedError(
-        DelayedError::UnknownRecipient(delayed_error),
-      ))
+      let delayed_error = match rd.read_u8()? {
+        0 => DelayedError::UnknownRecipient(clientid(rd)?),
+        1 => DelayedError::Expired(clientid(rd)?),
+        2 => DelayedError::Rejected(clientid(rd)?),
+        _ => return Err(anyhow::anyhow!("Invalid DelayedError variant")),
+      };
+      Ok(ClientPollReply::DelayedError(delayed_error))
     }
     2 => Ok(ClientPollReply::Nothing),
+    3 => {
+      let src = clientid(rd)?;
+      let content = rich_content(rd)?;
+      Ok(ClientPollReply::RichMessage { src, content })
+    }
+    4 => {
+      let room = roomid(rd)?;
+      let src = clientid(rd)?;
+      let content = string(rd)?;
+      Ok(ClientPollReply::RoomMessage { room, src, content })
+    }
+    5 => Ok(ClientPollReply::Receipt {
+      id: messageid(rd)?,
+      reader: clientid(rd)?,
+    }),
+    6 => Ok(ClientPollReply::Presence {
+      client: clientid(rd)?,
+      presence: presence(rd)?,
+    }),
+    7 => Ok(ClientPollReply::Event {
+      src: clientid(rd)?,
+      event: event(rd)?,
+    }),
+    8 => Ok(ClientPollReply::Delivered {
+      id: messageid(rd)?,
+      dst: clientid(rd)?,
+    }),
+    9 => Ok(ClientPollReply::Deleted { src: clientid(rd)? }),
+    10 => Ok(ClientPollReply::Data {
+      src: clientid(rd)?,
+      mime: string(rd)?,
+      bytes: bytes(rd)?,
+    }),
+    11 => Ok(ClientPollReply::KeyAgreement {
+      src: clientid(rd)?,
+      payload: bytes(rd)?,
+    }),
+    12 => Ok(ClientPollReply::ReplyTo {
+      in_reply_to: messageid(rd)?,
+      message: Box::new(budget::nested(|| client_poll_reply(rd))?),
+    }),
+    13 => Ok(ClientPollReply::Reaction {
+      id: messageid(rd)?,
+      reactor: clientid(rd)?,
+      emoji: string(rd)?,
+    }),
     _ => Err(anyhow::anyhow!("Invalid ClientPollReply")),
   }
 }
 
 pub fn userlist<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, String>> {
-  let nb_users = u128(rd)? as usize;
+  let nb_users = count(rd)?;
   let mut users = HashMap::new();
   for _ in 0..nb_users {
     users.insert(clientid(rd)?, string(rd)?);
@@ -205,6 +611,242 @@ pub fn userlist<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, String>
   Ok(users)
 }
 
+pub fn user_entries<R: Read>(rd: &mut R) -> anyhow::Result<Vec<UserEntry>> {
+  let nb_users = count(rd)?;
+  let mut users = Vec::new();
+  for _ in 0..nb_users {
+    users.push(UserEntry {
+      id: clientid(rd)?,
+      name: string(rd)?,
+      server: serverid(rd)?,
+      tag: match rd.read_u8()? {
+        0 => None,
+        1 => Some(string(rd)?),
+        _ => return Err(anyhow::anyhow!("Invalid Option variant")),
+      },
+    });
+  }
+  Ok(users)
+}
+
+pub fn user_query<R: Read>(rd: &mut R) -> anyhow::Result<UserQuery> {
+  let filter = match rd.read_u8()? {
+    0 => None,
+    1 => Some(NameFilter::Prefix(string(rd)?)),
+    2 => Some(NameFilter::Contains(string(rd)?)),
+    _ => return Err(anyhow::anyhow!("Invalid NameFilter variant")),
+  };
+  Ok(UserQuery {
+    filter,
+    after: user_lookup(rd)?,
+    limit: u128(rd)?,
+    federated: bool(rd)?,
+  })
+}
+
+pub fn user_page<R: Read>(rd: &mut R) -> anyhow::Result<UserPage> {
+  Ok(UserPage {
+    users: user_entries(rd)?,
+    next: user_lookup(rd)?,
+  })
+}
+
+fn bool<R: Read>(rd: &mut R) -> anyhow::Result<bool> {
+  match rd.read_u8()? {
+    0 => Ok(false),
+    1 => Ok(true),
+    _ => Err(anyhow::anyhow!("Invalid bool")),
+  }
+}
+
+fn option_u64<R: Read>(rd: &mut R) -> anyhow::Result<Option<u64>> {
+  match rd.read_u8()? {
+    0 => Ok(None),
+    1 => Ok(Some(u64::try_from(u128(rd)?)?)),
+    _ => Err(anyhow::anyhow!("Invalid Option variant")),
+  }
+}
+
+pub fn search_query<R: Read>(rd: &mut R) -> anyhow::Result<SearchQuery> {
+  let nb_terms = count(rd)?;
+  let mut terms = Vec::new();
+  for _ in 0..nb_terms {
+    terms.push(string(rd)?);
+  }
+  Ok(SearchQuery {
+    terms,
+    participant: user_lookup(rd)?,
+    since: option_u64(rd)?,
+    until: option_u64(rd)?,
+    before: option_messageid(rd)?,
+    limit: u128(rd)?,
+  })
+}
+
+pub fn search_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<SearchPage, ClientError>> {
+  match rd.read_u8()? {
+    0 => {
+      let nb_hits = count(rd)?;
+      let mut hits = Vec::new();
+      for _ in 0..nb_hits {
+        hits.push(SearchHit {
+          entry: history_entry(rd)?,
+          polled: u64::try_from(u128(rd)?)?,
+        });
+      }
+      Ok(Result::Ok(SearchPage {
+        hits,
+        next: option_messageid(rd)?,
+      }))
+    }
+    1 => Ok(Err(client_error(rd)?)),
+    _ => Err(anyhow::anyhow!("Invalid Result variant")),
+  }
+}
+
+pub fn history_entry<R: Read>(rd: &mut R) -> anyhow::Result<HistoryEntry> {
+  Ok(HistoryEntry {
+    id: messageid(rd)?,
+    message: client_poll_reply(rd)?,
+    reactions: reactions(rd)?,
+  })
+}
+
+fn reactions<R: Read>(rd: &mut R) -> anyhow::Result<Vec<String>> {
+  let mut reactions = Vec::new();
+  for _ in 0..count(rd)? {
+    reactions.push(string(rd)?);
+  }
+  Ok(reactions)
+}
+
+pub fn sync_cursor<R: Read>(rd: &mut R) -> anyhow::Result<SyncCursor> {
+  Ok(SyncCursor {
+    history: option_messageid(rd)?,
+    read_markers: u128(rd)?,
+  })
+}
+
+/// both encodings of the reply to `Sync`
+pub fn sync_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<SyncReply, ClientError>> {
+  match rd.read_u8()? {
+    0 => (),
+    1 => return Ok(Err(client_error(rd)?)),
+    2 => return compact_sync_reply(rd).map(Result::Ok),
+    _ => return Err(anyhow::anyhow!("Invalid Result variant")),
+  }
+  let mut reply = SyncReply::default();
+  for _ in 0..count(rd)? {
+    reply.messages.push(history_entry(rd)?);
+  }
+  for _ in 0..count(rd)? {
+    reply.presence.push((clientid(rd)?, presence(rd)?));
+  }
+  for _ in 0..count(rd)? {
+    reply.read_markers.push((clientid(rd)?, messageid(rd)?));
+  }
+  for _ in 0..count(rd)? {
+    reply.receipts.push((messageid(rd)?, clientid(rd)?));
+  }
+  for _ in 0..count(rd)? {
+    reply.other.push(client_poll_reply(rd)?);
+  }
+  reply.cursor = sync_cursor(rd)?;
+  reply.full = bool(rd)?;
+  Ok(Result::Ok(reply))
+}
+
+fn compact_sync_reply<R: Read>(rd: &mut R) -> anyhow::Result<SyncReply> {
+  let mut table = Vec::new();
+  for _ in 0..count(rd)? {
+    table.push(clientid(rd)?);
+  }
+  let client = |rd: &mut R| -> anyhow::Result<ClientId> {
+    let i = u128(rd)?;
+    usize::try_from(i)
+      .ok()
+      .and_then(|i| table.get(i).copied())
+      .ok_or_else(|| anyhow::anyhow!("Invalid client index {}", i))
+  };
+  let mut reply = SyncReply::default();
+  for _ in 0..count(rd)? {
+    let id = messageid(rd)?;
+    let message = match rd.read_u8()? {
+      0 => ClientPollReply::Message {
+        src: client(rd)?,
+        srcsrv: serverid(rd)?,
+        content: string(rd)?,
+        timestamp: u64::try_from(u128(rd)?)?,
+      },
+      1 => ClientPollReply::Deleted { src: client(rd)? },
+      2 => client_poll_reply(rd)?,
+      _ => return Err(anyhow::anyhow!("Invalid compact message variant")),
+    };
+    let reactions = reactions(rd)?;
+    reply.messages.push(HistoryEntry {
+      id,
+      message,
+      reactions,
+    });
+  }
+  for _ in 0..count(rd)? {
+    reply.presence.push((client(rd)?, presence(rd)?));
+  }
+  for _ in 0..count(rd)? {
+    reply.read_markers.push((client(rd)?, messageid(rd)?));
+  }
+  for _ in 0..count(rd)? {
+    reply.receipts.push((messageid(rd)?, client(rd)?));
+  }
+  for _ in 0..count(rd)? {
+    reply.other.push(client_poll_reply(rd)?);
+  }
+  reply.cursor = sync_cursor(rd)?;
+  reply.full = bool(rd)?;
+  Ok(reply)
+}
+
+pub fn user_lookup<R: Read>(rd: &mut R) -> anyhow::Result<Option<ClientId>> {
+  match rd.read_u8()? {
+    0 => Ok(None),
+    1 => Ok(Some(clientid(rd)?)),
+    _ => Err(anyhow::anyhow!("Invalid Option variant")),
+  }
+}
+
+fn minute<R: Read>(rd: &mut R) -> anyhow::Result<u16> {
+  let minute = u128(rd)?;
+  if minute >= 24 * 60 {
+    return Err(anyhow::anyhow!("Invalid minute of the day {}", minute));
+  }
+  Ok(minute as u16)
+}
+
+pub fn notification_prefs<R: Read>(rd: &mut R) -> anyhow::Result<NotificationPrefs> {
+  let nb_muted = count(rd)?;
+  let mut muted = Vec::new();
+  for _ in 0..nb_muted {
+    muted.push(clientid(rd)?);
+  }
+  let quiet_hours = match rd.read_u8()? {
+    0 => None,
+    1 => Some(QuietHours {
+      start: minute(rd)?,
+      end: minute(rd)?,
+    }),
+    _ => return Err(anyhow::anyhow!("Invalid Option variant")),
+  };
+  Ok(NotificationPrefs { muted, quiet_hours })
+}
+
+pub fn report_target<R: Read>(rd: &mut R) -> anyhow::Result<ReportTarget> {
+  match rd.read_u8()? {
+    0 => Ok(ReportTarget::Message(messageid(rd)?)),
+    1 => Ok(ReportTarget::Client(clientid(rd)?)),
+    _ => Err(anyhow::anyhow!("Invalid ReportTarget variant")),
+  }
+}
+
 pub fn client_query<R: Read>(rd: &mut R) -> anyhow::Result<ClientQuery> {
   let variant = rd.read_u8()?;
   match variant {
@@ -212,10 +854,86 @@ pub fn client_query<R: Read>(rd: &mut R) -> anyhow::Result<ClientQuery> {
     1 => Ok(ClientQuery::Message(client(rd)?)),
     2 => Ok(ClientQuery::Poll),
     3 => Ok(ClientQuery::ListUsers),
+    4 => Ok(ClientQuery::Report {
+      target: report_target(rd)?,
+      reason: string(rd)?,
+    }),
+    5 => Ok(ClientQuery::GetPrefs),
+    6 => Ok(ClientQuery::SetPrefs(notification_prefs(rd)?)),
+    7 => Ok(ClientQuery::RegisterGuest(string(rd)?)),
+    8 => Ok(ClientQuery::Upgrade),
+    9 => Ok(ClientQuery::CreateRoom(string(rd)?)),
+    10 => Ok(ClientQuery::JoinRoom(roomid(rd)?)),
+    11 => Ok(ClientQuery::LeaveRoom(roomid(rd)?)),
+    12 => Ok(ClientQuery::Unregister),
+    13 => Ok(ClientQuery::Ping(u128(rd)?)),
+    14 => Ok(ClientQuery::PollN(u128(rd)?)),
+    15 => {
+      let nb_clients = count(rd)?;
+      let mut blocked = Vec::new();
+      for _ in 0..nb_clients {
+        blocked.push(clientid(rd)?);
+      }
+      Ok(ClientQuery::SetBlocked(blocked))
+    }
+    16 => Ok(ClientQuery::RegisterTrusted {
+      id: clientid(rd)?,
+      name: string(rd)?,
+    }),
+    17 => Ok(ClientQuery::Rename(string(rd)?)),
+    18 => Ok(ClientQuery::LookupUser(string(rd)?)),
+    19 => Ok(ClientQuery::ListUserEntries),
+    20 => Ok(ClientQuery::ListUsersPage(user_query(rd)?)),
+    21 => Ok(ClientQuery::Search(search_query(rd)?)),
+    22 => Ok(ClientQuery::PollWait(u128(rd)?)),
+    23 => Ok(ClientQuery::Sync {
+      cursor: sync_cursor(rd)?,
+      limit: u128(rd)?,
+      compact: bool(rd)?,
+    }),
+    24 => {
+      let nb_codecs = count(rd)?;
+      let mut codecs = Vec::new();
+      for _ in 0..nb_codecs {
+        codecs.push(codec(rd)?);
+      }
+      Ok(ClientQuery::Hello(codecs))
+    }
+    25 => Ok(ClientQuery::RegisterInvited {
+      name: string(rd)?,
+      invite: bytes(rd)?,
+    }),
     _ => Err(anyhow::anyhow!("Invalid ClientQuery variant")),
   }
 }
 
+/// decodes a reply frame with `d`, transport errors are returned as `TransportError`
+pub fn frame<R, X, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<X>
+where
+  R: Read,
+  DEC: FnOnce(&mut R) -> anyhow::Result<X>,
+{
+  match rd.read_u8()? {
+    0 => d(rd),
+    1 => Err(
+      match rd.read_u8()? {
+        0 => TransportError::Malformed,
+        1 => TransportError::AuthRequired,
+        2 => TransportError::RateLimited,
+        3 => TransportError::TooLarge,
+        4 => TransportError::Refused,
+        5 => TransportError::Replayed,
+        6 => TransportError::SequenceReuse {
+          expected: u128(rd)?,
+        },
+        _ => return Err(anyhow::anyhow!("Invalid TransportError")),
+      }
+      .into(),
+    ),
+    _ => Err(anyhow::anyhow!("Invalid frame")),
+  }
+}
+
 pub fn sequence<R, X, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<Sequence<X>>
 where
   R: Read,
@@ -230,3 +948,55 @@ where
     content,
   })
 }
+
+pub fn server_sequence<R, X, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<ServerSequence<X>>
+where
+  R: Read,
+  DEC: FnOnce(&mut R) -> anyhow::Result<X>,
+{
+  let seqid = u128(rd)?;
+  let src = serverid(rd)?;
+  let content = d(rd)?;
+  Ok(Sequence {
+    seqid,
+    src,
+    content,
+  })
+}
+
+/// decodes a frame with `d`, followed by its extensions section, keeping the `known` extensions
+pub fn extended<R, X, DEC>(
+  rd: &mut R,
+  known: &[u128],
+  d: DEC,
+) -> anyhow::Result<(X, Vec<Extension>)>
+where
+  R: Read,
+  DEC: FnOnce(&mut R) -> anyhow::Result<X>,
+{
+  let content = d(rd)?;
+  let mut prefix = [0];
+  if rd.read(&mut prefix)? == 0 {
+    return Ok((content, Vec::new()));
+  }
+  let n = count(&mut prefix.chain(&mut *rd))?;
+  if n == 0 && mode::strict() {
+    // the section is left out when there is no extension
+    return Err(mode::Nonconforming::Extension.into());
+  }
+  let mut extensions = Vec::new();
+  let mut last = None;
+  for _ in 0..n {
+    let tag = u128(rd)?;
+    let value = bytes(rd)?;
+    let ordered = last.is_none_or(|last| last < tag);
+    last = Some(tag);
+    if mode::strict() && !(ordered && known.contains(&tag)) {
+      return Err(mode::Nonconforming::Extension.into());
+    }
+    if known.contains(&tag) {
+      extensions.push(Extension { tag, value });
+    }
+  }
+  Ok((content, extensions))
+}
@@ -3,9 +3,14 @@ use std::{collections::HashMap, io::Write};
 use byteorder::{LittleEndian, WriteBytesExt};
 use uuid::Uuid;
 
+use super::ext::Extension;
+
 use crate::messages::{
   AuthMessage, ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply,
-  DelayedError, Sequence, ServerId, ServerMessage,
+  Codec, ContentType, DelayedError, Event, HistoryEntry, Mention, MessageId, NameFilter,
+  NotificationPrefs, Presence, Priority, ReportTarget, RichContent, RoomId, SearchPage,
+  SearchQuery, Sequence, ServerId, ServerMessage, ServerSequence, SyncCursor, SyncReply,
+  TransportError, UserEntry, UserPage, UserQuery,
 };
 
 // look at the README.md for guidance on writing this function
@@ -58,6 +63,33 @@ where
   uuid(w, &m.0)
 }
 
+pub fn messageid<W>(w: &mut W, m: &MessageId) -> std::io::Result<()>
+where
+  W: Write,
+{
+  uuid(w, &m.0)
+}
+
+pub fn option_messageid<W>(w: &mut W, m: &Option<MessageId>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    None => w.write_u8(0),
+    Some(id) => {
+      w.write_u8(1)?;
+      messageid(w, id)
+    }
+  }
+}
+
+pub fn roomid<W>(w: &mut W, m: &RoomId) -> std::io::Result<()>
+where
+  W: Write,
+{
+  uuid(w, &m.0)
+}
+
 // strings are encoded as the underlying bytes array
 // so
 //  1/ get the underlying bytes
@@ -67,9 +99,15 @@ pub fn string<W>(w: &mut W, m: &str) -> std::io::Result<()>
 where
   W: Write,
 {
-  let bytes = m.as_bytes();
-  u128(w, bytes.len() as u128)?;
-  w.write_all(bytes)
+  bytes(w, m.as_bytes())
+}
+
+pub fn bytes<W>(w: &mut W, m: &[u8]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  w.write_all(m)
 }
 
 /* The following is VERY mechanical, and should be easy once the general principle is understood
@@ -118,6 +156,16 @@ where
       w.write_u8(2)?;
       w.write_all(response)
     }
+    AuthMessage::BoundNonce {
+      server,
+      nonce,
+      channel,
+    } => {
+      w.write_u8(3)?;
+      serverid(w, server)?;
+      w.write_all(nonce)?;
+      w.write_all(channel)
+    }
   }
 }
 
@@ -150,11 +198,226 @@ where
       }
 
       string(w, &fully_qualified_message.content)?;
+      content_type(w, &fully_qualified_message.content_type)?;
+      mentions(w, &fully_qualified_message.mentions)?;
+      u128(w, fully_qualified_message.seq)?;
+      option_messageid(w, &fully_qualified_message.in_reply_to)?;
+      u128(w, fully_qualified_message.timestamp as u128)?;
+      messageid(w, &fully_qualified_message.id)?;
+    }
+    ServerMessage::Batch(messages) => {
+      w.write_u8(2)?;
+      u128(w, messages.len() as u128)?;
+      for message in messages {
+        server(w, message)?;
+      }
+    }
+    ServerMessage::Receipt {
+      id,
+      reader,
+      dst,
+      dstsrv,
+    } => {
+      w.write_u8(3)?;
+      messageid(w, id)?;
+      clientid(w, reader)?;
+      clientid(w, dst)?;
+      serverid(w, dstsrv)?;
+    }
+    ServerMessage::Presence {
+      client,
+      presence: p,
+    } => {
+      w.write_u8(4)?;
+      clientid(w, client)?;
+      presence(w, p)?;
+    }
+    ServerMessage::Withdraw { srv, clients } => {
+      w.write_u8(5)?;
+      serverid(w, srv)?;
+      u128(w, clients.len() as u128)?;
+      for client in clients {
+        clientid(w, client)?;
+      }
+    }
+    ServerMessage::Event {
+      src,
+      dst,
+      dstsrv,
+      event: e,
+    } => {
+      w.write_u8(6)?;
+      clientid(w, src)?;
+      clientid(w, dst)?;
+      serverid(w, dstsrv)?;
+      event(w, e)?;
+    }
+    ServerMessage::Delivered {
+      id,
+      src,
+      srcsrv,
+      dst,
+    } => {
+      w.write_u8(7)?;
+      messageid(w, id)?;
+      clientid(w, src)?;
+      serverid(w, srcsrv)?;
+      clientid(w, dst)?;
+    }
+    ServerMessage::Resume { token } => {
+      w.write_u8(8)?;
+      u128(w, *token)?;
+    }
+    ServerMessage::Session { token, seqid } => {
+      w.write_u8(9)?;
+      u128(w, *token)?;
+      u128(w, *seqid)?;
+    }
+    ServerMessage::KeyAgreement {
+      src,
+      dst,
+      dstsrv,
+      payload,
+    } => {
+      w.write_u8(10)?;
+      clientid(w, src)?;
+      clientid(w, dst)?;
+      serverid(w, dstsrv)?;
+      bytes(w, payload)?;
+    }
+    ServerMessage::Reaction {
+      id,
+      reactor,
+      dst,
+      dstsrv,
+      emoji,
+    } => {
+      w.write_u8(11)?;
+      messageid(w, id)?;
+      clientid(w, reactor)?;
+      clientid(w, dst)?;
+      serverid(w, dstsrv)?;
+      string(w, emoji)?;
+    }
+    ServerMessage::RoomMembers {
+      room,
+      srv,
+      version,
+      members,
+    } => {
+      w.write_u8(12)?;
+      roomid(w, room)?;
+      serverid(w, srv)?;
+      u128(w, *version as u128)?;
+      u128(w, members.len() as u128)?;
+      for member in members {
+        clientid(w, member)?;
+      }
+    }
+    ServerMessage::RoomText {
+      room,
+      src,
+      srcsrv,
+      dstsrvs,
+      content,
+    } => {
+      w.write_u8(13)?;
+      roomid(w, room)?;
+      clientid(w, src)?;
+      serverid(w, srcsrv)?;
+      u128(w, dstsrvs.len() as u128)?;
+      for dstsrv in dstsrvs {
+        serverid(w, dstsrv)?;
+      }
+      string(w, content)?;
+    }
+  }
+  Ok(())
+}
+
+pub fn content_type<W>(w: &mut W, m: &ContentType) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ContentType::Plain => w.write_u8(0),
+    ContentType::Markdown => w.write_u8(1),
+    ContentType::Custom(mime) => {
+      w.write_u8(2)?;
+      string(w, mime)
     }
   }
+}
+
+// mentions are encoded as (client, start, len) triples
+pub fn rich_content<W>(w: &mut W, m: &RichContent) -> std::io::Result<()>
+where
+  W: Write,
+{
+  string(w, &m.text)?;
+  mentions(w, &m.mentions)?;
+  content_type(w, &m.content_type)
+}
+
+pub fn mentions<W>(w: &mut W, m: &[Mention]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  for mention in m {
+    clientid(w, &mention.client)?;
+    u128(w, mention.start as u128)?;
+    u128(w, mention.len as u128)?;
+  }
   Ok(())
 }
 
+pub fn presence<W>(w: &mut W, m: &Presence) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(match m {
+    Presence::Online => 0,
+    Presence::Away => 1,
+    Presence::Offline => 2,
+  })
+}
+
+pub fn event<W>(w: &mut W, m: &Event) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Event::Typing => w.write_u8(0),
+    Event::StoppedTyping => w.write_u8(1),
+    Event::Mentioned(id) => {
+      w.write_u8(2)?;
+      messageid(w, id)
+    }
+  }
+}
+
+pub fn priority<W>(w: &mut W, m: &Priority) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(match m {
+    Priority::System => 0,
+    Priority::High => 1,
+    Priority::Normal => 2,
+  })
+}
+
+pub fn codec<W>(w: &mut W, m: &Codec) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(match m {
+    Codec::Binary => 0,
+    Codec::Json => 1,
+  })
+}
+
 pub fn client<W>(w: &mut W, m: &ClientMessage) -> std::io::Result<()>
 where
   W: Write,
@@ -173,6 +436,178 @@ where
       }
       string(w, content)?;
     }
+    ClientMessage::Rich { dest, content } => {
+      w.write_u8(2)?;
+      u128(w, dest.len() as u128)?;
+      for d in dest {
+        clientid(w, d)?;
+      }
+      rich_content(w, content)?;
+    }
+    ClientMessage::RoomText { room, content } => {
+      w.write_u8(3)?;
+      roomid(w, room)?;
+      string(w, content)?;
+    }
+    ClientMessage::Ack(id) => {
+      w.write_u8(4)?;
+      messageid(w, id)?;
+    }
+    ClientMessage::SetPresence(p) => {
+      w.write_u8(5)?;
+      presence(w, p)?;
+    }
+    ClientMessage::SubscribePresence(client) => {
+      w.write_u8(6)?;
+      clientid(w, client)?;
+    }
+    ClientMessage::Broadcast { content } => {
+      w.write_u8(7)?;
+      string(w, content)?;
+    }
+    ClientMessage::Event { dest, event: e } => {
+      w.write_u8(8)?;
+      clientid(w, dest)?;
+      event(w, e)?;
+    }
+    ClientMessage::Prioritized {
+      priority: p,
+      message,
+    } => {
+      w.write_u8(9)?;
+      priority(w, p)?;
+      client(w, message)?;
+    }
+    ClientMessage::WithId { id, message } => {
+      w.write_u8(10)?;
+      messageid(w, id)?;
+      client(w, message)?;
+    }
+    ClientMessage::Edit { dest, id, content } => {
+      w.write_u8(11)?;
+      clientid(w, dest)?;
+      messageid(w, id)?;
+      string(w, content)?;
+    }
+    ClientMessage::Delete { dest, id } => {
+      w.write_u8(12)?;
+      clientid(w, dest)?;
+      messageid(w, id)?;
+    }
+    ClientMessage::Data {
+      dest,
+      mime,
+      bytes: payload,
+    } => {
+      w.write_u8(13)?;
+      clientid(w, dest)?;
+      string(w, mime)?;
+      bytes(w, payload)?;
+    }
+    ClientMessage::KeyAgreement { dest, payload } => {
+      w.write_u8(14)?;
+      clientid(w, dest)?;
+      bytes(w, payload)?;
+    }
+    ClientMessage::ReplyTo {
+      in_reply_to,
+      message,
+    } => {
+      w.write_u8(15)?;
+      messageid(w, in_reply_to)?;
+      client(w, message)?;
+    }
+    ClientMessage::React { message, emoji } => {
+      w.write_u8(16)?;
+      messageid(w, message)?;
+      string(w, emoji)?;
+    }
+    ClientMessage::SetDoNotDisturb(on) => {
+      w.write_u8(17)?;
+      w.write_u8(*on as u8)?;
+    }
+    ClientMessage::AtomicMText { dest, content } => {
+      w.write_u8(18)?;
+      u128(w, dest.len() as u128)?;
+      for d in dest {
+        clientid(w, d)?;
+      }
+      string(w, content)?;
+    }
+  }
+  Ok(())
+}
+
+pub fn client_error<W>(w: &mut W, m: &ClientError) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ClientError::UnknownClient => {
+      w.write_u8(0)?;
+    }
+    ClientError::BoxFull(client) => {
+      w.write_u8(1)?;
+      clientid(w, client)?;
+    }
+    ClientError::InternalError => {
+      w.write_u8(2)?;
+    }
+    ClientError::ServerBusy => {
+      w.write_u8(3)?;
+    }
+    ClientError::SpamDetected => {
+      w.write_u8(4)?;
+    }
+    ClientError::Forbidden => {
+      w.write_u8(5)?;
+    }
+    ClientError::UnknownRoom(room) => {
+      w.write_u8(6)?;
+      roomid(w, room)?;
+    }
+    ClientError::UnknownMessage(id) => {
+      w.write_u8(7)?;
+      messageid(w, id)?;
+    }
+    ClientError::Blocked(client) => {
+      w.write_u8(8)?;
+      clientid(w, client)?;
+    }
+    ClientError::AlreadyRegistered(client) => {
+      w.write_u8(9)?;
+      clientid(w, client)?;
+    }
+    ClientError::MessageTooLarge(max) => {
+      w.write_u8(10)?;
+      u128(w, *max)?;
+    }
+    ClientError::RateLimited => {
+      w.write_u8(11)?;
+    }
+    ClientError::Replayed(seqid) => {
+      w.write_u8(12)?;
+      u128(w, *seqid)?;
+    }
+    ClientError::TooManyDestinations(max) => {
+      w.write_u8(13)?;
+      u128(w, *max)?;
+    }
+    ClientError::NameTaken(client) => {
+      w.write_u8(14)?;
+      clientid(w, client)?;
+    }
+    ClientError::DelayedQuota(max) => {
+      w.write_u8(15)?;
+      u128(w, *max)?;
+    }
+    ClientError::Banned => w.write_u8(16)?,
+    ClientError::InvalidInvite => w.write_u8(17)?,
+    ClientError::SequenceReuse { expected, got } => {
+      w.write_u8(18)?;
+      u128(w, *expected)?;
+      u128(w, *got)?;
+    }
   }
   Ok(())
 }
@@ -181,58 +616,176 @@ pub fn client_replies<W>(w: &mut W, m: &[ClientReply]) -> std::io::Result<()>
 where
   W: Write,
 {
+  u128(w, m.len() as u128)?;
   for rep in m {
-    match rep {
-      ClientReply::Delivered => {
-        w.write_u8(0)?;
-      }
-      ClientReply::Error(error) => {
-        w.write_u8(1)?; // Variant ID for Error
-        match error {
-          ClientError::UnknownClient => {
-            w.write_u8(0)?;
-          }
-          ClientError::BoxFull(clientid) => {
-            w.write_u8(1)?;
-            crate::netproto::encode::clientid(w, clientid)?;
-          }
-          ClientError::InternalError => {
-            w.write_u8(2)?;
-          }
-        }
-      }
-      ClientReply::Delayed => {
-        w.write_u8(2)?;
+    client_reply(w, rep)?;
+  }
+  Ok(())
+}
+
+pub fn client_reply<W>(w: &mut W, m: &ClientReply) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ClientReply::Delivered(id) => {
+      w.write_u8(0)?;
+      option_messageid(w, id)?;
+    }
+    ClientReply::Error(error) => {
+      w.write_u8(1)?; // Variant ID for Error
+      client_error(w, error)?;
+    }
+    ClientReply::Delayed(id) => {
+      w.write_u8(2)?;
+      messageid(w, id)?;
+    }
+    ClientReply::Transfer(server_id, server_message, id) => {
+      w.write_u8(3)?;
+      serverid(w, &server_id.0)?;
+      server(w, server_message)?;
+      option_messageid(w, id)?;
+    }
+    ClientReply::Pong(nonce) => {
+      w.write_u8(4)?;
+      u128(w, *nonce)?;
+    }
+    ClientReply::Broadcast { delivered, failed } => {
+      w.write_u8(5)?;
+      u128(w, *delivered)?;
+      u128(w, failed.len() as u128)?;
+      for (client, error) in failed {
+        clientid(w, client)?;
+        client_error(w, error)?;
       }
-      ClientReply::Transfer(server_id, server_message) => {
-        w.write_u8(3)?;
-        serverid(w, server_id)?;
-        server(w, server_message)?;
+    }
+    ClientReply::Multi(replies) => {
+      w.write_u8(6)?;
+      u128(w, replies.len() as u128)?;
+      for (client, reply) in replies {
+        clientid(w, client)?;
+        client_reply(w, reply)?;
       }
     }
   }
   Ok(())
 }
 
+pub fn client_poll_replies<W>(w: &mut W, m: &[ClientPollReply]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  for reply in m {
+    client_poll_reply(w, reply)?;
+  }
+  Ok(())
+}
+
 pub fn client_poll_reply<W>(w: &mut W, m: &ClientPollReply) -> std::io::Result<()>
 where
   W: Write,
 {
   match m {
-    ClientPollReply::Message { src, content } => {
+    ClientPollReply::Message {
+      src,
+      srcsrv,
+      content,
+      timestamp,
+    } => {
       w.write_u8(0)?;
       clientid(w, src)?;
+      serverid(w, srcsrv)?;
       string(w, content)?;
+      u128(w, *timestamp as u128)?;
     }
     ClientPollReply::DelayedError(delayed_error) => {
       w.write_u8(1)?;
       match delayed_error {
-        DelayedError::UnknownRecipient(client_id) => clientid(w, client_id)?,
+        DelayedError::UnknownRecipient(client_id) => {
+          w.write_u8(0)?;
+          clientid(w, client_id)?
+        }
+        DelayedError::Expired(client_id) => {
+          w.write_u8(1)?;
+          clientid(w, client_id)?
+        }
+        DelayedError::Rejected(client_id) => {
+          w.write_u8(2)?;
+          clientid(w, client_id)?
+        }
       }
     }
     ClientPollReply::Nothing => {
       w.write_u8(2)?;
     }
+    ClientPollReply::RichMessage { src, content } => {
+      w.write_u8(3)?;
+      clientid(w, src)?;
+      rich_content(w, content)?;
+    }
+    ClientPollReply::RoomMessage { room, src, content } => {
+      w.write_u8(4)?;
+      roomid(w, room)?;
+      clientid(w, src)?;
+      string(w, content)?;
+    }
+    ClientPollReply::Receipt { id, reader } => {
+      w.write_u8(5)?;
+      messageid(w, id)?;
+      clientid(w, reader)?;
+    }
+    ClientPollReply::Presence {
+      client,
+      presence: p,
+    } => {
+      w.write_u8(6)?;
+      clientid(w, client)?;
+      presence(w, p)?;
+    }
+    ClientPollReply::Event { src, event: e } => {
+      w.write_u8(7)?;
+      clientid(w, src)?;
+      event(w, e)?;
+    }
+    ClientPollReply::Delivered { id, dst } => {
+      w.write_u8(8)?;
+      messageid(w, id)?;
+      clientid(w, dst)?;
+    }
+    ClientPollReply::Deleted { src } => {
+      w.write_u8(9)?;
+      clientid(w, src)?;
+    }
+    ClientPollReply::Data {
+      src,
+      mime,
+      bytes: payload,
+    } => {
+      w.write_u8(10)?;
+      clientid(w, src)?;
+      string(w, mime)?;
+      bytes(w, payload)?;
+    }
+    ClientPollReply::KeyAgreement { src, payload } => {
+      w.write_u8(11)?;
+      clientid(w, src)?;
+      bytes(w, payload)?;
+    }
+    ClientPollReply::ReplyTo {
+      in_reply_to,
+      message,
+    } => {
+      w.write_u8(12)?;
+      messageid(w, in_reply_to)?;
+      client_poll_reply(w, message)?;
+    }
+    ClientPollReply::Reaction { id, reactor, emoji } => {
+      w.write_u8(13)?;
+      messageid(w, id)?;
+      clientid(w, reactor)?;
+      string(w, emoji)?;
+    }
   }
   Ok(())
 }
@@ -250,6 +803,309 @@ where
   Ok(())
 }
 
+/// the reply to `ListUserEntries`
+pub fn user_entries<W>(w: &mut W, m: &[UserEntry]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  for entry in m {
+    clientid(w, &entry.id)?;
+    string(w, &entry.name)?;
+    serverid(w, &entry.server)?;
+    match &entry.tag {
+      None => w.write_u8(0)?,
+      Some(tag) => {
+        w.write_u8(1)?;
+        string(w, tag)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+pub fn user_query<W>(w: &mut W, m: &UserQuery) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match &m.filter {
+    None => w.write_u8(0)?,
+    Some(NameFilter::Prefix(prefix)) => {
+      w.write_u8(1)?;
+      string(w, prefix)?;
+    }
+    Some(NameFilter::Contains(part)) => {
+      w.write_u8(2)?;
+      string(w, part)?;
+    }
+  }
+  user_lookup(w, &m.after)?;
+  u128(w, m.limit)?;
+  w.write_u8(m.federated as u8)
+}
+
+/// the reply to `ListUsersPage`
+pub fn user_page<W>(w: &mut W, m: &UserPage) -> std::io::Result<()>
+where
+  W: Write,
+{
+  user_entries(w, &m.users)?;
+  user_lookup(w, &m.next)
+}
+
+fn option_u64<W>(w: &mut W, m: &Option<u64>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    None => w.write_u8(0),
+    Some(n) => {
+      w.write_u8(1)?;
+      u128(w, *n as u128)
+    }
+  }
+}
+
+pub fn search_query<W>(w: &mut W, m: &SearchQuery) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.terms.len() as u128)?;
+  for term in &m.terms {
+    string(w, term)?;
+  }
+  user_lookup(w, &m.participant)?;
+  option_u64(w, &m.since)?;
+  option_u64(w, &m.until)?;
+  option_messageid(w, &m.before)?;
+  u128(w, m.limit)
+}
+
+/// the reply to `Search`, an error when the server does not search or the client is unknown
+pub fn search_reply<W>(w: &mut W, m: &Result<SearchPage, ClientError>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(page) => {
+      w.write_u8(0)?;
+      u128(w, page.hits.len() as u128)?;
+      for hit in &page.hits {
+        history_entry(w, &hit.entry)?;
+        u128(w, hit.polled as u128)?;
+      }
+      option_messageid(w, &page.next)
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+pub fn history_entry<W>(w: &mut W, m: &HistoryEntry) -> std::io::Result<()>
+where
+  W: Write,
+{
+  messageid(w, &m.id)?;
+  client_poll_reply(w, &m.message)?;
+  reactions(w, &m.reactions)
+}
+
+fn reactions<W>(w: &mut W, m: &[String]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  for emoji in m {
+    string(w, emoji)?;
+  }
+  Ok(())
+}
+
+pub fn sync_cursor<W>(w: &mut W, m: &SyncCursor) -> std::io::Result<()>
+where
+  W: Write,
+{
+  option_messageid(w, &m.history)?;
+  u128(w, m.read_markers)
+}
+
+/// the reply to `Sync`, an error when the client is unknown
+pub fn sync_reply<W>(w: &mut W, m: &Result<SyncReply, ClientError>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  let m = match m {
+    Ok(m) => m,
+    Err(rr) => {
+      w.write_u8(1)?;
+      return client_error(w, rr);
+    }
+  };
+  w.write_u8(0)?;
+  u128(w, m.messages.len() as u128)?;
+  for entry in &m.messages {
+    history_entry(w, entry)?;
+  }
+  u128(w, m.presence.len() as u128)?;
+  for (client, p) in &m.presence {
+    clientid(w, client)?;
+    presence(w, p)?;
+  }
+  u128(w, m.read_markers.len() as u128)?;
+  for (author, id) in &m.read_markers {
+    clientid(w, author)?;
+    messageid(w, id)?;
+  }
+  u128(w, m.receipts.len() as u128)?;
+  for (id, reader) in &m.receipts {
+    messageid(w, id)?;
+    clientid(w, reader)?;
+  }
+  client_poll_replies(w, &m.other)?;
+  sync_cursor(w, &m.cursor)?;
+  w.write_u8(m.full as u8)
+}
+
+/// the reply to `Sync` with `compact`: the client ids come first, once, and are then referred to
+/// by their index; the history messages whose sender is all that has a client id are encoded
+/// with the index too, the others as in `client_poll_reply`
+pub fn compact_sync_reply<W>(w: &mut W, m: &Result<SyncReply, ClientError>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  let m = match m {
+    Ok(m) => m,
+    Err(rr) => {
+      w.write_u8(1)?;
+      return client_error(w, rr);
+    }
+  };
+  let mut table: Vec<ClientId> = Vec::new();
+  let mut index = |client: &ClientId| match table.iter().position(|c| c == client) {
+    Some(i) => i,
+    None => {
+      table.push(*client);
+      table.len() - 1
+    }
+  };
+  // the indexes, in the order they are written
+  let senders: Vec<Option<usize>> = m
+    .messages
+    .iter()
+    .map(|e| match &e.message {
+      ClientPollReply::Message { src, .. } | ClientPollReply::Deleted { src } => Some(index(src)),
+      _ => None,
+    })
+    .collect();
+  let presence: Vec<usize> = m.presence.iter().map(|(c, _)| index(c)).collect();
+  let authors: Vec<usize> = m.read_markers.iter().map(|(c, _)| index(c)).collect();
+  let readers: Vec<usize> = m.receipts.iter().map(|(_, c)| index(c)).collect();
+
+  w.write_u8(2)?;
+  u128(w, table.len() as u128)?;
+  for client in &table {
+    clientid(w, client)?;
+  }
+  u128(w, m.messages.len() as u128)?;
+  for (entry, sender) in m.messages.iter().zip(senders) {
+    messageid(w, &entry.id)?;
+    match (&entry.message, sender) {
+      (
+        ClientPollReply::Message {
+          srcsrv,
+          content,
+          timestamp,
+          ..
+        },
+        Some(i),
+      ) => {
+        w.write_u8(0)?;
+        u128(w, i as u128)?;
+        serverid(w, srcsrv)?;
+        string(w, content)?;
+        u128(w, *timestamp as u128)?;
+      }
+      (ClientPollReply::Deleted { .. }, Some(i)) => {
+        w.write_u8(1)?;
+        u128(w, i as u128)?;
+      }
+      (message, _) => {
+        w.write_u8(2)?;
+        client_poll_reply(w, message)?;
+      }
+    }
+    reactions(w, &entry.reactions)?;
+  }
+  u128(w, presence.len() as u128)?;
+  for (i, (_, p)) in presence.iter().zip(&m.presence) {
+    u128(w, *i as u128)?;
+    self::presence(w, p)?;
+  }
+  u128(w, authors.len() as u128)?;
+  for (i, (_, id)) in authors.iter().zip(&m.read_markers) {
+    u128(w, *i as u128)?;
+    messageid(w, id)?;
+  }
+  u128(w, readers.len() as u128)?;
+  for (i, (id, _)) in readers.iter().zip(&m.receipts) {
+    messageid(w, id)?;
+    u128(w, *i as u128)?;
+  }
+  client_poll_replies(w, &m.other)?;
+  sync_cursor(w, &m.cursor)?;
+  w.write_u8(m.full as u8)
+}
+
+/// the reply to `LookupUser`
+pub fn user_lookup<W>(w: &mut W, m: &Option<ClientId>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    None => w.write_u8(0),
+    Some(client) => {
+      w.write_u8(1)?;
+      clientid(w, client)
+    }
+  }
+}
+
+pub fn notification_prefs<W>(w: &mut W, m: &NotificationPrefs) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.muted.len() as u128)?;
+  for client in &m.muted {
+    clientid(w, client)?;
+  }
+  match &m.quiet_hours {
+    None => w.write_u8(0),
+    Some(quiet) => {
+      w.write_u8(1)?;
+      u128(w, quiet.start as u128)?;
+      u128(w, quiet.end as u128)
+    }
+  }
+}
+
+pub fn report_target<W>(w: &mut W, m: &ReportTarget) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ReportTarget::Message(id) => {
+      w.write_u8(0)?;
+      messageid(w, id)
+    }
+    ReportTarget::Client(id) => {
+      w.write_u8(1)?;
+      clientid(w, id)
+    }
+  }
+}
+
 pub fn client_query<W>(w: &mut W, m: &ClientQuery) -> std::io::Result<()>
 where
   W: Write,
@@ -269,11 +1125,141 @@ where
     ClientQuery::ListUsers => {
       w.write_u8(3)?;
     }
+    ClientQuery::Report { target, reason } => {
+      w.write_u8(4)?;
+      report_target(w, target)?;
+      string(w, reason)?;
+    }
+    ClientQuery::GetPrefs => {
+      w.write_u8(5)?;
+    }
+    ClientQuery::SetPrefs(prefs) => {
+      w.write_u8(6)?;
+      notification_prefs(w, prefs)?;
+    }
+    ClientQuery::RegisterGuest(name) => {
+      w.write_u8(7)?;
+      string(w, name)?;
+    }
+    ClientQuery::Upgrade => {
+      w.write_u8(8)?;
+    }
+    ClientQuery::CreateRoom(name) => {
+      w.write_u8(9)?;
+      string(w, name)?;
+    }
+    ClientQuery::JoinRoom(room) => {
+      w.write_u8(10)?;
+      roomid(w, room)?;
+    }
+    ClientQuery::LeaveRoom(room) => {
+      w.write_u8(11)?;
+      roomid(w, room)?;
+    }
+    ClientQuery::Unregister => {
+      w.write_u8(12)?;
+    }
+    ClientQuery::Ping(nonce) => {
+      w.write_u8(13)?;
+      u128(w, *nonce)?;
+    }
+    ClientQuery::PollN(max) => {
+      w.write_u8(14)?;
+      u128(w, *max)?;
+    }
+    ClientQuery::SetBlocked(blocked) => {
+      w.write_u8(15)?;
+      u128(w, blocked.len() as u128)?;
+      for client in blocked {
+        clientid(w, client)?;
+      }
+    }
+    ClientQuery::RegisterTrusted { id, name } => {
+      w.write_u8(16)?;
+      clientid(w, id)?;
+      string(w, name)?;
+    }
+    ClientQuery::Rename(name) => {
+      w.write_u8(17)?;
+      string(w, name)?;
+    }
+    ClientQuery::LookupUser(name) => {
+      w.write_u8(18)?;
+      string(w, name)?;
+    }
+    ClientQuery::ListUserEntries => {
+      w.write_u8(19)?;
+    }
+    ClientQuery::ListUsersPage(query) => {
+      w.write_u8(20)?;
+      user_query(w, query)?;
+    }
+    ClientQuery::Search(query) => {
+      w.write_u8(21)?;
+      search_query(w, query)?;
+    }
+    ClientQuery::PollWait(millis) => {
+      w.write_u8(22)?;
+      u128(w, *millis)?;
+    }
+    ClientQuery::Sync {
+      cursor,
+      limit,
+      compact,
+    } => {
+      w.write_u8(23)?;
+      sync_cursor(w, cursor)?;
+      u128(w, *limit)?;
+      w.write_u8(*compact as u8)?;
+    }
+    ClientQuery::Hello(codecs) => {
+      w.write_u8(24)?;
+      u128(w, codecs.len() as u128)?;
+      for c in codecs {
+        codec(w, c)?;
+      }
+    }
+    ClientQuery::RegisterInvited { name, invite } => {
+      w.write_u8(25)?;
+      string(w, name)?;
+      bytes(w, invite)?;
+    }
   }
 
   Ok(())
 }
 
+/// Frames sent back to clients: a reply, or a transport error.
+///
+/// The reply payload (whose encoding depends on the query) follows a `0` tag, and transport
+/// errors are a `1` tag and the error variant.
+pub fn reply_frame<W>(w: &mut W, reply: &[u8]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(0)?;
+  w.write_all(reply)
+}
+
+pub fn error_frame<W>(w: &mut W, m: &TransportError) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(1)?;
+  match m {
+    TransportError::Malformed => w.write_u8(0),
+    TransportError::AuthRequired => w.write_u8(1),
+    TransportError::RateLimited => w.write_u8(2),
+    TransportError::TooLarge => w.write_u8(3),
+    TransportError::Refused => w.write_u8(4),
+    TransportError::Replayed => w.write_u8(5),
+    TransportError::SequenceReuse { expected } => {
+      w.write_u8(6)?;
+      u128(w, *expected)
+    }
+  }
+}
+
 // TODO
 pub fn sequence<W, X, ENC>(w: &mut W, m: &Sequence<X>, f: ENC) -> std::io::Result<()>
 where
@@ -285,3 +1271,39 @@ where
   f(w, &m.content)?;
   Ok(())
 }
+
+pub fn server_sequence<W, X, ENC>(w: &mut W, m: &ServerSequence<X>, f: ENC) -> std::io::Result<()>
+where
+  W: Write,
+  ENC: FnOnce(&mut W, &X) -> std::io::Result<()>,
+{
+  u128(w, m.seqid)?;
+  serverid(w, &m.src)?;
+  f(w, &m.content)?;
+  Ok(())
+}
+
+/// encodes a frame with `f`, followed by its `extensions` section (none when there is none)
+pub fn extended<W, X, ENC>(
+  w: &mut W,
+  m: &X,
+  extensions: &[Extension],
+  f: ENC,
+) -> std::io::Result<()>
+where
+  W: Write,
+  ENC: FnOnce(&mut W, &X) -> std::io::Result<()>,
+{
+  f(w, m)?;
+  if extensions.is_empty() {
+    return Ok(());
+  }
+  let mut sorted: Vec<&Extension> = extensions.iter().collect();
+  sorted.sort_by_key(|e| e.tag);
+  u128(w, sorted.len() as u128)?;
+  for e in sorted {
+    u128(w, e.tag)?;
+    bytes(w, &e.value)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,20 @@
+//! the extensions section of frames
+//!
+//! Optional features are added to the wire format as extensions, appended after the end of a
+//! frame (a client query or a federation sequence) by `encode::extended`:
+//!
+//!  * a frame without extensions has no section at all, it is the frame as it was before;
+//!  * otherwise, the number of extensions, then each extension as its tag and its value as bytes,
+//!    by increasing tag.
+//!
+//! A decoder that predates the section ignores it in lenient mode, like any byte after the end of
+//! the frame. `decode::extended` reads it and keeps the extensions with a tag it knows: the others
+//! are skipped in lenient mode and refused in strict mode. An extension must then never change
+//! the meaning of the frame for a peer that ignores it.
+
+/// an extension of a frame, its value is decoded by whoever knows its tag
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extension {
+  pub tag: u128,
+  pub value: Vec<u8>,
+}
@@ -1,5 +1,10 @@
+pub mod budget;
+pub mod codec;
+pub mod debug;
 pub mod decode;
 pub mod encode;
+pub mod ext;
+pub mod mode;
 
 #[cfg(test)]
 mod test {
@@ -9,8 +14,12 @@ mod test {
 
   use crate::messages::*;
 
+  use super::codec;
+  use super::debug;
   use super::decode;
   use super::encode;
+  use super::ext::Extension;
+  use super::mode::{self, DecodeMode, Nonconforming};
 
   fn servermessages() -> Vec<ServerMessage> {
     // large announce
@@ -37,6 +46,12 @@ mod test {
         srcsrv: ServerId::default(),
         dsts: vec![(ClientId::default(), ServerId::default())],
         content: "Hello".into(),
+        content_type: ContentType::Plain,
+        mentions: Vec::new(),
+        seq: 0,
+        in_reply_to: None,
+        timestamp: 0,
+        id: MessageId::default(),
       }),
       ServerMessage::Message(FullyQualifiedMessage {
         src: ClientId::default(),
@@ -46,7 +61,24 @@ mod test {
           (ClientId::default(), ServerId::default()),
         ],
         content: "World!".into(),
+        content_type: ContentType::Custom("application/x-test".into()),
+        mentions: vec![Mention {
+          client: ClientId::default(),
+          start: 0,
+          len: 5,
+        }],
+        seq: 0,
+        in_reply_to: Some(MessageId::default()),
+        timestamp: 0,
+        id: MessageId::default(),
       }),
+      ServerMessage::Batch(vec![
+        ServerMessage::Announce {
+          route: vec![ServerId::default()],
+          clients: HashMap::new(),
+        },
+        ServerMessage::Batch(Vec::new()),
+      ]),
     ]
   }
 
@@ -81,6 +113,12 @@ mod test {
             ),
           ],
           content: "Yes!".into(),
+          content_type: ContentType::Markdown,
+          mentions: Vec::new(),
+          seq: 3,
+          in_reply_to: None,
+          timestamp: 0,
+          id: uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
         }),
         vec![
           1, 16, 80, 6, 77, 218, 134, 93, 64, 112, 168, 67, 170, 202, 41, 44, 184, 94, 16, 149,
@@ -88,7 +126,8 @@ mod test {
           119, 47, 112, 10, 64, 116, 155, 132, 226, 100, 5, 13, 171, 89, 16, 47, 6, 253, 122, 142,
           123, 70, 134, 159, 125, 102, 168, 228, 232, 145, 82, 16, 91, 130, 107, 77, 243, 48, 75,
           95, 131, 174, 198, 254, 5, 183, 247, 96, 16, 109, 26, 131, 191, 201, 1, 65, 108, 138,
-          179, 18, 64, 158, 9, 10, 15, 4, 89, 101, 115, 33,
+          179, 18, 64, 158, 9, 10, 15, 4, 89, 101, 115, 33, 1, 0, 3, 0, 0, 16, 39, 41, 62, 160, 35,
+          197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
         ],
       ),
     ]
@@ -126,6 +165,17 @@ mod test {
           83, 150, 85, 248, 241, 110,
         ],
       ),
+      (
+        AuthMessage::BoundNonce {
+          server: uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"].into(),
+          nonce: [185, 213, 83, 150, 85, 248, 241, 110],
+          channel: [7; 16],
+        },
+        vec![
+          3, 16, 42, 30, 113, 91, 90, 94, 64, 107, 144, 70, 123, 225, 50, 168, 223, 39, 185, 213,
+          83, 150, 85, 248, 241, 110, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+        ],
+      ),
     ]
   }
 
@@ -159,6 +209,18 @@ mod test {
           53, 67, 48, 53, 106, 99,
         ],
       ),
+      (ClientMessage::SetDoNotDisturb(true), vec![17, 1]),
+      (ClientMessage::SetDoNotDisturb(false), vec![17, 0]),
+      (
+        ClientMessage::AtomicMText {
+          dest: vec![uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()],
+          content: "ok".into(),
+        },
+        vec![
+          18, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 2,
+          111, 107,
+        ],
+      ),
     ]
   }
 
@@ -174,8 +236,14 @@ mod test {
     let buf = wr.into_inner();
     assert_eq!(buf, encoded);
 
+    // what the encoders write must conform
     let mut cursor = Cursor::new(buf);
-    let decoded = d(&mut cursor).unwrap();
+    let decoded = mode::within(DecodeMode::Strict, || {
+      let decoded = d(&mut cursor)?;
+      mode::end(&mut cursor)?;
+      Ok(decoded)
+    })
+    .unwrap();
     assert_eq!(&decoded, clear);
   }
 
@@ -259,6 +327,16 @@ mod test {
     }
   }
 
+  #[test]
+  fn server_batch() {
+    let (announce, encoded) = server_hardcoded().remove(0);
+    let batch = ServerMessage::Batch(vec![announce.clone(), announce]);
+    let mut expected = vec![2, 2];
+    expected.extend_from_slice(&encoded);
+    expected.extend_from_slice(&encoded);
+    round_trip(encode::server, decode::server, &batch, &expected);
+  }
+
   #[test]
   fn auth_encode() {
     for (msg, expected) in auth_hardcoded() {
@@ -308,64 +386,2093 @@ mod test {
   }
 
   #[test]
-  fn string_encode() {
-    let src = "Hello World ;)".to_string();
-    let mut wr = Cursor::new(Vec::new());
-    encode::string(&mut wr, &src).unwrap();
+  fn client_rich() {
+    let bob: ClientId = uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into();
+    let msg = ClientMessage::Rich {
+      dest: vec![bob],
+      content: RichContent::default().text("hi ").mention(bob, "bob"),
+    };
+    round_trip(
+      encode::client,
+      decode::client,
+      &msg,
+      &[
+        2, 1, 16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 7, 104,
+        105, 32, 64, 98, 111, 98, 1, 16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55,
+        193, 244, 20, 3, 4, 0,
+      ],
+    );
+  }
+
+  #[test]
+  fn rooms() {
+    let room: RoomId = uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into();
+    let bob: ClientId = uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into();
+    let room_bytes = [
+      16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
+    ];
+    let bob_bytes = [
+      16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27,
+    ];
+    let msg = ClientMessage::RoomText {
+      room,
+      content: "hi".into(),
+    };
+    let expected = [&[3][..], &room_bytes, &[2, 104, 105]].concat();
+    round_trip(encode::client, decode::client, &msg, &expected);
+
+    let reply = ClientPollReply::RoomMessage {
+      room,
+      src: bob,
+      content: "hi".into(),
+    };
+    let expected = [&[4][..], &room_bytes, &bob_bytes, &[2, 104, 105]].concat();
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &reply,
+      &expected,
+    );
+
+    for (query, tag) in [
+      (ClientQuery::JoinRoom(room), 10),
+      (ClientQuery::LeaveRoom(room), 11),
+    ] {
+      let expected = [&[tag][..], &room_bytes].concat();
+      round_trip(
+        encode::client_query,
+        decode::client_query,
+        &query,
+        &expected,
+      );
+    }
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::CreateRoom("a".into()),
+      &[9, 1, 97],
+    );
+    let expected = [&[1, 1, 6][..], &room_bytes].concat();
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::UnknownRoom(room))],
+      &expected,
+    );
+
+    let srv: ServerId = uuid!["3e61bf47-d846-4caf-8402-10464a7bd330"].into();
+    let srv_bytes = [
+      16, 62, 97, 191, 71, 216, 70, 76, 175, 132, 2, 16, 70, 74, 123, 211, 48,
+    ];
+    let members = ServerMessage::RoomMembers {
+      room,
+      srv,
+      version: 300,
+      members: vec![bob],
+    };
+    let expected = [
+      &[12][..],
+      &room_bytes,
+      &srv_bytes,
+      &[251, 44, 1, 1],
+      &bob_bytes,
+    ]
+    .concat();
+    round_trip(encode::server, decode::server, &members, &expected);
+    let text = ServerMessage::RoomText {
+      room,
+      src: bob,
+      srcsrv: srv,
+      dstsrvs: vec![srv],
+      content: "hi".into(),
+    };
+    let expected = [
+      &[13][..],
+      &room_bytes,
+      &bob_bytes,
+      &srv_bytes,
+      &[1],
+      &srv_bytes,
+      &[2, 104, 105],
+    ]
+    .concat();
+    round_trip(encode::server, decode::server, &text, &expected);
+  }
+
+  #[test]
+  fn receipts() {
+    let id: MessageId = uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into();
+    let bob: ClientId = uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into();
+    let id_bytes = [
+      16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
+    ];
+    let bob_bytes = [
+      16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27,
+    ];
+    let expected = [&[4][..], &id_bytes].concat();
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Ack(id),
+      &expected,
+    );
+
+    let reply = ClientPollReply::Receipt { id, reader: bob };
+    let expected = [&[5][..], &id_bytes, &bob_bytes].concat();
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &reply,
+      &expected,
+    );
+
+    let expected = [&[1, 1, 7][..], &id_bytes].concat();
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::UnknownMessage(id))],
+      &expected,
+    );
+
+    let msg = ServerMessage::Receipt {
+      id,
+      reader: bob,
+      dst: bob,
+      dstsrv: ServerId(bob.0),
+    };
+    let expected = [&[3][..], &id_bytes, &bob_bytes, &bob_bytes, &bob_bytes].concat();
+    round_trip(encode::server, decode::server, &msg, &expected);
+
+    for (presence, tag) in [
+      (Presence::Online, 0),
+      (Presence::Away, 1),
+      (Presence::Offline, 2),
+    ] {
+      let msg = ClientMessage::SetPresence(presence);
+      round_trip(encode::client, decode::client, &msg, &[5, tag]);
+      let msg = ServerMessage::Presence {
+        client: bob,
+        presence,
+      };
+      let expected = [&[4][..], &bob_bytes, &[tag]].concat();
+      round_trip(encode::server, decode::server, &msg, &expected);
+      let reply = ClientPollReply::Presence {
+        client: bob,
+        presence,
+      };
+      let expected = [&[6][..], &bob_bytes, &[tag]].concat();
+      round_trip(
+        encode::client_poll_reply,
+        decode::client_poll_reply,
+        &reply,
+        &expected,
+      );
+    }
+    let expected = [&[6][..], &bob_bytes].concat();
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::SubscribePresence(bob),
+      &expected,
+    );
+  }
+
+  #[test]
+  fn unregister() {
+    let bob: ClientId = uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into();
+    let bob_bytes = [
+      16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27,
+    ];
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Unregister,
+      &[12],
+    );
+    let msg = ServerMessage::Withdraw {
+      srv: ServerId(bob.0),
+      clients: vec![bob, bob],
+    };
+    let expected = [&[5][..], &bob_bytes, &[2], &bob_bytes, &bob_bytes].concat();
+    round_trip(encode::server, decode::server, &msg, &expected);
+  }
+
+  #[test]
+  fn ping() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Ping(300),
+      &[13, 251, 44, 1],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Pong(300)],
+      &[1, 4, 251, 44, 1],
+    );
+  }
+
+  #[test]
+  fn fully_qualified_builder() {
+    let (c1, c2) = (ClientId::from(1), ClientId::from(2));
+    let (s1, s2) = (ServerId::from(1), ServerId::from(2));
+    let message = FullyQualifiedMessage::builder(c1, s1)
+      .to(c2, s2)
+      .content("hi".into())
+      .id(MessageId::from(3))
+      .build()
+      .unwrap();
     assert_eq!(
-      wr.into_inner(),
-      [14, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100, 32, 59, 41]
+      message,
+      FullyQualifiedMessage {
+        src: c1,
+        srcsrv: s1,
+        dsts: vec![(c2, s2)],
+        content: "hi".into(),
+        content_type: ContentType::Plain,
+        mentions: Vec::new(),
+        seq: 0,
+        in_reply_to: None,
+        timestamp: 0,
+        id: MessageId::from(3),
+      }
+    );
+    let sequenced = FullyQualifiedMessage::builder(c1, s1)
+      .to(c2, s2)
+      .sequenced(4)
+      .build()
+      .unwrap();
+    assert_eq!(sequenced.seq, 4);
+    assert_eq!(
+      FullyQualifiedMessage::builder(c1, s1).build(),
+      Err(MessageError::NoDestination)
     );
+    assert_eq!(
+      FullyQualifiedMessage::builder(c1, s1)
+        .to(c2, s2)
+        .to(c2, s1)
+        .build(),
+      Err(MessageError::DuplicateDestination(c2))
+    );
+    assert_eq!(
+      FullyQualifiedMessage::builder(c1, s1)
+        .to(c2, s2)
+        .content("hi".into())
+        .mentions(vec![Mention {
+          client: c2,
+          start: 1,
+          len: 2,
+        }])
+        .build(),
+      Err(MessageError::InvalidMention(c2))
+    );
+
+    // invalid messages are refused by the decoder too
+    let mut wr = Cursor::new(Vec::new());
+    encode::server(
+      &mut wr,
+      &ServerMessage::Message(FullyQualifiedMessage {
+        dsts: Vec::new(),
+        ..message
+      }),
+    )
+    .unwrap();
+    assert!(decode::server(&mut Cursor::new(wr.into_inner())).is_err());
   }
 
   #[test]
-  fn client_query_register() {
-    let query = ClientQuery::Register("Bob".into());
+  fn broadcast() {
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Broadcast {
+        content: "hi".into(),
+      },
+      &[7, 2, 104, 105],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Broadcast {
+        delivered: 3,
+        failed: vec![(ClientId::from(1), ClientError::Forbidden)],
+      }],
+      &[
+        1, 5, 3, 1, 16, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5,
+      ],
+    );
+  }
+
+  #[test]
+  fn multi_reply() {
+    let mut one = vec![16, 1];
+    one.extend([0; 15]);
+    let mut two = vec![16, 2];
+    two.extend([0; 15]);
+    let mut encoded = vec![1, 6, 2];
+    encoded.extend(&one);
+    encoded.extend([0, 0]);
+    encoded.extend(&two);
+    encoded.extend([1, 5]);
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Multi(vec![
+        (ClientId::from(1), ClientReply::Delivered(None)),
+        (
+          ClientId::from(2),
+          ClientReply::Error(ClientError::Forbidden),
+        ),
+      ])],
+      &encoded,
+    );
+
+    // a Multi can't hold another one
+    let mut nested = vec![1, 6, 1];
+    nested.extend(&one);
+    nested.extend([6, 0]);
+    assert!(decode::client_replies(&mut Cursor::new(nested)).is_err());
+  }
+
+  #[test]
+  fn register_trusted() {
+    let id = ClientId::from(1);
     round_trip(
       encode::client_query,
       decode::client_query,
-      &query,
-      &[0, 3, 66, 111, 98],
+      &ClientQuery::RegisterTrusted {
+        id,
+        name: "Bob".into(),
+      },
+      &[
+        16, 16, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 66, 111, 98,
+      ],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::AlreadyRegistered(id))],
+      &[1, 1, 9, 16, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::RegisterInvited {
+        name: "Bob".into(),
+        invite: vec![7, 8],
+      },
+      &[25, 3, 66, 111, 98, 2, 7, 8],
     );
   }
 
   #[test]
-  fn client_query_poll() {
-    let query = ClientQuery::Poll;
-    round_trip(encode::client_query, decode::client_query, &query, &[2]);
+  fn delivered() {
+    let c1 = ClientId::from(1);
+    let id = MessageId::from(2);
+    let srv: ServerId = uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into();
+    let mut expected = vec![7, 16];
+    expected.extend(id.0.as_bytes());
+    expected.push(16);
+    expected.extend(c1.0.as_bytes());
+    expected.push(16);
+    expected.extend(srv.0.as_bytes());
+    expected.push(16);
+    expected.extend(c1.0.as_bytes());
+    round_trip(
+      encode::server,
+      decode::server,
+      &ServerMessage::Delivered {
+        id,
+        src: c1,
+        srcsrv: srv,
+        dst: c1,
+      },
+      &expected,
+    );
+    let mut expected = vec![8, 16];
+    expected.extend(id.0.as_bytes());
+    expected.push(16);
+    expected.extend(c1.0.as_bytes());
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Delivered { id, dst: c1 },
+      &expected,
+    );
   }
 
   #[test]
-  fn client_query_list_users() {
-    let query = ClientQuery::ListUsers;
-    round_trip(encode::client_query, decode::client_query, &query, &[3]);
+  fn rename() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Rename("Bob".into()),
+      &[17, 3, 66, 111, 98],
+    );
   }
 
   #[test]
-  fn string_decode() {
-    let mut cursor = Cursor::new([
-      14, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100, 32, 59, 41,
-    ]);
-    let decoded = decode::string(&mut cursor).unwrap();
-    assert_eq!(decoded, "Hello World ;)");
+  fn lookup_user() {
+    let id = ClientId::from(1);
+    let id_bytes = [&[16][..], id.0.as_bytes()].concat();
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::LookupUser("Bob".into()),
+      &[18, 3, 66, 111, 98],
+    );
+    round_trip(encode::user_lookup, decode::user_lookup, &None, &[0]);
+    round_trip(
+      encode::user_lookup,
+      decode::user_lookup,
+      &Some(id),
+      &[&[1][..], &id_bytes].concat(),
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::NameTaken(id))],
+      &[&[1, 1, 14][..], &id_bytes].concat(),
+    );
   }
 
   #[test]
-  fn sequence() {
-    let src = Sequence {
-      seqid: 12,
-      src: uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into(),
-      content: "Hello".to_string(),
+  fn user_pages() {
+    let id = ClientId::from(1);
+    let id_bytes = [&[16][..], id.0.as_bytes()].concat();
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::ListUsersPage(UserQuery {
+        filter: None,
+        after: None,
+        limit: 30,
+        federated: false,
+      }),
+      &[20, 0, 0, 30, 0],
+    );
+    round_trip(
+      encode::user_query,
+      decode::user_query,
+      &UserQuery {
+        filter: Some(NameFilter::Prefix("Bo".into())),
+        after: Some(id),
+        limit: 300,
+        federated: true,
+      },
+      &[&[1, 2, 66, 111, 1][..], &id_bytes, &[251, 44, 1, 1]].concat(),
+    );
+    round_trip(
+      encode::user_query,
+      decode::user_query,
+      &UserQuery {
+        filter: Some(NameFilter::Contains("o".into())),
+        after: None,
+        limit: 1,
+        federated: false,
+      },
+      &[2, 1, 111, 0, 1, 0],
+    );
+    round_trip(
+      encode::user_page,
+      decode::user_page,
+      &UserPage {
+        users: vec![UserEntry {
+          id,
+          name: "Bob".into(),
+          server: ServerId::from(2),
+          tag: None,
+        }],
+        next: Some(id),
+      },
+      &[
+        &[1][..],
+        &id_bytes,
+        &[3, 66, 111, 98, 16],
+        ServerId::from(2).0.as_bytes(),
+        &[0, 1],
+        &id_bytes,
+      ]
+      .concat(),
+    );
+  }
+
+  #[test]
+  fn sync() {
+    let src = ClientId::from(1);
+    let id = MessageId::from(2);
+    let src_bytes = [&[16][..], src.0.as_bytes()].concat();
+    let id_bytes = [&[16][..], id.0.as_bytes()].concat();
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Sync {
+        cursor: SyncCursor {
+          history: Some(id),
+          read_markers: 3,
+        },
+        limit: 50,
+        compact: true,
+      },
+      &[&[23, 1][..], &id_bytes, &[3, 50, 1]].concat(),
+    );
+    let reply = SyncReply {
+      messages: vec![HistoryEntry {
+        id,
+        message: ClientPollReply::Deleted { src },
+        reactions: Vec::new(),
+      }],
+      presence: vec![(src, Presence::Away)],
+      read_markers: vec![(src, id)],
+      receipts: vec![(id, src)],
+      other: vec![ClientPollReply::Delivered { id, dst: src }],
+      cursor: SyncCursor {
+        history: Some(id),
+        read_markers: 1,
+      },
+      full: false,
     };
-    let encoded = &[
-      12, 16, 119, 255, 82, 158, 117, 189, 72, 50, 191, 12, 109, 179, 57, 2, 41, 36, 5, 72, 101,
-      108, 108, 111,
-    ];
-    round_trip::<Sequence<String>, _, _>(
-      |w, seq| encode::sequence(w, seq, |w2, st| encode::string(w2, st.as_str())),
-      |rd| decode::sequence(rd, decode::string),
-      &src,
-      encoded,
+    round_trip(
+      encode::sync_reply,
+      decode::sync_reply,
+      &Ok(reply.clone()),
+      &[
+        &[0, 1][..],
+        &id_bytes,
+        &[9],
+        &src_bytes,
+        &[0, 1],
+        &src_bytes,
+        &[1, 1],
+        &src_bytes,
+        &id_bytes,
+        &[1],
+        &id_bytes,
+        &src_bytes,
+        &[1, 8],
+        &id_bytes,
+        &src_bytes,
+        &[1],
+        &id_bytes,
+        &[1, 0],
+      ]
+      .concat(),
+    );
+    // the client id is only sent once
+    round_trip(
+      encode::compact_sync_reply,
+      decode::sync_reply,
+      &Ok(reply),
+      &[
+        &[2, 1][..],
+        &src_bytes,
+        &[1],
+        &id_bytes,
+        &[1, 0, 0, 1, 0, 1, 1, 0],
+        &id_bytes,
+        &[1],
+        &id_bytes,
+        &[0, 1, 8],
+        &id_bytes,
+        &src_bytes,
+        &[1],
+        &id_bytes,
+        &[1, 0],
+      ]
+      .concat(),
+    );
+    let rich = SyncReply {
+      messages: vec![HistoryEntry {
+        id,
+        message: ClientPollReply::RichMessage {
+          src,
+          content: RichContent::default().text("hi"),
+        },
+        reactions: Vec::new(),
+      }],
+      full: true,
+      ..SyncReply::default()
+    };
+    let mut plain = Cursor::new(Vec::new());
+    encode::client_poll_reply(&mut plain, &rich.messages[0].message).unwrap();
+    round_trip(
+      encode::compact_sync_reply,
+      decode::sync_reply,
+      &Ok(rich.clone()),
+      &[
+        &[2, 0, 1][..],
+        &id_bytes,
+        &[2],
+        &plain.into_inner(),
+        &[0, 0, 0, 0, 0, 0, 0, 1],
+      ]
+      .concat(),
+    );
+    round_trip(
+      encode::sync_reply,
+      decode::sync_reply,
+      &Err(ClientError::UnknownClient),
+      &[1, 0],
+    );
+  }
+
+  #[test]
+  fn search() {
+    let src = ClientId::from(1);
+    let id = MessageId::from(2);
+    let src_bytes = [&[16][..], src.0.as_bytes()].concat();
+    let id_bytes = [&[16][..], id.0.as_bytes()].concat();
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Search(SearchQuery {
+        terms: vec!["hi".into()],
+        participant: None,
+        since: Some(5),
+        until: None,
+        before: None,
+        limit: 10,
+      }),
+      &[21, 1, 2, 104, 105, 0, 1, 5, 0, 0, 10],
+    );
+    round_trip(
+      encode::search_query,
+      decode::search_query,
+      &SearchQuery {
+        terms: Vec::new(),
+        participant: Some(src),
+        since: None,
+        until: Some(300),
+        before: Some(id),
+        limit: 1,
+      },
+      &[
+        &[0, 1][..],
+        &src_bytes,
+        &[0, 1, 251, 44, 1, 1],
+        &id_bytes,
+        &[1],
+      ]
+      .concat(),
+    );
+    round_trip(
+      encode::search_reply,
+      decode::search_reply,
+      &Ok(SearchPage {
+        hits: vec![SearchHit {
+          entry: HistoryEntry {
+            id,
+            message: ClientPollReply::Message {
+              src,
+              srcsrv: ServerId(src.0),
+              content: "hi".into(),
+              timestamp: 300,
+            },
+            reactions: Vec::new(),
+          },
+          polled: 7,
+        }],
+        next: None,
+      }),
+      &[
+        &[0, 1][..],
+        &id_bytes,
+        &[0],
+        &src_bytes,
+        &src_bytes,
+        &[2, 104, 105, 251, 44, 1, 0, 7, 0],
+      ]
+      .concat(),
+    );
+    round_trip(
+      encode::search_reply,
+      decode::search_reply,
+      &Err(ClientError::Forbidden),
+      &[1, 5],
+    );
+  }
+
+  #[test]
+  fn user_entries() {
+    let id = ClientId::from(1);
+    let id_bytes = [&[16][..], id.0.as_bytes()].concat();
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::ListUserEntries,
+      &[19],
+    );
+    let srv = ServerId::from(2);
+    round_trip(
+      |w, r: &Vec<UserEntry>| encode::user_entries(w, r),
+      decode::user_entries,
+      &vec![
+        UserEntry {
+          id,
+          name: "Bob".into(),
+          server: srv,
+          tag: None,
+        },
+        UserEntry {
+          id,
+          name: "Bob".into(),
+          server: srv,
+          tag: Some("01".into()),
+        },
+      ],
+      &[
+        &[2][..],
+        &id_bytes,
+        &[3, 66, 111, 98, 16],
+        srv.0.as_bytes(),
+        &[0],
+        &id_bytes,
+        &[3, 66, 111, 98, 16],
+        srv.0.as_bytes(),
+        &[1, 2, 48, 49],
+      ]
+      .concat(),
+    );
+  }
+
+  #[test]
+  fn key_agreement() {
+    let c1 = ClientId::from(1);
+    let c1_bytes = [&[16][..], c1.0.as_bytes()].concat();
+    let srv = ServerId::from(2);
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::KeyAgreement {
+        dest: c1,
+        payload: vec![0, 255],
+      },
+      &[&[14][..], &c1_bytes, &[2, 0, 255]].concat(),
+    );
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::KeyAgreement {
+        src: c1,
+        payload: vec![7],
+      },
+      &[&[11][..], &c1_bytes, &[1, 7]].concat(),
+    );
+    round_trip(
+      encode::server,
+      decode::server,
+      &ServerMessage::KeyAgreement {
+        src: c1,
+        dst: c1,
+        dstsrv: srv,
+        payload: Vec::new(),
+      },
+      &[
+        &[10][..],
+        &c1_bytes,
+        &c1_bytes,
+        &[16],
+        srv.0.as_bytes(),
+        &[0],
+      ]
+      .concat(),
+    );
+  }
+
+  #[test]
+  fn data() {
+    let c1 = ClientId::from(1);
+    let c1_bytes = [&[16][..], c1.0.as_bytes()].concat();
+    let expected = [&[13][..], &c1_bytes, &[3, 97, 47, 98, 2, 0, 255]].concat();
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Data {
+        dest: c1,
+        mime: "a/b".into(),
+        bytes: vec![0, 255],
+      },
+      &expected,
+    );
+    let expected = [&[10][..], &c1_bytes, &[3, 97, 47, 98, 0]].concat();
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Data {
+        src: c1,
+        mime: "a/b".into(),
+        bytes: Vec::new(),
+      },
+      &expected,
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::MessageTooLarge(4096))],
+      &[1, 1, 10, 251, 0, 16],
     );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::RateLimited)],
+      &[1, 1, 11],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::Replayed(300))],
+      &[1, 1, 12, 251, 44, 1],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::TooManyDestinations(256))],
+      &[1, 1, 13, 251, 0, 1],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::DelayedQuota(1024))],
+      &[1, 1, 15, 251, 0, 4],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::Banned)],
+      &[1, 1, 16],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::InvalidInvite)],
+      &[1, 1, 17],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::SequenceReuse {
+        expected: 300,
+        got: 3,
+      })],
+      &[1, 1, 18, 251, 44, 1, 3],
+    );
+  }
+
+  #[test]
+  fn sessions() {
+    round_trip(
+      encode::server,
+      decode::server,
+      &ServerMessage::Resume { token: 300 },
+      &[8, 251, 44, 1],
+    );
+    let token = u128::MAX;
+    let expected = [&[9, 254][..], &token.to_le_bytes(), &[42]].concat();
+    round_trip(
+      encode::server,
+      decode::server,
+      &ServerMessage::Session { token, seqid: 42 },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn codecs() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Hello(vec![Codec::Json, Codec::Binary]),
+      &[24, 2, 1, 0],
+    );
+    assert_eq!(codec::pick(&[Codec::Json], &[]), Codec::Binary);
+    assert_eq!(codec::pick(&[Codec::Json], &[Codec::Json]), Codec::Json);
+    assert_eq!(
+      codec::pick(&[Codec::Binary, Codec::Json], &[Codec::Json]),
+      Codec::Binary
+    );
+
+    let sq = Sequence {
+      seqid: 3,
+      src: ClientId::from(1),
+      content: ClientQuery::Message(ClientMessage::Text {
+        dest: ClientId::from(2),
+        content: "hi".into(),
+      }),
+    };
+    let encoded = |c| {
+      codec::encode(c, &sq, |w, sq| {
+        encode::sequence(w, sq, encode::client_query)
+      })
+      .unwrap()
+    };
+    // queries are decoded with the codec they were encoded with, if it is enabled
+    for c in [Codec::Binary, Codec::Json] {
+      assert_eq!(
+        codec::query(encoded(c), &[Codec::Json]).unwrap(),
+        (c, sq.clone())
+      );
+    }
+    assert!(codec::query(encoded(Codec::Json), &[]).is_err());
+    let reply = codec::encode(
+      Codec::Json,
+      &ClientPollReply::Nothing,
+      encode::client_poll_reply,
+    );
+    let decoded = codec::decode(
+      Codec::Json,
+      &mut Cursor::new(reply.unwrap()),
+      decode::client_poll_reply,
+    );
+    assert_eq!(decoded.unwrap(), ClientPollReply::Nothing);
+    // maps are keyed by ids
+    let users = HashMap::from([(ClientId::from(1), "a".to_string())]);
+    let reply = codec::encode(Codec::Json, &users, encode::userlist).unwrap();
+    let decoded = codec::decode(Codec::Json, &mut Cursor::new(reply), decode::userlist);
+    assert_eq!(decoded.unwrap(), users);
+  }
+
+  #[test]
+  fn reply_to() {
+    let c1 = ClientId::from(1);
+    let id = MessageId::from(2);
+    let mut expected = vec![15, 16];
+    expected.extend(id.0.as_bytes());
+    expected.extend([0, 16]);
+    expected.extend(c1.0.as_bytes());
+    expected.extend([2, 104, 105]);
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::ReplyTo {
+        in_reply_to: id,
+        message: Box::new(ClientMessage::Text {
+          dest: c1,
+          content: "hi".into(),
+        }),
+      },
+      &expected,
+    );
+    let mut expected = vec![12, 16];
+    expected.extend(id.0.as_bytes());
+    expected.extend([9, 16]);
+    expected.extend(c1.0.as_bytes());
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::ReplyTo {
+        in_reply_to: id,
+        message: Box::new(ClientPollReply::Deleted { src: c1 }),
+      },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn reactions() {
+    let c1 = ClientId::from(1);
+    let id = MessageId::from(2);
+    let emoji = "\u{1f44d}";
+    let mut expected = vec![16, 16];
+    expected.extend(id.0.as_bytes());
+    expected.extend([4, 240, 159, 145, 141]);
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::React {
+        message: id,
+        emoji: emoji.into(),
+      },
+      &expected,
+    );
+    let mut expected = vec![13, 16];
+    expected.extend(id.0.as_bytes());
+    expected.push(16);
+    expected.extend(c1.0.as_bytes());
+    expected.extend([4, 240, 159, 145, 141]);
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Reaction {
+        id,
+        reactor: c1,
+        emoji: emoji.into(),
+      },
+      &expected,
+    );
+    let mut expected = vec![11, 16];
+    expected.extend(id.0.as_bytes());
+    for _ in 0..3 {
+      expected.push(16);
+      expected.extend(c1.0.as_bytes());
+    }
+    expected.extend([4, 240, 159, 145, 141]);
+    round_trip(
+      encode::server,
+      decode::server,
+      &ServerMessage::Reaction {
+        id,
+        reactor: c1,
+        dst: c1,
+        dstsrv: ServerId(c1.0),
+        emoji: emoji.into(),
+      },
+      &expected,
+    );
+    // reactions follow the message of their history entry
+    let mut expected = vec![16];
+    expected.extend(id.0.as_bytes());
+    expected.extend([9, 16]);
+    expected.extend(c1.0.as_bytes());
+    expected.extend([2, 1, 97, 1, 98]);
+    round_trip(
+      encode::history_entry,
+      decode::history_entry,
+      &HistoryEntry {
+        id,
+        message: ClientPollReply::Deleted { src: c1 },
+        reactions: vec!["a".into(), "b".into()],
+      },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn edit_delete() {
+    let c1 = ClientId::from(1);
+    let id = MessageId::from(2);
+    let mut expected = vec![10, 16];
+    expected.extend(id.0.as_bytes());
+    expected.extend([7, 2, 104, 105]);
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::WithId {
+        id,
+        message: Box::new(ClientMessage::Broadcast {
+          content: "hi".into(),
+        }),
+      },
+      &expected,
+    );
+    let mut expected = vec![11, 16];
+    expected.extend(c1.0.as_bytes());
+    expected.push(16);
+    expected.extend(id.0.as_bytes());
+    expected.extend([2, 104, 105]);
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Edit {
+        dest: c1,
+        id,
+        content: "hi".into(),
+      },
+      &expected,
+    );
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Delete { dest: c1, id },
+      &[&[12], &expected[1..35]].concat(),
+    );
+    let mut expected = vec![9, 16];
+    expected.extend(c1.0.as_bytes());
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Deleted { src: c1 },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn prioritized() {
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Prioritized {
+        priority: Priority::High,
+        message: Box::new(ClientMessage::Broadcast {
+          content: "hi".into(),
+        }),
+      },
+      &[9, 1, 7, 2, 104, 105],
+    );
+  }
+
+  #[test]
+  fn event() {
+    let c1 = ClientId::from(1);
+    let mut expected = vec![8, 16];
+    expected.extend(c1.0.as_bytes());
+    expected.push(1);
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Event {
+        dest: c1,
+        event: Event::StoppedTyping,
+      },
+      &expected,
+    );
+    let mut expected = vec![7, 16];
+    expected.extend(c1.0.as_bytes());
+    expected.push(0);
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Event {
+        src: c1,
+        event: Event::Typing,
+      },
+      &expected,
+    );
+    let id = MessageId::from(2);
+    let mut expected = vec![7, 16];
+    expected.extend(c1.0.as_bytes());
+    expected.extend([2, 16]);
+    expected.extend(id.0.as_bytes());
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Event {
+        src: c1,
+        event: Event::Mentioned(id),
+      },
+      &expected,
+    );
+    let srv: ServerId = uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into();
+    let mut expected = vec![6, 16];
+    expected.extend(c1.0.as_bytes());
+    expected.push(16);
+    expected.extend(c1.0.as_bytes());
+    expected.push(16);
+    expected.extend(srv.0.as_bytes());
+    expected.push(0);
+    round_trip(
+      encode::server,
+      decode::server,
+      &ServerMessage::Event {
+        src: c1,
+        dst: c1,
+        dstsrv: srv,
+        event: Event::Typing,
+      },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn blocked() {
+    let client: ClientId = uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into();
+    let mut expected = vec![15, 1, 16];
+    expected.extend(client.0.as_bytes());
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::SetBlocked(vec![client]),
+      &expected,
+    );
+    let mut expected = vec![1, 1, 8, 16];
+    expected.extend(client.0.as_bytes());
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::Blocked(client))],
+      &expected,
+    );
+  }
+
+  #[test]
+  fn poll_n() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::PollN(300),
+      &[14, 251, 44, 1],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::PollWait(200),
+      &[22, 200],
+    );
+    round_trip(
+      |w, r: &Vec<ClientPollReply>| encode::client_poll_replies(w, r),
+      decode::client_poll_replies,
+      &vec![ClientPollReply::Nothing, ClientPollReply::Nothing],
+      &[2, 2, 2],
+    );
+  }
+
+  #[test]
+  fn delayed_errors() {
+    let client: ClientId = uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into();
+    let encoded = |variant| {
+      let mut bytes = vec![1, variant, 16];
+      bytes.extend(client.0.as_bytes());
+      bytes
+    };
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::DelayedError(DelayedError::UnknownRecipient(client)),
+      &encoded(0),
+    );
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::DelayedError(DelayedError::Expired(client)),
+      &encoded(1),
+    );
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::DelayedError(DelayedError::Rejected(client)),
+      &encoded(2),
+    );
+  }
+
+  #[test]
+  fn frames() {
+    let mut wr = Cursor::new(Vec::new());
+    encode::reply_frame(&mut wr, &[1, 0, 0]).unwrap();
+    let frame = wr.into_inner();
+    assert_eq!(frame, [0, 1, 0, 0]);
+    let repl = decode::frame(&mut Cursor::new(frame), decode::client_replies).unwrap();
+    assert_eq!(repl, [ClientReply::Delivered(None)]);
+
+    let mut wr = Cursor::new(Vec::new());
+    encode::error_frame(&mut wr, &TransportError::RateLimited).unwrap();
+    let frame = wr.into_inner();
+    assert_eq!(frame, [1, 2]);
+    let rr = decode::frame(&mut Cursor::new(frame), decode::client_replies).unwrap_err();
+    assert_eq!(
+      rr.downcast_ref::<TransportError>(),
+      Some(&TransportError::RateLimited)
+    );
+
+    let mut wr = Cursor::new(Vec::new());
+    encode::error_frame(&mut wr, &TransportError::Replayed).unwrap();
+    let frame = wr.into_inner();
+    assert_eq!(frame, [1, 5]);
+    let rr = decode::frame(&mut Cursor::new(frame), decode::client_replies).unwrap_err();
+    assert_eq!(
+      rr.downcast_ref::<TransportError>(),
+      Some(&TransportError::Replayed)
+    );
+
+    let mut wr = Cursor::new(Vec::new());
+    let stale = TransportError::SequenceReuse { expected: 300 };
+    encode::error_frame(&mut wr, &stale).unwrap();
+    let frame = wr.into_inner();
+    assert_eq!(frame, [1, 6, 251, 44, 1]);
+    let rr = decode::frame(&mut Cursor::new(frame), decode::client_replies).unwrap_err();
+    assert_eq!(rr.downcast_ref::<TransportError>(), Some(&stale));
+  }
+
+  #[test]
+  fn rich_invalid_mention() {
+    let content = RichContent {
+      text: "é".into(),
+      mentions: vec![Mention {
+        client: ClientId::default(),
+        start: 1,
+        len: 1,
+      }],
+      content_type: ContentType::Plain,
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::rich_content(&mut wr, &content).unwrap();
+    let mut cursor = Cursor::new(wr.into_inner());
+    assert!(decode::rich_content(&mut cursor).is_err());
+  }
+
+  #[test]
+  fn content_type() {
+    round_trip(
+      encode::content_type,
+      decode::content_type,
+      &ContentType::from("application/x-bot"),
+      &[
+        2, 17, 97, 112, 112, 108, 105, 99, 97, 116, 105, 111, 110, 47, 120, 45, 98, 111, 116,
+      ],
+    );
+    assert_eq!(ContentType::from("text/markdown"), ContentType::Markdown);
+    assert_eq!(ContentType::Plain.to_string(), "text/plain");
+  }
+
+  #[test]
+  fn string_encode() {
+    let src = "Hello World ;)".to_string();
+    let mut wr = Cursor::new(Vec::new());
+    encode::string(&mut wr, &src).unwrap();
+    assert_eq!(
+      wr.into_inner(),
+      [14, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100, 32, 59, 41]
+    );
+  }
+
+  #[test]
+  fn client_query_register() {
+    let query = ClientQuery::Register("Bob".into());
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[0, 3, 66, 111, 98],
+    );
+  }
+
+  #[test]
+  fn client_query_poll() {
+    let query = ClientQuery::Poll;
+    round_trip(encode::client_query, decode::client_query, &query, &[2]);
+  }
+
+  #[test]
+  fn client_query_guest() {
+    let query = ClientQuery::RegisterGuest("Bob".into());
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[7, 3, 66, 111, 98],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Upgrade,
+      &[8],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Error(ClientError::Forbidden)],
+      &[1, 1, 5],
+    );
+  }
+
+  #[test]
+  fn client_query_list_users() {
+    let query = ClientQuery::ListUsers;
+    round_trip(encode::client_query, decode::client_query, &query, &[3]);
+  }
+
+  #[test]
+  fn client_query_report() {
+    let query = ClientQuery::Report {
+      target: ReportTarget::Message(uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()),
+      reason: "spam".into(),
+    };
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[
+        4, 0, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 4,
+        115, 112, 97, 109,
+      ],
+    );
+    let query = ClientQuery::Report {
+      target: ReportTarget::Client(uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()),
+      reason: String::new(),
+    };
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[
+        4, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 0,
+      ],
+    );
+  }
+
+  #[test]
+  fn client_query_prefs() {
+    let query = ClientQuery::SetPrefs(NotificationPrefs {
+      muted: vec![uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()],
+      quiet_hours: Some(QuietHours {
+        start: 1320,
+        end: 420,
+      }),
+    });
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[
+        6, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 1,
+        251, 40, 5, 251, 164, 1,
+      ],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::GetPrefs,
+      &[5],
+    );
+    let mut cursor = Cursor::new([0, 1, 251, 160, 5, 0]);
+    assert!(decode::notification_prefs(&mut cursor).is_err());
+  }
+
+  #[test]
+  fn quiet_hours() {
+    let night = QuietHours {
+      start: 22 * 60,
+      end: 7 * 60,
+    };
+    assert!(night.contains(23 * 60) && night.contains(0) && !night.contains(12 * 60));
+    let prefs = NotificationPrefs {
+      muted: vec![ClientId::from(1)],
+      quiet_hours: Some(QuietHours {
+        start: 600,
+        end: 660,
+      }),
+    };
+    assert!(!prefs.should_notify(ClientId::from(1), 0));
+    assert!(!prefs.should_notify(ClientId::from(2), 630));
+    assert!(prefs.should_notify(ClientId::from(2), 660));
+  }
+
+  #[test]
+  fn client_replies() {
+    let id = MessageId(uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"]);
+    let replies = vec![
+      ClientReply::Delivered(None),
+      ClientReply::Error(ClientError::BoxFull(
+        uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+      )),
+      ClientReply::Delayed(id),
+      ClientReply::Delivered(Some(id)),
+    ];
+    let id_bytes = [
+      16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27,
+    ];
+    let expected = [
+      &[4, 0, 0, 1, 1][..],
+      &id_bytes,
+      &[2],
+      &id_bytes,
+      &[0, 1],
+      &id_bytes,
+    ]
+    .concat();
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &replies,
+      &expected,
+    );
+    let transfer = ClientReply::Transfer(
+      NextHop(ServerId::from(1)),
+      ServerMessage::Resume { token: 1 },
+      Some(id),
+    );
+    let expected = [&[1, 3, 16, 1][..], &[0; 15], &[8, 1, 1], &id_bytes].concat();
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![transfer],
+      &expected,
+    );
+  }
+
+  #[test]
+  fn string_decode() {
+    let mut cursor = Cursor::new([
+      14, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100, 32, 59, 41,
+    ]);
+    let decoded = decode::string(&mut cursor).unwrap();
+    assert_eq!(decoded, "Hello World ;)");
+  }
+
+  #[test]
+  fn budgets() {
+    use super::budget::{within, BudgetExceeded, DecodeBudget, FrameBudget, DEFAULT_DEPTH};
+
+    fn encoded<X>(
+      f: impl FnOnce(&mut Cursor<Vec<u8>>, &X) -> std::io::Result<()>,
+      x: &X,
+    ) -> Vec<u8> {
+      let mut wr = Cursor::new(Vec::new());
+      f(&mut wr, x).unwrap();
+      wr.into_inner()
+    }
+    fn exceeded<X: std::fmt::Debug>(r: anyhow::Result<X>) -> BudgetExceeded {
+      *r.unwrap_err().downcast_ref::<BudgetExceeded>().unwrap()
+    }
+    let budget = DecodeBudget {
+      depth: 2,
+      elements: 5,
+      bytes: 10,
+    };
+
+    let mtext = |n: usize, content: &str| {
+      encoded(
+        encode::client,
+        &ClientMessage::MText {
+          dest: vec![ClientId::default(); n],
+          content: content.into(),
+        },
+      )
+    };
+    let frame = mtext(5, "0123456789");
+    assert!(within(budget, || decode::client(&mut Cursor::new(&frame))).is_ok());
+    let frame = mtext(6, "");
+    assert_eq!(
+      exceeded(within(budget, || decode::client(&mut Cursor::new(&frame)))),
+      BudgetExceeded::Elements
+    );
+    // not limited outside of a budget
+    assert!(decode::client(&mut Cursor::new(&frame)).is_ok());
+    let frame = mtext(1, "01234567890");
+    assert_eq!(
+      exceeded(within(budget, || decode::client(&mut Cursor::new(&frame)))),
+      BudgetExceeded::Bytes
+    );
+
+    // siblings don't add up, only nesting does
+    let batch = ServerMessage::Batch(vec![ServerMessage::Batch(Vec::new()); 3]);
+    let frame = encoded(encode::server, &batch);
+    assert_eq!(
+      within(budget, || decode::server(&mut Cursor::new(&frame))).unwrap(),
+      batch
+    );
+    let frame = encoded(encode::server, &ServerMessage::Batch(vec![batch]));
+    assert!(within(budget, || decode::server(&mut Cursor::new(&frame))).is_ok());
+    let mut nested = ClientMessage::Ack(MessageId::default());
+    for _ in 0..3 {
+      nested = ClientMessage::Prioritized {
+        priority: Priority::High,
+        message: Box::new(nested),
+      };
+    }
+    let frame = encoded(encode::client, &nested);
+    assert_eq!(
+      exceeded(within(budget, || decode::client(&mut Cursor::new(&frame)))),
+      BudgetExceeded::Depth
+    );
+    // the depth is always limited, by default outside of a budget
+    assert!(decode::client(&mut Cursor::new(&frame)).is_ok());
+    for _ in 0..DEFAULT_DEPTH - 3 {
+      nested = ClientMessage::Prioritized {
+        priority: Priority::High,
+        message: Box::new(nested),
+      };
+    }
+    let frame = encoded(encode::client, &nested);
+    assert!(decode::client(&mut Cursor::new(&frame)).is_ok());
+    let frame = encoded(
+      encode::client,
+      &ClientMessage::Prioritized {
+        priority: Priority::High,
+        message: Box::new(nested.clone()),
+      },
+    );
+    assert_eq!(
+      exceeded(decode::client(&mut Cursor::new(&frame))),
+      BudgetExceeded::Depth
+    );
+    let deeper = DecodeBudget {
+      depth: 16,
+      ..budget
+    };
+    assert!(within(deeper, || decode::client(&mut Cursor::new(&frame))).is_ok());
+
+    // only the frames over budget are counted, per address
+    let transport = FrameBudget::new(budget);
+    let (a, b) = ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+    for _ in 0..2 {
+      assert!(transport
+        .decode(a, || decode::client(&mut Cursor::new(&frame)))
+        .is_err());
+    }
+    assert!(transport
+      .decode(b, || decode::client(&mut Cursor::new([255])))
+      .is_err());
+    assert_eq!((transport.violations(a), transport.violations(b)), (2, 0));
+  }
+
+  #[test]
+  fn huge_lengths() {
+    // lengths and counts far beyond the frame are errors, not allocations
+    let mut wr = Cursor::new(Vec::new());
+    encode::u128(&mut wr, u64::MAX as u128).unwrap();
+    wr.get_mut().extend_from_slice(b"abc");
+    let frame = wr.into_inner();
+    assert!(decode::bytes(&mut Cursor::new(&frame)).is_err());
+    assert!(decode::client_replies(&mut Cursor::new(&frame)).is_err());
+
+    let mut wr = Cursor::new(Vec::new());
+    encode::u128(&mut wr, u128::MAX).unwrap();
+    assert!(decode::bytes(&mut Cursor::new(wr.into_inner())).is_err());
+  }
+
+  #[test]
+  fn decode_modes() {
+    fn nonconforming<X: std::fmt::Debug>(r: anyhow::Result<X>) -> Nonconforming {
+      *r.unwrap_err().downcast_ref::<Nonconforming>().unwrap()
+    }
+    let strict =
+      |bytes: &[u8]| mode::within(DecodeMode::Strict, || decode::u128(&mut Cursor::new(bytes)));
+
+    // varints longer than needed
+    for (value, long) in [
+      (7u128, &[251, 7, 0][..]),
+      (0x1234, &[252, 52, 18, 0, 0]),
+      (0x12345678, &[253, 120, 86, 52, 18, 0, 0, 0, 0]),
+      (1, &[254, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+    ] {
+      assert_eq!(decode::u128(&mut Cursor::new(long)).unwrap(), value);
+      assert_eq!(nonconforming(strict(long)), Nonconforming::Varint);
+    }
+    assert_eq!(strict(&[250]).unwrap(), 250);
+    assert_eq!(strict(&[251, 251, 0]).unwrap(), 251);
+    assert_eq!(strict(&[252, 0, 0, 1, 0]).unwrap(), 1 << 16);
+
+    // trailing bytes, where newer peers put their extensions
+    let query = Sequence {
+      seqid: 1,
+      src: ClientId::default(),
+      content: ClientQuery::Poll,
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::sequence(&mut wr, &query, encode::client_query).unwrap();
+    let mut frame = wr.into_inner();
+    frame.push(42);
+    let (_, lenient) = codec::query(frame.clone(), &[]).unwrap();
+    assert_eq!(lenient, query);
+    let r = mode::within(DecodeMode::Strict, || codec::query(frame, &[]));
+    assert_eq!(nonconforming(r), Nonconforming::Trailing);
+
+    // unknown tags are refused in both modes
+    for mode in [DecodeMode::Strict, DecodeMode::Lenient] {
+      let r = mode::within(mode, || decode::client(&mut Cursor::new([200])));
+      assert!(r.is_err());
+    }
+    // the mode ends with its scope
+    assert!(decode::u128(&mut Cursor::new([251, 7, 0])).is_ok());
+    assert_eq!("strict".parse::<DecodeMode>().unwrap(), DecodeMode::Strict);
+    assert!("loose".parse::<DecodeMode>().is_err());
+  }
+
+  #[test]
+  fn extensions() {
+    fn nonconforming<X: std::fmt::Debug>(r: anyhow::Result<X>) -> Nonconforming {
+      *r.unwrap_err().downcast_ref::<Nonconforming>().unwrap()
+    }
+    let query = Sequence {
+      seqid: 3,
+      src: ClientId::default(),
+      content: ClientQuery::Poll,
+    };
+    let encode = |extensions: &[Extension]| {
+      let mut wr = Cursor::new(Vec::new());
+      encode::extended(&mut wr, &query, extensions, |w, q| {
+        encode::sequence(w, q, encode::client_query)
+      })
+      .unwrap();
+      wr.into_inner()
+    };
+    let decode = |frame: &[u8], known: &[u128]| {
+      decode::extended(&mut Cursor::new(frame), known, |rd| {
+        decode::sequence(rd, decode::client_query)
+      })
+    };
+    let trace = Extension {
+      tag: 2,
+      value: vec![1, 2, 3],
+    };
+    let priority = Extension {
+      tag: 300,
+      value: vec![9],
+    };
+
+    // no section without extensions
+    let plain = encode(&[]);
+    let mut wr = Cursor::new(Vec::new());
+    encode::sequence(&mut wr, &query, encode::client_query).unwrap();
+    assert_eq!(plain, wr.into_inner());
+    assert_eq!(decode(&plain, &[2]).unwrap(), (query.clone(), vec![]));
+
+    // the section is sorted by tag
+    let frame = encode(&[priority.clone(), trace.clone()]);
+    assert_eq!(&frame[plain.len()..], [2, 2, 3, 1, 2, 3, 251, 44, 1, 1, 9]);
+    let both = vec![trace.clone(), priority.clone()];
+    assert_eq!(
+      decode(&frame, &[2, 300]).unwrap(),
+      (query.clone(), both.clone())
+    );
+    let strict = mode::within(DecodeMode::Strict, || decode(&frame, &[2, 300]));
+    assert_eq!(strict.unwrap().1, both);
+
+    // unknown extensions are skipped in lenient mode, refused in strict mode
+    assert_eq!(decode(&frame, &[300]).unwrap().1, vec![priority.clone()]);
+    assert_eq!(decode(&frame, &[]).unwrap().1, vec![]);
+    let r = mode::within(DecodeMode::Strict, || decode(&frame, &[300]));
+    assert_eq!(nonconforming(r), Nonconforming::Extension);
+
+    // decoders that predate the section ignore it in lenient mode
+    let (_, old) = codec::query(frame.clone(), &[]).unwrap();
+    assert_eq!(old, query);
+
+    // out of order or empty sections are not canonical
+    let mut unordered = plain.clone();
+    unordered.extend([2, 251, 44, 1, 1, 9, 2, 3, 1, 2, 3]);
+    assert_eq!(decode(&unordered, &[2, 300]).unwrap().1.len(), 2);
+    let r = mode::within(DecodeMode::Strict, || decode(&unordered, &[2, 300]));
+    assert_eq!(nonconforming(r), Nonconforming::Extension);
+    let mut empty = plain.clone();
+    empty.push(0);
+    assert_eq!(decode(&empty, &[]).unwrap().1, vec![]);
+    let r = mode::within(DecodeMode::Strict, || decode(&empty, &[]));
+    assert_eq!(nonconforming(r), Nonconforming::Extension);
+
+    // a truncated section is an error in both modes
+    assert!(decode(&frame[..frame.len() - 1], &[2, 300]).is_err());
+  }
+
+  #[test]
+  fn sequence() {
+    let src = Sequence {
+      seqid: 12,
+      src: uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into(),
+      content: "Hello".to_string(),
+    };
+    let encoded = &[
+      12, 16, 119, 255, 82, 158, 117, 189, 72, 50, 191, 12, 109, 179, 57, 2, 41, 36, 5, 72, 101,
+      108, 108, 111,
+    ];
+    round_trip::<Sequence<String>, _, _>(
+      |w, seq| encode::sequence(w, seq, |w2, st| encode::string(w2, st.as_str())),
+      |rd| decode::sequence(rd, decode::string),
+      &src,
+      encoded,
+    );
+  }
+
+  #[test]
+  fn server_sequence() {
+    let src = ServerSequence {
+      seqid: 300,
+      src: uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into(),
+      content: ServerMessage::Batch(Vec::new()),
+    };
+    let encoded = &[
+      251, 44, 1, 16, 119, 255, 82, 158, 117, 189, 72, 50, 191, 12, 109, 179, 57, 2, 41, 36, 2, 0,
+    ];
+    round_trip(
+      |w, seq| encode::server_sequence(w, seq, encode::server),
+      |rd| decode::server_sequence(rd, decode::server),
+      &src,
+      encoded,
+    );
+  }
+
+  // the tag of each variant in `encode`, and its name in serde
+  fn tags<T, ENC>(values: &[T], e: ENC) -> Vec<(u8, String)>
+  where
+    T: serde::Serialize,
+    ENC: Fn(&mut Cursor<Vec<u8>>, &T) -> std::io::Result<()>,
+  {
+    values
+      .iter()
+      .map(|value| {
+        let mut wr = Cursor::new(Vec::new());
+        e(&mut wr, value).unwrap();
+        let name = match serde_json::to_value(value).unwrap() {
+          serde_json::Value::String(name) => name,
+          serde_json::Value::Object(fields) => fields.keys().next().unwrap().clone(),
+          other => panic!("not an enum: {}", other),
+        };
+        (wr.into_inner()[0], name)
+      })
+      .collect()
+  }
+
+  fn named(table: &[(u8, &str)]) -> Vec<(u8, String)> {
+    table
+      .iter()
+      .map(|(tag, name)| (*tag, name.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn debug_tables() {
+    let (c, s, m, r) = (
+      ClientId::default(),
+      ServerId::default(),
+      MessageId::default(),
+      RoomId::default(),
+    );
+    let text = || ClientMessage::Text {
+      dest: c,
+      content: "hi".into(),
+    };
+    // one of each variant, in the order of their tags
+    let queries = [
+      ClientQuery::Register("a".into()),
+      ClientQuery::Message(text()),
+      ClientQuery::Poll,
+      ClientQuery::ListUsers,
+      ClientQuery::Report {
+        target: ReportTarget::Client(c),
+        reason: "spam".into(),
+      },
+      ClientQuery::GetPrefs,
+      ClientQuery::SetPrefs(NotificationPrefs::default()),
+      ClientQuery::RegisterGuest("a".into()),
+      ClientQuery::Upgrade,
+      ClientQuery::CreateRoom("room".into()),
+      ClientQuery::JoinRoom(r),
+      ClientQuery::LeaveRoom(r),
+      ClientQuery::Unregister,
+      ClientQuery::Ping(1),
+      ClientQuery::PollN(2),
+      ClientQuery::SetBlocked(vec![c]),
+      ClientQuery::RegisterTrusted {
+        id: c,
+        name: "a".into(),
+      },
+      ClientQuery::Rename("b".into()),
+      ClientQuery::LookupUser("b".into()),
+      ClientQuery::ListUserEntries,
+      ClientQuery::ListUsersPage(UserQuery::default()),
+      ClientQuery::Search(SearchQuery::default()),
+      ClientQuery::PollWait(3),
+      ClientQuery::Sync {
+        cursor: SyncCursor::default(),
+        limit: 4,
+        compact: false,
+      },
+      ClientQuery::Hello(vec![Codec::Json]),
+      ClientQuery::RegisterInvited {
+        name: "a".into(),
+        invite: vec![1],
+      },
+    ];
+    let messages = [
+      text(),
+      ClientMessage::MText {
+        dest: vec![c],
+        content: "hi".into(),
+      },
+      ClientMessage::Rich {
+        dest: vec![c],
+        content: RichContent::default(),
+      },
+      ClientMessage::RoomText {
+        room: r,
+        content: "hi".into(),
+      },
+      ClientMessage::Ack(m),
+      ClientMessage::SetPresence(Presence::Away),
+      ClientMessage::SubscribePresence(c),
+      ClientMessage::Broadcast {
+        content: "hi".into(),
+      },
+      ClientMessage::Event {
+        dest: c,
+        event: Event::Typing,
+      },
+      ClientMessage::Prioritized {
+        priority: Priority::High,
+        message: Box::new(text()),
+      },
+      ClientMessage::WithId {
+        id: m,
+        message: Box::new(text()),
+      },
+      ClientMessage::Edit {
+        dest: c,
+        id: m,
+        content: "hello".into(),
+      },
+      ClientMessage::Delete { dest: c, id: m },
+      ClientMessage::Data {
+        dest: c,
+        mime: "image/png".into(),
+        bytes: vec![1],
+      },
+      ClientMessage::KeyAgreement {
+        dest: c,
+        payload: vec![2],
+      },
+      ClientMessage::ReplyTo {
+        in_reply_to: m,
+        message: Box::new(text()),
+      },
+      ClientMessage::React {
+        message: m,
+        emoji: "+1".into(),
+      },
+      ClientMessage::SetDoNotDisturb(true),
+      ClientMessage::AtomicMText {
+        dest: vec![c],
+        content: "all".into(),
+      },
+    ];
+    let servers = [
+      ServerMessage::Announce {
+        route: vec![s],
+        clients: HashMap::new(),
+      },
+      ServerMessage::Message(
+        FullyQualifiedMessage::builder(c, s)
+          .to(c, s)
+          .content("hi".into())
+          .build()
+          .unwrap(),
+      ),
+      ServerMessage::Batch(Vec::new()),
+      ServerMessage::Receipt {
+        id: m,
+        reader: c,
+        dst: c,
+        dstsrv: s,
+      },
+      ServerMessage::Presence {
+        client: c,
+        presence: Presence::Online,
+      },
+      ServerMessage::Withdraw {
+        srv: s,
+        clients: vec![c],
+      },
+      ServerMessage::Event {
+        src: c,
+        dst: c,
+        dstsrv: s,
+        event: Event::StoppedTyping,
+      },
+      ServerMessage::Delivered {
+        id: MessageId::from(4),
+        src: c,
+        srcsrv: s,
+        dst: c,
+      },
+      ServerMessage::Resume { token: 5 },
+      ServerMessage::Session { token: 5, seqid: 6 },
+      ServerMessage::KeyAgreement {
+        src: c,
+        dst: c,
+        dstsrv: s,
+        payload: vec![3],
+      },
+      ServerMessage::Reaction {
+        id: m,
+        reactor: c,
+        dst: c,
+        dstsrv: s,
+        emoji: "+1".into(),
+      },
+      ServerMessage::RoomMembers {
+        room: r,
+        srv: s,
+        version: 1,
+        members: vec![c],
+      },
+      ServerMessage::RoomText {
+        room: r,
+        src: c,
+        srcsrv: s,
+        dstsrvs: vec![s],
+        content: "hi".into(),
+      },
+    ];
+    let errors = [
+      TransportError::Malformed,
+      TransportError::AuthRequired,
+      TransportError::RateLimited,
+      TransportError::TooLarge,
+      TransportError::Refused,
+      TransportError::Replayed,
+      TransportError::SequenceReuse { expected: 1 },
+    ];
+    assert_eq!(tags(&queries, encode::client_query), named(debug::QUERIES));
+    assert_eq!(
+      tags(&messages, encode::client),
+      named(debug::CLIENT_MESSAGES)
+    );
+    assert_eq!(
+      tags(&servers, encode::server),
+      named(debug::SERVER_MESSAGES)
+    );
+    // after the status byte of the frame
+    let errors = tags(&errors, |w, rr| {
+      let mut frame = Vec::new();
+      encode::error_frame(&mut frame, rr)?;
+      w.get_mut().extend(&frame[1..]);
+      Ok(())
+    });
+    assert_eq!(errors, named(debug::TRANSPORT_ERRORS));
+  }
+
+  #[test]
+  fn debug_frames() {
+    let src = ClientId(uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"]);
+    let mut wr = Cursor::new(Vec::new());
+    let ping = Sequence {
+      seqid: 300,
+      src,
+      content: ClientQuery::Ping(7),
+    };
+    encode::sequence(&mut wr, &ping, encode::client_query).unwrap();
+    let query = wr.into_inner();
+    assert_eq!(
+      debug::hexdump(&query),
+      concat!(
+        "0000  fb 2c 01 10 73 20 37 af d3 84 4d 93 ab 4e eb af  |.,..s 7...M..N..|\n",
+        "0010  64 de 87 1b 0d 07                                |d.....|\n",
+      )
+    );
+    let pretty = debug::pretty(debug::Frame::Query, &query);
+    assert!(pretty.ends_with(&format!("{:#?}", (Codec::Binary, ping))));
+
+    // replies are decoded with their query, in its codec
+    let query = ClientQuery::Ping(7);
+    let reply = codec::encode(Codec::Json, &[ClientReply::Pong(7)][..], |w, r| {
+      encode::client_replies(w, r)
+    })
+    .unwrap();
+    let mut wr = Cursor::new(Vec::new());
+    encode::reply_frame(&mut wr, &reply).unwrap();
+    let decoded = debug::decode(debug::Frame::Reply(Codec::Json, &query), &wr.into_inner());
+    assert_eq!(format!("{:?}", decoded.unwrap()), "[Pong(7)]");
+    let mut wr = Cursor::new(Vec::new());
+    encode::error_frame(&mut wr, &TransportError::Refused).unwrap();
+    let refused = debug::decode(debug::Frame::Reply(Codec::Json, &query), &wr.into_inner());
+    assert_eq!(
+      refused.unwrap_err().downcast::<TransportError>().unwrap(),
+      TransportError::Refused
+    );
+
+    // truncated frames are reported, not decoded
+    for truncated in [&[][..], &[0], &[0, 16, 1]] {
+      let pretty = debug::pretty(debug::Frame::Server, truncated);
+      assert!(pretty.contains("undecodable"), "{}", pretty);
+    }
+    // and so are the frames over the decode budget
+    let mut batch = ServerMessage::Batch(Vec::new());
+    for _ in 0..20 {
+      batch = ServerMessage::Batch(vec![batch]);
+    }
+    let mut wr = Cursor::new(Vec::new());
+    let sq = ServerSequence {
+      seqid: 1,
+      src: ServerId::default(),
+      content: batch,
+    };
+    encode::server_sequence(&mut wr, &sq, encode::server).unwrap();
+    let rr = debug::decode(debug::Frame::Server, &wr.into_inner()).unwrap_err();
+    assert!(rr.is::<super::budget::BudgetExceeded>());
+
+    let lua = debug::dissector(4666, 4667);
+    assert!(lua.contains("local client_port = 4666\n"));
+    assert!(lua.contains("  [24] = \"Hello\",\n"));
+    assert!(lua.contains("  [11] = \"Reaction\",\n"));
+    assert!(lua.contains("udp:add(server_port, chatfed)"));
   }
 }
@@ -0,0 +1,92 @@
+//! how strictly frames are decoded
+//!
+//! The binary decoders always refuse unknown enum tags, as nothing tells how long the value
+//! behind them is. The rest depends on the `DecodeMode` the frame is decoded `within`:
+//!
+//!  * `Lenient`, the default, accepts varints longer than needed and ignores the bytes left
+//!    after a frame, where newer peers append their optional extensions.
+//!  * `Strict` refuses both with `Nonconforming`, so that there is a single encoding of each
+//!    frame. It is meant for conformance testing, not to talk to peers of other versions.
+//!
+//! Only the binary codec depends on the mode, JSON frames are decoded the same in both.
+
+use std::cell::Cell;
+use std::io::Read;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecodeMode {
+  Strict,
+  #[default]
+  Lenient,
+}
+
+impl FromStr for DecodeMode {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "strict" => Ok(DecodeMode::Strict),
+      "lenient" => Ok(DecodeMode::Lenient),
+      _ => Err(anyhow::anyhow!("unknown decode mode {}", s)),
+    }
+  }
+}
+
+/// what a strict decoder refused in a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Nonconforming {
+  /// a varint not in its shortest form
+  Varint,
+  /// bytes after the end of the frame
+  Trailing,
+  /// an extension with an unknown tag, or out of order
+  Extension,
+}
+
+impl std::fmt::Display for Nonconforming {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Nonconforming::Varint => "Non-canonical varint".fmt(f),
+      Nonconforming::Trailing => "Trailing bytes after the frame".fmt(f),
+      Nonconforming::Extension => "Unknown or unordered extension".fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for Nonconforming {}
+
+thread_local! {
+  // the mode of the frame being decoded on this thread, decoding is synchronous
+  static MODE: Cell<DecodeMode> = const { Cell::new(DecodeMode::Lenient) };
+}
+
+/// runs a decoder in a mode
+pub fn within<X>(mode: DecodeMode, f: impl FnOnce() -> anyhow::Result<X>) -> anyhow::Result<X> {
+  let outer = MODE.replace(mode);
+  let r = f();
+  MODE.set(outer);
+  r
+}
+
+pub(crate) fn strict() -> bool {
+  MODE.get() == DecodeMode::Strict
+}
+
+/// a varint `value` read with a prefix for values from `min`
+pub(crate) fn varint(value: u128, min: u128) -> anyhow::Result<u128> {
+  if strict() && value < min {
+    Err(Nonconforming::Varint.into())
+  } else {
+    Ok(value)
+  }
+}
+
+/// the end of a frame, checked for trailing bytes in strict mode
+pub fn end<R: Read>(rd: &mut R) -> anyhow::Result<()> {
+  if strict() && rd.read(&mut [0])? != 0 {
+    Err(Nonconforming::Trailing.into())
+  } else {
+    Ok(())
+  }
+}
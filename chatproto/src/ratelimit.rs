@@ -0,0 +1,101 @@
+//! token buckets, for the rate limits of the transports and of the server
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// calls to `ready` between two sweeps of the full buckets
+const PRUNE_EVERY: usize = 1024;
+
+/// One token bucket per key: each key can spend `burst` tokens at once, and then `rate` tokens
+/// per second.
+pub struct TokenBuckets<K> {
+  rate: f64,
+  burst: f64,
+  buckets: HashMap<K, Bucket>,
+  // calls to `ready` since the last sweep
+  calls: usize,
+}
+
+struct Bucket {
+  tokens: f64,
+  last: Instant,
+}
+
+impl<K: Hash + Eq> TokenBuckets<K> {
+  pub fn new(rate: f64, burst: u32) -> Self {
+    TokenBuckets {
+      rate,
+      burst: burst.max(1) as f64,
+      buckets: HashMap::new(),
+      calls: 0,
+    }
+  }
+
+  /// refills the bucket of `key`, and tells if it holds a token
+  pub fn ready(&mut self, key: K, now: Instant) -> bool {
+    // full buckets carry no information, they are forgotten from time to time
+    let (rate, burst) = (self.rate, self.burst);
+    self.calls += 1;
+    if self.calls >= PRUNE_EVERY {
+      self.calls = 0;
+      self
+        .buckets
+        .retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst);
+    }
+    let bucket = self.buckets.entry(key).or_insert(Bucket {
+      tokens: burst,
+      last: now,
+    });
+    bucket.tokens =
+      (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate).min(burst);
+    bucket.last = now;
+    bucket.tokens >= 1.0
+  }
+
+  /// spends a token of a bucket that was just found `ready`
+  pub fn take(&mut self, key: &K) {
+    if let Some(bucket) = self.buckets.get_mut(key) {
+      bucket.tokens -= 1.0;
+    }
+  }
+
+  /// spends a token of `key`, if there is one
+  pub fn acquire(&mut self, key: K, now: Instant) -> bool
+  where
+    K: Clone,
+  {
+    if self.ready(key.clone(), now) {
+      self.take(&key);
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn prune() {
+    let mut buckets = TokenBuckets::new(1.0, 2);
+    let start = Instant::now();
+    assert!(buckets.acquire(0, start));
+    assert!(buckets.acquire(0, start));
+    assert!(!buckets.acquire(0, start));
+    // the full buckets stay until the next sweep
+    for key in 1..PRUNE_EVERY - 3 {
+      assert!(buckets.ready(key, start));
+    }
+    assert_eq!(buckets.buckets.len(), PRUNE_EVERY - 3);
+    // the sweep forgets them, but not the bucket that is still refilling
+    let later = start + Duration::from_secs(1);
+    assert!(buckets.acquire(0, later));
+    assert_eq!(buckets.buckets.len(), 1);
+    assert!(!buckets.acquire(0, later));
+  }
+}
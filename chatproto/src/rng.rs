@@ -0,0 +1,88 @@
+//! randomness, behind an `Rng` that can be replaced
+//!
+//! Security-sensitive values (client ids, session tokens, nonces) come from the operating system
+//! CSPRNG by default (`OsRandom`). Tests can use a `SeededRng` instead, that always draws the same
+//! sequence for a given seed.
+
+use std::sync::{Arc, Mutex};
+
+use rand::rngs::{OsRng, StdRng};
+use rand::{RngCore, SeedableRng};
+use uuid::Uuid;
+
+pub trait Rng: Send + Sync {
+  fn fill(&self, bytes: &mut [u8]);
+
+  fn u128(&self) -> u128 {
+    let mut bytes = [0u8; 16];
+    self.fill(&mut bytes);
+    u128::from_le_bytes(bytes)
+  }
+
+  /// a random (version 4) uuid
+  fn uuid(&self) -> Uuid {
+    let mut bytes = [0u8; 16];
+    self.fill(&mut bytes);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+  }
+
+  /// uniform in [0, 1)
+  fn unit(&self) -> f64 {
+    // the 53 bits of a float mantissa
+    (self.u128() >> 75) as f64 / (1u64 << 53) as f64
+  }
+}
+
+pub type SharedRng = Arc<dyn Rng>;
+
+/// the randomness used when nothing else is configured
+pub fn os_rng() -> SharedRng {
+  Arc::new(OsRandom)
+}
+
+/// The operating system CSPRNG.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsRandom;
+
+impl Rng for OsRandom {
+  fn fill(&self, bytes: &mut [u8]) {
+    OsRng.fill_bytes(bytes)
+  }
+}
+
+/// A deterministic sequence, for tests.
+pub struct SeededRng(Mutex<StdRng>);
+
+impl SeededRng {
+  pub fn new(seed: u64) -> Self {
+    SeededRng(Mutex::new(StdRng::seed_from_u64(seed)))
+  }
+}
+
+impl Rng for SeededRng {
+  fn fill(&self, bytes: &mut [u8]) {
+    self.0.lock().unwrap().fill_bytes(bytes)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn seeded() {
+    let draw = |rng: &dyn Rng| (rng.u128(), rng.uuid(), rng.unit());
+    let (a, b) = (SeededRng::new(7), SeededRng::new(7));
+    let first = draw(&a);
+    assert_eq!(first, draw(&b));
+    assert_ne!(first, draw(&a));
+    assert_ne!(draw(&SeededRng::new(8)), draw(&SeededRng::new(7)));
+    assert_ne!(OsRandom.u128(), OsRandom.u128());
+
+    let rng = SeededRng::new(0);
+    for _ in 0..100 {
+      assert_eq!(rng.uuid().get_version_num(), 4);
+      assert!((0.0..1.0).contains(&rng.unit()));
+    }
+  }
+}
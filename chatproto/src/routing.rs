@@ -0,0 +1,129 @@
+//! next hop computation, from the announced routes
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::messages::{NextHop, OriginServer, ServerId};
+
+/// Knows the federation graph, as learnt from announces.
+///
+/// An announced route lists servers from the origin of the announce to our neighbour, so its last
+/// element is directly connected to us. Every announce adds its links to the graph, and paths are
+/// the shortest ones in that graph.
+pub struct Router {
+  me: ServerId,
+  links: HashMap<ServerId, Vec<ServerId>>,
+}
+
+impl Router {
+  pub fn new(me: ServerId) -> Self {
+    Router {
+      me,
+      links: HashMap::new(),
+    }
+  }
+
+  fn link(&mut self, a: ServerId, b: ServerId) {
+    let la = self.links.entry(a).or_default();
+    if !la.contains(&b) {
+      la.push(b);
+    }
+    let lb = self.links.entry(b).or_default();
+    if !lb.contains(&a) {
+      lb.push(a);
+    }
+  }
+
+  /// learns the links of an announced route, and tells where it comes from
+  /// empty routes are ignored
+  pub fn on_announce(&mut self, route: &[ServerId]) -> Option<OriginServer> {
+    for window in route.windows(2) {
+      self.link(window[0], window[1]);
+    }
+    let &neighbour = route.last()?;
+    self.link(self.me, neighbour);
+    Some(OriginServer(route[0]))
+  }
+
+  /// servers directly connected to us
+  pub fn neighbours(&self) -> Vec<NextHop> {
+    self
+      .links
+      .get(&self.me)
+      .into_iter()
+      .flatten()
+      .copied()
+      .map(NextHop)
+      .collect()
+  }
+
+  /// shortest path from us to `destination`, both included
+  pub fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    let mut queue = VecDeque::new();
+    // visited servers and their predecessors
+    let mut visited = HashMap::new();
+    queue.push_back(self.me);
+    visited.insert(self.me, None);
+
+    while let Some(current) = queue.pop_front() {
+      if current == destination {
+        let mut path = Vec::new();
+        let mut node = Some(current);
+        while let Some(n) = node {
+          path.push(n);
+          node = visited.get(&n).and_then(|&v| v);
+        }
+        path.reverse();
+        return Some(path);
+      }
+      for &neighbour in self.links.get(&current).into_iter().flatten() {
+        visited.entry(neighbour).or_insert_with(|| {
+          queue.push_back(neighbour);
+          Some(current)
+        });
+      }
+    }
+    None
+  }
+
+  /// neighbour to send to, in order to reach `destination`
+  /// there is none for ourselves, or for unknown servers
+  pub fn next_hop(&self, destination: ServerId) -> Option<NextHop> {
+    self.route_to(destination)?.get(1).copied().map(NextHop)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn shortest() {
+    /* map:
+
+         us - s1 - s2
+          |         |
+         s5 - s4 - s3
+    */
+    let me = ServerId::from(0);
+    let s: Vec<ServerId> = (0..6).map(ServerId::from).collect();
+    let mut router = Router::new(me);
+    assert_eq!(router.next_hop(s[4]), None);
+    assert_eq!(
+      router.on_announce(&[s[4], s[3], s[2], s[1]]),
+      Some(OriginServer(s[4]))
+    );
+    assert_eq!(
+      router.route_to(s[4]),
+      Some(vec![me, s[1], s[2], s[3], s[4]])
+    );
+    assert_eq!(router.next_hop(s[4]), Some(NextHop(s[1])));
+    router.on_announce(&[s[2], s[3], s[4], s[5]]);
+    assert_eq!(router.route_to(s[4]), Some(vec![me, s[5], s[4]]));
+    assert_eq!(router.next_hop(s[4]), Some(NextHop(s[5])));
+    assert_eq!(router.next_hop(s[1]), Some(NextHop(s[1])));
+    assert_eq!(router.next_hop(me), None);
+    assert_eq!(router.neighbours(), [NextHop(s[1]), NextHop(s[5])]);
+    assert_eq!(router.on_announce(&[]), None);
+    assert_eq!(router.next_hop(ServerId::from(42)), None);
+  }
+}
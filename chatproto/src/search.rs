@@ -0,0 +1,226 @@
+//! full-text search over the client histories, behind the `search` feature
+//!
+//! Each local client has its own inverted index, of the messages of its history, so that searches
+//! never see the messages of other clients. The index follows the history: messages are added
+//! when they are polled, reindexed when they are edited, and removed when they are deleted or
+//! fall out of the history. What is not plain text (binary payloads, custom content types, and
+//! end-to-end encrypted conversations) is left out by the server.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::messages::{ClientId, MessageId, SearchQuery};
+
+/// the words of a text, lowercased, without duplicates
+pub fn terms(text: &str) -> BTreeSet<String> {
+  text
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(str::to_lowercase)
+    .collect()
+}
+
+struct Document {
+  id: MessageId,
+  src: ClientId,
+  polled: u64,
+  terms: BTreeSet<String>,
+}
+
+/// The inverted index of the history of one client.
+#[derive(Default)]
+pub struct SearchIndex {
+  // in polling order
+  documents: BTreeMap<u64, Document>,
+  // where each message is in `documents`
+  positions: HashMap<MessageId, u64>,
+  // the documents with each term
+  postings: HashMap<String, BTreeSet<u64>>,
+  next: u64,
+}
+
+impl SearchIndex {
+  /// indexes a message that was just polled, `polled` is in milliseconds since the unix epoch
+  pub fn insert(&mut self, id: MessageId, src: ClientId, polled: u64, text: &str) {
+    self.remove(id);
+    let position = self.next;
+    self.next += 1;
+    let terms = terms(text);
+    for term in &terms {
+      self
+        .postings
+        .entry(term.clone())
+        .or_default()
+        .insert(position);
+    }
+    self.positions.insert(id, position);
+    self.documents.insert(
+      position,
+      Document {
+        id,
+        src,
+        polled,
+        terms,
+      },
+    );
+  }
+
+  /// replaces the text of an indexed message, messages that were not indexed stay so
+  pub fn update(&mut self, id: MessageId, text: &str) {
+    let Some(position) = self.positions.get(&id).copied() else {
+      return;
+    };
+    let terms = terms(text);
+    let document = self.documents.get_mut(&position).expect("indexed");
+    for term in document.terms.difference(&terms) {
+      Self::unpost(&mut self.postings, term, position);
+    }
+    for term in terms.difference(&document.terms) {
+      self
+        .postings
+        .entry(term.clone())
+        .or_default()
+        .insert(position);
+    }
+    document.terms = terms;
+  }
+
+  pub fn remove(&mut self, id: MessageId) {
+    let Some(position) = self.positions.remove(&id) else {
+      return;
+    };
+    if let Some(document) = self.documents.remove(&position) {
+      for term in &document.terms {
+        Self::unpost(&mut self.postings, term, position);
+      }
+    }
+  }
+
+  fn unpost(postings: &mut HashMap<String, BTreeSet<u64>>, term: &str, position: u64) {
+    if let Some(positions) = postings.get_mut(term) {
+      positions.remove(&position);
+      if positions.is_empty() {
+        postings.remove(term);
+      }
+    }
+  }
+
+  /// the ids and polling times of the messages that match, newest first, and where the next
+  /// page starts
+  /// as with `client_history`, nothing matches when `before` is not indexed
+  pub fn search(
+    &self,
+    query: &SearchQuery,
+    limit: usize,
+  ) -> (Vec<(MessageId, u64)>, Option<MessageId>) {
+    let end = match query.before {
+      None => u64::MAX,
+      Some(id) => match self.positions.get(&id) {
+        Some(position) => *position,
+        None => return (Vec::new(), None),
+      },
+    };
+    let wanted: BTreeSet<String> = query.terms.iter().flat_map(|t| terms(t)).collect();
+    // the rarest term gives the candidates, the others are checked on the documents
+    let candidates: Box<dyn Iterator<Item = u64>> = if wanted.is_empty() {
+      Box::new(
+        self
+          .documents
+          .range(..end)
+          .rev()
+          .map(|(position, _)| *position),
+      )
+    } else {
+      let rarest = wanted
+        .iter()
+        .map(|term| self.postings.get(term))
+        .min_by_key(|positions| positions.map_or(0, BTreeSet::len));
+      match rarest {
+        Some(Some(positions)) => Box::new(positions.range(..end).rev().copied()),
+        _ => return (Vec::new(), None),
+      }
+    };
+    let mut hits = Vec::new();
+    for position in candidates {
+      let document = &self.documents[&position];
+      let matches = wanted.is_subset(&document.terms)
+        && query.participant.is_none_or(|p| p == document.src)
+        && query.since.is_none_or(|since| document.polled >= since)
+        && query.until.is_none_or(|until| document.polled < until);
+      if !matches {
+        continue;
+      }
+      if hits.len() == limit {
+        let next = hits.last().map(|(id, _)| *id);
+        return (hits, next);
+      }
+      hits.push((document.id, document.polled));
+    }
+    (hits, None)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use uuid::Uuid;
+
+  fn id(n: u128) -> MessageId {
+    MessageId(Uuid::from_u128(n))
+  }
+
+  fn query(terms: &[&str]) -> SearchQuery {
+    SearchQuery {
+      terms: terms.iter().map(|t| t.to_string()).collect(),
+      ..SearchQuery::default()
+    }
+  }
+
+  #[test]
+  fn index() {
+    let (a, b) = (ClientId(Uuid::from_u128(1)), ClientId(Uuid::from_u128(2)));
+    let mut index = SearchIndex::default();
+    index.insert(id(1), a, 100, "Hello, world!");
+    index.insert(id(2), b, 200, "hello again");
+    index.insert(id(3), a, 300, "goodbye WORLD");
+
+    let ids = |(hits, _): (Vec<(MessageId, u64)>, _)| -> Vec<MessageId> {
+      hits.into_iter().map(|(id, _)| id).collect()
+    };
+    assert_eq!(ids(index.search(&query(&["world"]), 10)), [id(3), id(1)]);
+    assert_eq!(ids(index.search(&query(&["HELLO world"]), 10)), [id(1)]);
+    assert!(ids(index.search(&query(&["nowhere"]), 10)).is_empty());
+    assert_eq!(ids(index.search(&query(&[]), 10)).len(), 3);
+
+    let from_a = SearchQuery {
+      participant: Some(a),
+      ..query(&["hello"])
+    };
+    assert_eq!(ids(index.search(&from_a, 10)), [id(1)]);
+    let window = SearchQuery {
+      since: Some(150),
+      until: Some(300),
+      ..query(&[])
+    };
+    assert_eq!(index.search(&window, 10).0, [(id(2), 200)]);
+
+    // paging
+    let (first, next) = index.search(&query(&[]), 2);
+    assert_eq!(first.len(), 2);
+    assert_eq!(next, Some(id(2)));
+    let rest = SearchQuery {
+      before: next,
+      ..query(&[])
+    };
+    assert_eq!(index.search(&rest, 2), (vec![(id(1), 100)], None));
+
+    // edits and deletions
+    index.update(id(3), "see you");
+    assert_eq!(ids(index.search(&query(&["world"]), 10)), [id(1)]);
+    assert_eq!(ids(index.search(&query(&["you"]), 10)), [id(3)]);
+    index.remove(id(1));
+    assert!(ids(index.search(&query(&["world"]), 10)).is_empty());
+    index.update(id(1), "not indexed anymore");
+    assert!(ids(index.search(&query(&["indexed"]), 10)).is_empty());
+    assert_eq!(index.postings.len(), 4);
+  }
+}
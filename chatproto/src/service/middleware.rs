@@ -0,0 +1,465 @@
+//! built-in layers
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+
+use crate::messages::{ClientId, ClientQuery, TransportError};
+use crate::ratelimit::TokenBuckets;
+use crate::service::{Layer, Request, Response, Service};
+
+fn query_kind(query: &ClientQuery) -> &'static str {
+  match query {
+    ClientQuery::Register(_) => "register",
+    ClientQuery::Message(_) => "message",
+    ClientQuery::Poll | ClientQuery::PollN(_) | ClientQuery::PollWait(_) => "poll",
+    ClientQuery::ListUsers | ClientQuery::ListUserEntries | ClientQuery::ListUsersPage(_) => {
+      "list_users"
+    }
+    ClientQuery::LookupUser(_) => "lookup_user",
+    ClientQuery::Search(_) => "search",
+    ClientQuery::Sync { .. } => "sync",
+    ClientQuery::Report { .. } => "report",
+    ClientQuery::GetPrefs => "get_prefs",
+    ClientQuery::SetPrefs(_) => "set_prefs",
+    ClientQuery::SetBlocked(_) => "set_blocked",
+    ClientQuery::RegisterGuest(_) => "register_guest",
+    ClientQuery::RegisterInvited { .. } => "register_invited",
+    ClientQuery::RegisterTrusted { .. } => "register_trusted",
+    ClientQuery::Upgrade => "upgrade",
+    ClientQuery::CreateRoom(_) => "create_room",
+    ClientQuery::JoinRoom(_) => "join_room",
+    ClientQuery::LeaveRoom(_) => "leave_room",
+    ClientQuery::Unregister => "unregister",
+    ClientQuery::Rename(_) => "rename",
+    ClientQuery::Ping(_) => "ping",
+    ClientQuery::Hello(_) => "hello",
+  }
+}
+
+/// Logs every request, with its outcome and how long it took.
+#[derive(Clone, Copy, Default)]
+pub struct LogLayer {}
+
+pub struct Log<S> {
+  inner: S,
+}
+
+impl<S> Layer<S> for LogLayer {
+  type Service = Log<S>;
+
+  fn layer(&self, inner: S) -> Log<S> {
+    Log { inner }
+  }
+}
+
+#[async_trait]
+impl<S: Service + Send + Sync> Service for Log<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    let (peer, src, kind) = (req.peer, req.query.src, query_kind(&req.query.content));
+    let start = Instant::now();
+    let rsp = self.inner.call(req).await;
+    match &rsp {
+      Ok(_) => log::info!("{} {} {} ok in {:?}", peer, src, kind, start.elapsed()),
+      Err(rr) => log::info!(
+        "{} {} {} failed in {:?}: {}",
+        peer,
+        src,
+        kind,
+        start.elapsed(),
+        rr
+      ),
+    }
+    rsp
+  }
+}
+
+/// Refuses requests decoded from frames larger than `max` bytes.
+#[derive(Clone, Copy)]
+pub struct SizeLimitLayer {
+  pub max: usize,
+}
+
+pub struct SizeLimit<S> {
+  inner: S,
+  max: usize,
+}
+
+impl<S> Layer<S> for SizeLimitLayer {
+  type Service = SizeLimit<S>;
+
+  fn layer(&self, inner: S) -> SizeLimit<S> {
+    SizeLimit {
+      inner,
+      max: self.max,
+    }
+  }
+}
+
+#[async_trait]
+impl<S: Service + Send + Sync> Service for SizeLimit<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    let max = req.max_size.unwrap_or(self.max);
+    if req.size > max {
+      return Err(
+        anyhow::Error::new(TransportError::TooLarge)
+          .context(format!("Request too large ({} > {} bytes)", req.size, max)),
+      );
+    }
+    self.inner.call(req).await
+  }
+}
+
+/// Token bucket rate limiting, per peer address.
+///
+/// Each address can make `burst` requests at once, and then `rate` requests per second.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+  buckets: Arc<Mutex<TokenBuckets<IpAddr>>>,
+}
+
+impl RateLimitLayer {
+  pub fn new(rate: f64, burst: u32) -> Self {
+    RateLimitLayer {
+      buckets: Arc::new(Mutex::new(TokenBuckets::new(rate, burst))),
+    }
+  }
+
+  async fn acquire(&self, ip: IpAddr) -> bool {
+    self.buckets.lock().await.acquire(ip, Instant::now())
+  }
+}
+
+pub struct RateLimit<S> {
+  inner: S,
+  limiter: RateLimitLayer,
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+  type Service = RateLimit<S>;
+
+  fn layer(&self, inner: S) -> RateLimit<S> {
+    RateLimit {
+      inner,
+      limiter: self.clone(),
+    }
+  }
+}
+
+#[async_trait]
+impl<S: Service + Send + Sync> Service for RateLimit<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    if !self.limiter.acquire(req.peer.ip()).await {
+      return Err(
+        anyhow::Error::new(TransportError::RateLimited)
+          .context(format!("Rate limit exceeded for {}", req.peer.ip())),
+      );
+    }
+    self.inner.call(req).await
+  }
+}
+
+/// Limits the polls waiting at the same time (`PollWait`), per client and per peer address.
+///
+/// A waiting poll holds on to its request until something arrives, or it times out: the ones over
+/// a limit are refused with `RateLimited`. The other queries are not limited.
+#[derive(Clone)]
+pub struct WaitLimitLayer {
+  waits: Arc<std::sync::Mutex<Waits>>,
+  per_client: usize,
+  per_address: usize,
+}
+
+#[derive(Default)]
+struct Waits {
+  clients: HashMap<ClientId, usize>,
+  addresses: HashMap<IpAddr, usize>,
+}
+
+impl WaitLimitLayer {
+  pub fn new(per_client: usize, per_address: usize) -> Self {
+    WaitLimitLayer {
+      waits: Arc::default(),
+      per_client,
+      per_address,
+    }
+  }
+
+  // none when `client` or `ip` already wait as much as they can
+  fn acquire(&self, client: ClientId, ip: IpAddr) -> Option<Waiting> {
+    let mut waits = self.waits.lock().unwrap();
+    let by_client = waits.clients.get(&client).copied().unwrap_or(0);
+    let by_address = waits.addresses.get(&ip).copied().unwrap_or(0);
+    if by_client >= self.per_client || by_address >= self.per_address {
+      return None;
+    }
+    *waits.clients.entry(client).or_default() += 1;
+    *waits.addresses.entry(ip).or_default() += 1;
+    Some(Waiting {
+      waits: self.waits.clone(),
+      client,
+      ip,
+    })
+  }
+}
+
+// a waiting poll, until dropped
+struct Waiting {
+  waits: Arc<std::sync::Mutex<Waits>>,
+  client: ClientId,
+  ip: IpAddr,
+}
+
+impl Drop for Waiting {
+  fn drop(&mut self) {
+    fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+      if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+          counts.remove(key);
+        }
+      }
+    }
+    let mut waits = self.waits.lock().unwrap();
+    release(&mut waits.clients, &self.client);
+    release(&mut waits.addresses, &self.ip);
+  }
+}
+
+pub struct WaitLimit<S> {
+  inner: S,
+  limiter: WaitLimitLayer,
+}
+
+impl<S> Layer<S> for WaitLimitLayer {
+  type Service = WaitLimit<S>;
+
+  fn layer(&self, inner: S) -> WaitLimit<S> {
+    WaitLimit {
+      inner,
+      limiter: self.clone(),
+    }
+  }
+}
+
+#[async_trait]
+impl<S: Service + Send + Sync> Service for WaitLimit<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    if !matches!(req.query.content, ClientQuery::PollWait(_)) {
+      return self.inner.call(req).await;
+    }
+    let (src, ip) = (req.query.src, req.peer.ip());
+    let _waiting = match self.limiter.acquire(src, ip) {
+      Some(waiting) => waiting,
+      None => {
+        return Err(
+          anyhow::Error::new(TransportError::RateLimited)
+            .context(format!("Too many waiting polls for {} from {}", src, ip)),
+        )
+      }
+    };
+    self.inner.call(req).await
+  }
+}
+
+/// Only lets through the requests accepted by a policy.
+#[derive(Clone)]
+pub struct AuthLayer<P> {
+  policy: Arc<P>,
+}
+
+impl<P: Fn(&Request) -> bool> AuthLayer<P> {
+  pub fn new(policy: P) -> Self {
+    AuthLayer {
+      policy: Arc::new(policy),
+    }
+  }
+}
+
+pub struct Auth<S, P> {
+  inner: S,
+  policy: Arc<P>,
+}
+
+impl<S, P> Layer<S> for AuthLayer<P> {
+  type Service = Auth<S, P>;
+
+  fn layer(&self, inner: S) -> Auth<S, P> {
+    Auth {
+      inner,
+      policy: self.policy.clone(),
+    }
+  }
+}
+
+#[async_trait]
+impl<S, P> Service for Auth<S, P>
+where
+  S: Service + Send + Sync,
+  P: Fn(&Request) -> bool + Send + Sync,
+{
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    if !(self.policy)(&req) {
+      return Err(
+        anyhow::Error::new(TransportError::AuthRequired).context(format!(
+          "Unauthorized {} from {}",
+          query_kind(&req.query.content),
+          req.peer
+        )),
+      );
+    }
+    self.inner.call(req).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::messages::{ClientId, Codec, Sequence};
+  use crate::service::ServiceExt;
+
+  /// answers everything with an empty reply
+  struct Echo;
+
+  #[async_trait]
+  impl Service for Echo {
+    async fn call(&self, _req: Request) -> anyhow::Result<Response> {
+      Ok(Response::default())
+    }
+  }
+
+  fn request(peer: &str, size: usize, content: ClientQuery) -> Request {
+    Request {
+      peer: peer.parse().unwrap(),
+      size,
+      max_size: None,
+      codec: Codec::Binary,
+      query: Sequence {
+        seqid: 1,
+        src: ClientId::default(),
+        content,
+      },
+    }
+  }
+
+  #[test]
+  fn size_limit() {
+    async_std::task::block_on(async {
+      let service = Echo.with(SizeLimitLayer { max: 10 });
+      assert!(service
+        .call(request("127.0.0.1:1", 10, ClientQuery::Poll))
+        .await
+        .is_ok());
+      let rr = service
+        .call(request("127.0.0.1:1", 11, ClientQuery::Poll))
+        .await
+        .unwrap_err();
+      assert_eq!(TransportError::of(&rr), TransportError::TooLarge);
+    })
+  }
+
+  #[test]
+  fn rate_limit() {
+    async_std::task::block_on(async {
+      let service = Echo.with(RateLimitLayer::new(1000.0, 2));
+      for _ in 0..2 {
+        assert!(service
+          .call(request("10.0.0.1:1", 1, ClientQuery::Poll))
+          .await
+          .is_ok());
+      }
+      let rr = service
+        .call(request("10.0.0.1:2", 1, ClientQuery::Poll))
+        .await
+        .unwrap_err();
+      assert_eq!(TransportError::of(&rr), TransportError::RateLimited);
+      // other addresses have their own budget
+      assert!(service
+        .call(request("10.0.0.2:1", 1, ClientQuery::Poll))
+        .await
+        .is_ok());
+      // and it refills over time
+      async_std::task::sleep(Duration::from_millis(5)).await;
+      assert!(service
+        .call(request("10.0.0.1:1", 1, ClientQuery::Poll))
+        .await
+        .is_ok());
+    })
+  }
+
+  /// answers the waiting polls once its channel is closed, and the rest right away
+  struct Waiter(async_std::channel::Receiver<()>);
+
+  #[async_trait]
+  impl Service for Waiter {
+    async fn call(&self, req: Request) -> anyhow::Result<Response> {
+      if let ClientQuery::PollWait(_) = req.query.content {
+        let _ = self.0.recv().await;
+      }
+      Ok(Response::default())
+    }
+  }
+
+  #[test]
+  fn wait_limit() {
+    async_std::task::block_on(async {
+      let (release, waiting) = async_std::channel::bounded(1);
+      let service = Arc::new(Waiter(waiting).with(WaitLimitLayer::new(1, 2)));
+      let wait = |peer: &str, src: u128| {
+        let mut req = request(peer, 1, ClientQuery::PollWait(1000));
+        req.query.src = ClientId::from(src);
+        let service = service.clone();
+        async_std::task::spawn(async move { service.call(req).await })
+      };
+      let first = wait("10.0.0.1:1", 1);
+      let second = wait("10.0.0.1:2", 2);
+      async_std::task::sleep(Duration::from_millis(20)).await;
+
+      // one waiting poll per client, and two per address
+      for (peer, src) in [("10.0.0.2:1", 1), ("10.0.0.1:3", 3)] {
+        let rr = wait(peer, src).await.unwrap_err();
+        assert_eq!(TransportError::of(&rr), TransportError::RateLimited);
+      }
+      // the other queries are not limited
+      let mut poll = request("10.0.0.1:1", 1, ClientQuery::Poll);
+      poll.query.src = ClientId::from(1);
+      assert!(service.call(poll).await.is_ok());
+
+      // a dropped poll does not wait anymore
+      assert!(first.cancel().await.is_none());
+      let third = wait("10.0.0.1:3", 3);
+      release.close();
+      assert!(second.await.is_ok() && third.await.is_ok());
+    })
+  }
+
+  #[test]
+  fn auth_and_logging() {
+    async_std::task::block_on(async {
+      let service = Echo
+        .with(AuthLayer::new(|req: &Request| {
+          !matches!(req.query.content, ClientQuery::RegisterGuest(_))
+        }))
+        .with(LogLayer::default());
+      assert!(service
+        .call(request("127.0.0.1:1", 1, ClientQuery::Register("a".into())))
+        .await
+        .is_ok());
+      let rr = service
+        .call(request(
+          "127.0.0.1:1",
+          1,
+          ClientQuery::RegisterGuest("a".into()),
+        ))
+        .await
+        .unwrap_err();
+      assert_eq!(TransportError::of(&rr), TransportError::AuthRequired);
+    })
+  }
+}
@@ -0,0 +1,593 @@
+//! client queries as a request/response service
+//!
+//! A transport decodes a `Request`, hands it to a `Service`, and sends back the encoded reply of
+//! the `Response`. Cross-cutting concerns (logging, limits, policy) are `Layer`s wrapping the
+//! `ServerService`, instead of being wired by hand in every transport.
+
+use std::io::Cursor;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::core::{MessageServer, SpamChecker, MAX_POLL_WAIT};
+use crate::federation;
+use crate::messages::{
+  ClientError, ClientId, ClientQuery, ClientReply, Codec, NextHop, RoomId, Sequence, ServerMessage,
+  TransportError,
+};
+use crate::netproto::{codec, encode};
+
+pub mod middleware;
+
+/// a decoded client query, and where it came from
+#[derive(Clone, Debug)]
+pub struct Request {
+  pub peer: SocketAddr,
+  /// size of the frame the query was decoded from
+  pub size: usize,
+  /// largest frame the transport negotiated for this peer, if any (see `mux`), that replaces the
+  /// default of the size limit
+  pub max_size: Option<usize>,
+  /// the codec the query was decoded with, that the reply is encoded with
+  pub codec: Codec,
+  pub query: Sequence<ClientQuery>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+  /// encoded reply, to send back to the client
+  pub reply: Vec<u8>,
+  /// messages to hand to the federation driver
+  pub transfers: Vec<(NextHop, ServerMessage)>,
+  /// the client the query was authenticated as: the sender of a valid sequenced query, or the
+  /// client a registration created
+  pub client: Option<ClientId>,
+}
+
+impl Response {
+  fn reply(reply: Vec<u8>) -> Self {
+    Response {
+      reply,
+      transfers: Vec::new(),
+      client: None,
+    }
+  }
+}
+
+/// the frame a transport sends back, for a reply or a failed request
+pub fn frame(reply: Result<&[u8], TransportError>) -> Vec<u8> {
+  let mut ocurs = Cursor::new(Vec::new());
+  // writing to a Vec can't fail
+  let _ = match reply {
+    Ok(reply) => encode::reply_frame(&mut ocurs, reply),
+    Err(rr) => encode::error_frame(&mut ocurs, &rr),
+  };
+  ocurs.into_inner()
+}
+
+#[async_trait]
+pub trait Service {
+  async fn call(&self, req: Request) -> anyhow::Result<Response>;
+}
+
+#[async_trait]
+impl<S: Service + Send + Sync + ?Sized> Service for Arc<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    (**self).call(req).await
+  }
+}
+
+#[async_trait]
+impl<S: Service + Send + Sync + ?Sized> Service for Box<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    (**self).call(req).await
+  }
+}
+
+/// Wraps a service into another one.
+pub trait Layer<S> {
+  type Service;
+
+  fn layer(&self, inner: S) -> Self::Service;
+}
+
+pub trait ServiceExt: Service + Sized {
+  /// wraps this service with a layer, the last layer added sees the requests first
+  fn with<L: Layer<Self>>(self, layer: L) -> L::Service {
+    layer.layer(self)
+  }
+}
+
+impl<S: Service + Sized> ServiceExt for S {}
+
+/// The innermost service, answering queries with a `MessageServer`.
+pub struct ServerService<S, C> {
+  server: Arc<S>,
+  codecs: Vec<Codec>,
+  checker: PhantomData<fn() -> C>,
+}
+
+impl<S, C> ServerService<S, C> {
+  pub fn new(server: Arc<S>) -> Self {
+    ServerService {
+      server,
+      codecs: vec![Codec::Binary],
+      checker: PhantomData,
+    }
+  }
+
+  /// the codecs a hello can pick, on top of binary, which the transports must also decode
+  pub fn with_codecs(mut self, codecs: Vec<Codec>) -> Self {
+    self.codecs = codecs;
+    self
+  }
+
+  pub fn server(&self) -> &Arc<S> {
+    &self.server
+  }
+}
+
+/// the reply, encoded with the codec of its query
+fn encoded<T, ENC>(codec: Codec, reply: &T, e: ENC) -> anyhow::Result<Response>
+where
+  T: Serialize + ?Sized,
+  ENC: FnOnce(&mut Cursor<Vec<u8>>, &T) -> std::io::Result<()>,
+{
+  Ok(Response::reply(codec::encode(codec, reply, e)?))
+}
+
+fn replies(codec: Codec, repl: &[ClientReply]) -> anyhow::Result<Response> {
+  encoded(codec, repl, encode::client_replies)
+}
+
+/// the replies, with the transfers handed to the federation driver, the ones of a `Multi` too
+fn transfers(codec: Codec, repl: Vec<ClientReply>) -> anyhow::Result<Response> {
+  let mut response = replies(codec, &repl)?;
+  response.transfers = federation::transfers(repl)
+    .into_iter()
+    .map(|o| (o.nexthop, o.message))
+    .collect();
+  Ok(response)
+}
+
+/// the response, with the new members of `room` handed to the federation driver
+async fn announced<S, C>(srv: &S, room: RoomId, mut response: Response) -> anyhow::Result<Response>
+where
+  S: MessageServer<C>,
+  C: SpamChecker,
+{
+  let announces = srv.announce_room(room).await;
+  response
+    .transfers
+    .extend(announces.into_iter().map(|o| (o.nexthop, o.message)));
+  Ok(response)
+}
+
+/// the reply to a join or a leave, announced when the members changed
+async fn room_change<S, C>(
+  srv: &S,
+  codec: Codec,
+  room: RoomId,
+  reply: ClientReply,
+) -> anyhow::Result<Response>
+where
+  S: MessageServer<C>,
+  C: SpamChecker,
+{
+  let response = replies(codec, std::slice::from_ref(&reply))?;
+  match reply {
+    ClientReply::Error(_) => Ok(response),
+    _ => announced(srv, room, response).await,
+  }
+}
+
+#[async_trait]
+impl<S, C> Service for ServerService<S, C>
+where
+  S: MessageServer<C> + Send + Sync,
+  C: SpamChecker,
+{
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    let srv = &*self.server;
+    let (m, codec) = (req.query, req.codec);
+    log::debug!("received {:?}", m);
+    let src = m.src;
+
+    // liveness checks are answered by the transport layer, whoever sends them
+    if let ClientQuery::Ping(nonce) = m.content {
+      return replies(codec, &[ClientReply::Pong(nonce)]);
+    }
+    // and so are hellos, the transports decode the codecs this service enables
+    if let ClientQuery::Hello(offered) = &m.content {
+      let picked = codec::pick(offered, &self.codecs);
+      return encoded(codec, &picked, encode::codec);
+    }
+
+    // handle register
+    if matches!(
+      m.content,
+      ClientQuery::Register(_)
+        | ClientQuery::RegisterGuest(_)
+        | ClientQuery::RegisterInvited { .. }
+    ) {
+      log::debug!("handle register message");
+      let content = m.content.clone();
+      match srv.handle_sequenced_message(m).await {
+        Ok(_) => (),
+        Err(ClientError::UnknownClient) => (),
+        Err(rr) => {
+          anyhow::bail!("Error when handling register message: {}", rr);
+        }
+      }
+      let src_ip = req.peer.ip();
+      let id = match content {
+        ClientQuery::RegisterGuest(name) => srv.register_guest(src_ip, name).await,
+        ClientQuery::RegisterInvited { name, invite } => {
+          srv.register_invited_client(src_ip, name, invite).await
+        }
+        ClientQuery::Register(name) => srv.register_local_client(src_ip, name).await,
+        _ => unreachable!(),
+      }
+      .map_err(|rr| anyhow::anyhow!("registration refused: {}", rr))?;
+      let mut rsp = encoded(codec, &id, encode::clientid)?;
+      rsp.client = Some(id);
+      return Ok(rsp);
+    }
+
+    let mut rsp = match srv.handle_sequenced_message(m).await? {
+      ClientQuery::Poll => {
+        let repl = srv.client_poll(src).await;
+        log::debug!(" -> poll {:?}", repl);
+        encoded(codec, &repl, encode::client_poll_reply)
+      }
+      ClientQuery::PollWait(millis) => {
+        let wait = Duration::from_millis(u64::try_from(millis).unwrap_or(u64::MAX));
+        let repl = srv.client_poll_wait(src, wait.min(MAX_POLL_WAIT)).await;
+        log::debug!(" -> poll {:?}", repl);
+        encoded(codec, &repl, encode::client_poll_reply)
+      }
+      ClientQuery::Sync {
+        cursor,
+        limit,
+        compact,
+      } => {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        let repl = srv.sync(src, &cursor, limit).await;
+        log::debug!(" -> sync {:?}", repl);
+        // only the binary codec has a compact encoding
+        encoded(codec, &repl, |w, repl| {
+          if compact {
+            encode::compact_sync_reply(w, repl)
+          } else {
+            encode::sync_reply(w, repl)
+          }
+        })
+      }
+      ClientQuery::PollN(max) => {
+        let max = usize::try_from(max).unwrap_or(usize::MAX);
+        let repl = srv.client_poll_n(src, max).await;
+        log::debug!(" -> poll {:?}", repl);
+        encoded(codec, &repl[..], encode::client_poll_replies)
+      }
+      ClientQuery::ListUsers => {
+        let mut repl = srv.list_users().await;
+        // bracketed, so that they can't be mistaken for clients
+        repl.extend(
+          ClientId::SYSTEM
+            .iter()
+            .map(|(id, name)| (*id, format!("[{}]", name))),
+        );
+        encoded(codec, &repl, encode::userlist)
+      }
+      ClientQuery::ListUserEntries => encoded(
+        codec,
+        &srv.list_user_entries().await[..],
+        encode::user_entries,
+      ),
+      ClientQuery::ListUsersPage(query) => {
+        encoded(codec, &srv.list_users_page(&query).await, encode::user_page)
+      }
+      ClientQuery::Search(query) => encoded(
+        codec,
+        &srv.search_history(src, &query).await,
+        encode::search_reply,
+      ),
+      ClientQuery::LookupUser(name) => {
+        encoded(codec, &srv.lookup_user(&name).await, encode::user_lookup)
+      }
+      ClientQuery::Register(_)
+      | ClientQuery::RegisterGuest(_)
+      | ClientQuery::RegisterInvited { .. } => {
+        anyhow::bail!("Unexpected register message from enrolled client")
+      }
+      ClientQuery::Ping(_) | ClientQuery::Hello(_) => unreachable!(),
+      ClientQuery::Upgrade => match srv.upgrade_guest(src).await {
+        Ok(()) => replies(codec, &[ClientReply::Delivered(None)]),
+        Err(rr) => replies(codec, &[ClientReply::Error(rr)]),
+      },
+      ClientQuery::Report { target, reason } => {
+        replies(codec, &[srv.handle_report(src, target, reason).await])
+      }
+      ClientQuery::GetPrefs => {
+        let prefs = srv.notification_prefs(src).await?;
+        encoded(codec, &prefs, encode::notification_prefs)
+      }
+      ClientQuery::SetPrefs(prefs) => {
+        replies(codec, &[srv.set_notification_prefs(src, prefs).await])
+      }
+      ClientQuery::SetBlocked(blocked) => replies(codec, &[srv.set_blocked(src, blocked).await]),
+      ClientQuery::RegisterTrusted { id, name } => {
+        match srv
+          .register_trusted_client(src, id, name, req.peer.ip())
+          .await
+        {
+          Ok(()) => replies(codec, &[ClientReply::Delivered(None)]),
+          Err(rr) => replies(codec, &[ClientReply::Error(rr)]),
+        }
+      }
+      ClientQuery::CreateRoom(name) => {
+        let room = srv.create_room(src, name).await?;
+        announced(srv, room, encoded(codec, &room, encode::roomid)?).await
+      }
+      ClientQuery::JoinRoom(room) => {
+        room_change(srv, codec, room, srv.join_room(src, room).await).await
+      }
+      ClientQuery::LeaveRoom(room) => {
+        room_change(srv, codec, room, srv.leave_room(src, room).await).await
+      }
+      ClientQuery::Message(msg) => transfers(codec, srv.handle_client_message(src, msg).await),
+      ClientQuery::Unregister => transfers(codec, srv.unregister_local_client(src).await),
+      ClientQuery::Rename(name) => transfers(codec, srv.rename_client(src, name).await),
+    }?;
+    rsp.client = Some(src);
+    Ok(rsp)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+  use crate::client::Client;
+  use crate::core::{DefaultChecker, ServerConfig};
+  use crate::messages::{ClientId, ClientMessage, ClientPollReply, ServerId};
+  use crate::netproto::decode;
+  use crate::solutions::descamps_femery::Server;
+  use crate::testing::Unstamped;
+
+  struct Counting<S> {
+    inner: S,
+    calls: Arc<AtomicUsize>,
+  }
+
+  #[async_trait]
+  impl<S: Service + Send + Sync> Service for Counting<S> {
+    async fn call(&self, req: Request) -> anyhow::Result<Response> {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      self.inner.call(req).await
+    }
+  }
+
+  struct CountingLayer(Arc<AtomicUsize>);
+
+  impl<S> Layer<S> for CountingLayer {
+    type Service = Counting<S>;
+
+    fn layer(&self, inner: S) -> Counting<S> {
+      Counting {
+        inner,
+        calls: self.0.clone(),
+      }
+    }
+  }
+
+  fn request(query: Sequence<ClientQuery>) -> Request {
+    Request {
+      peer: "127.0.0.1:4000".parse().unwrap(),
+      size: 0,
+      max_size: None,
+      codec: Codec::Binary,
+      query,
+    }
+  }
+
+  #[test]
+  fn layered() {
+    async_std::task::block_on(async {
+      let sid = ServerId::default();
+      let server: Server<DefaultChecker> =
+        MessageServer::new(DefaultChecker::default(), sid, ServerConfig::default());
+      let calls = Arc::new(AtomicUsize::new(0));
+      let service = ServerService::new(Arc::new(server)).with(CountingLayer(calls.clone()));
+
+      let register = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Register("user".into()),
+      };
+      let rsp = service.call(request(register)).await.unwrap();
+      let id = decode::clientid(&mut Cursor::new(rsp.reply)).unwrap();
+      let mut client = Client::new(id);
+
+      let send = client.sequence(ClientQuery::Message(ClientMessage::Text {
+        dest: id,
+        content: "hello".into(),
+      }));
+      let rsp = service.call(request(send)).await.unwrap();
+      let repl = decode::client_replies(&mut Cursor::new(rsp.reply)).unwrap();
+      assert!(
+        matches!(repl[..], [ClientReply::Delivered(Some(_))]),
+        "{:?}",
+        repl
+      );
+      assert!(rsp.transfers.is_empty());
+
+      let rsp = service
+        .call(request(client.sequence(ClientQuery::Poll)))
+        .await
+        .unwrap();
+      let repl = decode::client_poll_reply(&mut Cursor::new(rsp.reply)).unwrap();
+      assert_eq!(
+        repl.unstamped(),
+        ClientPollReply::Message {
+          src: id,
+          srcsrv: sid,
+          content: "hello".into(),
+          timestamp: 0,
+        }
+      );
+      assert_eq!(calls.load(Ordering::SeqCst), 3);
+    })
+  }
+
+  #[test]
+  fn multi_transfers() {
+    async_std::task::block_on(async {
+      let server: Server<DefaultChecker> = MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let remote = ClientId::from(1);
+      let announce = ServerMessage::Announce {
+        route: vec![ServerId::from(2)],
+        clients: HashMap::from([(remote, "remote".into())]),
+      };
+      server.handle_server_message(announce).await;
+      let service = ServerService::new(Arc::new(server));
+
+      let register = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Register("user".into()),
+      };
+      let rsp = service.call(request(register)).await.unwrap();
+      let id = decode::clientid(&mut Cursor::new(rsp.reply)).unwrap();
+      let mut client = Client::new(id);
+      let send = client.sequence(ClientQuery::Message(ClientMessage::MText {
+        dest: vec![id, remote],
+        content: "hello".into(),
+      }));
+      let rsp = service.call(request(send)).await.unwrap();
+      assert!(matches!(
+        &rsp.transfers[..],
+        [(nexthop, ServerMessage::Message(m))] if nexthop.server() == ServerId::from(2)
+          && m.dsts == [(remote, ServerId::from(2))]
+      ));
+    })
+  }
+
+  #[test]
+  fn stale_sequence() {
+    async_std::task::block_on(async {
+      let server: Server<DefaultChecker> = MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let service = ServerService::new(Arc::new(server));
+      let register = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Register("user".into()),
+      };
+      let rsp = service.call(request(register)).await.unwrap();
+      let id = decode::clientid(&mut Cursor::new(rsp.reply)).unwrap();
+      let mut client = Client::new(id);
+      client.resync(1000);
+      let poll = client.sequence(ClientQuery::Poll);
+      assert_eq!(poll.seqid, 1000);
+      service.call(request(poll)).await.unwrap();
+
+      // a client that lost its sequence number learns the next one from the error frame
+      let mut restarted = Client::new(id);
+      let rr = service
+        .call(request(restarted.sequence(ClientQuery::Poll)))
+        .await
+        .unwrap_err();
+      let stale = TransportError::SequenceReuse { expected: 1001 };
+      assert_eq!(TransportError::of(&rr), stale);
+      let rr = decode::frame(
+        &mut Cursor::new(frame(Err(TransportError::of(&rr)))),
+        decode::client_poll_reply,
+      )
+      .unwrap_err();
+      assert_eq!(rr.downcast_ref::<TransportError>(), Some(&stale));
+
+      restarted.resync(1001);
+      let poll = restarted.sequence(ClientQuery::Poll);
+      assert_eq!(poll.seqid, 1001);
+      service.call(request(poll)).await.unwrap();
+    })
+  }
+
+  #[test]
+  fn ping() {
+    async_std::task::block_on(async {
+      let server: Server<DefaultChecker> = MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let service = ServerService::new(Arc::new(server));
+      // no registration needed
+      let ping = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Ping(42),
+      };
+      let rsp = service.call(request(ping)).await.unwrap();
+      let repl = decode::client_replies(&mut Cursor::new(rsp.reply)).unwrap();
+      assert_eq!(repl, [ClientReply::Pong(42)]);
+    })
+  }
+
+  #[test]
+  fn hello() {
+    async_std::task::block_on(async {
+      let server: Server<DefaultChecker> = MessageServer::new(
+        DefaultChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let server = Arc::new(server);
+      let hello = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Hello(vec![Codec::Json, Codec::Binary]),
+      };
+      // json is not enabled by default
+      let service = ServerService::new(server.clone());
+      let rsp = service.call(request(hello.clone())).await.unwrap();
+      let picked = decode::codec(&mut Cursor::new(rsp.reply)).unwrap();
+      assert_eq!(picked, Codec::Binary);
+
+      let service = ServerService::new(server).with_codecs(vec![Codec::Json]);
+      let rsp = service.call(request(hello)).await.unwrap();
+      let picked = decode::codec(&mut Cursor::new(rsp.reply)).unwrap();
+      assert_eq!(picked, Codec::Json);
+
+      // replies use the codec of their query
+      let ping = Sequence {
+        seqid: 0,
+        src: ClientId::default(),
+        content: ClientQuery::Ping(42),
+      };
+      let rsp = service
+        .call(Request {
+          codec: Codec::Json,
+          ..request(ping)
+        })
+        .await
+        .unwrap();
+      let repl: Vec<ClientReply> = serde_json::from_slice(&rsp.reply).unwrap();
+      assert_eq!(repl, [ClientReply::Pong(42)]);
+    })
+  }
+}
@@ -1,20 +1,43 @@
-use async_std::{future::timeout, sync::RwLock};
+use async_std::sync::RwLock;
 use async_trait::async_trait;
-use futures::join;
+use futures::{
+  channel::oneshot,
+  join,
+  stream::{self, BoxStream, StreamExt},
+};
 use std::{
-  collections::{HashMap, VecDeque},
+  collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
   net::IpAddr,
-  time::Duration,
+  sync::atomic::{AtomicU64, Ordering},
+  time::{Duration, Instant},
 };
-use uuid::Uuid;
 
 use crate::{
-  core::{MessageServer, SpamChecker, MAILBOX_SIZE},
+  admission::{AdmissionQueue, AdmissionStats},
+  archive::{ArchiveRecord, ExportFilter},
+  authz::{Action, Authorizer, DefaultAuthorizer, Tier},
+  clock::{system_clock, timeout, SharedClock},
+  core::{
+    AdminServer, MessageServer, NamePolicy, OverflowPolicy, SendRate, ServerConfig, SpamChecker,
+    DEFERRED_SIZE, DELAYED_PER_SENDER, DELAYED_SIZE, DELAYED_TOTAL, EVENTS_SIZE, HISTORY_SIZE,
+    MAX_DATA_SIZE, MAX_DESTINATIONS, MAX_REACTION_SIZE, MAX_REPORT_REASON, MESSAGE_TTL,
+    REGISTRATION_CONCURRENCY, REGISTRATION_QUEUE, REORDER_WAIT, REPORTS_PER_REPORTER, REPORTS_SIZE,
+    SEQUENCE_WINDOW, TOMBSTONES_SIZE, TOMBSTONE_GRACE, USER_PAGE_SIZE,
+  },
+  invite::{Invite, Invites},
   messages::{
-    ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, DelayedError,
-    FullyQualifiedMessage, Sequence, ServerId,
+    is_reserved_name, same_name, AbuseReport, ClientError, ClientId, ClientMessage,
+    ClientPollReply, ClientReply, ContentType, DelayedError, Event, FullyQualifiedMessage,
+    HistoryEntry, Mention, MessageBuilder, MessageId, NextHop, NotificationPrefs, OriginServer,
+    Presence, Priority, ReportTarget, RichContent, RoomId, SearchPage, SearchQuery, Sequence,
+    ServerId, ServerSequence, SyncCursor, SyncReply, UserEntry, UserPage, UserQuery,
   },
+  ratelimit::TokenBuckets,
+  rng::{os_rng, SharedRng},
+  routing::Router,
 };
+#[cfg(feature = "search")]
+use crate::{messages::SearchHit, search::SearchIndex};
 
 use crate::messages::{Outgoing, ServerMessage, ServerReply};
 
@@ -23,41 +46,955 @@ use crate::messages::{Outgoing, ServerMessage, ServerReply};
 pub struct Server<C: SpamChecker> {
   checker: C,
   id: ServerId,
+  config: ServerConfig,
   clients: RwLock<HashMap<ClientId, Client>>,
-  routes: RwLock<Vec<Vec<ServerId>>>,
+  router: RwLock<Router>,
   remote_clients: RwLock<HashMap<ClientId, RemoteClient>>,
-  stored_messages: RwLock<HashMap<ClientId, Message>>,
+  stored_messages: RwLock<Delayed>,
+  registrations: AdmissionQueue,
+  authorizer: Box<dyn Authorizer + Send + Sync>,
+  // the latest ones, oldest first
+  reports: RwLock<VecDeque<AbuseReport>>,
+  rooms: RwLock<HashMap<RoomId, Room>>,
+  // watched client -> local subscribers
+  subscribers: RwLock<HashMap<ClientId, Vec<ClientId>>>,
+  overflow: OverflowPolicy,
+  names: NamePolicy,
+  // how long messages wait in mailboxes, or for an unknown recipient
+  ttl: Duration,
+  // how long the tombstones of removed history entries are kept
+  tombstone_grace: Duration,
+  // how long messages wait for the earlier ones of their sender
+  reorder_wait: Duration,
+  // largest binary payload
+  max_data_size: usize,
+  // largest fan-out of a single message
+  max_destinations: usize,
+  // messages kept for unknown recipients, per sender and in total
+  delayed_quota: (usize, usize),
+  send_buckets: RwLock<SendBuckets>,
+  // client and room ids
+  rng: SharedRng,
+  // deadlines, expiry, timestamps and timeouts, shared with the clients
+  clock: SharedClock,
+  sequence_window: u32,
+  // last sequence number seen from each server
+  server_seqids: RwLock<HashMap<ServerId, u128>>,
+  // last version of our room announces, from the clock so that it still grows after a restart
+  room_version: AtomicU64,
+  banned: RwLock<Bans>,
+  // what the invites were used for, when registrations need one
+  invites: Option<Invites>,
+}
+
+// what the operators banned
+#[derive(Default)]
+struct Bans {
+  clients: HashSet<ClientId>,
+  addresses: HashSet<IpAddr>,
+}
+
+impl Bans {
+  fn is_banned(&self, clients: &HashMap<ClientId, Client>, client: ClientId) -> bool {
+    self.clients.contains(&client)
+      || clients
+        .get(&client)
+        .is_some_and(|info| self.addresses.contains(&info.src_ip))
+  }
+}
+
+// tokens of each sender, and of each address, no limit when missing
+#[derive(Default)]
+struct SendBuckets {
+  clients: Option<TokenBuckets<ClientId>>,
+  addresses: Option<TokenBuckets<IpAddr>>,
+  // broadcasts of each sender, on top of its messages
+  broadcasts: Option<TokenBuckets<ClientId>>,
+}
+
+// the messages kept for unknown recipients, and how many each sender has there
+#[derive(Default)]
+struct Delayed {
+  // recipient -> its messages, oldest first
+  waiting: HashMap<ClientId, VecDeque<Message>>,
+  senders: HashMap<ClientId, usize>,
+  total: usize,
+}
+
+impl Delayed {
+  // refused when the recipient, the sender or the server already keep too many messages
+  fn push(
+    &mut self,
+    dest: ClientId,
+    message: Message,
+    (per_sender, total): (usize, usize),
+  ) -> Result<(), ClientError> {
+    let waiting = self.waiting.entry(dest).or_default();
+    if waiting.len() >= DELAYED_SIZE {
+      return Err(ClientError::BoxFull(dest));
+    }
+    let sent = self.senders.entry(message.src).or_default();
+    if *sent >= per_sender {
+      return Err(ClientError::DelayedQuota(per_sender as u128));
+    }
+    if self.total >= total {
+      return Err(ClientError::ServerBusy);
+    }
+    *sent += 1;
+    self.total += 1;
+    waiting.push_back(message);
+    Ok(())
+  }
+
+  // the messages kept for `dest`, that are no longer counted
+  fn take(&mut self, dest: ClientId) -> VecDeque<Message> {
+    let messages = self.waiting.remove(&dest).unwrap_or_default();
+    messages.iter().for_each(|m| self.forget(m.src));
+    messages
+  }
+
+  // removes the expired messages, and returns their sender and recipient
+  fn expire(&mut self, now: Instant) -> Vec<(ClientId, ClientId)> {
+    let mut expired = Vec::new();
+    for (&dest, waiting) in self.waiting.iter_mut() {
+      waiting.retain(|message| {
+        if message.expires > now {
+          return true;
+        }
+        expired.push((message.src, dest));
+        false
+      });
+    }
+    self.waiting.retain(|_, waiting| !waiting.is_empty());
+    expired.iter().for_each(|(src, _)| self.forget(*src));
+    expired
+  }
+
+  fn forget(&mut self, src: ClientId) {
+    self.total -= 1;
+    if let Entry::Occupied(mut sent) = self.senders.entry(src) {
+      *sent.get_mut() -= 1;
+      if *sent.get() == 0 {
+        sent.remove();
+      }
+    }
+  }
 }
 
 struct Client {
-  _src_ip: IpAddr,
+  src_ip: IpAddr,
   name: String,
+  tier: Tier,
   seqid: u128,
-  mailbox: VecDeque<(ClientId, String)>,
+  // the numbers used below seqid, bit n for seqid - n
+  seen: u128,
+  // one lane per priority
+  mailbox: [VecDeque<Waiting>; 3],
+  // mails waiting in all the lanes, above which the overflow policy applies
+  mailbox_size: usize,
+  // approximate memory used by the mailbox
+  mailbox_bytes: usize,
+  // do-not-disturb mode, and the mails that came meanwhile, oldest first, with their lane
+  dnd: bool,
+  deferred: VecDeque<(Priority, Waiting)>,
+  // polled messages, oldest first, with the tombstones of the removed ones
+  history: VecDeque<Kept>,
+  // tombstones in the history
+  tombstones: usize,
+  prefs: NotificationPrefs,
+  presence: Presence,
+  blocked: HashSet<ClientId>,
+  // ephemeral events, outside of the mailbox
+  events: VecDeque<(ClientId, Event)>,
+  // idle clients are evicted
+  last_poll: Instant,
+  // pollers waiting for the next mail or event
+  waiters: Vec<oneshot::Sender<()>>,
+  // author -> last message acked, and the version it was acked at
+  read_markers: HashMap<ClientId, (MessageId, u128)>,
+  read_version: u128,
+  // recipient -> sequence number of the last message sent to it
+  sent: HashMap<ClientId, u128>,
+  // sender -> sequence number of the last message polled from it
+  received: HashMap<ClientId, u128>,
+  // the text messages of the history
+  #[cfg(feature = "search")]
+  index: SearchIndex,
+  // the clients we went through a key agreement with, their messages are not indexed
+  #[cfg(feature = "search")]
+  encrypted: HashSet<ClientId>,
+  clock: SharedClock,
 }
 
-struct RemoteClient {
+impl Client {
+  fn new(
+    src_ip: IpAddr,
+    name: String,
+    tier: Tier,
+    mailbox_size: usize,
+    clock: SharedClock,
+  ) -> Self {
+    Client {
+      src_ip,
+      name,
+      tier,
+      seqid: 0,
+      // 0 is the registration
+      seen: 1,
+      mailbox: Default::default(),
+      mailbox_size,
+      mailbox_bytes: 0,
+      dnd: false,
+      deferred: VecDeque::new(),
+      history: VecDeque::new(),
+      tombstones: 0,
+      prefs: NotificationPrefs::default(),
+      presence: Presence::default(),
+      blocked: HashSet::new(),
+      events: VecDeque::new(),
+      last_poll: clock.now(),
+      waiters: Vec::new(),
+      read_markers: HashMap::new(),
+      read_version: 0,
+      sent: HashMap::new(),
+      received: HashMap::new(),
+      #[cfg(feature = "search")]
+      index: SearchIndex::default(),
+      #[cfg(feature = "search")]
+      encrypted: HashSet::new(),
+      clock,
+    }
+  }
+
+  // accepts an unused sequence number, within the window below the highest one
+  fn sequence(&mut self, seqid: u128, window: u32) -> Result<(), ClientError> {
+    if seqid > self.seqid {
+      let shift = seqid - self.seqid;
+      self.seen = (if shift < 128 { self.seen << shift } else { 0 }) | 1;
+      self.seqid = seqid;
+      return Ok(());
+    }
+    let age = self.seqid - seqid;
+    if age >= window as u128 {
+      Err(ClientError::SequenceReuse {
+        expected: self.seqid.saturating_add(1),
+        got: seqid,
+      })
+    } else if self.seen & (1 << age) != 0 {
+      Err(ClientError::Replayed(seqid))
+    } else {
+      self.seen |= 1 << age;
+      Ok(())
+    }
+  }
+
+  // mails in every lane
+  fn len(&self) -> usize {
+    self.mailbox.iter().map(VecDeque::len).sum()
+  }
+
+  // would `count` more non-system mails of `size` bytes in total be accepted
+  fn has_room(&self, policy: OverflowPolicy, count: usize, size: usize) -> bool {
+    match policy {
+      OverflowPolicy::DropOldest => true,
+      _ if self.dnd => self.deferred.len() + count <= DEFERRED_SIZE,
+      OverflowPolicy::MemoryCap(max) => self.mailbox_bytes + size <= max,
+      _ => self.len() + count <= self.mailbox_size,
+    }
+  }
+
+  // false when the policy refuses the mail, system mails are never refused
+  fn deliver(
+    &mut self,
+    policy: OverflowPolicy,
+    expires: Instant,
+    priority: Priority,
+    src: ClientId,
+    mail: Mail,
+  ) -> bool {
+    let entry = Waiting {
+      src,
+      id: MessageId::default(),
+      mail,
+      expires,
+      seq: 0,
+      held_until: self.clock.now(),
+      in_reply_to: None,
+    };
+    self.deliver_entry(policy, priority, entry)
+  }
+
+  fn deliver_entry(&mut self, policy: OverflowPolicy, priority: Priority, entry: Waiting) -> bool {
+    if self.dnd && priority != Priority::System {
+      return self.defer(policy, priority, entry);
+    }
+    let mail = &entry.mail;
+    let size = mail.size();
+    let full = match policy {
+      OverflowPolicy::MemoryCap(max) => self.mailbox_bytes + size > max,
+      _ => self.len() >= self.mailbox_size,
+    };
+    if full {
+      match policy {
+        OverflowPolicy::DropOldest => self.evict(),
+        _ if priority == Priority::System => self.evict(),
+        _ => return false,
+      }
+    }
+    self.mailbox_bytes += size;
+    self.mailbox[priority as usize].push_back(entry);
+    self.wake();
+    true
+  }
+
+  // keeps a mail aside while in do-not-disturb mode, outside of the mailbox limits
+  fn defer(&mut self, policy: OverflowPolicy, priority: Priority, entry: Waiting) -> bool {
+    if self.deferred.len() >= DEFERRED_SIZE {
+      if policy != OverflowPolicy::DropOldest {
+        return false;
+      }
+      if let Some((_, dropped)) = self.deferred.pop_front() {
+        Self::passed(&mut self.received, &dropped);
+      }
+    }
+    self.deferred.push_back((priority, entry));
+    true
+  }
+
+  // the deferred mails move to the mailbox when the mode is turned off, even if it is full
+  fn set_dnd(&mut self, on: bool) {
+    self.dnd = on;
+    if on || self.deferred.is_empty() {
+      return;
+    }
+    for (priority, entry) in self.deferred.drain(..) {
+      self.mailbox_bytes += entry.mail.size();
+      self.mailbox[priority as usize].push_back(entry);
+    }
+    self.wake();
+  }
+
+  // wakes up the waiting pollers
+  fn wake(&mut self) {
+    for waiter in self.waiters.drain(..) {
+      let _ = waiter.send(());
+    }
+  }
+
+  // the pollers that gave up are forgotten on the way
+  fn wait(&mut self) -> oneshot::Receiver<()> {
+    let (waiter, woken) = oneshot::channel();
+    self.waiters.retain(|w| !w.is_canceled());
+    self.waiters.push(waiter);
+    woken
+  }
+
+  // the oldest mail of the lowest lane makes room
+  fn evict(&mut self) {
+    if let Some(lane) = self.mailbox.iter_mut().rev().find(|l| !l.is_empty()) {
+      if let Some(dropped) = lane.pop_front() {
+        self.mailbox_bytes -= dropped.mail.size();
+        Self::passed(&mut self.received, &dropped);
+      }
+    }
+  }
+
+  // never refused, the oldest event is dropped instead
+  fn signal(&mut self, src: ClientId, event: Event) {
+    if self.events.len() >= EVENTS_SIZE {
+      self.events.pop_front();
+    }
+    self.events.push_back((src, event));
+    self.wake();
+  }
+
+  // the first mail of the highest lane that `wanted`, among those that are in order
+  fn take(&mut self, wanted: impl Fn(&Waiting) -> bool) -> Option<Waiting> {
+    let now = self.clock.now();
+    let (lane, position) = self.mailbox.iter().enumerate().find_map(|(lane, mails)| {
+      mails
+        .iter()
+        .position(|e| wanted(e) && self.in_order(e, now))
+        .map(|position| (lane, position))
+    })?;
+    let entry = self.mailbox[lane].remove(position)?;
+    self.mailbox_bytes -= entry.mail.size();
+    Self::passed(&mut self.received, &entry);
+    Some(entry)
+  }
+
+  // the messages of the sender up to this one are not waited for anymore, whether this one was
+  // polled or dropped
+  fn passed(received: &mut HashMap<ClientId, u128>, entry: &Waiting) {
+    if entry.seq != 0 {
+      let received = received.entry(entry.src).or_default();
+      *received = entry.seq.max(*received);
+    }
+  }
+
+  // false while the earlier messages of the sender are missing, and the mail can still wait
+  fn in_order(&self, entry: &Waiting, now: Instant) -> bool {
+    let received = self.received.get(&entry.src).copied().unwrap_or(0);
+    entry.seq <= received + 1 || entry.held_until <= now
+  }
+
+  // when the first mail that is out of order can be polled anyway
+  fn held_until(&self) -> Option<Instant> {
+    let now = self.clock.now();
+    self
+      .mailbox
+      .iter()
+      .flatten()
+      .filter(|e| !self.in_order(e, now))
+      .map(|e| e.held_until)
+      .min()
+  }
+
+  // the next event or mail, messages are moved to the history
+  fn poll(&mut self) -> Option<ClientPollReply> {
+    if let Some((src, event)) = self.events.pop_front() {
+      return Some(ClientPollReply::Event { src, event });
+    }
+    let entry = self.take(|_| true)?;
+    Some(self.keep(entry))
+  }
+
+  // the minute of the day, in UTC, for the quiet hours
+  fn minute(&self) -> u16 {
+    (self.clock.now_ms() / 60_000 % (24 * 60)) as u16
+  }
+
+  // should something from `src` be notified now, according to the prefs
+  fn notifies(&self, src: ClientId) -> bool {
+    self.prefs.should_notify(src, self.minute())
+  }
+
+  // when the current quiet hours end
+  fn quiet_until(&self) -> Option<Instant> {
+    let quiet = self.prefs.quiet_hours?;
+    let minute = self.minute();
+    if !quiet.contains(minute) {
+      return None;
+    }
+    let minutes = (quiet.end + 24 * 60 - minute) % (24 * 60);
+    let left = minutes as u64 * 60_000 - self.clock.now_ms() % 60_000;
+    Some(self.clock.now() + Duration::from_millis(left))
+  }
+
+  // the next text message that can be notified, the other mails and the events are left for
+  // `poll`
+  fn poll_text(&mut self) -> Option<(ClientId, String)> {
+    let (prefs, minute) = (self.prefs.clone(), self.minute());
+    let entry = self.take(|e| {
+      matches!(e.mail, Mail::Text(..) | Mail::Rich(_) | Mail::Room(..))
+        && prefs.should_notify(e.src, minute)
+    })?;
+    let mut reply = self.keep(entry);
+    if let ClientPollReply::ReplyTo { message, .. } = reply {
+      reply = *message;
+    }
+    match reply {
+      ClientPollReply::Message { src, content, .. }
+      | ClientPollReply::RoomMessage { src, content, .. } => Some((src, content)),
+      ClientPollReply::RichMessage { src, content } => Some((src, content.text)),
+      _ => unreachable!("only text messages are taken"),
+    }
+  }
+
+  // the reply for a mail that was taken, messages are moved to the history
+  fn keep(&mut self, entry: Waiting) -> ClientPollReply {
+    let Waiting {
+      src,
+      id,
+      mail,
+      in_reply_to,
+      ..
+    } = entry;
+    let reply = match mail {
+      // receipts are not kept in the history
+      Mail::Receipt(id) => return ClientPollReply::Receipt { id, reader: src },
+      Mail::Reaction(id, emoji) => {
+        return ClientPollReply::Reaction {
+          id,
+          reactor: src,
+          emoji,
+        }
+      }
+      Mail::Expired => return ClientPollReply::DelayedError(DelayedError::Expired(src)),
+      Mail::Rejected => return ClientPollReply::DelayedError(DelayedError::Rejected(src)),
+      Mail::Delivered(id) => return ClientPollReply::Delivered { id, dst: src },
+      Mail::KeyAgreement(payload) => return ClientPollReply::KeyAgreement { src, payload },
+      Mail::Presence(presence) => {
+        return ClientPollReply::Presence {
+          client: src,
+          presence,
+        }
+      }
+      Mail::Text(srcsrv, timestamp, content) => ClientPollReply::Message {
+        src,
+        srcsrv,
+        content,
+        timestamp,
+      },
+      Mail::Rich(content) => ClientPollReply::RichMessage { src, content },
+      Mail::Room(room, content) => ClientPollReply::RoomMessage { room, src, content },
+      Mail::Data(mime, bytes) => ClientPollReply::Data { src, mime, bytes },
+      Mail::Deleted => ClientPollReply::Deleted { src },
+    };
+    let reply = threaded(reply, in_reply_to);
+    if self.history.len() - self.tombstones == HISTORY_SIZE {
+      if let Some(oldest) = self.history.iter().position(|k| k.removed.is_none()) {
+        self.bury(oldest);
+      }
+    }
+    #[cfg(feature = "search")]
+    if let Some((src, text)) = searchable(&reply) {
+      if !self.encrypted.contains(&src) {
+        self.index.insert(id, src, self.clock.now_ms(), text);
+      }
+    }
+    self.history.push_back(Kept {
+      entry: HistoryEntry {
+        id,
+        message: reply.clone(),
+        reactions: Vec::new(),
+      },
+      timestamp: self.clock.now_ms(),
+      removed: None,
+    });
+    if let ClientPollReply::Deleted { .. } = reply {
+      self.bury(self.history.len() - 1);
+    }
+    reply
+  }
+
+  // inserts an archived message in the history, by timestamp, false when its id is already
+  // there or it is older than a full history
+  fn restore(&mut self, entry: HistoryEntry, timestamp: u64) -> bool {
+    if self.history.iter().any(|k| k.entry.id == entry.id) {
+      return false;
+    }
+    let position = self.history.partition_point(|k| k.timestamp <= timestamp);
+    let full = self.history.len() - self.tombstones == HISTORY_SIZE;
+    let oldest = self.history.iter().position(|k| k.removed.is_none());
+    if full && oldest.is_some_and(|oldest| position <= oldest) {
+      return false;
+    }
+    #[cfg(feature = "search")]
+    if let Some((src, text)) = searchable(&entry.message) {
+      if !self.encrypted.contains(&src) {
+        self.index.insert(entry.id, src, timestamp, text);
+      }
+    }
+    self.history.insert(
+      position,
+      Kept {
+        entry,
+        timestamp,
+        removed: None,
+      },
+    );
+    if let Some(oldest) = oldest.filter(|_| full) {
+      self.bury(oldest);
+    }
+    true
+  }
+
+  // replaces a history entry with its tombstone
+  fn bury(&mut self, position: usize) {
+    let kept = &mut self.history[position];
+    if kept.removed.is_some() {
+      return;
+    }
+    let (ClientPollReply::Message { src, .. }
+    | ClientPollReply::RichMessage { src, .. }
+    | ClientPollReply::RoomMessage { src, .. }
+    | ClientPollReply::Data { src, .. }
+    | ClientPollReply::Deleted { src }) = *kept.entry.message.unthreaded()
+    else {
+      unreachable!("only messages are kept in the history")
+    };
+    kept.entry.message = ClientPollReply::Deleted { src };
+    kept.entry.reactions.clear();
+    kept.removed = Some(self.clock.now());
+    #[cfg(feature = "search")]
+    self.index.remove(kept.entry.id);
+    self.tombstones += 1;
+    if self.tombstones > TOMBSTONES_SIZE {
+      if let Some(oldest) = self.history.iter().position(|k| k.removed.is_some()) {
+        self.history.remove(oldest);
+        self.tombstones -= 1;
+      }
+    }
+  }
+
+  // drops the tombstones older than `grace`
+  fn purge(&mut self, now: Instant, grace: Duration) {
+    self.history.retain(|k| {
+      k.removed
+        .is_none_or(|t| now.saturating_duration_since(t) < grace)
+    });
+    self.tombstones = self.history.iter().filter(|k| k.removed.is_some()).count();
+  }
+
+  // rewrites the message `id` of `src`, in the mailbox or the history, or replaces it with a
+  // tombstone when there is no content; false when there is no such message
+  fn rewrite(&mut self, src: ClientId, id: MessageId, content: Option<String>) -> bool {
+    let this = |e: &Waiting| e.src == src && e.id == id;
+    // deferred mails are not counted in the mailbox
+    let in_mailbox = self.mailbox.iter().flatten().any(this);
+    let waiting = if in_mailbox {
+      self.mailbox.iter_mut().flatten().find(|e| this(e))
+    } else {
+      self.deferred.iter_mut().map(|(_, e)| e).find(|e| this(e))
+    };
+    if let Some(entry) = waiting {
+      let mail = match (&entry.mail, content) {
+        (Mail::Deleted, _) => return false,
+        (Mail::Text(srcsrv, timestamp, _), Some(text)) => Mail::Text(*srcsrv, *timestamp, text),
+        (Mail::Rich(rich), Some(text)) => Mail::Rich(RichContent {
+          text,
+          // the mentions pointed into the old text
+          mentions: Vec::new(),
+          content_type: rich.content_type.clone(),
+        }),
+        (Mail::Room(room, _), Some(text)) => Mail::Room(*room, text),
+        (Mail::Text(..) | Mail::Rich(_) | Mail::Room(..) | Mail::Data(..), None) => Mail::Deleted,
+        // notifications, and binary payloads, can't be rewritten
+        _ => return false,
+      };
+      if in_mailbox {
+        self.mailbox_bytes -= entry.mail.size();
+        self.mailbox_bytes += mail.size();
+      }
+      entry.mail = mail;
+      return true;
+    }
+    let Some(position) = self
+      .history
+      .iter()
+      .position(|k| k.entry.id == id && k.removed.is_none())
+    else {
+      return false;
+    };
+    let entry = &mut self.history[position].entry;
+    // a reply stays one when it is edited
+    let message = match (entry.message.unthreaded(), content) {
+      (
+        ClientPollReply::Message {
+          src: s,
+          srcsrv,
+          timestamp,
+          ..
+        },
+        Some(content),
+      ) if *s == src => ClientPollReply::Message {
+        src,
+        srcsrv: *srcsrv,
+        content,
+        timestamp: *timestamp,
+      },
+      (
+        ClientPollReply::RichMessage {
+          src: s,
+          content: rich,
+        },
+        Some(text),
+      ) if *s == src => ClientPollReply::RichMessage {
+        src,
+        content: RichContent {
+          text,
+          mentions: Vec::new(),
+          content_type: rich.content_type.clone(),
+        },
+      },
+      (ClientPollReply::RoomMessage { room, src: s, .. }, Some(content)) if *s == src => {
+        ClientPollReply::RoomMessage {
+          room: *room,
+          src,
+          content,
+        }
+      }
+      (
+        ClientPollReply::Message { src: s, .. }
+        | ClientPollReply::RichMessage { src: s, .. }
+        | ClientPollReply::RoomMessage { src: s, .. }
+        | ClientPollReply::Data { src: s, .. },
+        None,
+      ) if *s == src => ClientPollReply::Deleted { src },
+      _ => return false,
+    };
+    entry.message = threaded(message, entry.message.in_reply_to());
+    let deleted = matches!(entry.message, ClientPollReply::Deleted { .. });
+    #[cfg(feature = "search")]
+    if let Some((_, text)) = searchable(&entry.message) {
+      self.index.update(id, text);
+    }
+    if deleted {
+      self.bury(position);
+    }
+    true
+  }
+
+  // removes the expired mails, and returns them
+  fn expire(&mut self, now: Instant) -> Vec<(ClientId, Mail)> {
+    let mut expired = Vec::new();
+    for lane in self.mailbox.iter_mut() {
+      let mut kept = VecDeque::with_capacity(lane.len());
+      for entry in lane.drain(..) {
+        if entry.expires <= now {
+          self.mailbox_bytes -= entry.mail.size();
+          Self::passed(&mut self.received, &entry);
+          expired.push((entry.src, entry.mail));
+        } else {
+          kept.push_back(entry);
+        }
+      }
+      *lane = kept;
+    }
+    let mut kept = VecDeque::with_capacity(self.deferred.len());
+    for (priority, entry) in self.deferred.drain(..) {
+      if entry.expires <= now {
+        Self::passed(&mut self.received, &entry);
+        expired.push((entry.src, entry.mail));
+      } else {
+        kept.push_back((priority, entry));
+      }
+    }
+    self.deferred = kept;
+    expired
+  }
+}
+
+// a history entry, or the tombstone of a removed one, with the time of the removal
+struct Kept {
+  entry: HistoryEntry,
+  // when it was polled, or sent for imported messages, in ms since the epoch
+  timestamp: u64,
+  removed: Option<Instant>,
+}
+
+// a mail waiting in a mailbox
+struct Waiting {
+  src: ClientId,
+  // also the id of its history entry once polled
+  id: MessageId,
+  mail: Mail,
+  expires: Instant,
+  // the position among the messages of its sender to us, 0 when not sequenced
+  seq: u128,
+  // until when it waits for the earlier messages of its sender
+  held_until: Instant,
+  // the message it answers
+  in_reply_to: Option<MessageId>,
+}
+
+// what sits in a mailbox, mentions are only kept for local recipients
+#[derive(Clone)]
+enum Mail {
+  // with the server of its sender, and when that server accepted it
+  Text(ServerId, u64, String),
+  Rich(RichContent),
+  Room(RoomId, String),
+  // binary payload, with its media type
+  Data(String, Vec<u8>),
+  // opaque handshake, not kept in the history
+  KeyAgreement(Vec<u8>),
+  // the sender of this mail read our message
+  Receipt(MessageId),
+  // the sender of this mail reacted to our message
+  Reaction(MessageId, String),
+  // the sender of this mail changed its presence
+  Presence(Presence),
+  // our message to the sender of this mail expired before being read
+  Expired,
+  // our message kept for the sender of this mail was refused once it was known
+  Rejected,
+  // our transferred message reached the sender of this mail
+  Delivered(MessageId),
+  // tombstone of a message deleted by the sender of this mail
+  Deleted,
+}
+
+impl Mail {
+  // approximate memory used in a mailbox
+  fn size(&self) -> usize {
+    let text = match self {
+      Mail::Text(_, _, text) | Mail::Room(_, text) => text.len(),
+      Mail::Rich(rich) => rich.text.len() + rich.mentions.len() * std::mem::size_of::<Mention>(),
+      Mail::Data(mime, bytes) => mime.len() + bytes.len(),
+      Mail::KeyAgreement(payload) => payload.len(),
+      Mail::Reaction(_, emoji) => emoji.len(),
+      Mail::Receipt(_)
+      | Mail::Presence(_)
+      | Mail::Expired
+      | Mail::Rejected
+      | Mail::Delivered(_)
+      | Mail::Deleted => 0,
+    };
+    std::mem::size_of::<Waiting>() + text
+  }
+
+  // what survives federation
+  fn into_parts(self) -> (String, ContentType, Vec<Mention>) {
+    match self {
+      Mail::Text(_, _, text) => (text, ContentType::Plain, Vec::new()),
+      Mail::Rich(rich) => (rich.text, rich.content_type, rich.mentions),
+      Mail::Room(_, text) => (text, ContentType::Plain, Vec::new()),
+      Mail::Data(..) => unreachable!("binary payloads stay local"),
+      Mail::KeyAgreement(_) => unreachable!("handshakes are transferred on their own"),
+      Mail::Receipt(_)
+      | Mail::Reaction(..)
+      | Mail::Presence(_)
+      | Mail::Expired
+      | Mail::Rejected
+      | Mail::Delivered(_)
+      | Mail::Deleted => {
+        unreachable!("notifications are not sent as messages")
+      }
+    }
+  }
+
+  fn from_parts(message: &FullyQualifiedMessage) -> Self {
+    let text = message.content.clone();
+    match message.content_type.clone() {
+      ContentType::Plain if message.mentions.is_empty() => {
+        Mail::Text(message.srcsrv, message.timestamp, text)
+      }
+      content_type => Mail::Rich(RichContent {
+        text,
+        mentions: message.mentions.clone(),
+        content_type,
+      }),
+    }
+  }
+}
+
+#[derive(Default)]
+struct Room {
+  // unknown for the rooms created on other servers
   _name: String,
-  srcsrv: ServerId,
+  // local members, in join order
+  members: Vec<ClientId>,
+  // of the last announce of the local members
+  version: u64,
+  // the members on other servers, with the version of their announce
+  // the versions of the servers whose members left are kept too, so that a late copy of an older
+  // announce can't bring them back
+  remote: HashMap<ServerId, (u64, Vec<ClientId>)>,
+}
+
+impl Room {
+  // a room without members can't be joined
+  fn has_members(&self) -> bool {
+    !self.members.is_empty() || self.remote.values().any(|(_, m)| !m.is_empty())
+  }
+
+  // the other servers where the room has members
+  fn servers(&self) -> Vec<ServerId> {
+    let mut servers: Vec<ServerId> = self
+      .remote
+      .iter()
+      .filter(|(_, (_, members))| !members.is_empty())
+      .map(|(srv, _)| *srv)
+      .collect();
+    servers.sort();
+    servers
+  }
+}
+
+struct RemoteClient {
+  name: String,
+  srcsrv: OriginServer,
+  presence: Presence,
+}
+
+/// names that are the `same_name` have the same key
+fn name_key(name: &str) -> String {
+  name.trim().to_ascii_lowercase()
+}
+
+/// the only client of `holders`, if there is exactly one
+fn single(mut holders: impl Iterator<Item = ClientId>) -> Option<ClientId> {
+  match (holders.next(), holders.next()) {
+    (Some(client), None) => Some(client),
+    _ => None,
+  }
+}
+
+/// the reply to `in_reply_to`, tombstones are not threaded
+fn threaded(reply: ClientPollReply, in_reply_to: Option<MessageId>) -> ClientPollReply {
+  match in_reply_to {
+    Some(in_reply_to) if !matches!(reply, ClientPollReply::Deleted { .. }) => {
+      ClientPollReply::ReplyTo {
+        in_reply_to,
+        message: Box::new(reply),
+      }
+    }
+    _ => reply,
+  }
+}
+
+/// the sender and text of a history message that can be indexed
+#[cfg(feature = "search")]
+fn searchable(message: &ClientPollReply) -> Option<(ClientId, &str)> {
+  match message.unthreaded() {
+    ClientPollReply::Message { src, content, .. }
+    | ClientPollReply::RoomMessage { src, content, .. } => Some((*src, content)),
+    ClientPollReply::RichMessage { src, content } => match content.content_type {
+      ContentType::Plain | ContentType::Markdown => Some((*src, &content.text)),
+      ContentType::Custom(_) => None,
+    },
+    _ => None,
+  }
 }
 
 struct Message {
   src: ClientId,
   content: String,
+  content_type: ContentType,
+  mentions: Vec<Mention>,
+  expires: Instant,
+  seq: u128,
+  in_reply_to: Option<MessageId>,
+  timestamp: u64,
+  id: MessageId,
 }
 
 #[async_trait]
 impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
   const GROUP_NAME: &'static str = "Descamps Femery";
 
-  fn new(checker: C, id: ServerId) -> Self {
+  fn new(checker: C, id: ServerId, config: ServerConfig) -> Self {
     Server {
       checker,
       id,
+      invites: config.invite_key.clone().map(Invites::new),
+      config,
       clients: RwLock::new(HashMap::new()),
-      routes: RwLock::new(Vec::new()),
+      router: RwLock::new(Router::new(id)),
       remote_clients: RwLock::new(HashMap::new()),
-      stored_messages: RwLock::new(HashMap::new()),
+      stored_messages: RwLock::default(),
+      registrations: AdmissionQueue::new(REGISTRATION_CONCURRENCY, REGISTRATION_QUEUE),
+      authorizer: Box::new(DefaultAuthorizer::default()),
+      reports: RwLock::new(VecDeque::new()),
+      rooms: RwLock::new(HashMap::new()),
+      subscribers: RwLock::new(HashMap::new()),
+      overflow: OverflowPolicy::default(),
+      names: NamePolicy::default(),
+      ttl: MESSAGE_TTL,
+      tombstone_grace: TOMBSTONE_GRACE,
+      reorder_wait: REORDER_WAIT,
+      max_data_size: MAX_DATA_SIZE,
+      max_destinations: MAX_DESTINATIONS,
+      delayed_quota: (DELAYED_PER_SENDER, DELAYED_TOTAL),
+      send_buckets: RwLock::default(),
+      rng: os_rng(),
+      clock: system_clock(),
+      sequence_window: SEQUENCE_WINDOW,
+      server_seqids: RwLock::new(HashMap::new()),
+      room_version: AtomicU64::new(0),
+      banned: RwLock::default(),
     }
   }
 
@@ -68,36 +1005,183 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
   // for spam checking, you will need to run both checks in parallel, and take a decision as soon as
   // each checks return
 
-  async fn register_local_client(&self, src_ip: IpAddr, name: String) -> Option<ClientId> {
-    // timeout for the spam checks
-    let spam_check_timeout = Duration::from_secs(2);
+  async fn register_local_client(
+    &self,
+    src_ip: IpAddr,
+    name: String,
+  ) -> Result<ClientId, ClientError> {
+    self.register_member(src_ip, name, None).await
+  }
 
-    let (is_ip_spammer, is_user_spammer) = join!(
-      timeout(spam_check_timeout, self.checker.is_ip_spammer(&src_ip)),
-      timeout(spam_check_timeout, self.checker.is_user_spammer(&name)),
-    );
+  async fn register_invited_client(
+    &self,
+    src_ip: IpAddr,
+    name: String,
+    invite: Vec<u8>,
+  ) -> Result<ClientId, ClientError> {
+    self.register_member(src_ip, name, Some(&invite)).await
+  }
 
-    match (is_ip_spammer, is_user_spammer) {
-      (Ok(ip_result), Ok(user_result)) => {
-        // Only proceed if neither the IP nor the user is flagged as a spammer
-        if !ip_result && !user_result {
-          let client = ClientId(Uuid::new_v4());
-          let client_info = Client {
-            _src_ip: src_ip,
-            name,
-            seqid: 0,
-            mailbox: VecDeque::new(),
-          };
-          self.clients.write().await.insert(client, client_info);
-          return Some(client);
-        }
+  async fn unregister_local_client(&self, client: ClientId) -> Vec<ClientReply> {
+    if self.clients.write().await.remove(&client).is_none() {
+      return vec![ClientReply::Error(ClientError::UnknownClient)];
+    }
+    let rooms = self.forget_clients(&[client]).await;
+    let mut resp = vec![ClientReply::Delivered(None)];
+    for nexthop in self.router.read().await.neighbours() {
+      resp.push(ClientReply::Transfer(
+        nexthop,
+        ServerMessage::Withdraw {
+          srv: self.id,
+          clients: vec![client],
+        },
+        None,
+      ));
+    }
+    for room in rooms {
+      for o in self.announce_room(room).await {
+        resp.push(ClientReply::Transfer(o.nexthop, o.message, None));
       }
-      _ => {
-        // Handle timeout or error in spam checks (e.g., log a warning, or just return None)
-        eprintln!("Spam check timeout or error occurred");
+    }
+    resp
+  }
+
+  async fn evict_idle(
+    &self,
+    now: Instant,
+    max_age: Duration,
+  ) -> (Vec<ClientId>, Vec<Outgoing<ServerMessage>>) {
+    let mut evicted = Vec::new();
+    self.clients.write().await.retain(|id, info| {
+      let idle = now.saturating_duration_since(info.last_poll) >= max_age
+        && info.waiters.iter().all(oneshot::Sender::is_canceled);
+      if idle {
+        evicted.push(*id);
+      }
+      !idle
+    });
+    if evicted.is_empty() {
+      return (evicted, Vec::new());
+    }
+    let rooms = self.forget_clients(&evicted).await;
+    let mut withdraws: Vec<_> = self
+      .router
+      .read()
+      .await
+      .neighbours()
+      .into_iter()
+      .map(|nexthop| Outgoing {
+        nexthop,
+        message: ServerMessage::Withdraw {
+          srv: self.id,
+          clients: evicted.clone(),
+        },
+      })
+      .collect();
+    for room in rooms {
+      withdraws.extend(self.announce_room(room).await);
+    }
+    (evicted, withdraws)
+  }
+
+  async fn rename_client(&self, client: ClientId, name: String) -> Vec<ClientReply> {
+    let (src_ip, tier) = match self.clients.read().await.get(&client) {
+      Some(info) => (info.src_ip, info.tier),
+      None => return vec![ClientReply::Error(ClientError::UnknownClient)],
+    };
+    if is_reserved_name(&name) {
+      return vec![ClientReply::Error(ClientError::Forbidden)];
+    }
+    if tier != Tier::Guest {
+      if let Err(rr) = self.spam_check(src_ip, &name).await {
+        return vec![ClientReply::Error(rr)];
+      }
+    }
+    {
+      let mut clients = self.clients.write().await;
+      if let Err(rr) = self.check_name(&clients, client, &name) {
+        return vec![ClientReply::Error(rr)];
+      }
+      match clients.get_mut(&client) {
+        Some(info) => info.name = name,
+        None => return vec![ClientReply::Error(ClientError::UnknownClient)],
+      }
+    }
+    let announce = self.make_announce().await;
+    let mut resp = vec![ClientReply::Delivered(None)];
+    for nexthop in self.router.read().await.neighbours() {
+      resp.push(ClientReply::Transfer(nexthop, announce.clone(), None));
+    }
+    resp
+  }
+
+  async fn register_guest(&self, src_ip: IpAddr, name: String) -> Result<ClientId, ClientError> {
+    if is_reserved_name(&name) {
+      return Err(ClientError::Forbidden);
+    }
+    if self.invites.is_some() {
+      return Err(ClientError::InvalidInvite);
+    }
+    self.insert_client(src_ip, name, Tier::Guest).await
+  }
+
+  async fn register_trusted_client(
+    &self,
+    admin: ClientId,
+    id: ClientId,
+    name: String,
+    src_ip: IpAddr,
+  ) -> Result<(), ClientError> {
+    match self.clients.read().await.get(&admin) {
+      Some(info) if self.authorizer.allows(info.tier, Action::RegisterTrusted) => (),
+      Some(_) => return Err(ClientError::Forbidden),
+      None => return Err(ClientError::UnknownClient),
+    }
+    if is_reserved_name(&name) {
+      return Err(ClientError::Forbidden);
+    }
+    if id.is_system() || self.remote_clients.read().await.contains_key(&id) {
+      return Err(ClientError::AlreadyRegistered(id));
+    }
+    let mut clients = self.clients.write().await;
+    self.check_name(&clients, id, &name)?;
+    match clients.entry(id) {
+      Entry::Occupied(_) => Err(ClientError::AlreadyRegistered(id)),
+      Entry::Vacant(entry) => {
+        let mailbox_size = self.config.mailbox_size(&id);
+        let clock = self.clock.clone();
+        entry.insert(Client::new(src_ip, name, Tier::Member, mailbox_size, clock));
+        Ok(())
+      }
+    }
+  }
+
+  async fn upgrade_guest(&self, client: ClientId) -> Result<(), ClientError> {
+    let (src_ip, name) = match self.clients.read().await.get(&client) {
+      Some(info) if info.tier == Tier::Guest => (info.src_ip, info.name.clone()),
+      Some(_) => return Ok(()),
+      None => return Err(ClientError::UnknownClient),
+    };
+    let _permit = self.registrations.admit().await?;
+    self.spam_check(src_ip, &name).await?;
+    match self.clients.write().await.get_mut(&client) {
+      Some(info) => {
+        info.tier = Tier::Member;
+        Ok(())
       }
+      None => Err(ClientError::UnknownClient),
+    }
+  }
+
+  async fn handle_server_sequence<A: Send>(&self, sequence: ServerSequence<A>) -> Option<A> {
+    let mut seqids = self.server_seqids.write().await;
+    let last = seqids.entry(sequence.src).or_default();
+    if *last < sequence.seqid {
+      *last = sequence.seqid;
+      Some(sequence.content)
+    } else {
+      None
     }
-    None
   }
 
   /*
@@ -111,14 +1195,9 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
     let mut clients = self.clients.write().await;
     let client = clients.get_mut(&sequence.src);
     match client {
-      Some(client) => {
-        if client.seqid < sequence.seqid {
-          client.seqid = sequence.seqid;
-          Ok(sequence.content)
-        } else {
-          Err(ClientError::InternalError)
-        }
-      }
+      Some(client) => client
+        .sequence(sequence.seqid, self.sequence_window)
+        .map(|()| sequence.content),
       None => Err(ClientError::UnknownClient),
     }
   }
@@ -134,17 +1213,252 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
     both ClientMessage variants.
   */
   async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    // each wrapper can be used once, in any order
+    let (mut priority, mut id, mut reply_to) = (None, None, None);
+    let mut msg = msg;
+    loop {
+      msg = match msg {
+        ClientMessage::Prioritized {
+          priority: p,
+          message,
+        } if priority.is_none() => {
+          priority = Some(p);
+          *message
+        }
+        ClientMessage::WithId { id: i, message } if id.is_none() => {
+          id = Some(i);
+          *message
+        }
+        ClientMessage::ReplyTo {
+          in_reply_to,
+          message,
+        } if reply_to.is_none() => {
+          reply_to = Some(in_reply_to);
+          *message
+        }
+        _ => break,
+      };
+    }
+    let priority = priority.unwrap_or_default();
+    // the system lane is reserved to the server
+    if priority == Priority::System
+      || matches!(
+        msg,
+        ClientMessage::Prioritized { .. }
+          | ClientMessage::WithId { .. }
+          | ClientMessage::ReplyTo { .. }
+      )
+    {
+      return vec![ClientReply::Error(ClientError::Forbidden)];
+    }
+    // only messages that are kept in the history can be replies
+    if reply_to.is_some()
+      && !matches!(
+        msg,
+        ClientMessage::Text { .. }
+          | ClientMessage::MText { .. }
+          | ClientMessage::AtomicMText { .. }
+          | ClientMessage::Rich { .. }
+          | ClientMessage::RoomText { .. }
+          | ClientMessage::Data { .. }
+      )
+    {
+      return vec![ClientReply::Error(ClientError::Forbidden)];
+    }
+    // a single error, instead of one per recipient
+    if let ClientMessage::MText { dest, .. }
+    | ClientMessage::AtomicMText { dest, .. }
+    | ClientMessage::Rich { dest, .. } = &msg
+    {
+      if dest.len() > self.max_destinations {
+        let max = self.max_destinations as u128;
+        return vec![ClientReply::Error(ClientError::TooManyDestinations(max))];
+      }
+    }
+    // one error per recipient, for messages refused as a whole
+    let refused = |msg: &ClientMessage, rr: ClientError| match msg {
+      ClientMessage::MText { dest, .. } => {
+        let each = dest.iter().map(|d| (*d, ClientReply::Error(rr.clone())));
+        vec![ClientReply::Multi(each.collect())]
+      }
+      ClientMessage::Rich { dest, .. } => vec![ClientReply::Error(rr); dest.len()],
+      _ => vec![ClientReply::Error(rr)],
+    };
+    if self.is_banned(src).await {
+      return refused(&msg, ClientError::Banned);
+    }
+    // mentions must point inside the text, on character boundaries
+    if let ClientMessage::Rich { content, .. } = &msg {
+      if !content.is_valid() {
+        return refused(&msg, ClientError::Forbidden);
+      }
+    }
+    // acks, subscriptions and modes don't send anything
+    if !matches!(
+      msg,
+      ClientMessage::Ack(_)
+        | ClientMessage::SubscribePresence(_)
+        | ClientMessage::SetDoNotDisturb(_)
+    ) && !self.may_send(src).await
+    {
+      return refused(&msg, ClientError::RateLimited);
+    }
+    // without an id from the sender, every recipient gets its own
+    let id = || id.unwrap_or_default();
+    // reading is allowed to everyone, guests included
+    match msg {
+      ClientMessage::Ack(id) => return vec![self.ack(src, id).await],
+      ClientMessage::SetDoNotDisturb(on) => return vec![self.set_do_not_disturb(src, on).await],
+      ClientMessage::React { message, emoji } => {
+        if !self.allowed(src, Action::Send).await || emoji.is_empty() {
+          return vec![ClientReply::Error(ClientError::Forbidden)];
+        }
+        if emoji.len() > MAX_REACTION_SIZE {
+          let max = MAX_REACTION_SIZE as u128;
+          return vec![ClientReply::Error(ClientError::MessageTooLarge(max))];
+        }
+        return vec![self.react(src, message, emoji).await];
+      }
+      ClientMessage::SubscribePresence(client) => return vec![self.subscribe(src, client).await],
+      ClientMessage::Broadcast { content } => return self.broadcast(src, content).await,
+      // events are sent along messages, but they are not kept anywhere
+      ClientMessage::Event { dest, event } => {
+        if !self.allowed(src, Action::Send).await {
+          return vec![ClientReply::Error(ClientError::Forbidden)];
+        }
+        return vec![self.event(src, dest, event).await];
+      }
+      _ => (),
+    }
+    if !self.allowed(src, Action::Send).await {
+      return refused(&msg, ClientError::Forbidden);
+    }
+    if let ClientMessage::SetPresence(presence) = msg {
+      return self.set_presence(src, presence).await;
+    }
     let mut resp = Vec::new();
     match msg {
       ClientMessage::Text { dest, content } => {
-        resp.push(self.client_message(src, dest, content).await);
+        resp.push(
+          self
+            .client_message(src, dest, priority, id(), self.text(content), reply_to)
+            .await,
+        );
       }
+      // a single reply, with the outcome for each recipient
       ClientMessage::MText { dest, content } => {
+        let mut each = Vec::with_capacity(dest.len());
         for dst in dest {
-          resp.push(self.client_message(src, dst, content.clone()).await)
+          let reply = self
+            .client_message(
+              src,
+              dst,
+              priority,
+              id(),
+              self.text(content.clone()),
+              reply_to,
+            )
+            .await;
+          each.push((dst, reply));
         }
+        resp.push(ClientReply::Multi(each))
       }
-    }
+      // a single id and a single reply for all the recipients
+      ClientMessage::AtomicMText { dest, content } => {
+        let mail = self.text(content);
+        resp.push(
+          self
+            .atomic_message(src, &dest, priority, id(), mail, reply_to)
+            .await,
+        )
+      }
+      ClientMessage::Rich { dest, content } => {
+        for dst in dest {
+          let reply = self
+            .client_message(
+              src,
+              dst,
+              priority,
+              id(),
+              Mail::Rich(content.clone()),
+              reply_to,
+            )
+            .await;
+          if let ClientReply::Delivered(Some(id)) = reply {
+            if content.mentions_client(dst) {
+              self.mention(src, dst, id).await;
+            }
+          }
+          resp.push(reply)
+        }
+      }
+      ClientMessage::RoomText { room, content } => {
+        let (members, servers) = match self.rooms.read().await.get(&room) {
+          Some(r) if r.members.contains(&src) => (r.members.clone(), r.servers()),
+          Some(r) if r.has_members() => return vec![ClientReply::Error(ClientError::Forbidden)],
+          _ => return vec![ClientReply::Error(ClientError::UnknownRoom(room))],
+        };
+        // one reply per other member, in join order
+        for dst in members.into_iter().filter(|m| *m != src) {
+          resp.push(
+            self
+              .client_message(
+                src,
+                dst,
+                priority,
+                id(),
+                Mail::Room(room, content.clone()),
+                reply_to,
+              )
+              .await,
+          )
+        }
+        // then a single copy for each nexthop toward the other servers of the room
+        for (nexthop, dstsrvs) in self.by_nexthop(servers).await {
+          let message = ServerMessage::RoomText {
+            room,
+            src,
+            srcsrv: self.id,
+            dstsrvs,
+            content: content.clone(),
+          };
+          resp.push(ClientReply::Transfer(nexthop, message, None));
+        }
+      }
+      ClientMessage::Edit { dest, id, content } => {
+        resp.push(self.rewrite(src, dest, id, Some(content)).await)
+      }
+      ClientMessage::Delete { dest, id } => resp.push(self.rewrite(src, dest, id, None).await),
+      ClientMessage::Data { dest, mime, bytes } => {
+        if bytes.len() > self.max_data_size {
+          let max = self.max_data_size as u128;
+          return vec![ClientReply::Error(ClientError::MessageTooLarge(max))];
+        }
+        resp.push(
+          self
+            .client_message(src, dest, priority, id(), Mail::Data(mime, bytes), reply_to)
+            .await,
+        )
+      }
+      // the payload is opaque, only its size is limited
+      ClientMessage::KeyAgreement { dest, payload } => {
+        if payload.len() > self.max_data_size {
+          let max = self.max_data_size as u128;
+          return vec![ClientReply::Error(ClientError::MessageTooLarge(max))];
+        }
+        resp.push(self.key_agreement(src, dest, priority, payload).await)
+      }
+      ClientMessage::Ack(_)
+      | ClientMessage::React { .. }
+      | ClientMessage::SetDoNotDisturb(_)
+      | ClientMessage::SetPresence(_)
+      | ClientMessage::SubscribePresence(_)
+      | ClientMessage::Broadcast { .. }
+      | ClientMessage::Event { .. }
+      | ClientMessage::Prioritized { .. }
+      | ClientMessage::WithId { .. }
+      | ClientMessage::ReplyTo { .. } => unreachable!(),
+    }
     resp
   }
 
@@ -152,17 +1466,236 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
    */
   async fn client_poll(&self, client: ClientId) -> ClientPollReply {
     let mut clt = self.clients.write().await;
-    let clt = clt.get_mut(&client);
-    match clt {
+    match clt.get_mut(&client) {
       Some(clt) => {
-        let (src, content) = match clt.mailbox.pop_front() {
-          Some(value) => value,
-          None => return ClientPollReply::Nothing,
+        clt.last_poll = self.clock.now();
+        clt.poll().unwrap_or(ClientPollReply::Nothing)
+      }
+      None => ClientPollReply::DelayedError(DelayedError::UnknownRecipient(client)),
+    }
+  }
+
+  async fn client_poll_wait(&self, client: ClientId, wait: Duration) -> ClientPollReply {
+    let deadline = self.clock.now() + wait;
+    loop {
+      let (woken, held_until) = match self.clients.write().await.get_mut(&client) {
+        Some(clt) => {
+          clt.last_poll = self.clock.now();
+          match clt.poll() {
+            Some(reply) => return reply,
+            None => (clt.wait(), clt.held_until()),
+          }
+        }
+        None => return ClientPollReply::DelayedError(DelayedError::UnknownRecipient(client)),
+      };
+      // woken up, the client is gone, or a held message can be polled: poll again, another
+      // poller may have been faster
+      let until = held_until.map_or(deadline, |until| until.min(deadline));
+      let left = until.saturating_duration_since(self.clock.now());
+      if timeout(&*self.clock, left, woken).await.is_err() && self.clock.now() >= deadline {
+        return ClientPollReply::Nothing;
+      }
+    }
+  }
+
+  fn client_stream(&self, client: ClientId) -> BoxStream<'_, (ClientId, String)> {
+    stream::unfold(self, move |srv| async move {
+      loop {
+        let (woken, held_until) = match srv.clients.write().await.get_mut(&client) {
+          Some(clt) => {
+            clt.last_poll = self.clock.now();
+            match clt.poll_text() {
+              Some(message) => return Some((message, srv)),
+              None => (
+                clt.wait(),
+                clt.held_until().into_iter().chain(clt.quiet_until()).min(),
+              ),
+            }
+          }
+          None => return None,
         };
-        return ClientPollReply::Message { src, content };
+        // woken up by a mail or an event, the client is gone, or a held message or the end of
+        // the quiet hours can be polled
+        match held_until {
+          Some(until) => {
+            let _ = timeout(
+              &*self.clock,
+              until.saturating_duration_since(self.clock.now()),
+              woken,
+            )
+            .await;
+          }
+          None => {
+            let _ = woken.await;
+          }
+        }
+      }
+    })
+    .boxed()
+  }
+
+  async fn sync(
+    &self,
+    client: ClientId,
+    cursor: &SyncCursor,
+    limit: usize,
+  ) -> Result<SyncReply, ClientError> {
+    let limit = limit.clamp(1, HISTORY_SIZE + TOMBSTONES_SIZE);
+    let mut clients = self.clients.write().await;
+    let clt = clients.get_mut(&client).ok_or(ClientError::UnknownClient)?;
+    clt.last_poll = self.clock.now();
+    let mut reply = SyncReply::default();
+    while let Some(polled) = clt.poll() {
+      match polled {
+        ClientPollReply::Presence { client, presence } => reply.presence.push((client, presence)),
+        ClientPollReply::Receipt { id, reader } => reply.receipts.push((id, reader)),
+        // now in the history
+        ClientPollReply::Message { .. }
+        | ClientPollReply::RichMessage { .. }
+        | ClientPollReply::RoomMessage { .. }
+        | ClientPollReply::Data { .. }
+        | ClientPollReply::Deleted { .. }
+        | ClientPollReply::ReplyTo { .. } => (),
+        other => reply.other.push(other),
+      }
+    }
+    let found = cursor
+      .history
+      .and_then(|id| clt.history.iter().position(|k| k.entry.id == id));
+    // a device without a cursor, or that fell behind, gets everything
+    reply.full = found.is_none();
+    let start = found.map_or(0, |position| position + 1);
+    reply.messages = clt
+      .history
+      .iter()
+      .skip(start)
+      .take(limit)
+      .map(|k| k.entry.clone())
+      .collect();
+    reply.read_markers = clt
+      .read_markers
+      .iter()
+      .filter(|(_, (_, version))| reply.full || *version > cursor.read_markers)
+      .map(|(author, (id, _))| (*author, *id))
+      .collect();
+    reply.read_markers.sort();
+    reply.cursor = SyncCursor {
+      history: reply.messages.last().map(|e| e.id).or(cursor.history),
+      read_markers: clt.read_version,
+    };
+    if reply.full {
+      // the current presence of the subscriptions, rather than the changes
+      let remote_clients = self.remote_clients.read().await;
+      reply.presence = self
+        .subscribers
+        .read()
+        .await
+        .iter()
+        .filter(|(_, watchers)| watchers.contains(&client))
+        .filter_map(|(watched, _)| {
+          let presence = match clients.get(watched) {
+            Some(info) => info.presence,
+            None => remote_clients.get(watched)?.presence,
+          };
+          Some((*watched, presence))
+        })
+        .collect();
+      reply.presence.sort_by_key(|(watched, _)| *watched);
+    }
+    Ok(reply)
+  }
+
+  async fn client_poll_n(&self, client: ClientId, max: usize) -> Vec<ClientPollReply> {
+    let mut clt = self.clients.write().await;
+    match clt.get_mut(&client) {
+      Some(clt) => {
+        clt.last_poll = self.clock.now();
+        std::iter::from_fn(|| clt.poll()).take(max).collect()
+      }
+      None => vec![ClientPollReply::DelayedError(
+        DelayedError::UnknownRecipient(client),
+      )],
+    }
+  }
+
+  async fn expire_messages(&self, now: Instant) -> usize {
+    let mut clients = self.clients.write().await;
+    // local sender -> recipients of its expired messages
+    let mut senders: HashMap<ClientId, Vec<ClientId>> = HashMap::new();
+    let mut count = 0;
+    for (&client, info) in clients.iter_mut() {
+      info.purge(now, self.tombstone_grace);
+      for (src, mail) in info.expire(now) {
+        count += 1;
+        if matches!(mail, Mail::Text(..) | Mail::Rich(_) | Mail::Room(..)) {
+          senders.entry(src).or_default().push(client);
+        }
       }
-      None => return ClientPollReply::DelayedError(DelayedError::UnknownRecipient(client)),
     }
+    for (src, dest) in self.stored_messages.write().await.expire(now) {
+      count += 1;
+      senders.entry(src).or_default().push(dest);
+    }
+    self.undelivered(&mut clients, senders, Mail::Expired, now + self.ttl);
+    count
+  }
+
+  async fn client_history(
+    &self,
+    client: ClientId,
+    limit: usize,
+    before: Option<MessageId>,
+    include_tombstones: bool,
+  ) -> Result<Vec<HistoryEntry>, ClientError> {
+    let clients = self.clients.read().await;
+    let history: Vec<&HistoryEntry> = clients
+      .get(&client)
+      .ok_or(ClientError::UnknownClient)?
+      .history
+      .iter()
+      .filter(|k| include_tombstones || k.removed.is_none())
+      .map(|k| &k.entry)
+      .collect();
+    let end = match before {
+      None => history.len(),
+      Some(id) => history.iter().position(|e| e.id == id).unwrap_or(0),
+    };
+    let start = end.saturating_sub(limit);
+    Ok(history[start..end].iter().map(|e| (*e).clone()).collect())
+  }
+
+  #[cfg(feature = "search")]
+  async fn search_history(
+    &self,
+    client: ClientId,
+    query: &SearchQuery,
+  ) -> Result<SearchPage, ClientError> {
+    let limit = usize::try_from(query.limit)
+      .unwrap_or(usize::MAX)
+      .clamp(1, HISTORY_SIZE);
+    let clients = self.clients.read().await;
+    let client = clients.get(&client).ok_or(ClientError::UnknownClient)?;
+    let (found, next) = client.index.search(query, limit);
+    let hits = found
+      .into_iter()
+      .filter_map(|(id, polled)| {
+        let kept = client.history.iter().rev().find(|k| k.entry.id == id)?;
+        Some(SearchHit {
+          entry: kept.entry.clone(),
+          polled,
+        })
+      })
+      .collect();
+    Ok(SearchPage { hits, next })
+  }
+
+  #[cfg(not(feature = "search"))]
+  async fn search_history(
+    &self,
+    _client: ClientId,
+    _query: &SearchQuery,
+  ) -> Result<SearchPage, ClientError> {
+    Err(ClientError::Forbidden)
   }
 
   /* For announces
@@ -180,44 +1713,92 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
         if route.is_empty() {
           return ServerReply::EmptyRoute;
         } else {
-          // If not, store the route in some way associated from client_dst and the route
-          self.routes.write().await.push(route.clone());
-
-          let srv_dst = self.get_srv_dist(&route);
-          let nexthop = self.get_nexthop(&route);
+          let (origin, nexthop) = {
+            let mut router = self.router.write().await;
+            let origin = router.on_announce(&route);
+            (origin, origin.and_then(|o| router.next_hop(o.server())))
+          };
+          // our own announce, back to us
+          let (Some(origin), Some(nexthop)) = (origin, nexthop) else {
+            return ServerReply::Outgoing(Vec::new());
+          };
+          let srv_dst = origin.server();
 
           // On ajoute à la liste chaque message stored pour le client distant
           let mut resp = Vec::new();
+          // local sender -> recipients of its refused messages
+          let mut rejected: HashMap<ClientId, Vec<ClientId>> = HashMap::new();
 
+          // the locks are held until the stored messages are flushed, so that a message sent
+          // meanwhile to a newly known client can't overtake them (same order as client_message,
+          // after the bans like is_banned)
+          let bans = self.banned.read().await;
+          let mut locals = self.clients.write().await;
+          let mut remote_clients = self.remote_clients.write().await;
+          let mut stored_messages = self.stored_messages.write().await;
           for (client_dst, name) in clients {
             // On enregistre chaque client distant avec leur par leur ID client associé avec leur nom
             // Store the remote clients
-            self.remote_clients.write().await.insert(
+            // a new announce does not tell anything about the presence
+            let presence = remote_clients
+              .get(&client_dst)
+              .map_or(Presence::default(), |c| c.presence);
+            remote_clients.insert(
               client_dst,
               RemoteClient {
-                _name: name.clone(),
-                srcsrv: srv_dst,
+                name: name.clone(),
+                srcsrv: origin,
+                presence,
               },
             );
 
-            // if one of these remote clients has messages waiting, return them
-            if let Some(message) = self.stored_messages.write().await.remove(&client_dst) {
-              resp.push(Outgoing {
-                nexthop,
-                message: FullyQualifiedMessage {
-                  // Client source
-                  src: message.src,
-                  // Serveur source
-                  srcsrv: self.id,
-                  // Liste des serveurs distants avec leurs clients
-                  dsts: vec![(client_dst, srv_dst)],
-                  // Message texte envoyé
-                  content: message.content.clone(),
-                },
-              })
+            // if one of these remote clients has messages waiting, return them, oldest first
+            for message in stored_messages.take(client_dst) {
+              // the sender was banned since, the message is refused like it would be now
+              if bans.is_banned(&locals, message.src) {
+                rejected.entry(message.src).or_default().push(client_dst);
+                continue;
+              }
+              let src = message.src;
+              let built = self
+                .fully_qualified(message.src)
+                .to(client_dst, srv_dst)
+                .content(message.content)
+                .with_content_type(message.content_type)
+                .mentions(message.mentions)
+                .sequenced(message.seq)
+                .in_reply_to(message.in_reply_to)
+                .timestamp(message.timestamp)
+                .id(message.id)
+                .build();
+              match built {
+                Ok(message) => resp.push(Outgoing { nexthop, message }),
+                Err(rr) => {
+                  log::error!("Dropping a message to {}: {}", client_dst, rr);
+                  rejected.entry(src).or_default().push(client_dst);
+                }
+              }
             }
           }
-          ServerReply::Outgoing(resp)
+          drop((bans, remote_clients, stored_messages));
+          self.undelivered(&mut locals, rejected, Mail::Rejected, self.expiry());
+          drop(locals);
+          // a neighbour announcing itself learns the rooms it missed
+          let rooms = match route.len() {
+            1 => self.known_rooms().await,
+            _ => Vec::new(),
+          };
+          if rooms.is_empty() {
+            return ServerReply::Outgoing(resp);
+          }
+          let messages = resp.into_iter().map(|o| Outgoing {
+            nexthop: o.nexthop,
+            message: ServerMessage::Message(o.message),
+          });
+          let rooms = rooms
+            .into_iter()
+            .map(|message| Outgoing { nexthop, message });
+          ServerReply::Forward(messages.chain(rooms).collect())
         }
       }
       ServerMessage::Message(fully_qualified_message) => {
@@ -227,20 +1808,56 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
         {
           // Si le client distant correspond à client local on délivre le message
           if let Some(info) = self.clients.write().await.get_mut(&client_dst) {
-            info.mailbox.push_back((
-              fully_qualified_message.src,
-              fully_qualified_message.content.clone(),
-            ));
+            // the remote server already answered the sender, the message is just dropped
+            if info.blocked.contains(&fully_qualified_message.src) {
+              return ServerReply::Outgoing(vec![]);
+            }
+            let entry = Waiting {
+              src: fully_qualified_message.src,
+              id: fully_qualified_message.id,
+              mail: Mail::from_parts(&fully_qualified_message),
+              expires: self.expiry(),
+              seq: fully_qualified_message.seq,
+              held_until: self.clock.now() + self.reorder_wait,
+              in_reply_to: fully_qualified_message.in_reply_to,
+            };
+            if !info.deliver_entry(self.overflow, Priority::Normal, entry) {
+              return ServerReply::Error(format!("Mailbox of {} is full", client_dst));
+            }
+            // the mentions came with the message, the recipient is told like a local one
+            let mentioned = fully_qualified_message
+              .mentions
+              .iter()
+              .any(|m| m.client == client_dst);
+            if mentioned && info.notifies(fully_qualified_message.src) {
+              info.signal(
+                fully_qualified_message.src,
+                Event::Mentioned(fully_qualified_message.id),
+              );
+            }
+            // the sender only got a transfer, or a delay, it is told about the delivery
+            if server_dst == self.id {
+              return self
+                .delivered(
+                  fully_qualified_message.id,
+                  fully_qualified_message.src,
+                  fully_qualified_message.srcsrv,
+                  client_dst,
+                )
+                .await;
+            }
+          }
+
+          if server_dst == self.id {
+            return ServerReply::Outgoing(vec![]);
           }
 
-          // La route qui mène au client distant
-          let route = match self.route_to(server_dst).await {
+          // sinon on le transmet vers le serveur distant
+          let nexthop = match self.router.read().await.next_hop(server_dst) {
             Some(value) => value,
             None => return ServerReply::Error("Route for the client not found".to_string()),
           };
 
-          let nexthop = self.get_nexthop(&route);
-
           return ServerReply::Outgoing(vec![Outgoing {
             nexthop,
             message: fully_qualified_message,
@@ -248,6 +1865,205 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
         }
         ServerReply::Error("No destination found for the message".to_string())
       }
+      ServerMessage::Batch(messages) => {
+        // chaque message est traité dans l'ordre, une erreur n'interrompt pas le reste du lot
+        let mut resp = Vec::new();
+        let mut forward = Vec::new();
+        for message in messages {
+          match self.handle_server_message(message).await {
+            ServerReply::Outgoing(outgoing) => resp.extend(outgoing),
+            ServerReply::Forward(outgoing) => forward.extend(outgoing),
+            ServerReply::EmptyRoute => log::warn!("Empty route in batch"),
+            ServerReply::Error(rr) => log::warn!("Error in batch: {}", rr),
+          }
+        }
+        if forward.is_empty() {
+          return ServerReply::Outgoing(resp);
+        }
+        let messages = resp.into_iter().map(|o| Outgoing {
+          nexthop: o.nexthop,
+          message: ServerMessage::Message(o.message),
+        });
+        ServerReply::Forward(messages.chain(forward).collect())
+      }
+      ServerMessage::Receipt {
+        id,
+        reader,
+        dst,
+        dstsrv,
+      } => {
+        if dstsrv == self.id {
+          return match self.receipt(id, reader, dst).await {
+            ClientReply::Error(rr) => ServerReply::Error(format!("Receipt not delivered: {}", rr)),
+            _ => ServerReply::Outgoing(Vec::new()),
+          };
+        }
+        match self.router.read().await.next_hop(dstsrv) {
+          Some(nexthop) => ServerReply::Forward(vec![Outgoing {
+            nexthop,
+            message: ServerMessage::Receipt {
+              id,
+              reader,
+              dst,
+              dstsrv,
+            },
+          }]),
+          None => ServerReply::Error("Route for the client not found".to_string()),
+        }
+      }
+      ServerMessage::Reaction {
+        id,
+        reactor,
+        dst,
+        dstsrv,
+        emoji,
+      } => {
+        if dstsrv == self.id {
+          return match self.reaction(id, reactor, dst, emoji).await {
+            ClientReply::Error(rr) => ServerReply::Error(format!("Reaction not delivered: {}", rr)),
+            _ => ServerReply::Outgoing(Vec::new()),
+          };
+        }
+        match self.router.read().await.next_hop(dstsrv) {
+          Some(nexthop) => ServerReply::Forward(vec![Outgoing {
+            nexthop,
+            message: ServerMessage::Reaction {
+              id,
+              reactor,
+              dst,
+              dstsrv,
+              emoji,
+            },
+          }]),
+          None => ServerReply::Error("Route for the client not found".to_string()),
+        }
+      }
+      ServerMessage::Withdraw { srv, clients } => {
+        let mut remote_clients = self.remote_clients.write().await;
+        let mut subscribers = self.subscribers.write().await;
+        for client in clients {
+          // only the server of a client can withdraw it
+          if remote_clients
+            .get(&client)
+            .is_some_and(|c| c.srcsrv.server() == srv)
+          {
+            remote_clients.remove(&client);
+            subscribers.remove(&client);
+          }
+        }
+        ServerReply::Outgoing(Vec::new())
+      }
+      ServerMessage::Presence { client, presence } => {
+        match self.remote_clients.write().await.get_mut(&client) {
+          Some(remote) => remote.presence = presence,
+          None => return ServerReply::Error("Presence of an unknown client".to_string()),
+        }
+        self.notify_presence(client, presence).await;
+        ServerReply::Outgoing(Vec::new())
+      }
+      ServerMessage::Delivered {
+        id,
+        src,
+        srcsrv,
+        dst,
+      } => self.delivered(id, src, srcsrv, dst).await,
+      ServerMessage::Event {
+        src,
+        dst,
+        dstsrv,
+        event,
+      } => {
+        if dstsrv == self.id {
+          return match self.event(src, dst, event).await {
+            // like messages, the event is just dropped
+            ClientReply::Error(ClientError::Blocked(_)) => ServerReply::Outgoing(Vec::new()),
+            ClientReply::Error(rr) => ServerReply::Error(format!("Event not delivered: {}", rr)),
+            _ => ServerReply::Outgoing(Vec::new()),
+          };
+        }
+        match self.router.read().await.next_hop(dstsrv) {
+          Some(nexthop) => ServerReply::Forward(vec![Outgoing {
+            nexthop,
+            message: ServerMessage::Event {
+              src,
+              dst,
+              dstsrv,
+              event,
+            },
+          }]),
+          None => ServerReply::Error("Route for the client not found".to_string()),
+        }
+      }
+      ServerMessage::KeyAgreement {
+        src,
+        dst,
+        dstsrv,
+        payload,
+      } => {
+        if dstsrv == self.id {
+          return match self
+            .key_agreement(src, dst, Priority::Normal, payload)
+            .await
+          {
+            // like messages, the handshake is just dropped
+            ClientReply::Error(ClientError::Blocked(_)) => ServerReply::Outgoing(Vec::new()),
+            ClientReply::Error(rr) => {
+              ServerReply::Error(format!("Key agreement not delivered: {}", rr))
+            }
+            _ => ServerReply::Outgoing(Vec::new()),
+          };
+        }
+        match self.router.read().await.next_hop(dstsrv) {
+          Some(nexthop) => ServerReply::Forward(vec![Outgoing {
+            nexthop,
+            message: ServerMessage::KeyAgreement {
+              src,
+              dst,
+              dstsrv,
+              payload,
+            },
+          }]),
+          None => ServerReply::Error("Route for the client not found".to_string()),
+        }
+      }
+      ServerMessage::RoomMembers {
+        room,
+        srv,
+        version,
+        members,
+      } => self.room_members(room, srv, version, members).await,
+      ServerMessage::RoomText {
+        room,
+        src,
+        srcsrv,
+        dstsrvs,
+        content,
+      } => {
+        let (here, others): (Vec<_>, Vec<_>) = dstsrvs.into_iter().partition(|s| *s == self.id);
+        if !here.is_empty() {
+          self.room_text(room, src, content.clone()).await;
+        }
+        let forward = self
+          .by_nexthop(others)
+          .await
+          .into_iter()
+          .map(|(nexthop, dstsrvs)| Outgoing {
+            nexthop,
+            message: ServerMessage::RoomText {
+              room,
+              src,
+              srcsrv,
+              dstsrvs,
+              content: content.clone(),
+            },
+          })
+          .collect();
+        ServerReply::Forward(forward)
+      }
+      // link state between neighbours, kept by the federation driver
+      ServerMessage::Resume { .. } | ServerMessage::Session { .. } => {
+        ServerReply::Outgoing(Vec::new())
+      }
     }
   }
 
@@ -259,129 +2075,2898 @@ impl<C: SpamChecker + Send + Sync> MessageServer<C> for Server<C> {
       .collect()
   }
 
-  // return a route to the target server
-  // bonus points if it is the shortest route
-  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
-    let mut graph: HashMap<ServerId, Vec<ServerId>> = HashMap::new();
-
-    // Step 1: Build the graph
-    for route in self.routes.read().await.iter() {
-      for window in route.windows(2) {
-        let (a, b) = (window[0], window[1]);
-        graph.entry(a).or_default().push(b);
-        graph.entry(b).or_default().push(a); // Bidirectional edge
-      }
-      // Connect the self server to the nearest server in each route
-      if let Some(&first_server) = route.last() {
-        graph.entry(self.id).or_default().push(first_server);
-        graph.entry(first_server).or_default().push(self.id); // Bidirectional
-      }
-    }
-
-    // Step 2: BFS to find the shortest path
-    let mut queue = VecDeque::new();
-    let mut visited = HashMap::new(); // Track visited servers and their predecessors
-    queue.push_back(self.id);
-    visited.insert(self.id, None);
-
-    while let Some(current) = queue.pop_front() {
-      if current == destination {
-        // Step 3: Reconstruct the path
-        let mut path = Vec::new();
-        let mut node = Some(current);
-        while let Some(n) = node {
-          path.push(n);
-          node = visited.get(&n).and_then(|&v| v);
-        }
-        path.reverse();
-        return Some(path);
-      }
-
-      // Add neighbors to the queue
-      if let Some(neighbors) = graph.get(&current) {
-        for &neighbor in neighbors {
-          visited.entry(neighbor).or_insert_with(|| {
-            queue.push_back(neighbor);
-            Some(current) // Track the predecessor
-          });
-        }
+  async fn list_users_page(&self, query: &UserQuery) -> UserPage {
+    let limit = usize::try_from(query.limit)
+      .unwrap_or(usize::MAX)
+      .clamp(1, USER_PAGE_SIZE);
+    let clients = self.clients.read().await;
+    let remote_clients = self.remote_clients.read().await;
+    let local = clients.iter().map(|(id, c)| (*id, &c.name, self.id));
+    let remote = remote_clients
+      .iter()
+      .filter(|_| query.federated)
+      .map(|(id, c)| (*id, &c.name, c.srcsrv.server()));
+    let mut users: Vec<(ClientId, &String, ServerId)> = local
+      .chain(remote)
+      .filter(|(id, _, _)| query.after.is_none_or(|after| *id > after))
+      .filter(|(_, name, _)| query.filter.as_ref().is_none_or(|f| f.matches(name)))
+      .collect();
+    // only the names of the page are cloned
+    let more = users.len() > limit;
+    if more {
+      users.select_nth_unstable_by_key(limit - 1, |(id, _, _)| *id);
+      users.truncate(limit);
+    }
+    users.sort_unstable_by_key(|(id, _, _)| *id);
+    UserPage {
+      next: if more {
+        users.last().map(|(id, _, _)| *id)
+      } else {
+        None
+      },
+      users: users
+        .into_iter()
+        .map(|(id, name, server)| UserEntry {
+          id,
+          name: name.clone(),
+          server,
+          tag: None,
+        })
+        .collect(),
+    }
+  }
+
+  async fn list_user_entries(&self) -> Vec<UserEntry> {
+    let mut entries: Vec<UserEntry> = self
+      .clients
+      .read()
+      .await
+      .iter()
+      .map(|(id, client)| UserEntry {
+        id: *id,
+        name: client.name.clone(),
+        server: self.id,
+        tag: None,
+      })
+      .collect();
+    entries.extend(
+      self
+        .remote_clients
+        .read()
+        .await
+        .iter()
+        .map(|(id, client)| UserEntry {
+          id: *id,
+          name: client.name.clone(),
+          server: client.srcsrv.server(),
+          tag: None,
+        }),
+    );
+    let mut uses: HashMap<String, usize> = HashMap::new();
+    for entry in &entries {
+      *uses.entry(name_key(&entry.name)).or_default() += 1;
+    }
+    for entry in &mut entries {
+      if uses[&name_key(&entry.name)] > 1 {
+        entry.tag = Some(entry.id.short());
       }
     }
+    entries
+  }
 
-    None // No path found
+  async fn lookup_user(&self, name: &str) -> Option<ClientId> {
+    let local: Vec<ClientId> = self
+      .clients
+      .read()
+      .await
+      .iter()
+      .filter(|(_, client)| same_name(&client.name, name))
+      .map(|(id, _)| *id)
+      .collect();
+    if !local.is_empty() {
+      return single(local.into_iter());
+    }
+    single(
+      self
+        .remote_clients
+        .read()
+        .await
+        .iter()
+        .filter(|(_, client)| same_name(&client.name, name))
+        .map(|(id, _)| *id),
+    )
   }
-}
 
-impl<C: SpamChecker + Sync + Send> Server<C> {
-  async fn client_message(&self, src: ClientId, dest: ClientId, content: String) -> ClientReply {
-    let mut client = self.clients.write().await;
-    let client = client.get_mut(&dest);
-    match client {
-      // if the client is local
-      Some(client) => {
-        if client.mailbox.len() == MAILBOX_SIZE {
-          // if the mailbox is full, BoxFull should be returned
-          ClientReply::Error(ClientError::BoxFull(dest))
-        } else {
-          // otherwise, Delivered should be returned
-          client.mailbox.push_back((src, content));
-          ClientReply::Delivered
+  async fn remote_users(&self) -> HashMap<ClientId, ServerId> {
+    self
+      .remote_clients
+      .read()
+      .await
+      .iter()
+      .map(|(id, client)| (*id, client.srcsrv.server()))
+      .collect()
+  }
+
+  async fn handle_report(
+    &self,
+    src: ClientId,
+    target: ReportTarget,
+    reason: String,
+  ) -> ClientReply {
+    if !self.allowed(src, Action::Report).await {
+      return ClientReply::Error(ClientError::Forbidden);
+    }
+    if reason.len() > MAX_REPORT_REASON {
+      return ClientReply::Error(ClientError::MessageTooLarge(MAX_REPORT_REASON as u128));
+    }
+    if let ReportTarget::Client(client) = target {
+      let known = self.clients.read().await.contains_key(&client)
+        || self.remote_clients.read().await.contains_key(&client);
+      if !known {
+        return ClientReply::Error(ClientError::UnknownClient);
+      }
+    }
+    let mut reports = self.reports.write().await;
+    if reports.iter().filter(|r| r.reporter == src).count() >= REPORTS_PER_REPORTER {
+      return ClientReply::Error(ClientError::RateLimited);
+    }
+    log::info!("abuse report from {} about {:?}: {}", src, target, reason);
+    if reports.len() == REPORTS_SIZE {
+      reports.pop_front();
+    }
+    reports.push_back(AbuseReport {
+      reporter: src,
+      target,
+      reason,
+    });
+    ClientReply::Delivered(None)
+  }
+
+  async fn notification_prefs(&self, client: ClientId) -> Result<NotificationPrefs, ClientError> {
+    match self.clients.read().await.get(&client) {
+      Some(info) => Ok(info.prefs.clone()),
+      None => Err(ClientError::UnknownClient),
+    }
+  }
+
+  async fn set_blocked(&self, client: ClientId, blocked: Vec<ClientId>) -> ClientReply {
+    match self.clients.write().await.get_mut(&client) {
+      Some(info) => {
+        info.blocked = blocked.into_iter().collect();
+        ClientReply::Delivered(None)
+      }
+      None => ClientReply::Error(ClientError::UnknownClient),
+    }
+  }
+
+  async fn set_notification_prefs(
+    &self,
+    client: ClientId,
+    mut prefs: NotificationPrefs,
+  ) -> ClientReply {
+    prefs.muted.sort();
+    prefs.muted.dedup();
+    match self.clients.write().await.get_mut(&client) {
+      Some(info) => {
+        info.prefs = prefs;
+        ClientReply::Delivered(None)
+      }
+      None => ClientReply::Error(ClientError::UnknownClient),
+    }
+  }
+
+  async fn create_room(&self, client: ClientId, name: String) -> Result<RoomId, ClientError> {
+    if !self.clients.read().await.contains_key(&client) {
+      return Err(ClientError::UnknownClient);
+    }
+    let room = RoomId(self.rng.uuid());
+    self.rooms.write().await.insert(
+      room,
+      Room {
+        _name: name,
+        members: vec![client],
+        ..Room::default()
+      },
+    );
+    Ok(room)
+  }
+
+  async fn join_room(&self, client: ClientId, room: RoomId) -> ClientReply {
+    if !self.clients.read().await.contains_key(&client) {
+      return ClientReply::Error(ClientError::UnknownClient);
+    }
+    match self.rooms.write().await.get_mut(&room) {
+      Some(r) if r.has_members() => {
+        if !r.members.contains(&client) {
+          r.members.push(client);
         }
+        ClientReply::Delivered(None)
       }
-      None => {
-        let remote_client = self.remote_clients.write().await;
-        match remote_client.get(&dest) {
-          // if the client is remote, Transfer should be returned
-          Some(client_remote_info) => {
-            for route in self.routes.read().await.iter() {
-              let srv_dst = self.get_srv_dist(route);
-              let nexthop = self.get_nexthop(route);
-
-              if srv_dst == client_remote_info.srcsrv {
-                let message = ServerMessage::Message(FullyQualifiedMessage {
-                  src,
-                  srcsrv: self.id,
-                  dsts: vec![(dest, srv_dst)],
-                  content: content.clone(),
-                });
-                return ClientReply::Transfer(nexthop, message);
-              }
-            }
-            ClientReply::Error(ClientError::UnknownClient)
-          }
-          // if the client is unknown, the message should be stored and Delayed must be returned (federation)
-          None => {
-            self
-              .stored_messages
-              .write()
-              .await
-              .insert(dest, Message { src, content });
-            ClientReply::Delayed
-          }
+      _ => ClientReply::Error(ClientError::UnknownRoom(room)),
+    }
+  }
+
+  async fn leave_room(&self, client: ClientId, room: RoomId) -> ClientReply {
+    let mut rooms = self.rooms.write().await;
+    match rooms.get_mut(&room) {
+      Some(r) if r.has_members() => {
+        r.members.retain(|m| *m != client);
+        // nobody can join a room that nobody knows about anymore
+        if !r.has_members() && r.remote.is_empty() {
+          rooms.remove(&room);
+        }
+        ClientReply::Delivered(None)
+      }
+      _ => ClientReply::Error(ClientError::UnknownRoom(room)),
+    }
+  }
+
+  async fn make_announce(&self) -> ServerMessage {
+    ServerMessage::Announce {
+      route: vec![self.id],
+      clients: self.list_users().await,
+    }
+  }
+
+  async fn announce_room(&self, room: RoomId) -> Vec<Outgoing<ServerMessage>> {
+    let now = self.clock.now_ms();
+    // the members and their version are taken together, so that concurrent announces can't be
+    // received in the wrong order
+    let message = {
+      let mut rooms = self.rooms.write().await;
+      let last = self
+        .room_version
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+          Some(now.max(last + 1))
+        })
+        .unwrap_or_else(|last| last);
+      let version = now.max(last + 1);
+      let members = match rooms.get_mut(&room) {
+        Some(r) => {
+          r.version = version;
+          r.members.clone()
         }
+        // the room was deleted: nobody is left here
+        None => Vec::new(),
+      };
+      ServerMessage::RoomMembers {
+        room,
+        srv: self.id,
+        version,
+        members,
       }
+    };
+    self
+      .router
+      .read()
+      .await
+      .neighbours()
+      .into_iter()
+      .map(|nexthop| Outgoing {
+        nexthop,
+        message: message.clone(),
+      })
+      .collect()
+  }
+
+  // return a route to the target server
+  // bonus points if it is the shortest route
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    self.router.read().await.route_to(destination)
+  }
+}
+
+#[async_trait]
+impl<C: SpamChecker + Send + Sync> AdminServer<C> for Server<C> {
+  async fn ban_client(&self, client: ClientId) -> ClientReply {
+    if !self.clients.read().await.contains_key(&client) {
+      return ClientReply::Error(ClientError::UnknownClient);
     }
+    self.banned.write().await.clients.insert(client);
+    ClientReply::Delivered(None)
+  }
+
+  async fn kick_client(&self, client: ClientId) -> Vec<ClientReply> {
+    self.unregister_local_client(client).await
+  }
+
+  async fn ban_ip(&self, ip: IpAddr) {
+    self.banned.write().await.addresses.insert(ip);
+  }
+
+  async fn issue_invite(&self, ttl: Duration, uses: u32) -> Option<Invite> {
+    let invites = self.invites.as_ref()?;
+    Some(invites.issue(&*self.rng, self.clock.now_ms(), ttl, uses))
   }
 
-  // Le serveur distant correspond au premier serveur ID de la route
-  fn get_srv_dist(&self, route: &[ServerId]) -> ServerId {
-    *route.first().unwrap()
+  async fn import_history(&self, records: &[ArchiveRecord]) -> usize {
+    let mut clients = self.clients.write().await;
+    records
+      .iter()
+      .filter(|record| {
+        clients
+          .get_mut(&record.dst)
+          .is_some_and(|client| client.restore(record.to_entry(), record.timestamp))
+      })
+      .count()
   }
 
-  // Le nexthop correspond au premier serveur ID de la route
-  fn get_nexthop(&self, route: &[ServerId]) -> ServerId {
-    *route.last().unwrap()
+  async fn export_history(&self, filter: &ExportFilter) -> Vec<ArchiveRecord> {
+    let clients = self.clients.read().await;
+    let mut records = Vec::new();
+    for (dst, client) in clients.iter() {
+      records.extend(
+        client
+          .history
+          .iter()
+          .filter(|k| k.removed.is_none())
+          .filter_map(|k| ArchiveRecord::of_entry(*dst, &k.entry, k.timestamp, self.id))
+          .filter(|record| filter.matches(record)),
+      );
+    }
+    records
   }
 }
 
-#[cfg(test)]
-mod test {
-  use crate::testing::{test_message_server, TestChecker};
+impl<C: SpamChecker + Sync + Send> Server<C> {
+  /// replaces the default rules (guests can only read)
+  pub fn set_authorizer<A: Authorizer + Send + Sync + 'static>(&mut self, authorizer: A) {
+    self.authorizer = Box::new(authorizer);
+  }
 
-  use super::*;
+  /// what happens when a mailbox is full, new messages are rejected by default
+  pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+    self.overflow = policy;
+  }
 
-  #[test]
-  fn tester() {
-    test_message_server::<Server<TestChecker>>();
+  pub fn set_name_policy(&mut self, policy: NamePolicy) {
+    self.names = policy;
+  }
+
+  /// refuses the name of another local client, unless names are shared
+  fn check_name(
+    &self,
+    clients: &HashMap<ClientId, Client>,
+    client: ClientId,
+    name: &str,
+  ) -> Result<(), ClientError> {
+    if self.names == NamePolicy::Shared {
+      return Ok(());
+    }
+    match clients
+      .iter()
+      .find(|(id, info)| **id != client && same_name(&info.name, name))
+    {
+      Some((holder, _)) => Err(ClientError::NameTaken(*holder)),
+      None => Ok(()),
+    }
+  }
+
+  /// how long messages wait to be polled, or for their recipient to be known, one day by default
+  pub fn set_message_ttl(&mut self, ttl: Duration) {
+    self.ttl = ttl;
+  }
+
+  /// how long the tombstones of removed history entries are kept, `TOMBSTONE_GRACE` by default
+  pub fn set_tombstone_grace(&mut self, grace: Duration) {
+    self.tombstone_grace = grace;
+  }
+
+  /// how long a message can't be polled while the earlier messages of its sender are missing,
+  /// `REORDER_WAIT` by default
+  pub fn set_reorder_wait(&mut self, wait: Duration) {
+    self.reorder_wait = wait;
+  }
+
+  /// largest binary payload in `ClientMessage::Data`, in bytes, `MAX_DATA_SIZE` by default
+  pub fn set_max_data_size(&mut self, bytes: usize) {
+    self.max_data_size = bytes;
+  }
+
+  /// sequence numbers accepted below the highest one of each client, `SEQUENCE_WINDOW` by
+  /// default, between 1 (strictly increasing numbers) and 128
+  pub fn set_sequence_window(&mut self, window: u32) {
+    self.sequence_window = window.clamp(1, 128);
+  }
+
+  /// randomness of the client and room ids, the OS generator by default
+  pub fn set_rng(&mut self, rng: SharedRng) {
+    self.rng = rng;
+  }
+
+  /// time of the server, the system one by default, to be set before any client registers
+  pub fn set_clock(&mut self, clock: SharedClock) {
+    self.clock = clock;
+  }
+
+  /// how fast each client, and each address, can send messages, no limit by default
+  pub fn set_send_rate(&mut self, per_client: Option<SendRate>, per_address: Option<SendRate>) {
+    let buckets = self.send_buckets.get_mut();
+    buckets.clients = per_client.map(|r| TokenBuckets::new(r.rate, r.burst));
+    buckets.addresses = per_address.map(|r| TokenBuckets::new(r.rate, r.burst));
+  }
+
+  /// how fast each client can broadcast, on top of its send rate, no limit by default
+  pub fn set_broadcast_rate(&mut self, rate: Option<SendRate>) {
+    self.send_buckets.get_mut().broadcasts = rate.map(|r| TokenBuckets::new(r.rate, r.burst));
+  }
+
+  /// largest number of recipients of a single `MText` or `Rich`, `MAX_DESTINATIONS` by default
+  pub fn set_max_destinations(&mut self, max: usize) {
+    self.max_destinations = max;
+  }
+
+  /// messages kept for unknown recipients, for each sender and for the whole server,
+  /// `DELAYED_PER_SENDER` and `DELAYED_TOTAL` by default
+  pub fn set_delayed_quota(&mut self, per_sender: usize, total: usize) {
+    self.delayed_quota = (per_sender, total);
+  }
+
+  // spends a token of the sender and one of its address, only when both have one
+  async fn may_send(&self, src: ClientId) -> bool {
+    let ip = self.clients.read().await.get(&src).map(|c| c.src_ip);
+    let SendBuckets {
+      clients, addresses, ..
+    } = &mut *self.send_buckets.write().await;
+    let now = self.clock.now();
+    let client_ready = match clients {
+      Some(b) => b.ready(src, now),
+      None => true,
+    };
+    let address_ready = match (addresses.as_mut(), ip) {
+      (Some(b), Some(ip)) => b.ready(ip, now),
+      _ => true,
+    };
+    if !(client_ready && address_ready) {
+      return false;
+    }
+    if let Some(b) = clients {
+      b.take(&src);
+    }
+    if let (Some(b), Some(ip)) = (addresses, ip) {
+      b.take(&ip);
+    }
+    true
+  }
+
+  // a message from one of our clients to other servers
+  fn fully_qualified(&self, src: ClientId) -> MessageBuilder {
+    FullyQualifiedMessage::builder(src, self.id)
+  }
+
+  // a text accepted now
+  fn text(&self, content: String) -> Mail {
+    Mail::Text(self.id, self.clock.now_ms(), content)
+  }
+
+  fn expiry(&self) -> Instant {
+    self.clock.now() + self.ttl
+  }
+
+  // banned by its id, or by the address it registered from
+  async fn is_banned(&self, client: ClientId) -> bool {
+    let bans = self.banned.read().await;
+    bans.is_banned(&*self.clients.read().await, client)
+  }
+
+  // tells the local senders that their messages were not delivered, once per recipient, remote
+  // senders are not told
+  fn undelivered(
+    &self,
+    clients: &mut HashMap<ClientId, Client>,
+    senders: HashMap<ClientId, Vec<ClientId>>,
+    mail: Mail,
+    expires: Instant,
+  ) {
+    for (src, mut recipients) in senders {
+      let Some(sender) = clients.get_mut(&src) else {
+        continue;
+      };
+      recipients.sort();
+      recipients.dedup();
+      for recipient in recipients {
+        sender.deliver(
+          self.overflow,
+          expires,
+          Priority::System,
+          recipient,
+          mail.clone(),
+        );
+      }
+    }
+  }
+
+  /// changes what a local client is allowed to do, for instance to make it an admin
+  pub async fn set_tier(&self, client: ClientId, tier: Tier) -> Result<(), ClientError> {
+    let mut clients = self.clients.write().await;
+    let info = clients.get_mut(&client).ok_or(ClientError::UnknownClient)?;
+    info.tier = tier;
+    Ok(())
+  }
+
+  /// changes the mailbox size of a local client, the mails already above it stay until polled
+  pub async fn set_mailbox_size(&self, client: ClientId, size: usize) -> Result<(), ClientError> {
+    let mut clients = self.clients.write().await;
+    let info = clients.get_mut(&client).ok_or(ClientError::UnknownClient)?;
+    info.mailbox_size = size;
+    Ok(())
+  }
+
+  /// tells a local client something from `ClientId::SERVER`, through its mailbox
+  ///
+  /// Notices wait in the system lane, ignore block lists, and make room in a full mailbox.
+  pub async fn notify(&self, client: ClientId, text: String) -> Result<(), ClientError> {
+    let mut clients = self.clients.write().await;
+    let info = clients.get_mut(&client).ok_or(ClientError::UnknownClient)?;
+    info.deliver(
+      self.overflow,
+      self.expiry(),
+      Priority::System,
+      ClientId::SERVER,
+      self.text(text),
+    );
+    Ok(())
+  }
+
+  /// `notify` for every local client, returns how many were told
+  pub async fn notify_all(&self, text: String) -> usize {
+    let expires = self.expiry();
+    let mut clients = self.clients.write().await;
+    for info in clients.values_mut() {
+      info.deliver(
+        self.overflow,
+        expires,
+        Priority::System,
+        ClientId::SERVER,
+        self.text(text.clone()),
+      );
+    }
+    clients.len()
+  }
+
+  /// registration queue depth and counters
+  pub fn registration_stats(&self) -> AdmissionStats {
+    self.registrations.stats()
+  }
+
+  /// the latest abuse reports (see `REPORTS_SIZE`), oldest first
+  pub async fn reports(&self) -> Vec<AbuseReport> {
+    self.reports.read().await.iter().cloned().collect()
+  }
+
+  /// both spam checks, run in parallel
+  async fn spam_check(&self, src_ip: IpAddr, name: &str) -> Result<(), ClientError> {
+    // timeout for the spam checks
+    let spam_check_timeout = Duration::from_secs(2);
+
+    let (is_ip_spammer, is_user_spammer) = join!(
+      timeout(
+        &*self.clock,
+        spam_check_timeout,
+        self.checker.is_ip_spammer(&src_ip)
+      ),
+      timeout(
+        &*self.clock,
+        spam_check_timeout,
+        self.checker.is_user_spammer(name)
+      ),
+    );
+
+    match (is_ip_spammer, is_user_spammer) {
+      // Only proceed if neither the IP nor the user is flagged as a spammer
+      (Ok(false), Ok(false)) => Ok(()),
+      (Ok(true), _) | (_, Ok(true)) => Err(ClientError::SpamDetected),
+      _ => {
+        // a check did not answer in time, and the other one did not flag anything
+        log::warn!("Spam check timeout for {} ({})", name, src_ip);
+        Err(ClientError::InternalError)
+      }
+    }
+  }
+
+  // registers a member, with the invite it came with
+  async fn register_member(
+    &self,
+    src_ip: IpAddr,
+    name: String,
+    invite: Option<&[u8]>,
+  ) -> Result<ClientId, ClientError> {
+    if is_reserved_name(&name) {
+      return Err(ClientError::Forbidden);
+    }
+    if self.banned.read().await.addresses.contains(&src_ip) {
+      return Err(ClientError::Banned);
+    }
+    // before the spam checks, so that a closed server does not run them for strangers
+    let redeemed = match (&self.invites, invite) {
+      (None, _) => None,
+      (Some(invites), Some(invite)) => Some(invites.redeem(invite, self.clock.now_ms())?),
+      (Some(_), None) => return Err(ClientError::InvalidInvite),
+    };
+    let registered = async {
+      // wait for our turn, so that a registration storm can't flood the spam checker
+      let _permit = self.registrations.admit().await?;
+      self.spam_check(src_ip, &name).await?;
+      self.insert_client(src_ip, name, Tier::Member).await
+    }
+    .await;
+    if let (Err(_), Some(invites), Some(redeemed)) = (&registered, &self.invites, redeemed) {
+      invites.refund(&redeemed);
+    }
+    registered
+  }
+
+  async fn insert_client(
+    &self,
+    src_ip: IpAddr,
+    name: String,
+    tier: Tier,
+  ) -> Result<ClientId, ClientError> {
+    // checked under the same lock as the insertion, so that concurrent registrations can't both
+    // get the name
+    let mut clients = self.clients.write().await;
+    let client = ClientId(self.rng.uuid());
+    self.check_name(&clients, client, &name)?;
+    let mailbox_size = self.config.mailbox_size(&client);
+    let info = Client::new(src_ip, name, tier, mailbox_size, self.clock.clone());
+    clients.insert(client, info);
+    Ok(client)
+  }
+
+  // drops the room memberships and subscriptions of removed clients, returns the rooms they
+  // were in
+  async fn forget_clients(&self, removed: &[ClientId]) -> Vec<RoomId> {
+    let mut left = Vec::new();
+    {
+      let mut rooms = self.rooms.write().await;
+      for (id, room) in rooms.iter_mut() {
+        let before = room.members.len();
+        room.members.retain(|m| !removed.contains(m));
+        if room.members.len() != before {
+          left.push(*id);
+        }
+      }
+      rooms.retain(|_, room| room.has_members() || !room.remote.is_empty());
+    }
+    let mut subscribers = self.subscribers.write().await;
+    subscribers.retain(|watched, _| !removed.contains(watched));
+    for watchers in subscribers.values_mut() {
+      watchers.retain(|w| !removed.contains(w));
+    }
+    left
+  }
+
+  // the servers grouped by the nexthop toward them, the unreachable ones are left out
+  async fn by_nexthop(&self, servers: Vec<ServerId>) -> BTreeMap<NextHop, Vec<ServerId>> {
+    let router = self.router.read().await;
+    let mut groups: BTreeMap<NextHop, Vec<ServerId>> = BTreeMap::new();
+    for srv in servers {
+      match router.next_hop(srv) {
+        Some(nexthop) => groups.entry(nexthop).or_default().push(srv),
+        None => log::warn!("No route to {} for a room message", srv),
+      }
+    }
+    groups
+  }
+
+  // delivers a room message from a remote sender to the local members
+  async fn room_text(&self, room: RoomId, src: ClientId, content: String) {
+    let members = match self.rooms.read().await.get(&room) {
+      Some(r) => r.members.clone(),
+      None => return,
+    };
+    for dst in members.into_iter().filter(|m| *m != src) {
+      let mail = Mail::Room(room, content.clone());
+      let id = MessageId::default();
+      // like messages, the ones refused by a recipient are just dropped
+      if let ClientReply::Error(rr) = self
+        .client_message(src, dst, Priority::Normal, id, mail, None)
+        .await
+      {
+        log::warn!("Room message not delivered to {}: {}", dst, rr);
+      }
+    }
+  }
+
+  // a new announce of the members of `room` on `srv`, forwarded to every neighbour if it is newer
+  // than the one we have
+  async fn room_members(
+    &self,
+    room: RoomId,
+    srv: ServerId,
+    version: u64,
+    members: Vec<ClientId>,
+  ) -> ServerReply {
+    // our own announce, back to us
+    if srv == self.id {
+      return ServerReply::Outgoing(Vec::new());
+    }
+    {
+      let mut rooms = self.rooms.write().await;
+      let r = rooms.entry(room).or_default();
+      if r
+        .remote
+        .get(&srv)
+        .is_some_and(|(known, _)| *known >= version)
+      {
+        return ServerReply::Outgoing(Vec::new());
+      }
+      r.remote.insert(srv, (version, members.clone()));
+    }
+    let message = ServerMessage::RoomMembers {
+      room,
+      srv,
+      version,
+      members,
+    };
+    let forward = self
+      .router
+      .read()
+      .await
+      .neighbours()
+      .into_iter()
+      .map(|nexthop| Outgoing {
+        nexthop,
+        message: message.clone(),
+      })
+      .collect();
+    ServerReply::Forward(forward)
+  }
+
+  // every membership we know, for a new neighbour
+  async fn known_rooms(&self) -> Vec<ServerMessage> {
+    let mut known = Vec::new();
+    for (room, r) in self.rooms.read().await.iter() {
+      if r.version > 0 {
+        known.push(ServerMessage::RoomMembers {
+          room: *room,
+          srv: self.id,
+          version: r.version,
+          members: r.members.clone(),
+        });
+      }
+      for (srv, (version, members)) in &r.remote {
+        known.push(ServerMessage::RoomMembers {
+          room: *room,
+          srv: *srv,
+          version: *version,
+          members: members.clone(),
+        });
+      }
+    }
+    known
+  }
+
+  /// can this local client perform the action
+  async fn allowed(&self, client: ClientId, action: Action) -> bool {
+    let tier = match self.clients.read().await.get(&client) {
+      Some(info) => info.tier,
+      // unknown clients are caught elsewhere
+      None => return true,
+    };
+    self.authorizer.allows(tier, action)
+  }
+
+  // one reply for the change, and the presence is transferred to every neighbour
+  async fn set_presence(&self, client: ClientId, presence: Presence) -> Vec<ClientReply> {
+    match self.clients.write().await.get_mut(&client) {
+      Some(c) => c.presence = presence,
+      None => return vec![ClientReply::Error(ClientError::UnknownClient)],
+    }
+    self.notify_presence(client, presence).await;
+    let mut resp = vec![ClientReply::Delivered(None)];
+    for nexthop in self.router.read().await.neighbours() {
+      resp.push(ClientReply::Transfer(
+        nexthop,
+        ServerMessage::Presence { client, presence },
+        None,
+      ));
+    }
+    resp
+  }
+
+  // tells the local subscribers, notifications are dropped when mailboxes are full
+  async fn notify_presence(&self, client: ClientId, presence: Presence) {
+    let subscribers = self
+      .subscribers
+      .read()
+      .await
+      .get(&client)
+      .cloned()
+      .unwrap_or_default();
+    let mut clients = self.clients.write().await;
+    for subscriber in subscribers {
+      if let Some(s) = clients.get_mut(&subscriber) {
+        s.deliver(
+          self.overflow,
+          self.expiry(),
+          Priority::Normal,
+          client,
+          Mail::Presence(presence),
+        );
+      }
+    }
+  }
+
+  async fn subscribe(&self, subscriber: ClientId, client: ClientId) -> ClientReply {
+    let local = self.clients.read().await.get(&client).map(|c| c.presence);
+    let presence = match local {
+      Some(presence) => presence,
+      None => match self.remote_clients.read().await.get(&client) {
+        Some(remote) => remote.presence,
+        None => return ClientReply::Error(ClientError::UnknownClient),
+      },
+    };
+    let mut clients = self.clients.write().await;
+    let Some(s) = clients.get_mut(&subscriber) else {
+      return ClientReply::Error(ClientError::UnknownClient);
+    };
+    if !s.deliver(
+      self.overflow,
+      self.expiry(),
+      Priority::Normal,
+      client,
+      Mail::Presence(presence),
+    ) {
+      return ClientReply::Error(ClientError::BoxFull(subscriber));
+    }
+    let mut subscribers = self.subscribers.write().await;
+    let watchers = subscribers.entry(client).or_default();
+    if !watchers.contains(&subscriber) {
+      watchers.push(subscriber);
+    }
+    ClientReply::Delivered(None)
+  }
+
+  // defers the mails of a local client, or hands over the deferred ones
+  async fn set_do_not_disturb(&self, src: ClientId, on: bool) -> ClientReply {
+    match self.clients.write().await.get_mut(&src) {
+      Some(client) => {
+        client.set_dnd(on);
+        ClientReply::Delivered(None)
+      }
+      None => ClientReply::Error(ClientError::UnknownClient),
+    }
+  }
+
+  // sends a receipt to the author of a message from the reader history
+  async fn ack(&self, reader: ClientId, id: MessageId) -> ClientReply {
+    let author = {
+      let mut clients = self.clients.write().await;
+      let Some(client) = clients.get_mut(&reader) else {
+        return ClientReply::Error(ClientError::UnknownClient);
+      };
+      let author = client
+        .history
+        .iter()
+        .find(|k| k.entry.id == id)
+        .and_then(|k| match *k.entry.message.unthreaded() {
+          ClientPollReply::Message { src, .. }
+          | ClientPollReply::RichMessage { src, .. }
+          | ClientPollReply::RoomMessage { src, .. } => Some(src),
+          _ => None,
+        });
+      // the read marker of the conversation, for the other devices of the reader
+      if let Some(author) = author {
+        client.read_version += 1;
+        client
+          .read_markers
+          .insert(author, (id, client.read_version));
+      }
+      author
+    };
+    match author {
+      Some(author) => self.receipt(id, reader, author).await,
+      None => ClientReply::Error(ClientError::UnknownMessage(id)),
+    }
+  }
+
+  // reactions are kept once in the history entry of the reactor, and sent to the author
+  async fn react(&self, reactor: ClientId, id: MessageId, emoji: String) -> ClientReply {
+    let author = {
+      let mut clients = self.clients.write().await;
+      let Some(client) = clients.get_mut(&reactor) else {
+        return ClientReply::Error(ClientError::UnknownClient);
+      };
+      let kept = client
+        .history
+        .iter_mut()
+        .find(|k| k.entry.id == id && k.removed.is_none());
+      let Some(kept) = kept else {
+        return ClientReply::Error(ClientError::UnknownMessage(id));
+      };
+      let (ClientPollReply::Message { src, .. }
+      | ClientPollReply::RichMessage { src, .. }
+      | ClientPollReply::RoomMessage { src, .. }
+      | ClientPollReply::Data { src, .. }) = *kept.entry.message.unthreaded()
+      else {
+        return ClientReply::Error(ClientError::UnknownMessage(id));
+      };
+      if !kept.entry.reactions.contains(&emoji) {
+        kept.entry.reactions.push(emoji.clone());
+      }
+      src
+    };
+    self.reaction(id, reactor, author, emoji).await
+  }
+
+  // delivers a reaction to a local author, or transfers it to its server
+  async fn reaction(
+    &self,
+    id: MessageId,
+    reactor: ClientId,
+    author: ClientId,
+    emoji: String,
+  ) -> ClientReply {
+    let mail = Mail::Reaction(id, emoji.clone());
+    self
+      .notify_author(reactor, author, mail, |dstsrv| ServerMessage::Reaction {
+        id,
+        reactor,
+        dst: author,
+        dstsrv,
+        emoji,
+      })
+      .await
+  }
+
+  // delivers a receipt to a local author, or transfers it to its server
+  async fn receipt(&self, id: MessageId, reader: ClientId, author: ClientId) -> ClientReply {
+    self
+      .notify_author(reader, author, Mail::Receipt(id), |dstsrv| {
+        ServerMessage::Receipt {
+          id,
+          reader,
+          dst: author,
+          dstsrv,
+        }
+      })
+      .await
+  }
+
+  // delivers a notification from `src` to a local author, or transfers its remote version to
+  // the server of the author
+  async fn notify_author(
+    &self,
+    src: ClientId,
+    author: ClientId,
+    mail: Mail,
+    remote: impl FnOnce(ServerId) -> ServerMessage,
+  ) -> ClientReply {
+    if let Some(client) = self.clients.write().await.get_mut(&author) {
+      if !client.deliver(self.overflow, self.expiry(), Priority::Normal, src, mail) {
+        return ClientReply::Error(ClientError::BoxFull(author));
+      }
+      return ClientReply::Delivered(None);
+    }
+    let Some(dstsrv) = self
+      .remote_clients
+      .read()
+      .await
+      .get(&author)
+      .map(|r| r.srcsrv.server())
+    else {
+      return ClientReply::Error(ClientError::UnknownClient);
+    };
+    match self.router.read().await.next_hop(dstsrv) {
+      Some(nexthop) => ClientReply::Transfer(nexthop, remote(dstsrv), None),
+      None => ClientReply::Error(ClientError::UnknownClient),
+    }
+  }
+
+  // tells a local sender that its message reached `dst`, or forwards it to the server of the
+  // sender; senders that left are not told
+  async fn delivered(
+    &self,
+    id: MessageId,
+    src: ClientId,
+    srcsrv: ServerId,
+    dst: ClientId,
+  ) -> ServerReply {
+    if srcsrv == self.id {
+      if let Some(sender) = self.clients.write().await.get_mut(&src) {
+        sender.deliver(
+          self.overflow,
+          self.expiry(),
+          Priority::Normal,
+          dst,
+          Mail::Delivered(id),
+        );
+      }
+      return ServerReply::Outgoing(Vec::new());
+    }
+    match self.router.read().await.next_hop(srcsrv) {
+      Some(nexthop) => ServerReply::Forward(vec![Outgoing {
+        nexthop,
+        message: ServerMessage::Delivered {
+          id,
+          src,
+          srcsrv,
+          dst,
+        },
+      }]),
+      None => ServerReply::Error("Route for the sender not found".to_string()),
+    }
+  }
+
+  // edits or deletes a message to a local client; messages to remote clients are not kept here,
+  // and can't be found
+  async fn rewrite(
+    &self,
+    src: ClientId,
+    dest: ClientId,
+    id: MessageId,
+    content: Option<String>,
+  ) -> ClientReply {
+    let found = match self.clients.write().await.get_mut(&dest) {
+      Some(client) => client.rewrite(src, id, content),
+      None => false,
+    };
+    if found {
+      ClientReply::Delivered(Some(id))
+    } else {
+      ClientReply::Error(ClientError::UnknownMessage(id))
+    }
+  }
+
+  // delivers a handshake to a local mailbox, or transfers it to the server of its recipient;
+  // handshakes get no id, as they never reach the history, and are not stored for unknown clients
+  async fn key_agreement(
+    &self,
+    src: ClientId,
+    dst: ClientId,
+    priority: Priority,
+    payload: Vec<u8>,
+  ) -> ClientReply {
+    if dst.is_system() {
+      return ClientReply::Error(ClientError::Forbidden);
+    }
+    let mut clients = self.clients.write().await;
+    // from now on the conversation is end-to-end encrypted, on both sides
+    #[cfg(feature = "search")]
+    for (client, peer) in [(src, dst), (dst, src)] {
+      if let Some(client) = clients.get_mut(&client) {
+        client.encrypted.insert(peer);
+      }
+    }
+    if let Some(client) = clients.get_mut(&dst) {
+      if client.blocked.contains(&src) {
+        return ClientReply::Error(ClientError::Blocked(dst));
+      }
+      let mail = Mail::KeyAgreement(payload);
+      return if client.deliver(self.overflow, self.expiry(), priority, src, mail) {
+        ClientReply::Delivered(None)
+      } else {
+        ClientReply::Error(ClientError::BoxFull(dst))
+      };
+    }
+    let Some(dstsrv) = self
+      .remote_clients
+      .read()
+      .await
+      .get(&dst)
+      .map(|r| r.srcsrv.server())
+    else {
+      return ClientReply::Error(ClientError::UnknownClient);
+    };
+    drop(clients);
+    match self.router.read().await.next_hop(dstsrv) {
+      Some(nexthop) => ClientReply::Transfer(
+        nexthop,
+        ServerMessage::KeyAgreement {
+          src,
+          dst,
+          dstsrv,
+          payload,
+        },
+        None,
+      ),
+      None => ClientReply::Error(ClientError::UnknownClient),
+    }
+  }
+
+  // signals a local client, or transfers the event to its server; events to unknown clients are
+  // not stored, nor those the recipient silenced
+  async fn event(&self, src: ClientId, dst: ClientId, event: Event) -> ClientReply {
+    // only the server tells about mentions
+    if dst.is_system() || matches!(event, Event::Mentioned(_)) {
+      return ClientReply::Error(ClientError::Forbidden);
+    }
+    if let Some(client) = self.clients.write().await.get_mut(&dst) {
+      if client.blocked.contains(&src) {
+        return ClientReply::Error(ClientError::Blocked(dst));
+      }
+      // silenced events are dropped without telling the sender
+      if client.notifies(src) {
+        client.signal(src, event);
+      }
+      return ClientReply::Delivered(None);
+    }
+    let Some(dstsrv) = self
+      .remote_clients
+      .read()
+      .await
+      .get(&dst)
+      .map(|r| r.srcsrv.server())
+    else {
+      return ClientReply::Error(ClientError::UnknownClient);
+    };
+    match self.router.read().await.next_hop(dstsrv) {
+      Some(nexthop) => ClientReply::Transfer(
+        nexthop,
+        ServerMessage::Event {
+          src,
+          dst,
+          dstsrv,
+          event,
+        },
+        None,
+      ),
+      None => ClientReply::Error(ClientError::UnknownClient),
+    }
+  }
+
+  // tells a local recipient of a rich message that it mentions it, unless it silenced the sender
+  async fn mention(&self, src: ClientId, dst: ClientId, id: MessageId) {
+    if let Some(client) = self.clients.write().await.get_mut(&dst) {
+      if client.notifies(src) {
+        client.signal(src, Event::Mentioned(id));
+      }
+    }
+  }
+
+  // to every other local and remote client, from `ClientId::ADMIN`, the summary first and then
+  // the transfers
+  async fn broadcast(&self, src: ClientId, content: String) -> Vec<ClientReply> {
+    if !self.clients.read().await.contains_key(&src) {
+      return vec![ClientReply::Error(ClientError::UnknownClient)];
+    }
+    if !self.allowed(src, Action::Broadcast).await {
+      return vec![ClientReply::Error(ClientError::Forbidden)];
+    }
+    if let Some(b) = &mut self.send_buckets.write().await.broadcasts {
+      if !b.acquire(src, self.clock.now()) {
+        return vec![ClientReply::Error(ClientError::RateLimited)];
+      }
+    }
+    let mut dsts: Vec<ClientId> = self.clients.read().await.keys().copied().collect();
+    dsts.extend(self.remote_clients.read().await.keys());
+    let mut delivered = 0;
+    let mut failed = Vec::new();
+    let mut transfers = Vec::new();
+    for dst in dsts.into_iter().filter(|dst| *dst != src) {
+      match self
+        .client_message(
+          ClientId::ADMIN,
+          dst,
+          Priority::System,
+          MessageId::default(),
+          self.text(content.clone()),
+          None,
+        )
+        .await
+      {
+        ClientReply::Error(rr) => failed.push((dst, rr)),
+        reply => {
+          delivered += 1;
+          if let ClientReply::Transfer(..) = reply {
+            transfers.push(reply);
+          }
+        }
+      }
+    }
+    let mut resp = vec![ClientReply::Broadcast { delivered, failed }];
+    resp.extend(transfers);
+    resp
+  }
+
+  async fn client_message(
+    &self,
+    src: ClientId,
+    dest: ClientId,
+    priority: Priority,
+    id: MessageId,
+    content: Mail,
+    in_reply_to: Option<MessageId>,
+  ) -> ClientReply {
+    // nobody reads the mailboxes of system identities
+    if dest.is_system() {
+      return ClientReply::Error(ClientError::Forbidden);
+    }
+    let mut clients = self.clients.write().await;
+    // messages of local senders are sequenced per recipient, the number is used once the message
+    // is accepted, so that refused messages leave no gap
+    let seq = clients
+      .get(&src)
+      .map_or(0, |sender| sender.sent.get(&dest).copied().unwrap_or(0) + 1);
+    let sent = |clients: &mut HashMap<ClientId, Client>| {
+      if let Some(sender) = clients.get_mut(&src) {
+        sender.sent.insert(dest, seq);
+      }
+    };
+    match clients.get_mut(&dest) {
+      // if the client is local
+      Some(client) => {
+        if client.blocked.contains(&src) {
+          return ClientReply::Error(ClientError::Blocked(dest));
+        }
+        let entry = Waiting {
+          src,
+          id,
+          mail: content,
+          expires: self.expiry(),
+          seq,
+          // local messages can't overtake each other, unless they have a higher priority
+          held_until: self.clock.now(),
+          in_reply_to,
+        };
+        if client.deliver_entry(self.overflow, priority, entry) {
+          sent(&mut clients);
+          ClientReply::Delivered(Some(id))
+        } else {
+          // if the mailbox is full (according to the overflow policy), BoxFull should be returned
+          ClientReply::Error(ClientError::BoxFull(dest))
+        }
+      }
+      None => {
+        // federated messages are text, binary payloads are only for local clients
+        if let Mail::Data(..) = content {
+          return ClientReply::Error(ClientError::Forbidden);
+        }
+        // when we accepted it
+        let timestamp = match content {
+          Mail::Text(_, timestamp, _) => timestamp,
+          _ => self.clock.now_ms(),
+        };
+        let (content, content_type, mentions) = content.into_parts();
+        let remote_client = self.remote_clients.write().await;
+        match remote_client.get(&dest) {
+          // if the client is remote, Transfer should be returned
+          Some(client_remote_info) => {
+            let srv_dst = client_remote_info.srcsrv.server();
+            match self.router.read().await.next_hop(srv_dst) {
+              Some(nexthop) => {
+                let built = self
+                  .fully_qualified(src)
+                  .to(dest, srv_dst)
+                  .content(content)
+                  .with_content_type(content_type)
+                  .mentions(mentions)
+                  .sequenced(seq)
+                  .in_reply_to(in_reply_to)
+                  .timestamp(timestamp)
+                  .id(id)
+                  .build();
+                match built {
+                  Ok(message) => {
+                    sent(&mut clients);
+                    ClientReply::Transfer(nexthop, ServerMessage::Message(message), Some(id))
+                  }
+                  Err(_) => ClientReply::Error(ClientError::InternalError),
+                }
+              }
+              None => ClientReply::Error(ClientError::UnknownClient),
+            }
+          }
+          // if the client is unknown, the message should be stored and Delayed must be returned (federation)
+          None => {
+            let message = Message {
+              src,
+              content,
+              content_type,
+              mentions,
+              expires: self.expiry(),
+              seq,
+              in_reply_to,
+              timestamp,
+              id,
+            };
+            match self
+              .stored_messages
+              .write()
+              .await
+              .push(dest, message, self.delayed_quota)
+            {
+              Ok(()) => {
+                sent(&mut clients);
+                ClientReply::Delayed(id)
+              }
+              Err(rr) => ClientReply::Error(rr),
+            }
+          }
+        }
+      }
+    }
+  }
+
+  // delivers to every recipient or to none: they must all be local, not have blocked the sender,
+  // and have room for their copies, checked and filled under the same lock
+  async fn atomic_message(
+    &self,
+    src: ClientId,
+    dest: &[ClientId],
+    priority: Priority,
+    id: MessageId,
+    content: Mail,
+    in_reply_to: Option<MessageId>,
+  ) -> ClientReply {
+    let mut clients = self.clients.write().await;
+    let mut copies: HashMap<ClientId, usize> = HashMap::new();
+    for dst in dest {
+      *copies.entry(*dst).or_default() += 1;
+    }
+    for dst in dest {
+      if dst.is_system() {
+        return ClientReply::Error(ClientError::Forbidden);
+      }
+      let count = copies[dst];
+      match clients.get(dst) {
+        // remote mailboxes can't be checked, and unknown recipients would only be delayed
+        None => return ClientReply::Error(ClientError::UnknownClient),
+        Some(client) if client.blocked.contains(&src) => {
+          return ClientReply::Error(ClientError::Blocked(*dst))
+        }
+        Some(client) if !client.has_room(self.overflow, count, count * content.size()) => {
+          return ClientReply::Error(ClientError::BoxFull(*dst))
+        }
+        Some(_) => (),
+      }
+    }
+    for dst in dest {
+      let seq = clients
+        .get(&src)
+        .map_or(0, |sender| sender.sent.get(dst).copied().unwrap_or(0) + 1);
+      let entry = Waiting {
+        src,
+        id,
+        mail: content.clone(),
+        expires: self.expiry(),
+        seq,
+        held_until: self.clock.now(),
+        in_reply_to,
+      };
+      let client = clients.get_mut(dst).expect("checked above");
+      let delivered = client.deliver_entry(self.overflow, priority, entry);
+      debug_assert!(delivered, "room was checked above");
+      if let Some(sender) = clients.get_mut(&src) {
+        sender.sent.insert(*dst, seq);
+      }
+    }
+    ClientReply::Delivered(Some(id))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+
+  use crate::clock::{Clock, ManualClock};
+  use crate::rng::SeededRng;
+  use crate::testing::{mailbox_size, test_message_server, TestChecker, Unstamped};
+
+  use super::*;
+
+  #[test]
+  fn tester() {
+    test_message_server::<Server<TestChecker>>();
+  }
+
+  #[test]
+  fn overflow() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      for (policy, first) in [
+        (OverflowPolicy::RejectNew, "0".to_string()),
+        (OverflowPolicy::DropOldest, "1".to_string()),
+      ] {
+        let mut server: Server<TestChecker> = MessageServer::new(
+          TestChecker::default(),
+          ServerId::default(),
+          ServerConfig::default(),
+        );
+        server.set_overflow_policy(policy);
+        let c = server.register_local_client(ip, "c".into()).await.unwrap();
+        for i in 0..=mailbox_size() {
+          let r = server
+            .handle_client_message(
+              c,
+              ClientMessage::Text {
+                dest: c,
+                content: i.to_string(),
+              },
+            )
+            .await;
+          if i == mailbox_size() && policy == OverflowPolicy::RejectNew {
+            assert_eq!(r, [ClientReply::Error(ClientError::BoxFull(c))]);
+          } else {
+            assert!(
+              matches!(r[..], [ClientReply::Delivered(Some(_))]),
+              "{:?}",
+              r
+            );
+          }
+        }
+        let r = server.client_poll(c).await.unstamped();
+        assert_eq!(
+          r,
+          ClientPollReply::Message {
+            src: c,
+            srcsrv: server.id,
+            content: first,
+            timestamp: 0,
+          }
+        );
+      }
+
+      let one = Mail::Text(ServerId::default(), 0, "x".repeat(100)).size();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_overflow_policy(OverflowPolicy::MemoryCap(2 * one));
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+      let msg = ClientMessage::Text {
+        dest: c,
+        content: "x".repeat(100),
+      };
+      for _ in 0..2 {
+        let r = server.handle_client_message(c, msg.clone()).await;
+        assert!(
+          matches!(r[..], [ClientReply::Delivered(Some(_))]),
+          "{:?}",
+          r
+        );
+      }
+      assert_eq!(
+        server.handle_client_message(c, msg.clone()).await,
+        [ClientReply::Error(ClientError::BoxFull(c))]
+      );
+      // polling makes room
+      server.client_poll(c).await;
+      let r = server.handle_client_message(c, msg).await;
+      assert!(
+        matches!(r[..], [ClientReply::Delivered(Some(_))]),
+        "{:?}",
+        r
+      );
+
+      assert_eq!(
+        "cap:10".parse::<OverflowPolicy>().unwrap(),
+        OverflowPolicy::MemoryCap(10)
+      );
+      assert_eq!(
+        "drop-oldest".parse::<OverflowPolicy>().unwrap(),
+        OverflowPolicy::DropOldest
+      );
+      assert!("cap".parse::<OverflowPolicy>().is_err());
+    });
+  }
+
+  #[test]
+  fn sequence_window() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_sequence_window(1);
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+      let seq = |seqid| Sequence {
+        seqid,
+        src: c,
+        content: (),
+      };
+      assert!(server.handle_sequenced_message(seq(2)).await.is_ok());
+      assert_eq!(
+        server.handle_sequenced_message(seq(1)).await,
+        Err(ClientError::SequenceReuse {
+          expected: 3,
+          got: 1
+        })
+      );
+      assert_eq!(
+        server.handle_sequenced_message(seq(2)).await,
+        Err(ClientError::Replayed(2))
+      );
+
+      // a jump beyond the bitmap forgets everything below
+      server.set_sequence_window(128);
+      assert!(server.handle_sequenced_message(seq(1000)).await.is_ok());
+      assert!(server
+        .handle_sequenced_message(seq(1000 - 127))
+        .await
+        .is_ok());
+      assert!(server
+        .handle_sequenced_message(seq(1000 - 128))
+        .await
+        .is_err());
+
+      // the last sequence number does not overflow what is expected next
+      assert!(server
+        .handle_sequenced_message(seq(u128::MAX))
+        .await
+        .is_ok());
+      assert_eq!(
+        server.handle_sequenced_message(seq(1)).await,
+        Err(ClientError::SequenceReuse {
+          expected: u128::MAX,
+          got: 1
+        })
+      );
+    });
+  }
+
+  // a spam checker that never answers
+  struct Stuck;
+
+  #[async_trait]
+  impl SpamChecker for Stuck {
+    async fn is_user_spammer(&self, _name: &str) -> bool {
+      std::future::pending().await
+    }
+
+    async fn is_ip_spammer(&self, _ip: &IpAddr) -> bool {
+      std::future::pending().await
+    }
+  }
+
+  #[test]
+  fn manual_clock() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let clock = ManualClock::new(1_000);
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_clock(Arc::new(clock.clone()));
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+
+      // messages are stamped by the clock of the server
+      clock.advance(Duration::from_millis(500));
+      let text = ClientMessage::Text {
+        dest: c,
+        content: "hi".into(),
+      };
+      server.handle_client_message(c, text).await;
+      let polled = ClientPollReply::Message {
+        src: c,
+        srcsrv: server.id,
+        content: "hi".into(),
+        timestamp: 1_500,
+      };
+      assert_eq!(server.client_poll(c).await, polled);
+
+      // waiting polls give up once the clock went past their deadline, whatever the real time
+      let wait = Duration::from_secs(3600);
+      let (evicted, _) = server.evict_idle(clock.now(), wait).await;
+      assert!(evicted.is_empty());
+      let (polled, ()) = join!(server.client_poll_wait(c, wait), async {
+        clock.advance(wait);
+      });
+      assert_eq!(polled, ClientPollReply::Nothing);
+
+      // the client has been idle for as long by the clock
+      let (evicted, _) = server.evict_idle(clock.now(), wait).await;
+      assert_eq!(evicted, [c]);
+
+      // the spam checks time out by the clock too
+      let mut server: Server<Stuck> =
+        MessageServer::new(Stuck, ServerId::default(), ServerConfig::default());
+      server.set_clock(Arc::new(clock.clone()));
+      let (registered, ()) = join!(server.register_local_client(ip, "s".into()), async {
+        clock.advance(Duration::from_secs(2));
+      });
+      assert_eq!(registered, Err(ClientError::InternalError));
+    });
+  }
+
+  #[test]
+  fn reorder_wait() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_reorder_wait(Duration::from_millis(20));
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+      let (remote, srv) = (ClientId::default(), ServerId::default());
+      let forwarded = |content: &str, seq| {
+        let message = FullyQualifiedMessage::builder(remote, srv)
+          .to(c, server.id)
+          .content(content.into())
+          .sequenced(seq)
+          .build()
+          .unwrap();
+        ServerMessage::Message(message)
+      };
+      let polled = |content: &str| ClientPollReply::Message {
+        src: remote,
+        srcsrv: srv,
+        content: content.into(),
+        timestamp: 0,
+      };
+
+      // the first message was lost on the way, the second one waits for it for a while
+      server.handle_server_message(forwarded("2", 2)).await;
+      assert_eq!(server.client_poll(c).await, ClientPollReply::Nothing);
+      let start = Instant::now();
+      assert_eq!(
+        server.client_poll_wait(c, Duration::from_secs(10)).await,
+        polled("2")
+      );
+      assert!(start.elapsed() < Duration::from_secs(10));
+
+      // the late message is not held, nor the ones after it
+      server.handle_server_message(forwarded("1", 1)).await;
+      server.handle_server_message(forwarded("3", 3)).await;
+      assert_eq!(server.client_poll_n(c, 3).await, [polled("1"), polled("3")]);
+    });
+  }
+
+  #[test]
+  fn fan_out_limits() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_max_destinations(2);
+      server.set_broadcast_rate(Some(SendRate {
+        rate: 0.01,
+        burst: 1,
+      }));
+      let admin = server
+        .register_local_client(ip, "operator".into())
+        .await
+        .unwrap();
+      server.set_tier(admin, Tier::Admin).await.unwrap();
+      let rich = |count| ClientMessage::Rich {
+        dest: vec![admin; count],
+        content: RichContent {
+          text: "hi".into(),
+          mentions: Vec::new(),
+          content_type: ContentType::Plain,
+        },
+      };
+      assert_eq!(server.handle_client_message(admin, rich(2)).await.len(), 2);
+      assert_eq!(
+        server.handle_client_message(admin, rich(3)).await,
+        [ClientReply::Error(ClientError::TooManyDestinations(2))]
+      );
+
+      let broadcast = || ClientMessage::Broadcast {
+        content: "notice".into(),
+      };
+      let r = server.handle_client_message(admin, broadcast()).await;
+      assert!(matches!(r[..], [ClientReply::Broadcast { .. }]), "{:?}", r);
+      assert_eq!(
+        server.handle_client_message(admin, broadcast()).await,
+        [ClientReply::Error(ClientError::RateLimited)]
+      );
+    });
+  }
+
+  #[test]
+  fn seeded_ids() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let ids = || async {
+        let mut server: Server<TestChecker> = MessageServer::new(
+          TestChecker::default(),
+          ServerId::default(),
+          ServerConfig::default(),
+        );
+        server.set_rng(std::sync::Arc::new(SeededRng::new(1)));
+        let client = server.register_local_client(ip, "c".into()).await.unwrap();
+        let room = server.create_room(client, "r".into()).await.unwrap();
+        (client, room)
+      };
+      let (client, room) = ids().await;
+      assert_eq!(ids().await, (client, room));
+      assert_ne!(client.0, room.0);
+    });
+  }
+
+  #[test]
+  fn send_rate() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let rate = |burst| SendRate { rate: 0.01, burst };
+      server.set_send_rate(Some(rate(2)), Some(rate(3)));
+      let c1 = server.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = server.register_local_client(ip, "c2".into()).await.unwrap();
+      let text = |dest| ClientMessage::Text {
+        dest,
+        content: "hi".into(),
+      };
+      for _ in 0..2 {
+        let r = server.handle_client_message(c1, text(c2)).await;
+        assert!(
+          matches!(r[..], [ClientReply::Delivered(Some(_))]),
+          "{:?}",
+          r
+        );
+      }
+      assert_eq!(
+        server.handle_client_message(c1, text(c2)).await,
+        [ClientReply::Error(ClientError::RateLimited)]
+      );
+      // one error per recipient
+      let mtext = ClientMessage::MText {
+        dest: vec![c1, c2],
+        content: "hi".into(),
+      };
+      let limited = ClientReply::Error(ClientError::RateLimited);
+      assert_eq!(
+        server.handle_client_message(c1, mtext).await,
+        [ClientReply::Multi(vec![
+          (c1, limited.clone()),
+          (c2, limited)
+        ])]
+      );
+      // reading is not limited
+      let m = server.client_poll(c2).await;
+      assert!(matches!(m, ClientPollReply::Message { .. }), "{:?}", m);
+      let r = server
+        .handle_client_message(c2, ClientMessage::Ack(MessageId::default()))
+        .await;
+      assert_ne!(r, [ClientReply::Error(ClientError::RateLimited)]);
+
+      // c2 has its own tokens, but the address spent them
+      let r = server.handle_client_message(c2, text(c1)).await;
+      assert!(
+        matches!(r[..], [ClientReply::Delivered(Some(_))]),
+        "{:?}",
+        r
+      );
+      assert_eq!(
+        server.handle_client_message(c2, text(c1)).await,
+        [ClientReply::Error(ClientError::RateLimited)]
+      );
+    });
+  }
+
+  #[test]
+  fn announce() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let ca = a.register_local_client(ip, "a".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+
+      let announce = a.make_announce().await;
+      assert_eq!(
+        announce,
+        ServerMessage::Announce {
+          route: vec![a.id],
+          clients: HashMap::from([(ca, "a".to_string())]),
+        }
+      );
+      assert_eq!(
+        b.handle_server_message(announce).await,
+        ServerReply::Outgoing(Vec::new())
+      );
+      let r = b
+        .handle_client_message(
+          cb,
+          ClientMessage::Text {
+            dest: ca,
+            content: "hi".into(),
+          },
+        )
+        .await;
+      assert!(
+        matches!(&r[..], [ClientReply::Transfer(nexthop, _, Some(_))] if nexthop.server() == a.id)
+      );
+    });
+  }
+
+  #[test]
+  fn broadcast() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let admin = a.register_local_client(ip, "alice".into()).await.unwrap();
+      let c1 = a.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = a.register_local_client(ip, "c2".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+      // broadcasts come from the admin identity, not from whoever sent them
+      a.set_blocked(c2, vec![ClientId::ADMIN]).await;
+
+      let msg = ClientMessage::Broadcast {
+        content: "maintenance".into(),
+      };
+      assert_eq!(
+        a.handle_client_message(admin, msg.clone()).await,
+        [ClientReply::Error(ClientError::Forbidden)]
+      );
+      a.set_tier(admin, Tier::Admin).await.unwrap();
+      let r = a.handle_client_message(admin, msg).await;
+      assert_eq!(
+        r[0],
+        ClientReply::Broadcast {
+          delivered: 2,
+          failed: vec![(c2, ClientError::Blocked(c2))],
+        }
+      );
+      assert!(
+        matches!(&r[1..], [ClientReply::Transfer(nexthop, ServerMessage::Message(m), _)]
+        if nexthop.server() == b.id && m.src == ClientId::ADMIN && m.dsts == [(cb, b.id)])
+      );
+      assert_eq!(
+        a.client_poll(c1).await.unstamped(),
+        ClientPollReply::Message {
+          src: ClientId::ADMIN,
+          srcsrv: a.id,
+          content: "maintenance".into(),
+          timestamp: 0,
+        }
+      );
+      assert_eq!(a.client_poll(admin).await, ClientPollReply::Nothing);
+    });
+  }
+
+  #[test]
+  fn delivery_confirmation() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let ca = a.register_local_client(ip, "a".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+      b.handle_server_message(a.make_announce().await).await;
+
+      let msg = ClientMessage::Text {
+        dest: cb,
+        content: "hi".into(),
+      };
+      let r = a.handle_client_message(ca, msg).await;
+      let [ClientReply::Transfer(_, message, Some(id))] = &r[..] else {
+        panic!("Expected a transfer, got {:?}", r);
+      };
+      let ServerReply::Forward(confirmation) = b.handle_server_message(message.clone()).await
+      else {
+        panic!("Expected a confirmation");
+      };
+      let [Outgoing { nexthop, message }] = &confirmation[..] else {
+        panic!("Expected a single confirmation, got {:?}", confirmation);
+      };
+      assert_eq!(nexthop.server(), a.id);
+      assert_eq!(
+        a.handle_server_message(message.clone()).await,
+        ServerReply::Outgoing(Vec::new())
+      );
+      assert_eq!(
+        a.client_poll(ca).await,
+        ClientPollReply::Delivered { id: *id, dst: cb }
+      );
+      // not in the history
+      assert!(a
+        .client_history(ca, 10, None, false)
+        .await
+        .unwrap()
+        .is_empty());
+    });
+  }
+
+  #[test]
+  fn rename() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let c = a.register_local_client(ip, "c".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+      b.handle_server_message(a.make_announce().await).await;
+
+      assert_eq!(
+        a.rename_client(c, "Admin".into()).await,
+        [ClientReply::Error(ClientError::Forbidden)]
+      );
+      let r = a.rename_client(c, "carol".into()).await;
+      assert_eq!(r[0], ClientReply::Delivered(None));
+      let [ClientReply::Transfer(nexthop, announce, None)] = &r[1..] else {
+        panic!("Expected an announce to b, got {:?}", r);
+      };
+      assert_eq!(nexthop.server(), b.id);
+      assert_eq!(a.list_users().await.get(&c), Some(&"carol".to_string()));
+      b.handle_server_message(announce.clone()).await;
+      assert_eq!(b.remote_clients.read().await[&c].name, "carol");
+      assert_eq!(
+        a.rename_client(ClientId::default(), "dave".into()).await,
+        [ClientReply::Error(ClientError::UnknownClient)]
+      );
+    });
+  }
+
+  #[test]
+  fn delayed_quota() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      a.set_delayed_quota(2, 3);
+      let c1 = a.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = a.register_local_client(ip, "c2".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      let text = |dest| ClientMessage::Text {
+        dest,
+        content: "hi".into(),
+      };
+      let delayed = |r: Vec<ClientReply>| matches!(r[..], [ClientReply::Delayed(_)]);
+
+      assert!(delayed(a.handle_client_message(c1, text(cb)).await));
+      assert!(delayed(a.handle_client_message(c1, text(cb)).await));
+      assert_eq!(
+        a.handle_client_message(c1, text(cb)).await,
+        [ClientReply::Error(ClientError::DelayedQuota(2))]
+      );
+      assert!(delayed(a.handle_client_message(c2, text(cb)).await));
+      assert_eq!(
+        a.handle_client_message(c2, text(cb)).await,
+        [ClientReply::Error(ClientError::ServerBusy)]
+      );
+
+      // flushed messages no longer count
+      let ServerReply::Outgoing(flushed) = a.handle_server_message(b.make_announce().await).await
+      else {
+        panic!("Expected the delayed messages");
+      };
+      assert_eq!(flushed.len(), 3);
+      let stored = a.stored_messages.read().await;
+      assert_eq!((stored.total, stored.senders.len()), (0, 0));
+    });
+  }
+
+  #[test]
+  fn federated_key_agreement() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let ca = a.register_local_client(ip, "a".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+
+      let msg = ClientMessage::KeyAgreement {
+        dest: cb,
+        payload: vec![1, 2],
+      };
+      let r = a.handle_client_message(ca, msg.clone()).await;
+      let [ClientReply::Transfer(nexthop, transfer, None)] = &r[..] else {
+        panic!("Expected a transfer, got {:?}", r);
+      };
+      assert_eq!(nexthop.server(), b.id);
+      assert!(matches!(
+        b.handle_server_message(transfer.clone()).await,
+        ServerReply::Outgoing(o) if o.is_empty()
+      ));
+      assert_eq!(
+        b.client_poll(cb).await,
+        ClientPollReply::KeyAgreement {
+          src: ca,
+          payload: vec![1, 2],
+        }
+      );
+      assert!(b
+        .client_history(cb, 10, None, false)
+        .await
+        .unwrap()
+        .is_empty());
+
+      // blocked handshakes are dropped, like messages
+      b.set_blocked(cb, vec![ca]).await;
+      assert!(matches!(
+        b.handle_server_message(transfer.clone()).await,
+        ServerReply::Outgoing(o) if o.is_empty()
+      ));
+      assert_eq!(b.client_poll(cb).await, ClientPollReply::Nothing);
+    });
+  }
+
+  #[cfg(feature = "search")]
+  #[test]
+  fn search_history() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let c1 = a.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = a.register_local_client(ip, "c2".into()).await.unwrap();
+      let c3 = a.register_local_client(ip, "c3".into()).await.unwrap();
+      let text = |dest, content: &str| ClientMessage::Text {
+        dest,
+        content: content.into(),
+      };
+      let search = |terms: &[&str]| SearchQuery {
+        terms: terms.iter().map(|t| t.to_string()).collect(),
+        limit: 10,
+        ..SearchQuery::default()
+      };
+      let found = |page: SearchPage| -> Vec<ClientPollReply> {
+        page
+          .hits
+          .into_iter()
+          .map(|h| h.entry.message.unstamped())
+          .collect()
+      };
+
+      a.handle_client_message(c2, text(c1, "see you at noon"))
+        .await;
+      a.handle_client_message(c3, text(c1, "Noon is fine")).await;
+      a.handle_client_message(
+        c2,
+        ClientMessage::Data {
+          dest: c1,
+          mime: "text/plain".into(),
+          bytes: b"noon".to_vec(),
+        },
+      )
+      .await;
+      a.client_poll_n(c1, 10).await;
+      let page = a.search_history(c1, &search(&["noon"])).await.unwrap();
+      assert_eq!(
+        found(page),
+        [
+          ClientPollReply::Message {
+            src: c3,
+            srcsrv: a.id,
+            content: "Noon is fine".into(),
+            timestamp: 0,
+          },
+          ClientPollReply::Message {
+            src: c2,
+            srcsrv: a.id,
+            content: "see you at noon".into(),
+            timestamp: 0,
+          },
+        ]
+      );
+      let from_c2 = SearchQuery {
+        participant: Some(c2),
+        ..search(&["noon"])
+      };
+      assert_eq!(
+        found(a.search_history(c1, &from_c2).await.unwrap()).len(),
+        1
+      );
+      // other histories are not searched
+      assert!(found(a.search_history(c2, &search(&["noon"])).await.unwrap()).is_empty());
+      assert_eq!(
+        a.search_history(ClientId::default(), &search(&[])).await,
+        Err(ClientError::UnknownClient)
+      );
+
+      // encrypted conversations are not indexed anymore
+      let handshake = ClientMessage::KeyAgreement {
+        dest: c2,
+        payload: vec![1],
+      };
+      a.handle_client_message(c1, handshake).await;
+      a.handle_client_message(c2, text(c1, "noon again")).await;
+      a.client_poll_n(c1, 10).await;
+      assert_eq!(
+        a.client_history(c1, 10, None, false).await.unwrap().len(),
+        4
+      );
+      assert_eq!(
+        found(a.search_history(c1, &search(&["noon"])).await.unwrap()).len(),
+        2
+      );
+    });
+  }
+
+  #[cfg(not(feature = "search"))]
+  #[test]
+  fn search_disabled() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let c1 = a.register_local_client(ip, "c1".into()).await.unwrap();
+      assert_eq!(
+        a.search_history(c1, &SearchQuery::default()).await,
+        Err(ClientError::Forbidden)
+      );
+    });
+  }
+
+  #[test]
+  fn shared_names() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      a.set_name_policy(NamePolicy::Shared);
+      let a1 = a.register_local_client(ip, "alice".into()).await.unwrap();
+      let a2 = a.register_guest(ip, "Alice".into()).await.unwrap();
+      let bob = a.register_local_client(ip, "bob".into()).await.unwrap();
+      let b1 = b.register_local_client(ip, "ALICE".into()).await.unwrap();
+      let carol = b.register_local_client(ip, "carol".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+
+      let entries: HashMap<ClientId, UserEntry> = a
+        .list_user_entries()
+        .await
+        .into_iter()
+        .map(|entry| (entry.id, entry))
+        .collect();
+      assert_eq!(entries.len(), 5);
+      for alice in [a1, a2, b1] {
+        assert_eq!(entries[&alice].tag, Some(alice.short()));
+      }
+      assert_eq!(entries[&bob].display_name(), "bob");
+      assert_eq!(entries[&b1].server, b.id);
+      assert_eq!(entries[&carol].server, b.id);
+      assert_eq!(entries[&a2].server, a.id);
+
+      // ambiguous names are not found
+      assert_eq!(a.lookup_user("alice").await, None);
+      assert_eq!(a.lookup_user("carol").await, Some(carol));
+      assert_eq!(b.lookup_user("alice").await, Some(b1));
+    });
+  }
+
+  #[test]
+  fn notices() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let c1 = server.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = server.register_local_client(ip, "c2".into()).await.unwrap();
+      server.set_blocked(c2, vec![ClientId::SERVER]).await;
+      for n in 0..mailbox_size() {
+        let msg = ClientMessage::Text {
+          dest: c2,
+          content: n.to_string(),
+        };
+        server.handle_client_message(c1, msg).await;
+      }
+
+      // the mailbox is full, and the server is blocked, the notice still gets through
+      server.notify(c2, "restarting".into()).await.unwrap();
+      assert_eq!(server.notify_all("soon".into()).await, 2);
+      let notice = |content: &str| ClientPollReply::Message {
+        src: ClientId::SERVER,
+        srcsrv: server.id,
+        content: content.into(),
+        timestamp: 0,
+      };
+      assert_eq!(
+        server.client_poll_n(c2, 3).await.unstamped(),
+        [
+          notice("restarting"),
+          notice("soon"),
+          ClientPollReply::Message {
+            src: c1,
+            srcsrv: server.id,
+            content: "2".into(),
+            timestamp: 0,
+          }
+        ]
+      );
+      assert_eq!(server.client_poll(c1).await.unstamped(), notice("soon"));
+      assert_eq!(
+        server.notify(ClientId::default(), "lost".into()).await,
+        Err(ClientError::UnknownClient)
+      );
+    });
+  }
+
+  #[test]
+  fn trusted_registration() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let admin = a.register_local_client(ip, "alice".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+      a.set_tier(admin, Tier::Admin).await.unwrap();
+
+      let bridge = ClientId::from(42);
+      a.register_trusted_client(admin, bridge, "bridge".into(), ip)
+        .await
+        .unwrap();
+      assert_eq!(
+        a.list_users().await.get(&bridge),
+        Some(&"bridge".to_string())
+      );
+      let r = a
+        .handle_client_message(
+          admin,
+          ClientMessage::Text {
+            dest: bridge,
+            content: "hi".into(),
+          },
+        )
+        .await;
+      assert!(
+        matches!(r[..], [ClientReply::Delivered(Some(_))]),
+        "{:?}",
+        r
+      );
+      for id in [bridge, cb, admin, ClientId::ADMIN] {
+        assert_eq!(
+          a.register_trusted_client(admin, id, "again".into(), ip)
+            .await,
+          Err(ClientError::AlreadyRegistered(id))
+        );
+      }
+      assert_eq!(
+        a.register_trusted_client(admin, ClientId::from(43), "Server".into(), ip)
+          .await,
+        Err(ClientError::Forbidden)
+      );
+    });
+  }
+
+  #[test]
+  fn mailbox_sizes() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let bridge = ClientId::from(42);
+      let config = ServerConfig {
+        mailbox_size: 3,
+        mailbox_sizes: HashMap::from([(bridge, 5)]),
+        ..ServerConfig::default()
+      };
+      let server: Server<TestChecker> =
+        MessageServer::new(TestChecker::default(), ServerId::default(), config);
+      let admin = server.register_local_client(ip, "a".into()).await.unwrap();
+      server.set_tier(admin, Tier::Admin).await.unwrap();
+      server
+        .register_trusted_client(admin, bridge, "bridge".into(), ip)
+        .await
+        .unwrap();
+      // how many messages fit in the mailbox of `dest`
+      let server = &server;
+      let fill = |dest| async move {
+        let mut delivered = 0;
+        loop {
+          let msg = ClientMessage::Text {
+            dest,
+            content: "hi".into(),
+          };
+          match server.handle_client_message(admin, msg).await[..] {
+            [ClientReply::Delivered(_)] => delivered += 1,
+            _ => return delivered,
+          }
+        }
+      };
+      assert_eq!(fill(admin).await, 3);
+      assert_eq!(fill(bridge).await, 5);
+
+      server.set_mailbox_size(admin, 4).await.unwrap();
+      assert_eq!(fill(admin).await, 1);
+      assert_eq!(
+        server.set_mailbox_size(ClientId::default(), 4).await,
+        Err(ClientError::UnknownClient)
+      );
+    });
+  }
+
+  #[test]
+  fn bans() {
+    async_std::task::block_on(async {
+      let (ip, other): (IpAddr, IpAddr) =
+        ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+      let server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let c1 = server.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = server
+        .register_local_client(other, "c2".into())
+        .await
+        .unwrap();
+      let text = |dest| ClientMessage::Text {
+        dest,
+        content: "hi".into(),
+      };
+
+      // a banned client can still poll, but not send
+      assert_eq!(server.ban_client(c2).await, ClientReply::Delivered(None));
+      assert_eq!(
+        server.handle_client_message(c2, text(c1)).await,
+        [ClientReply::Error(ClientError::Banned)]
+      );
+      let r = server.handle_client_message(c1, text(c2)).await;
+      assert!(matches!(r[..], [ClientReply::Delivered(Some(_))]));
+      assert!(matches!(
+        server.client_poll(c2).await,
+        ClientPollReply::Message { src, .. } if src == c1
+      ));
+      assert_eq!(
+        server.ban_client(ClientId::default()).await,
+        ClientReply::Error(ClientError::UnknownClient)
+      );
+
+      // an address ban covers the clients already registered from it
+      server.ban_ip(ip).await;
+      let mtext = ClientMessage::MText {
+        dest: vec![c2],
+        content: "hi".into(),
+      };
+      assert_eq!(
+        server.handle_client_message(c1, mtext).await,
+        [ClientReply::Multi(vec![(
+          c2,
+          ClientReply::Error(ClientError::Banned)
+        )])]
+      );
+      assert_eq!(
+        server.register_local_client(ip, "c3".into()).await,
+        Err(ClientError::Banned)
+      );
+      assert!(server
+        .register_local_client(other, "c3".into())
+        .await
+        .is_ok());
+
+      // a kicked client is gone, and can register again
+      let r = server.kick_client(c1).await;
+      assert_eq!(r.first(), Some(&ClientReply::Delivered(None)));
+      assert!(!server.list_users().await.contains_key(&c1));
+      assert_eq!(
+        server.kick_client(c1).await,
+        [ClientReply::Error(ClientError::UnknownClient)]
+      );
+    });
+  }
+
+  #[test]
+  fn rejected_delayed() {
+    async_std::task::block_on(async {
+      let (ip, other): (IpAddr, IpAddr) =
+        ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let c1 = a.register_local_client(ip, "c1".into()).await.unwrap();
+      let c2 = a.register_local_client(other, "c2".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      for src in [c1, c1, c2] {
+        let msg = ClientMessage::Text {
+          dest: cb,
+          content: "hi".into(),
+        };
+        let r = a.handle_client_message(src, msg).await;
+        assert!(matches!(r[..], [ClientReply::Delayed(_)]));
+      }
+
+      // the messages of a sender banned meanwhile are refused once the recipient is known
+      a.ban_ip(ip).await;
+      let ServerReply::Outgoing(flushed) = a.handle_server_message(b.make_announce().await).await
+      else {
+        panic!("Expected the delayed messages");
+      };
+      assert!(matches!(&flushed[..], [o] if o.message.src == c2));
+      assert_eq!(
+        a.client_poll(c1).await,
+        ClientPollReply::DelayedError(DelayedError::Rejected(cb))
+      );
+      assert_eq!(a.client_poll(c1).await, ClientPollReply::Nothing);
+      assert_eq!(a.client_poll(c2).await, ClientPollReply::Nothing);
+      assert_eq!(a.stored_messages.read().await.total, 0);
+    });
+  }
+
+  #[test]
+  fn invites() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "10.0.0.1".parse().unwrap();
+      let config = ServerConfig {
+        invite_key: Some(b"key".to_vec()),
+        ..ServerConfig::default()
+      };
+      let mut server: Server<TestChecker> =
+        MessageServer::new(TestChecker::default(), ServerId::default(), config);
+      server.set_clock(Arc::new(ManualClock::new(1000)));
+      let invite = server
+        .issue_invite(Duration::from_secs(60), 2)
+        .await
+        .unwrap()
+        .to_bytes();
+
+      // without an invite, there is no way in
+      assert_eq!(
+        server.register_local_client(ip, "a".into()).await,
+        Err(ClientError::InvalidInvite)
+      );
+      assert_eq!(
+        server.register_guest(ip, "a".into()).await,
+        Err(ClientError::InvalidInvite)
+      );
+      let register = |name: &str, invite: &[u8]| {
+        server.register_invited_client(ip, name.into(), invite.to_vec())
+      };
+      assert!(register("a", &invite).await.is_ok());
+      // a refused registration does not use the invite up
+      assert_eq!(
+        register("a", &invite).await,
+        Err(ClientError::NameTaken(
+          server.lookup_user("a").await.unwrap()
+        ))
+      );
+      assert!(register("b", &invite).await.is_ok());
+      assert_eq!(
+        register("c", &invite).await,
+        Err(ClientError::InvalidInvite)
+      );
+      assert_eq!(
+        register("c", &invite[1..]).await,
+        Err(ClientError::InvalidInvite)
+      );
+
+      // an open server ignores invites, and issues none
+      let open: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      assert!(open
+        .register_invited_client(ip, "a".into(), invite)
+        .await
+        .is_ok());
+      assert_eq!(open.issue_invite(Duration::from_secs(60), 1).await, None);
+    });
+  }
+
+  #[test]
+  fn import_history() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      server.set_clock(Arc::new(ManualClock::new(1000)));
+      let a = server.register_local_client(ip, "a".into()).await.unwrap();
+      let b = server.register_local_client(ip, "b".into()).await.unwrap();
+      let text = ClientMessage::Text {
+        dest: b,
+        content: "polled".into(),
+      };
+      server.handle_client_message(a, text).await;
+      server.client_poll(b).await;
+      let polled = server.client_history(b, 10, None, false).await.unwrap()[0].id;
+
+      let record = |id: u128, timestamp, dst, content_type| ArchiveRecord {
+        id: MessageId::from(id),
+        timestamp,
+        src: a,
+        srcsrv: ServerId::from(2),
+        dst,
+        content: "archived".into(),
+        content_type,
+      };
+      let (old, late) = (
+        record(1, 500, b, ContentType::Plain),
+        record(2, 2000, b, ContentType::Markdown),
+      );
+      let mut duplicate = record(3, 600, b, ContentType::Plain);
+      duplicate.id = polled;
+      let records = [
+        old.clone(),
+        late.clone(),
+        old.clone(),
+        duplicate,
+        record(4, 700, ClientId::from(9), ContentType::Plain),
+      ];
+      assert_eq!(server.import_history(&records).await, 2);
+      assert_eq!(server.import_history(&records).await, 0);
+
+      // in the history, by timestamp, but not in the mailbox
+      let history = server.client_history(b, 10, None, false).await.unwrap();
+      assert_eq!(
+        history,
+        [old.to_entry(), history[1].clone(), late.to_entry()]
+      );
+      assert_eq!(history[1].id, polled);
+      assert!(matches!(
+        &history[0].message,
+        ClientPollReply::Message { srcsrv, timestamp: 500, .. } if *srcsrv == ServerId::from(2)
+      ));
+      assert_eq!(server.client_poll(b).await, ClientPollReply::Nothing);
+    });
+  }
+
+  #[test]
+  fn export_history() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let clock = Arc::new(ManualClock::new(1000));
+      server.set_clock(clock.clone());
+      let a = server.register_local_client(ip, "a".into()).await.unwrap();
+      let b = server.register_local_client(ip, "b".into()).await.unwrap();
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+      let text = |dest, content: &str| ClientMessage::Text {
+        dest,
+        content: content.into(),
+      };
+      server.handle_client_message(a, text(b, "ab")).await;
+      server.handle_client_message(a, text(c, "ac")).await;
+      server.client_poll(b).await;
+      server.client_poll(c).await;
+      clock.advance(Duration::from_secs(1));
+      let rich = ClientMessage::Rich {
+        dest: vec![b],
+        content: RichContent::default()
+          .text("cb")
+          .with_content_type(ContentType::Markdown),
+      };
+      server.handle_client_message(c, rich).await;
+      server.client_poll(b).await;
+
+      let mut all = server.export_history(&ExportFilter::default()).await;
+      all.sort_by_key(|r| (r.timestamp, r.content.clone()));
+      let contents: Vec<_> = all.iter().map(|r| r.content.as_str()).collect();
+      assert_eq!(contents, ["ab", "ac", "cb"]);
+      assert_eq!(
+        (all[2].timestamp, all[2].dst, &all[2].content_type),
+        (2000, b, &ContentType::Markdown)
+      );
+      let late = ExportFilter {
+        since: Some(1500),
+        ..ExportFilter::default()
+      };
+      assert_eq!(server.export_history(&late).await, [all[2].clone()]);
+
+      // one archive per conversation of the participant
+      let dir = std::env::temp_dir().join(format!("chat-export-{}", a));
+      let filter = ExportFilter {
+        participant: Some(a),
+        ..ExportFilter::default()
+      };
+      let files = crate::archive::export_history(&server, &dir, &filter)
+        .await
+        .unwrap();
+      assert_eq!(files.len(), 2);
+      std::fs::remove_dir_all(dir).unwrap();
+    });
+  }
+
+  #[test]
+  fn reports_ring() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let target = server
+        .register_local_client(ip, "target".into())
+        .await
+        .unwrap();
+      for i in 0..=REPORTS_SIZE / REPORTS_PER_REPORTER {
+        let reporter = server
+          .register_local_client(ip, format!("reporter {}", i))
+          .await
+          .unwrap();
+        for j in 0..REPORTS_PER_REPORTER {
+          let reason = format!("{} {}", i, j);
+          let r = server
+            .handle_report(reporter, ReportTarget::Client(target), reason)
+            .await;
+          assert_eq!(r, ClientReply::Delivered(None));
+        }
+      }
+      // the oldest ones make room for the latest
+      let reports = server.reports().await;
+      assert_eq!(reports.len(), REPORTS_SIZE);
+      assert_eq!(reports[0].reason, "1 0");
+      assert_eq!(
+        reports[REPORTS_SIZE - 1].reason,
+        format!(
+          "{} {}",
+          REPORTS_SIZE / REPORTS_PER_REPORTER,
+          REPORTS_PER_REPORTER - 1
+        )
+      );
+    });
+  }
+
+  #[test]
+  fn notification_prefs() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let mut server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      // midnight UTC, in the quiet hours
+      let clock = Arc::new(ManualClock::new(0));
+      server.set_clock(clock.clone());
+      let a = server.register_local_client(ip, "a".into()).await.unwrap();
+      let b = server.register_local_client(ip, "b".into()).await.unwrap();
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+      let prefs = NotificationPrefs {
+        muted: vec![a],
+        quiet_hours: Some(crate::messages::QuietHours { start: 0, end: 1 }),
+      };
+      server.set_notification_prefs(b, prefs).await;
+      let typing = |src| {
+        let server = &server;
+        async move {
+          let event = ClientMessage::Event {
+            dest: b,
+            event: Event::Typing,
+          };
+          server.handle_client_message(src, event).await
+        }
+      };
+      let text = |content: &str| ClientMessage::Text {
+        dest: b,
+        content: content.into(),
+      };
+
+      // silenced events are dropped, without telling their sender
+      assert_eq!(typing(c).await, [ClientReply::Delivered(None)]);
+      assert_eq!(server.client_poll(b).await, ClientPollReply::Nothing);
+
+      // silenced messages are left in the mailbox, until the quiet hours are over
+      server.handle_client_message(a, text("muted")).await;
+      server.handle_client_message(c, text("quiet")).await;
+      let mut messages = server.client_stream(b);
+      let (r, _) = futures::join!(messages.next(), async {
+        clock.advance(Duration::from_secs(60));
+      });
+      assert_eq!(r, Some((c, "quiet".to_string())));
+      drop(messages);
+      assert!(matches!(
+        server.client_poll(b).await,
+        ClientPollReply::Message { src, .. } if src == a
+      ));
+
+      typing(a).await;
+      typing(c).await;
+      assert_eq!(
+        server.client_poll(b).await,
+        ClientPollReply::Event {
+          src: c,
+          event: Event::Typing
+        }
+      );
+      assert_eq!(server.client_poll(b).await, ClientPollReply::Nothing);
+    });
+  }
+
+  #[test]
+  fn mentions() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let server: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let a = server.register_local_client(ip, "a".into()).await.unwrap();
+      let b = server.register_local_client(ip, "b".into()).await.unwrap();
+      let c = server.register_local_client(ip, "c".into()).await.unwrap();
+      let rich = |content: RichContent| ClientMessage::Rich {
+        dest: vec![b, c],
+        content,
+      };
+
+      // spans outside the text, or inside a character, are refused
+      for (text, start, len) in [("hi", 1, 2), ("hé", 2, 1), ("hi", usize::MAX, 2)] {
+        let content = RichContent {
+          text: text.into(),
+          mentions: vec![Mention {
+            client: b,
+            start,
+            len,
+          }],
+          ..RichContent::default()
+        };
+        assert_eq!(
+          server.handle_client_message(a, rich(content)).await,
+          [
+            ClientReply::Error(ClientError::Forbidden),
+            ClientReply::Error(ClientError::Forbidden)
+          ]
+        );
+      }
+      assert_eq!(server.client_poll(b).await, ClientPollReply::Nothing);
+
+      // only the mentioned recipient is told, before it polls the message
+      let content = RichContent::default().text("hi ").mention(b, "b");
+      let r = server.handle_client_message(a, rich(content.clone())).await;
+      let [ClientReply::Delivered(Some(id)), ClientReply::Delivered(Some(_))] = r[..] else {
+        panic!("{:?}", r);
+      };
+      assert_eq!(
+        server.client_poll(b).await,
+        ClientPollReply::Event {
+          src: a,
+          event: Event::Mentioned(id)
+        }
+      );
+      let message = ClientPollReply::RichMessage {
+        src: a,
+        content: content.clone(),
+      };
+      assert_eq!(server.client_poll(b).await, message);
+      assert!(matches!(
+        server.client_poll(c).await,
+        ClientPollReply::RichMessage { .. }
+      ));
+      assert_eq!(server.client_poll(c).await, ClientPollReply::Nothing);
+
+      // not when the sender is muted
+      let prefs = NotificationPrefs {
+        muted: vec![a],
+        ..NotificationPrefs::default()
+      };
+      server.set_notification_prefs(b, prefs).await;
+      server.handle_client_message(a, rich(content)).await;
+      assert_eq!(server.client_poll(b).await, message);
+
+      // and clients can't make it up
+      let forged = ClientMessage::Event {
+        dest: b,
+        event: Event::Mentioned(id),
+      };
+      assert_eq!(
+        server.handle_client_message(c, forged).await,
+        [ClientReply::Error(ClientError::Forbidden)]
+      );
+    });
+  }
+
+  #[test]
+  fn remote_mentions() {
+    async_std::task::block_on(async {
+      let ip: IpAddr = "127.0.0.1".parse().unwrap();
+      let a: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let b: Server<TestChecker> = MessageServer::new(
+        TestChecker::default(),
+        ServerId::default(),
+        ServerConfig::default(),
+      );
+      let ca = a.register_local_client(ip, "a".into()).await.unwrap();
+      let cb = b.register_local_client(ip, "b".into()).await.unwrap();
+      let cc = b.register_local_client(ip, "c".into()).await.unwrap();
+      a.handle_server_message(b.make_announce().await).await;
+      b.handle_server_message(a.make_announce().await).await;
+
+      // the spans travel with the message, only the mentioned recipient is told
+      let content = RichContent::default().text("hi ").mention(cb, "b");
+      let msg = ClientMessage::Rich {
+        dest: vec![cb, cc],
+        content: content.clone(),
+      };
+      let r = a.handle_client_message(ca, msg).await;
+      let [ClientReply::Transfer(_, to_b, Some(id)), ClientReply::Transfer(_, to_c, Some(_))] =
+        &r[..]
+      else {
+        panic!("Expected a transfer per recipient, got {:?}", r);
+      };
+      b.handle_server_message(to_b.clone()).await;
+      b.handle_server_message(to_c.clone()).await;
+      assert_eq!(
+        b.client_poll(cb).await,
+        ClientPollReply::Event {
+          src: ca,
+          event: Event::Mentioned(*id)
+        }
+      );
+      let polled = ClientPollReply::RichMessage { src: ca, content };
+      assert_eq!(b.client_poll(cb).await, polled);
+      assert_eq!(b.client_poll(cc).await, polled);
+    });
   }
 }
@@ -0,0 +1,229 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+
+use crate::core::{SpamChecker, Verdict};
+
+/// What to answer when the spam checking backend can't be relied on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailPolicy {
+  /// let everyone in
+  #[default]
+  Open,
+  /// consider everyone a spammer
+  Closed,
+}
+
+impl FailPolicy {
+  /// the `is_*_spammer` answer for this policy
+  pub fn verdict(self) -> bool {
+    self == FailPolicy::Closed
+  }
+}
+
+/// Counts consecutive failures of a backend, and stops calling it for a while once a threshold
+/// is reached.
+///
+/// After the cool-down, a single call is let through: if it succeeds the breaker closes again,
+/// otherwise it opens for another cool-down period.
+pub struct CircuitBreaker {
+  threshold: u32,
+  cooldown: Duration,
+  state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+  failures: u32,
+  open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+  pub fn new(threshold: u32, cooldown: Duration) -> Self {
+    CircuitBreaker {
+      threshold,
+      cooldown,
+      state: Mutex::new(BreakerState::default()),
+    }
+  }
+
+  /// true if the backend should not be called right now
+  pub async fn is_open(&self) -> bool {
+    let mut state = self.state.lock().await;
+    match state.open_until {
+      Some(until) if Instant::now() < until => true,
+      Some(_) => {
+        // half open: let one call through, and reopen right away if it fails
+        state.open_until = None;
+        state.failures = self.threshold.saturating_sub(1);
+        false
+      }
+      None => false,
+    }
+  }
+
+  pub async fn success(&self) {
+    let mut state = self.state.lock().await;
+    state.failures = 0;
+    state.open_until = None;
+  }
+
+  pub async fn failure(&self) {
+    let mut state = self.state.lock().await;
+    state.failures += 1;
+    if state.failures >= self.threshold {
+      log::warn!(
+        "circuit breaker open after {} failures, cooling down for {:?}",
+        state.failures,
+        self.cooldown
+      );
+      state.open_until = Some(Instant::now() + self.cooldown);
+    }
+  }
+}
+
+/// Settings for `BreakerChecker`.
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+  /// a check that takes longer than this counts as a failure
+  pub timeout: Duration,
+  /// consecutive failures before the checker is short-circuited
+  pub threshold: u32,
+  pub cooldown: Duration,
+  pub policy: FailPolicy,
+}
+
+impl Default for BreakerConfig {
+  fn default() -> Self {
+    BreakerConfig {
+      timeout: Duration::from_millis(500),
+      threshold: 5,
+      cooldown: Duration::from_secs(30),
+      policy: FailPolicy::Open,
+    }
+  }
+}
+
+/// Wraps a spam checker so that a slow or dead backend can't make registrations hang.
+///
+/// Checks that time out are answered according to the fail policy, and once `threshold` of them
+/// happen in a row, the inner checker is not called at all for the cool-down period.
+pub struct BreakerChecker<C> {
+  inner: C,
+  config: BreakerConfig,
+  breaker: CircuitBreaker,
+}
+
+impl<C: SpamChecker> BreakerChecker<C> {
+  pub fn new(inner: C, config: BreakerConfig) -> Self {
+    BreakerChecker {
+      inner,
+      breaker: CircuitBreaker::new(config.threshold, config.cooldown),
+      config,
+    }
+  }
+
+  async fn guard<F: Future<Output = Verdict>>(&self, check: F) -> Verdict {
+    if self.breaker.is_open().await {
+      return Verdict::Fallback(self.config.policy.verdict());
+    }
+    match timeout(self.config.timeout, check).await {
+      Ok(verdict) => {
+        self.breaker.success().await;
+        verdict
+      }
+      Err(_) => {
+        self.breaker.failure().await;
+        Verdict::Fallback(self.config.policy.verdict())
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl<C: SpamChecker + Send + Sync> SpamChecker for BreakerChecker<C> {
+  async fn is_user_spammer(&self, name: &str) -> bool {
+    self.user_verdict(name).await.is_spammer()
+  }
+  async fn is_ip_spammer(&self, ip: &IpAddr) -> bool {
+    self.ip_verdict(ip).await.is_spammer()
+  }
+  async fn user_verdict(&self, name: &str) -> Verdict {
+    self.guard(self.inner.user_verdict(name)).await
+  }
+  async fn ip_verdict(&self, ip: &IpAddr) -> Verdict {
+    self.guard(self.inner.ip_verdict(ip)).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+
+  /// a checker whose backend never answers
+  #[derive(Default)]
+  struct Hanging {
+    calls: AtomicUsize,
+  }
+
+  #[async_trait]
+  impl SpamChecker for Hanging {
+    async fn is_user_spammer(&self, _name: &str) -> bool {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      async_std::future::pending().await
+    }
+    async fn is_ip_spammer(&self, _ip: &IpAddr) -> bool {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      async_std::future::pending().await
+    }
+  }
+
+  #[test]
+  fn short_circuits() {
+    async_std::task::block_on(async {
+      let checker = BreakerChecker::new(
+        Hanging::default(),
+        BreakerConfig {
+          timeout: Duration::from_millis(10),
+          threshold: 2,
+          cooldown: Duration::from_secs(60),
+          policy: FailPolicy::Closed,
+        },
+      );
+      for _ in 0..5 {
+        assert!(checker.is_user_spammer("user").await);
+      }
+      assert!(checker.is_ip_spammer(&"127.0.0.1".parse().unwrap()).await);
+      assert_eq!(checker.inner.calls.load(Ordering::SeqCst), 2);
+    })
+  }
+
+  #[test]
+  fn opens_and_recovers() {
+    async_std::task::block_on(async {
+      let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+      breaker.failure().await;
+      assert!(!breaker.is_open().await);
+      breaker.failure().await;
+      assert!(breaker.is_open().await);
+
+      async_std::task::sleep(Duration::from_millis(60)).await;
+      // half open, a single failure is enough to open it again
+      assert!(!breaker.is_open().await);
+      breaker.failure().await;
+      assert!(breaker.is_open().await);
+
+      async_std::task::sleep(Duration::from_millis(60)).await;
+      assert!(!breaker.is_open().await);
+      breaker.success().await;
+      breaker.failure().await;
+      assert!(!breaker.is_open().await);
+    })
+  }
+}
@@ -0,0 +1,237 @@
+//! caching spam verdicts, in the process or in a store shared by a cluster
+//!
+//! `CachingChecker` wraps another checker and remembers its verdicts in a `VerdictStore` for a
+//! time to live. Keys are truncated SHA-256 hashes of the checked values (`verdict_key`): a store
+//! shared by several nodes can't be fed a value whose key collides with a cached verdict.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use crypto_hash::{digest, Algorithm};
+
+use crate::core::{SpamChecker, Verdict};
+
+/// Where spam verdicts are remembered.
+///
+/// Keys are stable hashes (see `verdict_key`), so that a store shared by several nodes of a
+/// cluster can be filled by one node and read by the others. Entries must not be returned once
+/// their time to live has elapsed.
+#[async_trait]
+pub trait VerdictStore {
+  async fn get(&self, key: u128) -> Option<bool>;
+  async fn put(&self, key: u128, verdict: bool, ttl: Duration);
+}
+
+/// time between two sweeps of the expired entries of a `MemoryStore`
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+/// A store local to the process.
+#[derive(Default)]
+pub struct MemoryStore {
+  entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+  verdicts: HashMap<u128, (bool, Instant)>,
+  // when the expired verdicts are next swept
+  sweep: Option<Instant>,
+}
+
+#[async_trait]
+impl VerdictStore for MemoryStore {
+  async fn get(&self, key: u128) -> Option<bool> {
+    let mut entries = self.entries.lock().await;
+    match entries.verdicts.get(&key) {
+      Some((verdict, until)) if Instant::now() < *until => Some(*verdict),
+      Some(_) => {
+        entries.verdicts.remove(&key);
+        None
+      }
+      None => None,
+    }
+  }
+
+  async fn put(&self, key: u128, verdict: bool, ttl: Duration) {
+    let mut entries = self.entries.lock().await;
+    let now = Instant::now();
+    if entries.sweep.is_none_or(|sweep| sweep <= now) {
+      entries.verdicts.retain(|_, (_, until)| now < *until);
+      entries.sweep = Some(now + SWEEP_EVERY);
+    }
+    entries.verdicts.insert(key, (verdict, now + ttl));
+  }
+}
+
+/// the first 128 bits of the SHA-256 of the kind of check and the checked value, identical on
+/// every node
+pub fn verdict_key(kind: &str, value: &str) -> u128 {
+  let mut data = Vec::with_capacity(kind.len() + 1 + value.len());
+  data.extend_from_slice(kind.as_bytes());
+  data.push(0);
+  data.extend_from_slice(value.as_bytes());
+  let hash = digest(Algorithm::SHA256, &data);
+  u128::from_be_bytes(hash[..16].try_into().expect("a SHA-256 has 32 bytes"))
+}
+
+/// Remembers the verdicts of a spam checker for `ttl`, but not the fallbacks it answers when its
+/// backend can't be reached (see `SpamChecker::user_verdict`).
+///
+/// With a shared store, a given IP or name is checked once for the whole cluster instead of once
+/// per node.
+pub struct CachingChecker<C, S> {
+  inner: C,
+  store: S,
+  ttl: Duration,
+}
+
+impl<C: SpamChecker, S: VerdictStore> CachingChecker<C, S> {
+  pub fn new(inner: C, store: S, ttl: Duration) -> Self {
+    CachingChecker { inner, store, ttl }
+  }
+
+  pub fn store(&self) -> &S {
+    &self.store
+  }
+
+  // the stored verdict of `key`, or the one of `check` when it is not a fallback
+  async fn remember<F: Future<Output = Verdict>>(&self, key: u128, check: F) -> Verdict {
+    if let Some(verdict) = self.store.get(key).await {
+      return Verdict::Checked(verdict);
+    }
+    let verdict = check.await;
+    if let Verdict::Checked(spammer) = verdict {
+      self.store.put(key, spammer, self.ttl).await;
+    }
+    verdict
+  }
+}
+
+#[async_trait]
+impl<C, S> SpamChecker for CachingChecker<C, S>
+where
+  C: SpamChecker + Send + Sync,
+  S: VerdictStore + Send + Sync,
+{
+  async fn is_user_spammer(&self, name: &str) -> bool {
+    self.user_verdict(name).await.is_spammer()
+  }
+  async fn is_ip_spammer(&self, ip: &IpAddr) -> bool {
+    self.ip_verdict(ip).await.is_spammer()
+  }
+  async fn user_verdict(&self, name: &str) -> Verdict {
+    let key = verdict_key("user", name);
+    self.remember(key, self.inner.user_verdict(name)).await
+  }
+  async fn ip_verdict(&self, ip: &IpAddr) -> Verdict {
+    let key = verdict_key("ip", &ip.to_string());
+    self.remember(key, self.inner.ip_verdict(ip)).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  use super::*;
+
+  /// flags every IP, and counts calls
+  #[derive(Default)]
+  struct Counting {
+    calls: AtomicUsize,
+  }
+
+  #[async_trait]
+  impl SpamChecker for Arc<Counting> {
+    async fn is_user_spammer(&self, _name: &str) -> bool {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      false
+    }
+    async fn is_ip_spammer(&self, _ip: &IpAddr) -> bool {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      true
+    }
+  }
+
+  #[async_trait]
+  impl VerdictStore for Arc<MemoryStore> {
+    async fn get(&self, key: u128) -> Option<bool> {
+      (**self).get(key).await
+    }
+    async fn put(&self, key: u128, verdict: bool, ttl: Duration) {
+      (**self).put(key, verdict, ttl).await
+    }
+  }
+
+  #[test]
+  fn shared_between_nodes() {
+    async_std::task::block_on(async {
+      let backend = Arc::new(Counting::default());
+      let store = Arc::new(MemoryStore::default());
+      let ttl = Duration::from_secs(60);
+      let node1 = CachingChecker::new(backend.clone(), store.clone(), ttl);
+      let node2 = CachingChecker::new(backend.clone(), store.clone(), ttl);
+      let ip = "10.0.0.1".parse().unwrap();
+
+      assert!(node1.is_ip_spammer(&ip).await);
+      assert!(node2.is_ip_spammer(&ip).await);
+      assert!(!node2.is_user_spammer("10.0.0.1").await);
+      assert_eq!(backend.calls.load(Ordering::SeqCst), 2);
+    })
+  }
+
+  /// a backend that can't be reached, answered by the fail-open policy
+  struct Unreachable;
+
+  #[async_trait]
+  impl SpamChecker for Unreachable {
+    async fn is_user_spammer(&self, name: &str) -> bool {
+      self.user_verdict(name).await.is_spammer()
+    }
+    async fn is_ip_spammer(&self, ip: &IpAddr) -> bool {
+      self.ip_verdict(ip).await.is_spammer()
+    }
+    async fn user_verdict(&self, _name: &str) -> Verdict {
+      Verdict::Fallback(false)
+    }
+    async fn ip_verdict(&self, _ip: &IpAddr) -> Verdict {
+      Verdict::Fallback(false)
+    }
+  }
+
+  #[test]
+  fn fallbacks_not_cached() {
+    async_std::task::block_on(async {
+      let checker =
+        CachingChecker::new(Unreachable, MemoryStore::default(), Duration::from_secs(60));
+      let ip = "10.0.0.1".parse().unwrap();
+      assert!(!checker.is_ip_spammer(&ip).await);
+      assert_eq!(checker.ip_verdict(&ip).await, Verdict::Fallback(false));
+      assert!(!checker.is_user_spammer("someone").await);
+      let key = verdict_key("user", "someone");
+      assert_eq!(checker.store().get(key).await, None);
+    })
+  }
+
+  #[test]
+  fn expires() {
+    async_std::task::block_on(async {
+      let store = MemoryStore::default();
+      store.put(1, true, Duration::from_millis(10)).await;
+      assert_eq!(store.get(1).await, Some(true));
+      async_std::task::sleep(Duration::from_millis(20)).await;
+      assert_eq!(store.get(1).await, None);
+    })
+  }
+
+  #[test]
+  fn stable_keys() {
+    assert_eq!(verdict_key("", ""), 0x6e340b9cffb37a989ca544e6bb780a2c);
+    assert_ne!(verdict_key("ip", "a"), verdict_key("user", "a"));
+  }
+}
@@ -0,0 +1,5 @@
+//! spam checker implementations
+
+pub mod breaker;
+pub mod cache;
+pub mod webhook;
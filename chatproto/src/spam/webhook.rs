@@ -0,0 +1,248 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{SpamChecker, Verdict};
+use crate::spam::breaker::{CircuitBreaker, FailPolicy};
+
+/// Settings for `WebhookChecker`.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+  /// endpoint, only plain `http://host[:port]/path` URLs are supported
+  pub url: String,
+  /// timeout for a single HTTP exchange
+  pub timeout: Duration,
+  /// extra attempts after a failed exchange
+  pub retries: u32,
+  /// what to answer when the endpoint can't be reached
+  pub on_failure: FailPolicy,
+  /// consecutive failed checks before the endpoint is left alone for `cooldown`
+  pub breaker_threshold: u32,
+  pub cooldown: Duration,
+}
+
+impl WebhookConfig {
+  pub fn new(url: &str) -> Self {
+    WebhookConfig {
+      url: url.to_string(),
+      timeout: Duration::from_millis(500),
+      retries: 1,
+      on_failure: FailPolicy::Open,
+      breaker_threshold: 5,
+      cooldown: Duration::from_secs(30),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct CheckRequest<'a> {
+  kind: &'a str,
+  value: &'a str,
+}
+
+#[derive(Deserialize)]
+struct CheckResponse {
+  spammer: bool,
+}
+
+/// A spam checker that asks an external HTTP service.
+///
+/// Each check is a `POST` of `{"kind": "ip" | "user", "value": ...}`, and the service must answer
+/// with a 2xx status and `{"spammer": bool}`.
+pub struct WebhookChecker {
+  host: String,
+  port: u16,
+  path: String,
+  config: WebhookConfig,
+  breaker: CircuitBreaker,
+}
+
+impl WebhookChecker {
+  pub fn new(config: WebhookConfig) -> anyhow::Result<Self> {
+    let rest = config
+      .url
+      .strip_prefix("http://")
+      .ok_or_else(|| anyhow::anyhow!("Unsupported webhook URL {}", config.url))?;
+    let (authority, path) = match rest.find('/') {
+      Some(idx) => (&rest[..idx], &rest[idx..]),
+      None => (rest, "/"),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+      // bracketed IPv6 literal
+      Some(v6) => {
+        let (host, port) = v6
+          .split_once(']')
+          .ok_or_else(|| anyhow::anyhow!("Invalid host in webhook URL {}", config.url))?;
+        match port.strip_prefix(':') {
+          Some(port) => (host, port.parse()?),
+          None => (host, 80),
+        }
+      }
+      None => match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (authority, 80),
+      },
+    };
+    if host.is_empty() {
+      anyhow::bail!("Missing host in webhook URL {}", config.url);
+    }
+    Ok(WebhookChecker {
+      host: host.to_string(),
+      port,
+      path: path.to_string(),
+      breaker: CircuitBreaker::new(config.breaker_threshold, config.cooldown),
+      config,
+    })
+  }
+
+  async fn check(&self, kind: &str, value: &str) -> Verdict {
+    if self.breaker.is_open().await {
+      return Verdict::Fallback(self.config.on_failure.verdict());
+    }
+    for attempt in 0..=self.config.retries {
+      match timeout(self.config.timeout, self.exchange(kind, value)).await {
+        Ok(Ok(verdict)) => {
+          self.breaker.success().await;
+          return Verdict::Checked(verdict);
+        }
+        Ok(Err(rr)) => log::warn!("spam webhook attempt {} failed: {}", attempt, rr),
+        Err(_) => log::warn!("spam webhook attempt {} timed out", attempt),
+      }
+    }
+    self.breaker.failure().await;
+    Verdict::Fallback(self.config.on_failure.verdict())
+  }
+
+  async fn exchange(&self, kind: &str, value: &str) -> anyhow::Result<bool> {
+    let body = serde_json::to_vec(&CheckRequest { kind, value })?;
+    let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+    // HTTP/1.0 so that the response is neither chunked nor kept alive
+    let head = format!(
+      "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+      self.path,
+      self.host,
+      body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let split = response
+      .windows(4)
+      .position(|w| w == b"\r\n\r\n")
+      .ok_or_else(|| anyhow::anyhow!("Truncated HTTP response"))?;
+    let head = std::str::from_utf8(&response[..split])?;
+    let status: u16 = head
+      .split_whitespace()
+      .nth(1)
+      .ok_or_else(|| anyhow::anyhow!("Missing HTTP status"))?
+      .parse()?;
+    if !(200..300).contains(&status) {
+      anyhow::bail!("HTTP status {}", status);
+    }
+    let verdict: CheckResponse = serde_json::from_slice(&response[split + 4..])?;
+    Ok(verdict.spammer)
+  }
+}
+
+#[async_trait]
+impl SpamChecker for WebhookChecker {
+  async fn is_user_spammer(&self, name: &str) -> bool {
+    self.check("user", name).await.is_spammer()
+  }
+  async fn is_ip_spammer(&self, ip: &IpAddr) -> bool {
+    self.check("ip", &ip.to_string()).await.is_spammer()
+  }
+  async fn user_verdict(&self, name: &str) -> Verdict {
+    self.check("user", name).await
+  }
+  async fn ip_verdict(&self, ip: &IpAddr) -> Verdict {
+    self.check("ip", &ip.to_string()).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  use async_std::net::TcpListener;
+
+  use super::*;
+
+  /// answers each connection with the next canned response, and counts requests
+  async fn fake_endpoint(responses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/check", listener.local_addr().unwrap());
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    async_std::task::spawn(async move {
+      let mut responses = responses.into_iter().cycle();
+      loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 4096];
+        let _ = stream.read(&mut buf).await;
+        counter.fetch_add(1, Ordering::SeqCst);
+        let _ = stream.write_all(responses.next().unwrap().as_bytes()).await;
+      }
+    });
+    (url, count)
+  }
+
+  const SPAMMER: &str = "HTTP/1.0 200 OK\r\n\r\n{\"spammer\": true}";
+  const FINE: &str = "HTTP/1.0 200 OK\r\n\r\n{\"spammer\": false}";
+  const BROKEN: &str = "HTTP/1.0 500 Internal Server Error\r\n\r\n";
+
+  #[test]
+  fn url() {
+    let checker =
+      WebhookChecker::new(WebhookConfig::new("http://spam.local:8080/v1/check")).unwrap();
+    assert_eq!(
+      (checker.host.as_str(), checker.port, checker.path.as_str()),
+      ("spam.local", 8080, "/v1/check")
+    );
+    let checker = WebhookChecker::new(WebhookConfig::new("http://spam.local")).unwrap();
+    assert_eq!((checker.port, checker.path.as_str()), (80, "/"));
+    let checker = WebhookChecker::new(WebhookConfig::new("http://[::1]:81/")).unwrap();
+    assert_eq!((checker.host.as_str(), checker.port), ("::1", 81));
+    assert!(WebhookChecker::new(WebhookConfig::new("https://spam.local")).is_err());
+  }
+
+  #[test]
+  fn verdicts() {
+    async_std::task::block_on(async {
+      let (url, _) = fake_endpoint(vec![SPAMMER, FINE]).await;
+      let checker = WebhookChecker::new(WebhookConfig::new(&url)).unwrap();
+      assert!(checker.is_user_spammer("bot").await);
+      assert!(!checker.is_ip_spammer(&"127.0.0.1".parse().unwrap()).await);
+    })
+  }
+
+  #[test]
+  fn retries_then_breaks() {
+    async_std::task::block_on(async {
+      // first exchange fails, the retry succeeds
+      let (url, count) = fake_endpoint(vec![BROKEN, SPAMMER]).await;
+      let checker = WebhookChecker::new(WebhookConfig::new(&url)).unwrap();
+      assert!(checker.is_user_spammer("bot").await);
+      assert_eq!(count.load(Ordering::SeqCst), 2);
+
+      // always failing: fall back to the configured verdict, then stop calling
+      let (url, count) = fake_endpoint(vec![BROKEN]).await;
+      let mut config = WebhookConfig::new(&url);
+      config.on_failure = FailPolicy::Closed;
+      config.breaker_threshold = 2;
+      let checker = WebhookChecker::new(config).unwrap();
+      for _ in 0..4 {
+        assert!(checker.is_user_spammer("someone").await);
+      }
+      assert_eq!(count.load(Ordering::SeqCst), 4);
+    })
+  }
+}
@@ -0,0 +1,198 @@
+//! the local store of a `ChatClient`
+//!
+//! It caches the messages the client polled and the users it listed, and queues the messages
+//! sent while the server could not be reached. A store can be kept in a file, written with the
+//! codec of the protocol, so that the cache and the queue outlive the client.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::messages::{ClientId, ClientMessage, ClientPollReply, Sequence};
+use crate::netproto::{decode, encode};
+
+/// messages kept in the cache, the oldest ones are dropped above
+pub const CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Default)]
+pub struct LocalStore {
+  path: Option<PathBuf>,
+  history: VecDeque<ClientPollReply>,
+  roster: HashMap<ClientId, String>,
+  // with the sequence numbers they were first sent with
+  outbox: VecDeque<Sequence<ClientMessage>>,
+}
+
+impl LocalStore {
+  /// a store that is lost with its client
+  pub fn in_memory() -> Self {
+    Self::default()
+  }
+
+  /// a store kept in `path`, and loaded from it when the file exists
+  pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+    let path = path.into();
+    let mut store = match File::open(&path) {
+      Ok(file) => Self::read(&mut BufReader::new(file))?,
+      Err(rr) if rr.kind() == std::io::ErrorKind::NotFound => Self::default(),
+      Err(rr) => return Err(rr.into()),
+    };
+    store.path = Some(path);
+    Ok(store)
+  }
+
+  /// the cached messages, oldest first
+  pub fn history(&self) -> impl Iterator<Item = &ClientPollReply> {
+    self.history.iter()
+  }
+
+  /// the users of the last listing
+  pub fn roster(&self) -> &HashMap<ClientId, String> {
+    &self.roster
+  }
+
+  /// the messages waiting to be sent, in sending order
+  pub fn pending(&self) -> impl Iterator<Item = &ClientMessage> {
+    self.outbox.iter().map(|sq| &sq.content)
+  }
+
+  // caches the messages, like the server history, false for everything else
+  pub(crate) fn record(&mut self, reply: &ClientPollReply) -> bool {
+    match reply.unthreaded() {
+      ClientPollReply::Message { .. }
+      | ClientPollReply::RichMessage { .. }
+      | ClientPollReply::RoomMessage { .. }
+      | ClientPollReply::Data { .. } => {
+        if self.history.len() == CACHE_SIZE {
+          self.history.pop_front();
+        }
+        self.history.push_back(reply.clone());
+        true
+      }
+      _ => false,
+    }
+  }
+
+  pub(crate) fn clear_history(&mut self) {
+    self.history.clear();
+  }
+
+  pub(crate) fn set_roster(&mut self, roster: HashMap<ClientId, String>) {
+    self.roster = roster;
+  }
+
+  pub(crate) fn queue(&mut self, message: Sequence<ClientMessage>) {
+    self.outbox.push_back(message);
+  }
+
+  pub(crate) fn next_queued(&self) -> Option<&Sequence<ClientMessage>> {
+    self.outbox.front()
+  }
+
+  pub(crate) fn dequeue(&mut self) {
+    self.outbox.pop_front();
+  }
+
+  /// writes the store to its file, if it has one
+  /// the file is replaced at once, so that a crash leaves the previous version
+  pub fn save(&self) -> anyhow::Result<()> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+    let partial = path.with_extension("partial");
+    let mut wr = BufWriter::new(File::create(&partial)?);
+    self.write(&mut wr)?;
+    wr.into_inner()?.sync_all()?;
+    std::fs::rename(partial, path)?;
+    Ok(())
+  }
+
+  fn write<W: Write>(&self, wr: &mut W) -> std::io::Result<()> {
+    encode::u128(wr, self.history.len() as u128)?;
+    for reply in &self.history {
+      encode::client_poll_reply(wr, reply)?;
+    }
+    encode::userlist(wr, &self.roster)?;
+    encode::u128(wr, self.outbox.len() as u128)?;
+    for message in &self.outbox {
+      encode::sequence(wr, message, encode::client)?;
+    }
+    Ok(())
+  }
+
+  fn read<R: std::io::Read>(rd: &mut R) -> anyhow::Result<Self> {
+    let mut store = Self::default();
+    for _ in 0..decode::u128(rd)? {
+      store.history.push_back(decode::client_poll_reply(rd)?);
+    }
+    store.roster = decode::userlist(rd)?;
+    for _ in 0..decode::u128(rd)? {
+      store
+        .outbox
+        .push_back(decode::sequence(rd, decode::client)?);
+    }
+    Ok(store)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::messages::{MessageId, ServerId};
+
+  #[test]
+  fn file_round_trip() {
+    let (a, b) = (ClientId::from(1), ClientId::from(2));
+    let path = std::env::temp_dir().join(format!("chat-store-{}", MessageId::default().0));
+    let mut store = LocalStore::open(&path).unwrap();
+    assert_eq!(store.history().count(), 0);
+
+    let message = ClientPollReply::Message {
+      src: b,
+      srcsrv: ServerId::from(3),
+      content: "hello".to_string(),
+      timestamp: 1_700_000_000_000,
+    };
+    assert!(store.record(&message));
+    assert!(!store.record(&ClientPollReply::Nothing));
+    store.set_roster(HashMap::from([(a, "a".to_string()), (b, "b".to_string())]));
+    let text = ClientMessage::Text {
+      dest: b,
+      content: "later".to_string(),
+    };
+    store.queue(Sequence {
+      seqid: 7,
+      src: a,
+      content: text.clone(),
+    });
+    store.save().unwrap();
+
+    let loaded = LocalStore::open(&path).unwrap();
+    assert_eq!(loaded.history().collect::<Vec<_>>(), [&message]);
+    assert_eq!(loaded.roster(), store.roster());
+    assert_eq!(loaded.pending().collect::<Vec<_>>(), [&text]);
+    assert_eq!(loaded.next_queued().map(|sq| sq.seqid), Some(7));
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn cache_size() {
+    let mut store = LocalStore::in_memory();
+    for n in 0..=CACHE_SIZE {
+      store.record(&ClientPollReply::Message {
+        src: ClientId::from(1),
+        srcsrv: ServerId::from(2),
+        content: n.to_string(),
+        timestamp: n as u64,
+      });
+    }
+    assert_eq!(store.history().count(), CACHE_SIZE);
+    assert!(matches!(
+      store.history().next(),
+      Some(ClientPollReply::Message { content, .. }) if content == "1"
+    ));
+    // nothing to write to
+    store.save().unwrap();
+  }
+}
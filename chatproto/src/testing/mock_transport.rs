@@ -0,0 +1,327 @@
+//! in-memory transport between servers, to test what depends on the transport without sockets
+//!
+//! A `MockNetwork` links any number of `MockTransport`, one per server. A frame waits in the
+//! inbox of its next hop until its latency elapsed. Like a datagram, it can be lost silently,
+//! and sending fails when the link is down, like a socket that can't reach its peer.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+
+use crate::federation::FederationTransport;
+use crate::messages::{ServerId, ServerMessage, ServerSequence};
+use crate::netproto::decode;
+use crate::rng::{OsRandom, Rng, SharedRng};
+
+struct Frame {
+  due: Instant,
+  src: ServerId,
+  dst: ServerId,
+  bytes: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Network {
+  // ordered by due time, in flight to every server
+  frames: VecDeque<Frame>,
+  latency: Duration,
+  // probability of losing a frame
+  loss: f64,
+  // the next frames are lost, whatever the loss
+  drops: usize,
+  // links that are down, both ways
+  down: HashSet<(ServerId, ServerId)>,
+  sent: usize,
+  lost: usize,
+  // draws the losses, the OS generator when missing
+  rng: Option<SharedRng>,
+}
+
+#[derive(Clone, Default)]
+pub struct MockNetwork(Arc<Mutex<Network>>);
+
+impl MockNetwork {
+  /// the transport of the server `me`
+  pub fn transport(&self, me: ServerId) -> MockTransport {
+    MockTransport {
+      me,
+      network: self.clone(),
+    }
+  }
+
+  /// delay of the frames sent from now on
+  pub async fn set_latency(&self, latency: Duration) {
+    self.0.lock().await.latency = latency;
+  }
+
+  /// probability of losing each frame, between 0 and 1
+  pub async fn set_loss(&self, loss: f64) {
+    self.0.lock().await.loss = loss;
+  }
+
+  /// draws the losses from `rng`, to lose the same frames every time
+  pub async fn set_rng(&self, rng: SharedRng) {
+    self.0.lock().await.rng = Some(rng);
+  }
+
+  /// the next `count` frames are lost
+  pub async fn drop_next(&self, count: usize) {
+    self.0.lock().await.drops = count;
+  }
+
+  /// brings the link between `a` and `b` down (sending fails) or back up
+  pub async fn set_link(&self, a: ServerId, b: ServerId, up: bool) {
+    let down = &mut self.0.lock().await.down;
+    for link in [(a, b), (b, a)] {
+      if up {
+        down.remove(&link);
+      } else {
+        down.insert(link);
+      }
+    }
+  }
+
+  /// frames accepted by the transports, and how many of them were lost
+  pub async fn stats(&self) -> (usize, usize) {
+    let network = self.0.lock().await;
+    (network.sent, network.lost)
+  }
+}
+
+pub struct MockTransport {
+  me: ServerId,
+  network: MockNetwork,
+}
+
+impl MockTransport {
+  pub fn id(&self) -> ServerId {
+    self.me
+  }
+
+  /// next frame for this server, with its sender, waiting for its latency
+  /// returns `None` when nothing is in flight to this server
+  pub async fn recv(&self) -> Option<(ServerId, Vec<u8>)> {
+    loop {
+      let due = {
+        let mut network = self.network.0.lock().await;
+        let position = network.frames.iter().position(|f| f.dst == self.me)?;
+        let due = network.frames[position].due;
+        if due <= Instant::now() {
+          let frame = network.frames.remove(position)?;
+          return Some((frame.src, frame.bytes));
+        }
+        due
+      };
+      async_std::task::sleep(due.saturating_duration_since(Instant::now())).await;
+    }
+  }
+
+  /// next frame for this server, decoded
+  pub async fn recv_sequence(&self) -> Option<anyhow::Result<ServerSequence<ServerMessage>>> {
+    let (_, frame) = self.recv().await?;
+    Some(decode::server_sequence(
+      &mut Cursor::new(frame),
+      decode::server,
+    ))
+  }
+
+  /// frames in flight to this server
+  pub async fn in_flight(&self) -> usize {
+    let network = self.network.0.lock().await;
+    network.frames.iter().filter(|f| f.dst == self.me).count()
+  }
+}
+
+#[async_trait]
+impl FederationTransport for MockTransport {
+  async fn send_frame(&self, nexthop: ServerId, frame: Vec<u8>) -> anyhow::Result<()> {
+    let mut network = self.network.0.lock().await;
+    if network.down.contains(&(self.me, nexthop)) {
+      anyhow::bail!("link from {} to {} is down", self.me, nexthop);
+    }
+    network.sent += 1;
+    if network.drops > 0 {
+      network.drops -= 1;
+      network.lost += 1;
+      return Ok(());
+    }
+    let rng = network.rng.as_deref().unwrap_or(&OsRandom as &dyn Rng);
+    if network.loss > 0.0 && rng.unit() < network.loss {
+      network.lost += 1;
+      return Ok(());
+    }
+    let due = Instant::now() + network.latency;
+    // a lower latency lets a frame overtake the ones in flight
+    let position = network.frames.partition_point(|f| f.due <= due);
+    network.frames.insert(
+      position,
+      Frame {
+        due,
+        src: self.me,
+        dst: nexthop,
+        bytes: frame,
+      },
+    );
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::federation::{FederationDriver, COALESCE_WINDOW};
+  use crate::messages::ClientId;
+  use crate::rng::SeededRng;
+
+  fn servers() -> (ServerId, ServerId) {
+    (ServerId::from(1), ServerId::from(2))
+  }
+
+  #[test]
+  fn latency() {
+    async_std::task::block_on(async {
+      let (s1, s2) = servers();
+      let network = MockNetwork::default();
+      let (t1, t2) = (network.transport(s1), network.transport(s2));
+      network.set_latency(Duration::from_millis(50)).await;
+      t1.send_frame(s2, vec![1]).await.unwrap();
+      network.set_latency(Duration::ZERO).await;
+      t1.send_frame(s2, vec![2]).await.unwrap();
+      assert_eq!(t1.recv().await, None);
+      assert_eq!(t2.in_flight().await, 2);
+
+      let start = Instant::now();
+      assert_eq!(t2.recv().await, Some((s1, vec![2])));
+      assert_eq!(t2.recv().await, Some((s1, vec![1])));
+      assert!(start.elapsed() >= Duration::from_millis(40));
+      assert_eq!(t2.recv().await, None);
+    })
+  }
+
+  #[test]
+  fn drops() {
+    async_std::task::block_on(async {
+      let (s1, s2) = servers();
+      let network = MockNetwork::default();
+      let (t1, t2) = (network.transport(s1), network.transport(s2));
+      network.drop_next(2).await;
+      for i in 0..3 {
+        t1.send_frame(s2, vec![i]).await.unwrap();
+      }
+      network.set_loss(1.0).await;
+      t1.send_frame(s2, vec![3]).await.unwrap();
+      network.set_loss(0.0).await;
+      network.set_link(s2, s1, false).await;
+      assert!(t1.send_frame(s2, vec![4]).await.is_err());
+      network.set_link(s1, s2, true).await;
+      t1.send_frame(s2, vec![5]).await.unwrap();
+
+      assert_eq!(network.stats().await, (5, 3));
+      assert_eq!(t2.recv().await, Some((s1, vec![2])));
+      assert_eq!(t2.recv().await, Some((s1, vec![5])));
+      assert_eq!(t2.recv().await, None);
+    })
+  }
+
+  #[test]
+  fn seeded_losses() {
+    async_std::task::block_on(async {
+      let (s1, s2) = servers();
+      let received = || async {
+        let network = MockNetwork::default();
+        network.set_rng(Arc::new(SeededRng::new(3))).await;
+        network.set_loss(0.5).await;
+        let (t1, t2) = (network.transport(s1), network.transport(s2));
+        for i in 0..20 {
+          t1.send_frame(s2, vec![i]).await.unwrap();
+        }
+        let mut received = Vec::new();
+        while let Some((_, frame)) = t2.recv().await {
+          received.extend(frame);
+        }
+        received
+      };
+      let first = received().await;
+      assert!(!first.is_empty() && first.len() < 20, "{:?}", first);
+      assert_eq!(received().await, first);
+    })
+  }
+
+  // two federation drivers, one announcing itself to the other, that acknowledges it
+  #[test]
+  fn resume_session() {
+    async_std::task::block_on(async {
+      let (s1, s2) = servers();
+      let network = MockNetwork::default();
+      let d1 = FederationDriver::new(s1, network.transport(s1), COALESCE_WINDOW);
+      let d2 = FederationDriver::new(s2, network.transport(s2), COALESCE_WINDOW);
+      let announce = ServerMessage::Announce {
+        route: vec![s1],
+        clients: HashMap::from([(ClientId::from(1), "alice".to_string())]),
+      };
+      // hands everything in flight to the driver at the other end, until nothing is left
+      let exchange = || async {
+        let mut received = Vec::new();
+        loop {
+          d1.flush().await.unwrap();
+          d2.flush().await.unwrap();
+          let mut idle = true;
+          for driver in [&d1, &d2] {
+            while let Some(sequence) = driver.transport().recv_sequence().await {
+              let sequence = sequence.unwrap();
+              let received_by = driver.transport().id();
+              idle = false;
+              if let Some(m) = driver
+                .receive(sequence.src, sequence.seqid, sequence.content)
+                .await
+              {
+                received.push((received_by, m));
+              }
+            }
+          }
+          if idle {
+            return received;
+          }
+        }
+      };
+
+      d1.link_up(s2, announce.clone()).await;
+      assert_eq!(exchange().await, [(s2, announce.clone())]);
+      // acknowledged, the announce is not sent again
+      d1.link_up(s2, announce.clone()).await;
+      assert_eq!(exchange().await, []);
+      assert_eq!(network.stats().await, (4, 0));
+
+      // the resume is lost, and the announce stays acknowledged
+      network.drop_next(1).await;
+      d1.link_up(s2, announce.clone()).await;
+      assert_eq!(exchange().await, []);
+      assert_eq!(network.stats().await, (5, 1));
+
+      // a new server at the other end does not know the session
+      let d2 = FederationDriver::new(s2, network.transport(s2), COALESCE_WINDOW);
+      d1.link_up(s2, announce.clone()).await;
+      d1.flush().await.unwrap();
+      let sequence = d2.transport().recv_sequence().await.unwrap().unwrap();
+      assert!(matches!(sequence.content, ServerMessage::Resume { .. }));
+      assert_eq!(
+        d2.receive(sequence.src, sequence.seqid, sequence.content)
+          .await,
+        None
+      );
+      d2.flush().await.unwrap();
+      let sequence = d1.transport().recv_sequence().await.unwrap().unwrap();
+      d1.receive(sequence.src, sequence.seqid, sequence.content)
+        .await;
+      d1.flush().await.unwrap();
+      let sequence = d2.transport().recv_sequence().await.unwrap().unwrap();
+      assert_eq!(sequence.content, announce);
+    })
+  }
+}
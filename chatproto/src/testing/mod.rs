@@ -0,0 +1,4209 @@
+pub mod mock_transport;
+
+use std::{
+  collections::{HashMap, HashSet},
+  net::IpAddr,
+  time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_std::task::sleep;
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use crate::{archive::now_ms, client::Client, core::*, messages::*};
+
+fn localhost() -> IpAddr {
+  "127.0.0.1".parse().unwrap()
+}
+
+/// the mailbox size of the servers under test
+pub fn mailbox_size() -> usize {
+  ServerConfig::default().mailbox_size
+}
+
+// id the server gave to the message of the first reply
+fn first_id(replies: &[ClientReply]) -> anyhow::Result<MessageId> {
+  match replies.first() {
+    Some(ClientReply::Delivered(Some(id)))
+    | Some(ClientReply::Delayed(id))
+    | Some(ClientReply::Transfer(_, _, Some(id))) => Ok(*id),
+    r => anyhow::bail!("Expected a message id, got {:?}", r),
+  }
+}
+
+/// what the server sent, with the timestamps tests can't know in advance set to 0
+pub(crate) trait Unstamped {
+  fn unstamped(self) -> Self;
+}
+
+impl Unstamped for ClientPollReply {
+  fn unstamped(self) -> Self {
+    match self {
+      ClientPollReply::Message {
+        src,
+        srcsrv,
+        content,
+        ..
+      } => ClientPollReply::Message {
+        src,
+        srcsrv,
+        content,
+        timestamp: 0,
+      },
+      ClientPollReply::ReplyTo {
+        in_reply_to,
+        message,
+      } => ClientPollReply::ReplyTo {
+        in_reply_to,
+        message: Box::new(message.unstamped()),
+      },
+      reply => reply,
+    }
+  }
+}
+
+impl Unstamped for HistoryEntry {
+  fn unstamped(self) -> Self {
+    HistoryEntry {
+      message: self.message.unstamped(),
+      ..self
+    }
+  }
+}
+
+impl Unstamped for FullyQualifiedMessage {
+  fn unstamped(self) -> Self {
+    FullyQualifiedMessage {
+      timestamp: 0,
+      ..self
+    }
+  }
+}
+
+impl Unstamped for ServerMessage {
+  fn unstamped(self) -> Self {
+    match self {
+      ServerMessage::Message(m) => ServerMessage::Message(m.unstamped()),
+      ServerMessage::Batch(ms) => ServerMessage::Batch(ms.unstamped()),
+      m => m,
+    }
+  }
+}
+
+impl<A: Unstamped> Unstamped for Outgoing<A> {
+  fn unstamped(self) -> Self {
+    Outgoing {
+      nexthop: self.nexthop,
+      message: self.message.unstamped(),
+    }
+  }
+}
+
+impl Unstamped for ClientReply {
+  fn unstamped(self) -> Self {
+    match self {
+      ClientReply::Transfer(nexthop, m, id) => ClientReply::Transfer(nexthop, m.unstamped(), id),
+      r => r,
+    }
+  }
+}
+
+impl Unstamped for ServerReply {
+  fn unstamped(self) -> Self {
+    match self {
+      ServerReply::Outgoing(o) => ServerReply::Outgoing(o.unstamped()),
+      ServerReply::Forward(o) => ServerReply::Forward(o.unstamped()),
+      r => r,
+    }
+  }
+}
+
+impl<T: Unstamped> Unstamped for Vec<T> {
+  fn unstamped(self) -> Self {
+    self.into_iter().map(Unstamped::unstamped).collect()
+  }
+}
+
+enum TestCheckerMode {
+  Standard,
+  Set {
+    ip: bool,
+    user: bool,
+  },
+  DelayIp,
+  DelayUser,
+  /// accepts everyone, after a while
+  Slow(Duration),
+}
+
+pub struct TestChecker {
+  mode: TestCheckerMode,
+}
+
+impl TestChecker {
+  fn new(mode: TestCheckerMode) -> Self {
+    Self { mode }
+  }
+}
+
+impl Default for TestChecker {
+  fn default() -> Self {
+    Self::new(TestCheckerMode::Standard)
+  }
+}
+
+#[async_trait]
+impl SpamChecker for TestChecker {
+  async fn is_user_spammer(&self, _name: &str) -> bool {
+    match self.mode {
+      TestCheckerMode::Standard => false,
+      TestCheckerMode::Set { ip: _, user } => user,
+      TestCheckerMode::DelayIp => true,
+      TestCheckerMode::Slow(delay) => {
+        sleep(delay).await;
+        false
+      }
+      TestCheckerMode::DelayUser => {
+        sleep(Duration::from_secs(10)).await;
+        panic!("should not happen, you did not handle spamming checks in parallel")
+      }
+    }
+  }
+  async fn is_ip_spammer(&self, _name: &IpAddr) -> bool {
+    match self.mode {
+      TestCheckerMode::Standard => false,
+      TestCheckerMode::Set { ip, user: _ } => ip,
+      TestCheckerMode::DelayUser => true,
+      TestCheckerMode::Slow(delay) => {
+        sleep(delay).await;
+        false
+      }
+      TestCheckerMode::DelayIp => {
+        sleep(Duration::from_secs(10)).await;
+        panic!("should not happen, you did not handle spamming checks in parallel")
+      }
+    }
+  }
+}
+
+async fn spammer_both<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::Set {
+      ip: true,
+      user: true,
+    }),
+    sid,
+    ServerConfig::default(),
+  );
+  if server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .is_ok()
+  {
+    anyhow::bail!("should have been recognized as spammer")
+  }
+  Ok(())
+}
+
+async fn spammer_ip<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::Set {
+      ip: true,
+      user: false,
+    }),
+    sid,
+    ServerConfig::default(),
+  );
+  if server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .is_ok()
+  {
+    anyhow::bail!("should have been recognized as spammer")
+  }
+  Ok(())
+}
+
+async fn spammer_user<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::Set {
+      ip: false,
+      user: true,
+    }),
+    sid,
+    ServerConfig::default(),
+  );
+  if server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .is_ok()
+  {
+    anyhow::bail!("should have been recognized as spammer")
+  }
+  Ok(())
+}
+
+async fn spammer_delay_ip<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::DelayIp),
+    sid,
+    ServerConfig::default(),
+  );
+  if server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .is_ok()
+  {
+    anyhow::bail!("should have been recognized as spammer")
+  }
+  Ok(())
+}
+
+async fn spammer_delay_user<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::DelayUser),
+    sid,
+    ServerConfig::default(),
+  );
+  if server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .is_ok()
+  {
+    anyhow::bail!("should have been recognized as spammer")
+  }
+  Ok(())
+}
+
+async fn registration_storm<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::Slow(Duration::from_millis(10))),
+    sid,
+    ServerConfig::default(),
+  );
+  let total = REGISTRATION_CONCURRENCY + REGISTRATION_QUEUE + 8;
+  let results = futures::future::join_all(
+    (0..total).map(|n| server.register_local_client(localhost(), format!("user {n}"))),
+  )
+  .await;
+  let busy = results
+    .iter()
+    .filter(|r| **r == Err(ClientError::ServerBusy))
+    .count();
+  let registered = results.iter().filter(|r| r.is_ok()).count();
+  if busy == 0 || busy + registered != total {
+    anyhow::bail!(
+      "Expected some ServerBusy and only successes otherwise, got {} busy and {} registered",
+      busy,
+      registered
+    );
+  }
+  Ok(())
+}
+
+async fn sequence_correct<M: MessageServer<TestChecker>>() -> Result<(), ClientError> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user2".to_string())
+    .await
+    .unwrap();
+  let mut client1 = Client::new(c1);
+  let mut client2 = Client::new(c2);
+
+  // send 1000 messages, correctly sequenced
+  for i in 0..100 {
+    let message = if i & 1 == 0 {
+      client1.sequence(())
+    } else {
+      client2.sequence(())
+    };
+    server.handle_sequenced_message(message).await?;
+  }
+  Ok(())
+}
+
+async fn sequence_unknown_user<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = ClientId::default();
+  let mut client1 = Client::new(c1);
+
+  let message = client1.sequence(());
+  match server.handle_sequenced_message(message).await {
+    Err(ClientError::UnknownClient) => Ok(()),
+    r => anyhow::bail!("Expected Err(UnknownClient), but got {:?}", r),
+  }
+}
+
+async fn sequence_window<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user1".to_string())
+    .await
+    .unwrap();
+  let seq = |seqid| Sequence {
+    seqid,
+    src: c1,
+    content: (),
+  };
+  let top = SEQUENCE_WINDOW as u128 + 10;
+
+  // retries arrive out of order, each number is accepted once
+  let accept = |seqids: Vec<u128>| async {
+    for seqid in seqids {
+      server
+        .handle_sequenced_message(seq(seqid))
+        .await
+        .with_context(|| format!("seqid {}", seqid))?;
+    }
+    anyhow::Ok(())
+  };
+  let replayed = |seqids: Vec<u128>| async {
+    for seqid in seqids {
+      match server.handle_sequenced_message(seq(seqid)).await {
+        Err(ClientError::Replayed(s)) if s == seqid => (),
+        r => anyhow::bail!("Expected Err(Replayed({})), but got {:?}", seqid, r),
+      }
+    }
+    anyhow::Ok(())
+  };
+  accept(vec![3, 1, 2]).await?;
+  // 0 is the registration
+  replayed(vec![0, 1, 3]).await?;
+  accept(vec![top, 11]).await?;
+  replayed(vec![11, top]).await?;
+  // too old to know, refused all the same
+  let stale = ClientError::SequenceReuse {
+    expected: top + 1,
+    got: 10,
+  };
+  match server.handle_sequenced_message(seq(10)).await {
+    Err(rr) if rr == stale => (),
+    r => anyhow::bail!("Expected Err({}) below the window, but got {:?}", stale, r),
+  }
+  Ok(())
+}
+
+async fn simple_client_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "hello".into(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+    anyhow::bail!("expected a single delivered message, got {:?}", r)
+  }
+  let reply = server.client_poll(c2).await.unstamped();
+  let expected = ClientPollReply::Message {
+    src: c1,
+    srcsrv: sid,
+    content: "hello".into(),
+    timestamp: 0,
+  };
+  if reply != expected {
+    anyhow::bail!(
+      "Did not receive expected message, expected {:?}, received {:?}",
+      expected,
+      reply
+    );
+  }
+  Ok(())
+}
+
+async fn list_users_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let mut usermap = HashMap::new();
+  for n in 0..100_u32 {
+    let username = format!("user {n}");
+    let id = server
+      .register_local_client(localhost(), username.clone())
+      .await
+      .unwrap();
+    usermap.insert(id, username);
+  }
+  let actual = server.list_users().await;
+
+  if actual != usermap {
+    anyhow::bail!("Incorrect user map");
+  }
+  Ok(())
+}
+
+/// pages through the users, with and without a filter, and with the remote users
+async fn list_users_page_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  for n in 0..100_u32 {
+    server
+      .register_local_client(localhost(), format!("user {n}"))
+      .await?;
+  }
+  let (remote, rsid) = (ClientId::default(), ServerId::default());
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![rsid],
+      clients: HashMap::from([(remote, "user 1000".into())]),
+    })
+    .await;
+  let pages = |filter: Option<NameFilter>, federated| {
+    let server = &server;
+    async move {
+      let mut query = UserQuery {
+        filter,
+        after: None,
+        limit: 30,
+        federated,
+      };
+      let mut users = Vec::new();
+      loop {
+        let page = server.list_users_page(&query).await;
+        if page.users.len() > 30 {
+          anyhow::bail!("page of {} users", page.users.len());
+        }
+        users.extend(page.users);
+        match page.next {
+          Some(next) => query.after = Some(next),
+          None => return Ok(users),
+        }
+      }
+    }
+  };
+
+  let users = pages(None, false).await?;
+  if !users.windows(2).all(|w| w[0].id < w[1].id) {
+    anyhow::bail!("users are not in the order of their ids");
+  }
+  if users.iter().any(|u| u.server != sid) {
+    anyhow::bail!("local users should be on the server");
+  }
+  let users: HashMap<ClientId, String> = users.into_iter().map(|u| (u.id, u.name)).collect();
+  if users != server.list_users().await {
+    anyhow::bail!("pages do not hold every user");
+  }
+
+  let mut names: Vec<String> = pages(Some(NameFilter::Prefix("USER 1".into())), false)
+    .await?
+    .into_iter()
+    .map(|u| u.name)
+    .collect();
+  names.sort();
+  let mut expected: Vec<String> = (0..100)
+    .map(|n| format!("user {n}"))
+    .filter(|name| name.starts_with("user 1"))
+    .collect();
+  expected.sort();
+  if names != expected {
+    anyhow::bail!("prefix filter: {:?}", names);
+  }
+  let found = pages(Some(NameFilter::Contains("er 42".into())), false).await?;
+  if found.len() != 1 || found[0].name != "user 42" {
+    anyhow::bail!("substring filter: {:?}", found);
+  }
+
+  // remote users are only listed on demand, with their server
+  let found = pages(Some(NameFilter::Contains("1000".into())), true).await?;
+  if found.len() != 1 || found[0].id != remote || found[0].server != rsid {
+    anyhow::bail!("federated listing: {:?}", found);
+  }
+  if pages(None, true).await?.len() != 101 {
+    anyhow::bail!("federated pages do not hold every user");
+  }
+  Ok(())
+}
+
+/// names are unique among local clients, and can be looked up
+async fn unique_names_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let alice = server
+    .register_local_client(localhost(), "alice".into())
+    .await?;
+  let bob = server.register_guest(localhost(), "bob".into()).await?;
+  for name in ["alice", " Alice"] {
+    let r = server.register_local_client(localhost(), name.into()).await;
+    if r != Err(ClientError::NameTaken(alice)) {
+      anyhow::bail!("registered {:?} twice: {:?}", name, r);
+    }
+  }
+  let r = server.rename_client(bob, "ALICE".into()).await;
+  if r != [ClientReply::Error(ClientError::NameTaken(alice))] {
+    anyhow::bail!("renamed to a taken name: {:?}", r);
+  }
+  // a client can change the case of its own name
+  let r = server.rename_client(alice, "Alice".into()).await;
+  if r.first() != Some(&ClientReply::Delivered(None)) {
+    anyhow::bail!("could not rename to the same name: {:?}", r);
+  }
+
+  let found = (
+    server.lookup_user("alice").await,
+    server.lookup_user("bob").await,
+    server.lookup_user("carol").await,
+  );
+  if found != (Some(alice), Some(bob), None) {
+    anyhow::bail!("lookup_user: {:?}", found);
+  }
+
+  // the name is free again once its client is gone
+  server.unregister_local_client(alice).await;
+  if server.lookup_user("alice").await.is_some() {
+    anyhow::bail!("unregistered client still found");
+  }
+  server
+    .register_local_client(localhost(), "alice".into())
+    .await?;
+  Ok(())
+}
+
+/// sends 100 single messages, and 100 multiple recipients messages
+async fn multiple_client_messages_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let c3 = server
+    .register_local_client(localhost(), "user 3".to_string())
+    .await
+    .unwrap();
+  for i in 0..100 {
+    let r = server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: c2,
+          content: i.to_string(),
+        },
+      )
+      .await;
+    if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+      anyhow::bail!("A> Could not deliver message {}, got {:?}", i, r);
+    }
+  }
+  for i in 0..100 {
+    let r = server
+      .handle_client_message(
+        c1,
+        ClientMessage::MText {
+          dest: vec![c2, c3],
+          content: (i + 100).to_string(),
+        },
+      )
+      .await;
+    if !matches!(
+      &r[..],
+      [ClientReply::Multi(each)] if matches!(
+        each[..],
+        [
+          (d2, ClientReply::Delivered(Some(_))),
+          (d3, ClientReply::Delivered(Some(_)))
+        ] if d2 == c2 && d3 == c3
+      )
+    ) {
+      anyhow::bail!("B> Could not deliver message {}, got {:?}", i, r);
+    }
+  }
+
+  for i in 0..200 {
+    let reply = server.client_poll(c2).await.unstamped();
+    let expected_reply = ClientPollReply::Message {
+      src: c1,
+      srcsrv: sid,
+      content: i.to_string(),
+      timestamp: 0,
+    };
+    if reply != expected_reply {
+      anyhow::bail!(
+        "A> Did not receive expected message {}, received {:?}",
+        i,
+        reply
+      );
+    }
+  }
+  for i in 100..200 {
+    let reply = server.client_poll(c3).await.unstamped();
+    let expected_reply = ClientPollReply::Message {
+      src: c1,
+      srcsrv: sid,
+      content: i.to_string(),
+      timestamp: 0,
+    };
+    if reply != expected_reply {
+      anyhow::bail!(
+        "B> Did not receive expected message {}, received {:?}",
+        i,
+        reply
+      );
+    }
+  }
+  let reply = server.client_poll(c2).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!(
+      "Did not receive the expected Nothing (for client 2) reply, got {:?}",
+      reply
+    );
+  }
+  let reply = server.client_poll(c3).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!(
+      "Did not receive the expected Nothing (for client 3) reply, got {:?}",
+      reply
+    );
+  }
+  Ok(())
+}
+
+async fn rich_message_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let users = server.list_users().await;
+  let content = RichContent::parse("hello @user 2, meet @user 1!", &users);
+  if content.mentions().collect::<Vec<_>>() != [(c2, "@user 2"), (c1, "@user 1")] {
+    anyhow::bail!("Unexpected mentions {:?}", content.mentions);
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Rich {
+        dest: vec![c2],
+        content: content.clone(),
+      },
+    )
+    .await;
+  let [ClientReply::Delivered(Some(id))] = r[..] else {
+    anyhow::bail!("expected a single delivered message, got {:?}", r)
+  };
+  // the mentioned recipient is told first
+  let reply = server.client_poll(c2).await;
+  let expected = ClientPollReply::Event {
+    src: c1,
+    event: Event::Mentioned(id),
+  };
+  if reply != expected {
+    anyhow::bail!("Expected {:?}, received {:?}", expected, reply);
+  }
+  let reply = server.client_poll(c2).await;
+  let expected = ClientPollReply::RichMessage { src: c1, content };
+  if reply != expected {
+    anyhow::bail!("Expected {:?}, received {:?}", expected, reply);
+  }
+  Ok(())
+}
+
+async fn mixed_results_client_message<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let c3 = ClientId::default();
+
+  let m = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3],
+        content: "Hello".to_string(),
+      },
+    )
+    .await;
+  if !matches!(
+    &m[..],
+    [ClientReply::Multi(each)] if matches!(
+      each[..],
+      [(_, ClientReply::Delivered(Some(_))), (_, ClientReply::Delayed(_))]
+    )
+  ) {
+    anyhow::bail!("Expected Delivered/Delayed, but got {:?}", m)
+  }
+  Ok(())
+}
+
+async fn mailbox_full<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+
+  for n in 0..mailbox_size() {
+    let m = server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: c2,
+          content: format!("{n}"),
+        },
+      )
+      .await;
+    if !matches!(m[..], [ClientReply::Delivered(Some(_))]) {
+      anyhow::bail!("Expected Delivered, but got {:?}", m)
+    }
+  }
+  let m = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "FULL".into(),
+      },
+    )
+    .await;
+  if m != [ClientReply::Error(ClientError::BoxFull(c2))] {
+    anyhow::bail!("Expected BoxFull, but got {:?}", m)
+  }
+  Ok(())
+}
+
+async fn message_to_outer_user<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let s2 = ServerId::default();
+  let s3 = ServerId::default();
+  let euuid = ClientId::default();
+
+  log::debug!("route: {} -> {} -> {} -> us", s1, s2, s3);
+
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1, s2, s3],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected empty outgoing answer, got {:?}", r);
+  }
+  assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: euuid,
+        content: "Hello".to_string(),
+      },
+    )
+    .await;
+  let expected = [ClientReply::Transfer(
+    NextHop(s3),
+    ServerMessage::Message(FullyQualifiedMessage {
+      src: c1,
+      srcsrv: sid,
+      dsts: vec![(euuid, s1)],
+      content: "Hello".to_string(),
+      content_type: ContentType::Plain,
+      mentions: Vec::new(),
+      seq: 1,
+      in_reply_to: None,
+      timestamp: 0,
+      id: first_id(&r)?,
+    }),
+    Some(first_id(&r)?),
+  )];
+
+  if r.clone().unstamped() != expected {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+
+  Ok(())
+}
+
+async fn message_to_outer_user_delayed<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let s2 = ServerId::default();
+  let s3 = ServerId::default();
+  let euuid = ClientId::default();
+
+  log::debug!("route: {} -> {} -> {} -> us", s1, s2, s3);
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: euuid,
+        content: "Hello".to_string(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Delayed(_)]) {
+    anyhow::bail!("Expected a delayed message first, but got {:?}", r);
+  }
+  let delayed = first_id(&r)?;
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1, s2, s3],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  let expected = ServerReply::Outgoing(vec![Outgoing {
+    nexthop: NextHop(s3),
+    message: FullyQualifiedMessage {
+      src: c1,
+      srcsrv: sid,
+      dsts: vec![(euuid, s1)],
+      content: "Hello".to_string(),
+      content_type: ContentType::Plain,
+      mentions: Vec::new(),
+      seq: 1,
+      in_reply_to: None,
+      timestamp: 0,
+      id: delayed,
+    },
+  }]);
+  if r.clone().unstamped() != expected {
+    anyhow::bail!("Expected {:?}\n,    got {:?}", expected, r);
+  }
+
+  Ok(())
+}
+
+async fn sender_order_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  let text = |content: &str| ClientMessage::Text {
+    dest: euuid,
+    content: content.to_string(),
+  };
+
+  // the delayed message, and the one sent once the recipient is known, follow different paths
+  server.handle_client_message(c1, text("first")).await;
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  let ServerReply::Outgoing(flushed) = r else {
+    anyhow::bail!("Expected the delayed message, got {:?}", r);
+  };
+  let r = server.handle_client_message(c1, text("second")).await;
+  let seqs: Vec<u128> = flushed
+    .iter()
+    .map(|o| o.message.seq)
+    .chain(r.iter().filter_map(|reply| match reply {
+      ClientReply::Transfer(_, ServerMessage::Message(m), _) => Some(m.seq),
+      _ => None,
+    }))
+    .collect();
+  if seqs != [1, 2] {
+    anyhow::bail!("Expected the messages to be sequenced, got {:?}", seqs);
+  }
+
+  // forwarded messages that overtook each other are polled in order
+  let forwarded = |content: &str, seq| {
+    FullyQualifiedMessage::builder(euuid, s1)
+      .to(c1, sid)
+      .content(content.to_string())
+      .sequenced(seq)
+      .build()
+      .map(ServerMessage::Message)
+  };
+  server.handle_server_message(forwarded("two", 2)?).await;
+  server.handle_server_message(forwarded("three", 3)?).await;
+  server.handle_server_message(forwarded("one", 1)?).await;
+  let polled = |content: &str| ClientPollReply::Message {
+    src: euuid,
+    srcsrv: s1,
+    content: content.to_string(),
+    timestamp: 0,
+  };
+  let r = server.client_poll_n(c1, 4).await;
+  let expected = [polled("one"), polled("two"), polled("three")];
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+
+  // unsequenced messages are never held
+  server.handle_server_message(forwarded("any", 0)?).await;
+  let r = server.client_poll(c1).await;
+  if r != polled("any") {
+    anyhow::bail!("Expected the unsequenced message, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn provenance_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  let text = |dest, content: &str| ClientMessage::Text {
+    dest,
+    content: content.to_string(),
+  };
+
+  // local messages are stamped by us when they are sent, not when they are polled
+  let before = now_ms();
+  let r = server.handle_client_message(c1, text(c2, "hi")).await;
+  let id = first_id(&r)?;
+  let after = now_ms();
+  sleep(Duration::from_millis(5)).await;
+  let stamp = match server.client_poll(c2).await {
+    ClientPollReply::Message {
+      src,
+      srcsrv,
+      timestamp,
+      ..
+    } if src == c1 && srcsrv == sid && (before..=after).contains(&timestamp) => timestamp,
+    r => anyhow::bail!("Expected a message stamped by {}, got {:?}", sid, r),
+  };
+  // and keep their stamp when they are edited
+  server
+    .handle_client_message(
+      c1,
+      ClientMessage::Edit {
+        dest: c2,
+        id,
+        content: "hello".to_string(),
+      },
+    )
+    .await;
+  let history = server.client_history(c2, 1, None, false).await?;
+  if !matches!(&history[..], [HistoryEntry { message: ClientPollReply::Message { content, timestamp, .. }, .. }]
+    if content == "hello" && *timestamp == stamp)
+  {
+    anyhow::bail!("Expected the edit to keep the timestamp, got {:?}", history);
+  }
+
+  // the stamp travels with federated messages, delayed ones keep the time they were sent
+  let before = now_ms();
+  let r = server.handle_client_message(c1, text(euuid, "later")).await;
+  if !matches!(r[..], [ClientReply::Delayed(_)]) {
+    anyhow::bail!("Expected a delayed message, got {:?}", r);
+  }
+  let after = now_ms();
+  sleep(Duration::from_millis(5)).await;
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  if !matches!(&r, ServerReply::Outgoing(o) if o.len() == 1 && (before..=after).contains(&o[0].message.timestamp))
+  {
+    anyhow::bail!(
+      "Expected the flushed message with its timestamp, got {:?}",
+      r
+    );
+  }
+  let incoming = FullyQualifiedMessage::builder(euuid, s1)
+    .to(c1, sid)
+    .content("hello".to_string())
+    .timestamp(42)
+    .build()?;
+  server
+    .handle_server_message(ServerMessage::Message(incoming))
+    .await;
+  let expected = ClientPollReply::Message {
+    src: euuid,
+    srcsrv: s1,
+    content: "hello".to_string(),
+    timestamp: 42,
+  };
+  let r = server.client_poll(c1).await;
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  Ok(())
+}
+
+async fn reply_to_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  let id = MessageId::default();
+  let reply = |message: ClientMessage| ClientMessage::WithId {
+    id: MessageId::from(1),
+    message: Box::new(ClientMessage::ReplyTo {
+      in_reply_to: id,
+      message: Box::new(message),
+    }),
+  };
+  let text = |dest, content: &str| ClientMessage::Text {
+    dest,
+    content: content.to_string(),
+  };
+
+  // delivered locally, the reply is threaded in the mailbox and in the history
+  server
+    .handle_client_message(c2, reply(text(c1, "yes")))
+    .await;
+  let expected = ClientPollReply::ReplyTo {
+    in_reply_to: id,
+    message: Box::new(ClientPollReply::Message {
+      src: c2,
+      srcsrv: sid,
+      content: "yes".to_string(),
+      timestamp: 0,
+    }),
+  };
+  let r = server.client_poll(c1).await.unstamped();
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  // an edit keeps the threading
+  server
+    .handle_client_message(
+      c2,
+      ClientMessage::Edit {
+        dest: c1,
+        id: MessageId::from(1),
+        content: "no".to_string(),
+      },
+    )
+    .await;
+  let history = server.client_history(c1, 1, None, false).await?;
+  let edited = history.first().map(|e| &e.message);
+  if edited.and_then(ClientPollReply::in_reply_to) != Some(id)
+    || !matches!(edited.map(ClientPollReply::unthreaded), Some(ClientPollReply::Message { content, .. }) if content == "no")
+  {
+    anyhow::bail!("Expected the edited reply, got {:?}", history);
+  }
+
+  // transferred, the id goes along with the message
+  let r = server
+    .handle_client_message(c1, reply(text(euuid, "remote")))
+    .await;
+  match &r[..] {
+    [ClientReply::Transfer(_, ServerMessage::Message(m), _)] if m.in_reply_to == Some(id) => (),
+    _ => anyhow::bail!("Expected a threaded transfer, got {:?}", r),
+  }
+  let forwarded = FullyQualifiedMessage::builder(euuid, s1)
+    .to(c2, sid)
+    .content("from afar".to_string())
+    .in_reply_to(Some(id))
+    .build()?;
+  server
+    .handle_server_message(ServerMessage::Message(forwarded))
+    .await;
+  let r = server.client_poll(c2).await;
+  if r.in_reply_to() != Some(id) {
+    anyhow::bail!("Expected the forwarded reply, got {:?}", r);
+  }
+
+  // only messages can be replies
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::ReplyTo {
+        in_reply_to: id,
+        message: Box::new(ClientMessage::Ack(id)),
+      },
+    )
+    .await;
+  if r != [ClientReply::Error(ClientError::Forbidden)] {
+    anyhow::bail!("Expected the threaded ack to be refused, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn delayed_queue<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+
+  let send = |i: usize| {
+    server.handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: euuid,
+        content: format!("message {}", i),
+      },
+    )
+  };
+  for i in 0..DELAYED_SIZE {
+    let r = send(i).await;
+    if !matches!(r[..], [ClientReply::Delayed(_)]) {
+      anyhow::bail!("Expected message {} to be delayed, got {:?}", i, r);
+    }
+  }
+  let r = send(DELAYED_SIZE).await;
+  if r != [ClientReply::Error(ClientError::BoxFull(euuid))] {
+    anyhow::bail!("Expected the delayed queue to be full, got {:?}", r);
+  }
+
+  // none of the kept messages is lost
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  let contents: Vec<String> = match r {
+    ServerReply::Outgoing(outgoing) => outgoing.into_iter().map(|o| o.message.content).collect(),
+    r => anyhow::bail!("Expected the delayed messages, got {:?}", r),
+  };
+  let expected: Vec<String> = (0..DELAYED_SIZE)
+    .map(|i| format!("message {}", i))
+    .collect();
+  if contents != expected {
+    anyhow::bail!("Expected {:?}\n,    got {:?}", expected, contents);
+  }
+  Ok(())
+}
+
+async fn expiry_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+
+  for dest in [c2, c2, euuid] {
+    server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest,
+          content: "Hello".to_string(),
+        },
+      )
+      .await;
+  }
+  let r = server.expire_messages(Instant::now()).await;
+  if r != 0 {
+    anyhow::bail!("Expected no message to expire yet, {} did", r);
+  }
+  let r = server
+    .expire_messages(Instant::now() + MESSAGE_TTL + Duration::from_secs(1))
+    .await;
+  if r != 3 {
+    anyhow::bail!("Expected 3 messages to expire, {} did", r);
+  }
+  let r = server.client_poll(c2).await;
+  if r != ClientPollReply::Nothing {
+    anyhow::bail!("Expected the expired messages to be gone, got {:?}", r);
+  }
+
+  // the sender is told once per recipient
+  let mut expired = Vec::new();
+  loop {
+    match server.client_poll(c1).await {
+      ClientPollReply::DelayedError(DelayedError::Expired(dest)) => expired.push(dest),
+      ClientPollReply::Nothing => break,
+      r => anyhow::bail!("Expected expiry notifications, got {:?}", r),
+    }
+  }
+  expired.sort();
+  let mut expected = vec![c2, euuid];
+  expected.sort();
+  if expired != expected {
+    anyhow::bail!(
+      "Expected expiry notifications for {:?}, got {:?}",
+      expected,
+      expired
+    );
+  }
+
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected the delayed message to be gone, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn content_type_federation<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+
+  // outgoing: the content type is carried in the federated message
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Rich {
+        dest: vec![euuid],
+        content: RichContent::default()
+          .text("*bold*")
+          .with_content_type(ContentType::Markdown),
+      },
+    )
+    .await;
+  let expected = [ClientReply::Transfer(
+    NextHop(s1),
+    ServerMessage::Message(FullyQualifiedMessage {
+      src: c1,
+      srcsrv: sid,
+      dsts: vec![(euuid, s1)],
+      content: "*bold*".to_string(),
+      content_type: ContentType::Markdown,
+      mentions: Vec::new(),
+      seq: 1,
+      in_reply_to: None,
+      timestamp: 0,
+      id: first_id(&r)?,
+    }),
+    Some(first_id(&r)?),
+  )];
+  if r.clone().unstamped() != expected {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+
+  // incoming: the local recipient gets it back as rich content
+  server
+    .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+      src: euuid,
+      srcsrv: s1,
+      dsts: vec![(c1, sid)],
+      content: "{}".to_string(),
+      content_type: ContentType::Custom("application/json".into()),
+      mentions: Vec::new(),
+      seq: 0,
+      in_reply_to: None,
+      timestamp: 0,
+      id: MessageId::default(),
+    }))
+    .await;
+  let reply = server.client_poll(c1).await;
+  let expected = ClientPollReply::RichMessage {
+    src: euuid,
+    content: RichContent::default()
+      .text("{}")
+      .with_content_type(ContentType::Custom("application/json".into())),
+  };
+  if reply != expected {
+    anyhow::bail!("Expected {:?}, received {:?}", expected, reply);
+  }
+  Ok(())
+}
+
+async fn batch_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  let (first, second) = (MessageId::default(), MessageId::default());
+  let message = |content: &str, id| FullyQualifiedMessage {
+    src: euuid,
+    srcsrv: s1,
+    dsts: vec![(c1, sid)],
+    content: content.to_string(),
+    content_type: ContentType::Plain,
+    mentions: Vec::new(),
+    seq: 0,
+    in_reply_to: None,
+    timestamp: 0,
+    id,
+  };
+
+  // a broken message in the middle does not stop the rest of the batch
+  let r = server
+    .handle_server_message(ServerMessage::Batch(vec![
+      ServerMessage::Announce {
+        route: vec![s1],
+        clients: HashMap::from([(euuid, "external user".into())]),
+      },
+      ServerMessage::Announce {
+        route: vec![],
+        clients: HashMap::new(),
+      },
+      ServerMessage::Batch(vec![ServerMessage::Message(message("first", first))]),
+      ServerMessage::Message(message("second", second)),
+    ]))
+    .await;
+  // both messages are confirmed to the server of the sender
+  let confirmed: Vec<_> = match &r {
+    ServerReply::Forward(outgoing) => outgoing
+      .iter()
+      .filter(|o| o.nexthop.server() == s1)
+      .map(|o| o.message.clone())
+      .collect(),
+    _ => Vec::new(),
+  };
+  let delivered = |id| ServerMessage::Delivered {
+    id,
+    src: euuid,
+    srcsrv: s1,
+    dst: c1,
+  };
+  if confirmed != [delivered(first), delivered(second)] {
+    anyhow::bail!("Expected two delivery confirmations, got {:?}", r);
+  }
+  for content in ["first", "second"] {
+    let reply = server.client_poll(c1).await;
+    let expected = ClientPollReply::Message {
+      src: euuid,
+      srcsrv: s1,
+      content: content.to_string(),
+      timestamp: 0,
+    };
+    if reply != expected {
+      anyhow::bail!("Expected {:?}, received {:?}", expected, reply);
+    }
+  }
+  Ok(())
+}
+
+async fn report_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+
+  let r = server
+    .handle_report(c1, ReportTarget::Client(c2), "spam".to_string())
+    .await;
+  if r != ClientReply::Delivered(None) {
+    anyhow::bail!("Expected the report to be accepted, got {:?}", r);
+  }
+  let r = server
+    .handle_report(
+      c1,
+      ReportTarget::Message(MessageId::default()),
+      "abuse".to_string(),
+    )
+    .await;
+  if r != ClientReply::Delivered(None) {
+    anyhow::bail!("Expected the report to be accepted, got {:?}", r);
+  }
+  let r = server
+    .handle_report(
+      c1,
+      ReportTarget::Client(ClientId::default()),
+      "spam".to_string(),
+    )
+    .await;
+  if r != ClientReply::Error(ClientError::UnknownClient) {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  let r = server
+    .handle_report(
+      c1,
+      ReportTarget::Client(c2),
+      "a".repeat(MAX_REPORT_REASON + 1),
+    )
+    .await;
+  if r != ClientReply::Error(ClientError::MessageTooLarge(MAX_REPORT_REASON as u128)) {
+    anyhow::bail!("Expected a too long reason to be refused, got {:?}", r);
+  }
+
+  // a reporter can't fill the store
+  for _ in 0..REPORTS_PER_REPORTER {
+    let r = server
+      .handle_report(c2, ReportTarget::Client(c1), "spam".to_string())
+      .await;
+    if r != ClientReply::Delivered(None) {
+      anyhow::bail!("Expected the report to be accepted, got {:?}", r);
+    }
+  }
+  let r = server
+    .handle_report(c2, ReportTarget::Client(c1), "spam".to_string())
+    .await;
+  if r != ClientReply::Error(ClientError::RateLimited) {
+    anyhow::bail!("Expected the reports to be limited, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn notification_prefs_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = ClientId::default();
+
+  let prefs = server.notification_prefs(c1).await?;
+  if prefs != NotificationPrefs::default() {
+    anyhow::bail!("Expected default preferences, got {:?}", prefs);
+  }
+  let wanted = NotificationPrefs {
+    muted: vec![c2, c2],
+    quiet_hours: Some(QuietHours {
+      start: 22 * 60,
+      end: 7 * 60,
+    }),
+  };
+  let r = server.set_notification_prefs(c1, wanted.clone()).await;
+  if r != ClientReply::Delivered(None) {
+    anyhow::bail!("Expected the preferences to be stored, got {:?}", r);
+  }
+  let prefs = server.notification_prefs(c1).await?;
+  if prefs.muted != [c2] || prefs.quiet_hours != wanted.quiet_hours {
+    anyhow::bail!("Expected {:?}, got {:?}", wanted, prefs);
+  }
+  let r = server
+    .set_notification_prefs(c2, NotificationPrefs::default())
+    .await;
+  if r != ClientReply::Error(ClientError::UnknownClient) {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn guest_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let guest = server
+    .register_guest(localhost(), "guest".to_string())
+    .await?;
+
+  // guests can read, but not send
+  let r = server
+    .handle_client_message(
+      guest,
+      ClientMessage::MText {
+        dest: vec![c1, c1],
+        content: "hello".to_string(),
+      },
+    )
+    .await;
+  let forbidden = (c1, ClientReply::Error(ClientError::Forbidden));
+  let expected = vec![ClientReply::Multi(vec![forbidden.clone(), forbidden])];
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  if !server.list_users().await.contains_key(&guest) {
+    anyhow::bail!("The guest should be listed");
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: guest,
+        content: "welcome".to_string(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+    anyhow::bail!(
+      "Expected the message to the guest to be delivered, got {:?}",
+      r
+    );
+  }
+
+  // once upgraded, it is a regular client
+  server.upgrade_guest(guest).await?;
+  let r = server
+    .handle_client_message(
+      guest,
+      ClientMessage::Text {
+        dest: c1,
+        content: "hello".to_string(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+    anyhow::bail!("Expected the message to be delivered, got {:?}", r);
+  }
+  if server.upgrade_guest(ClientId::default()).await != Err(ClientError::UnknownClient) {
+    anyhow::bail!("Upgrading an unknown client should fail");
+  }
+
+  // a guest that fails the checks stays a guest
+  let server: M = MessageServer::new(
+    TestChecker::new(TestCheckerMode::Set {
+      ip: false,
+      user: true,
+    }),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let guest = server
+    .register_guest(localhost(), "spammer".to_string())
+    .await?;
+  if server.upgrade_guest(guest).await != Err(ClientError::SpamDetected) {
+    anyhow::bail!("Expected the upgrade to be refused");
+  }
+  Ok(())
+}
+
+/// messages from one sender to one recipient keep their order, on every delivery path
+async fn ordering_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  const COUNT: usize = 100;
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+
+  // delayed, then flushed by the announce, interleaved with local deliveries
+  for i in 0..COUNT {
+    let msg = if i % 2 == 0 {
+      ClientMessage::Text {
+        dest: euuid,
+        content: i.to_string(),
+      }
+    } else {
+      ClientMessage::MText {
+        dest: vec![c2, euuid],
+        content: i.to_string(),
+      }
+    };
+    server.handle_client_message(c1, msg).await;
+  }
+  let mut sent = Vec::new();
+  match server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await
+  {
+    ServerReply::Outgoing(outgoing) => {
+      sent.extend(outgoing.into_iter().map(|o| o.message.content));
+    }
+    r => anyhow::bail!("Expected outgoing messages, got {:?}", r),
+  }
+  // then transferred right away
+  for i in COUNT..2 * COUNT {
+    for r in server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: euuid,
+          content: i.to_string(),
+        },
+      )
+      .await
+    {
+      match r {
+        ClientReply::Transfer(_, ServerMessage::Message(m), Some(_)) => sent.push(m.content),
+        r => anyhow::bail!("Expected a transfer, got {:?}", r),
+      }
+    }
+  }
+  let expected: Vec<String> = (0..2 * COUNT).map(|i| i.to_string()).collect();
+  if sent != expected {
+    anyhow::bail!("Remote messages out of order: {:?}", sent);
+  }
+
+  let mut received = Vec::new();
+  while let ClientPollReply::Message { content, .. } = server.client_poll(c2).await {
+    received.push(content);
+  }
+  let expected: Vec<String> = (0..COUNT)
+    .filter(|i| i % 2 == 1)
+    .map(|i| i.to_string())
+    .collect();
+  if received != expected {
+    anyhow::bail!("Local messages out of order: {:?}", received);
+  }
+
+  // several senders at once, each one sending in sequence
+  let senders = futures::future::join_all(
+    (0..8).map(|i| server.register_local_client(localhost(), format!("sender {}", i))),
+  )
+  .await;
+  let senders: Vec<ClientId> = senders.into_iter().collect::<Result<_, _>>()?;
+  futures::future::join_all(senders.iter().map(|src| {
+    let server = &server;
+    async move {
+      for i in 0..COUNT / 4 {
+        server
+          .handle_client_message(
+            *src,
+            ClientMessage::Text {
+              dest: c2,
+              content: i.to_string(),
+            },
+          )
+          .await;
+      }
+    }
+  }))
+  .await;
+  let mut last: HashMap<ClientId, usize> = HashMap::new();
+  while let ClientPollReply::Message { src, content, .. } = server.client_poll(c2).await {
+    let i: usize = content.parse()?;
+    if let Some(previous) = last.insert(src, i) {
+      if previous + 1 != i {
+        anyhow::bail!("{} after {} from {}", i, previous, src);
+      }
+    }
+  }
+  if last.values().any(|i| *i != COUNT / 4 - 1) {
+    anyhow::bail!("Missing messages: {:?}", last);
+  }
+  Ok(())
+}
+
+async fn room_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let mut clients = Vec::new();
+  for i in 0..3 {
+    clients.push(
+      server
+        .register_local_client(localhost(), format!("user {}", i))
+        .await
+        .unwrap(),
+    );
+  }
+  let (c1, c2, c3) = (clients[0], clients[1], clients[2]);
+
+  let room = server.create_room(c1, "lobby".to_string()).await?;
+  for c in [c2, c2] {
+    let r = server.join_room(c, room).await;
+    if r != ClientReply::Delivered(None) {
+      anyhow::bail!("Expected to join the room, got {:?}", r);
+    }
+  }
+  let say = |src, content: &str| {
+    server.handle_client_message(
+      src,
+      ClientMessage::RoomText {
+        room,
+        content: content.to_string(),
+      },
+    )
+  };
+
+  // everyone but the sender gets it
+  let r = say(c1, "hello").await;
+  if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+    anyhow::bail!("Expected a single delivery, got {:?}", r);
+  }
+  let reply = server.client_poll(c2).await;
+  let expected = ClientPollReply::RoomMessage {
+    room,
+    src: c1,
+    content: "hello".to_string(),
+  };
+  if reply != expected {
+    anyhow::bail!("Expected {:?}, received {:?}", expected, reply);
+  }
+  if server.client_poll(c1).await != ClientPollReply::Nothing {
+    anyhow::bail!("The sender should not get its own message");
+  }
+
+  // only members can talk
+  let r = say(c3, "let me in").await;
+  if r != [ClientReply::Error(ClientError::Forbidden)] {
+    anyhow::bail!("Expected a forbidden error, got {:?}", r);
+  }
+  server.join_room(c3, room).await;
+  let r = say(c3, "thanks").await;
+  if !matches!(
+    r[..],
+    [
+      ClientReply::Delivered(Some(_)),
+      ClientReply::Delivered(Some(_))
+    ]
+  ) {
+    anyhow::bail!("Expected two deliveries, got {:?}", r);
+  }
+
+  // the room disappears with its last member
+  for c in [c1, c2, c3] {
+    server.leave_room(c, room).await;
+  }
+  let r = server.join_room(c1, room).await;
+  if r != ClientReply::Error(ClientError::UnknownRoom(room)) {
+    anyhow::bail!("Expected an unknown room error, got {:?}", r);
+  }
+  let r = say(c1, "anyone?").await;
+  if r != [ClientReply::Error(ClientError::UnknownRoom(room))] {
+    anyhow::bail!("Expected an unknown room error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn federated_room_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  /* map:
+
+       s2 - s1 - us - s3
+  */
+  let (s1, s2, s3) = (ServerId::from(1), ServerId::from(2), ServerId::from(3));
+  for route in [vec![s1], vec![s2, s1], vec![s3]] {
+    server
+      .handle_server_message(ServerMessage::Announce {
+        route,
+        clients: HashMap::new(),
+      })
+      .await;
+  }
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let (r2, r3) = (ClientId::default(), ClientId::default());
+
+  // the members are announced to every neighbour
+  let room = server.create_room(c1, "lobby".to_string()).await?;
+  let r = server.announce_room(room).await;
+  let members = |r: &[Outgoing<ServerMessage>]| -> Vec<(NextHop, Vec<ClientId>)> {
+    let mut members: Vec<_> = r
+      .iter()
+      .filter_map(|o| match &o.message {
+        ServerMessage::RoomMembers {
+          room: rm,
+          srv,
+          members,
+          ..
+        } if *rm == room && *srv == sid => Some((o.nexthop, members.clone())),
+        _ => None,
+      })
+      .collect();
+    members.sort();
+    members
+  };
+  let expected = vec![(NextHop(s1), vec![c1]), (NextHop(s3), vec![c1])];
+  if members(&r) != expected {
+    anyhow::bail!("Expected the members for each neighbour, got {:?}", r);
+  }
+
+  // remote members are flooded once, older announces are dropped
+  let joined = |srv, version, members| ServerMessage::RoomMembers {
+    room,
+    srv,
+    version,
+    members,
+  };
+  for (srv, member) in [(s2, r2), (s3, r3)] {
+    let r = server
+      .handle_server_message(joined(srv, 2, vec![member]))
+      .await;
+    let ServerReply::Forward(forward) = r else {
+      anyhow::bail!("Expected the members to be forwarded, got {:?}", r);
+    };
+    if forward.len() != 2 {
+      anyhow::bail!("Expected a copy for each neighbour, got {:?}", forward);
+    }
+  }
+  let r = server.handle_server_message(joined(s2, 1, vec![])).await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected an older announce to be dropped, got {:?}", r);
+  }
+
+  // a single copy for each nexthop
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::RoomText {
+        room,
+        content: "hello".to_string(),
+      },
+    )
+    .await;
+  let text = |dstsrvs| ServerMessage::RoomText {
+    room,
+    src: c1,
+    srcsrv: sid,
+    dstsrvs,
+    content: "hello".to_string(),
+  };
+  let mut transfers: Vec<_> = r
+    .iter()
+    .map(|r| match r {
+      ClientReply::Transfer(nexthop, message, None) => Ok((*nexthop, message.clone())),
+      r => Err(anyhow::anyhow!("Expected transfers only, got {:?}", r)),
+    })
+    .collect::<Result<_, _>>()?;
+  transfers.sort_by_key(|(nexthop, _)| *nexthop);
+  let expected = vec![(NextHop(s1), text(vec![s2])), (NextHop(s3), text(vec![s3]))];
+  if transfers != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, transfers);
+  }
+
+  // a remote message is delivered here, and forwarded for the other servers
+  let r = server
+    .handle_server_message(ServerMessage::RoomText {
+      room,
+      src: r2,
+      srcsrv: s2,
+      dstsrvs: vec![sid, s3],
+      content: "hi".to_string(),
+    })
+    .await;
+  let expected = ServerReply::Forward(vec![Outgoing {
+    nexthop: NextHop(s3),
+    message: ServerMessage::RoomText {
+      room,
+      src: r2,
+      srcsrv: s2,
+      dstsrvs: vec![s3],
+      content: "hi".to_string(),
+    },
+  }]);
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  let r = server.client_poll(c1).await;
+  let expected = ClientPollReply::RoomMessage {
+    room,
+    src: r2,
+    content: "hi".to_string(),
+  };
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+
+  // a neighbour announcing itself learns the memberships
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s3],
+      clients: HashMap::new(),
+    })
+    .await;
+  let ServerReply::Forward(forward) = r else {
+    anyhow::bail!("Expected the memberships to be forwarded, got {:?}", r);
+  };
+  if members(&forward) != [(NextHop(s3), vec![c1])] || forward.len() != 3 {
+    anyhow::bail!("Expected the three memberships, got {:?}", forward);
+  }
+
+  // a room of another server can be joined, until its last member leaves
+  let elsewhere = RoomId::default();
+  let r = server
+    .handle_server_message(ServerMessage::RoomMembers {
+      room: elsewhere,
+      srv: s2,
+      version: 1,
+      members: vec![r2],
+    })
+    .await;
+  if !matches!(r, ServerReply::Forward(_)) {
+    anyhow::bail!("Expected the members to be forwarded, got {:?}", r);
+  }
+  let r = server.join_room(c1, elsewhere).await;
+  if r != ClientReply::Delivered(None) {
+    anyhow::bail!("Expected to join the remote room, got {:?}", r);
+  }
+  server.leave_room(c1, elsewhere).await;
+  server
+    .handle_server_message(ServerMessage::RoomMembers {
+      room: elsewhere,
+      srv: s2,
+      version: 2,
+      members: vec![],
+    })
+    .await;
+  let r = server.join_room(c1, elsewhere).await;
+  if r != ClientReply::Error(ClientError::UnknownRoom(elsewhere)) {
+    anyhow::bail!("Expected an unknown room error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn history_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  for content in ["a", "b", "c"] {
+    server
+      .handle_client_message(
+        c2,
+        ClientMessage::Text {
+          dest: c1,
+          content: content.to_string(),
+        },
+      )
+      .await;
+  }
+  let history = server.client_history(c1, 10, None, false).await?;
+  if !history.is_empty() {
+    anyhow::bail!(
+      "Expected an empty history before polling, got {:?}",
+      history
+    );
+  }
+  server.client_poll(c1).await;
+  server.client_poll(c1).await;
+
+  let history = server.client_history(c1, 10, None, false).await?;
+  let contents: Vec<_> = history
+    .iter()
+    .map(|e| match &e.message {
+      ClientPollReply::Message { src, content, .. } if *src == c2 => content.as_str(),
+      _ => "?",
+    })
+    .collect();
+  if contents != ["a", "b"] {
+    anyhow::bail!(
+      "Expected the polled messages, oldest first, got {:?}",
+      history
+    );
+  }
+  let last = server.client_history(c1, 1, None, false).await?;
+  if last != history[1..] {
+    anyhow::bail!("Expected the last message only, got {:?}", last);
+  }
+  let before = server
+    .client_history(c1, 10, Some(history[1].id), false)
+    .await?;
+  if before != history[..1] {
+    anyhow::bail!("Expected the first message only, got {:?}", before);
+  }
+  let unknown = server
+    .client_history(c1, 10, Some(MessageId::default()), false)
+    .await?;
+  if !unknown.is_empty() {
+    anyhow::bail!(
+      "Expected nothing before an unknown message, got {:?}",
+      unknown
+    );
+  }
+  match server
+    .client_history(ClientId::default(), 10, None, false)
+    .await
+  {
+    Err(ClientError::UnknownClient) => Ok(()),
+    r => anyhow::bail!("Expected an unknown client error, got {:?}", r),
+  }
+}
+
+async fn receipt_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let remote: M = MessageServer::new(TestChecker::default(), s1, ServerConfig::default());
+  let euuid = remote
+    .register_local_client(localhost(), "external user".to_string())
+    .await
+    .unwrap();
+  server
+    .handle_server_message(remote.make_announce().await)
+    .await;
+  remote
+    .handle_server_message(server.make_announce().await)
+    .await;
+
+  let r = server
+    .handle_client_message(c1, ClientMessage::Ack(MessageId::default()))
+    .await;
+  if !matches!(r[..], [ClientReply::Error(ClientError::UnknownMessage(_))]) {
+    anyhow::bail!("Expected an unknown message error, got {:?}", r);
+  }
+
+  // local author
+  server
+    .handle_client_message(
+      c2,
+      ClientMessage::Text {
+        dest: c1,
+        content: "hi".to_string(),
+      },
+    )
+    .await;
+  server.client_poll(c1).await;
+  let id = server.client_history(c1, 1, None, false).await?[0].id;
+  let r = server
+    .handle_client_message(c1, ClientMessage::Ack(id))
+    .await;
+  if r != [ClientReply::Delivered(None)] {
+    anyhow::bail!("Expected the receipt to be delivered, got {:?}", r);
+  }
+  let receipt = server.client_poll(c2).await;
+  if receipt != (ClientPollReply::Receipt { id, reader: c1 }) {
+    anyhow::bail!("Expected a receipt, got {:?}", receipt);
+  }
+  if !server.client_history(c2, 10, None, false).await?.is_empty() {
+    anyhow::bail!("Receipts should not be kept in the history");
+  }
+
+  // remote author, the id is the one its server returned
+  let r = remote
+    .handle_client_message(
+      euuid,
+      ClientMessage::Text {
+        dest: c1,
+        content: "hello".to_string(),
+      },
+    )
+    .await;
+  let [ClientReply::Transfer(_, ref message, Some(id))] = r[..] else {
+    anyhow::bail!("Expected a transfer, got {:?}", r);
+  };
+  server.handle_server_message(message.clone()).await;
+  server.client_poll(c1).await;
+  if server.client_history(c1, 1, None, false).await?[0].id != id {
+    anyhow::bail!("Expected the history entry to keep the id of the sender");
+  }
+  let r = server
+    .handle_client_message(c1, ClientMessage::Ack(id))
+    .await;
+  let expected = [ClientReply::Transfer(
+    NextHop(s1),
+    ServerMessage::Receipt {
+      id,
+      reader: c1,
+      dst: euuid,
+      dstsrv: s1,
+    },
+    None,
+  )];
+  if r != expected {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+
+  // remote reader
+  let r = server
+    .handle_server_message(ServerMessage::Receipt {
+      id,
+      reader: euuid,
+      dst: c2,
+      dstsrv: sid,
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected empty outgoing answer, got {:?}", r);
+  }
+  let receipt = server.client_poll(c2).await;
+  if receipt != (ClientPollReply::Receipt { id, reader: euuid }) {
+    anyhow::bail!("Expected a remote receipt, got {:?}", receipt);
+  }
+  Ok(())
+}
+
+async fn do_not_disturb_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let dnd = |on| ClientMessage::SetDoNotDisturb(on);
+  let text = |content: String| ClientMessage::Text { dest: c2, content };
+
+  let r = server.handle_client_message(c2, dnd(true)).await;
+  if r != [ClientReply::Delivered(None)] {
+    anyhow::bail!("Expected do-not-disturb to be set, got {:?}", r);
+  }
+  // more than a mailbox, none of them refused
+  let mut first = None;
+  for i in 0..=mailbox_size() {
+    let r = server.handle_client_message(c1, text(i.to_string())).await;
+    match r[..] {
+      [ClientReply::Delivered(Some(id))] => {
+        first.get_or_insert(id);
+      }
+      _ => anyhow::bail!("Expected message {} to be deferred, got {:?}", i, r),
+    }
+  }
+  let polled = server.client_poll(c2).await;
+  if polled != ClientPollReply::Nothing {
+    anyhow::bail!("Expected nothing to poll while deferred, got {:?}", polled);
+  }
+  // deferred messages can still be edited
+  let edit = ClientMessage::Edit {
+    dest: c2,
+    id: first.unwrap(),
+    content: "edited".to_string(),
+  };
+  let r = server.handle_client_message(c1, edit).await;
+  if !matches!(r[..], [ClientReply::Delivered(_)]) {
+    anyhow::bail!("Expected the deferred message to be edited, got {:?}", r);
+  }
+
+  server.handle_client_message(c2, dnd(false)).await;
+  let polled = server.client_poll_n(c2, mailbox_size() + 2).await;
+  let contents: Vec<&str> = polled
+    .iter()
+    .filter_map(|p| match p {
+      ClientPollReply::Message { src, content, .. } if *src == c1 => Some(content.as_str()),
+      _ => None,
+    })
+    .collect();
+  let expected: Vec<String> = std::iter::once("edited".to_string())
+    .chain((1..=mailbox_size()).map(|i| i.to_string()))
+    .collect();
+  if contents != expected || polled.len() != expected.len() {
+    anyhow::bail!("Expected the deferred messages in order, got {:?}", polled);
+  }
+
+  let r = server
+    .handle_client_message(ClientId::default(), dnd(true))
+    .await;
+  if r != [ClientReply::Error(ClientError::UnknownClient)] {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn reaction_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let remote: M = MessageServer::new(TestChecker::default(), s1, ServerConfig::default());
+  let euuid = remote
+    .register_local_client(localhost(), "external user".to_string())
+    .await
+    .unwrap();
+  server
+    .handle_server_message(remote.make_announce().await)
+    .await;
+  remote
+    .handle_server_message(server.make_announce().await)
+    .await;
+  let react = |message, emoji: &str| ClientMessage::React {
+    message,
+    emoji: emoji.to_string(),
+  };
+
+  let r = server
+    .handle_client_message(c1, react(MessageId::default(), "+1"))
+    .await;
+  if !matches!(r[..], [ClientReply::Error(ClientError::UnknownMessage(_))]) {
+    anyhow::bail!("Expected an unknown message error, got {:?}", r);
+  }
+
+  // local author, the reactor keeps each emoji once
+  server
+    .handle_client_message(
+      c2,
+      ClientMessage::Text {
+        dest: c1,
+        content: "hi".to_string(),
+      },
+    )
+    .await;
+  server.client_poll(c1).await;
+  let id = server.client_history(c1, 1, None, false).await?[0].id;
+  for emoji in ["+1", "+1", "ok"] {
+    let r = server.handle_client_message(c1, react(id, emoji)).await;
+    if r != [ClientReply::Delivered(None)] {
+      anyhow::bail!("Expected the reaction to be delivered, got {:?}", r);
+    }
+    let reaction = server.client_poll(c2).await;
+    let expected = ClientPollReply::Reaction {
+      id,
+      reactor: c1,
+      emoji: emoji.to_string(),
+    };
+    if reaction != expected {
+      anyhow::bail!("Expected {:?}, got {:?}", expected, reaction);
+    }
+  }
+  let history = server.client_history(c1, 1, None, false).await?;
+  if history[0].reactions != ["+1", "ok"] {
+    anyhow::bail!("Expected the reactions in the history, got {:?}", history);
+  }
+  if !server.client_history(c2, 10, None, false).await?.is_empty() {
+    anyhow::bail!("Reactions should not be kept in the history of the author");
+  }
+  for (emoji, expected) in [
+    ("", ClientError::Forbidden),
+    (
+      &"x".repeat(MAX_REACTION_SIZE + 1)[..],
+      ClientError::MessageTooLarge(MAX_REACTION_SIZE as u128),
+    ),
+  ] {
+    let r = server.handle_client_message(c1, react(id, emoji)).await;
+    if r != [ClientReply::Error(expected.clone())] {
+      anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+    }
+  }
+
+  // remote author, the id is the one its server returned
+  let r = remote
+    .handle_client_message(
+      euuid,
+      ClientMessage::Text {
+        dest: c1,
+        content: "hello".to_string(),
+      },
+    )
+    .await;
+  let [ClientReply::Transfer(_, ref message, Some(id))] = r[..] else {
+    anyhow::bail!("Expected a transfer, got {:?}", r);
+  };
+  server.handle_server_message(message.clone()).await;
+  server.client_poll(c1).await;
+  if server.client_history(c1, 1, None, false).await?[0].id != id {
+    anyhow::bail!("Expected the history entry to keep the id of the sender");
+  }
+  let r = server.handle_client_message(c1, react(id, "+1")).await;
+  let expected = [ClientReply::Transfer(
+    NextHop(s1),
+    ServerMessage::Reaction {
+      id,
+      reactor: c1,
+      dst: euuid,
+      dstsrv: s1,
+      emoji: "+1".to_string(),
+    },
+    None,
+  )];
+  if r != expected {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+
+  // remote reactor
+  let r = server
+    .handle_server_message(ServerMessage::Reaction {
+      id,
+      reactor: euuid,
+      dst: c2,
+      dstsrv: sid,
+      emoji: "ok".to_string(),
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected empty outgoing answer, got {:?}", r);
+  }
+  let reaction = server.client_poll(c2).await;
+  let expected = ClientPollReply::Reaction {
+    id,
+    reactor: euuid,
+    emoji: "ok".to_string(),
+  };
+  if reaction != expected {
+    anyhow::bail!("Expected a remote reaction, got {:?}", reaction);
+  }
+  Ok(())
+}
+
+/// Once traffic settled, the remote users known by each server must be exactly the local users
+/// of the other servers, registered on the right server.
+/// Withdrawn clients are ignored, whether they are still known or not.
+async fn check_directory<M: MessageServer<TestChecker>>(
+  servers: &[(ServerId, &M)],
+  withdrawn: &HashSet<ClientId>,
+) -> anyhow::Result<()> {
+  let mut locals = Vec::new();
+  for (id, server) in servers {
+    for client in server.list_users().await.into_keys() {
+      locals.push((client, *id));
+    }
+  }
+  for (id, server) in servers {
+    let expected: HashMap<ClientId, ServerId> = locals
+      .iter()
+      .filter(|(client, srv)| srv != id && !withdrawn.contains(client))
+      .copied()
+      .collect();
+    let mut known = server.remote_users().await;
+    known.retain(|client, _| !withdrawn.contains(client));
+    for (client, srv) in &expected {
+      match known.get(client) {
+        None => anyhow::bail!("{} does not know {} (on {})", id, client, srv),
+        Some(found) if found != srv => {
+          anyhow::bail!("{} thinks {} is on {}, not {}", id, client, found, srv)
+        }
+        Some(_) => (),
+      }
+    }
+    if let Some(client) = known.keys().find(|c| !expected.contains_key(c)) {
+      anyhow::bail!("{} knows {}, that is not registered anywhere", id, client);
+    }
+  }
+  Ok(())
+}
+
+async fn directory_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let ids: Vec<ServerId> = (0..3).map(|_| ServerId::default()).collect();
+  let servers: Vec<M> = ids
+    .iter()
+    .map(|id| MessageServer::new(TestChecker::default(), *id, ServerConfig::default()))
+    .collect();
+  for (i, server) in servers.iter().enumerate() {
+    for j in 0..=i {
+      server
+        .register_local_client(localhost(), format!("user {}.{}", i, j))
+        .await
+        .unwrap();
+    }
+  }
+  let pairs: Vec<(ServerId, &M)> = ids.iter().copied().zip(servers.iter()).collect();
+  let none = HashSet::new();
+  if check_directory(&pairs, &none).await.is_ok() {
+    anyhow::bail!("Expected an inconsistent directory before any announce");
+  }
+
+  // full mesh, everyone announces to everyone
+  for (id, server) in &pairs {
+    let announce = ServerMessage::Announce {
+      route: vec![*id],
+      clients: server.list_users().await,
+    };
+    for (other, peer) in &pairs {
+      if other != id {
+        peer.handle_server_message(announce.clone()).await;
+      }
+    }
+  }
+  check_directory(&pairs, &none).await?;
+
+  // a client that was not announced yet
+  let late = servers[0]
+    .register_local_client(localhost(), "late".to_string())
+    .await
+    .unwrap();
+  if check_directory(&pairs, &none).await.is_ok() {
+    anyhow::bail!("Expected the late client to be missing");
+  }
+  check_directory(&pairs, &HashSet::from([late])).await
+}
+
+async fn expect_presence<M: MessageServer<TestChecker>>(
+  server: &M,
+  subscriber: ClientId,
+  client: ClientId,
+  presence: Presence,
+) -> anyhow::Result<()> {
+  let r = server.client_poll(subscriber).await;
+  if r != (ClientPollReply::Presence { client, presence }) {
+    anyhow::bail!("Expected {:?} for {}, got {:?}", presence, client, r);
+  }
+  Ok(())
+}
+
+async fn presence_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+
+  let r = server
+    .handle_client_message(c2, ClientMessage::SubscribePresence(ClientId::default()))
+    .await;
+  if r != [ClientReply::Error(ClientError::UnknownClient)] {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+
+  // local client, the current presence comes first
+  server
+    .handle_client_message(c2, ClientMessage::SubscribePresence(c1))
+    .await;
+  expect_presence(&server, c2, c1, Presence::Online).await?;
+  let r = server
+    .handle_client_message(c1, ClientMessage::SetPresence(Presence::Away))
+    .await;
+  let expected = [
+    ClientReply::Delivered(None),
+    ClientReply::Transfer(
+      NextHop(s1),
+      ServerMessage::Presence {
+        client: c1,
+        presence: Presence::Away,
+      },
+      None,
+    ),
+  ];
+  if r != expected {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+  expect_presence(&server, c2, c1, Presence::Away).await?;
+
+  // remote client
+  server
+    .handle_client_message(c2, ClientMessage::SubscribePresence(euuid))
+    .await;
+  expect_presence(&server, c2, euuid, Presence::Online).await?;
+  let r = server
+    .handle_server_message(ServerMessage::Presence {
+      client: euuid,
+      presence: Presence::Offline,
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected empty outgoing answer, got {:?}", r);
+  }
+  expect_presence(&server, c2, euuid, Presence::Offline).await?;
+  if server.client_poll(c2).await != ClientPollReply::Nothing {
+    anyhow::bail!("Expected no more notifications");
+  }
+  Ok(())
+}
+
+async fn unregister_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  let room = server.create_room(c1, "room".to_string()).await?;
+
+  let r = server.unregister_local_client(c1).await;
+  let expected = [
+    ClientReply::Delivered(None),
+    ClientReply::Transfer(
+      NextHop(s1),
+      ServerMessage::Withdraw {
+        srv: sid,
+        clients: vec![c1],
+      },
+      None,
+    ),
+  ];
+  if r.get(..2) != Some(&expected[..]) {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+  // followed by the members left in its rooms
+  let left = matches!(
+    &r[2..],
+    [ClientReply::Transfer(nexthop, ServerMessage::RoomMembers { room: rm, srv, members, .. }, None)]
+      if *nexthop == NextHop(s1) && *rm == room && *srv == sid && members.is_empty()
+  );
+  if !left {
+    anyhow::bail!(
+      "Expected the room members to be announced, got {:?}",
+      &r[2..]
+    );
+  }
+  if server.list_users().await.contains_key(&c1) {
+    anyhow::bail!("Expected the client to be gone");
+  }
+  let r = server.client_poll(c1).await;
+  if r != ClientPollReply::DelayedError(DelayedError::UnknownRecipient(c1)) {
+    anyhow::bail!("Expected the mailbox to be gone, got {:?}", r);
+  }
+  let r = server.join_room(c2, room).await;
+  if r != ClientReply::Error(ClientError::UnknownRoom(room)) {
+    anyhow::bail!(
+      "Expected the room to be deleted with its last member, got {:?}",
+      r
+    );
+  }
+  let r = server.unregister_local_client(c1).await;
+  if r != [ClientReply::Error(ClientError::UnknownClient)] {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+
+  // only the server of a client can withdraw it
+  for srv in [sid, s1] {
+    server
+      .handle_server_message(ServerMessage::Withdraw {
+        srv,
+        clients: vec![euuid],
+      })
+      .await;
+    let known = server.remote_users().await.contains_key(&euuid);
+    if known != (srv == sid) {
+      anyhow::bail!("Unexpected directory after a withdraw from {}", srv);
+    }
+  }
+  Ok(())
+}
+
+/// Clients that did not poll for a while are removed, and withdrawn together.
+async fn evict_idle_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let s1 = ServerId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::new(),
+    })
+    .await;
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let c3 = server
+    .register_local_client(localhost(), "user 3".to_string())
+    .await?;
+  let max_age = Duration::from_secs(60);
+
+  let (evicted, withdraws) = server.evict_idle(Instant::now(), max_age).await;
+  if !evicted.is_empty() || !withdraws.is_empty() {
+    anyhow::bail!("Expected no eviction, got {:?} {:?}", evicted, withdraws);
+  }
+
+  // polling keeps c3
+  let deadline = Instant::now() + max_age;
+  sleep(Duration::from_millis(10)).await;
+  server.client_poll(c3).await;
+  let (mut evicted, withdraws) = server.evict_idle(deadline, max_age).await;
+  evicted.sort();
+  let mut expected = vec![c1, c2];
+  expected.sort();
+  if evicted != expected {
+    anyhow::bail!("Expected {:?} to be evicted, got {:?}", expected, evicted);
+  }
+  let [Outgoing {
+    nexthop: NextHop(next),
+    message: ServerMessage::Withdraw { srv, clients },
+  }] = &withdraws[..]
+  else {
+    anyhow::bail!("Expected a single withdraw, got {:?}", withdraws);
+  };
+  let mut clients = clients.clone();
+  clients.sort();
+  if (*next, *srv, &clients) != (s1, sid, &expected) {
+    anyhow::bail!("Unexpected withdraw {:?}", withdraws);
+  }
+  let users = server.list_users().await;
+  if users.contains_key(&c1) || users.contains_key(&c2) || !users.contains_key(&c3) {
+    anyhow::bail!("Unexpected users after the eviction: {:?}", users);
+  }
+  Ok(())
+}
+
+async fn broadcast_forbidden<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Broadcast {
+        content: "Hello everyone".to_string(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Error(ClientError::Forbidden)] {
+    anyhow::bail!("Expected members not to broadcast, got {:?}", r);
+  }
+  let r = server.client_poll(c2).await;
+  if r != ClientPollReply::Nothing {
+    anyhow::bail!("Expected nothing to be delivered, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn trusted_registration_forbidden<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let id = ClientId::default();
+  let r = server
+    .register_trusted_client(c1, id, "bridge".to_string(), localhost())
+    .await;
+  if r != Err(ClientError::Forbidden) {
+    anyhow::bail!(
+      "Expected members not to register trusted clients, got {:?}",
+      r
+    );
+  }
+  let r = server
+    .register_trusted_client(id, id, "bridge".to_string(), localhost())
+    .await;
+  if r != Err(ClientError::UnknownClient) {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  if server.list_users().await.contains_key(&id) {
+    anyhow::bail!("The client should not be registered");
+  }
+  Ok(())
+}
+
+async fn block_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let c3 = server
+    .register_local_client(localhost(), "user 3".to_string())
+    .await
+    .unwrap();
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+
+  let r = server.set_blocked(c2, vec![c1, euuid]).await;
+  if r != ClientReply::Delivered(None) {
+    anyhow::bail!("Expected the block list to be set, got {:?}", r);
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3],
+        content: "Hello".to_string(),
+      },
+    )
+    .await;
+  if !matches!(
+    &r[..],
+    [ClientReply::Multi(each)] if matches!(
+      each[..],
+      [
+        (_, ClientReply::Error(ClientError::Blocked(blocked))),
+        (_, ClientReply::Delivered(Some(_))),
+      ] if blocked == c2
+    )
+  ) {
+    anyhow::bail!("Expected Blocked/Delivered, got {:?}", r);
+  }
+  server
+    .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+      src: euuid,
+      srcsrv: s1,
+      dsts: vec![(c2, sid)],
+      content: "Hello".to_string(),
+      content_type: ContentType::Plain,
+      mentions: Vec::new(),
+      seq: 0,
+      in_reply_to: None,
+      timestamp: 0,
+      id: MessageId::default(),
+    }))
+    .await;
+  let r = server.client_poll(c2).await;
+  if r != ClientPollReply::Nothing {
+    anyhow::bail!("Expected the blocked messages to be dropped, got {:?}", r);
+  }
+
+  // other clients are not affected, and blocks can be lifted
+  let r = server.set_blocked(c2, Vec::new()).await;
+  if r != ClientReply::Delivered(None) {
+    anyhow::bail!("Expected the block list to be cleared, got {:?}", r);
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "Hello again".to_string(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+    anyhow::bail!("Expected the message to be delivered, got {:?}", r);
+  }
+  let r = server.set_blocked(euuid, vec![c1]).await;
+  if r != ClientReply::Error(ClientError::UnknownClient) {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  Ok(())
+}
+
+/// A sync polls everything, and returns what changed since the cursor.
+async fn sync_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  server
+    .handle_client_message(c2, ClientMessage::SubscribePresence(c1))
+    .await;
+  for content in ["one", "two"] {
+    server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: c2,
+          content: content.to_string(),
+        },
+      )
+      .await;
+  }
+
+  let first = server.sync(c2, &SyncCursor::default(), 1).await?;
+  let one = ClientPollReply::Message {
+    src: c1,
+    srcsrv: sid,
+    content: "one".to_string(),
+    timestamp: 0,
+  };
+  if first.messages.len() != 1 || first.messages[0].message.clone().unstamped() != one {
+    anyhow::bail!("Expected the first message only, got {:?}", first);
+  }
+  if !first.full
+    || first.presence.len() != 1
+    || first.presence[0].0 != c1
+    || !first.other.is_empty()
+  {
+    anyhow::bail!(
+      "Expected a full sync, with the subscription, got {:?}",
+      first
+    );
+  }
+  let read = first.messages[0].id;
+  let second = server.sync(c2, &first.cursor, 10).await?;
+  if second.full || second.messages.len() != 1 || !second.presence.is_empty() {
+    anyhow::bail!("Expected the second message only, got {:?}", second);
+  }
+
+  server
+    .handle_client_message(c2, ClientMessage::Ack(read))
+    .await;
+  server
+    .handle_client_message(c1, ClientMessage::SetPresence(Presence::Away))
+    .await;
+  let third = server.sync(c2, &second.cursor, 10).await?;
+  let expected = SyncReply {
+    messages: Vec::new(),
+    presence: vec![(c1, Presence::Away)],
+    read_markers: vec![(c1, read)],
+    receipts: Vec::new(),
+    other: Vec::new(),
+    cursor: SyncCursor {
+      history: second.cursor.history,
+      read_markers: third.cursor.read_markers,
+    },
+    full: false,
+  };
+  if third != expected || third.cursor.read_markers == second.cursor.read_markers {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, third);
+  }
+  let r = server.sync(c2, &third.cursor, 10).await?;
+  if r.cursor != third.cursor || !r.read_markers.is_empty() {
+    anyhow::bail!("Expected nothing new, got {:?}", r);
+  }
+  // a cursor that is too old falls back to a full sync
+  let stale = SyncCursor {
+    history: Some(MessageId::default()),
+    read_markers: third.cursor.read_markers,
+  };
+  let r = server.sync(c2, &stale, 10).await?;
+  if !r.full
+    || r.messages.len() != 2
+    || r.presence != [(c1, Presence::Away)]
+    || r.read_markers != [(c1, read)]
+  {
+    anyhow::bail!("Expected a full sync, got {:?}", r);
+  }
+
+  // the author gets the receipt
+  let r = server.sync(c1, &SyncCursor::default(), 10).await?;
+  if r.receipts != [(read, c2)] || !r.messages.is_empty() {
+    anyhow::bail!("Expected the receipt, got {:?}", r);
+  }
+  match server
+    .sync(ClientId::default(), &SyncCursor::default(), 10)
+    .await
+  {
+    Err(ClientError::UnknownClient) => Ok(()),
+    r => anyhow::bail!("Expected an unknown client error, got {:?}", r),
+  }
+}
+
+/// A waiting poll returns as soon as a message arrives, or `Nothing` once the wait is over.
+async fn poll_wait_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+
+  let start = Instant::now();
+  let (r, _) = futures::join!(
+    server.client_poll_wait(c1, Duration::from_secs(10)),
+    async {
+      sleep(Duration::from_millis(20)).await;
+      server
+        .handle_client_message(
+          c2,
+          ClientMessage::Text {
+            dest: c1,
+            content: "wake up".to_string(),
+          },
+        )
+        .await
+    }
+  );
+  let expected = ClientPollReply::Message {
+    src: c2,
+    srcsrv: sid,
+    content: "wake up".to_string(),
+    timestamp: 0,
+  };
+  if r.clone().unstamped() != expected || start.elapsed() >= Duration::from_secs(10) {
+    anyhow::bail!("Expected {:?} without waiting, got {:?}", expected, r);
+  }
+
+  let start = Instant::now();
+  let r = server.client_poll_wait(c1, Duration::from_millis(20)).await;
+  if r != ClientPollReply::Nothing || start.elapsed() < Duration::from_millis(20) {
+    anyhow::bail!("Expected Nothing after the wait, got {:?}", r);
+  }
+  let unknown = ClientId::default();
+  let r = server
+    .client_poll_wait(unknown, Duration::from_secs(10))
+    .await;
+  if r != ClientPollReply::DelayedError(DelayedError::UnknownRecipient(unknown)) {
+    anyhow::bail!("Expected an unknown recipient, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn stream_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let text = |content: &str| ClientMessage::Text {
+    dest: c2,
+    content: content.to_string(),
+  };
+  server
+    .handle_client_message(
+      c1,
+      ClientMessage::KeyAgreement {
+        dest: c2,
+        payload: vec![1, 2, 3],
+      },
+    )
+    .await;
+  server.handle_client_message(c1, text("one")).await;
+
+  let mut messages = server.client_stream(c2);
+  let r = messages.next().await;
+  if r != Some((c1, "one".to_string())) {
+    anyhow::bail!("Expected the waiting message, got {:?}", r);
+  }
+  let (r, _) = futures::join!(messages.next(), async {
+    sleep(Duration::from_millis(20)).await;
+    server.handle_client_message(c1, text("two")).await
+  });
+  if r != Some((c1, "two".to_string())) {
+    anyhow::bail!("Expected the pushed message, got {:?}", r);
+  }
+
+  // the handshake is left for polling, the messages are in the history
+  let r = server.client_poll(c2).await;
+  if !matches!(r, ClientPollReply::KeyAgreement { src, .. } if src == c1) {
+    anyhow::bail!("Expected the handshake, got {:?}", r);
+  }
+  let r = server.client_history(c2, 10, None, false).await?;
+  if r.len() != 2 {
+    anyhow::bail!("Expected the streamed messages in the history, got {:?}", r);
+  }
+
+  let (r, _) = futures::join!(messages.next(), async {
+    sleep(Duration::from_millis(20)).await;
+    server.unregister_local_client(c2).await
+  });
+  if r.is_some() {
+    anyhow::bail!("Expected the end of the stream, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn poll_n_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  for i in 0..5 {
+    server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: c2,
+          content: i.to_string(),
+        },
+      )
+      .await;
+  }
+  let message = |i: usize| ClientPollReply::Message {
+    src: c1,
+    srcsrv: sid,
+    content: i.to_string(),
+    timestamp: 0,
+  };
+  let r = server.client_poll_n(c2, 3).await.unstamped();
+  let expected: Vec<ClientPollReply> = (0..3).map(message).collect();
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  // single polls carry on from there
+  let r = server.client_poll(c2).await.unstamped();
+  if r != message(3) {
+    anyhow::bail!("Expected {:?}, got {:?}", message(3), r);
+  }
+  let r = server.client_poll_n(c2, 10).await.unstamped();
+  if r != [message(4)] {
+    anyhow::bail!("Expected the last message only, got {:?}", r);
+  }
+  let r = server.client_poll_n(c2, 10).await;
+  if !r.is_empty() {
+    anyhow::bail!("Expected nothing left, got {:?}", r);
+  }
+  // batch polled messages are in the history too
+  let r = server.client_history(c2, 10, None, false).await?;
+  if r.len() != 5 {
+    anyhow::bail!("Expected 5 messages in the history, got {:?}", r);
+  }
+  let euuid = ClientId::default();
+  let r = server.client_poll_n(euuid, 10).await;
+  if r
+    != [ClientPollReply::DelayedError(
+      DelayedError::UnknownRecipient(euuid),
+    )]
+  {
+    anyhow::bail!("Expected an unknown recipient error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn event_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  for n in 0..mailbox_size() {
+    server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: c2,
+          content: n.to_string(),
+        },
+      )
+      .await;
+  }
+  // the mailbox is full, events still get through
+  let typing = ClientMessage::Event {
+    dest: c2,
+    event: Event::Typing,
+  };
+  let r = server.handle_client_message(c1, typing).await;
+  if r != [ClientReply::Delivered(None)] {
+    anyhow::bail!("Expected the event to be delivered, got {:?}", r);
+  }
+  // and they are polled first
+  let r = server.client_poll(c2).await;
+  let expected = ClientPollReply::Event {
+    src: c1,
+    event: Event::Typing,
+  };
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  let r = server.client_poll(c2).await.unstamped();
+  let expected = ClientPollReply::Message {
+    src: c1,
+    srcsrv: sid,
+    content: "0".to_string(),
+    timestamp: 0,
+  };
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  let r = server.client_history(c2, 10, None, false).await?;
+  if r.len() != 1 {
+    anyhow::bail!("Expected only the message in the history, got {:?}", r);
+  }
+  // old events are dropped
+  for _ in 0..EVENTS_SIZE + 1 {
+    server
+      .handle_client_message(
+        c2,
+        ClientMessage::Event {
+          dest: c1,
+          event: Event::Typing,
+        },
+      )
+      .await;
+  }
+  let r = server.client_poll_n(c1, EVENTS_SIZE + 1).await;
+  if r.len() != EVENTS_SIZE {
+    anyhow::bail!("Expected {} events, got {:?}", EVENTS_SIZE, r);
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Event {
+        dest: ClientId::default(),
+        event: Event::StoppedTyping,
+      },
+    )
+    .await;
+  if r != [ClientReply::Error(ClientError::UnknownClient)] {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn reserved_names<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  for name in RESERVED_NAMES.into_iter().chain([" Admin "]) {
+    let r = server
+      .register_local_client(localhost(), name.to_string())
+      .await;
+    if r != Err(ClientError::Forbidden) {
+      anyhow::bail!("Expected {:?} to be reserved, got {:?}", name, r);
+    }
+    let r = server.register_guest(localhost(), name.to_string()).await;
+    if r != Err(ClientError::Forbidden) {
+      anyhow::bail!(
+        "Expected {:?} to be reserved for guests too, got {:?}",
+        name,
+        r
+      );
+    }
+  }
+  let c1 = server
+    .register_local_client(localhost(), "administrator".to_string())
+    .await
+    .unwrap();
+  for (id, _) in ClientId::SYSTEM {
+    let r = server
+      .handle_client_message(
+        c1,
+        ClientMessage::Text {
+          dest: id,
+          content: "hello".to_string(),
+        },
+      )
+      .await;
+    if r != [ClientReply::Error(ClientError::Forbidden)] {
+      anyhow::bail!(
+        "Expected system identities not to receive messages, got {:?}",
+        r
+      );
+    }
+  }
+  Ok(())
+}
+
+async fn priority_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let text = |content: &str| ClientMessage::Text {
+    dest: c2,
+    content: content.to_string(),
+  };
+  let prioritized = |priority, content: &str| ClientMessage::Prioritized {
+    priority,
+    message: Box::new(text(content)),
+  };
+  for msg in [
+    text("normal 1"),
+    prioritized(Priority::High, "high 1"),
+    prioritized(Priority::Normal, "normal 2"),
+    prioritized(Priority::High, "high 2"),
+  ] {
+    let r = server.handle_client_message(c1, msg).await;
+    if !matches!(r[..], [ClientReply::Delivered(Some(_))]) {
+      anyhow::bail!("Expected Delivered, got {:?}", r);
+    }
+  }
+  let r = server
+    .handle_client_message(c1, prioritized(Priority::System, "system"))
+    .await;
+  if r != [ClientReply::Error(ClientError::Forbidden)] {
+    anyhow::bail!("Expected the system lane to be forbidden, got {:?}", r);
+  }
+  let r = server.client_poll_n(c2, 10).await.unstamped();
+  let expected: Vec<ClientPollReply> = ["high 1", "high 2", "normal 1", "normal 2"]
+    .into_iter()
+    .map(|content| ClientPollReply::Message {
+      src: c1,
+      srcsrv: sid,
+      content: content.to_string(),
+      timestamp: 0,
+    })
+    .collect();
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  Ok(())
+}
+
+async fn edit_delete_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let (read, waiting, deleted) = (
+    MessageId::default(),
+    MessageId::default(),
+    MessageId::default(),
+  );
+  for (id, content) in [(read, "helo"), (waiting, "wrold"), (deleted, "oops")] {
+    let msg = ClientMessage::WithId {
+      id,
+      message: Box::new(ClientMessage::Text {
+        dest: c2,
+        content: content.to_string(),
+      }),
+    };
+    let r = server.handle_client_message(c1, msg).await;
+    if r != [ClientReply::Delivered(Some(id))] {
+      anyhow::bail!("Expected Delivered, got {:?}", r);
+    }
+  }
+  server.client_poll(c2).await;
+
+  let edit = |id, content: &str| ClientMessage::Edit {
+    dest: c2,
+    id,
+    content: content.to_string(),
+  };
+  for (id, msg) in [
+    (read, edit(read, "hello")),
+    (waiting, edit(waiting, "world")),
+    (
+      deleted,
+      ClientMessage::Delete {
+        dest: c2,
+        id: deleted,
+      },
+    ),
+  ] {
+    let r = server.handle_client_message(c1, msg).await;
+    if r != [ClientReply::Delivered(Some(id))] {
+      anyhow::bail!("Expected the message to be rewritten, got {:?}", r);
+    }
+  }
+  // only the sender can edit its messages, and tombstones stay tombstones
+  for (sender, msg) in [(c2, edit(read, "hijacked")), (c1, edit(deleted, "back"))] {
+    let r = server.handle_client_message(sender, msg.clone()).await;
+    let ClientMessage::Edit { id, .. } = msg else {
+      unreachable!()
+    };
+    if r != [ClientReply::Error(ClientError::UnknownMessage(id))] {
+      anyhow::bail!("Expected an unknown message, got {:?}", r);
+    }
+  }
+
+  let r = server.client_poll_n(c2, 10).await.unstamped();
+  let expected = [
+    ClientPollReply::Message {
+      src: c1,
+      srcsrv: sid,
+      content: "world".to_string(),
+      timestamp: 0,
+    },
+    ClientPollReply::Deleted { src: c1 },
+  ];
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  let r = server.client_history(c2, 10, None, true).await?.unstamped();
+  let expected = vec![
+    HistoryEntry {
+      id: read,
+      message: ClientPollReply::Message {
+        src: c1,
+        srcsrv: sid,
+        content: "hello".to_string(),
+        timestamp: 0,
+      },
+      reactions: Vec::new(),
+    },
+    HistoryEntry {
+      id: waiting,
+      message: expected[0].clone(),
+      reactions: Vec::new(),
+    },
+    HistoryEntry {
+      id: deleted,
+      message: expected[1].clone(),
+      reactions: Vec::new(),
+    },
+  ];
+  if r != expected {
+    anyhow::bail!("Expected the history {:?}, got {:?}", expected, r);
+  }
+  let r = server
+    .client_history(c2, 10, None, false)
+    .await?
+    .unstamped();
+  if r != expected[..2] {
+    anyhow::bail!("Expected the history without the tombstone, got {:?}", r);
+  }
+  Ok(())
+}
+
+/// Removed history entries leave tombstones, for a while.
+async fn tombstone_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let text = |content: String| ClientMessage::Text { dest: c2, content };
+  for n in 0..=HISTORY_SIZE {
+    server.handle_client_message(c1, text(n.to_string())).await;
+    server.client_poll(c2).await;
+  }
+  let history = server
+    .client_history(c2, HISTORY_SIZE + 1, None, false)
+    .await?;
+  if history.len() != HISTORY_SIZE {
+    anyhow::bail!("Expected {} messages, got {}", HISTORY_SIZE, history.len());
+  }
+  let last = history[HISTORY_SIZE - 1].id;
+  server
+    .handle_client_message(c1, ClientMessage::Delete { dest: c2, id: last })
+    .await;
+
+  // the forgotten message, and the deleted one
+  let all = server
+    .client_history(c2, HISTORY_SIZE + 1, None, true)
+    .await?;
+  let tombstones: Vec<_> = all
+    .iter()
+    .filter(|e| e.message == ClientPollReply::Deleted { src: c1 })
+    .collect();
+  if all.len() != HISTORY_SIZE + 1 || tombstones.len() != 2 || tombstones[1].id != last {
+    anyhow::bail!("Unexpected tombstones {:?}", tombstones);
+  }
+  let r = server.client_history(c2, 1, None, false).await?;
+  if r.first().map(|e| e.id) == Some(last) {
+    anyhow::bail!("Expected the tombstone to be hidden, got {:?}", r);
+  }
+
+  server.expire_messages(Instant::now()).await;
+  let r = server
+    .client_history(c2, HISTORY_SIZE + 1, None, true)
+    .await?;
+  if r.len() != HISTORY_SIZE + 1 {
+    anyhow::bail!(
+      "Expected the tombstones to be kept, got {} entries",
+      r.len()
+    );
+  }
+  server
+    .expire_messages(Instant::now() + TOMBSTONE_GRACE + Duration::from_secs(1))
+    .await;
+  let r = server
+    .client_history(c2, HISTORY_SIZE + 1, None, true)
+    .await?;
+  if r.len() != HISTORY_SIZE - 1 {
+    anyhow::bail!(
+      "Expected the tombstones to be dropped, got {} entries",
+      r.len()
+    );
+  }
+  Ok(())
+}
+
+async fn fan_out_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let mtext = |count| ClientMessage::MText {
+    dest: vec![c2; count],
+    content: "hi".to_string(),
+  };
+
+  // a single error, not one per recipient
+  let r = server
+    .handle_client_message(c1, mtext(MAX_DESTINATIONS + 1))
+    .await;
+  let expected = [ClientReply::Error(ClientError::TooManyDestinations(
+    MAX_DESTINATIONS as u128,
+  ))];
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  if server.client_poll(c2).await != ClientPollReply::Nothing {
+    anyhow::bail!("A refused message was delivered");
+  }
+  let r = server
+    .handle_client_message(c1, mtext(MAX_DESTINATIONS))
+    .await;
+  match &r[..] {
+    [ClientReply::Multi(each)]
+      if each.len() == MAX_DESTINATIONS
+        && each
+          .iter()
+          .all(|(d, r)| *d == c2 && matches!(r, ClientReply::Delivered(Some(_)))) => {}
+    _ => anyhow::bail!("Expected {} deliveries, got {:?}", MAX_DESTINATIONS, r),
+  }
+  Ok(())
+}
+
+async fn atomic_fan_out_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let c3 = server
+    .register_local_client(localhost(), "user 3".to_string())
+    .await
+    .unwrap();
+  let atomic = |dest: Vec<ClientId>| ClientMessage::AtomicMText {
+    dest,
+    content: "all".to_string(),
+  };
+  for i in 1..mailbox_size() {
+    let text = ClientMessage::Text {
+      dest: c3,
+      content: i.to_string(),
+    };
+    server.handle_client_message(c1, text).await;
+  }
+
+  // c3 has room for a single copy, and the other one does not exist: nobody gets it
+  for (dest, rr) in [
+    (vec![c2, c3, c3], ClientError::BoxFull(c3)),
+    (vec![c2, ClientId::default()], ClientError::UnknownClient),
+  ] {
+    let r = server.handle_client_message(c1, atomic(dest)).await;
+    if r != [ClientReply::Error(rr.clone())] {
+      anyhow::bail!("Expected a single {:?}, got {:?}", rr, r);
+    }
+  }
+  if server.client_poll(c2).await != ClientPollReply::Nothing {
+    anyhow::bail!("A refused message was delivered");
+  }
+
+  let r = server.handle_client_message(c1, atomic(vec![c2, c3])).await;
+  let id = match r[..] {
+    [ClientReply::Delivered(Some(id))] => id,
+    _ => anyhow::bail!("Expected a single delivery, got {:?}", r),
+  };
+  for dst in [c2, c3] {
+    let polled = server.client_poll_n(dst, mailbox_size()).await;
+    match polled.last() {
+      Some(ClientPollReply::Message { src, content, .. }) if *src == c1 && content == "all" => (),
+      _ => anyhow::bail!("Expected the message to reach {}, got {:?}", dst, polled),
+    }
+  }
+  // every copy has the same id
+  for dst in [c2, c3] {
+    let history = server.client_history(dst, 1, None, false).await?;
+    if history.first().map(|e| e.id) != Some(id) {
+      anyhow::bail!("Expected {} in the history, got {:?}", id, history);
+    }
+  }
+  Ok(())
+}
+
+async fn data_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await
+    .unwrap();
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await
+    .unwrap();
+  let data = |dest, size| ClientMessage::Data {
+    dest,
+    mime: "application/octet-stream".to_string(),
+    bytes: vec![42; size],
+  };
+
+  let r = server
+    .handle_client_message(c1, data(c2, MAX_DATA_SIZE + 1))
+    .await;
+  let expected = [ClientReply::Error(ClientError::MessageTooLarge(
+    MAX_DATA_SIZE as u128,
+  ))];
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  let r = server
+    .handle_client_message(c1, data(c2, MAX_DATA_SIZE))
+    .await;
+  let id = first_id(&r)?;
+  let expected = ClientPollReply::Data {
+    src: c1,
+    mime: "application/octet-stream".to_string(),
+    bytes: vec![42; MAX_DATA_SIZE],
+  };
+  let r = server.client_poll(c2).await;
+  if r != expected {
+    anyhow::bail!("Expected the payload, got {:?}", r);
+  }
+  let r = server.client_history(c2, 1, None, false).await?;
+  if r
+    != [HistoryEntry {
+      id,
+      message: expected,
+      reactions: Vec::new(),
+    }]
+  {
+    anyhow::bail!("Expected the payload in the history, got {:?}", r);
+  }
+
+  // federated messages are text only
+  let euuid = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![ServerId::default()],
+      clients: HashMap::from([(euuid, "external user".into())]),
+    })
+    .await;
+  for dest in [euuid, ClientId::default()] {
+    let r = server.handle_client_message(c1, data(dest, 1)).await;
+    if r != [ClientReply::Error(ClientError::Forbidden)] {
+      anyhow::bail!("Expected a forbidden error, got {:?}", r);
+    }
+  }
+  Ok(())
+}
+
+/// a sender can't fill the server with messages to unknown recipients
+async fn delayed_quota_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let text = |dest| ClientMessage::Text {
+    dest,
+    content: "hello".into(),
+  };
+  for _ in 0..DELAYED_PER_SENDER {
+    let r = server
+      .handle_client_message(c1, text(ClientId::default()))
+      .await;
+    if !matches!(r[..], [ClientReply::Delayed(_)]) {
+      anyhow::bail!("Expected a delayed message, got {:?}", r);
+    }
+  }
+  let r = server
+    .handle_client_message(c1, text(ClientId::default()))
+    .await;
+  let expected = [ClientReply::Error(ClientError::DelayedQuota(
+    DELAYED_PER_SENDER as u128,
+  ))];
+  if r != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, r);
+  }
+  // the quota is per sender
+  let r = server
+    .handle_client_message(c2, text(ClientId::default()))
+    .await;
+  if !matches!(r[..], [ClientReply::Delayed(_)]) {
+    anyhow::bail!("Expected a delayed message, got {:?}", r);
+  }
+  // and freed once the messages expire
+  server
+    .expire_messages(Instant::now() + MESSAGE_TTL + Duration::from_secs(1))
+    .await;
+  let r = server
+    .handle_client_message(c1, text(ClientId::default()))
+    .await;
+  if !matches!(r[..], [ClientReply::Delayed(_)]) {
+    anyhow::bail!("Expected a delayed message after expiration, got {:?}", r);
+  }
+  Ok(())
+}
+
+/// handshakes reach the mailbox, but not the history
+async fn key_agreement_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let c1 = server
+    .register_local_client(localhost(), "user 1".to_string())
+    .await?;
+  let c2 = server
+    .register_local_client(localhost(), "user 2".to_string())
+    .await?;
+  let hello = |dest| ClientMessage::KeyAgreement {
+    dest,
+    payload: vec![1, 2, 3],
+  };
+  let r = server.handle_client_message(c1, hello(c2)).await;
+  if r != [ClientReply::Delivered(None)] {
+    anyhow::bail!("Expected a delivery without id, got {:?}", r);
+  }
+  let r = server.client_poll(c2).await;
+  let expected = ClientPollReply::KeyAgreement {
+    src: c1,
+    payload: vec![1, 2, 3],
+  };
+  if r != expected {
+    anyhow::bail!("Expected the handshake, got {:?}", r);
+  }
+  let r = server.client_history(c2, 10, None, false).await?;
+  if !r.is_empty() {
+    anyhow::bail!("Handshakes should not be kept, got {:?}", r);
+  }
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::KeyAgreement {
+        dest: c2,
+        payload: vec![0; MAX_DATA_SIZE + 1],
+      },
+    )
+    .await;
+  if r
+    != [ClientReply::Error(ClientError::MessageTooLarge(
+      MAX_DATA_SIZE as u128,
+    ))]
+  {
+    anyhow::bail!("Expected a too large error, got {:?}", r);
+  }
+  // unlike messages, they are not kept for unknown recipients
+  let r = server
+    .handle_client_message(c1, hello(ClientId::default()))
+    .await;
+  if r != [ClientReply::Error(ClientError::UnknownClient)] {
+    anyhow::bail!("Expected an unknown client error, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn server_sequence_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let server: M = MessageServer::new(
+    TestChecker::default(),
+    ServerId::default(),
+    ServerConfig::default(),
+  );
+  let (s1, s2) = (ServerId::default(), ServerId::default());
+  let frame = |src, seqid| ServerSequence {
+    seqid,
+    src,
+    content: (),
+  };
+  if server.handle_server_sequence(frame(s1, 5)).await.is_none() {
+    anyhow::bail!("The first frame from a server should be accepted");
+  }
+  for seqid in [5, 4] {
+    if server
+      .handle_server_sequence(frame(s1, seqid))
+      .await
+      .is_some()
+    {
+      anyhow::bail!("Frame {} was replayed", seqid);
+    }
+  }
+  // each server has its own sequence
+  if server.handle_server_sequence(frame(s2, 1)).await.is_none() {
+    anyhow::bail!("Sequences should be kept per server");
+  }
+  if server.handle_server_sequence(frame(s1, 6)).await.is_none() {
+    anyhow::bail!("The next frame should be accepted");
+  }
+  Ok(())
+}
+
+async fn test_route<M: MessageServer<TestChecker>>(
+  server: &M,
+  dest: ServerId,
+  expected: Vec<ServerId>,
+) -> anyhow::Result<()> {
+  let rt = server.route_to(dest).await;
+  if rt.as_ref() != Some(&expected) {
+    anyhow::bail!("wrong route: expected {:?}, got {:?}", expected, rt)
+  }
+  Ok(())
+}
+
+async fn routing_test<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  /* map:
+
+       us - s1 - s2
+        |         |
+       s5 - s4 - s3
+  */
+  let s1 = ServerId::from(1);
+  let s2 = ServerId::from(2);
+  let s3 = ServerId::from(3);
+  let s4 = ServerId::from(4);
+  let s5 = ServerId::from(5);
+  let s4_user = ClientId::default();
+  // first advertise the long route
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s4, s3, s2, s1],
+      clients: HashMap::from([(s4_user, "s4 user".into())]),
+    })
+    .await;
+  let expected_empty_out = ServerReply::Outgoing(Vec::new());
+  if r != expected_empty_out {
+    anyhow::bail!("msg1: Expected {:?}\n,    got {:?}", expected_empty_out, r);
+  }
+  test_route(&server, s4, vec![sid, s1, s2, s3, s4]).await?;
+  // now advertise another alternative route
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s2, s3, s4, s5],
+      clients: HashMap::new(),
+    })
+    .await;
+  if r != expected_empty_out {
+    anyhow::bail!("msg3: Expected {:?}\n,    got {:?}", expected_empty_out, r);
+  }
+  test_route(&server, s4, vec![sid, s5, s4]).await?;
+  Ok(())
+}
+
+async fn routing_test2<M: MessageServer<TestChecker>>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(TestChecker::default(), sid, ServerConfig::default());
+
+  /* map:
+
+       us - s1 - s2 - s6
+        |         |    |
+       s5 - s4 - s3    |
+             |         |
+            s7 ---------
+  */
+  let s1 = ServerId::from(1);
+  let s2 = ServerId::from(2);
+  let s3 = ServerId::from(3);
+  let s4 = ServerId::from(4);
+  let s5 = ServerId::from(5);
+  let s6 = ServerId::from(6);
+  let s7 = ServerId::from(7);
+  let s7_user = ClientId::default();
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s7, s6, s2, s3, s4, s5],
+      clients: HashMap::from([(s7_user, "user".to_string())]),
+    })
+    .await;
+  let expected_empty_out = ServerReply::Outgoing(Vec::new());
+  if r != expected_empty_out {
+    anyhow::bail!("msg1: Expected {:?}\n,    got {:?}", expected_empty_out, r);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s5, s4, s7, s6, s2, s1],
+      clients: HashMap::new(),
+    })
+    .await;
+  let expected_empty_out = ServerReply::Outgoing(Vec::new());
+  if r != expected_empty_out {
+    anyhow::bail!("msg1: Expected {:?}\n,    got {:?}", expected_empty_out, r);
+  }
+  test_route(&server, s4, vec![sid, s5, s4])
+    .await
+    .context("r1")?;
+  test_route(&server, s6, vec![sid, s1, s2, s6])
+    .await
+    .context("r2")?;
+  test_route(&server, s7, vec![sid, s5, s4, s7])
+    .await
+    .context("r3")?;
+  Ok(())
+}
+
+async fn all_tests<M: MessageServer<TestChecker>>(counter: &mut usize) -> anyhow::Result<()> {
+  sequence_correct::<M>()
+    .await
+    .with_context(|| "sequence_correct")?;
+  *counter += 1;
+  sequence_unknown_user::<M>()
+    .await
+    .with_context(|| "sequence_unknown_user")?;
+  *counter += 1;
+  sequence_window::<M>()
+    .await
+    .with_context(|| "sequence_window")?;
+  *counter += 1;
+  simple_client_test::<M>()
+    .await
+    .with_context(|| "simple_client_test")?;
+  *counter += 1;
+  list_users_test::<M>()
+    .await
+    .with_context(|| "list_users_test")?;
+  *counter += 1;
+  unique_names_test::<M>()
+    .await
+    .with_context(|| "unique_names_test")?;
+  *counter += 1;
+  list_users_page_test::<M>()
+    .await
+    .with_context(|| "list_users_page_test")?;
+  *counter += 1;
+  multiple_client_messages_test::<M>()
+    .await
+    .with_context(|| "multiple_client_message_test")?;
+  *counter += 1;
+  mixed_results_client_message::<M>()
+    .await
+    .with_context(|| "mixed_results_client_message")?;
+  *counter += 1;
+  rich_message_test::<M>()
+    .await
+    .with_context(|| "rich_message_test")?;
+  *counter += 1;
+  mailbox_full::<M>().await.with_context(|| "mailbox_full")?;
+  *counter += 1;
+  spammer_ip::<M>().await.with_context(|| "spammer_ip")?;
+  *counter += 1;
+  spammer_user::<M>().await.with_context(|| "spammer_user")?;
+  *counter += 1;
+  spammer_both::<M>().await.with_context(|| "spammer_both")?;
+  *counter += 1;
+  message_to_outer_user::<M>()
+    .await
+    .with_context(|| "message_to_outer_user")?;
+  *counter += 1;
+  message_to_outer_user_delayed::<M>()
+    .await
+    .with_context(|| "message_to_outer_user_delayed")?;
+  *counter += 1;
+  delayed_queue::<M>()
+    .await
+    .with_context(|| "delayed_queue")?;
+  *counter += 1;
+  expiry_test::<M>().await.with_context(|| "expiry_test")?;
+  *counter += 1;
+  content_type_federation::<M>()
+    .await
+    .with_context(|| "content_type_federation")?;
+  *counter += 1;
+  batch_test::<M>().await.with_context(|| "batch_test")?;
+  *counter += 1;
+  report_test::<M>().await.with_context(|| "report_test")?;
+  *counter += 1;
+  notification_prefs_test::<M>()
+    .await
+    .with_context(|| "notification_prefs_test")?;
+  *counter += 1;
+  guest_test::<M>().await.with_context(|| "guest_test")?;
+  *counter += 1;
+  ordering_test::<M>()
+    .await
+    .with_context(|| "ordering_test")?;
+  *counter += 1;
+  room_test::<M>().await.with_context(|| "room_test")?;
+  *counter += 1;
+  federated_room_test::<M>()
+    .await
+    .with_context(|| "federated_room_test")?;
+  *counter += 1;
+  history_test::<M>().await.with_context(|| "history_test")?;
+  *counter += 1;
+  receipt_test::<M>().await.with_context(|| "receipt_test")?;
+  *counter += 1;
+  reaction_test::<M>()
+    .await
+    .with_context(|| "reaction_test")?;
+  *counter += 1;
+  do_not_disturb_test::<M>()
+    .await
+    .with_context(|| "do_not_disturb_test")?;
+  *counter += 1;
+  directory_test::<M>()
+    .await
+    .with_context(|| "directory_test")?;
+  *counter += 1;
+  presence_test::<M>()
+    .await
+    .with_context(|| "presence_test")?;
+  *counter += 1;
+  unregister_test::<M>()
+    .await
+    .with_context(|| "unregister_test")?;
+  *counter += 1;
+  evict_idle_test::<M>()
+    .await
+    .with_context(|| "evict_idle_test")?;
+  *counter += 1;
+  broadcast_forbidden::<M>()
+    .await
+    .with_context(|| "broadcast_forbidden")?;
+  *counter += 1;
+  trusted_registration_forbidden::<M>()
+    .await
+    .with_context(|| "trusted_registration_forbidden")?;
+  *counter += 1;
+  block_test::<M>().await.with_context(|| "block_test")?;
+  *counter += 1;
+  poll_n_test::<M>().await.with_context(|| "poll_n_test")?;
+  *counter += 1;
+  poll_wait_test::<M>()
+    .await
+    .with_context(|| "poll_wait_test")?;
+  *counter += 1;
+  stream_test::<M>().await.with_context(|| "stream_test")?;
+  *counter += 1;
+  sender_order_test::<M>()
+    .await
+    .with_context(|| "sender_order_test")?;
+  *counter += 1;
+  provenance_test::<M>()
+    .await
+    .with_context(|| "provenance_test")?;
+  *counter += 1;
+  reply_to_test::<M>()
+    .await
+    .with_context(|| "reply_to_test")?;
+  *counter += 1;
+  sync_test::<M>().await.with_context(|| "sync_test")?;
+  *counter += 1;
+  event_test::<M>().await.with_context(|| "event_test")?;
+  *counter += 1;
+  reserved_names::<M>()
+    .await
+    .with_context(|| "reserved_names")?;
+  *counter += 1;
+  priority_test::<M>()
+    .await
+    .with_context(|| "priority_test")?;
+  *counter += 1;
+  edit_delete_test::<M>()
+    .await
+    .with_context(|| "edit_delete_test")?;
+  *counter += 1;
+  tombstone_test::<M>()
+    .await
+    .with_context(|| "tombstone_test")?;
+  *counter += 1;
+  data_test::<M>().await.with_context(|| "data_test")?;
+  *counter += 1;
+  fan_out_test::<M>().await.with_context(|| "fan_out_test")?;
+  *counter += 1;
+  atomic_fan_out_test::<M>()
+    .await
+    .with_context(|| "atomic_fan_out_test")?;
+  *counter += 1;
+  key_agreement_test::<M>()
+    .await
+    .with_context(|| "key_agreement_test")?;
+  *counter += 1;
+  delayed_quota_test::<M>()
+    .await
+    .with_context(|| "delayed_quota_test")?;
+  *counter += 1;
+  server_sequence_test::<M>()
+    .await
+    .with_context(|| "server_sequence_test")?;
+  *counter += 1;
+  spammer_delay_ip::<M>()
+    .await
+    .with_context(|| "spammer_delay_ip")?;
+  *counter += 1;
+  spammer_delay_user::<M>()
+    .await
+    .with_context(|| "spammer_delay_user")?;
+  *counter += 1;
+  registration_storm::<M>()
+    .await
+    .with_context(|| "registration_storm")?;
+  *counter += 1;
+  routing_test::<M>().await.with_context(|| "real routing")?;
+  *counter += 1;
+  routing_test2::<M>()
+    .await
+    .with_context(|| "real routing 2")?;
+  *counter += 1;
+  Ok(())
+}
+
+pub(crate) fn test_message_server<M: MessageServer<TestChecker>>() {
+  pretty_env_logger::init();
+  async_std::task::block_on(async {
+    let mut counter = 0;
+    match all_tests::<M>(&mut counter).await {
+      Ok(()) => (),
+      Err(rr) => panic!("counter={}, error={:?}", counter, rr),
+    }
+  });
+}
@@ -0,0 +1,1291 @@
+//! The messages exchanged between clients and servers, and between servers.
+//!
+//! These types are the wire protocol: the codec in `chatproto::netproto` encodes them field by
+//! field and variant by variant. Any change to a type, its fields or its variants (order
+//! included) is a breaking change of this crate, and bumps its major version. New items that do
+//! not change existing encodings only bump the minor version.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct ClientId(pub Uuid);
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct ServerId(pub Uuid);
+
+/// identifies a single message, independently of its recipients
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct MessageId(pub Uuid);
+
+/// identifies a chat room
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct RoomId(pub Uuid);
+
+/// the neighbour a message must be sent to, only computed by the `Router` of the server
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct NextHop(pub ServerId);
+
+/// the server an announce (and its clients) comes from, only computed by the `Router` of the
+/// server
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct OriginServer(pub ServerId);
+
+impl NextHop {
+  pub fn server(self) -> ServerId {
+    self.0
+  }
+}
+
+impl OriginServer {
+  pub fn server(self) -> ServerId {
+    self.0
+  }
+}
+
+/// names that clients can't register with, whatever their case
+pub const RESERVED_NAMES: [&str; 3] = ["server", "admin", "system"];
+
+pub fn is_reserved_name(name: &str) -> bool {
+  RESERVED_NAMES.iter().any(|r| same_name(r, name))
+}
+
+/// names are compared without their surrounding spaces, whatever their case
+pub fn same_name(a: &str, b: &str) -> bool {
+  a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+impl ClientId {
+  /// source of the notices written by the server itself
+  pub const SERVER: ClientId = ClientId(Uuid::from_u128(u128::MAX));
+  /// source of the broadcasts, whichever admin sent them
+  pub const ADMIN: ClientId = ClientId(Uuid::from_u128(u128::MAX - 1));
+
+  /// the identities no client can be registered as, with their reserved name
+  pub const SYSTEM: [(ClientId, &'static str); 2] =
+    [(ClientId::SERVER, "server"), (ClientId::ADMIN, "admin")];
+
+  pub fn is_system(&self) -> bool {
+    self.system_name().is_some()
+  }
+
+  pub fn system_name(&self) -> Option<&'static str> {
+    ClientId::SYSTEM
+      .iter()
+      .find(|(id, _)| id == self)
+      .map(|(_, name)| *name)
+  }
+
+  /// the last 8 hex digits of the id, enough to tell apart the users of a name
+  pub fn short(&self) -> String {
+    let simple = self.0.simple().to_string();
+    simple[simple.len() - 8..].to_string()
+  }
+}
+
+impl From<u128> for ClientId {
+  fn from(value: u128) -> Self {
+    ClientId(Uuid::from_u128_le(value))
+  }
+}
+
+impl From<u128> for ServerId {
+  fn from(value: u128) -> Self {
+    ServerId(Uuid::from_u128_le(value))
+  }
+}
+
+impl From<u128> for MessageId {
+  fn from(value: u128) -> Self {
+    MessageId(Uuid::from_u128_le(value))
+  }
+}
+
+impl From<u128> for RoomId {
+  fn from(value: u128) -> Self {
+    RoomId(Uuid::from_u128_le(value))
+  }
+}
+
+impl From<&ClientId> for u128 {
+  fn from(value: &ClientId) -> Self {
+    value.0.to_u128_le()
+  }
+}
+
+impl From<&ServerId> for u128 {
+  fn from(value: &ServerId) -> Self {
+    value.0.to_u128_le()
+  }
+}
+
+impl Default for ClientId {
+  fn default() -> ClientId {
+    ClientId(Uuid::new_v4())
+  }
+}
+
+impl Default for ServerId {
+  fn default() -> ServerId {
+    ServerId(Uuid::new_v4())
+  }
+}
+
+impl Default for MessageId {
+  fn default() -> MessageId {
+    MessageId(Uuid::new_v4())
+  }
+}
+
+impl Default for RoomId {
+  fn default() -> RoomId {
+    RoomId(Uuid::new_v4())
+  }
+}
+
+impl From<Uuid> for ClientId {
+  fn from(value: Uuid) -> Self {
+    ClientId(value)
+  }
+}
+
+impl From<Uuid> for ServerId {
+  fn from(value: Uuid) -> Self {
+    ServerId(value)
+  }
+}
+
+impl From<Uuid> for MessageId {
+  fn from(value: Uuid) -> Self {
+    MessageId(value)
+  }
+}
+
+impl From<Uuid> for RoomId {
+  fn from(value: Uuid) -> Self {
+    RoomId(value)
+  }
+}
+
+impl std::str::FromStr for ClientId {
+  type Err = uuid::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Uuid::parse_str(s).map(ClientId)
+  }
+}
+
+impl std::str::FromStr for ServerId {
+  type Err = uuid::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Uuid::parse_str(s).map(ServerId)
+  }
+}
+
+impl std::fmt::Display for ClientId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "ClientId({})", self.0)
+  }
+}
+
+impl std::fmt::Display for ServerId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "ServerId({})", self.0)
+  }
+}
+
+impl std::fmt::Display for MessageId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "MessageId({})", self.0)
+  }
+}
+
+impl std::fmt::Display for RoomId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "RoomId({})", self.0)
+  }
+}
+
+impl std::fmt::Display for NextHop {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    self.0.fmt(f)
+  }
+}
+
+impl std::fmt::Display for OriginServer {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    self.0.fmt(f)
+  }
+}
+
+/// a message with the increasing sequence number of its sender, clients by default
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Sequence<A, S = ClientId> {
+  pub seqid: u128,
+  pub src: S,
+  pub content: A,
+}
+
+/// a frame between servers, sequenced by the server that sent it
+pub type ServerSequence<A> = Sequence<A, ServerId>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AuthMessage {
+  Hello {
+    user: ClientId,
+    nonce: [u8; 8],
+  },
+  Nonce {
+    server: ServerId,
+    nonce: [u8; 8],
+  },
+  Auth {
+    response: [u8; 16],
+  },
+  /// a challenge whose response must also cover the `channel` it was sent on, so that it cannot be
+  /// replayed on another connection
+  BoundNonce {
+    server: ServerId,
+    nonce: [u8; 8],
+    channel: [u8; 16],
+  },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClientQuery {
+  Register(String), // name of the user
+  Message(ClientMessage),
+  Poll,
+  ListUsers,
+  /// report abuse to the server operators
+  Report {
+    target: ReportTarget,
+    reason: String,
+  },
+  GetPrefs,
+  SetPrefs(NotificationPrefs),
+  /// read-only registration, without spam checks
+  RegisterGuest(String),
+  /// turns a guest into a full member, once the spam checks pass
+  Upgrade,
+  /// creates a room, and joins it
+  CreateRoom(String),
+  JoinRoom(RoomId),
+  LeaveRoom(RoomId),
+  /// removes the client, and everything the server keeps about it
+  Unregister,
+  /// liveness check, answered with a `Pong` carrying the same nonce
+  /// works before registration, and does not use up a sequence number
+  Ping(u128),
+  /// polls up to this many messages at once, `Nothing` is never part of the reply
+  PollN(u128),
+  /// replaces the clients whose messages are refused
+  SetBlocked(Vec<ClientId>),
+  /// registers a client with a chosen id, without spam checks, reserved to admins
+  /// for system accounts, bridges and migrations, that keep their identity
+  RegisterTrusted {
+    id: ClientId,
+    name: String,
+  },
+  /// changes the name of the client, on every server
+  Rename(String),
+  /// the id of the user with this name, if there is one
+  LookupUser(String),
+  /// lists known users like `ListUsers`, with their server, and a tag for the shared names
+  ListUserEntries,
+  /// a page of the users of `ListUsers`, and optionally of the remote ones
+  ListUsersPage(UserQuery),
+  /// a page of the messages of the client history that match, for servers built with search
+  Search(SearchQuery),
+  /// polls like `Poll`, but waits up to this many milliseconds for something to poll
+  PollWait(u128),
+  /// polls everything, and returns what changed since the cursor, in a single round trip
+  Sync {
+    cursor: SyncCursor,
+    /// the most history entries in the reply, servers can return less
+    limit: u128,
+    /// asks for the compact encoding of the reply, that sends every client id once
+    compact: bool,
+  },
+  /// the codecs the client can use, preferred first, answered with the one the server picked
+  /// (binary when it enables none of them), for the next queries
+  /// works before registration, and does not use up a sequence number
+  Hello(Vec<Codec>),
+  /// registers like `Register`, with an invite issued by the operators of a closed server
+  RegisterInvited {
+    name: String,
+    invite: Vec<u8>,
+  },
+}
+
+/// how a client query, and its reply, are encoded
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Codec {
+  /// the codec of `netproto`, always enabled
+  #[default]
+  Binary,
+  /// the serde representation of the messages, for debugging
+  Json,
+}
+
+impl std::fmt::Display for Codec {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Codec::Binary => "binary".fmt(f),
+      Codec::Json => "json".fmt(f),
+    }
+  }
+}
+
+impl std::str::FromStr for Codec {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "binary" => Ok(Codec::Binary),
+      "json" => Ok(Codec::Json),
+      _ => Err(anyhow::anyhow!("expected binary or json, got {}", s)),
+    }
+  }
+}
+
+/// where a device is in the stores of its client, `SyncCursor::default()` on the first sync
+/// a cursor whose history entry is gone is too old, and the sync falls back to a full one
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncCursor {
+  /// the last history entry seen, the whole history is returned when it is not there anymore
+  pub history: Option<MessageId>,
+  /// the version of the read markers seen
+  pub read_markers: u128,
+}
+
+/// the reply to `Sync`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReply {
+  /// the history after the cursor, oldest first, tombstones included
+  pub messages: Vec<HistoryEntry>,
+  /// presence changes of the clients we subscribed to, oldest first
+  pub presence: Vec<(ClientId, Presence)>,
+  /// our read markers that changed: the last message acked, per author
+  pub read_markers: Vec<(ClientId, MessageId)>,
+  /// receipts of our messages: the id from the reader history, and the reader
+  pub receipts: Vec<(MessageId, ClientId)>,
+  /// everything else that was polled (events, delivery notices, errors, handshakes)
+  pub other: Vec<ClientPollReply>,
+  /// where the next sync starts
+  pub cursor: SyncCursor,
+  /// the cursor was the default one or too old: the reply replaces the state of the device, with
+  /// the whole history, the current presence of every subscription, and every read marker
+  pub full: bool,
+}
+
+/// which users a name filter keeps, names are compared whatever their case
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum NameFilter {
+  Prefix(String),
+  Contains(String),
+}
+
+impl NameFilter {
+  pub fn matches(&self, name: &str) -> bool {
+    let name = name.to_lowercase();
+    match self {
+      NameFilter::Prefix(prefix) => name.starts_with(&prefix.to_lowercase()),
+      NameFilter::Contains(part) => name.contains(&part.to_lowercase()),
+    }
+  }
+}
+
+/// a page of users, in the order of their ids
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserQuery {
+  pub filter: Option<NameFilter>,
+  /// the `next` of the previous page, `None` for the first page
+  pub after: Option<ClientId>,
+  /// the most users in the page, servers can return less
+  pub limit: u128,
+  /// also the remote users the server learnt from the federation
+  pub federated: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserPage {
+  /// with their server, but without tags
+  pub users: Vec<UserEntry>,
+  /// where the next page starts, `None` on the last page
+  pub next: Option<ClientId>,
+}
+
+/// a user listed by `ListUserEntries` or `ListUsersPage`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserEntry {
+  pub id: ClientId,
+  pub name: String,
+  /// the server the user is registered on
+  pub server: ServerId,
+  /// set by `ListUserEntries` when other listed users have the same name, to render them apart
+  /// (the `short` id)
+  pub tag: Option<String>,
+}
+
+impl UserEntry {
+  /// the name, followed by the tag if there is one
+  pub fn display_name(&self) -> String {
+    match &self.tag {
+      Some(tag) => format!("{}#{}", self.name, tag),
+      None => self.name.clone(),
+    }
+  }
+}
+
+/// what a client tells about its availability, clients are online once registered
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Presence {
+  #[default]
+  Online,
+  Away,
+  Offline,
+}
+
+/// the mailbox lane a message waits in, lanes are polled in this order
+#[derive(
+  Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum Priority {
+  /// reserved to the server
+  System,
+  High,
+  #[default]
+  Normal,
+}
+
+/// a short-lived signal between two clients, never stored in mailboxes nor histories
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+  Typing,
+  StoppedTyping,
+  /// sent by the server to the local recipients a rich message mentions, with the id of the
+  /// message; clients can't send it
+  Mentioned(MessageId),
+}
+
+/// a daily period without notifications, in minutes since midnight UTC
+/// when `start > end`, the period wraps around midnight
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+  pub start: u16,
+  pub end: u16,
+}
+
+impl QuietHours {
+  pub fn contains(&self, minute: u16) -> bool {
+    if self.start <= self.end {
+      (self.start..self.end).contains(&minute)
+    } else {
+      minute >= self.start || minute < self.end
+    }
+  }
+}
+
+/// what a client wants to be notified about, kept by its server
+///
+/// Messages are always delivered to the mailbox, these preferences only tell notifying
+/// subsystems when to stay silent.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationPrefs {
+  pub muted: Vec<ClientId>,
+  pub quiet_hours: Option<QuietHours>,
+}
+
+impl NotificationPrefs {
+  /// should a notification about something from `src` be emitted at `minute` (since midnight UTC)
+  pub fn should_notify(&self, src: ClientId, minute: u16) -> bool {
+    !self.muted.contains(&src) && !self.quiet_hours.is_some_and(|q| q.contains(minute))
+  }
+}
+
+/// what an abuse report is about
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReportTarget {
+  Message(MessageId),
+  Client(ClientId),
+}
+
+/// an abuse report, as kept by the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AbuseReport {
+  pub reporter: ClientId,
+  pub target: ReportTarget,
+  pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+  /// simple text message
+  Text { dest: ClientId, content: String },
+  /// multiple targets text message
+  MText {
+    dest: Vec<ClientId>,
+    content: String,
+  },
+  /// structured message, for now text with mentions
+  Rich {
+    dest: Vec<ClientId>,
+    content: RichContent,
+  },
+  /// text message to every other member of a room
+  RoomText { room: RoomId, content: String },
+  /// the message, as found in the client history, was read
+  /// a `ClientPollReply::Receipt` is sent back to its author
+  Ack(MessageId),
+  /// changes our presence, subscribers are notified
+  SetPresence(Presence),
+  /// get the presence changes of a client in our poll stream, starting with its current presence
+  SubscribePresence(ClientId),
+  /// text message to every other client, local or remote, reserved to admins
+  Broadcast { content: String },
+  /// ephemeral event, that may be dropped when the recipient does not poll
+  Event { dest: ClientId, event: Event },
+  /// any other message, delivered in the given lane instead of `Priority::Normal`
+  Prioritized {
+    priority: Priority,
+    message: Box<ClientMessage>,
+  },
+  /// any other message, with an id chosen by the sender instead of the server, so that it can
+  /// be edited or deleted later
+  WithId {
+    id: MessageId,
+    message: Box<ClientMessage>,
+  },
+  /// rewrites our message `id` to `dest`, while it is in its mailbox or history
+  Edit {
+    dest: ClientId,
+    id: MessageId,
+    content: String,
+  },
+  /// replaces our message `id` to `dest` with a tombstone
+  Delete { dest: ClientId, id: MessageId },
+  /// binary payload of the given media type, refused with `MessageTooLarge` above the limit of
+  /// the server
+  Data {
+    dest: ClientId,
+    mime: String,
+    bytes: Vec<u8>,
+  },
+  /// opaque handshake message of an end-to-end encryption protocol (X3DH, Noise, ...), routed
+  /// like a message but never inspected, edited, nor kept in the history
+  KeyAgreement { dest: ClientId, payload: Vec<u8> },
+  /// a text, rich, room or data message answering `in_reply_to`, the id of a message of the
+  /// history, so that clients can thread the conversation
+  ReplyTo {
+    in_reply_to: MessageId,
+    message: Box<ClientMessage>,
+  },
+  /// reacts with an emoji to a message, as found in the client history
+  /// a `ClientPollReply::Reaction` is sent back to its author, and the reaction is kept with the
+  /// history entry
+  React { message: MessageId, emoji: String },
+  /// while on, the messages to us wait aside instead of in the mailbox, and are moved to it, in
+  /// the order they came, when it is turned off
+  SetDoNotDisturb(bool),
+  /// multiple targets text message, delivered to all of them or to none, with a single reply
+  AtomicMText {
+    dest: Vec<ClientId>,
+    content: String,
+  },
+}
+
+/// a reference to a client, as a byte span of the message text (usually "@name")
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mention {
+  pub client: ClientId,
+  pub start: usize,
+  pub len: usize,
+}
+
+impl Mention {
+  /// the span of `text` it points to, none when it does not fall on the text character boundaries
+  pub fn span<'a>(&self, text: &'a str) -> Option<&'a str> {
+    text.get(self.start..self.start.checked_add(self.len)?)
+  }
+}
+
+/// how the text of a message should be rendered
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ContentType {
+  /// text/plain
+  #[default]
+  Plain,
+  /// text/markdown
+  Markdown,
+  /// any other media type, such as "application/x-bot-command"
+  Custom(String),
+}
+
+impl std::fmt::Display for ContentType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ContentType::Plain => "text/plain".fmt(f),
+      ContentType::Markdown => "text/markdown".fmt(f),
+      ContentType::Custom(mime) => mime.fmt(f),
+    }
+  }
+}
+
+impl From<&str> for ContentType {
+  fn from(value: &str) -> Self {
+    match value {
+      "text/plain" => ContentType::Plain,
+      "text/markdown" => ContentType::Markdown,
+      mime => ContentType::Custom(mime.to_string()),
+    }
+  }
+}
+
+/// text annotated with mention spans, and tagged with its content type
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RichContent {
+  pub text: String,
+  pub mentions: Vec<Mention>,
+  pub content_type: ContentType,
+}
+
+impl RichContent {
+  /// sets the content type
+  pub fn with_content_type(mut self, content_type: ContentType) -> Self {
+    self.content_type = content_type;
+    self
+  }
+
+  /// appends raw text
+  pub fn text(mut self, text: &str) -> Self {
+    self.text.push_str(text);
+    self
+  }
+
+  /// appends "@name", recorded as a mention of `client`
+  pub fn mention(mut self, client: ClientId, name: &str) -> Self {
+    let start = self.text.len();
+    self.text.push('@');
+    self.text.push_str(name);
+    self.mentions.push(Mention {
+      client,
+      start,
+      len: self.text.len() - start,
+    });
+    self
+  }
+
+  /// builds content from user input, turning every "@name" of a known user into a mention
+  /// the longest matching name wins when several users share a prefix
+  pub fn parse(text: &str, users: &HashMap<ClientId, String>) -> Self {
+    let mut mentions = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = text[pos..].find('@') {
+      let start = pos + offset;
+      let rest = &text[start + 1..];
+      let best = users
+        .iter()
+        .filter(|(_, name)| !name.is_empty() && rest.starts_with(name.as_str()))
+        .max_by_key(|(_, name)| name.len());
+      match best {
+        Some((client, name)) => {
+          let len = name.len() + 1;
+          mentions.push(Mention {
+            client: *client,
+            start,
+            len,
+          });
+          pos = start + len;
+        }
+        None => pos = start + 1,
+      }
+    }
+    RichContent {
+      text: text.to_string(),
+      mentions,
+      content_type: ContentType::Plain,
+    }
+  }
+
+  /// mentioned clients, with the text they are attached to
+  /// spans that do not fall on the text character boundaries are skipped
+  pub fn mentions(&self) -> impl Iterator<Item = (ClientId, &str)> {
+    self
+      .mentions
+      .iter()
+      .filter_map(|m| m.span(&self.text).map(|span| (m.client, span)))
+  }
+
+  pub fn mentions_client(&self, client: ClientId) -> bool {
+    self.mentions().any(|(c, _)| c == client)
+  }
+
+  /// true if all mention spans can be resolved in the text
+  pub fn is_valid(&self) -> bool {
+    self.mentions().count() == self.mentions.len()
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FullyQualifiedMessage {
+  pub src: ClientId,
+  pub srcsrv: ServerId,
+  pub dsts: Vec<(ClientId, ServerId)>,
+  pub content: String,
+  pub content_type: ContentType,
+  /// the mentions of a rich message, as spans of `content`
+  pub mentions: Vec<Mention>,
+  /// the position of the message among those of `src` to its recipient, from 1, so that the
+  /// recipient gets them in order whatever their path; 0 when it is not sequenced
+  pub seq: u128,
+  /// the message this one answers
+  pub in_reply_to: Option<MessageId>,
+  /// when `srcsrv` accepted the message, in milliseconds since the Unix epoch; 0 when unknown
+  pub timestamp: u64,
+  /// the id `srcsrv` returned to the sender, that the recipient keeps for it too
+  pub id: MessageId,
+}
+
+impl FullyQualifiedMessage {
+  /// starts a message from `src`, a client of `srcsrv`
+  pub fn builder(src: ClientId, srcsrv: ServerId) -> MessageBuilder {
+    MessageBuilder(FullyQualifiedMessage {
+      src,
+      srcsrv,
+      dsts: Vec::new(),
+      content: String::new(),
+      content_type: ContentType::Plain,
+      mentions: Vec::new(),
+      seq: 0,
+      in_reply_to: None,
+      timestamp: 0,
+      id: MessageId::default(),
+    })
+  }
+
+  /// a message has at least one destination, each recipient at most once, and mentions inside
+  /// its content
+  pub fn validate(&self) -> Result<(), MessageError> {
+    if self.dsts.is_empty() {
+      return Err(MessageError::NoDestination);
+    }
+    if let Some(m) = self
+      .mentions
+      .iter()
+      .find(|m| m.span(&self.content).is_none())
+    {
+      return Err(MessageError::InvalidMention(m.client));
+    }
+    for (i, (dst, _)) in self.dsts.iter().enumerate() {
+      if self.dsts[..i].iter().any(|(other, _)| other == dst) {
+        return Err(MessageError::DuplicateDestination(*dst));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Builds a `FullyQualifiedMessage`, that is validated when built.
+#[derive(Clone, Debug)]
+pub struct MessageBuilder(FullyQualifiedMessage);
+
+impl MessageBuilder {
+  /// adds a recipient, and the server it is a client of
+  pub fn to(mut self, dst: ClientId, dstsrv: ServerId) -> Self {
+    self.0.dsts.push((dst, dstsrv));
+    self
+  }
+
+  pub fn content(mut self, content: String) -> Self {
+    self.0.content = content;
+    self
+  }
+
+  /// plain text by default
+  pub fn with_content_type(mut self, content_type: ContentType) -> Self {
+    self.0.content_type = content_type;
+    self
+  }
+
+  /// none by default
+  pub fn mentions(mut self, mentions: Vec<Mention>) -> Self {
+    self.0.mentions = mentions;
+    self
+  }
+
+  /// not sequenced by default
+  pub fn sequenced(mut self, seq: u128) -> Self {
+    self.0.seq = seq;
+    self
+  }
+
+  /// not a reply by default
+  pub fn in_reply_to(mut self, id: Option<MessageId>) -> Self {
+    self.0.in_reply_to = id;
+    self
+  }
+
+  /// unknown (0) by default
+  pub fn timestamp(mut self, timestamp: u64) -> Self {
+    self.0.timestamp = timestamp;
+    self
+  }
+
+  /// a random one by default
+  pub fn id(mut self, id: MessageId) -> Self {
+    self.0.id = id;
+    self
+  }
+
+  pub fn build(self) -> Result<FullyQualifiedMessage, MessageError> {
+    self.0.validate()?;
+    Ok(self.0)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageError {
+  NoDestination,
+  DuplicateDestination(ClientId),
+  InvalidMention(ClientId),
+}
+
+impl std::fmt::Display for MessageError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MessageError::NoDestination => "NoDestination".fmt(f),
+      MessageError::DuplicateDestination(client) => write!(f, "DuplicateDestination({})", client),
+      MessageError::InvalidMention(client) => write!(f, "InvalidMention({})", client),
+    }
+  }
+}
+
+impl std::error::Error for MessageError {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ServerMessage {
+  /// Servers announcements
+  Announce {
+    /// The route is the list of servers that were traversed to reach us.
+    /// The last element is the closest to us, and the first the farthest.
+    route: Vec<ServerId>,
+    /// list of clients registed on the source server, with their names
+    clients: HashMap<ClientId, String>,
+  },
+  Message(FullyQualifiedMessage),
+  /// several messages for the same next hop, sent as a single frame
+  Batch(Vec<ServerMessage>),
+  /// `reader` read the message `id`, sent by `dst`
+  Receipt {
+    id: MessageId,
+    reader: ClientId,
+    dst: ClientId,
+    dstsrv: ServerId,
+  },
+  /// presence change of a client, sent to the neighbours of its server
+  Presence {
+    client: ClientId,
+    presence: Presence,
+  },
+  /// clients of `srv` that were unregistered, and must be forgotten
+  Withdraw {
+    srv: ServerId,
+    clients: Vec<ClientId>,
+  },
+  /// the message `id` from `src` (of `srcsrv`) to `dst` reached the mailbox of `dst`, sent
+  /// back by the server of `dst`
+  Delivered {
+    id: MessageId,
+    src: ClientId,
+    srcsrv: ServerId,
+    dst: ClientId,
+  },
+  /// ephemeral event from `src` to `dst`
+  Event {
+    src: ClientId,
+    dst: ClientId,
+    dstsrv: ServerId,
+    event: Event,
+  },
+  /// `reactor` reacted with `emoji` to the message `id`, sent by `dst`
+  Reaction {
+    id: MessageId,
+    reactor: ClientId,
+    dst: ClientId,
+    dstsrv: ServerId,
+    emoji: String,
+  },
+  /// opaque key agreement message from `src` to `dst`
+  KeyAgreement {
+    src: ClientId,
+    dst: ClientId,
+    dstsrv: ServerId,
+    payload: Vec<u8>,
+  },
+  /// sent to a neighbour instead of an announce that it already acknowledged with `token`
+  Resume {
+    token: u128,
+  },
+  /// a neighbour got our announce (or resume) in the frame `seqid`, and holds it under `token`
+  /// a new token means that it lost what we announced before
+  Session {
+    token: u128,
+    seqid: u128,
+  },
+  /// the members of `room` on `srv`, flooded to every server, only the announce with the highest
+  /// `version` is kept for each room and server
+  RoomMembers {
+    room: RoomId,
+    srv: ServerId,
+    version: u64,
+    members: Vec<ClientId>,
+  },
+  /// message from `src` (of `srcsrv`) to the members of `room` on the servers `dstsrvs`
+  RoomText {
+    room: RoomId,
+    src: ClientId,
+    srcsrv: ServerId,
+    dstsrvs: Vec<ServerId>,
+    content: String,
+  },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClientError {
+  UnknownClient, // client is unknown
+  BoxFull(ClientId),
+  InternalError,
+  /// too many requests are already waiting, try again later
+  ServerBusy,
+  /// registration refused by the spam checks
+  SpamDetected,
+  /// not allowed for this client, guests can't send, and only members can talk in a room
+  Forbidden,
+  UnknownRoom(RoomId),
+  /// not in the client history
+  UnknownMessage(MessageId),
+  /// this recipient blocked the sender
+  Blocked(ClientId),
+  /// this id is already used, by a local or remote client
+  AlreadyRegistered(ClientId),
+  /// the payload is larger than the limit of the server, in bytes
+  MessageTooLarge(u128),
+  /// the sender, or its address, sends messages faster than the server allows, try again later
+  RateLimited,
+  /// this sequence number was already used: the query is a replay, or a retry of a query that
+  /// was handled
+  Replayed(u128),
+  /// the message has more recipients than the limit of the server
+  TooManyDestinations(u128),
+  /// this name is already used by this local client
+  NameTaken(ClientId),
+  /// the sender already has this many messages waiting for unknown recipients
+  DelayedQuota(u128),
+  /// the client, or its address, was banned by an operator
+  Banned,
+  /// the server only registers invited clients, and the invite is missing, forged, expired or
+  /// used up
+  InvalidInvite,
+  /// this sequence number is too far below the highest one of the client to tell whether it was
+  /// used, the next fresh one is `expected`
+  SequenceReuse {
+    expected: u128,
+    got: u128,
+  },
+}
+
+impl std::fmt::Display for ClientError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ClientError::BoxFull(clientid) => write!(f, "BoxFull({})", clientid),
+      ClientError::InternalError => "InternalError".fmt(f),
+      ClientError::UnknownClient => "UnknownClient".fmt(f),
+      ClientError::ServerBusy => "ServerBusy".fmt(f),
+      ClientError::SpamDetected => "SpamDetected".fmt(f),
+      ClientError::Forbidden => "Forbidden".fmt(f),
+      ClientError::UnknownRoom(room) => write!(f, "UnknownRoom({})", room),
+      ClientError::UnknownMessage(id) => write!(f, "UnknownMessage({})", id),
+      ClientError::Blocked(client) => write!(f, "Blocked({})", client),
+      ClientError::AlreadyRegistered(client) => write!(f, "AlreadyRegistered({})", client),
+      ClientError::MessageTooLarge(max) => write!(f, "MessageTooLarge({})", max),
+      ClientError::RateLimited => "RateLimited".fmt(f),
+      ClientError::Replayed(seqid) => write!(f, "Replayed({})", seqid),
+      ClientError::TooManyDestinations(max) => write!(f, "TooManyDestinations({})", max),
+      ClientError::NameTaken(client) => write!(f, "NameTaken({})", client),
+      ClientError::DelayedQuota(max) => write!(f, "DelayedQuota({})", max),
+      ClientError::Banned => "Banned".fmt(f),
+      ClientError::InvalidInvite => "InvalidInvite".fmt(f),
+      ClientError::SequenceReuse { expected, got } => {
+        write!(f, "SequenceReuse(expected {}, got {})", expected, got)
+      }
+    }
+  }
+}
+
+/// Why a transport could not hand a request to the server, or get a reply out of it.
+///
+/// These are sent instead of the reply, and are not about the content of the request: a message
+/// that the server rejects is still a reply, with a `ClientError`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportError {
+  /// the frame could not be decoded
+  Malformed,
+  /// this query is not allowed from this peer
+  AuthRequired,
+  RateLimited,
+  TooLarge,
+  /// the sequence number of the request was already used, see `ClientError::Replayed`
+  Replayed,
+  /// the request was decoded, but could not be handled (unknown client, refused
+  /// registration...)
+  Refused,
+  /// the sequence number of the request fell behind the window, the next one the server accepts
+  /// is `expected`, see `ClientError::SequenceReuse`
+  SequenceReuse {
+    expected: u128,
+  },
+}
+
+impl std::fmt::Display for TransportError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TransportError::Malformed => "Malformed".fmt(f),
+      TransportError::AuthRequired => "AuthRequired".fmt(f),
+      TransportError::RateLimited => "RateLimited".fmt(f),
+      TransportError::TooLarge => "TooLarge".fmt(f),
+      TransportError::Replayed => "Replayed".fmt(f),
+      TransportError::Refused => "Refused".fmt(f),
+      TransportError::SequenceReuse { expected } => {
+        write!(f, "SequenceReuse(expected {})", expected)
+      }
+    }
+  }
+}
+
+impl std::error::Error for TransportError {}
+
+impl TransportError {
+  /// the transport error for a failed request, `Refused` unless the error is about the transport
+  /// (or the sequence number)
+  pub fn of(rr: &anyhow::Error) -> Self {
+    match rr.downcast_ref::<ClientError>() {
+      Some(ClientError::Replayed(_)) => return TransportError::Replayed,
+      Some(ClientError::SequenceReuse { expected, .. }) => {
+        return TransportError::SequenceReuse {
+          expected: *expected,
+        }
+      }
+      _ => (),
+    }
+    rr.downcast_ref::<TransportError>()
+      .copied()
+      .unwrap_or(TransportError::Refused)
+  }
+}
+
+impl std::error::Error for ClientError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    None
+  }
+
+  fn description(&self) -> &str {
+    "description() is deprecated; use Display"
+  }
+
+  fn cause(&self) -> Option<&dyn std::error::Error> {
+    self.source()
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClientReply {
+  /// the request was handled; a message comes with the id it got, to correlate it with later
+  /// receipts, confirmations and edits
+  Delivered(Option<MessageId>),
+  Error(ClientError),
+  /// unknown recipient, no relays found
+  Delayed(MessageId),
+  /// send to an external server, with the id of the message, if it is one
+  Transfer(NextHop, ServerMessage, Option<MessageId>),
+  /// answer to a `Ping`
+  Pong(u128),
+  /// outcome of a broadcast: how many clients got it (or will, through other servers), and the
+  /// ones that did not
+  Broadcast {
+    delivered: u128,
+    failed: Vec<(ClientId, ClientError)>,
+  },
+  /// outcome of a message to several recipients: the reply of each, in the order they were
+  /// given (never another `Multi`)
+  Multi(Vec<(ClientId, ClientReply)>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClientPollReply {
+  /// `srcsrv` is the server of `src`, `timestamp` when it accepted the message
+  Message {
+    src: ClientId,
+    srcsrv: ServerId,
+    content: String,
+    /// milliseconds since the Unix epoch, by the clock of `srcsrv`
+    timestamp: u64,
+  },
+  DelayedError(DelayedError),
+  Nothing,
+  RichMessage {
+    src: ClientId,
+    content: RichContent,
+  },
+  RoomMessage {
+    room: RoomId,
+    src: ClientId,
+    content: String,
+  },
+  /// `reader` read one of our messages, `id` is the one from its history
+  Receipt {
+    id: MessageId,
+    reader: ClientId,
+  },
+  /// the presence of a client we subscribed to
+  Presence {
+    client: ClientId,
+    presence: Presence,
+  },
+  /// ephemeral event, polled before the mailbox
+  Event {
+    src: ClientId,
+    event: Event,
+  },
+  /// our message `id` to `dst`, that was transferred or delayed, reached its mailbox
+  Delivered {
+    id: MessageId,
+    dst: ClientId,
+  },
+  /// tombstone of a message deleted by its sender
+  Deleted {
+    src: ClientId,
+  },
+  /// binary payload
+  Data {
+    src: ClientId,
+    mime: String,
+    bytes: Vec<u8>,
+  },
+  /// handshake message of an end-to-end encryption protocol, not kept in the history
+  KeyAgreement {
+    src: ClientId,
+    payload: Vec<u8>,
+  },
+  /// a message answering `in_reply_to`
+  ReplyTo {
+    in_reply_to: MessageId,
+    message: Box<ClientPollReply>,
+  },
+  /// `reactor` reacted to one of our messages, `id` is the one from its history
+  Reaction {
+    id: MessageId,
+    reactor: ClientId,
+    emoji: String,
+  },
+}
+
+impl ClientPollReply {
+  /// the reply without its threading
+  pub fn unthreaded(&self) -> &ClientPollReply {
+    match self {
+      ClientPollReply::ReplyTo { message, .. } => message.unthreaded(),
+      other => other,
+    }
+  }
+
+  /// the message this one answers, if any
+  pub fn in_reply_to(&self) -> Option<MessageId> {
+    match self {
+      ClientPollReply::ReplyTo { in_reply_to, .. } => Some(*in_reply_to),
+      _ => None,
+    }
+  }
+}
+
+/// which messages of its history a client looks for
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+  /// words that must all be in the message, whatever their case
+  pub terms: Vec<String>,
+  /// only the messages from this client
+  pub participant: Option<ClientId>,
+  /// polled at or after this time, in milliseconds since the unix epoch
+  pub since: Option<u64>,
+  /// polled before this time
+  pub until: Option<u64>,
+  /// the `next` of the previous page, `None` for the first page
+  pub before: Option<MessageId>,
+  /// the most messages in the page, servers can return less
+  pub limit: u128,
+}
+
+/// a message found by a search, with the time it was polled
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SearchHit {
+  pub entry: HistoryEntry,
+  /// in milliseconds since the unix epoch
+  pub polled: u64,
+}
+
+/// a page of search results, newest first
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchPage {
+  pub hits: Vec<SearchHit>,
+  /// where the next page starts, `None` on the last page
+  pub next: Option<MessageId>,
+}
+
+/// a message that was polled by its recipient, as kept in its history
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+  pub id: MessageId,
+  /// what the poll returned
+  pub message: ClientPollReply,
+  /// the emojis the client reacted with, each once, in reaction order
+  pub reactions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DelayedError {
+  UnknownRecipient(ClientId),
+  /// a message to this client expired before it was read
+  Expired(ClientId),
+  /// a message to this client, kept until it was known, was refused once it was (the sender was
+  /// banned meanwhile, for instance)
+  Rejected(ClientId),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Outgoing<A> {
+  pub nexthop: NextHop,
+  pub message: A,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ServerReply {
+  Outgoing(Vec<Outgoing<FullyQualifiedMessage>>),
+  /// other server messages to send, for now receipts
+  Forward(Vec<Outgoing<ServerMessage>>),
+  EmptyRoute,
+  Error(String),
+}
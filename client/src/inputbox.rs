@@ -1,4 +1,4 @@
-/// abstract input box implementation
+//! abstract input box implementation
 
 pub struct IBox {
   input: String,
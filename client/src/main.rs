@@ -1,11 +1,9 @@
 use async_std::channel::{Receiver, Sender};
-use async_std::net::UdpSocket;
 use async_std::sync::RwLock;
-use chatproto::client::Client;
+use chatproto::client::ChatClient;
 use chatproto::messages::{
-  ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+  ClientId, ClientMessage, ClientPollReply, ClientReply, Codec, RichContent,
 };
-use chatproto::netproto::{decode, encode};
 use crossterm::event::KeyEventKind;
 use crossterm::{
   event::{DisableMouseCapture, EnableMouseCapture, KeyCode},
@@ -25,8 +23,7 @@ use ratatui::{
   Terminal,
 };
 use std::collections::{HashMap, HashSet};
-use std::io::Cursor;
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
 use structopt::StructOpt;
 
 mod inputbox;
@@ -44,35 +41,10 @@ struct Opt {
   #[structopt(long, default_value = "127.0.0.1")]
   /// address to connect to
   host: IpAddr,
-}
-
-struct Network {
-  socket: UdpSocket,
-}
-
-impl Network {
-  async fn new(target: SocketAddr) -> anyhow::Result<Self> {
-    let socket = UdpSocket::bind("127.0.0.1:0").await?;
-    socket.connect(target).await?;
-    Ok(Self { socket })
-  }
-
-  async fn send(&self, sq: &Sequence<ClientQuery>) -> anyhow::Result<()> {
-    let mut wr = Cursor::new(Vec::new());
-    encode::sequence(&mut wr, sq, encode::client_query)?;
-    self.socket.send(&wr.into_inner()).await?;
-    Ok(())
-  }
 
-  async fn get<X, F>(&self, f: F) -> anyhow::Result<X>
-  where
-    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
-  {
-    let mut buf = vec![0u8; 8192];
-    let n = self.socket.recv(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    f(&mut cursor)
-  }
+  #[structopt(long, default_value = "binary")]
+  /// codec to ask the server for: binary, or json to read the traffic while debugging
+  codec: Codec,
 }
 
 #[derive(Debug)]
@@ -86,6 +58,8 @@ enum Command {
 enum Source {
   Me,
   Other,
+  /// a message from someone else that mentions us
+  Mention,
 }
 
 #[derive(Default)]
@@ -267,10 +241,11 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
         .userlist
         .get(cid)
         .map(|u| {
+          let presence = if u.active { "" } else { " [offline]" };
           if u.unread > 0 {
-            format!("{} ({})", u.name, u.unread)
+            format!("{}{} ({})", u.name, presence, u.unread)
           } else {
-            u.name.to_string()
+            format!("{}{}", u.name, presence)
           }
         })
         .unwrap_or("???".to_string());
@@ -296,6 +271,7 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
       .map(|(source, msg)| match source {
         Source::Me => Line::from(format!("> {}", msg).blue()),
         Source::Other => Line::from(format!("< {}", msg)),
+        Source::Mention => Line::from(format!("< {}", msg).yellow().bold()),
       })
       .collect(),
   };
@@ -304,13 +280,10 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
 }
 
 async fn handle_network(
-  client: Client,
-  network: Network,
+  mut client: ChatClient,
   event_tx: Sender<UIEvent>,
   rx: Receiver<Command>,
 ) -> anyhow::Result<()> {
-  let mut client = client;
-
   loop {
     log::debug!("waiting for command");
     let cmd = rx.recv().await?;
@@ -319,9 +292,18 @@ async fn handle_network(
     match cmd {
       Command::Quit => break,
       Command::ListUsers => {
-        let msg = client.sequence(ClientQuery::ListUsers);
-        network.send(&msg).await?;
-        let list = network.get(decode::userlist).await?;
+        // shared names are told apart by their tag, and the server identities are not listed
+        let list: HashMap<ClientId, String> = client
+          .list_user_entries()
+          .await?
+          .into_iter()
+          .map(|entry| (entry.id, entry.display_name()))
+          .chain(
+            ClientId::SYSTEM
+              .iter()
+              .map(|(id, name)| (*id, format!("[{}]", name))),
+          )
+          .collect();
         let mut lk = USERS.write().await;
         let known_users = lk
           .userlist
@@ -339,6 +321,11 @@ async fn handle_network(
             .entry(*disappeared_user)
             .and_modify(|e| e.active = false);
         }
+        for returning_user in new_userids.intersection(&known_userids) {
+          lk.userlist
+            .entry(*returning_user)
+            .and_modify(|e| e.active = true);
+        }
         for new_user in new_userids.difference(&known_userids) {
           lk.userlist.insert(
             *new_user,
@@ -360,21 +347,88 @@ async fn handle_network(
         }
       }
       Command::Poll => {
-        let msg = client.sequence(ClientQuery::Poll);
-        network.send(&msg).await?;
-        let reply = network.get(decode::client_poll_reply).await?;
+        let reply = client.poll().await?;
         let mut lk = USERS.write().await;
         let selected = lk.selected;
+        // replies are shown inline, the TUI does not thread
+        let reply = match reply {
+          ClientPollReply::ReplyTo { message, .. } => *message,
+          reply => reply,
+        };
         match reply {
-          ClientPollReply::Nothing => continue,
+          // typing indicators are not shown yet, and the TUI does not encrypt
+          ClientPollReply::Nothing
+          | ClientPollReply::Event { .. }
+          | ClientPollReply::KeyAgreement { .. } => continue,
+          ClientPollReply::ReplyTo { .. } => unreachable!("unthreaded above"),
           ClientPollReply::DelayedError(msg) => ERRORS.write().await.push(format!("{:?}", msg)),
-          ClientPollReply::Message { src, content } => {
+          ClientPollReply::Presence { client, presence } => {
+            let uinfo = lk.userlist.entry(client).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, format!("({:?})", presence)));
+          }
+          ClientPollReply::Deleted { src } => {
+            let uinfo = lk.userlist.entry(src).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, "(deleted)".to_string()));
+          }
+          ClientPollReply::Delivered { dst, .. } => {
+            let uinfo = lk.userlist.entry(dst).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, "(delivered)".to_string()));
+          }
+          ClientPollReply::Receipt { id, reader } => {
+            let uinfo = lk.userlist.entry(reader).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, format!("(read {})", id)));
+          }
+          ClientPollReply::Reaction { id, reactor, emoji } => {
+            let uinfo = lk.userlist.entry(reactor).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, format!("(reacted {} to {})", emoji, id)));
+          }
+          ClientPollReply::Message { src, content, .. } => {
             let uinfo = lk.userlist.entry(src).or_default();
             uinfo.messages.push((Source::Other, content));
             if selected != Some(src) {
               uinfo.unread += 1;
             }
           }
+          ClientPollReply::RoomMessage { room, src, content } => {
+            let uinfo = lk.userlist.entry(src).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, format!("[{}] {}", room, content)));
+            if selected != Some(src) {
+              uinfo.unread += 1;
+            }
+          }
+          ClientPollReply::Data { src, mime, bytes } => {
+            let uinfo = lk.userlist.entry(src).or_default();
+            uinfo
+              .messages
+              .push((Source::Other, format!("({}, {} bytes)", mime, bytes.len())));
+            if selected != Some(src) {
+              uinfo.unread += 1;
+            }
+          }
+          ClientPollReply::RichMessage { src, content } => {
+            let source = if content.mentions_client(client.id()) {
+              Source::Mention
+            } else {
+              Source::Other
+            };
+            let uinfo = lk.userlist.entry(src).or_default();
+            uinfo.messages.push((source, content.text));
+            if selected != Some(src) {
+              uinfo.unread += 1;
+            }
+          }
         }
       }
       Command::SendMessage { message } => {
@@ -394,16 +448,28 @@ async fn handle_network(
           .or_default()
           .messages
           .push((Source::Me, message.clone()));
-        let msg = client.sequence(ClientQuery::Message(ClientMessage::Text {
-          dest: target,
-          content: message,
-        }));
-        network.send(&msg).await?;
-        let repls = network.get(decode::client_replies).await?;
+        let names = lk
+          .userlist
+          .iter()
+          .map(|(id, u)| (*id, u.name.clone()))
+          .collect::<HashMap<_, _>>();
+        let content = RichContent::parse(&message, &names);
+        let msg = if content.mentions.is_empty() {
+          ClientMessage::Text {
+            dest: target,
+            content: message,
+          }
+        } else {
+          ClientMessage::Rich {
+            dest: vec![target],
+            content,
+          }
+        };
+        let repls = client.send(msg).await?;
         for repl in repls {
           match repl {
-            ClientReply::Delivered => (),
-            ClientReply::Delayed => ERRORS
+            ClientReply::Delivered(_) | ClientReply::Pong(_) => (),
+            ClientReply::Delayed(_) => ERRORS
               .write()
               .await
               .push(format!("message to {} delayed ...", target)),
@@ -411,7 +477,24 @@ async fn handle_network(
               .write()
               .await
               .push(format!("message to {}: {}", target, rr)),
-            ClientReply::Transfer(_, _) => todo!(),
+            ClientReply::Transfer(nexthop, ..) => ERRORS
+              .write()
+              .await
+              .push(format!("message to {} handed over to {}", target, nexthop)),
+            ClientReply::Broadcast { failed, .. } => {
+              let mut errors = ERRORS.write().await;
+              for (dst, rr) in failed {
+                errors.push(format!("broadcast to {}: {}", dst, rr));
+              }
+            }
+            ClientReply::Multi(replies) => {
+              let mut errors = ERRORS.write().await;
+              for (dst, repl) in replies {
+                if let ClientReply::Error(rr) = repl {
+                  errors.push(format!("message to {}: {}", dst, rr));
+                }
+              }
+            }
           }
         }
       }
@@ -429,19 +512,12 @@ async fn main_task() -> anyhow::Result<()> {
   pretty_env_logger::init();
 
   let opt = Opt::from_args();
-  let network = Network::new((opt.host, opt.port).into()).await?;
-  let tempid = ClientId::default();
-
-  let sq = Sequence {
-    seqid: 0,
-    src: tempid,
-    content: ClientQuery::Register(opt.name),
-  };
-
-  network.send(&sq).await?;
-  let id = network.get(decode::clientid).await?;
-  log::info!("registered as {}", id);
-  let client = Client::new(id);
+  let mut client = ChatClient::connect((opt.host, opt.port).into(), opt.name).await?;
+  log::info!("registered as {}", client.id());
+  if opt.codec != Codec::Binary {
+    let codec = client.negotiate_codec(&[opt.codec]).await?;
+    log::info!("using the {} codec", codec);
+  }
 
   let (tx, rx) = async_std::channel::bounded::<Command>(16);
   let (event_tx, event_rx) = async_std::channel::bounded::<UIEvent>(32);
@@ -468,7 +544,7 @@ async fn main_task() -> anyhow::Result<()> {
       }
     })?;
 
-  handle_network(client, network, event_tx, rx).await?;
+  handle_network(client, event_tx, rx).await?;
   tpoll.await;
   t_ui.await?;
   t_input.await?;
@@ -0,0 +1,148 @@
+//! human readable descriptions of the datagrams going through the proxy
+//!
+//! Replies carry nothing about the query they answer, so they are decoded with the last query the
+//! same client sent, in the codec it was sent with. Clients wait for each reply before their next
+//! query, a reply to a retried or dropped query can still be decoded with the wrong one.
+
+use std::io::Cursor;
+
+use chatproto::messages::{ClientQuery, Codec, Sequence, TransportError};
+use chatproto::netproto::budget::{self, DecodeBudget};
+use chatproto::netproto::debug::{self, Frame};
+use chatproto::netproto::{codec, decode};
+
+/// a query as the proxy saw it, to decode its reply
+pub type Pending = (Codec, ClientQuery);
+
+/// describes a client datagram, and returns the query to decode its reply with
+///
+/// Datagrams are decoded within the default `DecodeBudget`, like the server does.
+pub fn query(datagram: &[u8]) -> (Option<Pending>, String) {
+  let decoded = budget::within(DecodeBudget::default(), || {
+    codec::query(datagram.to_vec(), &[Codec::Json])
+  });
+  match decoded {
+    Ok((
+      codec,
+      Sequence {
+        seqid,
+        src,
+        content,
+      },
+    )) => {
+      let text = format!("#{} {} {:?} {:?}", seqid, src, codec, content);
+      (Some((codec, content)), text)
+    }
+    Err(rr) => (
+      None,
+      format!("undecodable query ({}): {}", rr, hex(datagram)),
+    ),
+  }
+}
+
+/// describes a server datagram, answering `pending`
+pub fn reply(pending: Option<&Pending>, datagram: &[u8]) -> String {
+  let decoded = match pending {
+    Some((codec, query)) => debug::decode(Frame::Reply(*codec, query), datagram),
+    None => decode::frame(&mut Cursor::new(datagram), |_| {
+      Err(anyhow::anyhow!("reply to an unknown query"))
+    }),
+  };
+  match decoded {
+    Ok(reply) => format!("{:?}", reply),
+    Err(rr) if rr.is::<TransportError>() => format!("transport error {}", rr),
+    Err(rr) => format!("undecodable reply ({}): {}", rr, hex(datagram)),
+  }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(text: &str) -> anyhow::Result<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    anyhow::bail!("odd number of hex digits");
+  }
+  (0..text.len())
+    .step_by(2)
+    .map(|i| Ok(u8::from_str_radix(text.get(i..i + 2).unwrap_or("-"), 16)?))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use chatproto::messages::{ClientId, ClientMessage, ClientReply, MessageId, Priority};
+  use chatproto::netproto::encode;
+  use uuid::Uuid;
+
+  #[test]
+  fn round_trip() {
+    let src = ClientId(Uuid::from_u128(1));
+    let mut out = Cursor::new(Vec::new());
+    encode::sequence(
+      &mut out,
+      &Sequence {
+        seqid: 3,
+        src,
+        content: ClientQuery::Ping(7),
+      },
+      encode::client_query,
+    )
+    .unwrap();
+    let (pending, text) = query(&out.into_inner());
+    assert_eq!(text, format!("#3 {} Binary Ping(7)", src));
+    assert!(matches!(
+      pending,
+      Some((Codec::Binary, ClientQuery::Ping(7)))
+    ));
+
+    let mut out = Cursor::new(Vec::new());
+    encode::client_replies(&mut out, &[ClientReply::Pong(7)]).unwrap();
+    let mut replies = Cursor::new(Vec::new());
+    encode::reply_frame(&mut replies, &out.into_inner()).unwrap();
+    assert_eq!(reply(pending.as_ref(), &replies.into_inner()), "[Pong(7)]");
+    let mut rate_limited = Cursor::new(Vec::new());
+    encode::error_frame(&mut rate_limited, &TransportError::RateLimited).unwrap();
+    let rate_limited = rate_limited.into_inner();
+    assert_eq!(
+      reply(pending.as_ref(), &rate_limited),
+      "transport error RateLimited"
+    );
+    assert_eq!(reply(None, &rate_limited), "transport error RateLimited");
+    assert_eq!(
+      reply(None, &[0, 1, 4, 42]),
+      "undecodable reply (reply to an unknown query): 0001042a"
+    );
+    assert_eq!(
+      unhex(&hex(&[0, 1, 254, 255])).unwrap(),
+      vec![0, 1, 254, 255]
+    );
+    assert!(unhex("0").is_err() && unhex("zz").is_err());
+  }
+
+  #[test]
+  fn budget() {
+    let mut message = ClientMessage::Ack(MessageId::default());
+    for _ in 0..20 {
+      message = ClientMessage::Prioritized {
+        priority: Priority::High,
+        message: Box::new(message),
+      };
+    }
+    let mut out = Cursor::new(Vec::new());
+    encode::sequence(
+      &mut out,
+      &Sequence {
+        seqid: 3,
+        src: ClientId::default(),
+        content: ClientQuery::Message(message),
+      },
+      encode::client_query,
+    )
+    .unwrap();
+    let (pending, text) = query(&out.into_inner());
+    assert!(pending.is_none());
+    assert!(text.contains("Decode budget exceeded (depth)"), "{}", text);
+  }
+}
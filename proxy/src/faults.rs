@@ -0,0 +1,159 @@
+//! faults injected in the traffic going through the proxy
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chatproto::rng::SharedRng;
+
+use crate::record::Way;
+
+/// the datagrams faults apply to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+  Up,
+  Down,
+  Both,
+}
+
+impl Direction {
+  fn covers(self, way: Way) -> bool {
+    matches!(
+      (self, way),
+      (Direction::Both, _) | (Direction::Up, Way::Up) | (Direction::Down, Way::Down)
+    )
+  }
+}
+
+impl fmt::Display for Direction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Direction::Up => "up".fmt(f),
+      Direction::Down => "down".fmt(f),
+      Direction::Both => "both".fmt(f),
+    }
+  }
+}
+
+impl FromStr for Direction {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "up" => Ok(Direction::Up),
+      "down" => Ok(Direction::Down),
+      "both" => Ok(Direction::Both),
+      _ => Err(anyhow::anyhow!(
+        "unknown direction {}, expected up, down or both",
+        s
+      )),
+    }
+  }
+}
+
+pub struct Faults {
+  pub direction: Direction,
+  /// probability to drop a datagram
+  pub drop: f64,
+  /// probability to flip the bits of a random byte of a datagram
+  pub corrupt: f64,
+  /// added to every datagram
+  pub delay: Duration,
+  /// up to this much more, drawn for each datagram, so that datagrams can be reordered
+  pub jitter: Duration,
+  pub rng: SharedRng,
+}
+
+/// what happens to a datagram
+#[derive(Debug, PartialEq, Eq)]
+pub enum Fate {
+  Dropped,
+  Forwarded {
+    datagram: Vec<u8>,
+    delay: Duration,
+    /// the index of the corrupted byte
+    corrupted: Option<usize>,
+  },
+}
+
+impl Faults {
+  pub fn apply(&self, way: Way, mut datagram: Vec<u8>) -> Fate {
+    if !self.direction.covers(way) {
+      return Fate::Forwarded {
+        datagram,
+        delay: Duration::ZERO,
+        corrupted: None,
+      };
+    }
+    if self.rng.unit() < self.drop {
+      return Fate::Dropped;
+    }
+    let mut corrupted = None;
+    if !datagram.is_empty() && self.rng.unit() < self.corrupt {
+      let at = self.rng.u128() as usize % datagram.len();
+      // never 0, the byte always changes
+      datagram[at] ^= (self.rng.u128() % 255) as u8 + 1;
+      corrupted = Some(at);
+    }
+    let delay = self.delay + self.jitter.mul_f64(self.rng.unit());
+    Fate::Forwarded {
+      datagram,
+      delay,
+      corrupted,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use chatproto::rng::SeededRng;
+  use std::sync::Arc;
+
+  fn faults(direction: Direction, drop: f64, corrupt: f64) -> Faults {
+    Faults {
+      direction,
+      drop,
+      corrupt,
+      delay: Duration::from_millis(10),
+      jitter: Duration::from_millis(5),
+      rng: Arc::new(SeededRng::new(1)),
+    }
+  }
+
+  #[test]
+  fn fates() {
+    let datagram = vec![1, 2, 3, 4];
+    let passed = Fate::Forwarded {
+      datagram: datagram.clone(),
+      delay: Duration::ZERO,
+      corrupted: None,
+    };
+    let everything = faults(Direction::Up, 1.0, 1.0);
+    assert_eq!(everything.apply(Way::Up, datagram.clone()), Fate::Dropped);
+    assert_eq!(everything.apply(Way::Down, datagram.clone()), passed);
+
+    let corrupting = faults(Direction::Both, 0.0, 1.0);
+    for way in [Way::Up, Way::Down] {
+      match corrupting.apply(way, datagram.clone()) {
+        Fate::Forwarded {
+          datagram: out,
+          delay,
+          corrupted: Some(at),
+        } => {
+          let changed: Vec<usize> = (0..4).filter(|i| out[*i] != datagram[*i]).collect();
+          assert_eq!(changed, vec![at]);
+          assert!((Duration::from_millis(10)..Duration::from_millis(15)).contains(&delay));
+        }
+        fate => panic!("not corrupted: {:?}", fate),
+      }
+    }
+
+    let delaying = faults(Direction::Down, 0.0, 0.0);
+    assert!(matches!(
+      delaying.apply(Way::Down, datagram.clone()),
+      Fate::Forwarded { corrupted: None, delay, .. } if delay >= Duration::from_millis(10)
+    ));
+    assert!("sideways".parse::<Direction>().is_err());
+  }
+}
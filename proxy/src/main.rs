@@ -0,0 +1,379 @@
+//! a proxy between chat clients and a server, for protocol debugging
+//!
+//! It prints every datagram decoded, can record them to send the client side again later with
+//! `chatproxy replay`, and can drop, delay or corrupt them on the way.
+
+use async_std::net::UdpSocket;
+use async_std::task;
+use chatproto::netproto::debug::dissector;
+use chatproto::rng::{os_rng, SeededRng, SharedRng};
+use describe::Pending;
+use faults::{Direction, Fate, Faults};
+use record::{Record, Recorder, Way};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+mod describe;
+mod faults;
+mod record;
+
+const MAX_DATAGRAM: usize = 65536;
+
+#[derive(StructOpt)]
+enum Opt {
+  /// relays clients to a server, printing their traffic
+  Proxy {
+    #[structopt(long, default_value = "127.0.0.1:4668")]
+    /// address the clients connect to
+    listen: SocketAddr,
+
+    #[structopt(long, default_value = "127.0.0.1:4666")]
+    /// address of the server
+    server: SocketAddr,
+
+    #[structopt(long)]
+    /// file to record the traffic to, for `replay`
+    record: Option<PathBuf>,
+
+    #[structopt(long, default_value = "both")]
+    /// datagrams the faults apply to: up (to the server), down or both
+    direction: Direction,
+
+    #[structopt(long, default_value = "0")]
+    /// probability to drop a datagram
+    drop: f64,
+
+    #[structopt(long, default_value = "0")]
+    /// probability to corrupt a byte of a datagram
+    corrupt: f64,
+
+    #[structopt(long, default_value = "0")]
+    /// delay added to every datagram, in milliseconds
+    delay: u64,
+
+    #[structopt(long, default_value = "0")]
+    /// random delay added on top of `delay`, up to this many milliseconds
+    jitter: u64,
+
+    #[structopt(long)]
+    /// seed for the faults, to reproduce a run (random when missing)
+    seed: Option<u64>,
+  },
+  /// prints a Wireshark dissector of the client and federation datagrams, in Lua
+  Dissector {
+    #[structopt(long, default_value = "4666")]
+    /// port of the client datagrams
+    cport: u16,
+
+    #[structopt(long, default_value = "4667")]
+    /// port of the federation datagrams
+    sport: u16,
+  },
+  /// sends the client datagrams of a recording to a server again, with their original timing
+  Replay {
+    /// file recorded by `proxy --record`
+    recording: PathBuf,
+
+    #[structopt(long, default_value = "127.0.0.1:4666")]
+    /// address of the server
+    server: SocketAddr,
+
+    #[structopt(long, default_value = "1")]
+    /// how much faster than recorded to send the datagrams
+    speed: f64,
+
+    #[structopt(long, default_value = "1000")]
+    /// how long to wait for the last replies, in milliseconds
+    linger: u64,
+  },
+}
+
+fn show(start: Instant, way: Way, peer: SocketAddr, text: &str) {
+  let arrow = match way {
+    Way::Up => "->",
+    Way::Down => "<-",
+  };
+  println!(
+    "{:>9.3} {} {} {}",
+    start.elapsed().as_secs_f64(),
+    peer,
+    arrow,
+    text
+  );
+}
+
+/// a socket connected to `server`, from an unspecified address of the same family
+async fn upstream(server: SocketAddr) -> std::io::Result<UdpSocket> {
+  let local: SocketAddr = if server.is_ipv4() {
+    ([0, 0, 0, 0], 0).into()
+  } else {
+    ([0u16; 8], 0).into()
+  };
+  let socket = UdpSocket::bind(local).await?;
+  socket.connect(server).await?;
+  Ok(socket)
+}
+
+struct Proxy {
+  listener: UdpSocket,
+  faults: Faults,
+  recorder: Option<Recorder>,
+  start: Instant,
+}
+
+/// a client of the proxy, with its own socket to the server so that replies can be told apart
+struct Session {
+  upstream: Arc<UdpSocket>,
+  /// the last query of the client, to decode the replies with
+  pending: Arc<Mutex<Option<Pending>>>,
+}
+
+impl Proxy {
+  /// records, prints and forwards a datagram, to the server through `upstream` or to `peer`
+  async fn relay(
+    self: &Arc<Self>,
+    way: Way,
+    peer: SocketAddr,
+    datagram: Vec<u8>,
+    text: String,
+    upstream: &Arc<UdpSocket>,
+  ) {
+    if let Some(recorder) = &self.recorder {
+      let record = Record {
+        millis: self.start.elapsed().as_millis() as u64,
+        way,
+        peer,
+        datagram: datagram.clone(),
+      };
+      if let Err(rr) = recorder.record(&record) {
+        log::error!("Could not record a datagram: {}", rr);
+      }
+    }
+    let (datagram, delay) = match self.faults.apply(way, datagram) {
+      Fate::Dropped => {
+        show(self.start, way, peer, &format!("{} (dropped)", text));
+        return;
+      }
+      Fate::Forwarded {
+        datagram,
+        delay,
+        corrupted,
+      } => {
+        let mut notes = Vec::new();
+        if let Some(at) = corrupted {
+          notes.push(format!("corrupted byte {}", at));
+        }
+        if !delay.is_zero() {
+          notes.push(format!("delayed {} ms", delay.as_millis()));
+        }
+        if notes.is_empty() {
+          show(self.start, way, peer, &text);
+        } else {
+          show(
+            self.start,
+            way,
+            peer,
+            &format!("{} ({})", text, notes.join(", ")),
+          );
+        }
+        (datagram, delay)
+      }
+    };
+    let proxy = self.clone();
+    let upstream = upstream.clone();
+    let send = async move {
+      task::sleep(delay).await;
+      let sent = match way {
+        Way::Up => upstream.send(&datagram).await,
+        Way::Down => proxy.listener.send_to(&datagram, peer).await,
+      };
+      if let Err(rr) = sent {
+        log::error!("Error when relaying a datagram of {}: {}", peer, rr);
+      }
+    };
+    // delayed datagrams can overtake each other, the others keep their order
+    if delay.is_zero() {
+      send.await
+    } else {
+      task::spawn(send);
+    }
+  }
+
+  async fn open(self: &Arc<Self>, peer: SocketAddr, server: SocketAddr) -> anyhow::Result<Session> {
+    let session = Session {
+      upstream: Arc::new(upstream(server).await?),
+      pending: Arc::new(Mutex::new(None)),
+    };
+    log::info!("New client {}", peer);
+    let proxy = self.clone();
+    let upstream = session.upstream.clone();
+    let pending = session.pending.clone();
+    task::spawn(async move {
+      let mut buf = vec![0u8; MAX_DATAGRAM];
+      loop {
+        match upstream.recv(&mut buf).await {
+          Ok(size) => {
+            let datagram = buf[..size].to_vec();
+            let text = describe::reply(pending.lock().unwrap().as_ref(), &datagram);
+            proxy
+              .relay(Way::Down, peer, datagram, text, &upstream)
+              .await;
+          }
+          // the server can be restarted while the client is still there
+          Err(rr) => log::error!("Error when receiving from the server for {}: {}", peer, rr),
+        }
+      }
+    });
+    Ok(session)
+  }
+}
+
+async fn proxy(
+  listen: SocketAddr,
+  server: SocketAddr,
+  record: Option<PathBuf>,
+  faults: Faults,
+) -> anyhow::Result<()> {
+  let proxy = Arc::new(Proxy {
+    listener: UdpSocket::bind(listen).await?,
+    faults,
+    recorder: record.as_deref().map(Recorder::create).transpose()?,
+    start: Instant::now(),
+  });
+  log::info!(
+    "Relaying clients from {} to {}",
+    proxy.listener.local_addr()?,
+    server
+  );
+  let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+  let mut buf = vec![0u8; MAX_DATAGRAM];
+  loop {
+    let (size, peer) = proxy.listener.recv_from(&mut buf).await?;
+    let datagram = buf[..size].to_vec();
+    let session = match sessions.entry(peer) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => match proxy.open(peer, server).await {
+        Ok(session) => entry.insert(session),
+        Err(rr) => {
+          log::error!("Could not open a socket to the server for {}: {}", peer, rr);
+          continue;
+        }
+      },
+    };
+    let (pending, text) = describe::query(&datagram);
+    *session.pending.lock().unwrap() = pending;
+    proxy
+      .relay(Way::Up, peer, datagram, text, &session.upstream)
+      .await;
+  }
+}
+
+/// a session for the recorded client `peer`, that only prints the replies
+async fn replayer(start: Instant, peer: SocketAddr, server: SocketAddr) -> anyhow::Result<Session> {
+  let session = Session {
+    upstream: Arc::new(upstream(server).await?),
+    pending: Arc::new(Mutex::new(None)),
+  };
+  let upstream = session.upstream.clone();
+  let pending = session.pending.clone();
+  task::spawn(async move {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+      match upstream.recv(&mut buf).await {
+        Ok(size) => {
+          let text = describe::reply(pending.lock().unwrap().as_ref(), &buf[..size]);
+          show(start, Way::Down, peer, &text);
+        }
+        Err(rr) => log::error!("Error when receiving from the server for {}: {}", peer, rr),
+      }
+    }
+  });
+  Ok(session)
+}
+
+/// the server gives new client ids on registration, so replayed queries from the old ones can
+/// get different replies than recorded
+async fn replay(
+  recording: PathBuf,
+  server: SocketAddr,
+  speed: f64,
+  linger: Duration,
+) -> anyhow::Result<()> {
+  if speed.is_nan() || speed <= 0.0 {
+    anyhow::bail!("the speed must be positive, not {}", speed);
+  }
+  let records = record::read(&recording)?;
+  let start = Instant::now();
+  let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+  for record in records.into_iter().filter(|r| r.way == Way::Up) {
+    let at = Duration::from_millis(record.millis).div_f64(speed);
+    if let Some(wait) = at.checked_sub(start.elapsed()) {
+      task::sleep(wait).await;
+    }
+    let peer = record.peer;
+    let session = match sessions.entry(peer) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => entry.insert(replayer(start, peer, server).await?),
+    };
+    let (pending, text) = describe::query(&record.datagram);
+    *session.pending.lock().unwrap() = pending;
+    show(start, Way::Up, peer, &text);
+    session.upstream.send(&record.datagram).await?;
+  }
+  task::sleep(linger).await;
+  Ok(())
+}
+
+fn main() {
+  pretty_env_logger::init();
+  let done = match Opt::from_args() {
+    Opt::Proxy {
+      listen,
+      server,
+      record,
+      direction,
+      drop,
+      corrupt,
+      delay,
+      jitter,
+      seed,
+    } => {
+      let rng: SharedRng = match seed {
+        Some(seed) => Arc::new(SeededRng::new(seed)),
+        None => os_rng(),
+      };
+      let faults = Faults {
+        direction,
+        drop,
+        corrupt,
+        delay: Duration::from_millis(delay),
+        jitter: Duration::from_millis(jitter),
+        rng,
+      };
+      task::block_on(proxy(listen, server, record, faults))
+    }
+    Opt::Dissector { cport, sport } => {
+      print!("{}", dissector(cport, sport));
+      Ok(())
+    }
+    Opt::Replay {
+      recording,
+      server,
+      speed,
+      linger,
+    } => task::block_on(replay(
+      recording,
+      server,
+      speed,
+      Duration::from_millis(linger),
+    )),
+  };
+  if let Err(rr) = done {
+    log::error!("{}", rr);
+  }
+}
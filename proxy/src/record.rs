@@ -0,0 +1,148 @@
+//! recordings of the traffic going through the proxy, that `chatproxy replay` sends again
+//!
+//! One line per datagram, as the proxy received it (before any fault): the milliseconds since the
+//! proxy started, `up` (client to server) or `down`, the client address and the datagram in hex.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::describe::{hex, unhex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Way {
+  /// from the client to the server
+  Up,
+  /// from the server to the client
+  Down,
+}
+
+impl fmt::Display for Way {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Way::Up => "up".fmt(f),
+      Way::Down => "down".fmt(f),
+    }
+  }
+}
+
+impl FromStr for Way {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "up" => Ok(Way::Up),
+      "down" => Ok(Way::Down),
+      _ => Err(anyhow::anyhow!(
+        "unknown direction {}, expected up or down",
+        s
+      )),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+  pub millis: u64,
+  pub way: Way,
+  pub peer: SocketAddr,
+  pub datagram: Vec<u8>,
+}
+
+impl fmt::Display for Record {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} {} {} {}",
+      self.millis,
+      self.way,
+      self.peer,
+      hex(&self.datagram)
+    )
+  }
+}
+
+impl FromStr for Record {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    match fields[..] {
+      [millis, way, peer, datagram] => Ok(Record {
+        millis: millis.parse()?,
+        way: way.parse()?,
+        peer: peer.parse()?,
+        datagram: unhex(datagram)?,
+      }),
+      // an empty datagram
+      [millis, way, peer] => Ok(Record {
+        millis: millis.parse()?,
+        way: way.parse()?,
+        peer: peer.parse()?,
+        datagram: Vec::new(),
+      }),
+      _ => Err(anyhow::anyhow!("expected 4 fields, got {}", fields.len())),
+    }
+  }
+}
+
+/// appends records to a file, flushing each one so that a killed proxy leaves a usable recording
+pub struct Recorder(Mutex<BufWriter<File>>);
+
+impl Recorder {
+  pub fn create(path: &Path) -> anyhow::Result<Self> {
+    Ok(Recorder(Mutex::new(BufWriter::new(File::create(path)?))))
+  }
+
+  pub fn record(&self, record: &Record) -> anyhow::Result<()> {
+    let mut out = self.0.lock().unwrap();
+    writeln!(out, "{}", record)?;
+    out.flush()?;
+    Ok(())
+  }
+}
+
+/// reads a recording, blank lines and lines starting with `#` are skipped
+pub fn read(path: &Path) -> anyhow::Result<Vec<Record>> {
+  let mut records = Vec::new();
+  for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let record = line
+      .parse()
+      .map_err(|rr| anyhow::anyhow!("{}:{}: {}", path.display(), n + 1, rr))?;
+    records.push(record);
+  }
+  Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn lines() {
+    let record = Record {
+      millis: 1500,
+      way: Way::Down,
+      peer: "[::1]:4000".parse().unwrap(),
+      datagram: vec![0, 0x2a, 0xff],
+    };
+    assert_eq!(record.to_string(), "1500 down [::1]:4000 002aff");
+    assert_eq!(record.to_string().parse::<Record>().unwrap(), record);
+    let empty = Record {
+      datagram: Vec::new(),
+      ..record
+    };
+    assert_eq!(empty.to_string().parse::<Record>().unwrap(), empty);
+    assert!("1500 sideways [::1]:4000 00".parse::<Record>().is_err());
+    assert!("1500 up".parse::<Record>().is_err());
+  }
+}
@@ -1,13 +1,41 @@
-use async_std::net::UdpSocket;
+use async_std::net::{TcpListener, ToSocketAddrs, UdpSocket};
 use async_std::sync::RwLock;
 use async_std::task;
-use chatproto::core::{DefaultChecker, MessageServer};
+use async_trait::async_trait;
+use chatproto::archive::now_ms;
+use chatproto::auth::{AuthPolicy, AuthThrottle};
+use chatproto::clock::system_clock;
+use chatproto::core::{
+  DefaultChecker, MessageServer, NamePolicy, OverflowPolicy, SendRate, ServerConfig, SpamChecker,
+};
+use chatproto::credentials::FileCredentials;
+use chatproto::federation::{
+  connect_first, interleave_families, FederationDriver, FederationTransport, ATTEMPT_DELAY,
+  COALESCE_WINDOW,
+};
+use chatproto::invite::Invites;
 use chatproto::messages::ServerReply;
-use chatproto::messages::{ClientError, ClientQuery, Sequence, ServerId};
-use chatproto::netproto::{decode, encode};
+use chatproto::messages::{ClientId, ClientQuery, Codec, ServerId, TransportError};
+use chatproto::mux;
+use chatproto::netproto::budget::{BudgetExceeded, FrameBudget};
+use chatproto::netproto::mode::{self, DecodeMode};
+use chatproto::netproto::{codec, decode};
+use chatproto::rng::os_rng;
+use chatproto::service::middleware::{
+  AuthLayer, LogLayer, RateLimitLayer, SizeLimitLayer, WaitLimitLayer,
+};
+use chatproto::service::{frame, Layer, Request, Response, ServerService, Service, ServiceExt};
+use chatproto::solutions::descamps_femery::Server;
+use chatproto::spam::webhook::{WebhookChecker, WebhookConfig};
+use futures::{Stream, TryStreamExt};
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -27,138 +55,776 @@ struct Opt {
   #[structopt(long, default_value = "0.0.0.0")]
   /// address to listen for servers on
   slisten: IpAddr,
+
+  #[structopt(long)]
+  /// http endpoint used to check new clients for spam (no checks when missing)
+  spam_webhook: Option<String>,
+
+  #[structopt(long, default_value = "64")]
+  /// client queries handled at the same time
+  client_concurrency: usize,
+
+  #[structopt(long, default_value = "1024")]
+  /// waiting polls (PollWait) of all the clients, handled besides the other client queries
+  max_waiting_polls: usize,
+
+  #[structopt(long, default_value = "1")]
+  /// waiting polls of a single client at the same time
+  client_waiting_polls: usize,
+
+  #[structopt(long, default_value = "16")]
+  /// waiting polls from a single address at the same time
+  address_waiting_polls: usize,
+
+  #[structopt(long, default_value = "16")]
+  /// federation messages handled at the same time, independently of client traffic
+  federation_concurrency: usize,
+
+  #[structopt(long, default_value = "8192")]
+  /// largest client datagram accepted, in bytes
+  max_request_size: usize,
+
+  #[structopt(long, default_value = "0")]
+  /// client requests per second allowed from each address (0 for no limit)
+  rate_limit: f64,
+
+  #[structopt(long, default_value = "20")]
+  /// client requests allowed at once from each address, on top of the rate limit
+  rate_burst: u32,
+
+  #[structopt(long, default_value = "0")]
+  /// messages per second each client can send, whatever the transport (0 for no limit)
+  send_rate: f64,
+
+  #[structopt(long, default_value = "0")]
+  /// messages per second the clients of each address can send together (0 for no limit)
+  address_send_rate: f64,
+
+  #[structopt(long, default_value = "20")]
+  /// messages a client, or an address, can send at once, on top of the send rates
+  send_burst: u32,
+
+  #[structopt(long, default_value = "0")]
+  /// broadcasts per second across the whole server (0 for no limit, bursts of `send_burst`)
+  broadcast_rate: f64,
+
+  #[structopt(long, default_value = "256")]
+  /// destinations of a single message
+  max_destinations: usize,
+
+  #[structopt(long, default_value = "1024")]
+  /// messages each sender can have waiting for unknown recipients
+  delayed_per_sender: usize,
+
+  #[structopt(long, default_value = "65536")]
+  /// messages waiting for unknown recipients, all senders included
+  delayed_total: usize,
+
+  #[structopt(long)]
+  /// log every client request
+  log_requests: bool,
+
+  #[structopt(long = "codec")]
+  /// codec clients can pick with their hello, on top of binary: json (can be repeated)
+  codecs: Vec<Codec>,
+
+  #[structopt(long, default_value = "lenient")]
+  /// how the binary frames of clients and servers are decoded: lenient, or strict to refuse
+  /// non-canonical varints and trailing bytes
+  decoding: DecodeMode,
+
+  #[structopt(long, default_value = "256")]
+  /// messages waiting in a mailbox, see --mailbox-overflow
+  mailbox_size: usize,
+
+  #[structopt(long = "client-mailbox")]
+  /// mailbox size of a client, as id=size, instead of --mailbox-size (can be repeated)
+  client_mailboxes: Vec<ClientMailbox>,
+
+  #[structopt(long)]
+  /// file holding the key that signs the invites, only invited clients can register when given
+  invite_key_file: Option<PathBuf>,
+
+  #[structopt(long, default_value = "reject")]
+  /// what to do when a mailbox is full: reject, drop-oldest, or cap:<bytes> for mailboxes only
+  /// limited by the memory they use
+  mailbox_overflow: OverflowPolicy,
+
+  #[structopt(long, default_value = "unique")]
+  /// whether local clients can share a name: unique, or shared
+  names: NamePolicy,
+
+  #[structopt(long, default_value = "86400")]
+  /// seconds a message waits to be polled, or for its recipient to be known, before it expires
+  message_ttl: u64,
+
+  #[structopt(long, default_value = "604800")]
+  /// seconds the tombstones of deleted or forgotten history messages are kept, for the other
+  /// devices of their client to sync
+  tombstone_grace: u64,
+
+  #[structopt(long, default_value = "32")]
+  /// sequence numbers accepted below the highest one of each client, for retries arriving out of
+  /// order (1 for strictly increasing numbers, at most 128)
+  sequence_window: u32,
+
+  #[structopt(long, default_value = "4096")]
+  /// largest binary payload in a message, in bytes (requests are also limited in size)
+  max_data_size: usize,
+
+  #[structopt(long, default_value = "60")]
+  /// seconds between two sweeps of the expired messages
+  expiry_interval: u64,
+
+  #[structopt(long, default_value = "0")]
+  /// seconds without polling after which a client is removed, and withdrawn from the federation,
+  /// on the next sweep (0 keeps idle clients)
+  idle_timeout: u64,
+
+  #[structopt(long)]
+  /// refuse guest registrations
+  no_guests: bool,
+
+  #[structopt(long)]
+  /// port to accept multiplexed client connections on (TCP, disabled when missing)
+  mux_port: Option<u16>,
+
+  #[structopt(long, default_value = "65536")]
+  /// largest frame an authenticated client can negotiate on a multiplexed connection, in bytes
+  /// (other connections are limited to --max-request-size)
+  mux_max_frame: usize,
+
+  #[structopt(long)]
+  /// file with the secrets of the clients that can authenticate on multiplexed connections, one
+  /// `<id> secret <hex>` per line (no larger frames when missing)
+  credentials_file: Option<PathBuf>,
+
+  #[structopt(long)]
+  /// identity of this server (random when missing)
+  id: Option<ServerId>,
+
+  #[structopt(long, default_value = "30")]
+  /// seconds between two announces to the neighbours
+  announce_interval: u64,
+
+  #[structopt(long, default_value = "1200")]
+  /// largest federation datagram, in bytes: batches and announces are split to fit
+  max_frame_size: usize,
+
+  #[structopt(long = "peer")]
+  /// neighbouring server, as id=host:port, or id=host:port,host:port,... when it has several
+  /// addresses, tried in parallel (can be repeated)
+  peers: Vec<Peer>,
+
+  #[structopt(subcommand)]
+  /// runs a command instead of the server
+  command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+  /// prints an invite signed with the key of --invite-key-file, for the servers started with it
+  IssueInvite {
+    #[structopt(long, default_value = "604800")]
+    /// seconds the invite can be used for
+    ttl: u64,
+
+    #[structopt(long, default_value = "1")]
+    /// registrations the invite allows
+    uses: u32,
+  },
+}
+
+type Checker = Box<dyn SpamChecker + Send + Sync>;
+
+struct Peer {
+  id: ServerId,
+  // host:port, resolved when connecting
+  hosts: Vec<String>,
+}
+
+impl FromStr for Peer {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (id, hosts) = s
+      .split_once('=')
+      .ok_or_else(|| anyhow::anyhow!("expected id=host:port, got {}", s))?;
+    let hosts: Vec<String> = hosts.split(',').map(str::to_string).collect();
+    if let Some(host) = hosts.iter().find(|h| h.rsplit_once(':').is_none()) {
+      anyhow::bail!("expected host:port, got {}", host);
+    }
+    Ok(Peer {
+      id: id.parse()?,
+      hosts,
+    })
+  }
+}
+
+struct ClientMailbox {
+  id: ClientId,
+  size: usize,
+}
+
+impl FromStr for ClientMailbox {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (id, size) = s
+      .split_once('=')
+      .ok_or_else(|| anyhow::anyhow!("expected id=size, got {}", s))?;
+    Ok(ClientMailbox {
+      id: id.parse()?,
+      size: size.parse()?,
+    })
+  }
 }
 
-async fn server_thread<S: MessageServer<DefaultChecker>>(
+/// sends federation frames as datagrams to the configured peers
+///
+/// Each peer gets its own connected socket, to the first of its addresses that can be reached.
+/// The socket is dropped when a send fails, so that the next send connects again.
+struct UdpTransport {
+  peers: HashMap<ServerId, Vec<String>>,
+  links: RwLock<HashMap<ServerId, Arc<UdpSocket>>>,
+}
+
+impl UdpTransport {
+  async fn connect(&self, peer: ServerId) -> anyhow::Result<UdpSocket> {
+    let hosts = self
+      .peers
+      .get(&peer)
+      .ok_or_else(|| anyhow::anyhow!("no address for next hop {}", peer))?;
+    let mut addrs = Vec::new();
+    for host in hosts {
+      match host.to_socket_addrs().await {
+        Ok(resolved) => addrs.extend(resolved),
+        Err(rr) => log::warn!("Could not resolve {}: {}", host, rr),
+      }
+    }
+    connect_first(
+      &interleave_families(addrs),
+      ATTEMPT_DELAY,
+      |addr| async move {
+        let local: SocketAddr = if addr.is_ipv6() {
+          "[::]:0".parse()?
+        } else {
+          "0.0.0.0:0".parse()?
+        };
+        let socket = UdpSocket::bind(local).await?;
+        // fails right away when there is no route to the address
+        socket.connect(addr).await?;
+        Ok(socket)
+      },
+    )
+    .await
+  }
+}
+
+#[async_trait]
+impl FederationTransport for UdpTransport {
+  async fn send_frame(&self, nexthop: ServerId, frame: Vec<u8>) -> anyhow::Result<()> {
+    let link = self.links.read().await.get(&nexthop).cloned();
+    let socket = match link {
+      Some(socket) => socket,
+      None => {
+        let socket = Arc::new(self.connect(nexthop).await?);
+        log::info!("Link to {} through {}", nexthop, socket.peer_addr()?);
+        self.links.write().await.insert(nexthop, socket.clone());
+        socket
+      }
+    };
+    if let Err(rr) = socket.send(&frame).await {
+      self.links.write().await.remove(&nexthop);
+      return Err(rr.into());
+    }
+    Ok(())
+  }
+}
+
+type Driver = FederationDriver<UdpTransport>;
+
+/// Incoming datagrams on a socket.
+///
+/// Clients and servers each get their own socket and stream, handled with their own concurrency
+/// budget, so that a flood on one side can't starve the other.
+fn datagrams(
+  socket: &UdpSocket,
+) -> impl Stream<Item = std::io::Result<(Vec<u8>, SocketAddr)>> + '_ {
+  futures::stream::unfold(socket, |socket| async move {
+    let mut buf = vec![0u8; 65536];
+    let received = socket.recv_from(&mut buf).await.map(|(n, peer)| {
+      buf.truncate(n);
+      (buf, peer)
+    });
+    Some((received, socket))
+  })
+}
+
+// frames over their decode budget are counted, and reported with their count
+fn over_budget(budget: &FrameBudget, peer: SocketAddr, rr: &anyhow::Error) {
+  if rr.is::<BudgetExceeded>() {
+    let count = budget.violations(peer.ip());
+    log::warn!("{} went over its decode budget {} times", peer.ip(), count);
+  }
+}
+
+async fn server_thread<S: MessageServer<Checker> + Sync>(
   listen: IpAddr,
   port: u16,
-  srv: &RwLock<S>,
+  concurrency: usize,
+  srv: &S,
+  driver: &Driver,
+  budget: &FrameBudget,
+  mode: DecodeMode,
 ) -> std::io::Result<()> {
   let socket = UdpSocket::bind((listen, port)).await?;
   log::info!("Listening for servers on {}", socket.local_addr()?);
-  let mut buf = vec![0u8; 8192];
-  loop {
-    let (n, peer) = socket.recv_from(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    match decode::server(&mut cursor) {
-      Err(rr) => log::error!("Could not decode server message from {}: {}", peer, rr),
-      Ok(msg) => match srv.write().await.handle_server_message(msg).await {
-        ServerReply::Outgoing(_) => todo!(),
-        ServerReply::EmptyRoute => todo!(),
-        ServerReply::Error(rr) => {
-          log::error!("Error occured when handling message from {}: {}", peer, rr)
+  datagrams(&socket)
+    .try_for_each_concurrent(concurrency, |(buf, peer)| async move {
+      let mut cursor = Cursor::new(buf);
+      let sequence = budget.decode(peer.ip(), || {
+        mode::within(mode, || {
+          let sequence = decode::server_sequence(&mut cursor, decode::server)?;
+          mode::end(&mut cursor)?;
+          Ok(sequence)
+        })
+      });
+      let sequence = match sequence {
+        Err(rr) => {
+          log::error!("Could not decode server message from {}: {}", peer, rr);
+          over_budget(budget, peer, &rr);
+          return Ok(());
         }
-      },
+        Ok(sequence) => sequence,
+      };
+      let (from, seqid) = (sequence.src, sequence.seqid);
+      let msg = match srv.handle_server_sequence(sequence).await {
+        None => {
+          log::warn!("Replayed frame from {} ({})", from, peer);
+          return Ok(());
+        }
+        Some(msg) => msg,
+      };
+      // link state is for the driver, the rest for the server
+      if let Some(msg) = driver.receive(from, seqid, msg).await {
+        match srv.handle_server_message(msg).await {
+          ServerReply::Outgoing(outgoing) => driver.queue_outgoing(outgoing).await,
+          ServerReply::Forward(outgoing) => {
+            for o in outgoing {
+              driver.queue(o.nexthop, o.message).await;
+            }
+          }
+          ServerReply::EmptyRoute => log::warn!("Empty route announced by {}", peer),
+          ServerReply::Error(rr) => {
+            log::error!("Error occured when handling message from {}: {}", peer, rr)
+          }
+        }
+      }
+      Ok(())
+    })
+    .await
+}
+
+/// hands the messages for other servers to the federation driver
+struct FederateLayer(Arc<Driver>);
+
+impl<S> Layer<S> for FederateLayer {
+  type Service = Federate<S>;
+
+  fn layer(&self, inner: S) -> Federate<S> {
+    Federate {
+      inner,
+      driver: self.0.clone(),
     }
   }
 }
 
-async fn handle_client_query<S: MessageServer<DefaultChecker>>(
-  src_ip: IpAddr,
-  srv: &RwLock<S>,
-  m: Sequence<ClientQuery>,
-) -> anyhow::Result<Vec<u8>> {
-  log::debug!("received {:?}", m);
-  let src = m.src;
+struct Federate<S> {
+  inner: S,
+  driver: Arc<Driver>,
+}
 
-  let lock = srv.write().await;
+#[async_trait]
+impl<S: Service + Send + Sync> Service for Federate<S> {
+  async fn call(&self, req: Request) -> anyhow::Result<Response> {
+    let mut rsp = self.inner.call(req).await?;
+    for (nexthop, message) in rsp.transfers.drain(..) {
+      self.driver.queue(nexthop, message).await;
+    }
+    Ok(rsp)
+  }
+}
 
-  // handle register
-  if let ClientQuery::Register(name) = &m.content {
-    log::debug!("handle register message");
-    let name = name.clone();
-    match lock.handle_sequenced_message(m).await {
-      Ok(_) => (),
-      Err(ClientError::UnknownClient) => (),
-      Err(rr) => {
-        anyhow::bail!("Error when handling register message: {}", rr);
+/// client queries handled at the same time
+#[derive(Clone, Copy)]
+struct ClientConcurrency {
+  queries: usize,
+  /// the waiting polls, handled besides the other queries
+  waiting_polls: usize,
+}
+
+async fn client_thread<S: Service + Send + Sync + 'static>(
+  listen: IpAddr,
+  port: u16,
+  concurrency: ClientConcurrency,
+  service: Arc<S>,
+  budget: &FrameBudget,
+  codecs: &[Codec],
+  mode: DecodeMode,
+) -> anyhow::Result<()> {
+  let socket = Arc::new(UdpSocket::bind((listen, port)).await?);
+  log::info!("Listening for clients on {}", socket.local_addr()?);
+  let waiting = Arc::new(AtomicUsize::new(0));
+  datagrams(&socket)
+    .try_for_each_concurrent(concurrency.queries, |(buf, peer)| {
+      let (service, socket, waiting) = (service.clone(), socket.clone(), waiting.clone());
+      async move {
+        let size = buf.len();
+        let query = budget.decode(peer.ip(), || {
+          mode::within(mode, || codec::query(buf, codecs))
+        });
+        let request = match query {
+          Err(rr) => {
+            log::error!("Could not decode message from {}: {}", peer, rr);
+            over_budget(budget, peer, &rr);
+            send_reply(&socket, peer, frame(Err(TransportError::Malformed))).await;
+            return Ok(());
+          }
+          Ok((codec, query)) => Request {
+            peer,
+            size,
+            max_size: None,
+            codec,
+            query,
+          },
+        };
+        // waiting polls are answered outside of the concurrency limit, that they would hold on
+        // to for as long as they wait
+        if let ClientQuery::PollWait(_) = request.query.content {
+          if waiting.fetch_add(1, Ordering::SeqCst) >= concurrency.waiting_polls {
+            waiting.fetch_sub(1, Ordering::SeqCst);
+            log::warn!("Too many waiting polls, refusing the one of {}", peer);
+            send_reply(&socket, peer, frame(Err(TransportError::RateLimited))).await;
+          } else {
+            task::spawn(async move {
+              let reply = answer(&*service, request).await;
+              waiting.fetch_sub(1, Ordering::SeqCst);
+              send_reply(&socket, peer, reply).await;
+            });
+          }
+          return Ok(());
+        }
+        let reply = answer(&*service, request).await;
+        send_reply(&socket, peer, reply).await;
+        Ok(())
       }
+    })
+    .await?;
+  Ok(())
+}
+
+// the reply frame to a client request
+async fn answer<S: Service + ?Sized>(service: &S, request: Request) -> Vec<u8> {
+  let peer = request.peer;
+  match service.call(request).await {
+    Ok(Response { reply, .. }) => {
+      log::debug!("sending message {:?}", reply);
+      frame(Ok(&reply))
     }
-    let id = lock
-      .register_local_client(src_ip, name)
-      .await
-      .ok_or_else(|| anyhow::anyhow!("flagged as spammer"))?;
-    let mut ocurs = Cursor::new(Vec::new());
-    encode::clientid(&mut ocurs, &id)?;
-    return Ok(ocurs.into_inner());
-  }
-
-  match lock.handle_sequenced_message(m).await? {
-    ClientQuery::Poll => {
-      let repl = lock.client_poll(src).await;
-      log::debug!(" -> poll {:?}", repl);
-      let mut ocurs = Cursor::new(Vec::new());
-      encode::client_poll_reply(&mut ocurs, &repl)?;
-      Ok(ocurs.into_inner())
-    }
-    ClientQuery::ListUsers => {
-      let repl = lock.list_users().await;
-      let mut ocurs = Cursor::new(Vec::new());
-      encode::userlist(&mut ocurs, &repl)?;
-      Ok(ocurs.into_inner())
-    }
-    ClientQuery::Register(_) => {
-      anyhow::bail!("Unexpected register message from enrolled client")
-    }
-    ClientQuery::Message(msg) => {
-      let repl = lock.handle_client_message(src, msg).await;
-      let mut ocurs = Cursor::new(Vec::new());
-      encode::client_replies(&mut ocurs, &repl)?;
-      Ok(ocurs.into_inner())
+    Err(rr) => {
+      log::error!("Error when handling message to {}: {}", peer, rr);
+      frame(Err(TransportError::of(&rr)))
     }
   }
 }
 
-async fn client_thread<S: MessageServer<DefaultChecker>>(
+async fn send_reply(socket: &UdpSocket, peer: SocketAddr, reply: Vec<u8>) {
+  if let Err(rr) = socket.send_to(&reply, peer).await {
+    log::error!("Error when sending message to {}: {}", peer, rr)
+  }
+}
+
+/// wraps the server with the middleware enabled on the command line, outermost last
+fn client_service<S: Service + Send + Sync + 'static>(
+  opt: &Opt,
+  server: S,
+) -> Box<dyn Service + Send + Sync> {
+  let mut service: Box<dyn Service + Send + Sync> = Box::new(server);
+  if opt.no_guests {
+    service = Box::new(service.with(AuthLayer::new(|req: &Request| {
+      !matches!(req.query.content, ClientQuery::RegisterGuest(_))
+    })));
+  }
+  service = Box::new(service.with(SizeLimitLayer {
+    max: opt.max_request_size,
+  }));
+  service = Box::new(service.with(WaitLimitLayer::new(
+    opt.client_waiting_polls,
+    opt.address_waiting_polls,
+  )));
+  if opt.rate_limit > 0.0 {
+    service = Box::new(service.with(RateLimitLayer::new(opt.rate_limit, opt.rate_burst)));
+  }
+  if opt.log_requests {
+    service = Box::new(service.with(LogLayer::default()));
+  }
+  service
+}
+
+async fn mux_thread<S: Service + Send + Sync + 'static>(
   listen: IpAddr,
   port: u16,
-  srv: &RwLock<S>,
+  limits: mux::FrameLimits,
+  service: Arc<S>,
+  auth: Option<Arc<mux::MuxAuth>>,
+  decoding: mux::Decoding,
 ) -> anyhow::Result<()> {
-  let socket = UdpSocket::bind((listen, port)).await?;
-  log::info!("Listening for clients on {}", socket.local_addr()?);
-  let mut buf = vec![0u8; 8192];
+  let listener = TcpListener::bind((listen, port)).await?;
+  log::info!(
+    "Listening for multiplexed clients on {}",
+    listener.local_addr()?
+  );
   loop {
-    let (n, peer) = socket.recv_from(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    match decode::sequence(&mut cursor, decode::client_query) {
-      Err(rr) => log::error!("Could not decode message from {}: {}", peer, rr),
-      Ok(m) => match handle_client_query(peer.ip(), srv, m).await {
-        Ok(msg) => {
-          log::debug!("sending message {:?}", msg);
-          match socket.send_to(&msg, peer).await {
-            Ok(_) => (),
-            Err(rr) => log::error!("Error when sending message to {}: {}", peer, rr),
-          }
+    let (stream, peer) = listener.accept().await?;
+    log::debug!("Multiplexed connection from {}", peer);
+    let service = service.clone();
+    let auth = auth.clone();
+    let decoding = decoding.clone();
+    task::spawn(async move {
+      if let Err(rr) = mux::serve_connection(
+        stream,
+        service,
+        limits,
+        mux::ChannelLimits::default(),
+        auth,
+        decoding,
+      )
+      .await
+      {
+        log::error!("{}", rr)
+      }
+    });
+  }
+}
+
+/// periodically drops the expired messages, and the idle clients, forever
+async fn expiry_thread(
+  srv: Arc<Server<Checker>>,
+  driver: Arc<Driver>,
+  interval: Duration,
+  idle_timeout: Option<Duration>,
+) {
+  loop {
+    task::sleep(interval).await;
+    let expired = srv.expire_messages(Instant::now()).await;
+    if expired > 0 {
+      log::info!("{} messages expired", expired);
+    }
+    if let Some(max_age) = idle_timeout {
+      let (evicted, withdraws) = srv.evict_idle(Instant::now(), max_age).await;
+      if !evicted.is_empty() {
+        log::info!("{} idle clients removed", evicted.len());
+        for o in withdraws {
+          driver.queue(o.nexthop, o.message).await;
         }
-        Err(rr) => log::error!("Error when handling message to {}: {}", peer, rr),
-      },
+      }
     }
   }
 }
 
+async fn announce_thread(
+  srv: Arc<Server<Checker>>,
+  driver: Arc<Driver>,
+  peers: Vec<ServerId>,
+  interval: Duration,
+) {
+  loop {
+    let announce = srv.make_announce().await;
+    for peer in &peers {
+      driver.link_up(*peer, announce.clone()).await;
+    }
+    task::sleep(interval).await;
+  }
+}
+
 fn main() {
   pretty_env_logger::init();
   let opt = Opt::from_args();
 
-  let server = chatproto::solutions::descamps_femery::Server::new(
-    DefaultChecker::default(),
-    ServerId::default(),
+  let checker: Checker = match &opt.spam_webhook {
+    None => Box::new(DefaultChecker::default()),
+    Some(url) => match WebhookChecker::new(WebhookConfig::new(url)) {
+      Ok(checker) => Box::new(checker),
+      Err(rr) => {
+        log::error!("{}", rr);
+        return;
+      }
+    },
+  };
+  let id = opt.id.unwrap_or_default();
+  let config = ServerConfig {
+    mailbox_size: opt.mailbox_size,
+    mailbox_sizes: opt
+      .client_mailboxes
+      .iter()
+      .map(|m| (m.id, m.size))
+      .collect(),
+    invite_key: match opt.invite_key_file.as_ref().map(std::fs::read) {
+      None => None,
+      Some(Ok(key)) => Some(key),
+      Some(Err(rr)) => {
+        log::error!("could not read the invite key: {}", rr);
+        return;
+      }
+    },
+  };
+  if let Some(Command::IssueInvite { ttl, uses }) = opt.command {
+    match &config.invite_key {
+      None => log::error!("issuing invites needs --invite-key-file"),
+      Some(key) => {
+        let invites = Invites::new(key.clone());
+        let ttl = Duration::from_secs(ttl);
+        println!("{}", invites.issue(&*os_rng(), now_ms(), ttl, uses));
+      }
+    }
+    return;
+  }
+  let auth = match opt.credentials_file.as_ref().map(FileCredentials::open) {
+    None => None,
+    Some(Ok(credentials)) => Some(Arc::new(mux::MuxAuth {
+      server: id,
+      credentials: Arc::new(credentials),
+      throttle: Arc::new(AuthThrottle::new(AuthPolicy::default(), system_clock())),
+      rng: os_rng(),
+    })),
+    Some(Err(rr)) => {
+      log::error!("could not read the credentials: {}", rr);
+      return;
+    }
+  };
+  let mut server = Server::new(checker, id, config);
+  server.set_overflow_policy(opt.mailbox_overflow);
+  server.set_name_policy(opt.names);
+  server.set_message_ttl(Duration::from_secs(opt.message_ttl));
+  server.set_tombstone_grace(Duration::from_secs(opt.tombstone_grace));
+  server.set_max_data_size(opt.max_data_size);
+  server.set_sequence_window(opt.sequence_window);
+  let send_rate = |rate: f64| {
+    (rate > 0.0).then_some(SendRate {
+      rate,
+      burst: opt.send_burst,
+    })
+  };
+  server.set_send_rate(send_rate(opt.send_rate), send_rate(opt.address_send_rate));
+  server.set_broadcast_rate(send_rate(opt.broadcast_rate));
+  server.set_max_destinations(opt.max_destinations);
+  server.set_delayed_quota(opt.delayed_per_sender, opt.delayed_total);
+  let ssrv = Arc::new(server);
+  let service = client_service(
+    &opt,
+    ServerService::<_, Checker>::new(ssrv.clone()).with_codecs(opt.codecs.clone()),
   );
-  let clock = Arc::new(RwLock::new(server));
-  let slock = clock.clone();
 
   task::block_on(async move {
+    let transport = UdpTransport {
+      peers: opt.peers.iter().map(|p| (p.id, p.hosts.clone())).collect(),
+      links: RwLock::new(HashMap::new()),
+    };
+    let sdriver = Arc::new(
+      FederationDriver::new(id, transport, COALESCE_WINDOW).with_max_frame_size(opt.max_frame_size),
+    );
+    let ddriver = sdriver.clone();
+    let adriver = sdriver.clone();
+    let edriver = sdriver.clone();
+    let asrv = ssrv.clone();
+    let esrv = ssrv.clone();
+    let cservice = Arc::new(service.with(FederateLayer(sdriver.clone())));
+    let mservice = cservice.clone();
+    // both client transports count the violations of an address together
+    let cbudget = Arc::new(FrameBudget::default());
+    let mbudget = cbudget.clone();
+    // the transports decode the codecs the service lets clients pick
+    let ccodecs: Arc<[Codec]> = opt.codecs.clone().into();
+    let mcodecs = ccodecs.clone();
+
     let cchild = task::spawn(async move {
-      if let Err(rr) = client_thread(opt.clisten, opt.cport, &clock).await {
+      if let Err(rr) = client_thread(
+        opt.clisten,
+        opt.cport,
+        ClientConcurrency {
+          queries: opt.client_concurrency,
+          waiting_polls: opt.max_waiting_polls,
+        },
+        cservice,
+        &cbudget,
+        &ccodecs,
+        opt.decoding,
+      )
+      .await
+      {
         log::error!("{}", rr)
       }
     });
+    let mchild = opt.mux_port.map(|port| {
+      task::spawn(async move {
+        let limits = mux::FrameLimits {
+          anonymous: opt.max_request_size,
+          authenticated: opt.mux_max_frame,
+        };
+        if let Err(rr) = mux_thread(
+          opt.clisten,
+          port,
+          limits,
+          mservice,
+          auth,
+          mux::Decoding {
+            budget: mbudget,
+            codecs: mcodecs,
+            mode: opt.decoding,
+          },
+        )
+        .await
+        {
+          log::error!("{}", rr)
+        }
+      })
+    });
     let schild = task::spawn(async move {
-      if let Err(rr) = server_thread(opt.slisten, opt.sport, &slock).await {
+      if let Err(rr) = server_thread(
+        opt.slisten,
+        opt.sport,
+        opt.federation_concurrency,
+        &*ssrv,
+        &sdriver,
+        &FrameBudget::default(),
+        opt.decoding,
+      )
+      .await
+      {
         log::error!("{}", rr)
       }
     });
+    let achild = task::spawn(announce_thread(
+      asrv,
+      adriver,
+      opt.peers.iter().map(|p| p.id).collect(),
+      Duration::from_secs(opt.announce_interval.max(1)),
+    ));
+    let dchild = task::spawn(async move { ddriver.run().await });
+    let echild = task::spawn(expiry_thread(
+      esrv,
+      edriver,
+      Duration::from_secs(opt.expiry_interval.max(1)),
+      (opt.idle_timeout > 0).then(|| Duration::from_secs(opt.idle_timeout)),
+    ));
     cchild.await;
+    if let Some(mchild) = mchild {
+      let _ = mchild.cancel().await;
+    }
     let _ = schild.cancel().await;
+    let _ = achild.cancel().await;
+    let _ = dchild.cancel().await;
+    let _ = echild.cancel().await;
   });
 }